@@ -28,6 +28,7 @@ fn main() {
         "InitRuntimeResponse",
         "Attestation",
         "NetworkConfig",
+        "DomainPolicy",
     ] {
         builder = builder.type_attribute(name, "#[derive(::scale_info::TypeInfo)]");
     }
@@ -38,6 +39,8 @@ fn main() {
         "StatisticsReqeust.all",
         "HttpFetch.body",
         "HttpFetch.headers",
+        "NetworkConfig.dns_resolver",
+        "NetworkConfig.domain_policies",
     ] {
         builder = builder.field_attribute(field, "#[serde(default)]");
     }
@@ -9,6 +9,18 @@ pub enum QueryError {
     NoResponse,
     ServiceUnavailable,
     Timeout,
+    /// The query was rejected because it could not start before its deadline.
+    Overloaded {
+        retry_after_ms: u64,
+    },
+    /// The query was rejected because the worker is running out of memory.
+    MemoryPressure,
+    /// The encoded response exceeds the limit of the worker, and the query did not ask for a
+    /// chunked response.
+    ResponseTooLarge {
+        size: u64,
+        limit: u64,
+    },
 }
 
 impl std::error::Error for QueryError {}
@@ -21,6 +33,16 @@ impl std::fmt::Display for QueryError {
             QueryError::NoResponse => write!(f, "No response"),
             QueryError::ServiceUnavailable => write!(f, "Service unavailable"),
             QueryError::Timeout => write!(f, "Timeout"),
+            QueryError::Overloaded { retry_after_ms } => {
+                write!(f, "Overloaded, retry after {}ms", retry_after_ms)
+            }
+            QueryError::MemoryPressure => write!(f, "Rejected under memory pressure"),
+            QueryError::ResponseTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "Response of {size} bytes exceeds the limit of {limit} bytes"
+                )
+            }
         }
     }
 }
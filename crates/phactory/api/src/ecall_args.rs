@@ -40,6 +40,10 @@ pub struct InitArgs {
     /// Only sync blocks into pruntime without dispatching messages.
     pub safe_mode_level: u8,
 
+    /// Boot for diagnostics. Headers are synced but blocks are not dispatched, so that the state
+    /// loaded from the checkpoint is kept intact and can still be backed up.
+    pub safe_mode: bool,
+
     /// Disable the RCU policy to update the Phactory state.
     pub no_rcu: bool,
 
@@ -51,4 +55,22 @@ pub struct InitArgs {
 
     /// The timeout of a single contract query.
     pub query_timeout: u64,
+
+    /// Number of recent blocks whose state is kept for contract queries at a historical block.
+    pub query_history_blocks: u32,
+
+    /// Number of blocks the dispatching is held behind the latest received block.
+    pub dispatch_lag: u32,
+
+    /// Keep the compiled sidevm modules in the sealing path to speed up the restarts.
+    pub sidevm_compile_cache: bool,
+
+    /// The heap budget in MiB, at whose thresholds the load is shed. 0 to disable.
+    pub memory_budget_mb: u64,
+
+    /// The max size in KiB of an encoded contract query response. 0 for unlimited.
+    pub max_query_response_kb: u64,
+
+    /// Record the audit trail of the dispatch of the blocks.
+    pub audit_dispatch: bool,
 }
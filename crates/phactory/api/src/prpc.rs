@@ -1,5 +1,41 @@
 pub use crate::proto_generated::*;
 use alloc::vec::Vec;
-use phala_types::messaging::{MessageOrigin, SignedMessage};
+use phala_types::messaging::{MessageOrigin, QueueDepth, SignedMessage};
 pub use prpc::{client, server, Message};
 pub type EgressMessages = Vec<(MessageOrigin, Vec<SignedMessage>)>;
+pub type EgressQueueDepths = Vec<(MessageOrigin, QueueDepth)>;
+
+/// Bit flags of optional RPC features reported in `PhactoryInfo::rpc_features`.
+///
+/// Clients can use them to adapt their requests to the pruntime they are talking to.
+pub mod features {
+    /// RPC SyncCombinedHeaders is available.
+    pub const SYNC_COMBINED_HEADERS: u64 = 1 << 0;
+    /// RPC LoadChainState is available.
+    pub const LOAD_CHAIN_STATE: u64 = 1 << 1;
+    /// RPC GetContractEvents is available.
+    pub const GET_CONTRACT_EVENTS: u64 = 1 << 2;
+    /// RPC ListContracts is available.
+    pub const LIST_CONTRACTS: u64 = 1 << 3;
+    /// RPC GetEnclaveIdentity is available.
+    pub const GET_ENCLAVE_IDENTITY: u64 = 1 << 4;
+    /// RPC GetEgressQueueDepth is available.
+    pub const GET_EGRESS_QUEUE_DEPTH: u64 = 1 << 5;
+    /// RPC DryRunInstantiate is available.
+    pub const DRY_RUN_INSTANTIATE: u64 = 1 << 6;
+    /// Contract query responses can be read in chunks with RPC GetQueryResponseChunk.
+    pub const CHUNKED_QUERY_RESPONSE: u64 = 1 << 7;
+    /// RPC GetDispatchAudit is available.
+    pub const DISPATCH_AUDIT: u64 = 1 << 8;
+
+    /// All features supported by this version.
+    pub const ALL: u64 = SYNC_COMBINED_HEADERS
+        | LOAD_CHAIN_STATE
+        | GET_CONTRACT_EVENTS
+        | LIST_CONTRACTS
+        | GET_ENCLAVE_IDENTITY
+        | GET_EGRESS_QUEUE_DEPTH
+        | DRY_RUN_INSTANTIATE
+        | CHUNKED_QUERY_RESPONSE
+        | DISPATCH_AUDIT;
+}
@@ -0,0 +1,174 @@
+//! Off-chain replay of a single contract cluster.
+//!
+//! [`ClusterReplay`] runs the worker side of a cluster over the blocks of the chain, without the
+//! light client and the RPC service around it. Since the cluster key is never dispatched to the
+//! replaying worker, it has to be given up front, which only makes sense in dev/testing or for the
+//! forensics of a cluster whose key is at hand. The cluster is deployed once its creation is
+//! replayed, so the replay must start before the cluster is created.
+//!
+//! The messages sent by the worker and the contracts are collected per block instead of being
+//! pushed to the chain. Sidevm instances are started as usual, but their ocalls to the worker are
+//! not served.
+
+use crate::{
+    contracts::pink::context::{using, ContractExecContext},
+    pal,
+    sidevm_helper::create_sidevm_service,
+    system::System,
+    types::BlockInfo,
+    BaseBlockInfo, ChainStorage,
+};
+use anyhow::{anyhow, Result};
+use phactory_api::blocks::BlockHeaderWithChanges;
+use phala_crypto::sr25519::{Persistence, Sr25519SecretKey, KDF};
+use phala_mq::{ContractClusterId, Message, MessageDispatcher, MessageSendQueue};
+use pink_loader::types::{AccountId, ExecutionMode};
+use sp_core::{sr25519, Pair};
+use tracing::warn;
+
+pub struct ClusterReplay<Platform> {
+    system: System<Platform>,
+    storage: ChainStorage,
+    send_mq: MessageSendQueue,
+    recv_mq: MessageDispatcher,
+    sidevm_spawner: sidevm::service::Spawner,
+    block_number: chain::BlockNumber,
+}
+
+impl<Platform: pal::Platform> ClusterReplay<Platform> {
+    /// Creates a replay starting from `genesis_state`, the chain storage at the block before the
+    /// first block to replay. `cluster_secret_key` is the secret key of the cluster as dumped by
+    /// the workers.
+    pub fn new(
+        platform: Platform,
+        storage_path: String,
+        genesis_block_hash: [u8; 32],
+        genesis_state: Vec<(Vec<u8>, Vec<u8>)>,
+        cluster_id: ContractClusterId,
+        cluster_secret_key: &Sr25519SecretKey,
+    ) -> Self {
+        let mut storage = ChainStorage::default();
+        storage.load(genesis_state.into_iter());
+
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        recv_mq.set_chain_namespace(genesis_block_hash);
+
+        // A throwaway identity, the cluster never dispatches its key to it.
+        let (identity_key, _) = sr25519::Pair::generate();
+        let ecdh_key = identity_key.derive_ecdh_key();
+        let mut system = System::new(
+            platform,
+            true,
+            storage_path.clone(),
+            storage_path,
+            identity_key,
+            ecdh_key,
+            &send_mq,
+            &mut recv_mq,
+        );
+        let cluster_key = sr25519::Pair::restore_from_secret_key(cluster_secret_key);
+        system.preset_cluster_key(cluster_id, cluster_key);
+
+        // Nobody serves the ocalls of the sidevm instances.
+        let (sidevm_out_tx, _) = tokio::sync::mpsc::channel(1);
+        Self {
+            system,
+            storage,
+            send_mq,
+            recv_mq,
+            sidevm_spawner: create_sidevm_service(1, sidevm_out_tx),
+            block_number: 0,
+        }
+    }
+
+    /// The last replayed block.
+    pub fn block_number(&self) -> chain::BlockNumber {
+        self.block_number
+    }
+
+    /// The id of the cluster, if it has been deployed.
+    pub fn cluster_id(&self) -> Option<ContractClusterId> {
+        self.system
+            .contract_cluster
+            .as_ref()
+            .map(|cluster| cluster.id)
+    }
+
+    /// The contracts instantiated in the cluster so far.
+    pub fn contracts(&self) -> Vec<AccountId> {
+        self.system.contracts.keys().cloned().collect()
+    }
+
+    /// Applies the storage changes of `block` and processes its messages, returning the messages
+    /// sent by the worker and the contracts while processing it.
+    pub fn dispatch_block(&mut self, block: BlockHeaderWithChanges) -> Result<Vec<Message>> {
+        let header = &block.block_header;
+        let (state_root, transaction) = self.storage.inner().calc_root_if_changes(
+            &block.storage_changes.main_storage_changes,
+            &block.storage_changes.child_storage_changes,
+        );
+        if header.state_root != state_root {
+            return Err(anyhow!("State root mismatch at block {}", header.number));
+        }
+        self.storage
+            .inner_mut()
+            .apply_changes(state_root, transaction);
+
+        let block_number = header.number;
+        let now_ms = self.storage.timestamp_now();
+        let mut context = ContractExecContext::new(
+            ExecutionMode::Transaction,
+            now_ms,
+            block_number,
+            self.system.identity_key.clone(),
+            self.storage.snapshot(),
+            0,
+            self.system.contracts.clone(),
+            self.sidevm_spawner.event_tx(),
+            None,
+        );
+        using(&mut context, || {
+            self.handle_inbound_messages(block_number, now_ms)
+        });
+        self.block_number = block_number;
+
+        let messages = self
+            .send_mq
+            .all_messages()
+            .into_iter()
+            .map(|signed| signed.message)
+            .collect();
+        self.send_mq.purge(|_| u64::MAX);
+        Ok(messages)
+    }
+
+    fn handle_inbound_messages(&mut self, block_number: chain::BlockNumber, now_ms: u64) {
+        let messages = self.storage.mq_messages();
+        self.recv_mq.reset_local_index();
+
+        let mut block = BlockInfo {
+            base: BaseBlockInfo {
+                block_number,
+                now_ms,
+                storage: &self.storage,
+                send_mq: &self.send_mq,
+                recv_mq: &mut self.recv_mq,
+            },
+            sidevm_spawner: &self.sidevm_spawner,
+        };
+
+        self.system.will_process_block(&mut block);
+        for (index, message) in messages.into_iter().enumerate() {
+            let sequence = ((block_number as u64) << 32) | index as u64;
+            block.recv_mq.dispatch_sequenced(message, sequence);
+            self.system.process_messages(&mut block);
+        }
+        self.system.did_process_block(&mut block);
+
+        let n_unhandled = block.recv_mq.clear();
+        if n_unhandled > 0 {
+            warn!("There are {} unhandled messages dropped", n_unhandled);
+        }
+    }
+}
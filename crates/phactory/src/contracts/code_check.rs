@@ -0,0 +1,64 @@
+//! Upload time checks of ink and sidevm code, so that bad code is rejected at upload instead of
+//! failing at instantiation or at runtime.
+//!
+//! A rejection on the cluster upload path changes the cluster state, so the workers of a cluster
+//! must run pRuntimes with the same checks. The ink limits mirror those already enforced by
+//! pallet-contracts in the pink runtime.
+
+use phala_wasm_checker::{validate, CodeLimits, CodeRejection};
+use pink::SidevmConfig;
+
+/// Same as `MaxCodeLen` in the pink runtime.
+const MAX_INK_CODE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Upper bound of sidevm code stored in a cluster. The actual limit of an instance is given by
+/// its `SidevmConfig`.
+const MAX_SIDEVM_CODE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Sidevm instances are single threaded, waiting on an atomic would block forever.
+const SIDEVM_DENIED_OPERATORS: &[&str] = &[
+    "MemoryAtomicWait32",
+    "MemoryAtomicWait64",
+    "MemoryAtomicNotify",
+];
+
+pub(crate) fn check_ink_code(code: &[u8], deterministic: bool) -> Result<(), CodeRejection> {
+    validate(
+        code,
+        &CodeLimits {
+            max_code_size: MAX_INK_CODE_SIZE,
+            max_memory_pages: None,
+            deterministic,
+            denied_operators: &[],
+        },
+    )
+}
+
+/// Checks sidevm code uploaded to a cluster, before knowing which instance will run it.
+pub(crate) fn check_sidevm_code(code: &[u8]) -> Result<(), CodeRejection> {
+    validate(
+        code,
+        &CodeLimits {
+            max_code_size: MAX_SIDEVM_CODE_SIZE,
+            max_memory_pages: None,
+            deterministic: false,
+            denied_operators: SIDEVM_DENIED_OPERATORS,
+        },
+    )
+}
+
+/// Checks sidevm code against the config of the instance that is going to run it.
+pub(crate) fn check_sidevm_code_for(
+    code: &[u8],
+    config: &SidevmConfig,
+) -> Result<(), CodeRejection> {
+    validate(
+        code,
+        &CodeLimits {
+            max_code_size: config.max_code_size as usize,
+            max_memory_pages: Some(config.max_memory_pages as u64),
+            deterministic: false,
+            denied_operators: SIDEVM_DENIED_OPERATORS,
+        },
+    )
+}
@@ -1,4 +1,5 @@
 pub mod pink;
 pub use support::*;
+pub(crate) mod code_check;
 mod support;
 pub use phala_types::contract::*;
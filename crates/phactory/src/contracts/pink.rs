@@ -4,14 +4,20 @@ use std::sync::Mutex;
 use std::{convert::TryInto, time::Duration};
 
 use crate::{
+    contract_result::{ContractResult, ExecReturnValue, Weight},
     contracts::{self, block_on_run_module, QueryContext, TransactionContext},
+    dispatch_audit::AuditOp,
     system::{TransactionError, TransactionResult},
 };
 use anyhow::{Context, Result};
-use parity_scale_codec::Encode;
-use phala_crypto::sr25519::Persistence;
+use parity_scale_codec::{Decode, Encode};
+use phala_crypto::sr25519::{Persistence, KDF};
 use phala_mq::{ContractClusterId, MessageOrigin};
-use phala_types::{contract::messaging::ResourceType, SignedContentType};
+use phala_serde_more as more;
+use phala_types::{
+    contract::{messaging::ResourceType, ConvertTo, ExtensionPolicy, LogPolicy},
+    SignedContentType,
+};
 use pink::chain_extension::{JsCode, JsValue};
 use pink_loader::{
     capi::v1::{
@@ -37,15 +43,62 @@ use sidevm::{
     WasmEngine, WasmModule,
 };
 use sp_core::{blake2_256, sr25519, twox_64};
+use sp_runtime::{DispatchError, ModuleError};
 
-use tracing::info;
+use tracing::{info, warn};
 
 pub use phactory_api::contracts::{Query, QueryError, Response};
 pub use phala_types::contract::InkCommand;
 
 use super::ContractsKeeper;
 
+/// The index of `pallet_contracts` in the pink runtime, and the index of its `OutOfGas` error.
+const CONTRACTS_PALLET_INDEX: u8 = 4;
+const OUT_OF_GAS_ERROR: [u8; 4] = [2, 0, 0, 0];
+
+/// Caps the gas limit of a transaction execution at the execution deadline of the cluster.
+///
+/// The deadline is measured in gas and read from the chain state of the block being dispatched,
+/// so that all workers abort the same executions at the same point from the same block on.
+pub(crate) fn execution_gas_limit(gas_limit: u64, deadline: Option<u64>) -> u64 {
+    match deadline {
+        Some(deadline) => gas_limit.min(deadline),
+        None => gas_limit,
+    }
+}
+
+/// Whether an execution given `gas_limit` was aborted by running out of gas at the deadline.
+pub(crate) fn deadline_exceeded(output: &[u8], gas_limit: u64, deadline: Option<u64>) -> bool {
+    let Some(deadline) = deadline else {
+        return false;
+    };
+    if gas_limit < deadline {
+        // Ran out of the gas limit given by the caller, if ever.
+        return false;
+    }
+    match ContractResult::<ExecReturnValue>::decode(&mut &output[..]) {
+        Ok(result) => is_out_of_gas(&result.result),
+        Err(_) => false,
+    }
+}
+
+fn is_out_of_gas<R>(result: &Result<R, DispatchError>) -> bool {
+    matches!(
+        result,
+        Err(DispatchError::Module(ModuleError { index, error, .. }))
+            if *index == CONTRACTS_PALLET_INDEX && *error == OUT_OF_GAS_ERROR
+    )
+}
+
+/// The gas consumed by an execution, read from the head of the `ContractResult` it output.
+fn gas_consumed(output: &[u8]) -> u64 {
+    Weight::decode(&mut &output[..])
+        .map(|weight| weight.ref_time)
+        .unwrap_or_default()
+}
+
 pub(crate) mod http_counters;
+pub(crate) mod ink_events;
 
 #[derive(Serialize, Deserialize, Default, Clone, ::scale_info::TypeInfo)]
 pub struct ClusterConfig {
@@ -54,6 +107,42 @@ pub struct ClusterConfig {
     pub secret_salt: [u8; 32],
     #[serde(default)]
     pub js_runtime: Option<Hash>,
+    #[serde(default)]
+    pub log_policy: LogPolicy,
+    #[serde(default)]
+    pub extension_policy: ExtensionPolicy,
+    /// The root of the keys sealing the contract secrets, see [`cluster_secrets`]. It is held by
+    /// the worker and never written to the cluster storage. Clusters created before it have the
+    /// cluster key itself in their storage, so they can not seal secrets.
+    #[serde(default)]
+    pub secret_sealing_root: Option<[u8; 32]>,
+    /// The share of the cluster key held by this worker, renewed on each key refresh. The shares
+    /// of all the workers of the cluster add up to the cluster key.
+    #[serde(default, with = "more::option_key_bytes")]
+    #[codec(skip)]
+    pub key_share: Option<sr25519::Pair>,
+    /// The id of the last cluster key refresh applied to this worker.
+    #[serde(default)]
+    pub key_refresh_id: u64,
+}
+
+/// Splits the cluster key into the key written to the cluster storage, which the contracts derive
+/// their keys from, and the root of the keys sealing the contract secrets.
+///
+/// The storage key is a hard-derived child of the cluster key, so a dump of the cluster storage
+/// reveals neither the cluster key nor the sealing root.
+fn cluster_secrets(cluster_key: &sr25519::Pair) -> (sr25519::Pair, [u8; 32]) {
+    let storage_key = cluster_key
+        .derive_sr25519_pair(&[b"pink_storage_key"])
+        .expect("should not fail with valid info");
+    let sealing_root =
+        blake2_256(&(b"pink_secret_sealing", cluster_key.dump_secret_key()).encode());
+    (storage_key, sealing_root)
+}
+
+/// The key sealing the secrets of the contract.
+fn contract_sealing_key(sealing_root: &[u8; 32], contract: &AccountId) -> [u8; 32] {
+    blake2_256(&(b"pink_secret_sealing", sealing_root, contract).encode())
 }
 
 #[derive(Serialize, Deserialize, Clone, ::scale_info::TypeInfo)]
@@ -61,6 +150,10 @@ pub struct Cluster {
     pub id: ContractClusterId,
     pub config: ClusterConfig,
     pub storage: ClusterStorage,
+    /// The gas consumed by the transactions of the block being dispatched.
+    #[serde(skip)]
+    #[codec(skip)]
+    block_gas_consumed: u64,
 }
 
 pub struct RuntimeHandleMut<'a> {
@@ -88,9 +181,12 @@ impl RuntimeHandleMut<'_> {
         mode: ExecutionMode,
         tx_args: TransactionArguments,
     ) -> Vec<u8> {
-        context::using_entry(contract.clone(), tx_args.origin.clone(), move || {
-            self.contract_call(contract, input_data, mode, tx_args)
-        })
+        let handle = &mut *self;
+        let output = context::using_entry(contract.clone(), tx_args.origin.clone(), move || {
+            handle.contract_call(contract, input_data, mode, tx_args)
+        });
+        self.record_gas(mode, &output);
+        output
     }
 
     pub fn instantiate(
@@ -108,9 +204,18 @@ impl RuntimeHandleMut<'_> {
             &salt,
         );
         let entry = AccountId::from(blake2_256(&buf));
-        context::using_entry(entry, tx_args.origin.clone(), move || {
-            self.contract_instantiate(code_hash, instantiate_data, salt, mode, tx_args)
-        })
+        let handle = &mut *self;
+        let output = context::using_entry(entry, tx_args.origin.clone(), move || {
+            handle.contract_instantiate(code_hash, instantiate_data, salt, mode, tx_args)
+        });
+        self.record_gas(mode, &output);
+        output
+    }
+
+    fn record_gas(&mut self, mode: ExecutionMode, output: &[u8]) {
+        if mode == ExecutionMode::Transaction {
+            self.cluster.block_gas_consumed += gas_consumed(output);
+        }
     }
 }
 
@@ -403,6 +508,12 @@ impl OCalls for RuntimeHandle<'_> {
             error!("Pink log called outside of contract execution");
             return;
         };
+        let policy = &self.cluster.config.log_policy;
+        if !policy.forwards_log(&contract.convert_to(), level)
+            || !policy.forwards(&entry.convert_to())
+        {
+            return;
+        }
         let context = self.exec_context();
         let msg = SidevmCommand::PushSystemMessage(SystemMessage::PinkLog {
             block_number: context.block_number,
@@ -604,6 +715,11 @@ impl OCalls for RuntimeHandle<'_> {
     fn worker_sgx_quote(&self) -> Option<SgxQuote> {
         context::with(|ctx| ctx.worker_sgx_quote())
     }
+
+    fn secret_sealing_key(&self, contract: AccountId) -> Option<[u8; 32]> {
+        let root = self.cluster.config.secret_sealing_root?;
+        Some(contract_sealing_key(&root, &contract))
+    }
 }
 
 pub fn load_module(code_hash: &Hash, init: impl FnOnce() -> Option<Vec<u8>>) -> Result<WasmModule> {
@@ -642,6 +758,7 @@ impl OCalls for RuntimeHandleMut<'_> {
     }
 
     fn storage_commit(&mut self, root: Hash, changes: StorageChanges) {
+        crate::dispatch_audit::record(|| AuditOp::StorageCommit { root: root.0 });
         self.cluster.storage.commit(root, changes);
     }
 
@@ -740,6 +857,10 @@ impl OCalls for RuntimeHandleMut<'_> {
     fn worker_sgx_quote(&self) -> Option<SgxQuote> {
         self.readonly().worker_sgx_quote()
     }
+
+    fn secret_sealing_key(&self, contract: AccountId) -> Option<[u8; 32]> {
+        self.readonly().secret_sealing_key(contract)
+    }
 }
 
 impl v1::CrossCall for RuntimeHandle<'_> {
@@ -770,19 +891,21 @@ impl Cluster {
         cluster_key: &sr25519::Pair,
         runtime_version: (u32, u32),
     ) -> Self {
-        let secret_key = cluster_key.dump_secret_key();
-        let secret_salt = blake2_256(&secret_key);
+        let secret_salt = blake2_256(&cluster_key.dump_secret_key());
+        let (storage_key, sealing_root) = cluster_secrets(cluster_key);
         let mut cluster = Cluster {
             id: *id,
             storage: Default::default(),
             config: ClusterConfig {
                 runtime_version,
                 secret_salt,
+                secret_sealing_root: Some(sealing_root),
                 ..Default::default()
             },
+            block_gas_consumed: 0,
         };
         let mut runtime = cluster.default_runtime_mut();
-        runtime.set_key(secret_key);
+        runtime.set_key(storage_key.dump_secret_key());
         cluster
     }
 
@@ -801,6 +924,17 @@ impl Cluster {
         }
     }
 
+    /// Returns the gas consumed by the transactions since the last call, i.e. in the block being
+    /// dispatched when called at the end of each block.
+    pub(crate) fn take_block_gas_consumed(&mut self) -> u64 {
+        std::mem::take(&mut self.block_gas_consumed)
+    }
+
+    /// The gas consumed by the transactions of the block being dispatched so far.
+    pub(crate) fn block_gas_consumed(&self) -> u64 {
+        self.block_gas_consumed
+    }
+
     pub fn runtime_mut(&mut self, logger: Option<CommandSender>) -> RuntimeHandleMut {
         RuntimeHandleMut {
             cluster: self,
@@ -831,6 +965,14 @@ impl Cluster {
         resource_type: ResourceType,
         resource_data: Vec<u8>,
     ) -> Result<Hash, String> {
+        let checked = match resource_type {
+            ResourceType::InkCode => contracts::code_check::check_ink_code(&resource_data, true),
+            ResourceType::SidevmCode => contracts::code_check::check_sidevm_code(&resource_data),
+            ResourceType::IndeterministicInkCode => {
+                contracts::code_check::check_ink_code(&resource_data, false)
+            }
+        };
+        checked.map_err(|err| format!("CodeRejected: {err}"))?;
         match resource_type {
             ResourceType::InkCode => {
                 self.default_runtime_mut()
@@ -874,13 +1016,10 @@ impl Cluster {
                 estimating,
             } => {
                 let origin = origin.cloned().ok_or(QueryError::BadOrigin)?;
-                let _guard = context
-                    .query_scheduler
-                    .acquire(contract_id.clone(), context.weight)
-                    .await
-                    .or(Err(QueryError::ServiceUnavailable))?;
+                let _guard = context.acquire_slot(contract_id).await?;
 
-                if let Some(logger) = &context.log_handler {
+                let forward_logs = self.config.log_policy.forwards(&contract_id.convert_to());
+                if let Some(logger) = context.log_handler.as_ref().filter(|_| forward_logs) {
                     let fp = twox_64(&(&origin, &self.config.secret_salt).encode());
                     if let Err(_err) = logger.try_send(SidevmCommand::PushSystemMessage(
                         SystemMessage::Metric(Metric::PinkQueryIn(fp)),
@@ -971,11 +1110,7 @@ impl Cluster {
                 deposit,
                 transfer,
             } => {
-                let _guard = context
-                    .query_scheduler
-                    .acquire(contract_id.clone(), context.weight)
-                    .await
-                    .or(Err(QueryError::ServiceUnavailable))?;
+                let _guard = context.acquire_slot(contract_id).await?;
 
                 let origin = origin.cloned().ok_or(QueryError::BadOrigin)?;
                 let mut ctx = context::ContractExecContext::new(
@@ -1028,6 +1163,11 @@ impl Cluster {
                 gas_limit,
                 storage_deposit_limit,
             } => {
+                crate::dispatch_audit::record(|| AuditOp::ContractCall {
+                    contract: *contract_id.as_ref(),
+                    origin: origin.clone(),
+                    message_hash: blake2_256(&message),
+                });
                 let mut gas_free = false;
                 let origin: runtime::AccountId = match origin {
                     MessageOrigin::AccountId(origin) => origin.0.into(),
@@ -1041,6 +1181,8 @@ impl Cluster {
                     _ => return Err(TransactionError::BadOrigin),
                 };
 
+                let deadline = context.block.storage.cluster_execution_deadline(&self.id);
+                let gas_limit = execution_gas_limit(gas_limit, deadline);
                 let args = TransactionArguments {
                     origin: origin.clone(),
                     transfer,
@@ -1051,6 +1193,7 @@ impl Cluster {
                 };
 
                 let selector = head4(&message);
+                let log_policy = self.config.log_policy.clone();
                 let mut runtime = self.runtime_mut(context.log_handler.clone());
                 let output = context::using_call_nonce(nonce.clone().into(), || {
                     runtime.call(
@@ -1060,9 +1203,16 @@ impl Cluster {
                         args,
                     )
                 });
+                if deadline_exceeded(&output, gas_limit, deadline) {
+                    context.aborted_at = deadline;
+                }
 
-                if let Some(log_handler) = &context.log_handler {
-                    let mut output = output;
+                let log_handler = context
+                    .log_handler
+                    .as_ref()
+                    .filter(|_| log_policy.forwards(&contract_id.convert_to()));
+                if let Some(log_handler) = log_handler {
+                    let mut output = log_policy.redact(output);
                     output.extend_from_slice(&selector);
                     let msg = SidevmCommand::PushSystemMessage(SystemMessage::PinkMessageOutput {
                         origin: origin.into(),
@@ -1089,6 +1239,10 @@ impl Cluster {
         mode: ExecutionMode,
         tx_args: TransactionArguments,
     ) -> (Vec<u8>, Option<ExecSideEffects>) {
+        crate::dispatch_audit::record(|| AuditOp::ContractInstantiate {
+            code_hash: code_hash.0,
+            salt: salt.clone(),
+        });
         let mut runtime = self.runtime_mut(logger);
         let result = context::using_call_nonce(salt.clone(), || {
             runtime.instantiate(code_hash, instantiate_data, salt, mode, tx_args)
@@ -1115,6 +1269,58 @@ impl Cluster {
         self.config.runtime_version = version;
         self.default_runtime_mut().on_runtime_upgrade();
         info!("Runtime upgraded to {version:?}");
+        if self.config.extension_policy != ExtensionPolicy::default() {
+            // The policy might have been set before the runtime was able to enforce it.
+            self.set_extension_policy(self.config.extension_policy);
+        }
+    }
+
+    /// Replaces the chain extensions disabled in the cluster.
+    pub(crate) fn set_extension_policy(&mut self, policy: ExtensionPolicy) {
+        self.config.extension_policy = policy;
+        let version = self.config.runtime_version;
+        if !ECallsAvailable::set_extension_policy(version) {
+            warn!("The extension policy is not enforced by runtime {version:?}");
+            return;
+        }
+        self.default_runtime_mut()
+            .set_extension_policy(pink_loader::types::ExtensionPolicy {
+                disable_http: policy.disable_http,
+                disable_randomness: policy.disable_randomness,
+                disable_secrets: policy.disable_secrets,
+            });
+    }
+
+    /// Swaps the code of the contract, calling the `on_upgrade` message of the new code to
+    /// migrate the storage if `call_on_upgrade` is set.
+    pub(crate) fn upgrade_contract(
+        &mut self,
+        logger: Option<CommandSender>,
+        contract: AccountId,
+        code_hash: Hash,
+        call_on_upgrade: bool,
+        tx_args: TransactionArguments,
+    ) -> (Result<(), String>, Option<ExecSideEffects>) {
+        let version = self.config.runtime_version;
+        if !ECallsAvailable::contract_upgrade(version) {
+            return (
+                Err(format!(
+                    "Contract upgrade is not supported by runtime {version:?}"
+                )),
+                None,
+            );
+        }
+        let input_data = if call_on_upgrade {
+            head4(&blake2_256(b"on_upgrade")).to_vec()
+        } else {
+            vec![]
+        };
+        let origin = tx_args.origin.clone();
+        let mut runtime = self.runtime_mut(logger);
+        let result = context::using_entry(contract.clone(), origin, || {
+            runtime.contract_upgrade(contract, code_hash, input_data, tx_args)
+        });
+        (result, runtime.effects.take())
     }
 
     pub(crate) fn on_idle(&mut self, block_number: BlockNumber) {
@@ -1161,3 +1367,88 @@ impl ClusterContainer for Option<Cluster> {
         self.take()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract_result::StorageDeposit;
+    use phala_crypto::aead;
+    use sp_core::Pair;
+
+    fn output(gas_consumed: u64, result: Result<ExecReturnValue, DispatchError>) -> Vec<u8> {
+        let weight = Weight {
+            ref_time: gas_consumed,
+            proof_size: 0,
+        };
+        ContractResult {
+            gas_consumed: weight.clone(),
+            gas_required: weight,
+            storage_deposit: StorageDeposit::Charge(0),
+            debug_message: vec![],
+            result,
+        }
+        .encode()
+    }
+
+    fn module_error(index: u8, error: u8) -> DispatchError {
+        DispatchError::Module(ModuleError {
+            index,
+            error: [error, 0, 0, 0],
+            message: None,
+        })
+    }
+
+    #[test]
+    fn gas_limit_is_capped_only_with_a_deadline() {
+        assert_eq!(execution_gas_limit(1000, None), 1000);
+        assert_eq!(execution_gas_limit(1000, Some(100)), 100);
+        assert_eq!(execution_gas_limit(10, Some(100)), 10);
+    }
+
+    #[test]
+    fn only_running_out_of_gas_at_the_deadline_is_an_abort() {
+        let out_of_gas = output(100, Err(module_error(CONTRACTS_PALLET_INDEX, 2)));
+        assert!(deadline_exceeded(&out_of_gas, 100, Some(100)));
+        // Out of the gas limit given by the caller.
+        assert!(!deadline_exceeded(&out_of_gas, 50, Some(100)));
+        // No deadline set for the cluster.
+        assert!(!deadline_exceeded(&out_of_gas, 100, None));
+
+        // A trap after consuming all the gas is not an abort.
+        let trapped = output(100, Err(module_error(CONTRACTS_PALLET_INDEX, 12)));
+        assert!(!deadline_exceeded(&trapped, 100, Some(100)));
+        let disabled = output(100, Err(module_error(5, 2)));
+        assert!(!deadline_exceeded(&disabled, 100, Some(100)));
+    }
+
+    #[test]
+    fn cluster_storage_does_not_unseal_the_secrets() {
+        let cluster_key = sr25519::Pair::from_seed(&[1; 32]);
+        let (storage_key, sealing_root) = cluster_secrets(&cluster_key);
+        let contract = AccountId::new([2; 32]);
+        let sealing_key = contract_sealing_key(&sealing_root, &contract);
+        let iv = aead::generate_iv(b"nonce");
+        let mut sealed = b"secret".to_vec();
+        aead::encrypt(&iv, &sealing_key, &mut sealed).unwrap();
+
+        // What a dump of the cluster storage holds in `pallet_pink::Key`.
+        let dumped = storage_key.dump_secret_key();
+        assert_ne!(dumped, cluster_key.dump_secret_key());
+        let restored = sr25519::Pair::restore_from_secret_key(&dumped);
+        let candidates = [
+            // Derived as if the dumped key was the cluster key.
+            contract_sealing_key(&cluster_secrets(&restored).1, &contract),
+            // Derived from a salt hashed from the dumped key, as before the storage key was split.
+            contract_sealing_key(&blake2_256(&dumped), &contract),
+        ];
+        for key in candidates {
+            assert_ne!(key, sealing_key);
+            assert!(aead::decrypt(&iv, &key, &mut sealed.clone()).is_err());
+        }
+        let mut unsealed = sealed.clone();
+        assert_eq!(
+            aead::decrypt(&iv, &sealing_key, &mut unsealed).unwrap(),
+            &b"secret"[..]
+        );
+    }
+}
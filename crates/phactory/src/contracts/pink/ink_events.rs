@@ -0,0 +1,250 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use phala_types::contract::{ConvertTo, LogPolicy};
+use pink_loader::types::{AccountId, Hash};
+
+/// Max number of ink events kept in the in-enclave buffer for backfilling.
+const MAX_BUFFERED_EVENTS: usize = 4096;
+
+/// Max number of events returned by a single query.
+pub(crate) const MAX_EVENTS_PER_QUERY: usize = 512;
+
+/// An ink event emitted by a contract during command execution.
+#[derive(Debug, Clone)]
+pub struct InkEventRecord {
+    /// Monotonic sequence number assigned when the event was recorded.
+    pub sequence: u64,
+    /// The block number in which the command emitting the event was executed.
+    pub block_number: u32,
+    /// The contract emitting the event.
+    pub contract: AccountId,
+    /// The topics of the event.
+    pub topics: Vec<Hash>,
+    /// The SCALE encoded event payload.
+    pub payload: Vec<u8>,
+}
+
+/// A bounded ring buffer of recent ink events.
+///
+/// The buffer lives in memory only. It is not included in checkpoints, so events emitted before
+/// a restart can not be backfilled. The events are buffered as the log policy of their cluster
+/// forwards them, so that a query does not reveal what a contract chose to hide.
+#[derive(Default)]
+struct EventBuffer {
+    next_sequence: u64,
+    events: VecDeque<InkEventRecord>,
+}
+
+/// Filters for querying the buffered events.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    /// Only return events emitted by these contracts. Empty means any contract.
+    pub contracts: Vec<AccountId>,
+    /// Only return events containing at least one of these topics. Empty means any topic.
+    pub topics: Vec<Hash>,
+    /// Only return events with sequence number >= this value.
+    pub from_sequence: u64,
+    /// Max number of events to return.
+    pub limit: usize,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &InkEventRecord) -> bool {
+        if event.sequence < self.from_sequence {
+            return false;
+        }
+        if !self.contracts.is_empty() && !self.contracts.contains(&event.contract) {
+            return false;
+        }
+        if !self.topics.is_empty() && !event.topics.iter().any(|t| self.topics.contains(t)) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Result of an event query.
+#[derive(Debug, Default)]
+pub struct EventsPage {
+    /// The matched events in ascending sequence order.
+    pub events: Vec<InkEventRecord>,
+    /// The sequence number to pass as `from_sequence` to continue the subscription.
+    pub next_sequence: u64,
+    /// The sequence number of the oldest event still in the buffer.
+    pub oldest_sequence: u64,
+}
+
+impl EventBuffer {
+    fn push(
+        &mut self,
+        block_number: u32,
+        contract: AccountId,
+        topics: Vec<Hash>,
+        payload: Vec<u8>,
+    ) {
+        if self.events.len() >= MAX_BUFFERED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(InkEventRecord {
+            sequence: self.next_sequence,
+            block_number,
+            contract,
+            topics,
+            payload,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Pushes the event unless its contract opted out of the logs, redacting its payload if the
+    /// policy requires it.
+    fn push_visible(
+        &mut self,
+        block_number: u32,
+        contract: AccountId,
+        topics: Vec<Hash>,
+        payload: Vec<u8>,
+        policy: &LogPolicy,
+    ) {
+        if !policy.forwards(&contract.convert_to()) {
+            return;
+        }
+        self.push(block_number, contract, topics, policy.redact(payload));
+    }
+
+    fn oldest_sequence(&self) -> u64 {
+        self.events
+            .front()
+            .map(|e| e.sequence)
+            .unwrap_or(self.next_sequence)
+    }
+
+    fn query(&self, filter: &EventFilter) -> EventsPage {
+        let limit = filter.limit.clamp(1, MAX_EVENTS_PER_QUERY);
+        let mut events = Vec::new();
+        // When the page is full, the caller should continue from the first event that didn't fit.
+        let mut next_sequence = self.next_sequence;
+        for event in self.events.iter().filter(|e| filter.matches(e)) {
+            if events.len() == limit {
+                next_sequence = event.sequence;
+                break;
+            }
+            events.push(event.clone());
+        }
+        EventsPage {
+            events,
+            next_sequence,
+            oldest_sequence: self.oldest_sequence(),
+        }
+    }
+}
+
+static EVENT_BUFFER: once_cell::sync::OnceCell<Mutex<EventBuffer>> =
+    once_cell::sync::OnceCell::new();
+
+fn buffer() -> &'static Mutex<EventBuffer> {
+    EVENT_BUFFER.get_or_init(|| Mutex::new(EventBuffer::default()))
+}
+
+pub(crate) fn record(
+    block_number: u32,
+    contract: AccountId,
+    topics: Vec<Hash>,
+    payload: Vec<u8>,
+    policy: &LogPolicy,
+) {
+    buffer()
+        .lock()
+        .unwrap()
+        .push_visible(block_number, contract, topics, payload, policy);
+}
+
+pub(crate) fn query(filter: &EventFilter) -> EventsPage {
+    buffer().lock().unwrap().query(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(n: u8) -> AccountId {
+        AccountId::new([n; 32])
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let mut buffer = EventBuffer::default();
+        for i in 0..MAX_BUFFERED_EVENTS + 10 {
+            buffer.push(i as _, account(0), vec![], vec![]);
+        }
+        assert_eq!(buffer.events.len(), MAX_BUFFERED_EVENTS);
+        assert_eq!(buffer.oldest_sequence(), 10);
+    }
+
+    #[test]
+    fn opted_out_contracts_are_not_buffered() {
+        let mut buffer = EventBuffer::default();
+        let policy = LogPolicy {
+            opted_out: vec![account(1).convert_to()],
+            ..Default::default()
+        };
+        buffer.push_visible(1, account(1), vec![], b"secret".to_vec(), &policy);
+        buffer.push_visible(1, account(2), vec![], b"public".to_vec(), &policy);
+        let page = buffer.query(&EventFilter {
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].contract, account(2));
+        assert_eq!(page.events[0].payload, b"public");
+
+        let page = buffer.query(&EventFilter {
+            contracts: vec![account(1)],
+            limit: 10,
+            ..Default::default()
+        });
+        assert!(page.events.is_empty());
+    }
+
+    #[test]
+    fn payloads_are_redacted_by_policy() {
+        let mut buffer = EventBuffer::default();
+        let policy = LogPolicy {
+            redact_payloads: true,
+            ..Default::default()
+        };
+        buffer.push_visible(1, account(1), vec![], b"secret".to_vec(), &policy);
+        assert_eq!(
+            buffer.events[0].payload,
+            sp_core::blake2_256(b"secret").to_vec()
+        );
+    }
+
+    #[test]
+    fn query_filters_and_pages() {
+        let mut buffer = EventBuffer::default();
+        let topic = Hash::repeat_byte(1);
+        for i in 0..10u8 {
+            let topics = if i % 2 == 0 { vec![topic] } else { vec![] };
+            buffer.push(i as _, account(i % 3), topics, vec![i]);
+        }
+        let page = buffer.query(&EventFilter {
+            contracts: vec![account(0)],
+            topics: vec![topic],
+            from_sequence: 0,
+            limit: 1,
+        });
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].sequence, 0);
+        assert_eq!(page.next_sequence, 6);
+
+        let page = buffer.query(&EventFilter {
+            contracts: vec![account(0)],
+            topics: vec![topic],
+            from_sequence: page.next_sequence,
+            limit: 10,
+        });
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].sequence, 6);
+        assert_eq!(page.next_sequence, 10);
+    }
+}
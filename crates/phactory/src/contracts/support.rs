@@ -5,10 +5,11 @@ use pink::{chain_extension::JsValue, SidevmConfig};
 use pink_loader::types::{AccountId, ExecutionMode, TransactionArguments};
 use serde::{Deserialize, Serialize};
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
 
-use parity_scale_codec::Decode;
+use parity_scale_codec::{Decode, Encode};
 use phala_mq::SignedMessageChannel;
-use phala_scheduler::RequestScheduler;
+use phala_scheduler::{AcquireError, RequestScheduler, ServingGuard};
 use runtime::BlockNumber;
 use sidevm::{
     service::{Command as SidevmCommand, CommandSender, ExitReason},
@@ -18,14 +19,16 @@ use sidevm::{
 use super::pink::Cluster;
 use crate::{
     hex,
+    memory_budget::{self, Pressure},
     secret_channel::{KeyPair, SecretMessageChannel, SecretReceiver},
     system::{TransactionError, TransactionResult, WorkerIdentityKey},
     types::BlockInfo,
     ChainStorage, H256,
 };
+use phactory_api::contracts::QueryError;
 use phactory_api::prpc as pb;
 use tokio::sync::watch::Receiver as WatchReceiver;
-use tracing::{error, info, instrument, Instrument};
+use tracing::{debug, error, info, instrument, warn, Instrument};
 
 pub struct ExecuteEnv<'a, 'b> {
     pub block: &'a mut BlockInfo<'b>,
@@ -38,6 +41,9 @@ pub struct TransactionContext<'a, 'b> {
     pub mq: &'a SignedMessageChannel,
     pub secret_mq: SecretMessageChannel<'a, SignedMessageChannel>,
     pub log_handler: Option<CommandSender>,
+    /// The execution deadline of the cluster if the execution was aborted at it, see
+    /// [`super::pink::deadline_exceeded`].
+    pub aborted_at: Option<u64>,
 }
 
 pub struct QueryContext {
@@ -52,6 +58,26 @@ pub struct QueryContext {
     pub req_id: u64,
     pub sidevm_event_tx: OutgoingRequestChannel,
     pub attestation_provider: Option<AttestationProvider>,
+    /// The query is rejected if it is not estimated to start before the deadline.
+    pub deadline: Option<Instant>,
+}
+
+impl QueryContext {
+    /// Acquires a slot from the query scheduler to run a query of the given contract.
+    pub async fn acquire_slot(
+        &self,
+        contract_id: &AccountId,
+    ) -> Result<ServingGuard<AccountId>, QueryError> {
+        self.query_scheduler
+            .acquire_before(contract_id.clone(), self.weight, self.deadline)
+            .await
+            .map_err(|err| match err {
+                AcquireError::WouldMissDeadline { retry_after } => QueryError::Overloaded {
+                    retry_after_ms: retry_after.as_millis() as u64,
+                },
+                _ => QueryError::ServiceUnavailable,
+            })
+    }
 }
 
 pub(crate) struct RawData(Vec<u8>);
@@ -150,6 +176,14 @@ pub struct Contract {
     pub sidevm_info: Option<SidevmInfo>,
     weight: u32,
     on_block_end: Option<OnBlockEnd>,
+    /// The latest block at which the contract handled a command.
+    #[serde(default)]
+    last_active_block: BlockNumber,
+    /// The latest execution aborted at the deadline.
+    #[serde(default)]
+    last_abort: Option<ExecutionAbort>,
+    #[serde(default)]
+    on_idle: Option<OnIdle>,
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, ::scale_info::TypeInfo)]
@@ -158,6 +192,24 @@ struct OnBlockEnd {
     gas_limit: u64,
 }
 
+#[derive(Copy, Clone, Serialize, Deserialize, ::scale_info::TypeInfo)]
+struct OnIdle {
+    selector: u32,
+    gas_limit: u64,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, ::scale_info::TypeInfo)]
+pub struct ExecutionAbort {
+    pub block_number: BlockNumber,
+    pub gas_limit: u64,
+}
+
+/// Executions taking longer than this are reported. They are not aborted by the wall clock, which
+/// differs between workers, but by the gas deadline.
+const SLOW_EXECUTION_THRESHOLD: Duration = Duration::from_secs(2);
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
 impl Contract {
     pub(crate) fn new(
         send_mq: SignedMessageChannel,
@@ -175,6 +227,9 @@ impl Contract {
             sidevm_info: None,
             weight: 0,
             on_block_end: None,
+            last_active_block: 0,
+            last_abort: None,
+            on_idle: None,
         }
     }
 
@@ -198,17 +253,24 @@ impl Contract {
             mq: &self.send_mq,
             secret_mq,
             log_handler: env.log_handler.clone(),
+            aborted_at: None,
         };
 
         phala_mq::select! {
             next_cmd = self.cmd_rcv_mq => match next_cmd {
                 Ok((_, cmd, origin)) => {
                     info!("Contract {:?} handling tx call", self.address());
+                    self.last_active_block = context.block.block_number;
                     let Ok(command) = Decode::decode(&mut &cmd.0[..]) else {
                         error!("Failed to decode tx input");
                         return Some(Err(TransactionError::BadInput));
                     };
-                    env.contract_cluster.handle_command(self.address(), origin, command, &mut context)
+                    let started = std::time::Instant::now();
+                    let result = env.contract_cluster.handle_command(&self.address, origin, command, &mut context);
+                    let aborted_at = context.aborted_at;
+                    let block_number = context.block.block_number;
+                    self.check_execution(started, aborted_at, block_number);
+                    result
                 }
                 Err(_e) => {
                     Err(TransactionError::ChannelError)
@@ -227,22 +289,106 @@ impl Contract {
         };
 
         let input_data = selector.to_be_bytes();
+        let deadline = env
+            .block
+            .storage
+            .cluster_execution_deadline(&env.contract_cluster.id);
         let tx_args = TransactionArguments {
             origin: self.address.clone(),
             transfer: 0,
             gas_free: false,
             storage_deposit_limit: None,
-            gas_limit,
+            gas_limit: super::pink::execution_gas_limit(gas_limit, deadline),
             deposit: 0,
         };
+        let gas_limit = tx_args.gas_limit;
+        let started = std::time::Instant::now();
         let mut handle = env.contract_cluster.runtime_mut(env.log_handler.clone());
-        _ = handle.call(
+        let output = handle.call(
             self.address().clone(),
             input_data.to_vec(),
             ExecutionMode::Transaction,
             tx_args,
         );
-        Ok(handle.effects)
+        let effects = handle.effects;
+        let aborted_at =
+            deadline.filter(|_| super::pink::deadline_exceeded(&output, gas_limit, deadline));
+        self.check_execution(started, aborted_at, env.block.block_number);
+        Ok(effects)
+    }
+
+    pub(crate) fn has_on_idle(&self) -> bool {
+        self.on_idle.is_some()
+    }
+
+    /// Calls the `OnIdle` hook of the contract with the gas left in the idle budget of the block,
+    /// which also caps the gas limit of the call.
+    pub(crate) fn on_idle(
+        &mut self,
+        env: &mut ExecuteEnv,
+        remaining_gas: u64,
+    ) -> TransactionResult {
+        let Some(OnIdle {
+            selector,
+            gas_limit,
+        }) = self.on_idle
+        else {
+            return Ok(None);
+        };
+
+        let mut input_data = selector.to_be_bytes().to_vec();
+        remaining_gas.encode_to(&mut input_data);
+        let deadline = env
+            .block
+            .storage
+            .cluster_execution_deadline(&env.contract_cluster.id);
+        let tx_args = TransactionArguments {
+            origin: self.address.clone(),
+            transfer: 0,
+            gas_free: false,
+            storage_deposit_limit: None,
+            gas_limit: super::pink::execution_gas_limit(gas_limit.min(remaining_gas), deadline),
+            deposit: 0,
+        };
+        let gas_limit = tx_args.gas_limit;
+        let started = std::time::Instant::now();
+        let mut handle = env.contract_cluster.runtime_mut(env.log_handler.clone());
+        let output = handle.call(
+            self.address().clone(),
+            input_data,
+            ExecutionMode::Transaction,
+            tx_args,
+        );
+        let effects = handle.effects;
+        let aborted_at =
+            deadline.filter(|_| super::pink::deadline_exceeded(&output, gas_limit, deadline));
+        self.check_execution(started, aborted_at, env.block.block_number);
+        Ok(effects)
+    }
+
+    fn check_execution(
+        &mut self,
+        started: std::time::Instant,
+        aborted_at: Option<u64>,
+        block_number: BlockNumber,
+    ) {
+        let elapsed = started.elapsed();
+        if elapsed > SLOW_EXECUTION_THRESHOLD {
+            warn!(
+                "Contract {:?} took {elapsed:?} to execute at block {block_number}",
+                self.address
+            );
+        }
+        if let Some(gas_limit) = aborted_at {
+            error!(
+                "Contract {:?} execution aborted at the deadline at block {block_number}",
+                self.address
+            );
+            self.last_abort = Some(ExecutionAbort {
+                block_number,
+                gas_limit,
+            });
+        }
     }
 
     pub(crate) fn set_on_block_end_selector(&mut self, selector: u32, gas_limit: u64) {
@@ -252,6 +398,13 @@ impl Contract {
         });
     }
 
+    pub(crate) fn set_on_idle_selector(&mut self, selector: u32, gas_limit: u64) {
+        self.on_idle = Some(OnIdle {
+            selector,
+            gas_limit,
+        });
+    }
+
     pub(crate) fn start_sidevm(
         &mut self,
         spawner: &sidevm::service::Spawner,
@@ -333,6 +486,29 @@ impl Contract {
         Ok(())
     }
 
+    /// Swaps the sidevm program to another code, keeping the config of the running one.
+    ///
+    /// The previous instance is asked to stop and the new one starts after it exits. The local
+    /// cache is keyed by the contract address, so it survives the swap.
+    pub(crate) fn update_sidevm_code(
+        &mut self,
+        spawner: &sidevm::service::Spawner,
+        code: SidevmCode,
+    ) -> Result<()> {
+        let Some(info) = &self.sidevm_info else {
+            bail!("No sidevm to update");
+        };
+        let code_hash: H256 = match &code {
+            SidevmCode::Hash(hash) => *hash,
+            SidevmCode::Code(code) => sp_core::blake2_256(code).into(),
+        };
+        if code_hash == info.code_hash {
+            bail!("Sidevm is running code {code_hash:?} already");
+        }
+        let config = info.config.clone();
+        self.start_sidevm(spawner, code, false, config)
+    }
+
     pub(crate) fn restart_sidevm_if_needed(
         &mut self,
         spawner: &sidevm::service::Spawner,
@@ -359,6 +535,11 @@ impl Contract {
                 if !need_restart {
                     return Ok(());
                 }
+                if memory_budget::pressure() >= Pressure::PauseSidevmRestarts {
+                    let id = sidevm::ShortId(&self.address);
+                    debug!(target: "sidevm", id=%id, "Restart paused under memory pressure");
+                    return Ok(());
+                }
                 sidevm_info.start_time = chrono::Utc::now().to_rfc3339();
                 let handle = do_start_sidevm(
                     spawner,
@@ -381,6 +562,16 @@ impl Contract {
         Ok(())
     }
 
+    /// The linear memory the running sidevm instance may grow to, 0 if not running.
+    pub(crate) fn sidevm_reserved_memory(&self) -> u64 {
+        match (&self.sidevm_info, self.sidevm_handle()) {
+            (Some(info), Some(SidevmHandle::Running { .. })) => {
+                info.config.max_memory_pages as u64 * WASM_PAGE_SIZE
+            }
+            _ => 0,
+        }
+    }
+
     pub(crate) fn push_message_to_sidevm(&self, message: SidevmCommand) -> Result<()> {
         let handle = self
             .sidevm_info
@@ -460,6 +651,30 @@ impl Contract {
         self.weight
     }
 
+    pub fn last_active_block(&self) -> BlockNumber {
+        self.last_active_block
+    }
+
+    pub fn summary(&self, cluster: &Cluster) -> pb::ContractSummary {
+        let sidevm_state = match self.sidevm_handle() {
+            None => "",
+            Some(SidevmHandle::Running { .. }) => "running",
+            Some(SidevmHandle::Stopped(_)) => "stopped",
+        };
+        pb::ContractSummary {
+            address: hex(&self.address),
+            cluster: hex(self.cluster_id),
+            code_hash: cluster
+                .code_hash(&self.address)
+                .map(hex)
+                .unwrap_or_default(),
+            weight: self.weight,
+            sidevm_state: sidevm_state.into(),
+            last_active_block: self.last_active_block,
+            last_abort_block: self.last_abort.map(|abort| abort.block_number),
+        }
+    }
+
     pub fn info(&self, cluster: &Cluster) -> pb::ContractInfo {
         pb::ContractInfo {
             id: hex(&self.address),
@@ -620,7 +835,8 @@ pub fn block_on_run_module(
                 event_tx: &OutgoingRequestChannel,
             ) -> bool {
                 match event {
-                    event @ sidevm::OutgoingRequest::Query { .. } => {
+                    event @ (sidevm::OutgoingRequest::Query { .. }
+                    | sidevm::OutgoingRequest::ChainStorage { .. }) => {
                         match event_tx.send((vmid, event)).await {
                             Ok(_) => false,
                             Err(err) => {
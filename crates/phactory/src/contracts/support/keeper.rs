@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use sidevm::service::Spawner;
+use std::collections::BTreeMap;
 
 use pink_loader::{
     local_cache,
@@ -69,6 +70,29 @@ impl ContractsKeeper {
         self.contracts.iter().map(|(k, v)| (k, &**v))
     }
 
+    /// The distinct codes of the sidevm instances, running or not.
+    pub fn sidevm_codes(&self) -> Vec<Vec<u8>> {
+        let mut codes = BTreeMap::new();
+        for contract in self.contracts.values() {
+            if let Some(info) = &contract.sidevm_info {
+                if !info.code.is_empty() {
+                    codes
+                        .entry(info.code_hash)
+                        .or_insert_with(|| info.code.clone());
+                }
+            }
+        }
+        codes.into_values().collect()
+    }
+
+    /// The linear memory the running sidevm instances may grow to in total.
+    pub fn sidevm_reserved_memory(&self) -> u64 {
+        self.contracts
+            .values()
+            .map(|contract| contract.sidevm_reserved_memory())
+            .sum()
+    }
+
     pub fn apply_local_cache_quotas(&self) {
         local_cache::apply_quotas(calc_cache_quotas(&self.contracts));
     }
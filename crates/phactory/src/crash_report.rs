@@ -0,0 +1,160 @@
+//! Crash reports of pRuntime.
+//!
+//! pRuntime is built with `panic = "abort"`, so a panic takes the whole process down without any
+//! chance to save the state. The panic hook installed by [`install_panic_hook`] tries to take an
+//! emergency checkpoint if the state is at a block boundary, and writes a report of the crash to
+//! the storage directory. The report is picked up by the next run and served in `GetInfo`.
+
+use crate::Phactory;
+use phactory_api::prpc as pb;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, TryLockError, Weak,
+    },
+    time::SystemTime,
+};
+
+const CRASH_REPORT_FILE: &str = "crash_report.json";
+/// The report of the previous run is moved here once loaded, so that it is served only once.
+const LAST_CRASH_REPORT_FILE: &str = "crash_report.last.json";
+
+struct Progress {
+    block_number: chain::BlockNumber,
+    mq_sequences: HashMap<String, u64>,
+}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+static LAST_CRASH: Mutex<Option<pb::CrashReport>> = Mutex::new(None);
+
+/// Records the progress of the block dispatching, to be included in the crash report.
+pub(crate) fn record_progress(
+    block_number: chain::BlockNumber,
+    mq_sequences: impl IntoIterator<Item = (phala_mq::SenderId, u64)>,
+) {
+    let progress = Progress {
+        block_number,
+        mq_sequences: mq_sequences
+            .into_iter()
+            .map(|(sender, seq)| (sender.to_string(), seq))
+            .collect(),
+    };
+    *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(progress);
+}
+
+/// The crash report left by the previous run, if any.
+pub(crate) fn last_crash() -> Option<pb::CrashReport> {
+    LAST_CRASH.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Loads the crash report of the previous run and installs the panic hook writing the report of
+/// this run.
+pub fn install_panic_hook<P>(phactory: Weak<Mutex<Phactory<P>>>, platform: P, storage_path: &str)
+where
+    P: pal::Platform + Serialize + DeserializeOwned,
+{
+    let storage_path = PathBuf::from(storage_path);
+    match load_last_crash(&storage_path) {
+        Ok(Some(report)) => {
+            error!(
+                "pRuntime crashed in the last run at {}: {}",
+                report.location, report.message
+            );
+            *LAST_CRASH.lock().unwrap_or_else(|e| e.into_inner()) = Some(report);
+        }
+        Ok(None) => {}
+        Err(err) => error!("Failed to load the crash report: {err:?}"),
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        static PANICKING: AtomicBool = AtomicBool::new(false);
+        default_hook(info);
+        if PANICKING.swap(true, Ordering::SeqCst) {
+            // Either another thread is writing the report or the hook itself panicked.
+            return;
+        }
+        let mut report = pb::CrashReport {
+            message: panic_message(info),
+            location: info
+                .location()
+                .map(|loc| loc.to_string())
+                .unwrap_or_default(),
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            memory_usage: Some(platform.memory_usage()),
+            ..Default::default()
+        };
+        if let Ok(Some(progress)) = PROGRESS.try_lock().as_deref() {
+            report.last_block = Some(progress.block_number);
+            report.mq_sequences = progress.mq_sequences.clone();
+        }
+        match emergency_checkpoint(&phactory) {
+            Ok(file) => report.emergency_checkpoint = Some(file),
+            Err(err) => report.checkpoint_error = Some(format!("{err:?}")),
+        }
+        if let Err(err) = write_report(&storage_path, &report) {
+            error!("Failed to write the crash report: {err:?}");
+        }
+    }));
+}
+
+fn panic_message(info: &std::panic::PanicInfo) -> String {
+    let payload = info.payload();
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "<non-string panic payload>".into()
+    }
+}
+
+fn emergency_checkpoint<P>(phactory: &Weak<Mutex<Phactory<P>>>) -> anyhow::Result<String>
+where
+    P: pal::Platform + Serialize + DeserializeOwned,
+{
+    let phactory = phactory
+        .upgrade()
+        .ok_or_else(|| anyhow::anyhow!("Phactory is gone"))?;
+    // The panicking thread may be holding the lock in the middle of a block, in which case the
+    // state is not consistent and must not be saved. When the blocks are dispatched in the RCU
+    // way, the singleton phactory stays at the last block boundary and can be saved.
+    let mut phactory = match phactory.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::WouldBlock) => {
+            anyhow::bail!("Phactory is locked, state may be inconsistent")
+        }
+        Err(TryLockError::Poisoned(_)) => anyhow::bail!("Phactory lock poisoned"),
+    };
+    if !phactory.args.enable_checkpoint {
+        anyhow::bail!("Checkpoint is disabled");
+    }
+    let block = phactory.take_checkpoint()?;
+    Ok(crate::checkpoint_filename_for(
+        block,
+        &phactory.args.storage_path,
+    ))
+}
+
+fn write_report(storage_path: &Path, report: &pb::CrashReport) -> anyhow::Result<()> {
+    let content = serde_json::to_string_pretty(report)?;
+    std::fs::write(storage_path.join(CRASH_REPORT_FILE), content)?;
+    Ok(())
+}
+
+fn load_last_crash(storage_path: &Path) -> anyhow::Result<Option<pb::CrashReport>> {
+    let filename = storage_path.join(CRASH_REPORT_FILE);
+    let content = match std::fs::read(&filename) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    std::fs::rename(&filename, storage_path.join(LAST_CRASH_REPORT_FILE))?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
@@ -0,0 +1,258 @@
+//! Audit trail of the dispatch of the blocks.
+//!
+//! The dispatch of a block must be deterministic: two workers fed with the same blocks must end up
+//! in the same state. When they don't, the diverging state tells little about where it started.
+//! With `--audit-dispatch`, every state-mutating operation of the dispatch is chained into a
+//! digest, starting from `blake2_256(block_number)`:
+//!
+//! ```text
+//! digest = blake2_256((digest, op).encode())
+//! ```
+//!
+//! The operations are the inbound messages dispatched, the messages enqueued to the egress, the
+//! transactions and instantiations of the contracts and the storage commits of the cluster. The
+//! trails of the latest [`AUDIT_BLOCKS`] blocks are returned by the `GetDispatchAudit` RPC: the
+//! digests of the blocks tell where two workers diverged, and the digest after each operation of
+//! that block tells the first diverging operation.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use chain::BlockNumber;
+use parity_scale_codec::Encode;
+use phala_mq::{MessageOrigin, SendObserver, SignedMessage};
+use sp_core::blake2_256;
+
+/// The number of latest blocks whose trails are kept.
+pub const AUDIT_BLOCKS: usize = 256;
+
+#[derive(Encode, Debug, Clone, PartialEq, Eq)]
+pub enum AuditOp {
+    /// A message from the chain dispatched to the subscribers.
+    Inbound {
+        sender: MessageOrigin,
+        destination: Vec<u8>,
+        sequence: u64,
+        payload_hash: [u8; 32],
+    },
+    /// A message enqueued to the egress. The signature is left out, it is not deterministic.
+    Egress {
+        sender: MessageOrigin,
+        destination: Vec<u8>,
+        sequence: u64,
+        payload_hash: [u8; 32],
+    },
+    /// A transaction to a contract.
+    ContractCall {
+        contract: [u8; 32],
+        origin: MessageOrigin,
+        message_hash: [u8; 32],
+    },
+    ContractInstantiate {
+        code_hash: [u8; 32],
+        salt: Vec<u8>,
+    },
+    /// The storage changes of the contract cluster, by the resulting root.
+    StorageCommit {
+        root: [u8; 32],
+    },
+}
+
+impl AuditOp {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditOp::Inbound { .. } => "inbound",
+            AuditOp::Egress { .. } => "egress",
+            AuditOp::ContractCall { .. } => "contract_call",
+            AuditOp::ContractInstantiate { .. } => "contract_instantiate",
+            AuditOp::StorageCommit { .. } => "storage_commit",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            AuditOp::Inbound {
+                sender,
+                destination,
+                sequence,
+                payload_hash,
+            }
+            | AuditOp::Egress {
+                sender,
+                destination,
+                sequence,
+                payload_hash,
+            } => format!(
+                "sender={sender}, destination={}, sequence={sequence}, payload=0x{}",
+                String::from_utf8_lossy(destination),
+                hex::encode(payload_hash)
+            ),
+            AuditOp::ContractCall {
+                contract,
+                origin,
+                message_hash,
+            } => format!(
+                "contract=0x{}, origin={origin}, message=0x{}",
+                hex::encode(contract),
+                hex::encode(message_hash)
+            ),
+            AuditOp::ContractInstantiate { code_hash, salt } => format!(
+                "code_hash=0x{}, salt=0x{}",
+                hex::encode(code_hash),
+                hex::encode(salt)
+            ),
+            AuditOp::StorageCommit { root } => format!("root=0x{}", hex::encode(root)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditedOp {
+    pub op: AuditOp,
+    /// The digest after the operation.
+    pub digest: [u8; 32],
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockTrail {
+    pub block_number: BlockNumber,
+    /// The digest after the last operation.
+    pub digest: [u8; 32],
+    pub ops: Vec<AuditedOp>,
+}
+
+impl BlockTrail {
+    fn new(block_number: BlockNumber) -> Self {
+        Self {
+            block_number,
+            digest: blake2_256(&block_number.encode()),
+            ops: vec![],
+        }
+    }
+
+    fn record(&mut self, op: AuditOp) {
+        self.digest = blake2_256(&(self.digest, &op).encode());
+        self.ops.push(AuditedOp {
+            op,
+            digest: self.digest,
+        });
+    }
+}
+
+environmental::environmental!(current_trail: BlockTrail);
+
+/// Records the operation in the trail of the block being dispatched, if it is audited.
+pub fn record(op: impl FnOnce() -> AuditOp) {
+    current_trail::with(|trail| trail.record(op()));
+}
+
+/// The trails of the latest audited blocks.
+#[derive(Clone, Default)]
+pub struct DispatchAudit {
+    trails: Arc<Mutex<VecDeque<BlockTrail>>>,
+}
+
+impl DispatchAudit {
+    /// Runs the dispatch of the block, recording its trail.
+    pub fn audit<R>(&self, block_number: BlockNumber, dispatch: impl FnOnce() -> R) -> R {
+        let mut trail = BlockTrail::new(block_number);
+        let ret = current_trail::using(&mut trail, dispatch);
+        let mut trails = self.trails.lock().unwrap();
+        // The blocks dispatched again after restoring a checkpoint replace the previous trails.
+        while trails
+            .back()
+            .map_or(false, |last| last.block_number >= block_number)
+        {
+            trails.pop_back();
+        }
+        if trails.len() >= AUDIT_BLOCKS {
+            trails.pop_front();
+        }
+        trails.push_back(trail);
+        ret
+    }
+
+    /// The trail of the given block, or of the latest audited one.
+    pub fn get(&self, block_number: Option<BlockNumber>) -> Option<BlockTrail> {
+        let trails = self.trails.lock().unwrap();
+        match block_number {
+            Some(number) => trails.iter().find(|t| t.block_number == number).cloned(),
+            None => trails.back().cloned(),
+        }
+    }
+}
+
+/// Records the messages enqueued to the egress.
+pub struct AuditEgress;
+
+impl SendObserver for AuditEgress {
+    fn on_enqueue(&self, message: &SignedMessage) {
+        record(|| AuditOp::Egress {
+            sender: message.message.sender.clone(),
+            destination: message.message.destination.path().clone(),
+            sequence: message.sequence,
+            payload_hash: blake2_256(&message.message.payload),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(root: u8) -> AuditOp {
+        AuditOp::StorageCommit { root: [root; 32] }
+    }
+
+    #[test]
+    fn records_only_in_audited_dispatch() {
+        let audit = DispatchAudit::default();
+        record(|| commit(0));
+        audit.audit(1, || {
+            record(|| commit(1));
+            record(|| commit(2));
+        });
+        let trail = audit.get(None).unwrap();
+        assert_eq!(trail.block_number, 1);
+        assert_eq!(trail.ops.len(), 2);
+        assert_eq!(trail.digest, trail.ops[1].digest);
+        assert!(audit.get(Some(2)).is_none());
+    }
+
+    #[test]
+    fn trails_diverge_at_the_first_different_op() {
+        let audit_a = DispatchAudit::default();
+        let audit_b = DispatchAudit::default();
+        audit_a.audit(1, || {
+            record(|| commit(1));
+            record(|| commit(2));
+            record(|| commit(3));
+        });
+        audit_b.audit(1, || {
+            record(|| commit(1));
+            record(|| commit(4));
+            record(|| commit(3));
+        });
+        let a = audit_a.get(Some(1)).unwrap();
+        let b = audit_b.get(Some(1)).unwrap();
+        assert_ne!(a.digest, b.digest);
+        let first_diverged = a
+            .ops
+            .iter()
+            .zip(&b.ops)
+            .position(|(a, b)| a.digest != b.digest);
+        assert_eq!(first_diverged, Some(1));
+    }
+
+    #[test]
+    fn replaces_the_trails_of_redispatched_blocks() {
+        let audit = DispatchAudit::default();
+        for number in 1..=3 {
+            audit.audit(number, || record(|| commit(number as u8)));
+        }
+        audit.audit(2, || {});
+        assert!(audit.get(Some(3)).is_none());
+        assert!(audit.get(Some(2)).unwrap().ops.is_empty());
+        assert_eq!(audit.get(Some(1)).unwrap().ops.len(), 1);
+    }
+}
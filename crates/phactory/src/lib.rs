@@ -29,10 +29,10 @@ use crate::{
 };
 use phactory_api::contracts::QueryError;
 use phala_types::{contract::ContractQueryError, DcapHandoverChallenge};
-use sidevm_env::messages::{QueryError as SidevmQueryError, QueryResponse};
+use sidevm_env::messages::{ChainStorageResponse, QueryError as SidevmQueryError, QueryResponse};
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     future::Future,
     str::FromStr,
     sync::{Arc, Mutex, Weak},
@@ -83,13 +83,18 @@ pub type PRuntimeLightValidation = LightValidation<chain::Runtime>;
 pub mod benchmark;
 
 mod bin_api_service;
+pub mod cluster_replay;
 mod contract_result;
 pub mod contracts;
+pub mod crash_report;
 mod cryptography;
+mod dispatch_audit;
 mod im_helpers;
 mod light_validation;
+mod memory_budget;
 mod nts;
 mod prpc_service;
+mod response_chunks;
 mod secret_channel;
 mod storage;
 mod system;
@@ -104,8 +109,9 @@ struct RuntimeState {
     #[codec(skip)]
     send_mq: MessageSendQueue,
 
-    #[serde(skip)]
-    #[codec(skip)]
+    /// Only the sender sequences of the dispatcher are persisted.
+    #[serde(default)]
+    #[cfg_attr(not(test), codec(skip))]
     recv_mq: MessageDispatcher,
 
     // chain storage synchonizing
@@ -120,6 +126,39 @@ struct RuntimeState {
     genesis_block_hash: H256,
 
     para_id: u32,
+
+    /// Blocks received and validated, but held back by the dispatch lag. `chain_storage` is the
+    /// state before the first of them, while `storage_synchronizer` is past the last of them.
+    #[serde(default)]
+    #[codec(skip)]
+    lagged_blocks: VecDeque<LaggedBlock>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LaggedBlock {
+    #[serde(with = "more::scale_bytes")]
+    block: blocks::BlockHeaderWithChanges,
+    /// The chain state after the block. It is not persisted, the changes of the block are applied
+    /// again when it is dispatched after a restart.
+    #[serde(skip)]
+    state: Option<ChainStorage>,
+}
+
+impl LaggedBlock {
+    /// Applies the changes of the block, which were validated on receipt, to `storage`.
+    fn apply_to(&self, storage: &mut ChainStorage, drop_proofs: bool) {
+        let storage = storage.inner_mut();
+        if drop_proofs {
+            storage.set_root(self.block.block_header.state_root);
+            return;
+        }
+        let changes = &self.block.storage_changes;
+        let (root, transaction) = storage.calc_root_if_changes(
+            &changes.main_storage_changes,
+            &changes.child_storage_changes,
+        );
+        storage.apply_changes(root, transaction);
+    }
 }
 
 impl RuntimeState {
@@ -127,6 +166,30 @@ impl RuntimeState {
         self.send_mq
             .purge(|sender| self.chain_storage.mq_sequence(sender))
     }
+
+    /// The number of the last dispatched block, which `chain_storage` is at.
+    fn dispatched_block_number(&self) -> chain::BlockNumber {
+        let received = self.storage_synchronizer.counters().next_block_number;
+        received.saturating_sub(1 + self.lagged_blocks.len() as chain::BlockNumber)
+    }
+
+    /// The chain state after the last received block.
+    fn received_state(&self, drop_proofs: bool) -> ChainStorage {
+        if let Some(LaggedBlock {
+            state: Some(state), ..
+        }) = self.lagged_blocks.back()
+        {
+            return state.snapshot();
+        }
+        let mut state = self.chain_storage.snapshot();
+        for lagged in &self.lagged_blocks {
+            match &lagged.state {
+                Some(lagged_state) => state = lagged_state.snapshot(),
+                None => lagged.apply_to(&mut state, drop_proofs),
+            }
+        }
+        state
+    }
 }
 
 const RUNTIME_SEALED_DATA_FILE: &str = "runtime-data.seal";
@@ -338,6 +401,18 @@ pub struct Phactory<Platform> {
     #[serde(skip)]
     #[serde(default = "sidevm_helper::create_sidevm_service_default")]
     sidevm_spawner: sidevm::service::Spawner,
+
+    #[codec(skip)]
+    #[serde(skip)]
+    memory_budget: memory_budget::MemoryBudget,
+
+    #[codec(skip)]
+    #[serde(skip)]
+    response_chunks: response_chunks::ResponseChunks,
+
+    #[codec(skip)]
+    #[serde(skip)]
+    dispatch_audit: dispatch_audit::DispatchAudit,
 }
 
 mod sidevm_helper {
@@ -425,6 +500,9 @@ impl<Platform: pal::Platform> Phactory<Platform> {
                 args.cores as _,
                 create_sidevm_outgoing_channel(weak_self),
             ),
+            memory_budget: Default::default(),
+            response_chunks: Default::default(),
+            dispatch_audit: Default::default(),
         };
         me.init(args);
         me
@@ -443,10 +521,19 @@ impl<Platform: pal::Platform> Phactory<Platform> {
     pub fn set_args(&mut self, args: InitArgs) {
         contracts::pink::context::set_query_time_limit(args.query_timeout);
         self.args = Arc::new(args);
+        self.memory_budget = memory_budget::MemoryBudget::new(self.args.memory_budget_mb);
         if let Some(system) = &mut self.system {
             system.sealing_path = self.args.sealing_path.clone();
             system.storage_path = self.args.storage_path.clone();
         }
+        if self.args.sidevm_compile_cache {
+            // In the sealing path, the artifacts are loaded as native code.
+            let dir = PathBuf::from(&self.args.sealing_path).join("sidevm_compile_cache");
+            match sidevm::compile_cache::CompileCache::new(dir) {
+                Ok(cache) => self.sidevm_spawner.set_compile_cache(cache),
+                Err(err) => warn!("Sidevm compile cache disabled: {err:?}"),
+            }
+        }
     }
 
     fn init_runtime_data(
@@ -538,9 +625,12 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         }
     }
 
-    pub fn set_netconfig(&mut self, config: NetworkConfig) {
+    pub fn set_netconfig(&mut self, config: NetworkConfig) -> Result<()> {
+        // Validate before taking it, so that a bad config is rejected rather than half applied.
+        dns_config(&config)?;
         self.netconfig = Some(config);
         self.reconfigure_network();
+        Ok(())
     }
 
     fn reconfigure_network(&self) {
@@ -557,9 +647,34 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         }
         reconfig_one("all_proxy", &config.all_proxy);
         reconfig_one("i2p_proxy", &config.i2p_proxy);
+        match dns_config(config) {
+            Ok(dns) => sidevm::dns::set_config(dns),
+            Err(err) => error!("Invalid sidevm DNS config: {err:?}"),
+        }
     }
 }
 
+fn dns_config(config: &NetworkConfig) -> Result<sidevm::dns::DnsConfig> {
+    let mut dns = sidevm::dns::DnsConfig {
+        resolver: config.dns_resolver.parse()?,
+        ..Default::default()
+    };
+    for policy in &config.domain_policies {
+        let allowed = sidevm::dns::DomainPolicy::parse(&policy.allowed)
+            .with_context(|| format!("Invalid domain policy of {}", policy.contract))?;
+        if policy.contract == "*" {
+            dns.default_policy = Some(allowed);
+            continue;
+        }
+        let id: sidevm::VmId = hex::decode(policy.contract.trim_start_matches("0x"))
+            .ok()
+            .and_then(|id| id.try_into().ok())
+            .ok_or_else(|| anyhow!("Invalid contract id {}", policy.contract))?;
+        dns.contract_policies.insert(id, allowed);
+    }
+    Ok(dns)
+}
+
 impl<P: pal::Platform> Phactory<P> {
     // Restored from checkpoint
     pub fn on_restored(&mut self) -> Result<()> {
@@ -637,6 +752,27 @@ impl<P: pal::Platform> Phactory<P> {
         request: sidevm::OutgoingRequest,
         weak_phactory: Weak<Mutex<Phactory<P>>>,
     ) -> Option<impl Future<Output = ()>> {
+        if let sidevm::OutgoingRequest::ChainStorage { keys, reply_tx } = request {
+            // Only the snapshot is taken under the lock, the proof is generated off it.
+            let snapshot = self.runtime_state.as_ref().map(|state| {
+                (
+                    state.chain_storage.snapshot(),
+                    state.dispatched_block_number(),
+                )
+            });
+            tokio::task::spawn_blocking(move || {
+                let response = match snapshot {
+                    Some((storage, block_number)) => {
+                        read_chain_storage(&storage, block_number, &keys)
+                    }
+                    None => Err(SidevmQueryError::ServiceUnavailable),
+                };
+                if reply_tx.send(response.encode()).is_err() {
+                    error!("Failed to send sidevm chain storage reply");
+                }
+            });
+            return None;
+        }
         let sidevm::OutgoingRequest::Query {
             contract_id,
             payload,
@@ -674,6 +810,8 @@ impl<P: pal::Platform> Phactory<P> {
                     .chain_storage,
                 self.sidevm_spawner.event_tx(),
                 self.attestation_provider,
+                None,
+                None,
             );
         let pink_runtime_version = self
             .cluster_runtime_version()
@@ -714,6 +852,45 @@ impl<P: pal::Platform> Phactory<P> {
     }
 }
 
+/// Reads entries of the chain storage at the block `block_number` for sidevm, with a proof against
+/// the state root of the block.
+fn read_chain_storage(
+    storage: &ChainStorage,
+    block_number: chain::BlockNumber,
+    keys: &[Vec<u8>],
+) -> Result<ChainStorageResponse, SidevmQueryError> {
+    use sidevm_env::messages::{MAX_CHAIN_STORAGE_KEYS, MAX_CHAIN_STORAGE_RESPONSE_SIZE};
+
+    if keys.len() > MAX_CHAIN_STORAGE_KEYS {
+        return Err(SidevmQueryError::OtherError(format!(
+            "Too many keys, at most {MAX_CHAIN_STORAGE_KEYS} are allowed"
+        )));
+    }
+    let too_large = || {
+        SidevmQueryError::OtherError(format!(
+            "The response exceeds {MAX_CHAIN_STORAGE_RESPONSE_SIZE} bytes"
+        ))
+    };
+    let values: Vec<_> = keys.iter().map(|key| storage.inner().get(key)).collect();
+    let values_size: usize = values.iter().flatten().map(Vec::len).sum();
+    if values_size > MAX_CHAIN_STORAGE_RESPONSE_SIZE {
+        return Err(too_large());
+    }
+    let proof = storage
+        .read_proof(keys)
+        .map_err(|err| SidevmQueryError::OtherError(err.to_string()))?;
+    let proof_size: usize = proof.iter().map(Vec::len).sum();
+    if values_size + proof_size > MAX_CHAIN_STORAGE_RESPONSE_SIZE {
+        return Err(too_large());
+    }
+    Ok(ChainStorageResponse {
+        block_number,
+        state_root: storage.root().0,
+        values,
+        proof,
+    })
+}
+
 fn opaque_to_sidevm_err(err: ContractQueryError) -> SidevmQueryError {
     match err {
         ContractQueryError::InvalidSignature => SidevmQueryError::InvalidSignature,
@@ -731,6 +908,12 @@ fn to_sidevm_err(err: QueryError) -> SidevmQueryError {
         QueryError::NoResponse => SidevmQueryError::NoResponse,
         QueryError::ServiceUnavailable => SidevmQueryError::ServiceUnavailable,
         QueryError::Timeout => SidevmQueryError::Timeout,
+        QueryError::Overloaded { .. } | QueryError::MemoryPressure => {
+            SidevmQueryError::ServiceUnavailable
+        }
+        err @ QueryError::ResponseTooLarge { .. } => {
+            SidevmQueryError::RuntimeError(err.to_string())
+        }
     }
 }
 
@@ -807,7 +990,8 @@ impl<P: pal::Platform> Phactory<P> {
 
 impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform> {
     pub fn take_checkpoint(&mut self) -> anyhow::Result<chain::BlockNumber> {
-        if self.args.safe_mode_level > 0 {
+        // In diagnostic safe mode the state is kept as loaded, so it is fine to take a backup.
+        if self.args.safe_mode_level > 0 && !self.args.safe_mode {
             anyhow::bail!("Checkpoint is disabled in safe mode");
         }
         let (current_block, _) = self.current_block()?;
@@ -1032,6 +1216,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
                     })
                     .collect(),
             }),
+            queries_in_flight: Some(self.query_scheduler.in_flight()),
         })
     }
 }
@@ -1085,6 +1270,13 @@ impl<Platform: Serialize + DeserializeOwned> Phactory<Platform> {
                     .next_element()?
                     .ok_or_else(|| de::Error::custom("Missing Phactory"))?;
 
+                // The namespace of the dispatcher is not persisted, restore it from the runtime state.
+                if let Some(runtime_state) = factory.runtime_state.as_mut() {
+                    runtime_state
+                        .recv_mq
+                        .set_chain_namespace(runtime_state.genesis_block_hash.0);
+                }
+
                 if self.safe_mode_level < 2 {
                     factory.system = {
                         let runtime_state = factory
@@ -1235,3 +1427,61 @@ pub fn public_data_dir(storage_path: impl AsRef<Path>) -> PathBuf {
 pub const fn version_str() -> &'static str {
     this_crate::version_str!()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sidevm_env::messages::{MAX_CHAIN_STORAGE_KEYS, MAX_CHAIN_STORAGE_RESPONSE_SIZE};
+
+    fn storage(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> ChainStorage {
+        ChainStorage::from_pairs(pairs.into_iter())
+    }
+
+    #[test]
+    fn chain_storage_reads_are_proven() {
+        let storage = storage(vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]);
+        let keys = vec![b"key2".to_vec(), b"missing".to_vec()];
+        let response = read_chain_storage(&storage, 10, &keys).unwrap();
+        assert_eq!(response.block_number, 10);
+        assert_eq!(response.state_root, storage.root().0);
+        assert_eq!(response.values, vec![Some(b"value2".to_vec()), None]);
+
+        let proof = sp_trie::StorageProof::new(response.proof);
+        let proven = sp_state_machine::read_proof_check::<RuntimeHasher, _>(
+            response.state_root.into(),
+            proof,
+            &keys,
+        )
+        .unwrap();
+        assert_eq!(proven.get(&keys[0]), Some(&Some(b"value2".to_vec())));
+        assert_eq!(proven.get(&keys[1]), Some(&None));
+    }
+
+    #[test]
+    fn chain_storage_reads_are_capped_in_keys() {
+        let storage = storage(vec![(b"key".to_vec(), b"value".to_vec())]);
+        let keys = vec![b"key".to_vec(); MAX_CHAIN_STORAGE_KEYS];
+        assert!(read_chain_storage(&storage, 1, &keys).is_ok());
+        let keys = vec![b"key".to_vec(); MAX_CHAIN_STORAGE_KEYS + 1];
+        assert!(matches!(
+            read_chain_storage(&storage, 1, &keys),
+            Err(SidevmQueryError::OtherError(_))
+        ));
+    }
+
+    #[test]
+    fn chain_storage_reads_are_capped_in_size() {
+        let storage = storage(vec![
+            (b"small".to_vec(), vec![1; 16]),
+            (b"large".to_vec(), vec![2; MAX_CHAIN_STORAGE_RESPONSE_SIZE]),
+        ]);
+        assert!(read_chain_storage(&storage, 1, &[b"small".to_vec()]).is_ok());
+        assert!(matches!(
+            read_chain_storage(&storage, 1, &[b"large".to_vec()]),
+            Err(SidevmQueryError::OtherError(_))
+        ));
+    }
+}
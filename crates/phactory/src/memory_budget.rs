@@ -0,0 +1,166 @@
+//! Heap budget of the enclave and the load shedding under memory pressure.
+//!
+//! Running out of heap aborts the enclave, so when the heap usage reported by the allocator
+//! approaches the budget given by `--memory-budget-mb`, the load is shed step by step instead: the
+//! caches are evicted, the stopped sidevm instances are not restarted, and new contract queries
+//! are rejected with [`QueryError::MemoryPressure`](phactory_api::contracts::QueryError). A step is
+//! lifted once the usage drops below its threshold by a margin.
+//!
+//! The usage of each subsystem is estimated from the sizes of the data it holds. The estimations
+//! are approximate and only serve diagnostics, the pressure is decided by the allocator stats.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::BlockNumber;
+
+/// Number of blocks between two estimations of the subsystem usage, which walk the storages.
+const ESTIMATE_INTERVAL: BlockNumber = 100;
+/// The percent of the budget at which each level of pressure is entered.
+const THRESHOLDS: [(Pressure, u64); 3] = [
+    (Pressure::EvictCaches, 80),
+    (Pressure::PauseSidevmRestarts, 90),
+    (Pressure::RejectQueries, 95),
+];
+/// The percent below its threshold at which a level of pressure is left.
+const HYSTERESIS: u64 = 5;
+
+static PRESSURE: AtomicU8 = AtomicU8::new(Pressure::Normal as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Pressure {
+    Normal = 0,
+    /// The caches are evicted and the query history is not kept.
+    EvictCaches = 1,
+    /// The sidevm instances are not restarted, in addition to the above.
+    PauseSidevmRestarts = 2,
+    /// New contract queries are rejected, in addition to the above.
+    RejectQueries = 3,
+}
+
+impl Pressure {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::EvictCaches,
+            2 => Self::PauseSidevmRestarts,
+            3 => Self::RejectQueries,
+            _ => Self::Normal,
+        }
+    }
+
+    /// The level of pressure with `used` out of `budget`, given the current level.
+    fn evaluate(self, used: u64, budget: u64) -> Self {
+        let percent = used.saturating_mul(100) / budget.max(1);
+        THRESHOLDS
+            .iter()
+            .rev()
+            .find(|(level, threshold)| {
+                let threshold = if *level <= self {
+                    threshold.saturating_sub(HYSTERESIS)
+                } else {
+                    *threshold
+                };
+                percent >= threshold
+            })
+            .map(|(level, _)| *level)
+            .unwrap_or(Self::Normal)
+    }
+}
+
+/// The current level of pressure.
+pub fn pressure() -> Pressure {
+    Pressure::from_u8(PRESSURE.load(Ordering::Relaxed))
+}
+
+/// Approximate heap usage in bytes of each subsystem.
+#[derive(Debug, Clone, Default)]
+pub struct SubsystemUsage {
+    pub chain_storage: u64,
+    /// The cluster storage holding the contracts.
+    pub contracts: u64,
+    pub caches: u64,
+    /// The linear memory the running sidevm instances may grow to.
+    pub sidevm: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryBudget {
+    /// In bytes, 0 for disabled.
+    budget: u64,
+    usage: SubsystemUsage,
+    estimated_at: Option<BlockNumber>,
+}
+
+impl MemoryBudget {
+    pub fn new(budget_mb: u64) -> Self {
+        Self {
+            budget: budget_mb.saturating_mul(1024 * 1024),
+            ..Default::default()
+        }
+    }
+
+    /// Re-evaluates the pressure with the heap usage from the allocator. Returns true if it rose.
+    pub fn update(&mut self, heap_used: u64) -> bool {
+        if self.budget == 0 {
+            return false;
+        }
+        let current = pressure();
+        let level = current.evaluate(heap_used, self.budget);
+        if level == current {
+            return false;
+        }
+        PRESSURE.store(level as u8, Ordering::Relaxed);
+        let used_mb = heap_used / 1024 / 1024;
+        let budget_mb = self.budget / 1024 / 1024;
+        if level > current {
+            warn!(
+                "Memory pressure raised to {level:?}, heap used {used_mb}MiB of {budget_mb}MiB, {:?}",
+                self.usage
+            );
+            true
+        } else {
+            info!("Memory pressure lowered to {level:?}, heap used {used_mb}MiB of {budget_mb}MiB");
+            false
+        }
+    }
+
+    /// Whether the subsystem usage is due to be estimated again.
+    pub fn should_estimate(&self, block_number: BlockNumber) -> bool {
+        self.budget != 0
+            && self
+                .estimated_at
+                .map_or(true, |at| block_number >= at + ESTIMATE_INTERVAL)
+    }
+
+    pub fn set_usage(&mut self, block_number: BlockNumber, usage: SubsystemUsage) {
+        debug!("Estimated memory usage at block {block_number}: {usage:?}");
+        self.estimated_at = Some(block_number);
+        self.usage = usage;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_levels_follow_the_thresholds() {
+        use Pressure::*;
+        assert_eq!(Normal.evaluate(79, 100), Normal);
+        assert_eq!(Normal.evaluate(80, 100), EvictCaches);
+        assert_eq!(Normal.evaluate(92, 100), PauseSidevmRestarts);
+        assert_eq!(Normal.evaluate(100, 100), RejectQueries);
+        assert_eq!(Normal.evaluate(200, 100), RejectQueries);
+    }
+
+    #[test]
+    fn pressure_levels_are_left_with_a_margin() {
+        use Pressure::*;
+        assert_eq!(RejectQueries.evaluate(91, 100), RejectQueries);
+        assert_eq!(RejectQueries.evaluate(89, 100), PauseSidevmRestarts);
+        assert_eq!(PauseSidevmRestarts.evaluate(76, 100), EvictCaches);
+        assert_eq!(EvictCaches.evaluate(75, 100), EvictCaches);
+        assert_eq!(EvictCaches.evaluate(74, 100), Normal);
+        assert_eq!(Normal.evaluate(0, 0), Normal);
+    }
+}
@@ -3,9 +3,10 @@ use std::future::Future;
 use std::io::Read;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::benchmark::Flags;
+use crate::dispatch_audit::{AuditEgress, AuditOp};
 use crate::system::{System, MAX_SUPPORTED_CONSENSUS_VERSION};
 use crate::types::BaseBlockInfo;
 use crate::{hex, try_decode_hex};
@@ -28,8 +29,9 @@ use phala_pallets::utils::attestation::{validate as validate_attestation_report,
 use phala_types::contract::contract_id_preimage;
 use phala_types::{
     contract, messaging::EncryptedKey, wrap_content_to_sign, AttestationReport,
-    ChallengeHandlerInfo, EncryptedWorkerKey, HandoverChallenge, SignedContentType,
-    VersionedWorkerEndpoints, WorkerEndpointPayload, WorkerPublicKey, WorkerRegistrationInfoV2,
+    ChallengeHandlerInfo, EnclaveIdentityPayload, EncryptedWorkerKey, HandoverChallenge,
+    SgxMeasurement, SignedContentType, VersionedWorkerEndpoints, WorkerEndpointPayload,
+    WorkerPublicKey, WorkerRegistrationInfoV2,
 };
 use phala_types::{DcapChallengeHandlerInfo, DcapHandoverChallenge};
 use pink_loader::types::{AccountId, ExecSideEffects, ExecutionMode};
@@ -70,12 +72,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
 
     pub(crate) fn current_block(&mut self) -> RpcResult<(BlockNumber, u64)> {
         let now_ms = self.runtime_state()?.chain_storage.timestamp_now();
-        let block = self
-            .runtime_state()?
-            .storage_synchronizer
-            .counters()
-            .next_block_number
-            .saturating_sub(1);
+        let block = self.runtime_state()?.dispatched_block_number();
         Ok((block, now_ms))
     }
 
@@ -85,15 +82,23 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         let genesis_block_hash = state.map(|state| hex::encode(state.genesis_block_hash));
         let dev_mode = self.dev_mode;
 
-        let (state_root, pending_messages, counters) = match state.as_ref() {
-            Some(state) => {
-                let state_root = hex::encode(state.chain_storage.root());
-                let pending_messages = state.send_mq.count_messages();
-                let counters = state.storage_synchronizer.counters();
-                (state_root, pending_messages, counters)
-            }
-            None => Default::default(),
-        };
+        let (state_root, pending_messages, mq_duplicates_dropped, counters, lagged_blocks) =
+            match state.as_ref() {
+                Some(state) => {
+                    let state_root = hex::encode(state.chain_storage.root());
+                    let pending_messages = state.send_mq.count_messages();
+                    let mq_duplicates_dropped = state.recv_mq.duplicates_dropped();
+                    let counters = state.storage_synchronizer.counters();
+                    (
+                        state_root,
+                        pending_messages,
+                        mq_duplicates_dropped,
+                        counters,
+                        state.lagged_blocks.len() as u32,
+                    )
+                }
+                None => Default::default(),
+            };
 
         let system_info = self.system.as_ref().map(|s| s.get_info());
         let score = benchmark::score();
@@ -164,6 +169,11 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             supported_attestation_methods: self.platform.supported_attestation_methods(),
             live_sidevm_instances: sidevm::vm_count() as u32,
             query_timeout: self.args.query_timeout as _,
+            rpc_features: pb::features::ALL,
+            mq_duplicates_dropped,
+            last_crash: crate::crash_report::last_crash(),
+            dispatch_lag: self.args.dispatch_lag,
+            lagged_blocks,
         }
     }
 
@@ -274,23 +284,46 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             .unwrap_or(counters.next_block_number - 1);
 
         let safe_mode_level = self.args.safe_mode_level;
+        let dispatch_lag = self.args.dispatch_lag as usize;
+        let audit_dispatch = self.args.audit_dispatch;
+        let drop_proofs = safe_mode_level > 1;
 
-        for block in blocks.into_iter() {
-            info!(block = block.block_header.number, "Dispatching");
-            let state = self.runtime_state()?;
-            let drop_proofs = safe_mode_level > 1;
+        // The blocks are validated on receipt, against the state after the blocks held back by
+        // the dispatch lag, so that an invalid block is rejected in the request bringing it.
+        let state = self.runtime_state()?;
+        let mut received_state = state.received_state(drop_proofs);
+        for block in blocks {
             state
                 .storage_synchronizer
-                .feed_block(&block, state.chain_storage.inner_mut(), drop_proofs)
+                .feed_block(&block, received_state.inner_mut(), drop_proofs)
                 .map_err(from_display)?;
+            state.lagged_blocks.push_back(LaggedBlock {
+                block,
+                state: Some(received_state.snapshot()),
+            });
+        }
+
+        while self.runtime_state()?.lagged_blocks.len() > dispatch_lag {
+            let state = self.runtime_state()?;
+            let Some(mut lagged) = state.lagged_blocks.pop_front() else {
+                break;
+            };
+            info!(block = lagged.block.block_header.number, "Dispatching");
+            match lagged.state.take() {
+                Some(lagged_state) => state.chain_storage = lagged_state,
+                None => lagged.apply_to(&mut state.chain_storage, drop_proofs),
+            }
             if safe_mode_level > 0 {
                 continue;
             }
             info!("State synced");
             state.purge_mq();
+            if audit_dispatch {
+                state.send_mq.set_observer(Some(Arc::new(AuditEgress)));
+            }
             let now_ms = state.chain_storage.timestamp_now();
             let chain_storage = state.chain_storage.snapshot();
-            let block_number = block.block_header.number;
+            let block_number = lagged.block.block_header.number;
             let contracts = self.system()?.contracts.clone();
             let mut context = contracts::pink::context::ContractExecContext::new(
                 ExecutionMode::Transaction,
@@ -304,13 +337,24 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
                 None, // Not allowed in TX
             );
             self.check_requirements();
-            contracts::pink::context::using(&mut context, || {
-                self.handle_inbound_messages(block_number)
-            })?;
+            let dispatch_audit = self.dispatch_audit.clone();
+            let dispatch = || {
+                contracts::pink::context::using(&mut context, || {
+                    self.handle_inbound_messages(block_number)
+                })
+            };
+            if audit_dispatch {
+                dispatch_audit.audit(block_number, dispatch)?;
+            } else {
+                dispatch()?;
+            }
 
             self.maybe_apply_cluster_state();
+            if let Some(state) = &self.runtime_state {
+                crate::crash_report::record_progress(block_number, state.send_mq.sequences());
+            }
 
-            if let Err(e) = self.maybe_take_checkpoint() {
+            if let Err(e) = self.maybe_take_checkpoint(block_number) {
                 error!("Failed to take checkpoint: {:?}", e);
             }
         }
@@ -319,12 +363,30 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         })
     }
 
-    fn maybe_take_checkpoint(&mut self) -> anyhow::Result<()> {
+    /// Takes a checkpoint every `checkpoint_interval` seconds, or at the blocks aligned to the
+    /// checkpoint interval of the cluster if it is set on chain.
+    fn maybe_take_checkpoint(&mut self, block_number: BlockNumber) -> anyhow::Result<()> {
         if !self.args.enable_checkpoint {
             return Ok(());
         }
-        if self.last_checkpoint.elapsed().as_secs() < self.args.checkpoint_interval {
-            return Ok(());
+        let coordinated_interval = match (&self.system, &self.runtime_state) {
+            (Some(system), Some(state)) => system
+                .contract_cluster
+                .as_ref()
+                .and_then(|cluster| state.chain_storage.cluster_checkpoint_interval(&cluster.id)),
+            _ => None,
+        };
+        match coordinated_interval {
+            Some(interval) => {
+                if block_number % interval != 0 {
+                    return Ok(());
+                }
+            }
+            None => {
+                if self.last_checkpoint.elapsed().as_secs() < self.args.checkpoint_interval {
+                    return Ok(());
+                }
+            }
         }
         self.take_checkpoint()?;
         Ok(())
@@ -457,7 +519,8 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         };
 
         let send_mq = MessageSendQueue::default();
-        let recv_mq = MessageDispatcher::default();
+        let mut recv_mq = MessageDispatcher::default();
+        recv_mq.set_chain_namespace(genesis_block_hash.0);
 
         let mut runtime_state = RuntimeState {
             send_mq,
@@ -466,6 +529,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             chain_storage,
             genesis_block_hash,
             para_id,
+            lagged_blocks: Default::default(),
         };
 
         // In parachain mode the state root is stored in parachain header which isn't passed in here.
@@ -602,6 +666,13 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         Ok(messages)
     }
 
+    fn get_egress_queue_depth(&self) -> pb::EgressQueueDepths {
+        self.runtime_state
+            .as_ref()
+            .map(|state| state.send_mq.depths().into_iter().collect())
+            .unwrap_or_default()
+    }
+
     fn contract_query(
         &mut self,
         req_id: u64,
@@ -609,6 +680,9 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
     ) -> RpcResult<
         impl Future<Output = RpcResult<(pb::ContractQueryResponse, Option<ExecSideEffects>)>>,
     > {
+        let deadline = request
+            .deadline_ms
+            .map(|ms| Instant::now() + Duration::from_millis(ms));
         // Validate signature
         let origin = if let Some(sig) = &request.signature {
             let current_block = self.get_info().blocknum - 1;
@@ -668,13 +742,30 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
                     .chain_storage,
                 self.sidevm_spawner.event_tx(),
                 attestation_provider,
+                request.at_block,
+                deadline,
             )?;
 
+        let max_size = self.args.max_query_response_kb as usize * 1024;
+        let chunked = request.chunked && max_size > 0;
+        let response_chunks = self.response_chunks.clone();
         Ok(async move {
             let (_, response, effects) = query_future.await?;
+            let mut result = response.encode();
+            if max_size > 0 && result.len() > max_size && !chunked {
+                info!(
+                    "Contract query response of {} bytes is too large",
+                    result.len()
+                );
+                let err = QueryError::ResponseTooLarge {
+                    size: result.len() as u64,
+                    limit: max_size as u64,
+                };
+                result = Err::<phactory_api::contracts::Response, _>(err).encode();
+            }
             let response = contract::ContractQueryResponse {
                 nonce: head.nonce,
-                result: contract::Data(response.encode()),
+                result: contract::Data(result),
             };
             let response_data = response.encode();
 
@@ -687,7 +778,103 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             )
             .map_err(from_debug)?;
 
-            Ok((pb::ContractQueryResponse::new(encrypted_resp), effects))
+            let encoded_encrypted_data = encrypted_resp.encode();
+            let total_size = encoded_encrypted_data.len() as u64;
+            if !chunked || encoded_encrypted_data.len() <= max_size {
+                let response = pb::ContractQueryResponse::new(encrypted_resp, None, total_size);
+                return Ok((response, effects));
+            }
+            let handle = response_chunks
+                .park(encoded_encrypted_data)
+                .ok_or_else(|| from_display("Too many chunked responses pending, retry later"))?;
+            let response = pb::ContractQueryResponse {
+                encoded_encrypted_data: vec![],
+                chunked_handle: Some(handle),
+                total_size,
+            };
+            Ok((response, effects))
+        })
+    }
+
+    fn get_query_response_chunk(
+        &self,
+        request: pb::QueryResponseChunkRequest,
+    ) -> RpcResult<pb::QueryResponseChunk> {
+        let max_size = (self.args.max_query_response_kb as usize * 1024).max(1024);
+        let (data, total_size) = self
+            .response_chunks
+            .read(request.handle, request.offset, max_size)
+            .ok_or_else(|| from_display("Response not found or expired"))?;
+        Ok(pb::QueryResponseChunk { data, total_size })
+    }
+
+    fn get_dispatch_audit(
+        &self,
+        request: pb::GetDispatchAuditRequest,
+    ) -> RpcResult<pb::DispatchAudit> {
+        if !self.args.audit_dispatch {
+            return Err(from_display("Dispatch audit disabled"));
+        }
+        let trail = self
+            .dispatch_audit
+            .get(request.block_number)
+            .ok_or_else(|| from_display("Block not audited"))?;
+        let ops = if request.with_ops {
+            trail
+                .ops
+                .iter()
+                .map(|audited| pb::AuditedOp {
+                    kind: audited.op.kind().into(),
+                    detail: audited.op.detail(),
+                    digest: audited.digest.to_vec(),
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(pb::DispatchAudit {
+            block_number: trail.block_number,
+            digest: trail.digest.to_vec(),
+            op_count: trail.ops.len() as u32,
+            ops,
+        })
+    }
+
+    fn dry_run_instantiate(
+        &mut self,
+        req_id: u64,
+        request: pb::DryRunInstantiateRequest,
+    ) -> RpcResult<impl Future<Output = RpcResult<pb::DryRunInstantiateResponse>>> {
+        let args = DryRunInstantiateArgs::decode(request)?;
+        let query_scheduler = self.query_scheduler.clone();
+        let sidevm_event_tx = self.sidevm_spawner.event_tx();
+        let system = self
+            .system
+            .as_ref()
+            .ok_or_else(|| from_display("Runtime not initialized"))?;
+        let chain_storage = &self
+            .runtime_state
+            .as_ref()
+            .ok_or_else(|| from_display("Runtime not initialized"))?
+            .chain_storage;
+        let block_number = system.block_number;
+        let dry_run = system
+            .make_dry_run_instantiate(
+                req_id,
+                AccountId::new(args.deployer),
+                args.code_hash.into(),
+                args.constructor,
+                args.salt,
+                args.transfer,
+                query_scheduler,
+                chain_storage,
+                sidevm_event_tx,
+            )
+            .map_err(from_display)?;
+
+        Ok(async move {
+            let (address, output, effects) = dry_run.await.map_err(from_display)?;
+            dry_run_instantiate_response(&address, &output, effects, block_number)
         })
     }
 
@@ -721,6 +908,8 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
 
         system.will_process_block(&mut block);
 
+        // The number of messages of each sender dispatched in this block.
+        let mut sender_counts = BTreeMap::<MessageOrigin, u64>::new();
         for message in messages {
             use phala_types::messaging::SystemEvent;
             macro_rules! log_message {
@@ -749,11 +938,33 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
                     message.sender, message.destination
                 );
             }
-            block.recv_mq.dispatch(message);
+            // The position of the message among the ones of its sender on chain, which increases
+            // over the blocks. The on-chain messages do not carry the ingress sequences.
+            let index = sender_counts.entry(message.sender.clone()).or_default();
+            let sequence = ((block_number as u64) << 32) | *index;
+            *index += 1;
+            crate::dispatch_audit::record(|| AuditOp::Inbound {
+                sender: message.sender.clone(),
+                destination: message.destination.path().clone(),
+                sequence,
+                payload_hash: blake2_256(&message.payload),
+            });
+            block.recv_mq.dispatch_sequenced(message, sequence);
 
             system.process_messages(&mut block);
         }
         system.did_process_block(&mut block);
+        if self.memory_budget.should_estimate(block_number) {
+            let usage = system.estimate_memory_usage(block.storage);
+            self.memory_budget.set_usage(block_number, usage);
+        }
+        if self
+            .memory_budget
+            .update(self.platform.memory_usage().rust_used)
+        {
+            system.evict_caches();
+        }
+        system.record_query_history(&block, self.args.query_history_blocks as usize);
 
         let n_unhandled = block.recv_mq.clear();
         if n_unhandled > 0 {
@@ -845,6 +1056,36 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         Ok(signature)
     }
 
+    fn get_enclave_identity(&mut self) -> RpcResult<pb::EnclaveIdentityResponse> {
+        let runtime_state = self.runtime_state()?;
+        let genesis_block_hash = runtime_state.genesis_block_hash;
+        let para_id = runtime_state.para_id;
+        let system = self.system()?;
+        let identity_key = system.identity_key.clone();
+        let signing_time = system.now_ms;
+        let payload = EnclaveIdentityPayload {
+            pubkey: identity_key.public(),
+            sgx_measurement: self
+                .platform
+                .measurement()
+                .and_then(|_| my_sgx_measurement()),
+            attestation_provider: self.attestation_provider,
+            supported_attestation_methods: self.platform.supported_attestation_methods(),
+            version: self.args.version.clone(),
+            git_revision: self.args.git_revision.clone(),
+            rpc_features: pb::features::ALL,
+            max_supported_pink_runtime_version: pink_loader::runtimes::max_supported_version(),
+            genesis_block_hash,
+            para_id,
+            dev_mode: self.dev_mode,
+            signing_time,
+        };
+        let wrapped_data =
+            wrap_content_to_sign(&payload.encode(), SignedContentType::EnclaveIdentity);
+        let signature = identity_key.sign(&wrapped_data).encode();
+        Ok(pb::EnclaveIdentityResponse::new(payload, signature))
+    }
+
     pub fn get_contract_info(
         &mut self,
         contract_ids: &[String],
@@ -916,10 +1157,126 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
                     .as_ref()
                     .map(hex)
                     .unwrap_or_default(),
+                extension_policy: Some(pb::ExtensionPolicy {
+                    disable_http: cluster.config.extension_policy.disable_http,
+                    disable_randomness: cluster.config.extension_policy.disable_randomness,
+                    disable_secrets: cluster.config.extension_policy.disable_secrets,
+                }),
             }),
         })
     }
 
+    pub fn get_contract_events(
+        &self,
+        request: pb::GetContractEventsRequest,
+    ) -> RpcResult<pb::GetContractEventsResponse> {
+        use crate::contracts::pink::ink_events::{self, EventFilter};
+
+        fn decode_h256(hex_str: &str, what: &str) -> RpcResult<[u8; 32]> {
+            try_decode_hex(hex_str)
+                .ok()
+                .and_then(|raw| raw.try_into().ok())
+                .ok_or_else(|| from_display(format!("Invalid {what}")))
+        }
+        let contracts = request
+            .contracts
+            .iter()
+            .map(|id| decode_h256(id, "contract id").map(AccountId::new))
+            .collect::<RpcResult<_>>()?;
+        let topics = request
+            .topics
+            .iter()
+            .map(|topic| decode_h256(topic, "topic").map(Into::into))
+            .collect::<RpcResult<_>>()?;
+        let limit = if request.limit == 0 {
+            ink_events::MAX_EVENTS_PER_QUERY
+        } else {
+            request.limit as usize
+        };
+        let page = ink_events::query(&EventFilter {
+            contracts,
+            topics,
+            from_sequence: request.from_sequence,
+            limit,
+        });
+        Ok(pb::GetContractEventsResponse {
+            events: page
+                .events
+                .into_iter()
+                .map(|event| pb::ContractEvent {
+                    sequence: event.sequence,
+                    block_number: event.block_number,
+                    contract: hex(&event.contract),
+                    topics: event.topics.iter().map(hex).collect(),
+                    payload: event.payload,
+                })
+                .collect(),
+            next_sequence: page.next_sequence,
+            oldest_sequence: page.oldest_sequence,
+        })
+    }
+
+    pub fn list_contracts(
+        &self,
+        request: pb::ListContractsRequest,
+    ) -> RpcResult<pb::ListContractsResponse> {
+        const DEFAULT_LIMIT: usize = 100;
+        const MAX_LIMIT: usize = 1000;
+
+        let Some(System {
+            contract_cluster: Some(cluster),
+            contracts,
+            ..
+        }) = &self.system
+        else {
+            return Ok(Default::default());
+        };
+        let code_hash = if request.code_hash.is_empty() {
+            None
+        } else {
+            let raw = try_decode_hex(&request.code_hash)
+                .map_err(|_| from_display("Invalid code hash"))?;
+            Some(hex(raw))
+        };
+        let sidevm_state = match request.sidevm_state.as_str() {
+            "" => None,
+            "none" => Some(""),
+            state @ ("running" | "stopped") => Some(state),
+            _ => return Err(from_display("Invalid sidevm state")),
+        };
+        let mut summaries: Vec<_> = contracts
+            .iter()
+            .map(|(_, contract)| contract.summary(cluster))
+            .filter(|summary| {
+                code_hash.as_ref().map_or(true, |h| &summary.code_hash == h)
+                    && sidevm_state.map_or(true, |state| summary.sidevm_state == state)
+            })
+            .collect();
+        // Contracts are iterated in the order of their address, keep it as the secondary order.
+        match request.sort_by.as_str() {
+            "" | "address" => {}
+            "weight" => summaries.sort_by_key(|summary| summary.weight),
+            "last_active_block" => summaries.sort_by_key(|summary| summary.last_active_block),
+            _ => return Err(from_display("Invalid sort key")),
+        }
+        if request.descending {
+            summaries.reverse();
+        }
+        let limit = match request.limit as usize {
+            0 => DEFAULT_LIMIT,
+            limit => limit.min(MAX_LIMIT),
+        };
+        let total = summaries.len() as u32;
+        Ok(pb::ListContractsResponse {
+            contracts: summaries
+                .into_iter()
+                .skip(request.offset as usize)
+                .take(limit)
+                .collect(),
+            total,
+        })
+    }
+
     pub fn upload_sidevm_code(&mut self, contract_id: AccountId, code: Vec<u8>) -> RpcResult<()> {
         let spawner = self.sidevm_spawner.clone();
         self.system()?
@@ -1414,6 +1771,9 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
         let blocks = request.decode_blocks()?;
         let mut phactory = {
             let mut phactory = self.lock_phactory(false, true)?;
+            if phactory.args.safe_mode {
+                return Err(from_display("Dispatching blocks is disabled in safe mode"));
+            }
             if phactory.args.no_rcu || benchmark::syncing() {
                 // If RCU way is not suitable here, we do the traditional locked dispatch.
                 return phactory.dispatch_blocks(self.req_id, blocks);
@@ -1483,6 +1843,14 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
             .map(pb::GetEgressMessagesResponse::new)
     }
 
+    async fn get_egress_queue_depth(
+        &mut self,
+        _: (),
+    ) -> RpcResult<pb::GetEgressQueueDepthResponse> {
+        let depths = self.lock_phactory(true, false)?.get_egress_queue_depth();
+        Ok(pb::GetEgressQueueDepthResponse::new(depths))
+    }
+
     async fn contract_query(
         &mut self,
         request: pb::ContractQueryRequest,
@@ -2069,8 +2437,9 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
         &mut self,
         request: super::NetworkConfig,
     ) -> Result<(), prpc::server::Error> {
-        self.lock_phactory(false, false)?.set_netconfig(request);
-        Ok(())
+        self.lock_phactory(false, false)?
+            .set_netconfig(request)
+            .map_err(from_debug)
     }
 
     async fn http_fetch(
@@ -2213,7 +2582,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
     }
     async fn take_checkpoint(&mut self, _req: ()) -> Result<pb::SyncedTo, prpc::server::Error> {
         let synced_to = self
-            .lock_phactory(false, false)?
+            .lock_phactory(false, true)?
             .take_checkpoint()
             .map_err(from_debug)?;
         Ok(pb::SyncedTo { synced_to })
@@ -2304,6 +2673,147 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi for Rpc
         cluster.on_idle(block_number);
         Ok(())
     }
+
+    async fn get_contract_events(
+        &mut self,
+        request: pb::GetContractEventsRequest,
+    ) -> Result<pb::GetContractEventsResponse, prpc::server::Error> {
+        self.lock_phactory(true, false)?
+            .get_contract_events(request)
+    }
+
+    async fn list_contracts(
+        &mut self,
+        request: pb::ListContractsRequest,
+    ) -> Result<pb::ListContractsResponse, prpc::server::Error> {
+        self.lock_phactory(true, false)?.list_contracts(request)
+    }
+
+    async fn get_enclave_identity(&mut self, _: ()) -> RpcResult<pb::EnclaveIdentityResponse> {
+        self.lock_phactory(true, false)?.get_enclave_identity()
+    }
+
+    async fn dry_run_instantiate(
+        &mut self,
+        request: pb::DryRunInstantiateRequest,
+    ) -> RpcResult<pb::DryRunInstantiateResponse> {
+        let dry_run = self
+            .lock_phactory(true, false)?
+            .dry_run_instantiate(self.req_id, request)?;
+        dry_run.await
+    }
+
+    async fn get_query_response_chunk(
+        &mut self,
+        request: pb::QueryResponseChunkRequest,
+    ) -> RpcResult<pb::QueryResponseChunk> {
+        self.lock_phactory(true, false)?
+            .get_query_response_chunk(request)
+    }
+
+    async fn get_dispatch_audit(
+        &mut self,
+        request: pb::GetDispatchAuditRequest,
+    ) -> RpcResult<pb::DispatchAudit> {
+        self.lock_phactory(true, false)?.get_dispatch_audit(request)
+    }
+}
+
+/// The arguments of a dry-run instantiation, decoded from the RPC request.
+struct DryRunInstantiateArgs {
+    deployer: [u8; 32],
+    code_hash: [u8; 32],
+    constructor: Vec<u8>,
+    salt: Vec<u8>,
+    transfer: u128,
+}
+
+impl DryRunInstantiateArgs {
+    fn decode(request: pb::DryRunInstantiateRequest) -> RpcResult<Self> {
+        let deployer: [u8; 32] = try_decode_hex(&request.deployer)
+            .ok()
+            .and_then(|raw| raw.try_into().ok())
+            .ok_or_else(|| from_display("Invalid deployer"))?;
+        let code_hash: [u8; 32] = try_decode_hex(&request.code_hash)
+            .ok()
+            .and_then(|raw| raw.try_into().ok())
+            .ok_or_else(|| from_display("Invalid code hash"))?;
+        let salt = try_decode_hex(&request.salt).map_err(|_| from_display("Invalid code salt"))?;
+        let transfer = if request.transfer.is_empty() {
+            0
+        } else {
+            request
+                .transfer
+                .parse::<u128>()
+                .map_err(|_| from_display("Invalid transfer"))?
+        };
+        Ok(Self {
+            deployer,
+            code_hash,
+            constructor: request.constructor,
+            salt,
+            transfer,
+        })
+    }
+}
+
+/// Converts the outcome of a dry-run instantiation into the RPC response.
+fn dry_run_instantiate_response(
+    address: &AccountId,
+    output: &[u8],
+    effects: Option<ExecSideEffects>,
+    block_number: chain::BlockNumber,
+) -> RpcResult<pb::DryRunInstantiateResponse> {
+    use crate::contract_result::{ContractResult, InstantiateReturnValue, StorageDeposit};
+
+    let result = ContractResult::<InstantiateReturnValue>::decode(&mut &output[..])
+        .map_err(|_| from_display("Invalid contract instantiate result"))?;
+    let (success, error) = match &result.result {
+        Ok(value) if value.flags & 1 == 0 => (true, String::new()),
+        Ok(value) => (false, format!("Reverted: {}", hex(&value.data))),
+        Err(err) => (false, format!("{err:?}")),
+    };
+    let (storage_deposit, storage_deposit_is_charge) = match result.storage_deposit {
+        StorageDeposit::Charge(value) => (value, true),
+        StorageDeposit::Refund(value) => (value, false),
+    };
+    let mut response = pb::DryRunInstantiateResponse {
+        address: hex(address),
+        success,
+        error,
+        gas_consumed: result.gas_consumed.ref_time,
+        gas_required: result.gas_required.ref_time,
+        storage_deposit: storage_deposit.to_string(),
+        storage_deposit_is_charge,
+        debug_message: String::from_utf8_lossy(&result.debug_message).into(),
+        ..Default::default()
+    };
+    if let Some(ExecSideEffects::V1 {
+        pink_events,
+        ink_events,
+        instantiated,
+    }) = effects
+    {
+        response.events = ink_events
+            .into_iter()
+            .map(|(contract, topics, payload)| pb::ContractEvent {
+                sequence: 0,
+                block_number,
+                contract: hex(&contract),
+                topics: topics.iter().map(hex).collect(),
+                payload,
+            })
+            .collect();
+        response.pink_events = pink_events
+            .iter()
+            .map(|(_, event)| event.name().into())
+            .collect();
+        response.instantiated = instantiated
+            .iter()
+            .map(|(_, address)| hex(address))
+            .collect();
+    }
+    Ok(response)
 }
 
 fn measurement_of(report: &sgx_api_lite::Report) -> Vec<u8> {
@@ -2318,6 +2828,18 @@ fn measurement_of(report: &sgx_api_lite::Report) -> Vec<u8> {
     ias_fields.extend_mrenclave()
 }
 
+/// Reads the measurement from a local report. Returns None if not running in SGX.
+fn my_sgx_measurement() -> Option<SgxMeasurement> {
+    let target_info = sgx_api_lite::target_info().ok()?;
+    let report = sgx_api_lite::report(&target_info, &[0; 64]).ok()?;
+    Some(SgxMeasurement {
+        mr_enclave: report.body.mr_enclave.m,
+        mr_signer: report.body.mr_signer.m,
+        isv_prod_id: report.body.isv_prod_id,
+        isv_svn: report.body.isv_svn,
+    })
+}
+
 fn my_measurement() -> Vec<u8> {
     let my_la_report = {
         // target_info and reportdata not important, we just need the report metadata
@@ -2326,3 +2848,137 @@ fn my_measurement() -> Vec<u8> {
     };
     measurement_of(&my_la_report)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract_result::{ContractResult, InstantiateReturnValue, StorageDeposit, Weight};
+    use pink_loader::types::PinkEvent;
+
+    fn dry_run_request() -> pb::DryRunInstantiateRequest {
+        pb::DryRunInstantiateRequest {
+            deployer: hex([1u8; 32]),
+            code_hash: hex([2u8; 32]),
+            constructor: vec![0xde, 0xad, 0xbe, 0xef],
+            salt: "0x0102".into(),
+            transfer: String::new(),
+        }
+    }
+
+    fn instantiate_output(
+        result: Result<InstantiateReturnValue, sp_runtime::DispatchError>,
+        storage_deposit: StorageDeposit,
+    ) -> Vec<u8> {
+        ContractResult {
+            gas_consumed: Weight {
+                ref_time: 100,
+                proof_size: 1,
+            },
+            gas_required: Weight {
+                ref_time: 200,
+                proof_size: 2,
+            },
+            storage_deposit,
+            debug_message: b"hello".to_vec(),
+            result,
+        }
+        .encode()
+    }
+
+    fn return_value(flags: u32) -> InstantiateReturnValue {
+        InstantiateReturnValue {
+            flags,
+            data: vec![0x42],
+            account_id: [3; 32],
+        }
+    }
+
+    #[test]
+    fn dry_run_request_is_decoded() {
+        let args = DryRunInstantiateArgs::decode(dry_run_request()).unwrap();
+        assert_eq!(args.deployer, [1; 32]);
+        assert_eq!(args.code_hash, [2; 32]);
+        assert_eq!(args.constructor, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(args.salt, vec![1, 2]);
+        assert_eq!(args.transfer, 0);
+
+        let request = pb::DryRunInstantiateRequest {
+            transfer: "1000000000000".into(),
+            ..dry_run_request()
+        };
+        let args = DryRunInstantiateArgs::decode(request).unwrap();
+        assert_eq!(args.transfer, 1_000_000_000_000);
+    }
+
+    #[test]
+    fn invalid_dry_run_requests_are_rejected() {
+        let requests = [
+            pb::DryRunInstantiateRequest {
+                deployer: "0x0102".into(),
+                ..dry_run_request()
+            },
+            pb::DryRunInstantiateRequest {
+                code_hash: "not hex".into(),
+                ..dry_run_request()
+            },
+            pb::DryRunInstantiateRequest {
+                salt: "0x0".into(),
+                ..dry_run_request()
+            },
+            pb::DryRunInstantiateRequest {
+                transfer: "-1".into(),
+                ..dry_run_request()
+            },
+        ];
+        for request in requests {
+            assert!(DryRunInstantiateArgs::decode(request).is_err());
+        }
+    }
+
+    #[test]
+    fn dry_run_response_reports_the_cost_and_effects() {
+        let address = AccountId::new([3; 32]);
+        let output = instantiate_output(Ok(return_value(0)), StorageDeposit::Charge(500));
+        let effects = ExecSideEffects::V1 {
+            pink_events: vec![(address.clone(), PinkEvent::StopSidevm)],
+            ink_events: vec![(address.clone(), vec![Default::default()], vec![1, 2, 3])],
+            instantiated: vec![(AccountId::new([1; 32]), address.clone())],
+        };
+        let response = dry_run_instantiate_response(&address, &output, Some(effects), 10).unwrap();
+        assert_eq!(response.address, hex([3u8; 32]));
+        assert!(response.success);
+        assert_eq!(response.error, "");
+        assert_eq!(response.gas_consumed, 100);
+        assert_eq!(response.gas_required, 200);
+        assert_eq!(response.storage_deposit, "500");
+        assert!(response.storage_deposit_is_charge);
+        assert_eq!(response.debug_message, "hello");
+        assert_eq!(response.events.len(), 1);
+        assert_eq!(response.events[0].block_number, 10);
+        assert_eq!(response.events[0].contract, hex([3u8; 32]));
+        assert_eq!(response.events[0].payload, vec![1, 2, 3]);
+        assert_eq!(response.pink_events, vec!["StopSidevm".to_string()]);
+        assert_eq!(response.instantiated, vec![hex([3u8; 32])]);
+    }
+
+    #[test]
+    fn dry_run_response_reports_failures() {
+        let address = AccountId::new([3; 32]);
+        let output = instantiate_output(Ok(return_value(1)), StorageDeposit::Refund(7));
+        let response = dry_run_instantiate_response(&address, &output, None, 10).unwrap();
+        assert!(!response.success);
+        assert_eq!(response.error, "Reverted: 0x42");
+        assert_eq!(response.storage_deposit, "7");
+        assert!(!response.storage_deposit_is_charge);
+
+        let output = instantiate_output(
+            Err(sp_runtime::DispatchError::Other("boom")),
+            StorageDeposit::Charge(0),
+        );
+        let response = dry_run_instantiate_response(&address, &output, None, 10).unwrap();
+        assert!(!response.success);
+        assert!(!response.error.is_empty());
+
+        assert!(dry_run_instantiate_response(&address, &[0xff], None, 10).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Size limit of the contract query responses and the chunked retrieval of the large ones.
+//!
+//! The response of a contract query is buffered, encrypted and sent as a whole, so a contract
+//! returning a huge payload, e.g. a file, could exhaust the output buffers of the enclave. The
+//! encoded response is limited by `--max-query-response-kb`. A larger one fails with
+//! [`QueryError::ResponseTooLarge`](phactory_api::contracts::QueryError), unless the query asked
+//! for a chunked response: the encrypted response is then parked here and read back with
+//! `GetQueryResponseChunk`, at most the limit at a time.
+//!
+//! The parked responses expire after [`RESPONSE_TTL`] and their total size is bounded, so that
+//! the unread ones can't pile up.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The time a parked response is kept after its last read.
+pub const RESPONSE_TTL: Duration = Duration::from_secs(60);
+/// The total size of the parked responses.
+const MAX_PARKED_SIZE: usize = 64 * 1024 * 1024;
+
+struct Parked {
+    data: Vec<u8>,
+    touched_at: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    responses: BTreeMap<u64, Parked>,
+    size: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, handle: u64) {
+        if let Some(parked) = self.responses.remove(&handle) {
+            self.size -= parked.data.len();
+        }
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .responses
+            .iter()
+            .filter(|(_, parked)| now.duration_since(parked.touched_at) >= RESPONSE_TTL)
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in expired {
+            self.remove(handle);
+        }
+    }
+}
+
+/// The responses waiting to be read in chunks.
+#[derive(Clone, Default)]
+pub struct ResponseChunks {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ResponseChunks {
+    /// Parks the encoded encrypted response, returns the handle to read it with, or None if
+    /// there is no room left.
+    pub fn park(&self, data: Vec<u8>) -> Option<u64> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.evict_expired(now);
+        if inner.size + data.len() > MAX_PARKED_SIZE {
+            return None;
+        }
+        // Random handles, so that the response of a client can't be consumed by another one.
+        let handle = loop {
+            let handle = rand::random::<u64>();
+            if !inner.responses.contains_key(&handle) {
+                break handle;
+            }
+        };
+        inner.size += data.len();
+        inner.responses.insert(
+            handle,
+            Parked {
+                data,
+                touched_at: now,
+            },
+        );
+        Some(handle)
+    }
+
+    /// Reads at most `max_len` bytes of the response from `offset`, with the total size of the
+    /// response. The response is dropped once its end is read.
+    pub fn read(&self, handle: u64, offset: u64, max_len: usize) -> Option<(Vec<u8>, u64)> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.evict_expired(now);
+        let parked = inner.responses.get_mut(&handle)?;
+        parked.touched_at = now;
+        let total_size = parked.data.len();
+        let start = (offset as usize).min(total_size);
+        let end = start.saturating_add(max_len.max(1)).min(total_size);
+        let chunk = parked.data[start..end].to_vec();
+        if end == total_size {
+            inner.remove(handle);
+        }
+        Some((chunk, total_size as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_in_chunks_until_the_end() {
+        let chunks = ResponseChunks::default();
+        let handle = chunks.park((0..10).collect()).unwrap();
+        assert_eq!(chunks.read(handle, 0, 4), Some((vec![0, 1, 2, 3], 10)));
+        assert_eq!(chunks.read(handle, 4, 4), Some((vec![4, 5, 6, 7], 10)));
+        assert_eq!(chunks.read(handle, 8, 4), Some((vec![8, 9], 10)));
+        assert_eq!(chunks.read(handle, 0, 4), None);
+        assert_eq!(chunks.inner.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn rejects_beyond_the_parked_size() {
+        let chunks = ResponseChunks::default();
+        assert!(chunks.park(vec![0; MAX_PARKED_SIZE]).is_some());
+        assert!(chunks.park(vec![0]).is_none());
+    }
+}
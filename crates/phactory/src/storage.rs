@@ -30,9 +30,13 @@ impl BlockValidator for LightValidation<chain::Runtime> {
 mod storage_ext {
     use crate::chain;
     use chain::{pallet_computation, pallet_mq, pallet_phat, pallet_registry};
+    use parity_scale_codec::DecodeAll;
     use phala_mq::{ContractClusterId, Message, MessageOrigin};
     use phala_trie_storage::TrieStorage;
-    use phala_types::messaging::TokenomicParameters;
+    use phala_types::{
+        contract::{ExtensionPolicy, LogPolicy},
+        messaging::TokenomicParameters,
+    };
     use serde::{Deserialize, Serialize};
     use sp_state_machine::{Ext, OverlayedChanges};
 
@@ -80,6 +84,11 @@ mod storage_ext {
             self.trie_storage.root()
         }
 
+        /// Approximate heap size of the storage.
+        pub fn approx_size(&self) -> usize {
+            self.trie_storage.approx_size()
+        }
+
         pub fn inner(&self) -> &TrieStorage<crate::RuntimeHasher> {
             &self.trie_storage
         }
@@ -88,6 +97,16 @@ mod storage_ext {
             &mut self.trie_storage
         }
 
+        /// Generates a proof of the values of `keys` against the current state root.
+        pub fn read_proof(&self, keys: &[Vec<u8>]) -> anyhow::Result<Vec<Vec<u8>>> {
+            let proof = sp_state_machine::prove_read_on_trie_backend(
+                self.trie_storage.as_trie_backend(),
+                keys,
+            )
+            .map_err(|err| anyhow::anyhow!("Failed to generate the read proof: {err}"))?;
+            Ok(proof.into_iter_nodes().collect())
+        }
+
         pub fn execute_with<R>(&self, f: impl FnOnce() -> R) -> R {
             let backend = self.trie_storage.as_trie_backend();
             let mut overlay = OverlayedChanges::default();
@@ -107,6 +126,15 @@ mod storage_ext {
             self.execute_with(chain::Timestamp::now)
         }
 
+        /// The timestamp of the block, None if it is missing or fails to decode, in which cases
+        /// `timestamp_now` silently returns 0.
+        pub fn try_timestamp_now(&self) -> Option<chain::Moment> {
+            let key = [sp_core::twox_128(b"Timestamp"), sp_core::twox_128(b"Now")].concat();
+            let value = self.execute_with(|| sp_io::storage::get(&key))?;
+            let now = chain::Moment::decode_all(&mut &value[..]).ok()?;
+            (now > 0).then_some(now)
+        }
+
         pub fn pink_system_code(&self) -> (u16, Vec<u8>) {
             self.execute_with(pallet_phat::PinkSystemCode::<chain::Runtime>::get)
         }
@@ -143,6 +171,25 @@ mod storage_ext {
                 .is_some()
         }
 
+        pub fn worker_confidence_level(&self, worker: &phala_types::WorkerPublicKey) -> Option<u8> {
+            self.execute_with(|| pallet_registry::Workers::<chain::Runtime>::get(worker))
+                .map(|info| info.confidence_level)
+        }
+
+        /// The number of reward halvings since the computing started, at the given block. None if
+        /// the halving is not configured on chain.
+        pub fn halving_period(&self, block: chain::BlockNumber) -> Option<u32> {
+            let start =
+                self.execute_with(pallet_computation::ComputingStartBlock::<chain::Runtime>::get)?;
+            let interval = self.execute_with(
+                pallet_computation::ComputingHalvingInterval::<chain::Runtime>::get,
+            )?;
+            if interval == 0 {
+                return None;
+            }
+            Some(block.saturating_sub(start) / interval)
+        }
+
         pub(crate) fn minimum_pruntime_version(&self) -> (u32, u32, u32) {
             self.execute_with(pallet_registry::MinimumPRuntimeVersion::<chain::Runtime>::get)
         }
@@ -172,6 +219,44 @@ mod storage_ext {
             self.execute_with(|| pallet_phat::ClusterByWorkers::<chain::Runtime>::get(worker))
         }
 
+        pub(crate) fn cluster_log_policy(&self, cluster: &ContractClusterId) -> LogPolicy {
+            self.execute_with(|| pallet_phat::ClusterLogPolicies::<chain::Runtime>::get(cluster))
+        }
+
+        pub(crate) fn cluster_extension_policy(
+            &self,
+            cluster: &ContractClusterId,
+        ) -> ExtensionPolicy {
+            self.execute_with(|| {
+                pallet_phat::ClusterExtensionPolicies::<chain::Runtime>::get(cluster)
+            })
+        }
+
+        /// The block interval of the coordinated checkpoints of the cluster, if set on chain.
+        pub(crate) fn cluster_checkpoint_interval(
+            &self,
+            cluster: &ContractClusterId,
+        ) -> Option<u32> {
+            self.execute_with(|| {
+                pallet_phat::ClusterCheckpointIntervals::<chain::Runtime>::get(cluster)
+            })
+        }
+
+        /// The gas budget of each block under which the `OnIdle` hooks of the cluster are called.
+        pub(crate) fn cluster_idle_budget(&self, cluster: &ContractClusterId) -> Option<u64> {
+            self.execute_with(|| pallet_phat::ClusterIdleBudgets::<chain::Runtime>::get(cluster))
+        }
+
+        /// The gas at which each contract execution in a transaction of the cluster is aborted.
+        pub(crate) fn cluster_execution_deadline(
+            &self,
+            cluster: &ContractClusterId,
+        ) -> Option<u64> {
+            self.execute_with(|| {
+                pallet_phat::ClusterExecutionDeadlines::<chain::Runtime>::get(cluster)
+            })
+        }
+
         pub(crate) fn trusted_ntp_servers(&self) -> Vec<String> {
             self.execute_with(pallet_registry::TrustedNtpServers::<chain::Runtime>::get)
         }
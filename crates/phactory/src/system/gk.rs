@@ -9,7 +9,7 @@ use phala_mq::{traits::MessageChannel, MessageDispatcher, Sr25519Signer};
 use phala_serde_more as more;
 use phala_types::{
     contract::{
-        messaging::{ClusterEvent, ClusterOperation},
+        messaging::{BatchRefreshClusterKeyEvent, ClusterEvent, ClusterKeyShare, ClusterOperation},
         ContractClusterId,
     },
     messaging::{
@@ -71,6 +71,39 @@ fn get_cluster_key(master_key: &sr25519::Pair, cluster: &ContractClusterId) -> s
         .expect("should not fail with valid info")
 }
 
+/// Splits the cluster key into fresh additive shares, one for each worker.
+///
+/// The shares are derived from the master key and the refresh id, so that every gatekeeper makes
+/// the same shares while the shares of different refreshes are unrelated.
+fn split_cluster_key(
+    master_key: &sr25519::Pair,
+    cluster_key: &sr25519::Pair,
+    cluster: &ContractClusterId,
+    refresh_id: u64,
+    workers: &[WorkerPublicKey],
+) -> Vec<sr25519::Pair> {
+    let mut shares: Vec<_> = workers
+        .iter()
+        .map(|worker| {
+            master_key
+                .derive_sr25519_pair(&[
+                    b"cluster_key_share",
+                    cluster.as_bytes(),
+                    &refresh_id.to_be_bytes(),
+                    &worker.0,
+                ])
+                .expect("should not fail with valid info")
+                .dump_secret_key()
+        })
+        .collect();
+    key_share::split_secret_into(&cluster_key.dump_secret_key(), &mut shares)
+        .expect("should not fail with valid keys");
+    shares
+        .iter()
+        .map(sr25519::Pair::restore_from_secret_key)
+        .collect()
+}
+
 #[cfg(feature = "gk-stat")]
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 struct WorkerStat {
@@ -113,6 +146,14 @@ impl WorkerInfo {
     pub fn pubkey(&self) -> &WorkerPublicKey {
         &self.state.pubkey
     }
+
+    /// The id of the computing session assigned on chain, None if the worker is not computing.
+    pub fn session_id(&self) -> Option<u32> {
+        self.state
+            .working_state
+            .as_ref()
+            .map(|state| state.session_id)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, ::scale_info::TypeInfo)]
@@ -526,6 +567,74 @@ where
                 );
                 Ok(())
             }
+            ClusterEvent::RefreshClusterKey {
+                cluster,
+                pubkey,
+                workers,
+                refresh_id,
+            } => {
+                if !origin.is_pallet() {
+                    error!("Attempt to refresh cluster key from bad origin");
+                    return Err(TransactionError::BadOrigin);
+                }
+                if workers.is_empty() {
+                    error!("No worker to share the key of cluster {:?}", cluster);
+                    return Err(TransactionError::BadInput);
+                }
+                // The cluster key was derived from the master key in use when the cluster was
+                // deployed, which may have been rotated since then.
+                let cluster_key = std::iter::once(self.master_key.clone())
+                    .chain(
+                        self.master_key_history
+                            .iter()
+                            .rev()
+                            .map(|key| sr25519::Pair::restore_from_secret_key(&key.secret)),
+                    )
+                    .map(|master_key| get_cluster_key(&master_key, &cluster))
+                    .find(|cluster_key| cluster_key.public() == pubkey)
+                    .ok_or(TransactionError::ClusterKeyMismatch)?;
+                let worker_pubkeys: Vec<_> = workers.iter().map(|worker| worker.pubkey).collect();
+                let shares = split_cluster_key(
+                    &self.master_key,
+                    &cluster_key,
+                    &cluster,
+                    refresh_id,
+                    &worker_pubkeys,
+                );
+                let shares: BTreeMap<_, _> = workers
+                    .into_iter()
+                    .zip(shares)
+                    .map(|(worker, share)| {
+                        let encrypted_key = self.encrypt_key_to(
+                            &[b"cluster_key_sharing"],
+                            &worker.ecdh_pubkey,
+                            &share.dump_secret_key(),
+                            block.block_number,
+                        );
+                        let share = ClusterKeyShare {
+                            encrypted_key,
+                            pubkey: share.public(),
+                        };
+                        (worker.pubkey, share)
+                    })
+                    .collect();
+                info!(
+                    "Refreshing key of cluster {:?} with {} shares, refresh_id={}",
+                    cluster,
+                    shares.len(),
+                    refresh_id
+                );
+                self.egress
+                    .push_message(&ClusterOperation::<chain::AccountId>::RefreshKeys(
+                        BatchRefreshClusterKeyEvent {
+                            refresh_id,
+                            cluster,
+                            pubkey,
+                            shares,
+                        },
+                    ));
+                Ok(())
+            }
         }
     }
 
@@ -1143,6 +1252,25 @@ impl<MsgChan: MessageChannel<Signer = Sr25519Signer>> ComputingEconomics<MsgChan
             .map(|info| info.tokenomic.share())
             .sum()
     }
+
+    /// Estimates the payout each working worker would get if it sent a heartbeat at the given
+    /// block, without changing any state.
+    pub fn estimate_payouts(
+        &self,
+        block_number: chain::BlockNumber,
+    ) -> Vec<(WorkerPublicKey, FixedPoint)> {
+        let sum_share = self.sum_share();
+        self.workers
+            .values()
+            .filter(|info| !info.unresponsive && info.state.working_state.is_some())
+            .map(|info| {
+                let payout =
+                    info.tokenomic
+                        .estimate_payout(&self.tokenomic_params, sum_share, block_number);
+                (info.state.pubkey, payout)
+            })
+            .collect()
+    }
 }
 
 struct WorkerSMTracker<'a> {
@@ -1348,32 +1476,56 @@ mod tokenomic {
             self.v_deductible += delta_v;
         }
 
-        /// case2: Idle, successful heartbeat
-        /// return payout
-        pub fn update_v_heartbeat(
-            &mut self,
+        /// The budget released by a heartbeat at the given block, if any.
+        fn heartbeat_budget(
+            &self,
             params: &Params,
             sum_share: FixedPoint,
-            now_ms: u64,
             block_number: u32,
-        ) -> (FixedPoint, FixedPoint) {
-            const NO_UPDATE: (FixedPoint, FixedPoint) = (fp!(0), fp!(0));
+        ) -> Option<FixedPoint> {
             if sum_share == fp!(0) {
-                return NO_UPDATE;
+                return None;
             }
             if self.v_deductible == fp!(0) {
-                return NO_UPDATE;
+                return None;
             }
             if block_number <= self.v_update_block {
                 // May receive more than one heartbeat for a single worker in a single block.
-                return NO_UPDATE;
+                return None;
             }
             let share = self.share();
             if share == fp!(0) {
-                return NO_UPDATE;
+                return None;
             }
             let blocks = FixedPoint::from_num(block_number - self.v_update_block);
-            let budget = share / sum_share * params.budget_per_block * blocks;
+            Some(share / sum_share * params.budget_per_block * blocks)
+        }
+
+        /// The payout of a successful heartbeat at the given block, without updating the state.
+        pub fn estimate_payout(
+            &self,
+            params: &Params,
+            sum_share: FixedPoint,
+            block_number: u32,
+        ) -> FixedPoint {
+            self.heartbeat_budget(params, sum_share, block_number)
+                .map(|budget| budget * params.payout_ration)
+                .unwrap_or(fp!(0))
+        }
+
+        /// case2: Idle, successful heartbeat
+        /// return payout
+        pub fn update_v_heartbeat(
+            &mut self,
+            params: &Params,
+            sum_share: FixedPoint,
+            now_ms: u64,
+            block_number: u32,
+        ) -> (FixedPoint, FixedPoint) {
+            const NO_UPDATE: (FixedPoint, FixedPoint) = (fp!(0), fp!(0));
+            let Some(budget) = self.heartbeat_budget(params, sum_share, block_number) else {
+                return NO_UPDATE;
+            };
             let actual_payout = budget * params.payout_ration;
             let actual_treasury = budget * params.treasury_ration;
 
@@ -2325,4 +2477,29 @@ pub mod tests {
         let fp_de: Wrapper = ciborium::de::from_reader(&*buf).unwrap();
         assert_eq!(fp.0, fp_de.0);
     }
+
+    #[test]
+    fn cluster_key_shares_add_up_to_the_cluster_key() {
+        use phala_crypto::key_share::combine_public_keys;
+        use sp_core::{sr25519, Pair};
+
+        let master_key = sr25519::Pair::from_seed(&[1; 32]);
+        let cluster = Default::default();
+        let cluster_key = super::get_cluster_key(&master_key, &cluster);
+        let workers: Vec<WorkerPublicKey> = (0..3).map(|i| sr25519::Public([i; 32])).collect();
+        let split = |refresh_id| {
+            super::split_cluster_key(&master_key, &cluster_key, &cluster, refresh_id, &workers)
+        };
+
+        let shares = split(1);
+        assert_eq!(shares.len(), workers.len());
+        let pubkeys: Vec<_> = shares.iter().map(|share| share.public().0).collect();
+        assert_eq!(
+            combine_public_keys(&pubkeys).unwrap(),
+            cluster_key.public().0
+        );
+        // Every gatekeeper makes the same shares, and every refresh makes new ones.
+        assert_eq!(split(1)[0].public(), shares[0].public());
+        assert_ne!(split(2)[0].public(), shares[0].public());
+    }
 }
@@ -1,9 +1,11 @@
 pub mod gk;
 mod master_key;
+mod query_history;
 
 use crate::{
     benchmark,
     contracts::{ContractsKeeper, ExecuteEnv, SidevmCode},
+    memory_budget::{self, Pressure, SubsystemUsage},
     pink::{Cluster, ClusterContainer},
     secret_channel::{ecdh_serde, SecretReceiver},
     types::{deopaque_query, BlockInfo, OpaqueError, OpaqueQuery},
@@ -15,6 +17,7 @@ use phactory_api::contracts::{Query, QueryError, QueryType, Response};
 use phala_scheduler::RequestScheduler;
 use pink_loader::{
     capi::v1::ecall::{ClusterSetupConfig, ECalls},
+    constants::WEIGHT_REF_TIME_PER_SECOND,
     local_cache,
     types::{
         AccountId, ExecSideEffects, ExecutionMode, HookPoint, PinkEvent, TransactionArguments,
@@ -43,19 +46,21 @@ use phala_types::{
     contract::{
         self,
         messaging::{
-            BatchDispatchClusterKeyEvent, ClusterOperation, ContractOperation, ResourceType,
-            WorkerClusterReport,
+            BatchDispatchClusterKeyEvent, BatchRefreshClusterKeyEvent, ClusterOperation,
+            ContractOperation, ResourceType, WorkerClusterReport,
         },
-        CodeIndex, ConvertTo,
+        CodeIndex, ConvertTo, LogPolicy,
     },
     messaging::{
-        AeadIV, BatchRotateMasterKeyEvent, DispatchMasterKeyEvent, DispatchMasterKeyHistoryEvent,
-        GatekeeperChange, GatekeeperLaunch, HeartbeatChallenge, KeyDistribution,
-        NewGatekeeperEvent, RemoveGatekeeperEvent, RotateMasterKeyEvent, SystemEvent, WorkerEvent,
-        WorkingReportEvent,
+        AeadIV, BatchRotateMasterKeyEvent, BindTopic, DispatchMasterKeyEvent,
+        DispatchMasterKeyHistoryEvent, GatekeeperChange, GatekeeperLaunch, HeartbeatChallenge,
+        KeyDistribution, MessageReceipts, MqUsageReport, NewGatekeeperEvent, RemoveGatekeeperEvent,
+        RotateMasterKeyEvent, SystemEvent, WorkerEvent, WorkingReportEvent,
+        MAX_USAGE_REPORT_SENDERS, MQ_USAGE_WINDOW,
     },
     wrap_content_to_sign, AttestationProvider, EcdhPublicKey, SignedContentType, WorkerPublicKey,
 };
+use query_history::{HistoricalState, QueryHistory};
 use serde::{Deserialize, Serialize};
 use sidevm::{
     service::{Command as SidevmCommand, CommandSender, Spawner, SystemMessage},
@@ -66,6 +71,7 @@ use sp_core::{hashing::blake2_256, sr25519, Pair, U256};
 use pink::{SidevmOperation, Workers};
 use std::convert::TryFrom;
 use std::future::Future;
+use std::time::Instant;
 use tracing::{error, info};
 
 pub type TransactionResult = Result<Option<ExecSideEffects>, TransactionError>;
@@ -117,6 +123,7 @@ pub enum TransactionError {
     NoClusterOnGatekeeper,
     NoPinkSystemCode,
     BadPinkSystemVersion,
+    ClusterKeyMismatch,
 }
 
 impl From<BadOrigin> for TransactionError {
@@ -429,6 +436,11 @@ impl WorkerIdentityKey {
 #[serde(transparent)]
 pub(crate) struct ContractKey(#[serde(with = "more::key_bytes")] sr25519::Pair);
 
+/// Restores the receipts subscription for checkpoints taken before it existed.
+fn subscribe_message_receipts() -> TypedReceiver<MessageReceipts> {
+    phala_mq::checkpoint_helper::subscribe_default(MessageReceipts::topic()).into()
+}
+
 fn get_contract_key(cluster_key: &sr25519::Pair, contract_id: &ContractId) -> sr25519::Pair {
     // Introduce deployer in key generation to prevent Replay Attacks
     cluster_key
@@ -451,6 +463,8 @@ pub struct System<Platform> {
     key_distribution_events: TypedReceiver<KeyDistribution<chain::BlockNumber>>,
     cluster_key_distribution_events: TypedReceiver<ClusterOperation<chain::AccountId>>,
     contract_operation_events: TypedReceiver<ContractOperation<chain::Hash, chain::AccountId>>,
+    #[serde(default = "subscribe_message_receipts")]
+    message_receipts: TypedReceiver<MessageReceipts>,
     // Worker
     #[codec(skip)]
     pub(crate) identity_key: WorkerIdentityKey,
@@ -475,6 +489,17 @@ pub struct System<Platform> {
 
     // If non-zero indicates the block which this worker loaded the chain state from.
     pub(crate) genesis_block: BlockNumber,
+
+    /// States of recent blocks for queries at a historical block.
+    #[codec(skip)]
+    #[serde(skip)]
+    query_history: QueryHistory,
+
+    /// The key of a cluster known in advance, used instead of the one dispatched to the worker.
+    /// Only set when replaying a cluster off-chain.
+    #[codec(skip)]
+    #[serde(skip)]
+    preset_cluster_key: Option<(phala_mq::ContractClusterId, sr25519::Pair)>,
 }
 
 impl<Platform: pal::Platform> System<Platform> {
@@ -508,6 +533,7 @@ impl<Platform: pal::Platform> System<Platform> {
             key_distribution_events: recv_mq.subscribe_bound(),
             cluster_key_distribution_events: recv_mq.subscribe_bound(),
             contract_operation_events: recv_mq.subscribe_bound(),
+            message_receipts: recv_mq.subscribe_bound(),
             identity_key,
             ecdh_key,
             worker_state: WorkerState::new(pubkey),
@@ -517,9 +543,21 @@ impl<Platform: pal::Platform> System<Platform> {
             block_number: 0,
             now_ms: 0,
             genesis_block: 0,
+            query_history: Default::default(),
+            preset_cluster_key: None,
         }
     }
 
+    /// Deploys the given cluster with `cluster_key` once it is created on chain, even if the key
+    /// is not dispatched to this worker. For replaying the cluster in dev/testing only.
+    pub(crate) fn preset_cluster_key(
+        &mut self,
+        cluster_id: phala_mq::ContractClusterId,
+        cluster_key: sr25519::Pair,
+    ) {
+        self.preset_cluster_key = Some((cluster_id, cluster_key));
+    }
+
     pub fn get_system_message_handler(&self) -> Option<CommandSender> {
         let handler_contract_id = self
             .contract_cluster
@@ -547,6 +585,8 @@ impl<Platform: pal::Platform> System<Platform> {
         chain_storage: &ChainStorage,
         sidevm_event_tx: OutgoingRequestChannel,
         attestation_provider: Option<AttestationProvider>,
+        at_block: Option<BlockNumber>,
+        deadline: Option<Instant>,
     ) -> Result<
         impl Future<
             Output = Result<
@@ -560,40 +600,71 @@ impl<Platform: pal::Platform> System<Platform> {
         >,
         OpaqueError,
     > {
-        let contract = self
-            .contracts
+        let historical = match at_block {
+            Some(block_number) if block_number != self.block_number => {
+                Some(self.query_history.get(block_number).ok_or_else(|| {
+                    OpaqueError::OtherError(format!(
+                        "Block {block_number} not in query history, available: {:?}",
+                        self.query_history.range()
+                    ))
+                })?)
+            }
+            _ => None,
+        };
+        let (block_number, now_ms, all_contracts, cluster, chain_storage) = match historical {
+            Some(state) => (
+                state.block_number,
+                state.now_ms,
+                &state.contracts,
+                state.cluster.snapshot(),
+                state.chain_storage.snapshot(),
+            ),
+            None => (
+                self.block_number,
+                self.now_ms,
+                &self.contracts,
+                self.contract_cluster
+                    .as_ref()
+                    .expect("BUG: contract cluster should always exists")
+                    .snapshot(),
+                chain_storage.snapshot(),
+            ),
+        };
+        let contract = all_contracts
             .get(contract_id)
             .ok_or(OpaqueError::ContractNotFound)?;
-        let cluster = self
-            .contract_cluster
-            .as_ref()
-            .expect("BUG: contract cluster should always exists")
-            .snapshot();
         let sidevm_handle = contract.sidevm_handle();
         let weight = contract.weight();
         let context = contracts::QueryContext {
-            block_number: self.block_number,
-            now_ms: self.now_ms,
+            block_number,
+            now_ms,
             sidevm_handle,
             log_handler: self.get_system_message_handler(),
             query_scheduler,
             weight,
             worker_identity_key: self.identity_key.clone(),
-            chain_storage: chain_storage.snapshot(),
+            chain_storage,
             req_id,
             sidevm_event_tx,
             attestation_provider,
+            deadline,
         };
         let origin = origin.cloned();
         let query = deopaque_query::<Query>(&query)?;
         let contract_id = contract_id.clone();
-        let contracts = self.contracts.clone();
+        let contracts = all_contracts.clone();
+        let is_historical = historical.is_some();
         Ok(async move {
             let query_type = query.query_type();
+            if memory_budget::pressure() >= Pressure::RejectQueries {
+                return Ok((query_type, Err(QueryError::MemoryPressure), None));
+            }
             let result = cluster
                 .handle_query(&contract_id, origin.as_ref(), query, context, contracts)
                 .await;
             let (result, effects) = match result {
+                // Effects of a query at a historical state must not be applied to the current one.
+                Ok((reply, _)) if is_historical => (Ok(reply), None),
                 Ok((reply, effects)) => (Ok(reply), effects),
                 Err(err) => {
                     log::error!("Contract query error: {:?}", err);
@@ -604,6 +675,86 @@ impl<Platform: pal::Platform> System<Platform> {
         })
     }
 
+    /// Simulates a contract instantiation in a snapshot of the cluster, nothing is committed.
+    ///
+    /// Resolves to the contract address, the encoded `ContractInstantiateResult` and the side
+    /// effects the instantiation would have.
+    #[allow(clippy::too_many_arguments)]
+    pub fn make_dry_run_instantiate(
+        &self,
+        req_id: u64,
+        deployer: AccountId,
+        code_hash: chain::Hash,
+        constructor: Vec<u8>,
+        salt: Vec<u8>,
+        transfer: u128,
+        query_scheduler: RequestScheduler<AccountId>,
+        chain_storage: &ChainStorage,
+        sidevm_event_tx: OutgoingRequestChannel,
+    ) -> Result<
+        impl Future<Output = Result<(AccountId, Vec<u8>, Option<ExecSideEffects>), QueryError>>,
+    > {
+        let mut cluster = self
+            .contract_cluster
+            .as_ref()
+            .ok_or_else(|| anyhow!("No cluster deployed"))?
+            .snapshot();
+        if cluster.system_contract().is_none() {
+            anyhow::bail!("The system contract is missing, Cannot deploy contract");
+        }
+        let address = AccountId::new(blake2_256(&contract::contract_id_preimage(
+            deployer.as_ref(),
+            code_hash.as_ref(),
+            cluster.id.as_ref(),
+            &salt,
+        )));
+        let mut ctx = crate::pink::context::ContractExecContext::new(
+            ExecutionMode::Estimating,
+            self.now_ms,
+            self.block_number,
+            self.identity_key.clone(),
+            chain_storage.snapshot(),
+            req_id,
+            self.contracts.clone(),
+            sidevm_event_tx,
+            None,
+        );
+        Ok(async move {
+            if memory_budget::pressure() >= Pressure::RejectQueries {
+                return Err(QueryError::MemoryPressure);
+            }
+            let _guard = query_scheduler
+                .acquire(address.clone(), 0)
+                .await
+                .or(Err(QueryError::ServiceUnavailable))?;
+            let span = tracing::trace_span!("blocking");
+            tokio::task::spawn_blocking(move || {
+                let _guard = span.enter();
+                crate::pink::context::using(&mut ctx, move || {
+                    let tx_args = TransactionArguments {
+                        origin: deployer,
+                        transfer,
+                        gas_limit: WEIGHT_REF_TIME_PER_SECOND * 10,
+                        gas_free: true,
+                        storage_deposit_limit: None,
+                        deposit: 0,
+                    };
+                    let (result, effects) = cluster.instantiate(
+                        None,
+                        code_hash,
+                        constructor,
+                        salt,
+                        ExecutionMode::Estimating,
+                        tx_args,
+                    );
+                    (address, result, effects)
+                })
+            })
+            .await
+            .map_err(|_| QueryError::RuntimeError("Failed to spawn blocking task".into()))
+        })
+    }
+
     pub fn process_next_message(&mut self, block: &mut BlockInfo) -> anyhow::Result<bool> {
         let ok = phala_mq::select_ignore_errors! {
             (event, origin) = self.system_events => {
@@ -627,6 +778,12 @@ impl<Platform: pal::Platform> System<Platform> {
             (event, origin) = self.contract_operation_events => {
                 self.process_contract_operation_event(block, origin, event)?
             },
+            (event, origin) = self.message_receipts => {
+                if !origin.is_pallet() {
+                    anyhow::bail!("Invalid MessageReceipts sender: {}", origin);
+                }
+                self.process_message_receipts(block, event);
+            },
         };
         Ok(ok.is_none())
     }
@@ -742,6 +899,44 @@ impl<Platform: pal::Platform> System<Platform> {
                     block.storage,
                 );
             }
+            if let Some(budget) = block.storage.cluster_idle_budget(&cluster.id) {
+                let mut remaining = budget.saturating_sub(cluster.block_gas_consumed());
+                let contract_ids: Vec<_> = self
+                    .contracts
+                    .iter()
+                    .filter(|(_, contract)| contract.has_on_idle())
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in contract_ids {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let contract = match self.contracts.get_mut(&key) {
+                        None => continue,
+                        Some(v) => v,
+                    };
+                    let consumed_before = cluster.block_gas_consumed();
+                    let mut env = ExecuteEnv {
+                        block,
+                        contract_cluster: cluster,
+                        log_handler: log_handler.clone(),
+                    };
+                    let result = contract.on_idle(&mut env, remaining);
+                    remaining =
+                        remaining.saturating_sub(cluster.block_gas_consumed() - consumed_before);
+                    handle_contract_command_result(
+                        self.identity_key.public(),
+                        result,
+                        &mut self.contracts,
+                        cluster,
+                        block,
+                        &self.egress,
+                        log_handler.clone(),
+                        block.storage,
+                    );
+                }
+            }
+            cluster.take_block_gas_consumed();
         }
         if self.contracts.weight_changed {
             self.contracts.weight_changed = false;
@@ -752,6 +947,78 @@ impl<Platform: pal::Platform> System<Platform> {
 
         let contract_running = self.contract_cluster.is_some();
         benchmark::set_flag(benchmark::Flags::CONTRACT_RUNNING, contract_running);
+
+        if block.block_number % MQ_USAGE_WINDOW == 0 {
+            self.report_mq_usage(block);
+        }
+    }
+
+    /// Drops the egress messages acknowledged by the chain, instead of waiting for the next
+    /// sequence polling in `purge_mq`.
+    fn process_message_receipts(&mut self, block: &BlockInfo, receipts: MessageReceipts) {
+        debug!(
+            "Received {} message receipts of block {}",
+            receipts.receipts.len(),
+            receipts.block
+        );
+        for (sender, next_sequence) in receipts.receipts {
+            block.send_mq.purge_sender(&sender, next_sequence);
+        }
+    }
+
+    /// Reports the egress traffic of the hosted senders in the window ending at this block.
+    fn report_mq_usage(&mut self, block: &BlockInfo) {
+        let senders: Vec<_> = block.send_mq.take_usage().into_iter().collect();
+        // The usage is taken anyway, so that the traffic before the registration is not reported.
+        if senders.is_empty() || !self.worker_state.registered {
+            return;
+        }
+        for senders in senders.chunks(MAX_USAGE_REPORT_SENDERS as usize) {
+            self.egress.push_message(&MqUsageReport {
+                window_end: block.block_number,
+                senders: senders.to_vec(),
+            });
+        }
+    }
+
+    /// Estimates the heap usage of the subsystems, see [`crate::memory_budget`].
+    pub fn estimate_memory_usage(&self, chain_storage: &ChainStorage) -> SubsystemUsage {
+        SubsystemUsage {
+            chain_storage: chain_storage.approx_size() as u64,
+            contracts: self
+                .contract_cluster
+                .as_ref()
+                .map_or(0, |cluster| cluster.storage.approx_size() as u64),
+            caches: local_cache::total_size() as u64,
+            sidevm: self.contracts.sidevm_reserved_memory(),
+        }
+    }
+
+    /// Releases the memory held by the caches, under memory pressure.
+    pub fn evict_caches(&mut self) {
+        const LOCAL_CACHE_KEEP_PERCENT: usize = 50;
+        self.query_history.clear();
+        local_cache::evict(LOCAL_CACHE_KEEP_PERCENT);
+    }
+
+    /// Keeps the state at the end of the block for queries at a historical block.
+    pub fn record_query_history(&mut self, block: &BlockInfo, capacity: usize) {
+        let Some(cluster) = &self.contract_cluster else {
+            return;
+        };
+        if capacity == 0 || memory_budget::pressure() >= Pressure::EvictCaches {
+            return;
+        }
+        self.query_history.record(
+            HistoricalState {
+                block_number: block.block_number,
+                now_ms: block.now_ms,
+                cluster: cluster.snapshot(),
+                contracts: self.contracts.clone(),
+                chain_storage: block.storage.snapshot(),
+            },
+            capacity,
+        );
     }
 
     fn process_system_event(&mut self, block: &BlockInfo, event: &SystemEvent) {
@@ -1213,6 +1480,106 @@ impl<Platform: pal::Platform> System<Platform> {
                 warn!("If you want to keep providing computation power to some cluster, please create a new worker.");
                 std::process::exit(0);
             }
+            ClusterOperation::SetLogPolicy { cluster_id, policy } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin");
+                }
+                let Some(cluster) = self.contract_cluster.get_cluster_mut(&cluster_id) else {
+                    return Ok(());
+                };
+                info!("Set log policy of cluster {cluster_id:?} to {policy:?}");
+                set_sidevm_log_policy(block.sidevm_spawner, &policy);
+                cluster.config.log_policy = policy;
+            }
+            ClusterOperation::SetExtensionPolicy { cluster_id, policy } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin");
+                }
+                let Some(cluster) = self.contract_cluster.get_cluster_mut(&cluster_id) else {
+                    return Ok(());
+                };
+                info!("Set extension policy of cluster {cluster_id:?} to {policy:?}");
+                cluster.set_extension_policy(policy);
+            }
+            ClusterOperation::UpgradeContract {
+                origin,
+                cluster_id,
+                contract_id,
+                code_hash,
+                call_on_upgrade,
+                gas_limit,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin");
+                }
+                let log_handler = self.get_system_message_handler();
+                let Some(cluster) = self.contract_cluster.get_cluster_mut(&cluster_id) else {
+                    return Ok(());
+                };
+                let tx_args = TransactionArguments {
+                    origin,
+                    transfer: 0,
+                    gas_limit,
+                    gas_free: false,
+                    storage_deposit_limit: None,
+                    deposit: 0,
+                };
+                let (result, effects) = cluster.upgrade_contract(
+                    log_handler.clone(),
+                    contract_id.convert_to(),
+                    code_hash,
+                    call_on_upgrade,
+                    tx_args,
+                );
+                if let Some(effects) = effects {
+                    apply_pink_side_effects(
+                        self.identity_key.public(),
+                        effects,
+                        &mut self.contracts,
+                        cluster,
+                        block,
+                        &self.egress,
+                        log_handler,
+                        block.storage,
+                    );
+                }
+                match result {
+                    Ok(()) => info!("Contract {contract_id:?} upgraded to code {code_hash:?}"),
+                    Err(err) => warn!("Failed to upgrade contract {contract_id:?}: {err}"),
+                }
+            }
+            ClusterOperation::UpdateSidevmCode {
+                origin,
+                cluster_id,
+                contract_id,
+                code_hash,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin");
+                }
+                let Some(cluster) = self.contract_cluster.get_cluster_mut(&cluster_id) else {
+                    return Ok(());
+                };
+                let contract_id: AccountId = contract_id.convert_to();
+                let vmid = sidevm::ShortId(&contract_id);
+                let Some(contract) = self.contracts.get_mut(&contract_id) else {
+                    warn!(target: "sidevm", %vmid, "Sidevm code update for unknown contract");
+                    return Ok(());
+                };
+                let code = match cluster.get_resource(ResourceType::SidevmCode, &code_hash) {
+                    Some(code) => SidevmCode::Code(code),
+                    None => SidevmCode::Hash(code_hash),
+                };
+                match contract.update_sidevm_code(block.sidevm_spawner, code) {
+                    Ok(()) => {
+                        info!(target: "sidevm", %vmid, ?origin, "Sidevm updated to code {code_hash:?}")
+                    }
+                    Err(err) => warn!(target: "sidevm", %vmid, "Failed to update sidevm: {err}"),
+                }
+            }
+            ClusterOperation::RefreshKeys(event) => {
+                self.process_cluster_key_refresh(origin, event)?;
+            }
         }
         Ok(())
     }
@@ -1450,7 +1817,12 @@ impl<Platform: pal::Platform> System<Platform> {
         }
 
         let my_pubkey = self.identity_key.public();
-        if event.secret_keys.contains_key(&my_pubkey) {
+        let preset_key = self
+            .preset_cluster_key
+            .as_ref()
+            .filter(|(id, _)| *id == event.cluster)
+            .map(|(_, key)| key.clone());
+        if preset_key.is_some() || event.secret_keys.contains_key(&my_pubkey) {
             let BatchDispatchClusterKeyEvent {
                 secret_keys,
                 cluster: cluster_id,
@@ -1461,13 +1833,22 @@ impl<Platform: pal::Platform> System<Platform> {
                 deposit_per_byte,
                 treasury_account,
             } = event;
-            let encrypted_key = &secret_keys[&my_pubkey];
-            let cluster_key = self.decrypt_key_from(
-                &encrypted_key.ecdh_pubkey,
-                &encrypted_key.encrypted_key,
-                &encrypted_key.iv,
-            );
-            info!("Worker: successfully decrypt received cluster key");
+            let cluster_key = match preset_key {
+                Some(key) => {
+                    info!("Worker: using the preset key of cluster {cluster_id:?}");
+                    key
+                }
+                None => {
+                    let encrypted_key = &secret_keys[&my_pubkey];
+                    let key = self.decrypt_key_from(
+                        &encrypted_key.ecdh_pubkey,
+                        &encrypted_key.encrypted_key,
+                        &encrypted_key.iv,
+                    );
+                    info!("Worker: successfully decrypt received cluster key");
+                    key
+                }
+            };
 
             // TODO(shelven): forget cluster key after expiration time
             if let Some(cluster) = &self.contract_cluster {
@@ -1494,6 +1875,9 @@ impl<Platform: pal::Platform> System<Platform> {
                 &cluster_key,
                 block.storage.pink_runtime_version(),
             );
+            // The policy could have been set before this worker joined the cluster.
+            cluster.config.log_policy = block.storage.cluster_log_policy(&cluster_id);
+            set_sidevm_log_policy(block.sidevm_spawner, &cluster.config.log_policy);
             let config = ClusterSetupConfig {
                 cluster_id: event.cluster,
                 owner,
@@ -1520,6 +1904,10 @@ impl<Platform: pal::Platform> System<Platform> {
                     block.storage,
                 );
             }
+            let extension_policy = block.storage.cluster_extension_policy(&cluster_id);
+            if extension_policy != Default::default() {
+                cluster.set_extension_policy(extension_policy);
+            }
             self.contract_cluster = Some(cluster);
 
             let message = WorkerClusterReport::ClusterDeployed {
@@ -1531,6 +1919,80 @@ impl<Platform: pal::Platform> System<Platform> {
         Ok(())
     }
 
+    /// Process the fresh shares of a cluster key from the gatekeeper.
+    ///
+    /// The shares must add up to the unchanged public key of the cluster. Only the share of this
+    /// worker is replaced, the cluster keeps running with the keys derived at its deployment, so
+    /// the contracts keep serving during the refresh. The share and the refresh id are kept in
+    /// the cluster config, and so in the checkpoints.
+    fn process_cluster_key_refresh(
+        &mut self,
+        origin: MessageOrigin,
+        event: BatchRefreshClusterKeyEvent,
+    ) -> anyhow::Result<()> {
+        if !origin.is_gatekeeper() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return Err(TransactionError::BadOrigin.into());
+        }
+        let my_pubkey = self.identity_key.public();
+        let Some(my_share) = event.shares.get(&my_pubkey) else {
+            return Ok(());
+        };
+        let Some(cluster) = self.contract_cluster.get_cluster_mut(&event.cluster) else {
+            warn!(
+                "Ignoring key refresh of cluster {:?} which is not deployed on this worker",
+                event.cluster
+            );
+            return Ok(());
+        };
+        if event.refresh_id <= cluster.config.key_refresh_id {
+            warn!(
+                "Ignoring stale key refresh {} of cluster {:?}, already at {}",
+                event.refresh_id, event.cluster, cluster.config.key_refresh_id
+            );
+            return Ok(());
+        }
+        let share_pubkeys: Vec<_> = event.shares.values().map(|share| share.pubkey.0).collect();
+        let combined = key_share::combine_public_keys(&share_pubkeys)
+            .map_err(|err| anyhow!("Bad shares of the cluster key: {err:?}"))?;
+        if combined != event.pubkey.0 {
+            error!(
+                "Shares of cluster {:?} do not add up to the cluster key",
+                event.cluster
+            );
+            return Err(TransactionError::ClusterKeyMismatch.into());
+        }
+        let my_ecdh_key = self.identity_key.derive_ecdh_key();
+        let encrypted_key = &my_share.encrypted_key;
+        let secret = key_share::decrypt_secret_from(
+            &my_ecdh_key,
+            &encrypted_key.ecdh_pubkey.0,
+            &encrypted_key.encrypted_key,
+            &encrypted_key.iv,
+        )
+        .map_err(|err| anyhow!("Failed to decrypt cluster key share: {err:?}"))?;
+        let share = sr25519::Pair::restore_from_secret_key(&secret);
+        if share.public() != my_share.pubkey {
+            error!(
+                "Share of cluster {:?} does not match its public key",
+                event.cluster
+            );
+            return Err(TransactionError::ClusterKeyMismatch.into());
+        }
+        cluster.config.key_share = Some(share);
+        cluster.config.key_refresh_id = event.refresh_id;
+        info!(
+            "Worker: cluster key share refreshed, cluster={:?}, refresh_id={}",
+            event.cluster, event.refresh_id
+        );
+        let message = WorkerClusterReport::ClusterKeyRefreshed {
+            id: event.cluster,
+            refresh_id: event.refresh_id,
+        };
+        self.egress.push_message(&message);
+        Ok(())
+    }
+
     pub fn is_registered(&self) -> bool {
         self.worker_state.registered
     }
@@ -1576,8 +2038,12 @@ impl<P: pal::Platform> System<P> {
         if safe_mode_level > 0 {
             return Ok(());
         }
+        if let Some(cluster) = &self.contract_cluster {
+            set_sidevm_log_policy(sidevm_spawner, &cluster.config.log_policy);
+        }
         self.contracts
             .try_restart_sidevms(sidevm_spawner, self.block_number);
+        sidevm_spawner.warm_compile_cache(self.contracts.sidevm_codes());
         self.contracts.apply_local_cache_quotas();
         Ok(())
     }
@@ -1623,6 +2089,8 @@ impl<P: pal::Platform> System<P> {
         if self.block_number > info.config.deadline {
             anyhow::bail!("Sidevm is expired");
         }
+        crate::contracts::code_check::check_sidevm_code_for(&code, &info.config)
+            .map_err(|err| anyhow!("CodeRejected: {err}"))?;
         let config = info.config.clone();
         contract.start_sidevm(sidevm_spawner, SidevmCode::Code(code), true, config)
     }
@@ -1684,7 +2152,7 @@ pub fn apply_pink_side_effects(
         block.sidevm_spawner,
         chain_storage,
     );
-    apply_ink_side_effects(ink_events, block, log_handler);
+    apply_ink_side_effects(ink_events, block, log_handler, &cluster.config.log_policy);
 }
 
 fn apply_instantiating_events(
@@ -1774,6 +2242,9 @@ pub(crate) fn apply_pink_events(
                     HookPoint::OnBlockEnd => {
                         contract.set_on_block_end_selector(selector, gas_limit);
                     }
+                    HookPoint::OnIdle => {
+                        contract.set_on_idle_selector(selector, gas_limit);
+                    }
                 }
             }
             PinkEvent::DeploySidevmTo {
@@ -1893,18 +2364,39 @@ pub(crate) fn apply_pink_events(
     }
 }
 
+/// Applies the log policy of the cluster to the logs the sidevm instances let out of the worker.
+fn set_sidevm_log_policy(spawner: &Spawner, policy: &LogPolicy) {
+    let policy = policy.clone();
+    spawner.set_log_filter(Box::new(move |vmid, level| {
+        policy.forwards_log(&crate::H256(*vmid), level)
+    }));
+}
+
 fn apply_ink_side_effects(
     ink_events: Vec<(AccountId, Vec<crate::H256>, Vec<u8>)>,
     block: &mut BlockInfo,
     log_handler: Option<CommandSender>,
+    log_policy: &LogPolicy,
 ) {
+    for (contract, topics, payload) in ink_events.iter() {
+        crate::contracts::pink::ink_events::record(
+            block.block_number,
+            contract.clone(),
+            topics.clone(),
+            payload.clone(),
+            log_policy,
+        );
+    }
     if let Some(log_handler) = log_handler {
         for (contract, topics, payload) in ink_events {
+            if !log_policy.forwards(&contract.convert_to()) {
+                continue;
+            }
             if log_handler
                 .try_send(SidevmCommand::PushSystemMessage(SystemMessage::PinkEvent {
                     contract: contract.into(),
                     block_number: block.block_number,
-                    payload,
+                    payload: log_policy.redact(payload),
                     topics: topics.into_iter().map(Into::into).collect(),
                 }))
                 .is_err()
@@ -0,0 +1,58 @@
+//! The states at the boundaries of recent blocks, kept for queries at a historical block.
+//!
+//! The cluster storage, the contracts and the chain storage are all backed by `im` structures,
+//! so keeping a few snapshots only costs the nodes changed since.
+
+use std::collections::VecDeque;
+
+use runtime::BlockNumber;
+
+use crate::{contracts::ContractsKeeper, pink::Cluster, ChainStorage};
+
+#[derive(Clone)]
+pub(crate) struct HistoricalState {
+    pub block_number: BlockNumber,
+    pub now_ms: u64,
+    pub cluster: Cluster,
+    pub contracts: ContractsKeeper,
+    pub chain_storage: ChainStorage,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct QueryHistory {
+    states: VecDeque<HistoricalState>,
+}
+
+impl QueryHistory {
+    /// Records the state after a block, keeping at most `capacity` blocks.
+    pub fn record(&mut self, state: HistoricalState, capacity: usize) {
+        if let Some(last) = self.states.back() {
+            if last.block_number + 1 != state.block_number {
+                // Blocks are not continuous, e.g. after loading a chain state.
+                self.states.clear();
+            }
+        }
+        self.states.push_back(state);
+        while self.states.len() > capacity {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    pub fn get(&self, block_number: BlockNumber) -> Option<&HistoricalState> {
+        let first = self.states.front()?.block_number;
+        let index = block_number.checked_sub(first)?;
+        self.states.get(index as usize)
+    }
+
+    /// The range of blocks available, both inclusive.
+    pub fn range(&self) -> Option<(BlockNumber, BlockNumber)> {
+        Some((
+            self.states.front()?.block_number,
+            self.states.back()?.block_number,
+        ))
+    }
+}
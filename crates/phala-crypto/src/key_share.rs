@@ -1,11 +1,14 @@
 use crate::aead::{self, IV};
 use crate::ecdh::{self, EcdhKey, EcdhPublicKey};
-use crate::sr25519::{Sr25519SecretKey, KDF};
+use crate::sr25519::{Sr25519PublicKey, Sr25519SecretKey, KDF};
 use crate::CryptoError;
 
 use alloc::borrow::ToOwned;
 use alloc::vec::Vec;
 use core::convert::TryInto;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
 use sp_core::sr25519;
 
 pub fn encrypt_secret_to(
@@ -38,6 +41,43 @@ pub fn decrypt_secret_from(
         .or(Err(CryptoError::Sr25519InvalidSecret))
 }
 
+fn secret_scalar(secret_key: &Sr25519SecretKey) -> Result<Scalar, CryptoError> {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&secret_key[0..32]);
+    Scalar::from_canonical_bytes(key).ok_or(CryptoError::Sr25519InvalidSecret)
+}
+
+/// Turns `shares`, which are random secret keys, into additive shares of `secret_key`
+///
+/// The scalar of the last share is replaced with the remainder, so that the scalars of the shares
+/// add up to the one of `secret_key`. Each share is still a valid secret key, and the public keys
+/// of the shares add up to the public key of `secret_key`, see [`combine_public_keys`].
+pub fn split_secret_into(
+    secret_key: &Sr25519SecretKey,
+    shares: &mut [Sr25519SecretKey],
+) -> Result<(), CryptoError> {
+    let (last, others) = shares
+        .split_last_mut()
+        .ok_or(CryptoError::Sr25519InvalidSecret)?;
+    let mut remainder = secret_scalar(secret_key)?;
+    for share in others.iter() {
+        remainder -= secret_scalar(share)?;
+    }
+    last[0..32].copy_from_slice(remainder.as_bytes());
+    Ok(())
+}
+
+/// Adds up the public keys, e.g. of the shares made by [`split_secret_into`]
+pub fn combine_public_keys(pubkeys: &[Sr25519PublicKey]) -> Result<Sr25519PublicKey, CryptoError> {
+    let mut sum = RistrettoPoint::identity();
+    for pubkey in pubkeys {
+        sum += CompressedRistretto(*pubkey)
+            .decompress()
+            .ok_or(CryptoError::Sr25519InvalidPublic)?;
+    }
+    Ok(sum.compress().0)
+}
+
 #[test]
 fn it_works() {
     use sp_core::Pair;
@@ -54,3 +94,27 @@ fn it_works() {
         decrypt_secret_from(&key1.derive_ecdh_key(), &pubkey, &encrypted_key, &iv).unwrap();
     assert_eq!(decrypted_key, [1u8; 64]);
 }
+
+#[test]
+fn shares_add_up_to_the_secret() {
+    use crate::sr25519::Persistence;
+    use sp_core::Pair;
+
+    let key = sr25519::Pair::from_seed(b"12345678901234567890123456789012");
+    let mut shares: Vec<_> = (0u8..4)
+        .map(|i| sr25519::Pair::from_seed(&[i; 32]).dump_secret_key())
+        .collect();
+    split_secret_into(&key.dump_secret_key(), &mut shares).unwrap();
+
+    let pubkeys: Vec<_> = shares
+        .iter()
+        .map(|share| sr25519::Pair::restore_from_secret_key(share).public().0)
+        .collect();
+    assert_eq!(combine_public_keys(&pubkeys).unwrap(), key.public().0);
+    assert_ne!(combine_public_keys(&pubkeys[1..]).unwrap(), key.public().0);
+
+    // A single share is the secret itself
+    let mut shares = [[0u8; 64]];
+    split_secret_into(&key.dump_secret_key(), &mut shares).unwrap();
+    assert_eq!(shares[0][0..32], key.dump_secret_key()[0..32]);
+}
@@ -25,4 +25,5 @@ pub enum CryptoError {
     AeadDecryptError,
     // sr25519
     Sr25519InvalidSecret,
+    Sr25519InvalidPublic,
 }
@@ -0,0 +1,768 @@
+//! A zstd decoder (RFC 8878) in plain Rust, so that the runtime can unpack the payloads as well as
+//! the workers.
+//!
+//! It decodes a single frame. Dictionaries are not supported and the content checksum is not
+//! verified, the messages carrying the frames are signed already.
+
+use alloc::{vec, vec::Vec};
+
+pub type Result<T> = core::result::Result<T, &'static str>;
+
+const MAGIC: u32 = 0xFD2F_B528;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+const MAX_HUFFMAN_BITS: u32 = 11;
+
+/// Baseline and number of extra bits of the literals length codes.
+const LITERALS_LENGTHS: [(u32, u32); 36] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Baseline and number of extra bits of the match length codes from 32, the lower codes being
+/// the length minus 3.
+const MATCH_LENGTHS: [(u32, u32); 21] = [
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+const LITERALS_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OFFSET_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+/// Decompresses a zstd frame, failing if the content exceeds `max_size`.
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let mut input = Reader(data);
+    let header = FrameHeader::read(&mut input)?;
+    if let Some(size) = header.content_size {
+        if size > max_size as u64 {
+            return Err("Content too large");
+        }
+    }
+    let mut output = Vec::new();
+    let mut state = FrameState::default();
+    loop {
+        let block = input.le(3)? as usize;
+        let last = block & 1 == 1;
+        let size = block >> 3;
+        if size > MAX_BLOCK_SIZE {
+            return Err("Block too large");
+        }
+        match (block >> 1) & 3 {
+            0 => output.extend_from_slice(input.take(size)?),
+            1 => {
+                let byte = input.byte()?;
+                output.resize(output.len() + size, byte);
+            }
+            2 => state.decode_block(input.take(size)?, &mut output)?,
+            _ => return Err("Reserved block type"),
+        }
+        if output.len() > max_size {
+            return Err("Content too large");
+        }
+        if last {
+            break;
+        }
+    }
+    if header.checksum {
+        input.take(4)?;
+    }
+    if !input.0.is_empty() {
+        return Err("Trailing data after the frame");
+    }
+    if let Some(size) = header.content_size {
+        if output.len() as u64 != size {
+            return Err("Content size mismatch");
+        }
+    }
+    Ok(output)
+}
+
+/// The content size declared in the header of the frame, if any.
+pub fn content_size(data: &[u8]) -> Option<u64> {
+    FrameHeader::read(&mut Reader(data)).ok()?.content_size
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err("Unexpected end of input");
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a little endian integer of `len` bytes.
+    fn le(&mut self, len: usize) -> Result<u64> {
+        Ok(self
+            .take(len)?
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+}
+
+struct FrameHeader {
+    content_size: Option<u64>,
+    checksum: bool,
+}
+
+impl FrameHeader {
+    fn read(input: &mut Reader) -> Result<Self> {
+        if input.le(4)? as u32 != MAGIC {
+            return Err("Bad magic number");
+        }
+        let descriptor = input.byte()?;
+        if descriptor & 0x08 != 0 {
+            return Err("Reserved bit set");
+        }
+        let single_segment = descriptor & 0x20 != 0;
+        if !single_segment {
+            // The window descriptor. The whole content is kept, so any window fits.
+            input.byte()?;
+        }
+        let dictionary = match descriptor & 3 {
+            0 => 0,
+            1 => input.le(1)?,
+            2 => input.le(2)?,
+            _ => input.le(4)?,
+        };
+        if dictionary != 0 {
+            return Err("Dictionaries are not supported");
+        }
+        let content_size = match descriptor >> 6 {
+            0 if single_segment => Some(input.le(1)?),
+            0 => None,
+            1 => Some(input.le(2)? + 256),
+            2 => Some(input.le(4)?),
+            _ => Some(input.le(8)?),
+        };
+        Ok(Self {
+            content_size,
+            checksum: descriptor & 0x04 != 0,
+        })
+    }
+}
+
+/// The tables and offsets which the compressed blocks of a frame may reuse from the previous ones.
+struct FrameState {
+    huffman: Option<HuffmanTable>,
+    literals_lengths: Option<FseTable>,
+    offsets: Option<FseTable>,
+    match_lengths: Option<FseTable>,
+    repeated_offsets: [u64; 3],
+}
+
+impl Default for FrameState {
+    fn default() -> Self {
+        Self {
+            huffman: None,
+            literals_lengths: None,
+            offsets: None,
+            match_lengths: None,
+            repeated_offsets: [1, 4, 8],
+        }
+    }
+}
+
+struct Sequence {
+    literals: usize,
+    offset: u64,
+    matched: usize,
+}
+
+impl FrameState {
+    fn decode_block(&mut self, block: &[u8], output: &mut Vec<u8>) -> Result<()> {
+        let mut input = Reader(block);
+        let literals = self.decode_literals(&mut input)?;
+        let sequences = self.decode_sequences(&mut input)?;
+
+        let block_start = output.len();
+        let mut literals = &literals[..];
+        for sequence in sequences {
+            if sequence.literals > literals.len() {
+                return Err("Not enough literals");
+            }
+            if output.len() - block_start + sequence.literals + sequence.matched > MAX_BLOCK_SIZE {
+                return Err("Block too large");
+            }
+            let (head, rest) = literals.split_at(sequence.literals);
+            output.extend_from_slice(head);
+            literals = rest;
+
+            let offset = self.resolve_offset(sequence.offset, sequence.literals);
+            if offset == 0 || offset > output.len() as u64 {
+                return Err("Bad match offset");
+            }
+            let start = output.len() - offset as usize;
+            // The match may overlap the bytes it produces.
+            for i in 0..sequence.matched {
+                output.push(output[start + i]);
+            }
+        }
+        if output.len() - block_start + literals.len() > MAX_BLOCK_SIZE {
+            return Err("Block too large");
+        }
+        output.extend_from_slice(literals);
+        Ok(())
+    }
+
+    /// Turns the offset value of a sequence into the distance of the match, updating the
+    /// repeated offsets.
+    fn resolve_offset(&mut self, value: u64, literals: usize) -> u64 {
+        let rep = &mut self.repeated_offsets;
+        if value > 3 {
+            let offset = value - 3;
+            *rep = [offset, rep[0], rep[1]];
+            return offset;
+        }
+        let index = value as usize - 1 + usize::from(literals == 0);
+        if index == 0 {
+            return rep[0];
+        }
+        let offset = if index < 3 { rep[index] } else { rep[0] - 1 };
+        if index > 1 {
+            rep[2] = rep[1];
+        }
+        rep[1] = rep[0];
+        rep[0] = offset;
+        offset
+    }
+
+    fn decode_literals(&mut self, input: &mut Reader) -> Result<Vec<u8>> {
+        let first = input.byte()?;
+        let kind = first & 3;
+        let size_format = (first >> 2) & 3;
+        if kind < 2 {
+            let size = match size_format {
+                0 | 2 => (first >> 3) as usize,
+                1 => (first >> 4) as usize + ((input.byte()? as usize) << 4),
+                _ => (first >> 4) as usize + ((input.le(2)? as usize) << 4),
+            };
+            if size > MAX_BLOCK_SIZE {
+                return Err("Literals too large");
+            }
+            return Ok(if kind == 0 {
+                input.take(size)?.to_vec()
+            } else {
+                vec![input.byte()?; size]
+            });
+        }
+
+        let (streams, bits, extra) = match size_format {
+            0 => (1, 10, 2),
+            1 => (4, 10, 2),
+            2 => (4, 14, 3),
+            _ => (4, 18, 4),
+        };
+        let header = first as u64 | (input.le(extra)? << 8);
+        let mask = (1 << bits) - 1;
+        let size = ((header >> 4) & mask) as usize;
+        let compressed_size = ((header >> (4 + bits)) & mask) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err("Literals too large");
+        }
+        let mut data = Reader(input.take(compressed_size)?);
+        if kind == 2 {
+            self.huffman = Some(HuffmanTable::read(&mut data)?);
+        }
+        let table = self.huffman.as_ref().ok_or("Missing Huffman table")?;
+
+        let mut literals = Vec::with_capacity(size);
+        if streams == 1 {
+            table.decode(data.0, size, &mut literals)?;
+            return Ok(literals);
+        }
+        let jump = [data.le(2)?, data.le(2)?, data.le(2)?];
+        let segment = size.div_ceil(4);
+        let last_segment = size.checked_sub(segment * 3).ok_or("Bad literals size")?;
+        for len in jump {
+            table.decode(data.take(len as usize)?, segment, &mut literals)?;
+        }
+        table.decode(data.0, last_segment, &mut literals)?;
+        Ok(literals)
+    }
+
+    fn decode_sequences(&mut self, input: &mut Reader) -> Result<Vec<Sequence>> {
+        let first = input.byte()? as usize;
+        let count = match first {
+            0 => return Ok(vec![]),
+            1..=127 => first,
+            128..=254 => ((first - 128) << 8) + input.byte()? as usize,
+            _ => input.le(2)? as usize + 0x7F00,
+        };
+        let modes = input.byte()?;
+        if modes & 3 != 0 {
+            return Err("Reserved bits set");
+        }
+        let literals_lengths = read_table(
+            input,
+            modes >> 6,
+            self.literals_lengths.take(),
+            &LITERALS_LENGTH_DEFAULT,
+            6,
+            9,
+        )?;
+        let offsets = read_table(
+            input,
+            (modes >> 4) & 3,
+            self.offsets.take(),
+            &OFFSET_DEFAULT,
+            5,
+            8,
+        )?;
+        let match_lengths = read_table(
+            input,
+            (modes >> 2) & 3,
+            self.match_lengths.take(),
+            &MATCH_LENGTH_DEFAULT,
+            6,
+            9,
+        )?;
+        if literals_lengths.max_symbol() >= LITERALS_LENGTHS.len()
+            || offsets.max_symbol() > 31
+            || match_lengths.max_symbol() >= 32 + MATCH_LENGTHS.len()
+        {
+            return Err("Bad sequence code");
+        }
+
+        let mut bits = BackwardBits::new(input.0)?;
+        let mut literals_length_state = bits.read(literals_lengths.log) as usize;
+        let mut offset_state = bits.read(offsets.log) as usize;
+        let mut match_length_state = bits.read(match_lengths.log) as usize;
+        let mut sequences = Vec::with_capacity(count.min(MAX_BLOCK_SIZE));
+        for i in 0..count {
+            let offset_code = offsets.symbols[offset_state] as u32;
+            let offset = (1 << offset_code) + bits.read(offset_code);
+
+            let match_code = match_lengths.symbols[match_length_state];
+            let matched = match match_code {
+                0..=31 => match_code as u64 + 3,
+                _ => {
+                    let (base, extra) = MATCH_LENGTHS[match_code as usize - 32];
+                    base as u64 + bits.read(extra)
+                }
+            };
+
+            let (base, extra) =
+                LITERALS_LENGTHS[literals_lengths.symbols[literals_length_state] as usize];
+            let literals = base as u64 + bits.read(extra);
+
+            sequences.push(Sequence {
+                literals: literals as usize,
+                offset,
+                matched: matched as usize,
+            });
+            if i + 1 < count {
+                literals_length_state = literals_lengths.update(literals_length_state, &mut bits);
+                match_length_state = match_lengths.update(match_length_state, &mut bits);
+                offset_state = offsets.update(offset_state, &mut bits);
+            }
+        }
+        if bits.offset != 0 {
+            return Err("Corrupted sequences");
+        }
+        self.literals_lengths = Some(literals_lengths);
+        self.offsets = Some(offsets);
+        self.match_lengths = Some(match_lengths);
+        Ok(sequences)
+    }
+}
+
+/// Reads the FSE table of a sequence symbol in the given mode.
+fn read_table(
+    input: &mut Reader,
+    mode: u8,
+    previous: Option<FseTable>,
+    default: &[i16],
+    default_log: u32,
+    max_log: u32,
+) -> Result<FseTable> {
+    match mode {
+        0 => FseTable::new(default_log, default),
+        1 => Ok(FseTable::rle(input.byte()?)),
+        2 => {
+            let (table, len) = FseTable::read(input.0, max_log)?;
+            input.take(len)?;
+            Ok(table)
+        }
+        _ => previous.ok_or("Missing repeated table"),
+    }
+}
+
+fn highest_bit(value: u32) -> u32 {
+    31 - value.leading_zeros()
+}
+
+/// A bitstream written forward and read backward, from the highest bit of the last byte set as
+/// a marker.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// The number of bits left, negative once read past the start.
+    offset: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self> {
+        let last = *data.last().ok_or("Empty bitstream")?;
+        if last == 0 {
+            return Err("Missing bitstream marker");
+        }
+        Ok(Self {
+            data,
+            offset: (data.len() as isize - 1) * 8 + highest_bit(last as u32) as isize,
+        })
+    }
+
+    /// Reads `count` bits, up to 56. The bits before the start of the stream are zeros.
+    fn read(&mut self, count: u32) -> u64 {
+        if count == 0 {
+            return 0;
+        }
+        self.offset -= count as isize;
+        if self.offset >= 0 {
+            return self.bits_at(self.offset as usize, count);
+        }
+        let missing = (-self.offset) as u32;
+        if missing >= count {
+            return 0;
+        }
+        self.bits_at(0, count - missing) << missing
+    }
+
+    fn bits_at(&self, start: usize, count: u32) -> u64 {
+        let bytes = self.data[start / 8..].iter().take(8);
+        let value = bytes
+            .rev()
+            .fold(0u64, |value, &byte| (value << 8) | byte as u64);
+        (value >> (start % 8)) & ((1 << count) - 1)
+    }
+}
+
+/// Reads `count` bits of a bitstream read forward, from the lowest bit of the first byte.
+fn forward_bits(data: &[u8], position: &mut usize, count: u32) -> Result<u32> {
+    let mut value = 0;
+    for i in 0..count as usize {
+        let bit = *position + i;
+        let byte = data.get(bit / 8).ok_or("Unexpected end of input")?;
+        value |= (((byte >> (bit % 8)) & 1) as u32) << i;
+    }
+    *position += count as usize;
+    Ok(value)
+}
+
+#[derive(Clone)]
+struct FseTable {
+    log: u32,
+    symbols: Vec<u8>,
+    bits: Vec<u8>,
+    base: Vec<u16>,
+}
+
+impl FseTable {
+    /// Reads the table description, returning the table and the number of bytes read.
+    fn read(data: &[u8], max_log: u32) -> Result<(Self, usize)> {
+        let mut position = 0;
+        let log = forward_bits(data, &mut position, 4)? + 5;
+        if log > max_log {
+            return Err("FSE accuracy too large");
+        }
+        let mut remaining = 1i32 << log;
+        let mut probabilities = Vec::new();
+        while remaining > 0 {
+            if probabilities.len() > 255 {
+                return Err("Too many FSE symbols");
+            }
+            let bits = highest_bit(remaining as u32 + 1) + 1;
+            let mut value = forward_bits(data, &mut position, bits)?;
+            let lower_mask = (1 << (bits - 1)) - 1;
+            let threshold = (1 << bits) - 1 - (remaining as u32 + 1);
+            if value & lower_mask < threshold {
+                position -= 1;
+                value &= lower_mask;
+            } else if value > lower_mask {
+                value -= threshold;
+            }
+            let probability = value as i32 - 1;
+            remaining -= probability.abs();
+            probabilities.push(probability as i16);
+            if probability == 0 {
+                loop {
+                    let repeat = forward_bits(data, &mut position, 2)?;
+                    probabilities.resize(probabilities.len() + repeat as usize, 0);
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        if remaining != 0 || probabilities.len() > 256 {
+            return Err("Bad FSE distribution");
+        }
+        Ok((Self::new(log, &probabilities)?, position.div_ceil(8)))
+    }
+
+    /// Builds the decoding table of the distribution, the probability -1 meaning less than one.
+    fn new(log: u32, probabilities: &[i16]) -> Result<Self> {
+        let size = 1usize << log;
+        let mut symbols = vec![0u8; size];
+        let mut next = vec![0u32; probabilities.len()];
+        let mut high = size;
+        for (symbol, &probability) in probabilities.iter().enumerate() {
+            if probability == -1 {
+                high = high.checked_sub(1).ok_or("Bad FSE distribution")?;
+                symbols[high] = symbol as u8;
+                next[symbol] = 1;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mask = size - 1;
+        let mut position = 0;
+        for (symbol, &probability) in probabilities.iter().enumerate() {
+            if probability <= 0 {
+                continue;
+            }
+            next[symbol] = probability as u32;
+            for _ in 0..probability {
+                symbols[position] = symbol as u8;
+                loop {
+                    position = (position + step) & mask;
+                    if position < high {
+                        break;
+                    }
+                }
+            }
+        }
+        if position != 0 {
+            return Err("Bad FSE distribution");
+        }
+        let mut bits = vec![0u8; size];
+        let mut base = vec![0u16; size];
+        for state in 0..size {
+            let symbol = symbols[state] as usize;
+            let next_state = next[symbol];
+            next[symbol] += 1;
+            let count = log - highest_bit(next_state);
+            bits[state] = count as u8;
+            base[state] = ((next_state << count) - size as u32) as u16;
+        }
+        Ok(Self {
+            log,
+            symbols,
+            bits,
+            base,
+        })
+    }
+
+    fn rle(symbol: u8) -> Self {
+        Self {
+            log: 0,
+            symbols: vec![symbol],
+            bits: vec![0],
+            base: vec![0],
+        }
+    }
+
+    fn max_symbol(&self) -> usize {
+        self.symbols.iter().copied().max().unwrap_or(0) as usize
+    }
+
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        self.base[state] as usize + bits.read(self.bits[state] as u32) as usize
+    }
+}
+
+struct HuffmanTable {
+    max_bits: u32,
+    symbols: Vec<u8>,
+    bits: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn read(input: &mut Reader) -> Result<Self> {
+        let header = input.byte()?;
+        let weights = if header < 128 {
+            decode_weights(input.take(header as usize)?)?
+        } else {
+            let count = (header - 127) as usize;
+            let packed = input.take(count.div_ceil(2))?;
+            (0..count)
+                .map(|i| {
+                    let byte = packed[i / 2];
+                    if i % 2 == 0 {
+                        byte >> 4
+                    } else {
+                        byte & 0x0f
+                    }
+                })
+                .collect()
+        };
+        Self::new(weights)
+    }
+
+    /// Builds the decoding table of the weights, the weight of the last symbol being implied.
+    fn new(mut weights: Vec<u8>) -> Result<Self> {
+        if weights.len() > 255 {
+            return Err("Too many Huffman symbols");
+        }
+        let mut total = 0u32;
+        for &weight in &weights {
+            if weight as u32 > MAX_HUFFMAN_BITS {
+                return Err("Bad Huffman weight");
+            }
+            if weight > 0 {
+                total += 1 << (weight - 1);
+            }
+        }
+        if total == 0 {
+            return Err("Bad Huffman weights");
+        }
+        let max_bits = highest_bit(total) + 1;
+        let left = (1 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !left.is_power_of_two() {
+            return Err("Bad Huffman weights");
+        }
+        weights.push(highest_bit(left) as u8 + 1);
+
+        let symbol_bits: Vec<u32> = weights
+            .iter()
+            .map(|&weight| match weight {
+                0 => 0,
+                _ => max_bits + 1 - weight as u32,
+            })
+            .collect();
+        let mut rank_count = [0usize; MAX_HUFFMAN_BITS as usize + 1];
+        for &count in &symbol_bits {
+            rank_count[count as usize] += 1;
+        }
+        let size = 1usize << max_bits;
+        let mut bits = vec![0u8; size];
+        let mut rank_start = [0usize; MAX_HUFFMAN_BITS as usize + 1];
+        for count in (1..=max_bits as usize).rev() {
+            let end = rank_start[count] + (rank_count[count] << (max_bits as usize - count));
+            if end > size {
+                return Err("Bad Huffman weights");
+            }
+            bits[rank_start[count]..end].fill(count as u8);
+            rank_start[count - 1] = end;
+        }
+        let mut symbols = vec![0u8; size];
+        for (symbol, &count) in symbol_bits.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let start = rank_start[count as usize];
+            let len = 1 << (max_bits - count);
+            symbols[start..start + len].fill(symbol as u8);
+            rank_start[count as usize] += len;
+        }
+        Ok(Self {
+            max_bits,
+            symbols,
+            bits,
+        })
+    }
+
+    /// Decodes `count` symbols of the stream.
+    fn decode(&self, stream: &[u8], count: usize, output: &mut Vec<u8>) -> Result<()> {
+        let mut bits = BackwardBits::new(stream)?;
+        let mask = (1 << self.max_bits) - 1;
+        let mut state = bits.read(self.max_bits) as usize;
+        for _ in 0..count {
+            output.push(self.symbols[state]);
+            let count = self.bits[state] as u32;
+            state = ((state << count) + bits.read(count) as usize) & mask;
+        }
+        if bits.offset != -(self.max_bits as isize) {
+            return Err("Corrupted Huffman stream");
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the Huffman weights compressed with FSE, two states sharing the bitstream.
+fn decode_weights(data: &[u8]) -> Result<Vec<u8>> {
+    let (table, len) = FseTable::read(data, 6)?;
+    let mut bits = BackwardBits::new(&data[len..])?;
+    let mut states = [bits.read(table.log) as usize, bits.read(table.log) as usize];
+    let mut weights = Vec::new();
+    for i in (0..2).cycle() {
+        if weights.len() > 255 {
+            return Err("Too many Huffman symbols");
+        }
+        weights.push(table.symbols[states[i]]);
+        states[i] = table.update(states[i], &mut bits);
+        if bits.offset < 0 {
+            weights.push(table.symbols[states[1 - i]]);
+            break;
+        }
+    }
+    Ok(weights)
+}
@@ -0,0 +1,157 @@
+//! Transparent payload compression of the topics flagged by [`Topic::compressed`].
+//!
+//! The payloads sent to a compressed topic are wrapped in an envelope: a one byte flag telling
+//! whether the rest is raw or a zstd frame. The senders pack them before signing, and the
+//! receivers, i.e. the `MessageDispatcher` of the workers and the mq pallet, unpack them before
+//! their subscribers see them. Payloads smaller than [`COMPRESSION_THRESHOLD`] are sent raw.
+//!
+//! Compressing requires the `compression` feature, which links the zstd library. Decompressing is
+//! done in plain Rust, so that the runtime unpacks the payloads the same way as the workers.
+//!
+//! [`Topic::compressed`]: crate::Topic::compressed
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use derive_more::Display;
+
+mod decoder;
+
+/// Payloads smaller than this are not worth compressing.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Upper bound of an unpacked payload, to avoid decompression bombs.
+pub const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+const FLAG_RAW: u8 = 0;
+const FLAG_ZSTD: u8 = 1;
+
+#[derive(Display, Debug, Clone, PartialEq, Eq)]
+pub enum UnpackError {
+    #[display(fmt = "Empty payload")]
+    EmptyPayload,
+    #[display(fmt = "Unknown compression flag {_0}")]
+    UnknownFlag(u8),
+    #[display(fmt = "Bad compressed payload: {_0}")]
+    BadPayload(&'static str),
+}
+
+/// Wraps the payload into the envelope, compressing it if it is large enough and the
+/// `compression` feature is enabled.
+pub fn pack(payload: Vec<u8>) -> Vec<u8> {
+    if payload.len() >= COMPRESSION_THRESHOLD {
+        if let Some(compressed) = compress(&payload) {
+            if compressed.len() < payload.len() {
+                return envelope(FLAG_ZSTD, &compressed);
+            }
+        }
+    }
+    pack_raw(payload)
+}
+
+/// Wraps the payload into the envelope without compressing it.
+///
+/// The runtime packs its payloads with this, its output must not depend on the features the
+/// native build is compiled with.
+pub fn pack_raw(payload: Vec<u8>) -> Vec<u8> {
+    envelope(FLAG_RAW, &payload)
+}
+
+fn envelope(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(data.len() + 1);
+    packed.push(flag);
+    packed.extend_from_slice(data);
+    packed
+}
+
+/// Unwraps the envelope, decompressing the payload if needed.
+pub fn unpack(packed: &[u8]) -> Result<Vec<u8>, UnpackError> {
+    let (flag, data) = packed.split_first().ok_or(UnpackError::EmptyPayload)?;
+    match *flag {
+        FLAG_RAW => Ok(data.to_vec()),
+        FLAG_ZSTD => {
+            decoder::decompress(data, MAX_DECOMPRESSED_SIZE).map_err(UnpackError::BadPayload)
+        }
+        flag => Err(UnpackError::UnknownFlag(flag)),
+    }
+}
+
+/// The size of the payload once unpacked, as declared in the envelope. None if a compressed
+/// payload does not declare its size.
+pub fn unpacked_len(packed: &[u8]) -> Option<usize> {
+    match packed.split_first()? {
+        (&FLAG_ZSTD, data) => decoder::content_size(data)?.try_into().ok(),
+        (_, data) => Some(data.len()),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    zstd::bulk::compress(data, 0).ok()
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_payload_is_not_compressed() {
+        let packed = pack(b"hello".to_vec());
+        assert_eq!(packed, b"\x00hello");
+        assert_eq!(unpacked_len(&packed), Some(5));
+        assert_eq!(unpack(&packed).unwrap(), b"hello");
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn large_payload_is_compressed() {
+        let payload: Vec<u8> = (0..COMPRESSION_THRESHOLD * 8)
+            .map(|i| (i % 251) as u8 ^ (i / 97) as u8)
+            .collect();
+        let packed = pack(payload.clone());
+        assert_eq!(packed[0], FLAG_ZSTD);
+        assert!(packed.len() < payload.len());
+        assert_eq!(unpacked_len(&packed), Some(payload.len()));
+        assert_eq!(unpack(&packed).unwrap(), payload);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn the_decoder_reads_the_zstd_frames() {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(500);
+        let noise: Vec<u8> = (0u32..20_000)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8 % 7)
+            .collect();
+        for payload in [text, noise, vec![0; 300_000]] {
+            for level in [1, 3, 19] {
+                let frame = zstd::bulk::compress(&payload, level).unwrap();
+                assert_eq!(
+                    decoder::decompress(&frame, MAX_DECOMPRESSED_SIZE).unwrap(),
+                    payload
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn decompression_is_bounded() {
+        let frame = zstd::bulk::compress(&[0; 4096], 3).unwrap();
+        assert!(decoder::decompress(&frame, 4096).is_ok());
+        assert!(decoder::decompress(&frame, 4095).is_err());
+    }
+
+    #[test]
+    fn bad_envelope() {
+        assert_eq!(unpack(&[]), Err(UnpackError::EmptyPayload));
+        assert_eq!(unpack(&[9, 1, 2]), Err(UnpackError::UnknownFlag(9)));
+        assert!(matches!(
+            unpack(&[FLAG_ZSTD, 1, 2]),
+            Err(UnpackError::BadPayload(_))
+        ));
+    }
+}
@@ -1,11 +1,13 @@
 use core::marker::PhantomData;
 
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use scale_info::TypeInfo;
 use serde::{Deserialize, Serialize};
 
 use crate::simple_mpsc::{channel, ReceiveError, Receiver as RawReceiver, Sender, Seq};
-use crate::types::{Message, Path};
+use crate::types::{Message, Path, Topic};
 use crate::{BindTopic, MessageOrigin};
 use derive_more::Display;
 use parity_scale_codec::{Decode, Error as CodecError};
@@ -16,10 +18,94 @@ impl Seq for (u64, Message) {
     }
 }
 
+/// Max number of senders whose next sequence is kept. Beyond it, the sender seen least recently
+/// is forgotten, so that the persisted state does not grow with every sender ever seen.
+pub const MAX_DEDUP_SENDERS: usize = 16384;
+
+/// The next sequence expected from a sender, and when the sender was last seen.
+#[derive(Default, Clone, TypeInfo)]
+#[cfg_attr(feature = "checkpoint", derive(Serialize, Deserialize))]
+pub struct SenderWindow {
+    next: u64,
+    seen_at: u64,
+}
+
+/// The next sequence expected from each sender, to detect redelivered messages.
+///
+/// It is the persisted state of a [`MessageDispatcher`]. Only the [`MAX_DEDUP_SENDERS`] senders
+/// seen most recently are kept, which covers the blocks redelivered after a checkpoint restore.
+#[derive(Default, Clone, TypeInfo)]
+#[cfg_attr(feature = "checkpoint", derive(Serialize, Deserialize))]
+pub struct SenderSequences {
+    next: BTreeMap<MessageOrigin, SenderWindow>,
+    /// Number of sequences observed, to order the senders by recency.
+    clock: u64,
+    duplicates: u64,
+}
+
+impl SenderSequences {
+    /// Records the sequence, returns false if it is below the next one expected from the sender.
+    fn observe(&mut self, sender: &MessageOrigin, sequence: u64) -> bool {
+        self.observe_bounded(sender, sequence, MAX_DEDUP_SENDERS)
+    }
+
+    fn observe_bounded(
+        &mut self,
+        sender: &MessageOrigin,
+        sequence: u64,
+        max_senders: usize,
+    ) -> bool {
+        self.clock += 1;
+        if !self.next.contains_key(sender) && self.next.len() >= max_senders {
+            self.forget_least_recent();
+        }
+        let window = self.next.entry(sender.clone()).or_default();
+        window.seen_at = self.clock;
+        if sequence < window.next {
+            self.duplicates += 1;
+            return false;
+        }
+        window.next = sequence + 1;
+        true
+    }
+
+    fn forget_least_recent(&mut self) {
+        let oldest = self
+            .next
+            .iter()
+            .min_by_key(|(_, window)| window.seen_at)
+            .map(|(sender, _)| sender.clone());
+        if let Some(sender) = oldest {
+            self.next.remove(&sender);
+        }
+    }
+}
+
+/// A hook run on the messages about to be delivered by a [`MessageDispatcher`], e.g. for
+/// metrics, auditing or filtering spam by sender.
+///
+/// Middlewares run in the order they were added, after the namespace check and the unpacking of
+/// the compressed payloads, so they see the messages as the subscribers would. Once a middleware
+/// drops a message, the following ones are not run.
+pub trait DispatchMiddleware: Send + Sync {
+    /// Returns false to drop the message.
+    fn before_dispatch(&self, _message: &Message) -> bool {
+        true
+    }
+
+    /// Called once the message is delivered, with the number of receivers.
+    fn after_dispatch(&self, _message: &Message, _receivers: usize) {}
+}
+
 #[derive(Default, Clone)]
 pub struct MessageDispatcher {
     subscribers: im::OrdMap<Path, Vec<Sender<(u64, Message)>>>,
+    /// Genesis hash of the chain this dispatcher serves. Messages sent to topics namespaced to
+    /// other chains are dropped.
+    chain_namespace: Option<[u8; 32]>,
     local_index: u64,
+    dedup: SenderSequences,
+    middlewares: Vec<Arc<dyn DispatchMiddleware>>,
     //match_subscribers: Vec<Matcher, Vec<Sender<Message>>>,
 }
 
@@ -36,6 +122,14 @@ pub struct ReceiverTypeInfo {
     topic: Vec<u8>,
 }
 
+impl scale_info::TypeInfo for MessageDispatcher {
+    type Identity = SenderSequences;
+
+    fn type_info() -> scale_info::Type {
+        SenderSequences::type_info()
+    }
+}
+
 impl<T> scale_info::TypeInfo for Receiver<T> {
     type Identity = <ReceiverTypeInfo as TypeInfo>::Identity;
 
@@ -62,15 +156,25 @@ impl MessageDispatcher {
     pub fn new() -> Self {
         MessageDispatcher {
             subscribers: Default::default(),
+            chain_namespace: None,
             local_index: 0,
+            dedup: Default::default(),
+            middlewares: Default::default(),
         }
     }
 
     /// Subscribe messages which are sent to `path`.
+    ///
+    /// Subscribing to a compressed topic also receives the messages sent uncompressed to the same
+    /// topic before it was compressed, see [`Topic::uncompressed`].
     /// Returns a Receiver channel end.
     pub fn subscribe(&mut self, path: impl Into<Path>) -> Receiver<Message> {
         let path = path.into();
         let (rx, tx) = channel();
+        if let Some(legacy) = Topic::new(path.clone()).uncompressed() {
+            let entry = self.subscribers.entry(legacy.path().clone()).or_default();
+            entry.push(tx.clone());
+        }
         let entry = self.subscribers.entry(path.clone()).or_default();
         entry.push(tx);
         Receiver {
@@ -85,9 +189,59 @@ impl MessageDispatcher {
         self.subscribe(<T as BindTopic>::topic()).into()
     }
 
+    /// Set the genesis hash of the chain this dispatcher serves.
+    ///
+    /// Messages sent to a topic namespaced to any other chain are dropped. Without a namespace
+    /// set, all namespaced messages are dropped.
+    pub fn set_chain_namespace(&mut self, genesis_hash: [u8; 32]) {
+        self.chain_namespace = Some(genesis_hash);
+    }
+
+    /// Add a middleware run on every message before it is delivered.
+    ///
+    /// Like the subscribers, the middlewares are not persisted in the checkpoints and must be
+    /// added again after a restore.
+    pub fn add_middleware(&mut self, middleware: Arc<dyn DispatchMiddleware>) {
+        self.middlewares.push(middleware);
+    }
+
     /// Dispatch a message.
+    ///
+    /// The payloads of the messages sent to a compressed topic are unpacked, and dropped if
+    /// malformed.
     /// Returns number of receivers dispatched to.
-    pub fn dispatch(&mut self, message: Message) -> usize {
+    pub fn dispatch(&mut self, mut message: Message) -> usize {
+        if let Some(namespace) = message.destination.namespace() {
+            if self.chain_namespace != Some(namespace) {
+                log::warn!(
+                    "Dropped message sent to another chain, from={}, to={:?}",
+                    message.sender,
+                    message.destination
+                );
+                return 0;
+            }
+        }
+        if message.destination.is_compressed() {
+            match crate::compression::unpack(&message.payload) {
+                Ok(payload) => message.payload = payload,
+                Err(err) => {
+                    log::warn!(
+                        "Dropped message with a bad payload, from={}, to={:?}: {err}",
+                        message.sender,
+                        message.destination
+                    );
+                    return 0;
+                }
+            }
+        }
+        if !self.middlewares.iter().all(|m| m.before_dispatch(&message)) {
+            log::debug!(
+                "Message dropped by middleware, from={}, to={:?}",
+                message.sender,
+                message.destination
+            );
+            return 0;
+        }
         let mut count = 0;
         let sn = self.local_index;
         self.local_index += 1;
@@ -104,9 +258,36 @@ impl MessageDispatcher {
                 }
             });
         }
+        for middleware in self.middlewares.iter() {
+            middleware.after_dispatch(&message, count);
+        }
         count
     }
 
+    /// Dispatch a message carrying a sequence which increases over the messages of its sender.
+    ///
+    /// The chain delivers messages at least once, e.g. the messages of a block can come again
+    /// after a checkpoint restore. A message whose sequence is below the next one expected from
+    /// the same sender is dropped and counted in [`Self::duplicates_dropped`].
+    /// Returns number of receivers dispatched to.
+    pub fn dispatch_sequenced(&mut self, message: Message, sequence: u64) -> usize {
+        if !self.dedup.observe(&message.sender, sequence) {
+            log::warn!(
+                "Dropped duplicated message, from={}, to={:?}, seq={}",
+                message.sender,
+                message.destination,
+                sequence
+            );
+            return 0;
+        }
+        self.dispatch(message)
+    }
+
+    /// The number of messages dropped by [`Self::dispatch_sequenced`] as duplicates.
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.dedup.duplicates
+    }
+
     pub fn reset_local_index(&mut self) {
         self.local_index = 0;
     }
@@ -199,6 +380,28 @@ const _: () = {
     use crate::checkpoint_helper::subscribe_default;
     use serde::Serializer;
 
+    /// Only the sender sequences are persisted, the subscribers are restored along with the receivers.
+    impl Serialize for MessageDispatcher {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            self.dedup.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MessageDispatcher {
+        fn deserialize<D>(de: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            Ok(Self {
+                dedup: Deserialize::deserialize(de)?,
+                ..Self::new()
+            })
+        }
+    }
+
     impl Serialize for Receiver<Message> {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -301,7 +504,7 @@ macro_rules! select_ignore_errors {
 mod tests {
     use parity_scale_codec::{Decode, Encode};
 
-    use crate::bind_topic;
+    use crate::{bind_topic, Topic};
 
     use super::*;
 
@@ -361,6 +564,29 @@ mod tests {
         assert!(err.is_sender_gone());
     }
 
+    #[test]
+    fn drops_messages_of_other_chains() {
+        let this_chain = [1u8; 32];
+        let other_chain = [2u8; 32];
+        let topic = Topic::namespaced(&this_chain, "test");
+        let mut dispatcher = MessageDispatcher::new();
+        let mut rx = dispatcher.subscribe(topic.clone());
+        let message = |genesis_hash| {
+            Message::new(
+                MessageOrigin::Gatekeeper,
+                Topic::namespaced(genesis_hash, "test"),
+                b"hello".to_vec(),
+            )
+        };
+
+        assert_eq!(dispatcher.dispatch(message(&this_chain)), 0);
+        dispatcher.set_chain_namespace(this_chain);
+        assert_eq!(dispatcher.dispatch(message(&other_chain)), 0);
+        assert_eq!(dispatcher.dispatch(message(&this_chain)), 1);
+        assert!(matches!(rx.try_next(), Ok(Some(_))));
+        assert!(matches!(rx.try_next(), Ok(None)));
+    }
+
     #[test]
     #[should_panic]
     fn test_malformed_gk_message() {
@@ -408,6 +634,143 @@ mod tests {
         assert!(err.is_codec_error());
     }
 
+    #[test]
+    fn unpacks_compressed_topics() {
+        let topic = crate::Topic::compressed(*b"test");
+        let mut dispatcher = MessageDispatcher::new();
+        let mut rx = dispatcher.subscribe(topic.clone());
+        let payload = crate::compression::pack(b"hello".to_vec());
+        dispatcher.dispatch(Message::new(
+            MessageOrigin::Gatekeeper,
+            topic.clone(),
+            payload,
+        ));
+        let (_, msg) = rx.try_next().unwrap().unwrap();
+        assert_eq!(msg.payload, b"hello");
+
+        // The messages sent before the topic was compressed are delivered as they are.
+        dispatcher.dispatch(Message::new(
+            MessageOrigin::Gatekeeper,
+            "test",
+            b"legacy".to_vec(),
+        ));
+        let (_, msg) = rx.try_next().unwrap().unwrap();
+        assert_eq!(msg.payload, b"legacy");
+
+        // Malformed envelopes are dropped.
+        assert_eq!(
+            dispatcher.dispatch(Message::new(MessageOrigin::Gatekeeper, topic, vec![9])),
+            0
+        );
+    }
+
+    #[test]
+    fn drops_duplicated_sequences() {
+        fn dispatch(dispatcher: &mut MessageDispatcher, sender: MessageOrigin, seq: u64) -> usize {
+            dispatcher.dispatch_sequenced(Message::new(sender, "test", b"hello".to_vec()), seq)
+        }
+        let mut dispatcher = MessageDispatcher::new();
+        let _rx = dispatcher.subscribe("test");
+
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 1), 1);
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 1), 0);
+        // Sequences are per sender.
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Reserved, 1), 1);
+        assert_eq!(dispatcher.duplicates_dropped(), 1);
+        // Gaps are fine, older sequences are dropped however old they are.
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 100), 1);
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 0), 0);
+        assert_eq!(dispatcher.duplicates_dropped(), 2);
+
+        // The sequences survive a checkpoint.
+        let mut dispatcher: MessageDispatcher =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&dispatcher).unwrap()).unwrap();
+        let _rx = dispatcher.subscribe("test");
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 100), 0);
+        assert_eq!(dispatch(&mut dispatcher, MessageOrigin::Gatekeeper, 101), 1);
+        assert_eq!(dispatcher.duplicates_dropped(), 3);
+    }
+
+    #[test]
+    fn forgets_the_least_recent_senders() {
+        let worker = |i: u8| MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([i; 32]));
+        let mut sequences = SenderSequences::default();
+        assert!(sequences.observe_bounded(&worker(1), 10, 2));
+        assert!(sequences.observe_bounded(&worker(2), 10, 2));
+        // Worker 1 is seen again, worker 2 is now the least recent one.
+        assert!(!sequences.observe_bounded(&worker(1), 10, 2));
+        assert!(sequences.observe_bounded(&worker(3), 10, 2));
+        assert_eq!(sequences.next.len(), 2);
+        assert!(!sequences.next.contains_key(&worker(2)));
+
+        // The senders kept still drop their duplicates, the forgotten one starts over.
+        assert!(!sequences.observe_bounded(&worker(1), 10, 2));
+        assert!(!sequences.observe_bounded(&worker(3), 10, 2));
+        assert!(sequences.observe_bounded(&worker(2), 10, 2));
+        assert!(!sequences.next.contains_key(&worker(1)));
+        assert_eq!(sequences.duplicates, 3);
+    }
+
+    #[test]
+    fn middlewares_observe_and_filter() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder {
+            delivered: Mutex<Vec<(Vec<u8>, usize)>>,
+        }
+        impl DispatchMiddleware for Recorder {
+            fn after_dispatch(&self, message: &Message, receivers: usize) {
+                self.delivered
+                    .lock()
+                    .unwrap()
+                    .push((message.payload.clone(), receivers));
+            }
+        }
+        struct DropSender(MessageOrigin);
+        impl DispatchMiddleware for DropSender {
+            fn before_dispatch(&self, message: &Message) -> bool {
+                message.sender != self.0
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut dispatcher = MessageDispatcher::new();
+        dispatcher.add_middleware(Arc::new(DropSender(MessageOrigin::Reserved)));
+        dispatcher.add_middleware(recorder.clone());
+        let mut rx = dispatcher.subscribe("test");
+
+        let payload = b"hello".to_vec();
+        assert_eq!(
+            dispatcher.dispatch(Message::new(
+                MessageOrigin::Gatekeeper,
+                "test",
+                payload.clone()
+            )),
+            1
+        );
+        assert_eq!(
+            dispatcher.dispatch(Message::new(MessageOrigin::Reserved, "test", payload)),
+            0
+        );
+        assert!(matches!(rx.try_next(), Ok(Some(_))));
+        assert!(matches!(rx.try_next(), Ok(None)));
+        // Only the delivered message is seen.
+        assert_eq!(
+            *recorder.delivered.lock().unwrap(),
+            vec![(b"hello".to_vec(), 1)]
+        );
+
+        // The middlewares are shared by the clones of the dispatcher.
+        let mut forked = dispatcher.clone();
+        forked.dispatch(Message::new(
+            MessageOrigin::Gatekeeper,
+            "other",
+            b"world".to_vec(),
+        ));
+        assert_eq!(recorder.delivered.lock().unwrap().len(), 2);
+    }
+
     #[test]
     fn typeinfo_works() {
         use type_info_stringify::type_info_stringify;
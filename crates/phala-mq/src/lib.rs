@@ -5,6 +5,7 @@ pub extern crate serde_sgx as serde;
 
 extern crate alloc;
 
+pub mod compression;
 mod signer;
 pub mod types;
 
@@ -19,9 +20,9 @@ mod simple_mpsc;
 pub mod checkpoint_helper;
 
 #[cfg(feature = "dispatcher")]
-pub use dispatcher::{MessageDispatcher, TypedReceiveError, TypedReceiver};
+pub use dispatcher::{DispatchMiddleware, MessageDispatcher, TypedReceiveError, TypedReceiver};
 #[cfg(feature = "queue")]
-pub use send_queue::{Channel as ChannelState, MessageChannel, MessageSendQueue};
+pub use send_queue::{Channel as ChannelState, MessageChannel, MessageSendQueue, SendObserver};
 #[cfg(any(feature = "queue", feature = "dispatcher"))]
 pub use simple_mpsc::{ReceiveError, Receiver};
 
@@ -1,5 +1,6 @@
 use crate::{
-    Message, MessageOrigin, MessageSigner, Mutex, SenderId, SignedMessage, SigningMessage,
+    Message, MessageOrigin, MessageSigner, Mutex, QueueDepth, SenderId, SenderUsage, SignedMessage,
+    SigningMessage,
 };
 use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use serde::{Deserialize, Serialize};
@@ -9,11 +10,23 @@ pub struct Channel {
     sequence: u64,
     messages: Vec<SignedMessage>,
     dummy: bool,
+    /// The traffic since the last `take_usage`.
+    #[serde(default)]
+    usage: SenderUsage,
+}
+
+/// A hook run on every message enqueued to a [`MessageSendQueue`], e.g. for auditing.
+///
+/// It is called with the lock of the queue held, so it must not access the queue.
+pub trait SendObserver: Send + Sync {
+    fn on_enqueue(&self, message: &SignedMessage);
 }
 
 #[derive(Clone, Default)]
 pub struct MessageSendQueue {
     inner: Arc<Mutex<BTreeMap<SenderId, Channel>>>,
+    /// Not persisted.
+    observer: Arc<Mutex<Option<Arc<dyn SendObserver>>>>,
 }
 
 impl Serialize for MessageSendQueue {
@@ -35,6 +48,7 @@ impl<'de> Deserialize<'de> for MessageSendQueue {
         let inner = BTreeMap::<SenderId, Channel>::deserialize(deserializer)?;
         Ok(MessageSendQueue {
             inner: Arc::new(Mutex::new(inner)),
+            observer: Default::default(),
         })
     }
 }
@@ -43,9 +57,15 @@ impl MessageSendQueue {
     pub fn new() -> Self {
         MessageSendQueue {
             inner: Default::default(),
+            observer: Default::default(),
         }
     }
 
+    /// Set the hook run on every message enqueued, replacing the previous one.
+    pub fn set_observer(&self, observer: Option<Arc<dyn SendObserver>>) {
+        *self.observer.lock() = observer;
+    }
+
     pub fn channel<Si: MessageSigner>(&self, sender: SenderId, signer: Si) -> MessageChannel<Si> {
         MessageChannel::new(self.clone(), sender, signer)
     }
@@ -76,6 +96,13 @@ impl MessageSendQueue {
                     entry.sequence,
                 );
             }
+            entry.usage.record(
+                message.message.destination.path(),
+                message.message.payload.len(),
+            );
+            if let Some(observer) = &*self.observer.lock() {
+                observer.on_enqueue(&message);
+            }
             entry.messages.push(message);
         }
         entry.sequence += 1;
@@ -119,6 +146,40 @@ impl MessageSendQueue {
             .sum()
     }
 
+    /// The next sequence of each sender.
+    pub fn sequences(&self) -> BTreeMap<SenderId, u64> {
+        self.inner
+            .lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.sequence))
+            .collect()
+    }
+
+    /// The depth of the queue of each sender.
+    pub fn depths(&self) -> BTreeMap<SenderId, QueueDepth> {
+        self.inner
+            .lock()
+            .iter()
+            .map(|(k, v)| {
+                let depth = QueueDepth {
+                    pending: v.messages.len() as u32,
+                    next_sequence: v.sequence,
+                };
+                (k.clone(), depth)
+            })
+            .collect()
+    }
+
+    /// Takes the traffic of each sender since the last call, skipping the idle ones.
+    pub fn take_usage(&self) -> BTreeMap<SenderId, SenderUsage> {
+        self.inner
+            .lock()
+            .iter_mut()
+            .filter(|(_k, v)| !v.usage.is_empty())
+            .map(|(k, v)| (k.clone(), core::mem::take(&mut v.usage)))
+            .collect()
+    }
+
     /// Purge the messages which are aready accepted on chain.
     pub fn purge(&self, next_sequence_for: impl Fn(&SenderId) -> u64) {
         let mut inner = self.inner.lock();
@@ -128,6 +189,13 @@ impl MessageSendQueue {
         }
     }
 
+    /// Purge the messages of the sender acknowledged by a receipt from the chain.
+    pub fn purge_sender(&self, sender: &SenderId, next_sequence: u64) {
+        if let Some(channel) = self.inner.lock().get_mut(sender) {
+            channel.messages.retain(|msg| msg.sequence >= next_sequence);
+        }
+    }
+
     pub fn dump_state(&self, sender: &SenderId) -> Option<Channel> {
         let inner = self.inner.lock();
         inner.get(sender).cloned()
@@ -142,7 +210,7 @@ impl MessageSendQueue {
 pub use msg_channel::*;
 mod msg_channel {
     use super::*;
-    use crate::{types::Path, MessageSigner, SenderId};
+    use crate::{types::Path, MessageSigner, SenderId, Topic};
 
     #[derive(Clone, Serialize, Deserialize, ::scale_info::TypeInfo)]
     pub struct MessageChannel<Si> {
@@ -172,9 +240,16 @@ mod msg_channel {
         ) -> SigningMessage<Si> {
             let sender = self.sender.clone();
             let signer = self.signer.clone();
+            let destination: Topic = to.into().into();
+            // Packed before signing, the signature covers what goes on chain.
+            let payload = if destination.is_compressed() {
+                crate::compression::pack(payload)
+            } else {
+                payload
+            };
             let message = Message {
                 sender,
-                destination: to.into().into(),
+                destination,
                 payload,
             };
             SigningMessage { message, signer }
@@ -304,6 +379,99 @@ mod tests {
         assert_eq!(mq.count_messages(), 1);
     }
 
+    #[test]
+    fn packs_payloads_to_compressed_topics() {
+        let mq = MessageSendQueue::new();
+        let ch = msg_channel::MessageChannel::new(mq.clone(), MessageOrigin::Reserved, TestSigner);
+        ch.push_data(b"hello".to_vec(), crate::Topic::compressed(*b"test"));
+        ch.push_data(b"hello".to_vec(), *b"test");
+        let messages = mq.all_messages();
+        assert_eq!(messages[0].message.payload, b"\x00hello");
+        assert_eq!(messages[1].message.payload, b"hello");
+    }
+
+    #[test]
+    fn observer_sees_enqueued_messages() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder {
+            sequences: Mutex<Vec<u64>>,
+        }
+        impl SendObserver for Recorder {
+            fn on_enqueue(&self, message: &SignedMessage) {
+                self.sequences.lock().unwrap().push(message.sequence);
+            }
+        }
+
+        let mq = MessageSendQueue::new();
+        let recorder = Arc::new(Recorder::default());
+        let mut ch =
+            msg_channel::MessageChannel::new(mq.clone(), MessageOrigin::Reserved, TestSigner);
+        ch.set_dummy(false);
+        ch.push_message(&TestMessage(b"hello".to_vec()));
+        mq.set_observer(Some(recorder.clone()));
+        ch.push_message(&TestMessage(b"world".to_vec()));
+        mq.set_observer(None);
+        ch.push_message(&TestMessage(b"!".to_vec()));
+
+        assert_eq!(*recorder.sequences.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_queue_depths() {
+        let mq = MessageSendQueue::new();
+        let mut ch =
+            msg_channel::MessageChannel::new(mq.clone(), MessageOrigin::Reserved, TestSigner);
+        ch.set_dummy(false);
+        ch.push_message(&TestMessage(b"hello".to_vec()));
+        ch.push_message(&TestMessage(b"world".to_vec()));
+        mq.purge(|_| 1);
+
+        let depths = mq.depths();
+        assert_eq!(
+            depths.get(&MessageOrigin::Reserved),
+            Some(&QueueDepth {
+                pending: 1,
+                next_sequence: 2,
+            })
+        );
+        assert_eq!(mq.sequences().get(&MessageOrigin::Reserved), Some(&2));
+    }
+
+    #[test]
+    fn test_purge_sender() {
+        let mq = MessageSendQueue::new();
+        let ch = msg_channel::MessageChannel::new(mq.clone(), MessageOrigin::Reserved, TestSigner);
+        ch.push_message(&TestMessage(b"hello".to_vec()));
+        ch.push_message(&TestMessage(b"world".to_vec()));
+
+        mq.purge_sender(&MessageOrigin::Gatekeeper, 2);
+        assert_eq!(mq.count_messages(), 2);
+        mq.purge_sender(&MessageOrigin::Reserved, 1);
+        assert_eq!(mq.count_messages(), 1);
+        mq.purge_sender(&MessageOrigin::Reserved, 2);
+        assert_eq!(mq.count_messages(), 0);
+    }
+
+    #[test]
+    fn test_take_usage() {
+        let mq = MessageSendQueue::new();
+        let ch = msg_channel::MessageChannel::new(mq.clone(), MessageOrigin::Reserved, TestSigner);
+        ch.push_message(&TestMessage(b"hello".to_vec()));
+        ch.push_data(b"world".to_vec(), b"/other".to_vec());
+        ch.set_dummy(true);
+        ch.push_message(&TestMessage(b"dropped".to_vec()));
+
+        let usage = mq.take_usage();
+        let usage = usage.get(&MessageOrigin::Reserved).unwrap();
+        assert_eq!(usage.messages, 2);
+        assert_eq!(usage.bytes, 6 + 5);
+        assert_eq!(usage.topics.get(&b"/test"[..]), Some(&1));
+        assert_eq!(usage.topics.get(&b"/other"[..]), Some(&1));
+        assert!(mq.take_usage().is_empty());
+    }
+
     #[test]
     fn dump_type_info() {
         insta::assert_display_snapshot!(type_info_stringify::<
@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::hash::{Hash, Hasher};
@@ -119,6 +120,8 @@ pub struct BadOrigin;
 ///  Meaning of some special values appearing at the first byte:
 ///
 ///  - b'^': The topic's subscribers are on-chain only.
+///  - b'~': The topic is namespaced to a chain, see [`Topic::namespaced`].
+///  - b'*': The payloads sent to the topic are compressed, see [`Topic::compressed`].
 ///
 /// # Example:
 /// ```rust
@@ -147,27 +150,116 @@ impl core::fmt::Debug for Topic {
 
 impl Topic {
     const RESERVED_BYTES: &'static [u8] = b"~!@#$%&*_+-=|<>?,./;:'";
+    const NAMESPACE_PREFIX: u8 = b'~';
+    const COMPRESSED_PREFIX: u8 = b'*';
+    /// `~` + hex encoded genesis hash + `/`
+    const NAMESPACE_LEN: usize = 1 + 64 + 1;
 
     pub fn new(path: impl Into<Path>) -> Self {
         Self(path.into())
     }
 
+    /// Creates a topic bound to the chain with the given genesis hash.
+    ///
+    /// The namespace is part of the signed message, so a message sent to a namespaced topic
+    /// can not be replayed on another chain as long as the dispatcher of that chain enforces its
+    /// own namespace. See `MessageDispatcher::set_chain_namespace`.
+    pub fn namespaced(genesis_hash: &[u8; 32], path: impl Into<Path>) -> Self {
+        let path = path.into();
+        let mut namespaced = Vec::with_capacity(Self::NAMESPACE_LEN + path.len());
+        namespaced.push(Self::NAMESPACE_PREFIX);
+        namespaced.extend_from_slice(hex::encode(genesis_hash).as_bytes());
+        namespaced.push(b'/');
+        namespaced.extend_from_slice(&path);
+        Self(namespaced)
+    }
+
+    /// Creates a topic whose payloads are packed with [`crate::compression::pack`].
+    ///
+    /// The flag is part of the topic, so the senders, the dispatchers and the mq pallet agree on
+    /// it without any configuration. It comes after the namespace, if any, and before the
+    /// on-chain only indicator.
+    pub fn compressed(path: impl Into<Path>) -> Self {
+        let path = path.into();
+        let mut compressed = Vec::with_capacity(1 + path.len());
+        compressed.push(Self::COMPRESSED_PREFIX);
+        compressed.extend_from_slice(&path);
+        Self(compressed)
+    }
+
+    /// Returns whether the payloads sent to the topic are compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.unnamespaced().first() == Some(&Self::COMPRESSED_PREFIX)
+    }
+
+    /// Returns the topic without the compression flag, if the topic is compressed.
+    ///
+    /// A topic moved to compression keeps its former messages on chain, sent uncompressed to
+    /// this topic, so they must still reach the receivers of the compressed one.
+    pub fn uncompressed(&self) -> Option<Topic> {
+        if !self.is_compressed() {
+            return None;
+        }
+        let mut path = self.0.clone();
+        path.remove(self.0.len() - self.unnamespaced().len());
+        Some(Self(path))
+    }
+
+    /// Returns whether the messages sent to `path` are messages of this topic, including those
+    /// sent uncompressed before the topic was compressed.
+    pub fn accepts(&self, path: &[u8]) -> bool {
+        self.0 == path || matches!(self.uncompressed(), Some(topic) if topic.0 == path)
+    }
+
     pub fn path(&self) -> &Path {
         &self.0
     }
 
+    /// Returns the genesis hash of the chain the topic is bound to, if the topic is namespaced.
+    pub fn namespace(&self) -> Option<[u8; 32]> {
+        if self.0.len() < Self::NAMESPACE_LEN
+            || self.0[0] != Self::NAMESPACE_PREFIX
+            || self.0[Self::NAMESPACE_LEN - 1] != b'/'
+        {
+            return None;
+        }
+        let mut genesis_hash = [0u8; 32];
+        hex::decode_to_slice(&self.0[1..Self::NAMESPACE_LEN - 1], &mut genesis_hash).ok()?;
+        Some(genesis_hash)
+    }
+
+    /// Returns the topic path without the chain namespace.
+    pub fn unnamespaced(&self) -> &[u8] {
+        if self.namespace().is_some() {
+            &self.0[Self::NAMESPACE_LEN..]
+        } else {
+            &self.0
+        }
+    }
+
+    /// Returns the topic path without the chain namespace and the compression flag.
+    fn unflagged(&self) -> &[u8] {
+        let path = self.unnamespaced();
+        if self.is_compressed() {
+            &path[1..]
+        } else {
+            path
+        }
+    }
+
     pub fn is_offchain(&self) -> bool {
         if !self.is_valid() {
             return false;
         }
-        self.0[0] != b'^'
+        self.unflagged()[0] != b'^'
     }
 
     pub fn is_valid(&self) -> bool {
-        if self.0.is_empty() {
+        let path = self.unflagged();
+        if path.is_empty() {
             return false;
         }
-        !Self::RESERVED_BYTES.contains(&self.0[0])
+        !Self::RESERVED_BYTES.contains(&path[0])
     }
 }
 
@@ -263,6 +355,41 @@ pub struct SignedMessage {
     pub signature: Vec<u8>,
 }
 
+/// The depth of the egress queue of a sender.
+#[derive(Encode, Decode, TypeInfo, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct QueueDepth {
+    /// The number of messages kept in the queue, i.e. not yet known to be accepted on chain.
+    pub pending: u32,
+    /// The sequence of the next message to be sent.
+    pub next_sequence: u64,
+}
+
+/// The egress traffic of a sender within an accounting window.
+#[derive(
+    Encode, Decode, TypeInfo, Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize,
+)]
+pub struct SenderUsage {
+    /// The number of messages sent.
+    pub messages: u32,
+    /// The total size of the payloads sent.
+    pub bytes: u64,
+    /// The number of messages sent to each topic.
+    pub topics: BTreeMap<Path, u32>,
+}
+
+impl SenderUsage {
+    pub fn record(&mut self, topic: &[u8], payload_len: usize) {
+        self.messages = self.messages.saturating_add(1);
+        self.bytes = self.bytes.saturating_add(payload_len as u64);
+        let count = self.topics.entry(topic.to_vec()).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages == 0
+    }
+}
+
 impl SignedMessage {
     pub fn data_be_signed(&self) -> Vec<u8> {
         MessageToBeSigned {
@@ -332,6 +459,64 @@ mod test {
         println!("topic: {topic:?}");
     }
 
+    #[test]
+    fn test_namespaced_topic() {
+        let genesis_hash = [0xab; 32];
+        let topic = Topic::namespaced(&genesis_hash, *b"topic");
+        assert!(topic.is_valid());
+        assert!(topic.is_offchain());
+        assert_eq!(topic.namespace(), Some(genesis_hash));
+        assert_eq!(topic.unnamespaced(), b"topic");
+
+        let topic = Topic::namespaced(&genesis_hash, *b"^topic");
+        assert!(topic.is_valid());
+        assert!(!topic.is_offchain());
+
+        let topic = Topic::namespaced(&genesis_hash, *b"");
+        assert!(!topic.is_valid());
+
+        // A bad namespace is just an invalid topic
+        let topic = Topic::new(*b"~topic");
+        assert_eq!(topic.namespace(), None);
+        assert!(!topic.is_valid());
+    }
+
+    #[test]
+    fn test_compressed_topic() {
+        let topic = Topic::compressed(*b"topic");
+        assert!(topic.is_compressed());
+        assert!(topic.is_valid());
+        assert!(topic.is_offchain());
+        assert!(!Topic::new(*b"topic").is_compressed());
+
+        let topic = Topic::compressed(*b"^topic");
+        assert!(topic.is_valid());
+        assert!(!topic.is_offchain());
+
+        let topic = Topic::namespaced(&[0xab; 32], Topic::compressed(*b"topic"));
+        assert!(topic.is_compressed());
+        assert!(topic.is_offchain());
+
+        assert!(!Topic::compressed(*b"").is_valid());
+    }
+
+    #[test]
+    fn test_uncompressed_topic() {
+        let topic = Topic::compressed(*b"topic");
+        assert_eq!(topic.uncompressed(), Some(Topic::new(*b"topic")));
+        assert!(topic.accepts(b"*topic"));
+        assert!(topic.accepts(b"topic"));
+        assert!(!topic.accepts(b"other"));
+        assert_eq!(Topic::new(*b"topic").uncompressed(), None);
+        assert!(!Topic::new(*b"topic").accepts(b"*topic"));
+
+        let topic = Topic::namespaced(&[0xab; 32], Topic::compressed(*b"topic"));
+        assert_eq!(
+            topic.uncompressed(),
+            Some(Topic::namespaced(&[0xab; 32], *b"topic"))
+        );
+    }
+
     #[test]
     fn test_origin() {
         use sp_core::sr25519::Public;
@@ -1,4 +1,4 @@
-pub use request_scheduler::RequestScheduler;
+pub use request_scheduler::{AcquireError, RequestScheduler, ServingGuard};
 pub use task_scheduler::TaskScheduler;
 
 mod request_scheduler;
@@ -36,6 +36,8 @@ pub enum AcquireError {
     Overloaded,
     #[error("canceled while acquiring slot from the fair queue")]
     Canceled,
+    #[error("fair queue overloaded, the request can not start before its deadline, retry after {retry_after:?}")]
+    WouldMissDeadline { retry_after: Duration },
 }
 
 impl<FlowId: FlowIdType> RequestScheduler<FlowId> {
@@ -51,9 +53,24 @@ impl<FlowId: FlowIdType> RequestScheduler<FlowId> {
         &self,
         flow_id: FlowId,
         weight: u32,
+    ) -> Result<ServingGuard<FlowId>, AcquireError> {
+        self.acquire_before(flow_id, weight, None).await
+    }
+
+    /// Like `acquire`, but rejects the request immediately if it is estimated to not be able to
+    /// start before the given deadline.
+    pub async fn acquire_before(
+        &self,
+        flow_id: FlowId,
+        weight: u32,
+        deadline: Option<Instant>,
     ) -> Result<ServingGuard<FlowId>, AcquireError> {
         // Don't merge the following 2 lines of code into one line or you would get a deadlock.
-        let rx = self.inner.lock().unwrap().acquire(flow_id, weight)?;
+        let rx = self
+            .inner
+            .lock()
+            .unwrap()
+            .acquire(flow_id, weight, deadline)?;
         rx.await.or(Err(AcquireError::Canceled))
     }
 
@@ -104,6 +121,12 @@ impl<FlowId: FlowIdType> RequestScheduler<FlowId> {
     pub fn stats_global(&self) -> Counters {
         self.inner.lock().unwrap().counters.clone()
     }
+
+    /// The number of requests being served or waiting in the backlog.
+    pub fn in_flight(&self) -> u32 {
+        let inner = self.inner.lock().unwrap();
+        inner.serving + inner.backlog.len() as u32
+    }
 }
 
 struct Flow {
@@ -147,8 +170,9 @@ pub struct ServingGuard<FlowId: FlowIdType> {
 
 impl<FlowId: FlowIdType> Drop for ServingGuard<FlowId> {
     fn drop(&mut self) {
+        let elapsed = self.start_time.elapsed();
         let actual_cost = self.actual_cost.unwrap_or_else(|| {
-            let cost = self.start_time.elapsed().as_nanos() as VirtualTime;
+            let cost = elapsed.as_nanos() as VirtualTime;
             // Scale it in order to avoid underflow while dividing the cost by the weight.
             cost << 32
         });
@@ -156,7 +180,7 @@ impl<FlowId: FlowIdType> Drop for ServingGuard<FlowId> {
             .inner
             .lock()
             .unwrap()
-            .release(&self.flow_id, actual_cost);
+            .release(&self.flow_id, actual_cost, elapsed);
     }
 }
 
@@ -175,6 +199,8 @@ struct SchedulerInner<FlowId: FlowIdType> {
     serving: u32,
     virtual_time: VirtualTime,
     counters: Counters,
+    /// Moving average of the wall time the requests are served, used to estimate the waiting time.
+    average_serving_time: Duration,
 }
 
 unsafe impl<T: FlowIdType> Send for SchedulerInner<T> {}
@@ -190,14 +216,46 @@ impl<FlowId: FlowIdType> SchedulerInner<FlowId> {
             serving: 0,
             virtual_time: 0,
             counters: Counters::default(),
+            average_serving_time: Duration::ZERO,
+        }
+    }
+
+    /// Estimates how long a request with the given start tag would wait before being served.
+    fn estimated_wait(&self, start_tag: VirtualTime) -> Duration {
+        if self.serving < self.depth {
+            return Duration::ZERO;
         }
+        let ahead = self
+            .backlog
+            .iter()
+            .take_while(|(tag, _)| **tag <= start_tag)
+            .count() as u32;
+        // Every `depth` requests ahead take about one serving time, plus the ones being served.
+        self.average_serving_time * (ahead / self.depth + 1)
     }
 
     fn acquire(
         &mut self,
         flow_id: FlowId,
         weight: u32,
+        deadline: Option<Instant>,
     ) -> Result<Receiver<ServingGuard<FlowId>>, AcquireError> {
+        if let Some(deadline) = deadline {
+            let previous_finish_tag = self
+                .flows
+                .get(&flow_id)
+                .map_or(0, |flow| flow.previous_finish_tag);
+            let wait = self.estimated_wait(self.virtual_time.max(previous_finish_tag));
+            if Instant::now() + wait > deadline {
+                if let Some(flow) = self.flows.get_mut(&flow_id) {
+                    flow.counters.total += 1;
+                    flow.counters.dropped += 1;
+                }
+                self.counters.total += 1;
+                self.counters.dropped += 1;
+                return Err(AcquireError::WouldMissDeadline { retry_after: wait });
+            }
+        }
         let flow = self.flows.entry(flow_id.clone()).or_insert_with(|| Flow {
             previous_finish_tag: 0,
             average_cost: 0,
@@ -254,7 +312,8 @@ impl<FlowId: FlowIdType> SchedulerInner<FlowId> {
         Ok(rx)
     }
 
-    fn release(&mut self, flow: &FlowId, actual_cost: VirtualTime) {
+    fn release(&mut self, flow: &FlowId, actual_cost: VirtualTime, elapsed: Duration) {
+        self.average_serving_time = (self.average_serving_time * 4 + elapsed) / 5;
         if let Some(flow) = self.flows.get_mut(flow) {
             flow.average_cost = (flow.average_cost * 4 + actual_cost) / 5;
             flow.counters.time += actual_cost;
@@ -547,4 +606,55 @@ mod test {
             );
         }
     }
+
+    #[tokio::test]
+    async fn test_reject_request_missing_deadline() {
+        let queue = RequestScheduler::new(10, 1);
+
+        // Learn the serving time.
+        let guard = queue.acquire(1, 1).await.unwrap();
+        sleep_ms(50).await;
+        drop(guard);
+
+        let serving = queue.acquire(1, 1).await.unwrap();
+        let deadline = Instant::now() + Duration::from_millis(1);
+        let result = queue.acquire_before(1, 1, Some(deadline)).await;
+        assert!(matches!(
+            result,
+            Err(AcquireError::WouldMissDeadline { retry_after }) if retry_after > Duration::ZERO
+        ));
+        assert_eq!(queue.stats_for(&1).dropped, 1);
+        assert_eq!(queue.stats_global().dropped, 1);
+
+        // A far deadline is admitted to the backlog.
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let pending = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire_before(2, 1, Some(deadline)).await.is_ok() }
+        });
+        sleep_ms(10).await;
+        drop(serving);
+        assert!(pending.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let queue = RequestScheduler::new(10, 1);
+        assert_eq!(queue.in_flight(), 0);
+
+        let serving = queue.acquire(1, 1).await.unwrap();
+        assert_eq!(queue.in_flight(), 1);
+        let pending = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let _guard = queue.acquire(2, 1).await.unwrap();
+            }
+        });
+        sleep_ms(10).await;
+        assert_eq!(queue.in_flight(), 2);
+
+        drop(serving);
+        pending.await.unwrap();
+        assert_eq!(queue.in_flight(), 0);
+    }
 }
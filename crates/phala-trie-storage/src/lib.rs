@@ -193,6 +193,11 @@ where
             .collect()
     }
 
+    /// Sum of the size of all the trie nodes and their keys.
+    pub fn approx_size(&self) -> usize {
+        self.0.backend_storage().approx_size()
+    }
+
     pub fn as_trie_backend(&self) -> &InMemoryBackend<H> {
         &self.0
     }
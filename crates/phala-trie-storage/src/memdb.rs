@@ -287,6 +287,18 @@ where
         }
     }
 
+    /// Sum of the size of all the keys and values in the database.
+    pub fn approx_size(&self) -> usize
+    where
+        T: AsRef<[u8]>,
+        KF::Key: AsRef<[u8]>,
+    {
+        self.data
+            .iter()
+            .map(|(k, v)| k.as_ref().len() + v.0.as_ref().len())
+            .sum()
+    }
+
     /// Get the keys in the database together with number of underlying references.
     pub fn keys(&self) -> Map<KF::Key, i32> {
         self.data
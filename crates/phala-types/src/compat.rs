@@ -0,0 +1,584 @@
+//! Golden SCALE encodings of the mq messages, to keep the historical messages decodable.
+//!
+//! The messages sent through the mq stay on chain forever, and replaying the chain requires
+//! decoding every one of them with the current types. The golden vectors in
+//! `golden/mq_messages.tsv` are encodings of the messages in [`crate::messaging`] and
+//! [`crate::contract::messaging`] as they are on chain. The tests decode each of them with the current type bound to its topic and check that
+//! it encodes back to the same bytes, so that a change breaking the decoding of historical
+//! messages fails the build. The vectors of a topic moved to compression keep the uncompressed
+//! topic they were sent to, and those of the compressed topics hold the unpacked payloads.
+//!
+//! Downstream decoders can check their own implementation against the same vectors with
+//! [`golden_vectors`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use codec::{DecodeAll, Encode};
+use phala_mq::{BindTopic, Topic};
+use sp_core::{crypto::AccountId32, H256};
+
+use crate::contract::messaging::{
+    ClusterEvent, ClusterOperation, ContractOperation, WorkerClusterReport,
+};
+use crate::messaging::{
+    GatekeeperChange, GatekeeperEvent, GatekeeperLaunch, KeyDistribution, MessageReceipts,
+    MqUsageReport, SystemEvent, WorkingInfoUpdateEvent, WorkingReportEvent,
+};
+
+/// The golden vectors file, one message per line of `<name>\t<topic>\t<hex payload>`.
+pub const GOLDEN_VECTORS_TSV: &str = include_str!("../golden/mq_messages.tsv");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoldenVector {
+    /// The message type and variant, e.g. `GatekeeperLaunch::FirstGatekeeper`.
+    pub name: String,
+    pub topic: String,
+    /// The SCALE encoded payload.
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The line of the vectors file is malformed.
+    BadVector(usize),
+    /// No message type in this crate is bound to the topic.
+    UnknownTopic(String),
+    Decode(codec::Error),
+}
+
+/// Parses the golden vectors shipped with this crate.
+pub fn golden_vectors() -> Result<Vec<GoldenVector>, Error> {
+    GOLDEN_VECTORS_TSV
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            let mut fields = line.split('\t');
+            let mut parse = || -> Option<GoldenVector> {
+                let name = fields.next()?.into();
+                let topic = fields.next()?.into();
+                let payload = hex::decode(fields.next()?).ok()?;
+                Some(GoldenVector {
+                    name,
+                    topic,
+                    payload,
+                })
+            };
+            parse().ok_or(Error::BadVector(index + 1))
+        })
+        .collect()
+}
+
+macro_rules! with_message_types {
+    ($m: ident) => {
+        $m!(
+            SystemEvent,
+            WorkingReportEvent,
+            WorkingInfoUpdateEvent<u32>,
+            GatekeeperLaunch,
+            GatekeeperChange,
+            KeyDistribution<u32>,
+            GatekeeperEvent,
+            MqUsageReport,
+            MessageReceipts,
+            ClusterEvent,
+            ContractOperation<H256, AccountId32>,
+            WorkerClusterReport,
+            ClusterOperation<AccountId32>
+        )
+    };
+}
+
+/// The topics of the messages covered by the golden vectors.
+pub fn message_topics() -> Vec<Vec<u8>> {
+    macro_rules! topics {
+        ($($t: ty),*) => {
+            alloc::vec![$(<$t as BindTopic>::topic()),*]
+        };
+    }
+    with_message_types!(topics)
+}
+
+/// Decodes `payload` as the message type bound to `topic` and encodes it back.
+pub fn round_trip(topic: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    fn round_trip_as<T: DecodeAll + Encode>(mut payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let message = T::decode_all(&mut payload).map_err(Error::Decode)?;
+        Ok(message.encode())
+    }
+    macro_rules! dispatch {
+        ($($t: ty),*) => {
+            $(
+                if Topic::new(<$t as BindTopic>::topic()).accepts(topic) {
+                    return round_trip_as::<$t>(payload);
+                }
+            )*
+        };
+    }
+    with_message_types!(dispatch);
+    Err(Error::UnknownTopic(String::from_utf8_lossy(topic).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::messaging::{
+        BatchDispatchClusterKeyEvent, BatchRefreshClusterKeyEvent, ClusterKeyShare, ResourceType,
+    };
+    use crate::contract::{CodeIndex, ContractInfo, ExtensionPolicy, LogPolicy};
+    use crate::messaging::*;
+    use crate::WorkerIdentity;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+    use core::fmt::Debug;
+    use phala_mq::{MessageOrigin, SenderUsage};
+    use sp_core::{sr25519::Public, U256};
+
+    /// Checks that the golden vectors decode to the messages they were encoded from.
+    struct Expectations {
+        vectors: Vec<GoldenVector>,
+        checked: Vec<String>,
+    }
+
+    impl Expectations {
+        fn expect<T: BindTopic + DecodeAll + PartialEq + Debug>(&mut self, name: &str, message: T) {
+            let vector = self
+                .vectors
+                .iter()
+                .find(|v| v.name == name)
+                .unwrap_or_else(|| panic!("No golden vector named {name}"));
+            assert!(
+                Topic::new(T::topic()).accepts(vector.topic.as_bytes()),
+                "{name} is bound to another topic"
+            );
+            let decoded = T::decode_all(&mut &vector.payload[..])
+                .unwrap_or_else(|err| panic!("{name} no longer decodes: {err:?}"));
+            assert_eq!(decoded, message, "{name} decodes to another message");
+            self.checked.push(name.into());
+        }
+    }
+
+    fn key(byte: u8) -> Public {
+        Public([byte; 32])
+    }
+
+    fn account(byte: u8) -> AccountId32 {
+        AccountId32::new([byte; 32])
+    }
+
+    fn encrypted_key() -> EncryptedKey {
+        EncryptedKey {
+            ecdh_pubkey: key(0x22),
+            encrypted_key: vec![0xaa; 4],
+            iv: [0x44; 12],
+        }
+    }
+
+    fn worker_event(event: WorkerEvent) -> SystemEvent {
+        SystemEvent::new_worker_event(key(0x11), event)
+    }
+
+    fn cluster_op(op: ClusterOperation<AccountId32>) -> ClusterOperation<AccountId32> {
+        op
+    }
+
+    #[test]
+    fn golden_vectors_decode_to_their_messages() {
+        let mut ex = Expectations {
+            vectors: golden_vectors().unwrap(),
+            checked: Vec::new(),
+        };
+
+        ex.expect(
+            "SystemEvent::WorkerEvent(Registered)",
+            worker_event(WorkerEvent::Registered(WorkerInfo {
+                confidence_level: 2,
+            })),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(BenchStart)",
+            worker_event(WorkerEvent::BenchStart { duration: 10 }),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(BenchScore)",
+            worker_event(WorkerEvent::BenchScore(1234)),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(Started)",
+            worker_event(WorkerEvent::Started {
+                session_id: 7,
+                init_v: 1 << 64,
+                init_p: 100,
+            }),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(Stopped)",
+            worker_event(WorkerEvent::Stopped),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(EnterUnresponsive)",
+            worker_event(WorkerEvent::EnterUnresponsive),
+        );
+        ex.expect(
+            "SystemEvent::WorkerEvent(ExitUnresponsive)",
+            worker_event(WorkerEvent::ExitUnresponsive),
+        );
+        ex.expect(
+            "SystemEvent::HeartbeatChallenge",
+            SystemEvent::HeartbeatChallenge(HeartbeatChallenge {
+                seed: U256::from(0x1234),
+                online_target: U256::MAX,
+            }),
+        );
+
+        ex.expect(
+            "WorkingReportEvent::Heartbeat",
+            WorkingReportEvent::Heartbeat {
+                session_id: 7,
+                challenge_block: 1000,
+                challenge_time: 6640625000,
+                iterations: 123456789,
+            },
+        );
+        ex.expect(
+            "WorkingReportEvent::HeartbeatV2",
+            WorkingReportEvent::HeartbeatV2 {
+                session_id: 7,
+                challenge_block: 1000,
+                challenge_time: 6640625000,
+                iterations: 123456789,
+                n_clusters: 1,
+                n_contracts: 5,
+            },
+        );
+        ex.expect(
+            "WorkingInfoUpdateEvent",
+            WorkingInfoUpdateEvent::<u32> {
+                block_number: 1000,
+                timestamp_ms: 6640625000,
+                offline: vec![key(0x11)],
+                recovered_to_online: vec![key(0x12)],
+                settle: vec![SettleInfo {
+                    pubkey: key(0x11),
+                    v: 1 << 64,
+                    payout: 1 << 63,
+                    treasury: 0,
+                }],
+            },
+        );
+
+        ex.expect(
+            "GatekeeperLaunch::FirstGatekeeper",
+            GatekeeperLaunch::first_gatekeeper(key(0x11), key(0x22)),
+        );
+        ex.expect(
+            "GatekeeperLaunch::MasterPubkeyOnChain",
+            GatekeeperLaunch::master_pubkey_on_chain(key(0x33)),
+        );
+        ex.expect(
+            "GatekeeperLaunch::RotateMasterKey",
+            GatekeeperLaunch::rotate_master_key(
+                1,
+                vec![WorkerIdentity {
+                    pubkey: key(0x11),
+                    ecdh_pubkey: key(0x22),
+                }],
+            ),
+        );
+        ex.expect(
+            "GatekeeperLaunch::MasterPubkeyRotated",
+            GatekeeperLaunch::master_pubkey_rotated(key(0x33)),
+        );
+        ex.expect(
+            "GatekeeperChange::Registered",
+            GatekeeperChange::gatekeeper_registered(key(0x11), key(0x22)),
+        );
+        ex.expect(
+            "GatekeeperChange::Unregistered",
+            GatekeeperChange::gatekeeper_unregistered(key(0x11)),
+        );
+
+        ex.expect(
+            "KeyDistribution::MasterKeyDistribution",
+            KeyDistribution::<u32>::master_key_distribution(
+                key(0x11),
+                key(0x22),
+                vec![0xaa; 4],
+                [0x44; 12],
+            ),
+        );
+        ex.expect(
+            "KeyDistribution::MasterKeyRotation",
+            KeyDistribution::<u32>::MasterKeyRotation(BatchRotateMasterKeyEvent {
+                rotation_id: 1,
+                secret_keys: BTreeMap::from([(key(0x11), encrypted_key())]),
+                sender: key(0x12),
+                sig: vec![0x55; 64],
+            }),
+        );
+        ex.expect(
+            "KeyDistribution::MasterKeyHistory",
+            KeyDistribution::<u32>::MasterKeyHistory(DispatchMasterKeyHistoryEvent {
+                dest: key(0x11),
+                encrypted_master_key_history: vec![(1, 1000, encrypted_key())],
+            }),
+        );
+
+        ex.expect(
+            "GatekeeperEvent::NewRandomNumber",
+            GatekeeperEvent::NewRandomNumber(RandomNumberEvent {
+                block_number: 1000,
+                random_number: [0x66; 32],
+                last_random_number: [0x77; 32],
+            }),
+        );
+        ex.expect(
+            "GatekeeperEvent::TokenomicParametersChanged",
+            GatekeeperEvent::TokenomicParametersChanged(TokenomicParameters {
+                pha_rate: 1,
+                rho: 2,
+                budget_per_block: 3,
+                v_max: 4,
+                cost_k: 5,
+                cost_b: 6,
+                slash_rate: 7,
+                treasury_ratio: 8,
+                heartbeat_window: 10,
+                rig_k: 11,
+                rig_b: 12,
+                re: 13,
+                k: 14,
+                kappa: 15,
+            }),
+        );
+        ex.expect("GatekeeperEvent::_RepairV", GatekeeperEvent::_RepairV);
+        ex.expect(
+            "GatekeeperEvent::_PhalaLaunched",
+            GatekeeperEvent::_PhalaLaunched,
+        );
+        ex.expect("GatekeeperEvent::_UnrespFix", GatekeeperEvent::_UnrespFix);
+
+        ex.expect(
+            "MqUsageReport",
+            MqUsageReport {
+                window_end: 3600,
+                senders: vec![(
+                    MessageOrigin::Worker(key(0x11)),
+                    SenderUsage {
+                        messages: 3,
+                        bytes: 300,
+                        topics: BTreeMap::from([(b"phala/mining/report".to_vec(), 3)]),
+                    },
+                )],
+            },
+        );
+        ex.expect(
+            "MessageReceipts",
+            MessageReceipts {
+                block: 1000,
+                receipts: vec![
+                    (MessageOrigin::Pallet(b"PhalaMq".to_vec()), 5),
+                    (MessageOrigin::Gatekeeper, 7),
+                ],
+            },
+        );
+
+        ex.expect(
+            "ClusterEvent::DeployCluster",
+            ClusterEvent::DeployCluster {
+                owner: account(0x22),
+                cluster: H256::repeat_byte(0x33),
+                workers: vec![WorkerIdentity {
+                    pubkey: key(0x11),
+                    ecdh_pubkey: key(0x12),
+                }],
+                deposit: 1000,
+                gas_price: 1,
+                deposit_per_item: 2,
+                deposit_per_byte: 3,
+                treasury_account: account(0x44),
+            },
+        );
+        ex.expect(
+            "ContractOperation::InstantiateCode",
+            ContractOperation::instantiate_code(
+                ContractInfo {
+                    deployer: account(0x22),
+                    code_index: CodeIndex::WasmCode(H256::repeat_byte(0x66)),
+                    salt: vec![1, 2],
+                    cluster_id: H256::repeat_byte(0x33),
+                    instantiate_data: vec![0xaa, 0xbb],
+                },
+                10,
+                1000000,
+                Some(100),
+            ),
+        );
+        ex.expect(
+            "WorkerClusterReport::ClusterDeployed",
+            WorkerClusterReport::ClusterDeployed {
+                id: H256::repeat_byte(0x33),
+                pubkey: key(0x11),
+            },
+        );
+        ex.expect(
+            "WorkerClusterReport::ClusterDeploymentFailed",
+            WorkerClusterReport::ClusterDeploymentFailed {
+                id: H256::repeat_byte(0x33),
+            },
+        );
+
+        ex.expect(
+            "ClusterOperation::DispatchKeys",
+            cluster_op(ClusterOperation::DispatchKeys(
+                BatchDispatchClusterKeyEvent {
+                    secret_keys: BTreeMap::from([(key(0x11), encrypted_key())]),
+                    cluster: H256::repeat_byte(0x33),
+                    owner: account(0x22),
+                    deposit: 1000,
+                    gas_price: 1,
+                    deposit_per_item: 2,
+                    deposit_per_byte: 3,
+                    treasury_account: account(0x44),
+                },
+            )),
+        );
+        ex.expect(
+            "ClusterOperation::DestroyCluster",
+            cluster_op(ClusterOperation::DestroyCluster(H256::repeat_byte(0x33))),
+        );
+        ex.expect(
+            "ClusterOperation::UploadResource",
+            cluster_op(ClusterOperation::UploadResource {
+                origin: account(0x22),
+                cluster_id: H256::repeat_byte(0x33),
+                resource_type: ResourceType::SidevmCode,
+                resource_data: vec![1, 2, 3],
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::Deposit",
+            cluster_op(ClusterOperation::Deposit {
+                cluster_id: H256::repeat_byte(0x33),
+                account: account(0x22),
+                amount: 1000,
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::RemoveWorker",
+            cluster_op(ClusterOperation::RemoveWorker {
+                cluster_id: H256::repeat_byte(0x33),
+                worker: key(0x11),
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::SetLogPolicy",
+            cluster_op(ClusterOperation::SetLogPolicy {
+                cluster_id: H256::repeat_byte(0x33),
+                policy: LogPolicy {
+                    max_level: 3,
+                    redact_payloads: true,
+                    opted_out: vec![H256::repeat_byte(0x55)],
+                },
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::UpgradeContract",
+            cluster_op(ClusterOperation::UpgradeContract {
+                origin: account(0x22),
+                cluster_id: H256::repeat_byte(0x33),
+                contract_id: H256::repeat_byte(0x55),
+                code_hash: H256::repeat_byte(0x66),
+                call_on_upgrade: true,
+                gas_limit: 1000000,
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::UpdateSidevmCode",
+            cluster_op(ClusterOperation::UpdateSidevmCode {
+                origin: account(0x22),
+                cluster_id: H256::repeat_byte(0x33),
+                contract_id: H256::repeat_byte(0x55),
+                code_hash: H256::repeat_byte(0x66),
+            }),
+        );
+        ex.expect(
+            "ClusterOperation::SetExtensionPolicy",
+            cluster_op(ClusterOperation::SetExtensionPolicy {
+                cluster_id: H256::repeat_byte(0x33),
+                policy: ExtensionPolicy {
+                    disable_http: true,
+                    disable_randomness: false,
+                    disable_secrets: true,
+                },
+            }),
+        );
+        ex.expect(
+            "ClusterEvent::RefreshClusterKey",
+            ClusterEvent::RefreshClusterKey {
+                cluster: H256::repeat_byte(0x33),
+                pubkey: key(0x11),
+                workers: vec![WorkerIdentity {
+                    pubkey: key(0x11),
+                    ecdh_pubkey: key(0x12),
+                }],
+                refresh_id: 2,
+            },
+        );
+        ex.expect(
+            "WorkerClusterReport::ClusterKeyRefreshed",
+            WorkerClusterReport::ClusterKeyRefreshed {
+                id: H256::repeat_byte(0x33),
+                refresh_id: 2,
+            },
+        );
+        ex.expect(
+            "ClusterOperation::RefreshKeys",
+            cluster_op(ClusterOperation::RefreshKeys(BatchRefreshClusterKeyEvent {
+                refresh_id: 2,
+                cluster: H256::repeat_byte(0x33),
+                pubkey: key(0x11),
+                shares: BTreeMap::from([(
+                    key(0x11),
+                    ClusterKeyShare {
+                        encrypted_key: encrypted_key(),
+                        pubkey: key(0x55),
+                    },
+                )]),
+            })),
+        );
+
+        for vector in &ex.vectors {
+            assert!(
+                ex.checked.contains(&vector.name),
+                "No expected message for {}",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn golden_vectors_round_trip() {
+        for vector in golden_vectors().unwrap() {
+            let encoded = round_trip(vector.topic.as_bytes(), &vector.payload)
+                .unwrap_or_else(|err| panic!("{} no longer decodes: {:?}", vector.name, err));
+            assert_eq!(
+                hex::encode(encoded),
+                hex::encode(&vector.payload),
+                "{} encodes differently",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn every_message_type_has_vectors() {
+        let vectors = golden_vectors().unwrap();
+        for topic in message_topics() {
+            let topic = Topic::new(topic);
+            assert!(
+                vectors.iter().any(|v| topic.accepts(v.topic.as_bytes())),
+                "No golden vector for topic {topic:?}"
+            );
+        }
+    }
+}
@@ -5,6 +5,9 @@ use codec::{Decode, Encode};
 use scale_info::TypeInfo;
 use sp_core::{bounded::BoundedVec, ConstU32};
 
+#[cfg(feature = "enable_serde")]
+use serde::{Deserialize, Serialize};
+
 pub use phala_mq::{ContractClusterId, ContractId};
 
 pub type ContractId32 = u32;
@@ -53,7 +56,7 @@ pub mod messaging {
     use core::fmt::Debug;
     use scale_info::TypeInfo;
 
-    use super::{ContractClusterId, ContractInfo};
+    use super::{ContractClusterId, ContractId, ContractInfo, ExtensionPolicy, LogPolicy};
     use crate::messaging::EncryptedKey;
     use crate::{ClusterPublicKey, WorkerIdentity, WorkerPublicKey};
     use phala_mq::bind_topic;
@@ -73,6 +76,14 @@ pub mod messaging {
             deposit_per_byte: u128,
             treasury_account: AccountId32,
         },
+        /// Re-share the key of a deployed cluster among its workers, keeping the public key.
+        RefreshClusterKey {
+            cluster: ContractClusterId,
+            pubkey: ClusterPublicKey,
+            workers: Vec<WorkerIdentity>,
+            /// Increased on each refresh of the cluster, to discard replayed refreshes.
+            refresh_id: u64,
+        },
     }
 
     bind_topic!(ContractOperation<CodeHash, AccountId>, b"phala/contract/op");
@@ -120,6 +131,10 @@ pub mod messaging {
         ClusterDeploymentFailed {
             id: ContractClusterId,
         },
+        ClusterKeyRefreshed {
+            id: ContractClusterId,
+            refresh_id: u64,
+        },
     }
 
     #[derive(Encode, Decode, TypeInfo, Clone, PartialEq, Eq, Debug)]
@@ -135,7 +150,26 @@ pub mod messaging {
         pub treasury_account: AccountId32,
     }
 
-    bind_topic!(ClusterOperation<AccountId>, b"phala/cluster/key");
+    /// A share of the cluster key, encrypted to a worker.
+    #[derive(Encode, Decode, TypeInfo, Clone, PartialEq, Eq, Debug)]
+    pub struct ClusterKeyShare {
+        pub encrypted_key: EncryptedKey,
+        /// The public key of the share. The public keys of all the shares add up to the public
+        /// key of the cluster.
+        pub pubkey: ClusterPublicKey,
+    }
+
+    #[derive(Encode, Decode, TypeInfo, Clone, PartialEq, Eq, Debug)]
+    pub struct BatchRefreshClusterKeyEvent {
+        pub refresh_id: u64,
+        pub cluster: ContractClusterId,
+        /// The public key of the cluster, which must be unchanged by the refresh.
+        pub pubkey: ClusterPublicKey,
+        pub shares: BTreeMap<WorkerPublicKey, ClusterKeyShare>,
+    }
+
+    // Compressed, carrying the cluster keys and the code of the contracts
+    bind_topic!(ClusterOperation<AccountId>, b"*phala/cluster/key");
     #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
     pub enum ClusterOperation<AccountId> {
         // TODO.shelven: a better way for real large batch key distribution
@@ -162,6 +196,42 @@ pub mod messaging {
             cluster_id: ContractClusterId,
             worker: WorkerPublicKey,
         },
+        /// Replace the log policy of a cluster.
+        SetLogPolicy {
+            cluster_id: ContractClusterId,
+            policy: LogPolicy,
+        },
+        /// Swap the code of a contract to an uploaded code.
+        ///
+        /// If `call_on_upgrade` is set, the `on_upgrade` message of the new code is called with
+        /// `gas_limit` to migrate the storage, and the upgrade is reverted if the call fails.
+        UpgradeContract {
+            origin: AccountId,
+            cluster_id: ContractClusterId,
+            contract_id: ContractId,
+            code_hash: sp_core::H256,
+            call_on_upgrade: bool,
+            gas_limit: u64,
+        },
+        /// Swap the sidevm program of a contract to an uploaded sidevm code.
+        ///
+        /// The running instance is stopped gracefully and the new code is started with the same
+        /// config, keeping the local cache of the contract.
+        UpdateSidevmCode {
+            origin: AccountId,
+            cluster_id: ContractClusterId,
+            contract_id: ContractId,
+            code_hash: sp_core::H256,
+        },
+        /// Replace the chain extensions disabled in a cluster.
+        SetExtensionPolicy {
+            cluster_id: ContractClusterId,
+            policy: ExtensionPolicy,
+        },
+        /// MessageOrigin::Gatekeeper -> ALL
+        ///
+        /// Fresh shares of the cluster key, while the contracts of the cluster keep running.
+        RefreshKeys(BatchRefreshClusterKeyEvent),
     }
 
     impl<AccountId> ClusterOperation<AccountId> {
@@ -206,6 +276,73 @@ pub struct ClusterInfo<AccountId> {
     pub deposit_per_byte: u128,
 }
 
+/// The most contracts a [`LogPolicy`] can opt out.
+pub const MAX_LOG_OPTED_OUT_CONTRACTS: usize = 64;
+
+/// What the workers of a cluster forward to the log server of the cluster, and what the sidevm
+/// instances of the cluster log on the workers.
+///
+/// The policy is enforced inside the workers, so that confidential data does not leak through a
+/// log server which is readable by the public.
+#[cfg_attr(feature = "enable_serde", derive(Serialize, Deserialize))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
+pub struct LogPolicy {
+    /// The most verbose level of the forwarded logs, as in `log::Level`: 1 for errors only, up
+    /// to 5 for traces. 0 drops all the logs.
+    pub max_level: u8,
+    /// Replace the ink event payloads and the message outputs with their blake2_256 hashes.
+    pub redact_payloads: bool,
+    /// Contracts whose logs, events and outputs are not forwarded at all, at most
+    /// [`MAX_LOG_OPTED_OUT_CONTRACTS`].
+    pub opted_out: Vec<ContractId>,
+}
+
+impl Default for LogPolicy {
+    fn default() -> Self {
+        Self {
+            max_level: 5,
+            redact_payloads: false,
+            opted_out: Vec::new(),
+        }
+    }
+}
+
+impl LogPolicy {
+    /// Whether anything of the contract is forwarded.
+    pub fn forwards(&self, contract: &ContractId) -> bool {
+        !self.opted_out.contains(contract)
+    }
+
+    /// Whether a log of the contract at the given level is forwarded.
+    pub fn forwards_log(&self, contract: &ContractId, level: u8) -> bool {
+        level <= self.max_level && self.forwards(contract)
+    }
+
+    /// The payload as forwarded.
+    pub fn redact(&self, payload: Vec<u8>) -> Vec<u8> {
+        if self.redact_payloads {
+            sp_core::blake2_256(&payload).to_vec()
+        } else {
+            payload
+        }
+    }
+}
+
+/// The chain extensions disabled in a cluster by its owner.
+///
+/// Contracts calling a disabled extension fail with an `ExtensionDisabled` error, in queries and
+/// transactions alike.
+#[cfg_attr(feature = "enable_serde", derive(Serialize, Deserialize))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Debug, Default, TypeInfo)]
+pub struct ExtensionPolicy {
+    /// Disables `http_request` and `batch_http_request`.
+    pub disable_http: bool,
+    /// Disables `getrandom`.
+    pub disable_randomness: bool,
+    /// Disables `secret_set`, `secret_get` and `secret_remove`.
+    pub disable_secrets: bool,
+}
+
 /// On-chain contract registration info
 #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
 pub struct ContractInfo<CodeHash, AccountId> {
@@ -442,4 +579,27 @@ mod tests {
         let data = Data(vec![1, 2, 3]).encode();
         assert_eq!(data, vec![1, 2, 3]);
     }
+
+    #[test]
+    fn log_policy_works() {
+        let private = ContractId([1; 32]);
+        let public = ContractId([2; 32]);
+        let policy = LogPolicy {
+            max_level: 3,
+            redact_payloads: true,
+            opted_out: vec![private],
+        };
+        assert!(policy.forwards_log(&public, 3));
+        assert!(!policy.forwards_log(&public, 4));
+        assert!(!policy.forwards_log(&private, 1));
+        assert!(!policy.forwards(&private));
+        assert_eq!(
+            policy.redact(vec![1, 2, 3]),
+            sp_core::blake2_256(&[1, 2, 3]).to_vec()
+        );
+
+        let policy = LogPolicy::default();
+        assert!(policy.forwards_log(&private, 5));
+        assert_eq!(policy.redact(vec![1, 2, 3]), vec![1, 2, 3]);
+    }
 }
@@ -1,7 +1,9 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 extern crate alloc;
 
+pub mod compat;
 pub mod contract;
+pub mod schema;
 
 use alloc::borrow::Cow;
 use alloc::string::String;
@@ -30,8 +32,21 @@ pub mod messaging {
     use super::{EcdhPublicKey, MasterPublicKey, WorkerIdentity, WorkerPublicKey};
 
     pub use phala_mq::bind_topic;
+    pub use phala_mq::compression;
     pub use phala_mq::types::*;
 
+    /// The topic `path` bound to the chain with the given genesis hash.
+    ///
+    /// Messages sent to it are dropped by the dispatchers of any other chain.
+    pub fn chain_topic(genesis_hash: &sp_core::H256, path: impl Into<Path>) -> Topic {
+        Topic::namespaced(genesis_hash.as_fixed_bytes(), path)
+    }
+
+    /// The topic of message `T` bound to the chain with the given genesis hash.
+    pub fn chain_bound_topic<T: BindTopic>(genesis_hash: &sp_core::H256) -> Topic {
+        chain_topic(genesis_hash, T::topic())
+    }
+
     // TODO.kevin: reuse the Payload in secret_channel.rs.
     #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
     pub enum CommandPayload<T> {
@@ -274,8 +289,8 @@ pub mod messaging {
         }
     }
 
-    // Messages: Distribution of master key and contract keys
-    bind_topic!(KeyDistribution<BlockNumber>, b"phala/gatekeeper/key");
+    // Messages: Distribution of master key and contract keys, compressed
+    bind_topic!(KeyDistribution<BlockNumber>, b"*phala/gatekeeper/key");
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
     pub enum KeyDistribution<BlockNumber> {
         /// Legacy single master key sharing, use `MasterKeyHistory` after we enable master key rotation
@@ -367,6 +382,37 @@ pub mod messaging {
         }
     }
 
+    /// The number of blocks in an mq usage accounting window.
+    pub const MQ_USAGE_WINDOW: u32 = 1800;
+
+    /// The max number of senders in an `MqUsageReport`, a worker hosting more senders splits its
+    /// report.
+    pub const MAX_USAGE_REPORT_SENDERS: u32 = 64;
+
+    // Messages: MQ usage
+    bind_topic!(MqUsageReport, b"^phala/mq/usage");
+    /// Worker -> pallet-mq
+    ///
+    /// The egress traffic of the senders hosted by a worker within the accounting window ending at
+    /// `window_end`. The senders without traffic are omitted, and at most
+    /// `MAX_USAGE_REPORT_SENDERS` are reported per message.
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct MqUsageReport {
+        pub window_end: u32,
+        pub senders: Vec<(MessageOrigin, SenderUsage)>,
+    }
+
+    bind_topic!(MessageReceipts, b"phala/mq/receipts");
+    /// pallet-mq -> Worker
+    ///
+    /// The offchain messages accepted by pallet-mq in `block`, as the next expected sequence of
+    /// each sender. Only emitted when the receipts are enabled on chain.
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo, Default)]
+    pub struct MessageReceipts {
+        pub block: u32,
+        pub receipts: Vec<(MessageOrigin, u64)>,
+    }
+
     // Messages: Gatekeeper
     bind_topic!(GatekeeperEvent, b"phala/gatekeeper/event");
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
@@ -587,6 +633,36 @@ pub struct WorkerEndpointPayload {
     pub signing_time: u64,
 }
 
+/// The measurement of an SGX enclave, the same for IAS and DCAP attestations.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, TypeInfo)]
+pub struct SgxMeasurement {
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+}
+
+/// The identity of a pRuntime instance, signed by its worker identity key.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, TypeInfo)]
+pub struct EnclaveIdentityPayload {
+    pub pubkey: WorkerPublicKey,
+    /// None if the pRuntime is not running in an SGX enclave.
+    pub sgx_measurement: Option<SgxMeasurement>,
+    /// The attestation provider chosen at init.
+    pub attestation_provider: Option<AttestationProvider>,
+    pub supported_attestation_methods: Vec<String>,
+    pub version: String,
+    pub git_revision: String,
+    /// Bit flags of the optional RPC features.
+    pub rpc_features: u64,
+    pub max_supported_pink_runtime_version: (u32, u32),
+    /// The genesis block hash the pRuntime was initialized with.
+    pub genesis_block_hash: H256,
+    pub para_id: u32,
+    pub dev_mode: bool,
+    pub signing_time: u64,
+}
+
 #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, Default, TypeInfo)]
 pub struct RoundInfo<BlockNumber> {
     pub round: u32,
@@ -634,6 +710,7 @@ pub enum SignedContentType {
     MasterKeyStore = 4,
     ClusterStateRequest = 5,
     EventChainBlock = 6,
+    EnclaveIdentity = 7,
 }
 
 pub fn wrap_content_to_sign(data: &[u8], sigtype: SignedContentType) -> Cow<[u8]> {
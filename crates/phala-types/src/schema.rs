@@ -0,0 +1,133 @@
+//! Machine-readable schema of the mq messages.
+//!
+//! The schema is a scale-info portable registry with the types of all the messages bound to a
+//! topic in this crate, plus the signed message envelope they are wrapped in on chain. It can be
+//! serialized to JSON and fed to any scale-info aware decoder (e.g. polkadot.js or
+//! scale-info-py), so that Phala mq traffic can be decoded without porting the Rust types.
+//!
+//! Generic messages are instantiated with the types used on the Phala chains, i.e. `u32` block
+//! numbers, `AccountId32` accounts and `H256` code hashes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use phala_mq::{BindTopic, SignedMessage};
+use scale_info::{PortableRegistry, Registry, TypeInfo};
+use sp_core::{crypto::AccountId32, H256};
+
+use crate::contract::messaging::{
+    ClusterEvent, ClusterOperation, ContractOperation, WorkerClusterReport,
+};
+use crate::messaging::{
+    GatekeeperChange, GatekeeperEvent, GatekeeperLaunch, KeyDistribution, SystemEvent,
+    WorkingInfoUpdateEvent, WorkingReportEvent,
+};
+
+#[cfg(feature = "enable_serde")]
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the layout of [`MqSchema`] itself changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A topic and the type of the messages published to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable_serde", derive(Serialize, Deserialize))]
+pub struct TopicSchema {
+    /// The topic path. Paths starting with `^` are system topics which only accept messages
+    /// from the chain.
+    pub topic: String,
+    /// The type id of the payload in [`MqSchema::types`].
+    pub type_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable_serde", derive(Serialize, Deserialize))]
+pub struct MqSchema {
+    pub version: u32,
+    /// The type id of `phala_mq::SignedMessage`, the envelope of the messages on chain.
+    pub signed_message_type_id: u32,
+    pub topics: Vec<TopicSchema>,
+    pub types: PortableRegistry,
+}
+
+struct Builder {
+    registry: Registry,
+    topics: Vec<TopicSchema>,
+}
+
+impl Builder {
+    fn register<T: TypeInfo + 'static>(&mut self) -> u32 {
+        self.registry
+            .register_type(&scale_info::meta_type::<T>())
+            .id
+    }
+
+    fn topic<T: BindTopic + TypeInfo + 'static>(mut self) -> Self {
+        let type_id = self.register::<T>();
+        self.topics.push(TopicSchema {
+            topic: String::from_utf8_lossy(&T::topic()).into(),
+            type_id,
+        });
+        self
+    }
+}
+
+/// Returns the schema of all the mq messages defined in this crate.
+pub fn mq_schema() -> MqSchema {
+    let mut builder = Builder {
+        registry: Registry::new(),
+        topics: Vec::new(),
+    };
+    let signed_message_type_id = builder.register::<SignedMessage>();
+    let builder = builder
+        .topic::<SystemEvent>()
+        .topic::<WorkingReportEvent>()
+        .topic::<WorkingInfoUpdateEvent<u32>>()
+        .topic::<GatekeeperLaunch>()
+        .topic::<GatekeeperChange>()
+        .topic::<KeyDistribution<u32>>()
+        .topic::<GatekeeperEvent>()
+        .topic::<ClusterEvent>()
+        .topic::<ContractOperation<H256, AccountId32>>()
+        .topic::<WorkerClusterReport>()
+        .topic::<ClusterOperation<AccountId32>>();
+    MqSchema {
+        version: SCHEMA_VERSION,
+        signed_message_type_id,
+        topics: builder.topics,
+        types: builder.registry.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_topics_resolve() {
+        let schema = mq_schema();
+        assert!(schema
+            .types
+            .resolve(schema.signed_message_type_id)
+            .is_some());
+        for topic in &schema.topics {
+            assert!(
+                schema.types.resolve(topic.type_id).is_some(),
+                "{} is not in the registry",
+                topic.topic
+            );
+        }
+        let mut topics: Vec<_> = schema.topics.iter().map(|t| &t.topic).collect();
+        topics.sort();
+        topics.dedup();
+        assert_eq!(topics.len(), schema.topics.len());
+    }
+
+    #[cfg(feature = "enable_serde")]
+    #[test]
+    fn json_roundtrip() {
+        let schema = mq_schema();
+        let json = serde_json::to_string(&schema).unwrap();
+        let decoded: MqSchema = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, schema);
+    }
+}
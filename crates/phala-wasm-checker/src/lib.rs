@@ -1,8 +1,10 @@
 pub use error::ParseError;
+pub use validate::{validate, CodeLimits, CodeRejection};
 
 use wasmparser::{Parser, Payload};
 
 mod error;
+mod validate;
 
 #[derive(Default)]
 pub struct WasmInfo {
@@ -0,0 +1,247 @@
+use std::fmt::{self, Write as _};
+
+use wasmparser::{MemoryType, Operator, Parser, Payload, TypeRef};
+
+/// Limits a wasm code has to satisfy to be accepted.
+#[derive(Debug, Clone, Default)]
+pub struct CodeLimits {
+    /// Max size of the code in bytes.
+    pub max_code_size: usize,
+    /// Max number of pages of any memory declared or imported by the code.
+    pub max_memory_pages: Option<u64>,
+    /// Reject instructions with non-deterministic results: floats, atomics and relaxed SIMD.
+    pub deterministic: bool,
+    /// Names of denied instructions as in `wasmparser::Operator`, e.g. `MemoryAtomicWait32`.
+    pub denied_operators: &'static [&'static str],
+}
+
+/// The reason a code is rejected by [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeRejection {
+    TooLarge { size: usize, max: usize },
+    InvalidWasm(String),
+    DeniedInstruction(String),
+    MemoryTooLarge { pages: u64, max: u64 },
+    NonDeterministic(String),
+}
+
+impl std::error::Error for CodeRejection {}
+impl fmt::Display for CodeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodeRejection::TooLarge { size, max } => {
+                write!(f, "Code too large: {size} bytes, max {max}")
+            }
+            CodeRejection::InvalidWasm(err) => write!(f, "Invalid wasm: {err}"),
+            CodeRejection::DeniedInstruction(name) => write!(f, "Denied instruction: {name}"),
+            CodeRejection::MemoryTooLarge { pages, max } => {
+                write!(f, "Memory too large: {pages} pages, max {max}")
+            }
+            CodeRejection::NonDeterministic(what) => write!(f, "Non-deterministic feature: {what}"),
+        }
+    }
+}
+
+impl From<wasmparser::BinaryReaderError> for CodeRejection {
+    fn from(e: wasmparser::BinaryReaderError) -> Self {
+        CodeRejection::InvalidWasm(e.to_string())
+    }
+}
+
+/// Checks the code against the given limits without instantiating it.
+pub fn validate(code: &[u8], limits: &CodeLimits) -> Result<(), CodeRejection> {
+    if code.len() > limits.max_code_size {
+        return Err(CodeRejection::TooLarge {
+            size: code.len(),
+            max: limits.max_code_size,
+        });
+    }
+    let check_ops = limits.deterministic || !limits.denied_operators.is_empty();
+    for payload in Parser::new(0).parse_all(code) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let TypeRef::Memory(memory) = import?.ty {
+                        check_memory(&memory, limits)?;
+                    }
+                }
+            }
+            Payload::MemorySection(memories) => {
+                for memory in memories {
+                    check_memory(&memory?, limits)?;
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader()?;
+                while !reader.eof() {
+                    let op = reader.read()?;
+                    if check_ops {
+                        check_operator(&op, limits)?;
+                    }
+                }
+            }
+            Payload::End(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_memory(memory: &MemoryType, limits: &CodeLimits) -> Result<(), CodeRejection> {
+    if memory.shared && limits.deterministic {
+        return Err(CodeRejection::NonDeterministic("shared memory".into()));
+    }
+    if let Some(max) = limits.max_memory_pages {
+        let pages = memory.maximum.unwrap_or(0).max(memory.initial);
+        if pages > max {
+            return Err(CodeRejection::MemoryTooLarge { pages, max });
+        }
+    }
+    Ok(())
+}
+
+fn check_operator(op: &Operator, limits: &CodeLimits) -> Result<(), CodeRejection> {
+    let name = operator_name(op);
+    let name = name.as_str();
+    if limits.denied_operators.contains(&name) {
+        return Err(CodeRejection::DeniedInstruction(name.into()));
+    }
+    if limits.deterministic && is_non_deterministic(name) {
+        return Err(CodeRejection::NonDeterministic(name.into()));
+    }
+    Ok(())
+}
+
+fn is_non_deterministic(name: &str) -> bool {
+    name.contains("F32")
+        || name.contains("F64")
+        || name.contains("Atomic")
+        || name.contains("Relaxed")
+}
+
+/// Collects the variant name of an operator from its Debug output without allocating.
+struct OperatorName {
+    buf: [u8; 48],
+    len: usize,
+}
+
+impl OperatorName {
+    fn as_str(&self) -> &str {
+        // Only ASCII alphanumerics are collected.
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for OperatorName {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if !b.is_ascii_alphanumeric() || self.len == self.buf.len() {
+                // Stop at the end of the name.
+                return Err(fmt::Error);
+            }
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+fn operator_name(op: &Operator) -> OperatorName {
+    let mut name = OperatorName {
+        buf: [0; 48],
+        len: 0,
+    };
+    let _ = write!(name, "{op:?}");
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn module(sections: &[&[u8]]) -> Vec<u8> {
+        let mut code = HEADER.to_vec();
+        for section in sections {
+            code.extend_from_slice(section);
+        }
+        code
+    }
+
+    // (memory 2 16)
+    const MEMORY_2_16: &[u8] = &[5, 4, 1, 1, 2, 16];
+    // (func (result f32) f32.const 0)
+    const FLOAT_FUNC: &[&[u8]] = &[
+        &[1, 5, 1, 0x60, 0, 1, 0x7d],
+        &[3, 2, 1, 0],
+        &[10, 9, 1, 7, 0, 0x43, 0, 0, 0, 0, 0x0b],
+    ];
+
+    fn limits() -> CodeLimits {
+        CodeLimits {
+            max_code_size: 1024,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_large_code() {
+        let code = module(&[MEMORY_2_16]);
+        let limits = CodeLimits {
+            max_code_size: 8,
+            ..limits()
+        };
+        assert_eq!(
+            validate(&code, &limits),
+            Err(CodeRejection::TooLarge {
+                size: code.len(),
+                max: 8
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_wasm() {
+        assert!(matches!(
+            validate(b"foo", &limits()),
+            Err(CodeRejection::InvalidWasm(_))
+        ));
+    }
+
+    #[test]
+    fn checks_memory_pages() {
+        let code = module(&[MEMORY_2_16]);
+        assert_eq!(validate(&code, &limits()), Ok(()));
+        let limits = CodeLimits {
+            max_memory_pages: Some(8),
+            ..limits()
+        };
+        assert_eq!(
+            validate(&code, &limits),
+            Err(CodeRejection::MemoryTooLarge { pages: 16, max: 8 })
+        );
+    }
+
+    #[test]
+    fn checks_instructions() {
+        let code = module(FLOAT_FUNC);
+        assert_eq!(validate(&code, &limits()), Ok(()));
+        let deterministic = CodeLimits {
+            deterministic: true,
+            ..limits()
+        };
+        assert_eq!(
+            validate(&code, &deterministic),
+            Err(CodeRejection::NonDeterministic("F32Const".into()))
+        );
+        let denied = CodeLimits {
+            denied_operators: &["F32Const"],
+            ..limits()
+        };
+        assert_eq!(
+            validate(&code, &denied),
+            Err(CodeRejection::DeniedInstruction("F32Const".into()))
+        );
+    }
+}
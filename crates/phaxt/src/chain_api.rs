@@ -7,7 +7,9 @@ use subxt::storage::Storage;
 
 use parity_scale_codec::{Decode, Encode};
 use phala_types::{VersionedWorkerEndpoints, WorkerPublicKey};
+use std::collections::HashMap;
 
+use crate::rpc::{ExtraRpcExt as _, StorageKey};
 use crate::{BlockNumber, ChainApi, Config, Hash, RpcClient};
 
 impl ChainApi {
@@ -34,16 +36,29 @@ impl ChainApi {
         self.storage_key("Paras", "Heads", &id)
     }
 
+    pub fn para_pending_availability_key(&self, para_id: u32) -> Result<Vec<u8>> {
+        let id = crate::ParaId(para_id);
+        self.storage_key("ParaInclusion", "PendingAvailability", &id)
+    }
+
     pub async fn relay_parent_number(&self) -> Result<BlockNumber> {
+        self.relay_parent_number_at(1).await
+    }
+
+    /// The relay chain block the given parachain block was built on.
+    pub async fn relay_parent_number_at(&self, block: BlockNumber) -> Result<BlockNumber> {
         let hash = self
             .rpc()
-            .block_hash(Some(SubxtBlockNumber::from(NumberOrHex::Number(1))))
+            .block_hash(Some(SubxtBlockNumber::from(NumberOrHex::Number(
+                block.into(),
+            ))))
             .await
-            .context("Failed get the HASH of block 1")?;
+            .with_context(|| format!("Failed get the HASH of block {block}"))?
+            .ok_or_else(|| anyhow!("Block number {block} not found"))?;
         let addr = subxt::dynamic::storage_root("ParachainSystem", "ValidationData");
         let validation_data = self
             .storage()
-            .at(hash.expect("hash must not None"))
+            .at(hash)
             .fetch(&addr)
             .await
             .context("Failed to fetch validation data")?
@@ -174,6 +189,39 @@ impl ChainApi {
         Ok(endpoints)
     }
 
+    /// Fetches the values of the storage keys at the given block in a single
+    /// `state_queryStorageAt` roundtrip.
+    ///
+    /// The values are returned in the order of the keys, `None` for the absent ones.
+    pub async fn query_multi<V: Decode>(
+        &self,
+        keys: &[Vec<u8>],
+        hash: Hash,
+    ) -> Result<Vec<Option<V>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let storage_keys = keys.iter().cloned().map(StorageKey).collect();
+        let change_sets = self
+            .extra_rpc()
+            .query_storage_at(storage_keys, Some(hash))
+            .await
+            .context("Failed to query storage")?;
+        let values: HashMap<_, _> = change_sets
+            .into_iter()
+            .flat_map(|set| set.changes)
+            .map(|(key, data)| (key.0, data))
+            .collect();
+        keys.iter()
+            .map(|key| match values.get(key) {
+                Some(Some(data)) => Ok(Some(
+                    V::decode(&mut &data.0[..]).context("Failed to decode storage value")?,
+                )),
+                _ => Ok(None),
+            })
+            .collect()
+    }
+
     pub async fn storage_keys(&self, prefix: &[u8], hash: Option<Hash>) -> Result<Vec<Vec<u8>>> {
         let page = 100;
         let mut keys: Vec<Vec<u8>> = vec![];
@@ -0,0 +1,410 @@
+//! Typed errors of the chain RPC.
+//!
+//! subxt and jsonrpsee report most of the failures as nested errors whose only common ground is
+//! their message. [`ChainError`] classifies them once, so that callers can match on the kind of
+//! failure and ask whether retrying makes sense instead of matching substrings of the messages.
+
+use subxt::error::{DispatchError, RpcError, TransactionError};
+
+/// Error codes of the transaction pool returned by `author_submitExtrinsic`.
+mod pool_code {
+    pub const INVALID_TX: i32 = 1010;
+    pub const UNKNOWN_VALIDITY: i32 = 1011;
+    pub const TEMPORARILY_BANNED: i32 = 1012;
+    pub const ALREADY_IMPORTED: i32 = 1013;
+    pub const TOO_LOW_PRIORITY: i32 = 1014;
+    pub const CYCLE_DETECTED: i32 = 1015;
+    pub const IMMEDIATELY_DROPPED: i32 = 1016;
+    pub const UNACTIONABLE: i32 = 1017;
+    pub const FUTURE_TX: i32 = 1020;
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum TxPoolRejection {
+    /// The transaction is invalid, e.g. bad signature or outdated nonce. Holds the reason given
+    /// by the node.
+    #[error("invalid transaction: {0}")]
+    Invalid(String),
+    #[error("transaction validity can't be determined")]
+    UnknownValidity,
+    #[error("transaction is temporarily banned")]
+    TemporarilyBanned,
+    #[error("transaction already imported")]
+    AlreadyImported,
+    #[error("priority is too low")]
+    TooLowPriority,
+    #[error("cycle detected")]
+    CycleDetected,
+    #[error("transaction immediately dropped")]
+    ImmediatelyDropped,
+    #[error("transaction unactionable")]
+    Unactionable,
+    #[error("transaction from the future")]
+    FutureTransaction,
+}
+
+impl TxPoolRejection {
+    fn from_code(code: i32, reason: String) -> Option<Self> {
+        use pool_code::*;
+        Some(match code {
+            INVALID_TX => Self::Invalid(reason),
+            UNKNOWN_VALIDITY => Self::UnknownValidity,
+            TEMPORARILY_BANNED => Self::TemporarilyBanned,
+            ALREADY_IMPORTED => Self::AlreadyImported,
+            TOO_LOW_PRIORITY => Self::TooLowPriority,
+            CYCLE_DETECTED => Self::CycleDetected,
+            IMMEDIATELY_DROPPED => Self::ImmediatelyDropped,
+            UNACTIONABLE => Self::Unactionable,
+            FUTURE_TX => Self::FutureTransaction,
+            _ => return None,
+        })
+    }
+
+    pub fn is_bad_signature(&self) -> bool {
+        matches!(self, Self::Invalid(reason) if reason.contains("bad signature"))
+    }
+
+    /// The nonce of the transaction has been used, resubmitting with a fresh nonce may succeed.
+    pub fn is_outdated(&self) -> bool {
+        matches!(self, Self::Invalid(reason) if reason.contains("outdated") || reason.contains("Stale"))
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// The connection to the node failed. With `restart_required`, the client is unusable and
+    /// must be reconnected.
+    #[error("transport error: {reason}")]
+    Transport {
+        reason: String,
+        restart_required: bool,
+    },
+    /// Failed to encode or decode the data exchanged with the node.
+    #[error("codec error: {0}")]
+    Decode(String),
+    /// The transaction was rejected by the transaction pool of the node.
+    #[error("{0}")]
+    TxPool(TxPoolRejection),
+    /// The extrinsic failed with an error of a pallet.
+    #[error("{pallet}::{variant}")]
+    Module { pallet: String, variant: String },
+    /// The extrinsic failed with a dispatch error not from a pallet, e.g. `BadOrigin`.
+    #[error("dispatch error: {0}")]
+    Dispatch(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl ChainError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Transport { .. } | Self::Timeout => true,
+            Self::TxPool(rejection) => {
+                rejection.is_outdated()
+                    || matches!(
+                        rejection,
+                        TxPoolRejection::UnknownValidity
+                            | TxPoolRejection::TemporarilyBanned
+                            | TxPoolRejection::TooLowPriority
+                            | TxPoolRejection::ImmediatelyDropped
+                            | TxPoolRejection::Unactionable
+                    )
+            }
+            Self::Decode(_) | Self::Module { .. } | Self::Dispatch(_) | Self::Other(_) => false,
+        }
+    }
+
+    pub fn is_restart_required(&self) -> bool {
+        matches!(
+            self,
+            Self::Transport {
+                restart_required: true,
+                ..
+            }
+        )
+    }
+
+    /// Classifies the first error of the chain of `err` that is known, falling back to
+    /// [`ChainError::Other`].
+    ///
+    /// Errors which only made it as far as a message, e.g. formatted into an `anyhow!` or
+    /// persisted, are recognized by their message as a last resort.
+    pub fn classify(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<ChainError>() {
+                return err.clone();
+            }
+            if let Some(err) = cause.downcast_ref::<subxt::Error>() {
+                return err.into();
+            }
+            if let Some(err) = cause.downcast_ref::<jsonrpsee::core::Error>() {
+                return err.into();
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return Self::Timeout;
+            }
+        }
+        for cause in err.chain() {
+            if let Some(err) = Self::from_message(&cause.to_string()) {
+                return err;
+            }
+        }
+        Self::Other(err.to_string())
+    }
+
+    /// Recognizes the messages of [`ChainError`] itself and of the errors it is converted from.
+    fn from_message(message: &str) -> Option<Self> {
+        if let Some(code) = rpc_error_code(message) {
+            if let Some(rejection) = TxPoolRejection::from_code(code, message.into()) {
+                return Some(Self::TxPool(rejection));
+            }
+        }
+        if let Some(reason) = message.strip_prefix("invalid transaction: ") {
+            return Some(Self::TxPool(TxPoolRejection::Invalid(reason.into())));
+        }
+        if let Some(reason) = message.strip_prefix("transport error: ") {
+            return Some(Self::Transport {
+                reason: reason.into(),
+                restart_required: false,
+            });
+        }
+        if message.contains("restart required") {
+            return Some(Self::Transport {
+                reason: message.into(),
+                restart_required: true,
+            });
+        }
+        if message.starts_with("Networking or low-level protocol error") {
+            return Some(Self::Transport {
+                reason: message.into(),
+                restart_required: false,
+            });
+        }
+        if message == "timed out"
+            || message == "Request timeout"
+            || message == "deadline has elapsed"
+        {
+            return Some(Self::Timeout);
+        }
+        if let Some(reason) = message.strip_prefix("codec error: ") {
+            return Some(Self::Decode(reason.into()));
+        }
+        if let Some(reason) = message.strip_prefix("dispatch error: ") {
+            return Some(Self::Dispatch(reason.into()));
+        }
+        for rejection in [
+            TxPoolRejection::UnknownValidity,
+            TxPoolRejection::TemporarilyBanned,
+            TxPoolRejection::AlreadyImported,
+            TxPoolRejection::TooLowPriority,
+            TxPoolRejection::CycleDetected,
+            TxPoolRejection::ImmediatelyDropped,
+            TxPoolRejection::Unactionable,
+            TxPoolRejection::FutureTransaction,
+        ] {
+            if message == rejection.to_string() {
+                return Some(Self::TxPool(rejection));
+            }
+        }
+        let (pallet, variant) = message.split_once("::")?;
+        let is_ident = |s: &str| {
+            s.starts_with(|c: char| c.is_ascii_uppercase())
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        (is_ident(pallet) && is_ident(variant)).then(|| Self::Module {
+            pallet: pallet.into(),
+            variant: variant.into(),
+        })
+    }
+}
+
+/// The code of the JSON-RPC error in the message of a jsonrpsee error, either as printed in
+/// debug, `ServerError(1010)`, or as serialized, `"code":1010`.
+fn rpc_error_code(message: &str) -> Option<i32> {
+    ["ServerError(", "\"code\":"].iter().find_map(|prefix| {
+        let start = message.find(prefix)? + prefix.len();
+        let digits = message[start..]
+            .trim_start()
+            .split(|c: char| !c.is_ascii_digit() && c != '-')
+            .next()?;
+        digits.parse().ok()
+    })
+}
+
+impl From<&subxt::Error> for ChainError {
+    fn from(err: &subxt::Error) -> Self {
+        match err {
+            subxt::Error::Io(err) => Self::Transport {
+                reason: err.to_string(),
+                restart_required: false,
+            },
+            subxt::Error::Rpc(RpcError::ClientError(err)) => {
+                match err.downcast_ref::<jsonrpsee::core::Error>() {
+                    Some(err) => err.into(),
+                    None => Self::Other(err.to_string()),
+                }
+            }
+            subxt::Error::Rpc(RpcError::SubscriptionDropped) => Self::Transport {
+                reason: "subscription dropped".into(),
+                restart_required: false,
+            },
+            subxt::Error::Codec(err) => Self::Decode(err.to_string()),
+            subxt::Error::Serialization(err) => Self::Decode(err.to_string()),
+            subxt::Error::Decode(err) => Self::Decode(err.to_string()),
+            subxt::Error::Encode(err) => Self::Decode(err.to_string()),
+            subxt::Error::Runtime(err) => err.into(),
+            subxt::Error::Transaction(TransactionError::Invalid(reason)) => {
+                Self::TxPool(TxPoolRejection::Invalid(reason.clone()))
+            }
+            subxt::Error::Transaction(TransactionError::Dropped(_)) => {
+                Self::TxPool(TxPoolRejection::ImmediatelyDropped)
+            }
+            err => Self::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<&DispatchError> for ChainError {
+    fn from(err: &DispatchError) -> Self {
+        match err {
+            DispatchError::Module(err) => match err.details() {
+                Ok(details) => Self::Module {
+                    pallet: details.pallet.name().into(),
+                    variant: details.variant.name.clone(),
+                },
+                Err(_) => Self::Dispatch(err.to_string()),
+            },
+            err => Self::Dispatch(format!("{err:?}")),
+        }
+    }
+}
+
+impl From<&jsonrpsee::core::Error> for ChainError {
+    fn from(err: &jsonrpsee::core::Error) -> Self {
+        use jsonrpsee::core::Error as RpcClientError;
+        use jsonrpsee::types::error::CallError;
+        match err {
+            RpcClientError::Transport(err) => Self::Transport {
+                reason: err.to_string(),
+                restart_required: false,
+            },
+            RpcClientError::RestartNeeded(reason) => Self::Transport {
+                reason: reason.clone(),
+                restart_required: true,
+            },
+            RpcClientError::RequestTimeout => Self::Timeout,
+            RpcClientError::ParseError(err) => Self::Decode(err.to_string()),
+            RpcClientError::Call(CallError::Custom(err)) => {
+                let reason = match err.data() {
+                    Some(data) => format!("{}: {}", err.message(), data.get()),
+                    None => err.message().to_string(),
+                };
+                match TxPoolRejection::from_code(err.code(), reason.clone()) {
+                    Some(rejection) => Self::TxPool(rejection),
+                    None => Self::Other(reason),
+                }
+            }
+            err => Self::Other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use jsonrpsee::types::error::{CallError, ErrorObject};
+
+    fn pool_error(code: i32, message: &str) -> jsonrpsee::core::Error {
+        jsonrpsee::core::Error::Call(CallError::Custom(ErrorObject::owned(
+            code, message, None::<()>,
+        )))
+    }
+
+    #[test]
+    fn classify_typed_errors() {
+        let err = anyhow::Error::new(pool_error(
+            pool_code::TOO_LOW_PRIORITY,
+            "Priority is too low",
+        ))
+        .context("Failed to submit");
+        assert_eq!(
+            ChainError::classify(&err),
+            ChainError::TxPool(TxPoolRejection::TooLowPriority)
+        );
+        let err = anyhow::Error::new(ChainError::Timeout).context("Failed to submit");
+        assert_eq!(ChainError::classify(&err), ChainError::Timeout);
+    }
+
+    #[test]
+    fn classify_errors_wrapped_in_strings() {
+        let err = pool_error(pool_code::TOO_LOW_PRIORITY, "Priority is too low");
+        assert_eq!(
+            ChainError::classify(&anyhow!("Failed to submit: {err}")),
+            ChainError::TxPool(TxPoolRejection::TooLowPriority)
+        );
+        let err = pool_error(pool_code::INVALID_TX, "Invalid Transaction");
+        assert!(matches!(
+            ChainError::classify(&anyhow!("{err}")),
+            ChainError::TxPool(TxPoolRejection::Invalid(_))
+        ));
+
+        // The messages of ChainError round trip.
+        for err in [
+            ChainError::Transport {
+                reason: "connection reset".into(),
+                restart_required: false,
+            },
+            ChainError::Decode("bad input".into()),
+            ChainError::TxPool(TxPoolRejection::Invalid("Transaction is outdated".into())),
+            ChainError::TxPool(TxPoolRejection::TemporarilyBanned),
+            ChainError::Module {
+                pallet: "PhalaRegistry".into(),
+                variant: "InvalidSignature".into(),
+            },
+            ChainError::Dispatch("BadOrigin".into()),
+            ChainError::Timeout,
+        ] {
+            assert_eq!(ChainError::classify(&anyhow!(err.to_string())), err);
+        }
+        let err = anyhow!("The background task been terminated because: closed; restart required");
+        assert!(ChainError::classify(&err).is_restart_required());
+
+        let err = anyhow!("Pool operator not found");
+        assert_eq!(
+            ChainError::classify(&err),
+            ChainError::Other("Pool operator not found".into())
+        );
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let retryable = [
+            ChainError::Timeout,
+            ChainError::Transport {
+                reason: "connection reset".into(),
+                restart_required: true,
+            },
+            ChainError::TxPool(TxPoolRejection::TooLowPriority),
+            ChainError::TxPool(TxPoolRejection::Invalid("Transaction is outdated".into())),
+        ];
+        for err in retryable {
+            assert!(err.is_retryable(), "{err:?}");
+        }
+        let fatal = [
+            ChainError::TxPool(TxPoolRejection::Invalid("bad signature".into())),
+            ChainError::TxPool(TxPoolRejection::AlreadyImported),
+            ChainError::Module {
+                pallet: "PhalaComputation".into(),
+                variant: "WorkerNotBound".into(),
+            },
+            ChainError::Dispatch("BadOrigin".into()),
+            ChainError::Decode("bad input".into()),
+        ];
+        for err in fatal {
+            assert!(!err.is_retryable(), "{err:?}");
+        }
+    }
+}
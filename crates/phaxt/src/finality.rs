@@ -0,0 +1,279 @@
+//! A single stream of the best and finalized heads of a chain.
+//!
+//! [`FinalityStream`] merges the subscriptions of the best and the finalized block headers into
+//! [`HeadUpdate`]s with the following guarantees:
+//!
+//! - the finalized number never decreases, and each finalized block is yielded in order. The
+//!   finalized heads skipped by the node are filled in, one update per block;
+//! - the best number never decreases either, a best head below the reported one (a reorg to a
+//!   shorter fork) is held back until the chain grows past it;
+//! - the best head is never behind the finalized one.
+//!
+//! The guarantees only hold within one stream. The stream ends when a subscription is closed,
+//! e.g. when the connection to the node is lost, and a new stream has to be subscribed then. The
+//! new stream starts from the heads of the node again, without filling in the finalized blocks
+//! missed in between.
+
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use subxt::config::Header as _;
+use subxt::rpc::types::{BlockNumber as SubxtBlockNumber, NumberOrHex};
+use subxt::rpc::Subscription;
+
+use crate::{BlockNumber, ChainApi, Config, Hash};
+
+type Header = <Config as subxt::Config>::Header;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Head {
+    pub number: BlockNumber,
+    pub hash: Hash,
+}
+
+impl Head {
+    fn of(header: &Header) -> Self {
+        Self {
+            number: header.number,
+            hash: header.hash(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadUpdate {
+    pub best: Head,
+    pub finalized: Head,
+}
+
+pub struct FinalityStream {
+    api: ChainApi,
+    best_sub: Subscription<Header>,
+    finalized_sub: Subscription<Header>,
+    heads: Heads,
+}
+
+impl FinalityStream {
+    pub async fn subscribe(api: &ChainApi) -> Result<Self> {
+        let best_sub = api
+            .rpc()
+            .subscribe_best_block_headers()
+            .await
+            .context("Failed to subscribe best block headers")?;
+        let finalized_sub = api
+            .rpc()
+            .subscribe_finalized_block_headers()
+            .await
+            .context("Failed to subscribe finalized block headers")?;
+        Ok(Self {
+            api: api.clone(),
+            best_sub,
+            finalized_sub,
+            heads: Heads::default(),
+        })
+    }
+
+    /// The last yielded best head.
+    pub fn best(&self) -> Option<Head> {
+        self.heads.best
+    }
+
+    /// The last yielded finalized head.
+    pub fn finalized(&self) -> Option<Head> {
+        self.heads.finalized
+    }
+
+    /// The next update, or `None` if the subscriptions are closed.
+    pub async fn next(&mut self) -> Option<Result<HeadUpdate>> {
+        loop {
+            match self.heads.fill_gap() {
+                Some(GapFill::Fetch(next)) => return Some(self.fetch_finalized(next).await),
+                Some(GapFill::Filled(update)) => return Some(Ok(update)),
+                None => (),
+            }
+            tokio::select! {
+                header = self.finalized_sub.next() => {
+                    let header = match header? {
+                        Ok(header) => header,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    if let Some(update) = self.heads.on_finalized(Head::of(&header)) {
+                        return Some(Ok(update));
+                    }
+                }
+                header = self.best_sub.next() => {
+                    let header = match header? {
+                        Ok(header) => header,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    if let Some(update) = self.heads.on_best(Head::of(&header)) {
+                        return Some(Ok(update));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Yields the finalized block `next` of the gap.
+    ///
+    /// On error the gap is given up, so that the caller is not handed the same error in a loop.
+    /// It is filled in from the next finalized head received instead.
+    async fn fetch_finalized(&mut self, next: BlockNumber) -> Result<HeadUpdate> {
+        let hash = self
+            .api
+            .rpc()
+            .block_hash(Some(SubxtBlockNumber::from(NumberOrHex::Number(
+                next.into(),
+            ))))
+            .await
+            .with_context(|| format!("Failed to get the hash of finalized block {next}"))
+            .and_then(|hash| hash.ok_or_else(|| anyhow!("Finalized block {next} not found")));
+        match hash {
+            Ok(hash) => Ok(self.heads.advance_finalized(Head { number: next, hash })),
+            Err(err) => {
+                self.heads.gap_target = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+enum GapFill {
+    /// The hash of the next finalized block is to be fetched.
+    Fetch(BlockNumber),
+    /// The gap is filled, with the target as the last update.
+    Filled(HeadUpdate),
+}
+
+/// The heads yielded so far.
+#[derive(Default)]
+struct Heads {
+    best: Option<Head>,
+    finalized: Option<Head>,
+    /// A finalized head received after skipping some blocks, which are yielded before it.
+    gap_target: Option<Head>,
+}
+
+impl Heads {
+    fn on_finalized(&mut self, head: Head) -> Option<HeadUpdate> {
+        match self.finalized {
+            Some(finalized) if head.number <= finalized.number => None,
+            Some(finalized) if head.number > finalized.number + 1 => {
+                self.gap_target = Some(head);
+                None
+            }
+            _ => Some(self.advance_finalized(head)),
+        }
+    }
+
+    fn on_best(&mut self, head: Head) -> Option<HeadUpdate> {
+        if matches!(self.best, Some(best) if head.number < best.number) {
+            return None;
+        }
+        self.best = Some(head);
+        let finalized = self.finalized?;
+        Some(self.update(finalized))
+    }
+
+    /// The next step to fill the gap, if any.
+    fn fill_gap(&mut self) -> Option<GapFill> {
+        let target = self.gap_target?;
+        let next = self.finalized.map_or(target.number, |f| f.number + 1);
+        if next < target.number {
+            return Some(GapFill::Fetch(next));
+        }
+        self.gap_target = None;
+        Some(GapFill::Filled(self.advance_finalized(target)))
+    }
+
+    fn advance_finalized(&mut self, head: Head) -> HeadUpdate {
+        self.finalized = Some(head);
+        self.update(head)
+    }
+
+    fn update(&mut self, finalized: Head) -> HeadUpdate {
+        let best = match self.best {
+            Some(best) if best.number >= finalized.number => best,
+            _ => finalized,
+        };
+        self.best = Some(best);
+        HeadUpdate { best, finalized }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(number: BlockNumber) -> Head {
+        Head {
+            number,
+            hash: Hash::repeat_byte(number as u8),
+        }
+    }
+
+    fn update(best: BlockNumber, finalized: BlockNumber) -> HeadUpdate {
+        HeadUpdate {
+            best: head(best),
+            finalized: head(finalized),
+        }
+    }
+
+    #[test]
+    fn best_heads_wait_for_the_first_finalized() {
+        let mut heads = Heads::default();
+        assert_eq!(heads.on_best(head(5)), None);
+        assert_eq!(heads.on_finalized(head(3)), Some(update(5, 3)));
+        assert_eq!(heads.on_best(head(6)), Some(update(6, 3)));
+    }
+
+    #[test]
+    fn best_heads_never_go_back() {
+        let mut heads = Heads::default();
+        heads.on_finalized(head(3));
+        assert_eq!(heads.on_best(head(6)), Some(update(6, 3)));
+        assert_eq!(heads.on_best(head(5)), None);
+        assert_eq!(heads.on_finalized(head(4)), Some(update(6, 4)));
+        // The finalized head overtakes the best one.
+        assert_eq!(heads.on_finalized(head(5)), Some(update(6, 5)));
+        assert_eq!(heads.on_finalized(head(6)), Some(update(6, 6)));
+        assert_eq!(heads.on_finalized(head(7)), Some(update(7, 7)));
+    }
+
+    #[test]
+    fn old_finalized_heads_are_ignored() {
+        let mut heads = Heads::default();
+        heads.on_finalized(head(3));
+        assert_eq!(heads.on_finalized(head(3)), None);
+        assert_eq!(heads.on_finalized(head(2)), None);
+        assert!(heads.fill_gap().is_none());
+    }
+
+    #[test]
+    fn skipped_finalized_heads_are_filled_in() {
+        let mut heads = Heads::default();
+        heads.on_finalized(head(3));
+        assert_eq!(heads.on_finalized(head(6)), None);
+        assert!(matches!(heads.fill_gap(), Some(GapFill::Fetch(4))));
+        heads.advance_finalized(head(4));
+        assert!(matches!(heads.fill_gap(), Some(GapFill::Fetch(5))));
+        heads.advance_finalized(head(5));
+        assert!(matches!(
+            heads.fill_gap(),
+            Some(GapFill::Filled(filled)) if filled == update(6, 6)
+        ));
+        assert!(heads.fill_gap().is_none());
+    }
+
+    #[test]
+    fn gaps_given_up_are_filled_from_the_next_finalized_head() {
+        let mut heads = Heads::default();
+        heads.on_finalized(head(3));
+        heads.on_finalized(head(6));
+        assert!(matches!(heads.fill_gap(), Some(GapFill::Fetch(4))));
+        // Fetching #4 failed.
+        heads.gap_target = None;
+        assert!(heads.fill_gap().is_none());
+        assert_eq!(heads.on_finalized(head(7)), None);
+        assert!(matches!(heads.fill_gap(), Some(GapFill::Fetch(4))));
+    }
+}
@@ -0,0 +1,307 @@
+//! A WebSocket RPC client that checks the liveness of its connection and reconnects on failure.
+//!
+//! A connection can die silently, e.g. behind a NAT that dropped the mapping, in which case
+//! requests hang and subscriptions stop yielding without any error. The client sends a health
+//! check request periodically, and reconnects if it is not answered in time. Active subscriptions
+//! are re-established on the new connection transparently to the subscribers.
+
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::{
+    future::{self, BoxFuture, Either},
+    stream::{self, Stream, StreamExt},
+};
+use jsonrpsee::async_client::Client;
+use serde_json::value::RawValue;
+use subxt::{
+    error::RpcError,
+    rpc::{RawRpcFuture, RawRpcSubscription, RpcClientT},
+};
+use tokio::sync::watch;
+
+/// Connects a new client, called for the initial connection and each reconnection.
+pub type Connector = Arc<dyn Fn() -> BoxFuture<'static, Result<Client>> + Send + Sync>;
+
+/// Called with the endpoint url and the event on each change of the connection state.
+pub type EventCallback = Arc<dyn Fn(&str, &ConnectionEvent) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// The health check failed, the connection is going to be re-established.
+    Unhealthy {
+        reason: String,
+    },
+    Reconnecting {
+        attempt: u32,
+    },
+    ReconnectFailed {
+        attempt: u32,
+        error: String,
+    },
+    Reconnected,
+    /// A subscription has been re-established on the new connection.
+    Resubscribed {
+        method: String,
+    },
+    ResubscribeFailed {
+        method: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for ConnectionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unhealthy { reason } => write!(f, "unhealthy: {reason}"),
+            Self::Reconnecting { attempt } => write!(f, "reconnecting, attempt {attempt}"),
+            Self::ReconnectFailed { attempt, error } => {
+                write!(f, "reconnect attempt {attempt} failed: {error}")
+            }
+            Self::Reconnected => write!(f, "reconnected"),
+            Self::Resubscribed { method } => write!(f, "resubscribed {method}"),
+            Self::ResubscribeFailed { method, error } => {
+                write!(f, "failed to resubscribe {method}: {error}")
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct KeepAlive {
+    /// Interval between two health checks.
+    pub interval: Duration,
+    /// The connection is considered dead if a health check is not answered within it.
+    pub timeout: Duration,
+    /// Upper bound of the delay between two reconnection attempts.
+    pub max_backoff: Duration,
+    pub on_event: Option<EventCallback>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+            on_event: None,
+        }
+    }
+}
+
+struct Shared {
+    url: String,
+    connector: Connector,
+    keep_alive: KeepAlive,
+    current: watch::Sender<Arc<Client>>,
+}
+
+impl Shared {
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(on_event) = &self.keep_alive.on_event {
+            on_event(&self.url, &event);
+        }
+    }
+
+    fn client(&self) -> Arc<Client> {
+        self.current.borrow().clone()
+    }
+
+    async fn check_health(&self, client: &Client) -> Result<(), String> {
+        if !client.is_connected() {
+            return Err("disconnected".into());
+        }
+        let request = RpcClientT::request_raw(client, "system_health", None);
+        match tokio::time::timeout(self.keep_alive.timeout, request).await {
+            Ok(Ok(_)) => Ok(()),
+            // An error response still proves the connection is alive.
+            Ok(Err(_)) if client.is_connected() => Ok(()),
+            Ok(Err(err)) => Err(format!("health check failed: {err}")),
+            Err(_) => Err("health check timed out".into()),
+        }
+    }
+
+    async fn reconnect(&self) {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.emit(ConnectionEvent::Reconnecting { attempt });
+            match (self.connector)().await {
+                Ok(client) => {
+                    // Subscribers are watching the current client and resubscribe on the change.
+                    self.current.send_replace(Arc::new(client));
+                    self.emit(ConnectionEvent::Reconnected);
+                    return;
+                }
+                Err(err) => {
+                    self.emit(ConnectionEvent::ReconnectFailed {
+                        attempt,
+                        error: format!("{err:?}"),
+                    });
+                    let backoff = self.keep_alive.interval * attempt;
+                    tokio::time::sleep(backoff.min(self.keep_alive.max_backoff)).await;
+                }
+            }
+        }
+    }
+}
+
+async fn keep_alive_loop(shared: Weak<Shared>) {
+    loop {
+        let interval = match shared.upgrade() {
+            Some(shared) => shared.keep_alive.interval,
+            None => return,
+        };
+        tokio::time::sleep(interval).await;
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let client = shared.client();
+        if let Err(reason) = shared.check_health(&client).await {
+            shared.emit(ConnectionEvent::Unhealthy { reason });
+            shared.reconnect().await;
+        }
+    }
+}
+
+/// A [`RpcClientT`] that reconnects when the connection is found dead, see the module docs.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    shared: Arc<Shared>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(url: &str, connector: Connector, keep_alive: KeepAlive) -> Result<Self> {
+        let client = connector().await?;
+        let (current, _) = watch::channel(Arc::new(client));
+        let shared = Arc::new(Shared {
+            url: url.to_string(),
+            connector,
+            keep_alive,
+            current,
+        });
+        tokio::spawn(keep_alive_loop(Arc::downgrade(&shared)));
+        Ok(Self { shared })
+    }
+}
+
+type RawSubscriptionStream =
+    Pin<Box<dyn Stream<Item = Result<Box<RawValue>, RpcError>> + Send + 'static>>;
+
+/// A subscription that follows the client across reconnections.
+struct Resubscription {
+    shared: Arc<Shared>,
+    watcher: watch::Receiver<Arc<Client>>,
+    client: Arc<Client>,
+    stream: Option<RawSubscriptionStream>,
+    sub: String,
+    params: Option<Box<RawValue>>,
+    unsub: String,
+}
+
+impl Resubscription {
+    async fn next(&mut self) -> Option<Result<Box<RawValue>, RpcError>> {
+        loop {
+            let stream = self.stream.as_mut()?;
+            let replaced = {
+                let next = stream.next();
+                let changed = self.watcher.changed();
+                futures::pin_mut!(next, changed);
+                match future::select(next, changed).await {
+                    Either::Left((Some(item), _)) => return Some(item),
+                    Either::Left((None, _)) => false,
+                    Either::Right((Ok(()), _)) => true,
+                    Either::Right((Err(_), _)) => return None,
+                }
+            };
+            if !replaced {
+                if self.client.is_connected() {
+                    // Closed by the server, not because of the connection.
+                    return None;
+                }
+                // Wait for the keep alive loop to reconnect.
+                if self.watcher.changed().await.is_err() {
+                    return None;
+                }
+            }
+            let client = self.watcher.borrow_and_update().clone();
+            if Arc::ptr_eq(&client, &self.client) {
+                continue;
+            }
+            self.client = client;
+            let subscribed = RpcClientT::subscribe_raw(
+                &*self.client,
+                &self.sub,
+                self.params.clone(),
+                &self.unsub,
+            )
+            .await;
+            match subscribed {
+                Ok(subscription) => {
+                    self.stream = Some(subscription.stream);
+                    self.shared.emit(ConnectionEvent::Resubscribed {
+                        method: self.sub.clone(),
+                    });
+                }
+                Err(err) => {
+                    self.stream = None;
+                    self.shared.emit(ConnectionEvent::ResubscribeFailed {
+                        method: self.sub.clone(),
+                        error: err.to_string(),
+                    });
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl RpcClientT for ReconnectingClient {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RawRpcFuture<'a, Box<RawValue>> {
+        Box::pin(async move {
+            let client = self.shared.client();
+            RpcClientT::request_raw(&*client, method, params).await
+        })
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        unsub: &'a str,
+    ) -> RawRpcFuture<'a, RawRpcSubscription> {
+        Box::pin(async move {
+            let mut watcher = self.shared.current.subscribe();
+            let client = watcher.borrow_and_update().clone();
+            let subscription =
+                RpcClientT::subscribe_raw(&*client, sub, params.clone(), unsub).await?;
+            let resubscription = Resubscription {
+                shared: self.shared.clone(),
+                watcher,
+                client,
+                stream: Some(subscription.stream),
+                sub: sub.to_string(),
+                params,
+                unsub: unsub.to_string(),
+            };
+            let stream = stream::unfold(resubscription, |mut resubscription| async move {
+                let item = resubscription.next().await?;
+                Some((item, resubscription))
+            });
+            Ok(RawRpcSubscription {
+                stream: Box::pin(stream),
+                id: subscription.id,
+            })
+        })
+    }
+}
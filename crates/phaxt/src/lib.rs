@@ -8,9 +8,20 @@ use subxt::config::polkadot::{PolkadotExtrinsicParams, PolkadotExtrinsicParamsBu
 
 mod chain_api;
 pub mod dynamic;
+pub mod error;
+pub mod finality;
+pub mod keep_alive;
+pub mod offline;
+pub mod profile;
+pub mod resubmit;
 pub mod rpc;
+pub mod storage_map;
 
+pub use error::{ChainError, TxPoolRejection};
+pub use finality::{FinalityStream, Head, HeadUpdate};
+pub use profile::ChainProfile;
 pub use sp_core;
+pub use storage_map::{KeyHasher, StorageMap};
 
 #[derive(Encode, Decode, Clone, PartialEq, Eq, TypeInfo, PartialOrd, Ord, Debug, EncodeAsType)]
 pub struct ParaId(pub u32);
@@ -49,10 +60,17 @@ impl PairSigner {
 }
 
 #[derive(Clone)]
-pub struct ChainApi(pub RpcClient);
+pub struct ChainApi(pub RpcClient, pub Arc<ChainProfile>);
 pub type ParachainApi = ChainApi;
 pub type RelaychainApi = ChainApi;
 
+impl ChainApi {
+    /// The profile of the chain the client is connected to.
+    pub fn profile(&self) -> &ChainProfile {
+        &self.1
+    }
+}
+
 impl Deref for ChainApi {
     type Target = RpcClient;
 
@@ -72,17 +90,53 @@ use jsonrpsee::{
     client_transport::ws::{Uri, WsTransportClientBuilder},
 };
 
+pub use keep_alive::{ConnectionEvent, KeepAlive};
+
 pub async fn connect(uri: &str) -> Result<ChainApi> {
-    let rpc_client = ws_client(uri).await?;
+    connect_with_keep_alive(uri, Default::default()).await
+}
+
+/// Connects to `uri`, reconnecting and resubscribing whenever the connection is found dead.
+pub async fn connect_with_keep_alive(uri: &str, keep_alive: KeepAlive) -> Result<ChainApi> {
+    connect_with_profile(uri, keep_alive, Default::default()).await
+}
+
+/// Like [`connect_with_keep_alive`], for the chain described by `profile`.
+pub async fn connect_with_profile(
+    uri: &str,
+    keep_alive: KeepAlive,
+    profile: ChainProfile,
+) -> Result<ChainApi> {
+    let url = uri.to_string();
+    let connector: keep_alive::Connector = Arc::new(move || {
+        let url = url.clone();
+        Box::pin(async move { ws_client(&url).await })
+    });
+    connect_with_connector(uri, connector, keep_alive, profile).await
+}
+
+/// Like [`connect_with_profile`], with the transport created by `connector`.
+///
+/// Fails if the pallets pinned by `profile` don't match the runtime metadata of the chain.
+pub async fn connect_with_connector(
+    uri: &str,
+    connector: keep_alive::Connector,
+    keep_alive: KeepAlive,
+    profile: ChainProfile,
+) -> Result<ChainApi> {
+    let rpc_client = keep_alive::ReconnectingClient::connect(uri, connector, keep_alive).await?;
     let client = RpcClient::from_rpc_client(Arc::new(rpc_client))
         .await
         .context("Failed to connect to substrate")?;
+    profile
+        .verify(&client.metadata())
+        .with_context(|| format!("Unexpected chain at {uri}"))?;
     let update_client = client.updater();
     tokio::spawn(async move {
         let result = update_client.perform_runtime_updates().await;
         eprintln!("Runtime update failed with result={result:?}");
     });
-    Ok(ChainApi(client))
+    Ok(ChainApi(client, Arc::new(profile)))
 }
 
 async fn ws_client(url: &str) -> Result<jsonrpsee::async_client::Client> {
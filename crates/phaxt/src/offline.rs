@@ -0,0 +1,204 @@
+//! Offline signing of extrinsics.
+//!
+//! The workflow has three steps:
+//! 1. An online machine exports an [`UnsignedExtrinsic`] for a call with
+//!    [`ChainApi::export_unsigned`]. It contains everything needed to rebuild the extrinsic,
+//!    including the `signer_payload` that has to be signed.
+//! 2. The offline signer (hardware wallet, air-gapped machine, ...) signs the `signer_payload`
+//!    with the sr25519 key of `signer`.
+//! 3. An online machine assembles the extrinsic with the returned signature and submits it with
+//!    [`ChainApi::submit_signed`].
+//!
+//! The exported payload is bound to the runtime version at the time of export. If the runtime is
+//! upgraded before the extrinsic is submitted, it must be exported and signed again.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sp_core::{sr25519, Pair as _};
+use std::convert::TryInto;
+use subxt::{
+    config::substrate::Era,
+    tx::{SubmittableExtrinsic, TxPayload},
+    utils::{MultiAddress, MultiSignature},
+};
+
+use crate::{
+    resubmit::mortal_era, rpc::ExtraRpcExt as _, AccountId, BlockNumber, ChainApi, Config,
+    ExtrinsicParamsBuilder, Hash, Index, RpcClient,
+};
+
+/// The mortality of an extrinsic, see [`Era::Mortal`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Mortality {
+    pub period: u64,
+    pub phase: u64,
+    /// The hash of the block the era is born at.
+    #[serde(with = "serde_hex")]
+    pub checkpoint: [u8; 32],
+}
+
+/// An extrinsic exported for offline signing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UnsignedExtrinsic {
+    /// The account expected to sign the extrinsic.
+    pub signer: AccountId,
+    /// The SCALE encoded call.
+    #[serde(with = "serde_hex")]
+    pub call_data: Vec<u8>,
+    pub nonce: Index,
+    pub tip: u128,
+    /// None for an immortal extrinsic.
+    pub mortality: Option<Mortality>,
+    /// The bytes to be signed by the signer.
+    #[serde(with = "serde_hex")]
+    pub signer_payload: Vec<u8>,
+}
+
+impl UnsignedExtrinsic {
+    fn params(&self) -> ExtrinsicParamsBuilder {
+        let params = ExtrinsicParamsBuilder::new().tip(self.tip);
+        match &self.mortality {
+            Some(m) => params.era(Era::Mortal(m.period, m.phase), Hash::from(m.checkpoint)),
+            None => params,
+        }
+    }
+}
+
+/// A call given as its SCALE encoded bytes.
+pub struct RawCall<'a>(pub &'a [u8]);
+
+impl TxPayload for RawCall<'_> {
+    fn encode_call_data_to(
+        &self,
+        _metadata: &subxt::Metadata,
+        out: &mut Vec<u8>,
+    ) -> Result<(), subxt::Error> {
+        out.extend_from_slice(self.0);
+        Ok(())
+    }
+}
+
+impl ChainApi {
+    /// Builds the unsigned extrinsic of `call` to be signed offline by `signer`.
+    ///
+    /// The nonce is fetched from the chain if not given. `longevity` is the number of blocks the
+    /// extrinsic stays valid at least, 0 for immortal. See [`mortal_era`] for how it is rounded.
+    pub async fn export_unsigned(
+        &self,
+        call: &impl TxPayload,
+        signer: &AccountId,
+        nonce: Option<Index>,
+        longevity: u64,
+        tip: u128,
+    ) -> Result<UnsignedExtrinsic> {
+        let mut call_data = Vec::new();
+        call.encode_call_data_to(&self.metadata(), &mut call_data)?;
+        let nonce = match nonce {
+            Some(nonce) => nonce,
+            None => self.extra_rpc().account_nonce(signer).await?,
+        };
+        let mortality = if longevity > 0 {
+            let header = self
+                .rpc()
+                .header(None)
+                .await?
+                .ok_or_else(|| anyhow!("No header"))?;
+            let (era, birth) = mortal_era(longevity, header.number as u64);
+            let Era::Mortal(period, phase) = era else {
+                unreachable!("mortal_era returns a mortal era");
+            };
+            let checkpoint = self
+                .rpc()
+                .block_hash(Some((birth as BlockNumber).into()))
+                .await?
+                .ok_or_else(|| anyhow!("No hash of block {birth}"))?;
+            Some(Mortality {
+                period,
+                phase,
+                checkpoint: checkpoint.0,
+            })
+        } else {
+            None
+        };
+        let mut unsigned = UnsignedExtrinsic {
+            signer: signer.clone(),
+            call_data,
+            nonce,
+            tip,
+            mortality,
+            signer_payload: vec![],
+        };
+        unsigned.signer_payload = self
+            .tx()
+            .create_partial_signed_with_nonce(
+                &RawCall(&unsigned.call_data),
+                nonce,
+                unsigned.params(),
+            )?
+            .signer_payload();
+        Ok(unsigned)
+    }
+
+    /// Assembles the extrinsic with the sr25519 signature returned by the offline signer.
+    ///
+    /// Fails if the signature doesn't match, or if the chain no longer produces the same signer
+    /// payload, e.g. because of a runtime upgrade since the export.
+    pub fn assemble_signed(
+        &self,
+        unsigned: &UnsignedExtrinsic,
+        signature: &[u8],
+    ) -> Result<SubmittableExtrinsic<Config, RpcClient>> {
+        let partial = self.tx().create_partial_signed_with_nonce(
+            &RawCall(&unsigned.call_data),
+            unsigned.nonce,
+            unsigned.params(),
+        )?;
+        if partial.signer_payload() != unsigned.signer_payload {
+            bail!("The signer payload changed since the export, please export and sign again");
+        }
+        let signature: [u8; 64] = signature
+            .try_into()
+            .map_err(|_| anyhow!("Invalid sr25519 signature length"))?;
+        let public = sr25519::Public::from_raw(unsigned.signer.0);
+        let sig = sr25519::Signature::from_raw(signature);
+        if !sr25519::Pair::verify(&sig, &unsigned.signer_payload, &public) {
+            bail!("Bad signature");
+        }
+        Ok(partial.sign_with_address_and_signature(
+            &MultiAddress::Id(unsigned.signer.clone()),
+            &MultiSignature::Sr25519(signature),
+        ))
+    }
+
+    /// Assembles the extrinsic and submits it, returning the extrinsic hash.
+    pub async fn submit_signed(
+        &self,
+        unsigned: &UnsignedExtrinsic,
+        signature: &[u8],
+    ) -> Result<Hash> {
+        let extrinsic = self.assemble_signed(unsigned, signature)?;
+        Ok(extrinsic.submit().await?)
+    }
+}
+
+mod serde_hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::convert::TryFrom;
+
+    pub fn serialize<S: Serializer>(
+        bytes: impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<Vec<u8>>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(D::Error::custom)?;
+        T::try_from(bytes).map_err(|_| D::Error::custom("Invalid length"))
+    }
+}
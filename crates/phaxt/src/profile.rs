@@ -0,0 +1,220 @@
+//! The constants of the chains the clients connect to.
+//!
+//! A [`ChainProfile`] is resolved when a client is constructed, either from one of the named
+//! profiles or from a JSON file for test networks and forks, e.g.
+//!
+//! ```json
+//! {
+//!   "name": "my-fork",
+//!   "ss58_prefix": 42,
+//!   "token_symbol": "UNIT",
+//!   "token_decimals": 12,
+//!   "pallet_indices": { "PhalaMq": 85 }
+//! }
+//! ```
+//!
+//! The pallets pinned by `pallet_indices` are checked against the runtime metadata once
+//! connected, so that a node of another chain configured by mistake is refused.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// The names of the built-in profiles.
+pub const NAMED_PROFILES: &[&str] = &[
+    "khala", "phala", "kusama", "polkadot", "westend", "paseo", "dev",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainProfile {
+    pub name: String,
+    pub ss58_prefix: u16,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+    /// Pallet name to the expected pallet index.
+    #[serde(default)]
+    pub pallet_indices: BTreeMap<String, u8>,
+}
+
+impl Default for ChainProfile {
+    fn default() -> Self {
+        Self::named("khala").expect("khala is a named profile")
+    }
+}
+
+impl ChainProfile {
+    fn new(name: &str, ss58_prefix: u16, token_symbol: &str, token_decimals: u8) -> Self {
+        Self {
+            name: name.into(),
+            ss58_prefix,
+            token_symbol: token_symbol.into(),
+            token_decimals,
+            pallet_indices: Default::default(),
+        }
+    }
+
+    fn with_pallets(mut self, pallets: &[(&str, u8)]) -> Self {
+        self.pallet_indices = pallets
+            .iter()
+            .map(|&(name, index)| (name.into(), index))
+            .collect();
+        self
+    }
+
+    /// One of the [`NAMED_PROFILES`].
+    pub fn named(name: &str) -> Option<Self> {
+        // Khala and Phala share the layout of their runtimes.
+        const PHALA_PALLETS: &[(&str, u8)] = &[
+            ("System", 0),
+            ("Timestamp", 1),
+            ("ParachainInfo", 20),
+            ("ParachainSystem", 21),
+            ("Balances", 40),
+            ("PhalaMq", 85),
+            ("PhalaRegistry", 86),
+            ("PhalaComputation", 87),
+        ];
+        // Paseo runs the Polkadot runtime.
+        const POLKADOT_PALLETS: &[(&str, u8)] = &[
+            ("System", 0),
+            ("Babe", 2),
+            ("Timestamp", 3),
+            ("Balances", 5),
+            ("Session", 9),
+            ("Grandpa", 11),
+            ("Paras", 56),
+        ];
+        let profile = match name {
+            "khala" => Self::new(name, 30, "PHA", 12).with_pallets(PHALA_PALLETS),
+            "phala" => Self::new(name, 30, "PHA", 12).with_pallets(PHALA_PALLETS),
+            "kusama" => Self::new(name, 2, "KSM", 12).with_pallets(&[
+                ("System", 0),
+                ("Babe", 1),
+                ("Timestamp", 2),
+                ("Balances", 4),
+                ("Session", 8),
+                ("Grandpa", 10),
+                ("Paras", 56),
+            ]),
+            "polkadot" => Self::new(name, 0, "DOT", 10).with_pallets(POLKADOT_PALLETS),
+            "westend" => Self::new(name, 42, "WND", 12).with_pallets(&[
+                ("System", 0),
+                ("Babe", 1),
+                ("Timestamp", 2),
+                ("Balances", 4),
+                ("Session", 8),
+                ("Grandpa", 10),
+                ("Paras", 47),
+            ]),
+            "paseo" => Self::new(name, 0, "PAS", 10).with_pallets(POLKADOT_PALLETS),
+            // The standalone phala-node, serving as both the relay chain and the parachain.
+            "dev" => Self::new(name, 30, "PHA", 12).with_pallets(&[
+                ("System", 1),
+                ("Babe", 3),
+                ("Timestamp", 4),
+                ("Balances", 7),
+                ("Session", 14),
+                ("Grandpa", 20),
+                ("PhalaMq", 40),
+                ("PhalaRegistry", 41),
+                ("PhalaComputation", 42),
+            ]),
+            _ => return None,
+        };
+        Some(profile)
+    }
+
+    /// Loads a custom profile from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read chain profile {path}"))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid chain profile {path}"))
+    }
+
+    /// Resolves `spec` as a named profile, or else as the path of a custom profile.
+    pub fn resolve(spec: &str) -> Result<Self> {
+        if let Some(profile) = Self::named(spec) {
+            return Ok(profile);
+        }
+        if std::path::Path::new(spec).is_file() {
+            return Self::from_file(spec);
+        }
+        Err(anyhow!(
+            "Unknown chain profile {spec}, expected one of {NAMED_PROFILES:?} or a JSON file"
+        ))
+    }
+
+    /// Checks the pinned pallet indices against the runtime metadata.
+    pub fn verify(&self, metadata: &subxt::Metadata) -> Result<()> {
+        for (pallet_name, &expected) in &self.pallet_indices {
+            let Some(pallet) = metadata.pallet_by_name(pallet_name) else {
+                bail!(
+                    "Pallet {pallet_name} of chain profile {} not found",
+                    self.name
+                );
+            };
+            if pallet.index() != expected {
+                bail!(
+                    "Pallet {pallet_name} is at index {}, but chain profile {} expects {expected}",
+                    pallet.index(),
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Formats an amount in the smallest unit as tokens, e.g. `1.5 PHA`.
+    pub fn format_balance(&self, amount: u128) -> String {
+        let unit = 10u128.pow(self.token_decimals as u32);
+        let fraction = amount % unit;
+        let whole = amount / unit;
+        if fraction == 0 {
+            return format!("{whole} {}", self.token_symbol);
+        }
+        let fraction = format!("{fraction:0width$}", width = self.token_decimals as usize);
+        format!(
+            "{whole}.{} {}",
+            fraction.trim_end_matches('0'),
+            self.token_symbol
+        )
+    }
+}
+
+static INSTALLED: OnceLock<ChainProfile> = OnceLock::new();
+
+/// Installs the profile of the parachain for the whole process, for the code formatting addresses
+/// or amounts without a client at hand. Can be installed only once.
+pub fn install(profile: ChainProfile) -> Result<()> {
+    INSTALLED
+        .set(profile)
+        .map_err(|_| anyhow!("A chain profile is installed already"))
+}
+
+/// The installed profile, or the default one if none was installed.
+pub fn installed() -> &'static ChainProfile {
+    INSTALLED.get_or_init(ChainProfile::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_profiles_pin_their_pallets() {
+        for name in NAMED_PROFILES {
+            let profile = ChainProfile::named(name).unwrap();
+            assert!(
+                profile.pallet_indices.contains_key("System"),
+                "{name} doesn't pin System"
+            );
+            assert!(
+                profile.pallet_indices.len() > 1,
+                "{name} pins too few pallets"
+            );
+        }
+        let khala = ChainProfile::named("khala").unwrap();
+        assert_eq!(khala.pallet_indices["PhalaMq"], 85);
+    }
+}
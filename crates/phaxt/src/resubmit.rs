@@ -0,0 +1,247 @@
+//! Resubmission of signed extrinsics.
+//!
+//! An extrinsic which did not make it on chain, e.g. because its era expired or it was dropped
+//! from the pool, has to be signed again with a new era, nonce or tip. Rebuilding the call from
+//! its inputs for that risks a different call, e.g. when the inputs changed in the meantime or
+//! the call is encoded with newer metadata. Instead, [`SignedExtrinsic::decode`] takes the call
+//! out of the previous extrinsic, and [`ChainApi::resign`] signs the very same call bytes again,
+//! checking that the replacement carries them unchanged.
+//!
+//! The signed extensions are expected to be the ones of [`crate::ExtrinsicParams`]: the era,
+//! the nonce and the tip.
+
+use anyhow::{anyhow, bail, Context, Result};
+use parity_scale_codec::{Compact, Decode};
+use subxt::{
+    config::{substrate::Era, Header as _},
+    tx::{Signer, SubmittableExtrinsic},
+    utils::{MultiAddress, MultiSignature},
+};
+
+use crate::{
+    offline::RawCall, rpc::ExtraRpcExt as _, AccountId, BlockNumber, ChainApi, Config,
+    ExtrinsicParamsBuilder, Index, RpcClient,
+};
+
+/// The version byte of a signed extrinsic of format version 4.
+const SIGNED_EXTRINSIC_V4: u8 = 0b1000_0100;
+
+/// The longest period a mortal era can encode.
+pub const MAX_LONGEVITY: u64 = 1 << 16;
+
+/// Returns the mortal era starting around block `current` and valid for at least `longevity`
+/// blocks, along with the number of the block the era is born at, whose hash the extrinsic has
+/// to be signed against.
+///
+/// Mirrors `sp_runtime::generic::Era::mortal` and `Era::birth`: the period is rounded up to a
+/// power of two within `4..=65536`, and for periods above 4096 the phase is quantized, so the
+/// birth block may be a bit older than `current`.
+pub fn mortal_era(longevity: u64, current: u64) -> (Era, u64) {
+    let period = longevity
+        .checked_next_power_of_two()
+        .unwrap_or(MAX_LONGEVITY)
+        .clamp(4, MAX_LONGEVITY);
+    let phase = current % period;
+    let quantize_factor = (period >> 12).max(1);
+    let quantized_phase = phase / quantize_factor * quantize_factor;
+    let birth =
+        (current.max(quantized_phase) - quantized_phase) / period * period + quantized_phase;
+    (Era::Mortal(period, quantized_phase), birth)
+}
+
+/// A signed extrinsic taken apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedExtrinsic {
+    pub signer: AccountId,
+    /// The SCALE encoded call.
+    pub call_data: Vec<u8>,
+    pub nonce: Index,
+    pub tip: u128,
+    pub era: Era,
+}
+
+impl SignedExtrinsic {
+    /// Decodes a signed extrinsic as it is submitted, i.e. prefixed with its length.
+    pub fn decode(extrinsic: &[u8]) -> Result<Self> {
+        let mut input = extrinsic;
+        let len = Compact::<u32>::decode(&mut input).context("Bad extrinsic length")?;
+        if len.0 as usize != input.len() {
+            bail!(
+                "Extrinsic length mismatch, expected {}, got {}",
+                len.0,
+                input.len()
+            );
+        }
+        let version = u8::decode(&mut input)?;
+        if version != SIGNED_EXTRINSIC_V4 {
+            bail!("Not a signed v4 extrinsic, version byte {version:#x}");
+        }
+        let signer = match MultiAddress::<AccountId, u32>::decode(&mut input)? {
+            MultiAddress::Id(id) => id,
+            _ => bail!("Unsupported signer address"),
+        };
+        let _signature = MultiSignature::decode(&mut input)?;
+        let era = Era::decode(&mut input).context("Bad era")?;
+        let nonce = Compact::<Index>::decode(&mut input).context("Bad nonce")?.0;
+        let tip = Compact::<u128>::decode(&mut input).context("Bad tip")?.0;
+        Ok(Self {
+            signer,
+            call_data: input.to_vec(),
+            nonce,
+            tip,
+            era,
+        })
+    }
+}
+
+/// The parameters of a replacement extrinsic, the ones left `None` are taken from the previous
+/// extrinsic.
+#[derive(Clone, Debug, Default)]
+pub struct Resubmission {
+    /// `None` keeps the nonce of the previous extrinsic, unless it is used on chain already, in
+    /// which case the next nonce of the account is taken.
+    pub nonce: Option<Index>,
+    pub tip: Option<u128>,
+    /// Number of blocks the replacement stays valid from the best block, 0 for immortal. It is
+    /// rounded up to a power of two and capped at [`MAX_LONGEVITY`]. `None` keeps the period of
+    /// the previous extrinsic, starting at the best block.
+    pub longevity: Option<u64>,
+}
+
+impl ChainApi {
+    /// Signs the call of the `previous` signed extrinsic again, with a new era, nonce or tip.
+    ///
+    /// Fails if `signer` is not the signer of the previous extrinsic, or if the replacement does
+    /// not carry the same call bytes.
+    pub async fn resign<S: Signer<Config>>(
+        &self,
+        previous: &[u8],
+        signer: &S,
+        resubmission: Resubmission,
+    ) -> Result<SubmittableExtrinsic<Config, RpcClient>> {
+        let previous = SignedExtrinsic::decode(previous)?;
+        if signer.account_id() != &previous.signer {
+            bail!("The extrinsic was signed by another account");
+        }
+        let nonce = match resubmission.nonce {
+            Some(nonce) => nonce,
+            None => {
+                let next = self.extra_rpc().account_nonce(&previous.signer).await?;
+                previous.nonce.max(next)
+            }
+        };
+        let tip = resubmission.tip.unwrap_or(previous.tip);
+        let longevity = match (resubmission.longevity, previous.era) {
+            (Some(longevity), _) => longevity,
+            (None, Era::Mortal(period, _)) => period,
+            (None, Era::Immortal) => 0,
+        };
+        let mut params = ExtrinsicParamsBuilder::new().tip(tip);
+        if longevity > 0 {
+            let header = self
+                .rpc()
+                .header(None)
+                .await?
+                .ok_or_else(|| anyhow!("No header"))?;
+            let (era, birth) = mortal_era(longevity, header.number as u64);
+            let birth_hash = self
+                .rpc()
+                .block_hash(Some((birth as BlockNumber).into()))
+                .await?
+                .ok_or_else(|| anyhow!("No hash of block {birth}"))?;
+            params = params.era(era, birth_hash);
+        }
+        let extrinsic = self.tx().create_signed_with_nonce(
+            &RawCall(&previous.call_data),
+            signer,
+            nonce,
+            params,
+        )?;
+        let replacement = SignedExtrinsic::decode(extrinsic.encoded())?;
+        if replacement.call_data != previous.call_data {
+            bail!("The replacement extrinsic carries a different call");
+        }
+        Ok(extrinsic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_scale_codec::Encode;
+
+    fn signed_extrinsic(era: Era, nonce: Index, tip: u128, call_data: &[u8]) -> Vec<u8> {
+        let mut body = vec![SIGNED_EXTRINSIC_V4];
+        MultiAddress::<AccountId, u32>::Id(AccountId::from([1u8; 32])).encode_to(&mut body);
+        MultiSignature::Sr25519([2u8; 64]).encode_to(&mut body);
+        era.encode_to(&mut body);
+        Compact(nonce).encode_to(&mut body);
+        Compact(tip).encode_to(&mut body);
+        body.extend_from_slice(call_data);
+        let mut extrinsic = Compact(body.len() as u32).encode();
+        extrinsic.extend(body);
+        extrinsic
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let era = Era::Mortal(64, 42);
+        let extrinsic = signed_extrinsic(era, 7, 1_000, &[3, 4, 5]);
+        let decoded = SignedExtrinsic::decode(&extrinsic).unwrap();
+        assert_eq!(
+            decoded,
+            SignedExtrinsic {
+                signer: AccountId::from([1u8; 32]),
+                call_data: vec![3, 4, 5],
+                nonce: 7,
+                tip: 1_000,
+                era,
+            }
+        );
+        assert_eq!(
+            signed_extrinsic(decoded.era, decoded.nonce, decoded.tip, &decoded.call_data),
+            extrinsic
+        );
+    }
+
+    #[test]
+    fn decode_rejects_malformed_extrinsics() {
+        let mut extrinsic = signed_extrinsic(Era::Immortal, 0, 0, &[3]);
+        extrinsic.push(0);
+        assert!(SignedExtrinsic::decode(&extrinsic).is_err());
+
+        let unsigned = [Compact(2u32).encode(), vec![0b0000_0100, 3]].concat();
+        assert!(SignedExtrinsic::decode(&unsigned).is_err());
+    }
+
+    #[test]
+    fn mortal_era_is_born_at_the_current_block() {
+        for current in [0, 3, 100, 1_000_003] {
+            let (era, birth) = mortal_era(64, current);
+            assert_eq!(era, Era::Mortal(64, current % 64));
+            assert_eq!(birth, current);
+        }
+    }
+
+    #[test]
+    fn mortal_era_rounds_up_and_caps_the_period() {
+        assert_eq!(mortal_era(1, 10).0, Era::Mortal(4, 2));
+        assert_eq!(mortal_era(100, 10).0, Era::Mortal(128, 10));
+        assert_eq!(mortal_era(u64::MAX, 10).0, Era::Mortal(MAX_LONGEVITY, 0));
+        assert_eq!(mortal_era(100_000, 10).0, Era::Mortal(MAX_LONGEVITY, 0));
+    }
+
+    #[test]
+    fn mortal_era_of_long_periods_is_quantized() {
+        // The phase of a period of 65536 is quantized by 16.
+        let current = 1_000_003;
+        let (era, birth) = mortal_era(MAX_LONGEVITY, current);
+        let phase = current % MAX_LONGEVITY / 16 * 16;
+        assert_eq!(era, Era::Mortal(MAX_LONGEVITY, phase));
+        assert_eq!(birth, current / MAX_LONGEVITY * MAX_LONGEVITY + phase);
+        assert!(birth <= current && current - birth < 16);
+        // The era encodes the quantized phase without loss.
+        let encoded = era.encode();
+        assert_eq!(Era::decode(&mut &encoded[..]).unwrap(), era);
+    }
+}
@@ -6,7 +6,7 @@ use subxt::{
     Config, Error, OnlineClient,
 };
 
-pub use sp_core::storage::{StorageData, StorageKey};
+pub use sp_core::storage::{StorageChangeSet, StorageData, StorageKey};
 
 pub trait ExtraRpcExt {
     type Config: Config;
@@ -66,6 +66,16 @@ impl<'a, T: Config> ExtraRpcClient<'a, T> {
         Ok(data)
     }
 
+    /// Query the values of many storage keys at a block in a single call
+    pub async fn query_storage_at(
+        &self,
+        keys: Vec<StorageKey>,
+        at: Option<T::Hash>,
+    ) -> Result<Vec<StorageChangeSet<T::Hash>>, Error> {
+        let params = rpc_params![to_json_value(keys)?, to_json_value(at)?];
+        self.client.request("state_queryStorageAt", params).await
+    }
+
     /// Fetch block syncing status
     pub async fn system_sync_state(&self) -> Result<SyncState, Error> {
         self.client.request("system_syncState", rpc_params![]).await
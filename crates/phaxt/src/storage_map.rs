@@ -0,0 +1,175 @@
+//! Typed iteration of the storage maps.
+//!
+//! Iterating a big map, e.g. all the workers, by `state_getKeysPaged` by hand is error-prone: the
+//! pages must be read at the same block, the keys decoded after their hash and the values fetched
+//! separately. [`ChainApi::iter_map`] does all of it for a map declared with [`StorageMap`].
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, Context, Result};
+use parity_scale_codec::Decode;
+
+use crate::rpc::{ExtraRpcExt as _, StorageKey};
+use crate::{ChainApi, Hash};
+
+/// The number of keys fetched per page.
+pub const DEFAULT_PAGE_SIZE: u32 = 1000;
+
+/// The hasher of the keys of a map. Only the hashers keeping the key are supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyHasher {
+    Identity,
+    Twox64Concat,
+    Blake2_128Concat,
+}
+
+impl KeyHasher {
+    fn hash_len(&self) -> usize {
+        match self {
+            KeyHasher::Identity => 0,
+            KeyHasher::Twox64Concat => 8,
+            KeyHasher::Blake2_128Concat => 16,
+        }
+    }
+}
+
+/// A storage map of the chain, e.g.
+///
+/// ```ignore
+/// struct WorkerBindings;
+/// impl StorageMap for WorkerBindings {
+///     const PALLET: &'static str = "PhalaComputation";
+///     const MAP: &'static str = "WorkerBindings";
+///     const HASHER: KeyHasher = KeyHasher::Twox64Concat;
+///     type Key = WorkerPublicKey;
+///     type Value = AccountId32;
+/// }
+/// ```
+pub trait StorageMap {
+    const PALLET: &'static str;
+    const MAP: &'static str;
+    const HASHER: KeyHasher;
+    type Key: Decode;
+    type Value: Decode;
+}
+
+/// The pages of a map, all read at the same block.
+pub struct MapPages<'a, M> {
+    api: &'a ChainApi,
+    prefix: Vec<u8>,
+    at: Hash,
+    page_size: u32,
+    last_key: Option<Vec<u8>>,
+    done: bool,
+    _map: PhantomData<M>,
+}
+
+impl<M: StorageMap> MapPages<'_, M> {
+    /// The block the map is read at.
+    pub fn at(&self) -> Hash {
+        self.at
+    }
+
+    pub fn with_page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// The next page of entries, None once all the entries are read.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<(M::Key, M::Value)>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let keys: Vec<Vec<u8>> = self
+            .api
+            .rpc()
+            .storage_keys_paged(
+                &self.prefix,
+                self.page_size,
+                self.last_key.as_deref(),
+                Some(self.at),
+            )
+            .await
+            .with_context(|| format!("Failed to get the keys of {}::{}", M::PALLET, M::MAP))?
+            .into_iter()
+            .map(|key| key.0)
+            .collect();
+        if keys.len() < self.page_size as usize {
+            self.done = true;
+        }
+        let Some(last_key) = keys.last() else {
+            return Ok(None);
+        };
+        self.last_key = Some(last_key.clone());
+
+        let storage_keys = keys.iter().cloned().map(StorageKey).collect();
+        let mut values: HashMap<_, _> = self
+            .api
+            .extra_rpc()
+            .query_storage_at(storage_keys, Some(self.at))
+            .await
+            .with_context(|| format!("Failed to get the values of {}::{}", M::PALLET, M::MAP))?
+            .into_iter()
+            .flat_map(|set| set.changes)
+            .filter_map(|(key, data)| Some((key.0, data?.0)))
+            .collect();
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(value) = values.remove(&key) else {
+                continue;
+            };
+            entries.push((decode_key::<M>(&key)?, decode_value::<M>(&value)?));
+        }
+        Ok(Some(entries))
+    }
+}
+
+fn decode_key<M: StorageMap>(key: &[u8]) -> Result<M::Key> {
+    // twox128(pallet) ++ twox128(map) ++ hash(key) ++ key
+    let offset = 32 + M::HASHER.hash_len();
+    let mut encoded = key
+        .get(offset..)
+        .ok_or_else(|| anyhow!("Short key of {}::{}", M::PALLET, M::MAP))?;
+    M::Key::decode(&mut encoded).with_context(|| format!("Bad key of {}::{}", M::PALLET, M::MAP))
+}
+
+fn decode_value<M: StorageMap>(mut value: &[u8]) -> Result<M::Value> {
+    M::Value::decode(&mut value).with_context(|| format!("Bad value of {}::{}", M::PALLET, M::MAP))
+}
+
+impl ChainApi {
+    /// The pages of the map at the block `at`, or at the finalized head if None.
+    pub async fn map_pages<M: StorageMap>(&self, at: Option<Hash>) -> Result<MapPages<'_, M>> {
+        let at = match at {
+            Some(at) => at,
+            None => self
+                .rpc()
+                .finalized_head()
+                .await
+                .context("Failed to get finalized head")?,
+        };
+        Ok(MapPages {
+            api: self,
+            prefix: crate::dynamic::storage_key(M::PALLET, M::MAP),
+            at,
+            page_size: DEFAULT_PAGE_SIZE,
+            last_key: None,
+            done: false,
+            _map: PhantomData,
+        })
+    }
+
+    /// All the entries of the map at the block `at`, or at the finalized head if None.
+    pub async fn iter_map<M: StorageMap>(
+        &self,
+        at: Option<Hash>,
+    ) -> Result<Vec<(M::Key, M::Value)>> {
+        let mut pages = self.map_pages::<M>(at).await?;
+        let mut entries = vec![];
+        while let Some(page) = pages.next_page().await? {
+            entries.extend(page);
+        }
+        Ok(entries)
+    }
+}
@@ -1,4 +1,5 @@
 use scale::{Decode, Encode};
+use scale_info::TypeInfo;
 use sp_core::Hasher;
 use sp_runtime::{traits::BlakeTwo256, AccountId32};
 
@@ -73,6 +74,32 @@ impl ExecutionMode {
     }
 }
 
+/// The chain extensions disabled in the cluster by its owner.
+#[derive(Decode, Encode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ExtensionPolicy {
+    /// Disables `http_request` and `batch_http_request`.
+    pub disable_http: bool,
+    /// Disables `getrandom`.
+    pub disable_randomness: bool,
+    /// Disables `secret_set`, `secret_get` and `secret_remove`.
+    pub disable_secrets: bool,
+}
+
+impl ExtensionPolicy {
+    /// Returns whether the chain extension function with given id is disabled.
+    pub fn disables(&self, func_id: u16) -> bool {
+        match func_id {
+            // http_request, batch_http_request
+            1 | 22 => self.disable_http,
+            // getrandom
+            11 => self.disable_randomness,
+            // secret_set, secret_get, secret_remove
+            26..=28 => self.disable_secrets,
+            _ => false,
+        }
+    }
+}
+
 /// Events emitted by contracts which can potentially lead to further actions by the pruntime.
 #[derive(Decode, Encode, Debug, Clone)]
 pub enum ExecSideEffects {
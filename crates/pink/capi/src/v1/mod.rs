@@ -41,7 +41,9 @@ pub trait OCall: CrossCall {}
 
 pub mod ecall {
     use super::{CrossCallMut, ECall, Executing};
-    use crate::types::{AccountId, Balance, BlockNumber, ExecutionMode, Hash, Weight};
+    use crate::types::{
+        AccountId, Balance, BlockNumber, ExecutionMode, ExtensionPolicy, Hash, Weight,
+    };
     use pink_runtime_macro::cross_call;
     use scale::{Decode, Encode};
 
@@ -180,6 +182,22 @@ pub mod ecall {
         /// Would be called once per block.
         #[xcall(id = 24, since = "1.2")]
         fn on_idle(&mut self, block_number: BlockNumber);
+
+        /// Swaps the code of the contract to the code with given hash, then calls the contract
+        /// with `input_data` as the migration entry point if it is not empty. The upgrade is
+        /// reverted if the call fails or reverts.
+        #[xcall(id = 25, since = "1.2")]
+        fn contract_upgrade(
+            &mut self,
+            contract: AccountId,
+            code_hash: Hash,
+            input_data: Vec<u8>,
+            tx_args: TransactionArguments,
+        ) -> Result<(), String>;
+
+        /// Sets the chain extensions disabled in the cluster.
+        #[xcall(id = 26, since = "1.2")]
+        fn set_extension_policy(&mut self, policy: ExtensionPolicy);
     }
 
     #[test]
@@ -337,6 +355,12 @@ pub mod ocall {
         /// Returns the SGX quote of the worker.
         #[xcall(id = 21)]
         fn worker_sgx_quote(&self) -> Option<SgxQuote>;
+
+        /// Returns the key sealing the secrets of the contract. It is derived by the worker from a
+        /// cluster secret kept out of the cluster storage, and is `None` for the clusters which
+        /// have no such secret.
+        #[xcall(id = 22)]
+        fn secret_sealing_key(&self, contract: AccountId) -> Option<[u8; 32]>;
     }
 }
 
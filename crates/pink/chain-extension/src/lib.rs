@@ -385,6 +385,22 @@ impl<T: PinkRuntimeEnv, E: From<&'static str>> PinkExtBackend for DefaultPinkExt
     fn worker_sgx_quote(&self) -> Result<Option<SgxQuote>, Self::Error> {
         Ok(None)
     }
+
+    fn secret_set(
+        &self,
+        _key: Cow<[u8]>,
+        _value: Cow<[u8]>,
+    ) -> Result<Result<(), StorageQuotaExceeded>, Self::Error> {
+        Ok(Ok(()))
+    }
+
+    fn secret_get(&self, _key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(None)
+    }
+
+    fn secret_remove(&self, _key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(None)
+    }
 }
 
 struct LimitedWriter<W> {
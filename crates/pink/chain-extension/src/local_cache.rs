@@ -224,6 +224,23 @@ impl LocalCache {
         store.remove(key)
     }
 
+    /// Sum of the size of all the keys and values in the cache.
+    pub fn total_size(&self) -> usize {
+        self.storages.values().map(|storage| storage.size).sum()
+    }
+
+    /// Evicts the items closest to the expiration date, until each storage is down to
+    /// `keep_percent` of its current size. The quotas are left unchanged.
+    pub fn evict(&mut self, keep_percent: usize) {
+        self.clear_expired();
+        for storage in self.storages.values_mut() {
+            let max_size = storage.max_size;
+            storage.max_size = storage.size * keep_percent.min(100) / 100;
+            storage.fit_size();
+            storage.max_size = max_size;
+        }
+    }
+
     pub fn apply_quotas<'a>(&mut self, quotas: impl IntoIterator<Item = (&'a [u8], usize)>) {
         for (contract, max_size) in quotas.into_iter() {
             log::trace!(
@@ -287,6 +304,14 @@ pub fn apply_quotas<'a>(quotas: impl IntoIterator<Item = (&'a [u8], usize)>) {
     with_global_cache(|cache| cache.apply_quotas(quotas))
 }
 
+pub fn total_size() -> usize {
+    with_global_cache(|cache| cache.total_size())
+}
+
+pub fn evict(keep_percent: usize) {
+    with_global_cache(|cache| cache.evict(keep_percent))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -408,6 +433,23 @@ mod test {
         assert_eq!(store.size, 8);
     }
 
+    #[test]
+    fn evict_works() {
+        let mut cache = test_cache();
+        cache.default_value_lifetime = 1000;
+        cache.apply_quotas([(&b"id"[..], 1000)]);
+        for key in [b"k0", b"k1", b"k2", b"k3"] {
+            assert!(cache.set(cow(b"id"), cow(key), cow(b"v0")).is_ok());
+        }
+        assert_eq!(cache.total_size(), 16);
+
+        cache.evict(50);
+        assert_eq!(cache.total_size(), 8);
+        assert_eq!(cache.storages.get(&b"id"[..]).unwrap().max_size, 1000);
+        assert!(cache.set(cow(b"id"), cow(b"k4"), cow(b"v0")).is_ok());
+        assert_eq!(cache.total_size(), 12);
+    }
+
     #[test]
     fn cache_op_works() {
         use pink::CacheOp;
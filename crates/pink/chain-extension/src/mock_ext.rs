@@ -166,10 +166,33 @@ impl ext::PinkExtBackend for MockExtension {
             quote,
         }))
     }
+
+    fn secret_set(
+        &self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+    ) -> Result<Result<(), ext::StorageQuotaExceeded>, Self::Error> {
+        SECRETS.with(|secrets| {
+            secrets
+                .borrow_mut()
+                .insert(key.into_owned(), value.into_owned())
+        });
+        Ok(Ok(()))
+    }
+
+    fn secret_get(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(SECRETS.with(|secrets| secrets.borrow().get(key.as_ref()).cloned()))
+    }
+
+    fn secret_remove(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(SECRETS.with(|secrets| secrets.borrow_mut().remove(key.as_ref())))
+    }
 }
 
 thread_local! {
     static IS_COMMAND_MODE: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static SECRETS: std::cell::RefCell<std::collections::BTreeMap<Vec<u8>, Vec<u8>>> =
+        Default::default();
 }
 
 pub fn set_mode(is_command: bool) {
@@ -282,6 +305,22 @@ mod tests {
         assert!(result.unwrap().is_none());
     }
 
+    #[test]
+    fn secret_works() {
+        mock_all_ext();
+        let ext = MockExtension;
+        let key = b"hello";
+        let value = b"world";
+        let result = ext.secret_set(Cow::Borrowed(key), Cow::Borrowed(value));
+        assert!(matches!(result, Ok(Ok(()))));
+        let result = ext.secret_get(Cow::Borrowed(key));
+        assert_eq!(result.unwrap().unwrap(), value);
+        let result = ext.secret_remove(Cow::Borrowed(key));
+        assert_eq!(result.unwrap().unwrap(), value);
+        let result = ext.secret_get(Cow::Borrowed(key));
+        assert!(result.unwrap().is_none());
+    }
+
     #[test]
     fn log_works() {
         mock_all_ext();
@@ -42,6 +42,14 @@ impl ClusterStorage {
         }
     }
 
+    /// Sum of the size of all the keys and values in the storage.
+    pub fn approx_size(&self) -> usize {
+        self.kv_store
+            .iter()
+            .map(|(key, (_rc, value))| key.len() + value.len())
+            .sum()
+    }
+
     pub fn commit(&mut self, root: Hash, changes: StorageChanges) {
         for (key, (value, rc)) in changes {
             self.update(key, value, rc);
@@ -719,6 +719,67 @@ pub trait PinkExt {
     /// ```
     #[ink(extension = 25, handle_status = false)]
     fn worker_sgx_quote() -> Option<SgxQuote>;
+
+    /// Store a secret sealed to the cluster key.
+    ///
+    /// Unlike the contract storage, the value is encrypted with a key derived from the cluster key
+    /// and the contract address before being put into the cluster state. It survives checkpoints,
+    /// but never shows up in plain text in the cluster state dumps and can only be read back by
+    /// the contract which stored it.
+    ///
+    /// The secrets of each contract share a byte quota counting both the keys and the values.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key used to identify the secret in the namespace of the contract.
+    /// * `value`: The secret.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), StorageQuotaExceeded>` - `Ok(())` or `Err(StorageQuotaExceeded)` if the
+    ///     secret quota of the contract is exceeded.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// pink::ext().secret_set(b"api key", b"42").expect("Secret quota exceeded");
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | transaction
+    ///
+    /// Secrets set in a query are dropped together with the other state changes of the query.
+    /// Clusters created before the cluster key was kept out of the cluster state can not seal
+    /// secrets, the call fails there.
+    ///
+    /// # Runtime version
+    /// 1.3
+    #[ink(extension = 26, handle_status = true)]
+    fn secret_set(key: &[u8], value: &[u8]) -> Result<(), StorageQuotaExceeded>;
+
+    /// Get a secret stored by [`secret_set`](Self::secret_set) of the current contract.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<u8>>` - The secret, or `None` if it does not exist.
+    ///
+    /// # Availability
+    /// any contract | query | transaction
+    ///
+    /// # Runtime version
+    /// 1.3
+    #[ink(extension = 27, handle_status = false)]
+    fn secret_get(key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Remove a secret of the current contract, returning the removed value if it existed.
+    ///
+    /// # Availability
+    /// any contract | transaction
+    ///
+    /// # Runtime version
+    /// 1.3
+    #[ink(extension = 28, handle_status = false)]
+    fn secret_remove(key: &[u8]) -> Option<Vec<u8>>;
 }
 
 pub fn pink_extension_instance() -> <PinkExt as ChainExtensionInstance>::Instance {
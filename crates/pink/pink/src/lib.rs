@@ -58,6 +58,9 @@ where
 pub enum HookPoint {
     /// When all events in a block are processed.
     OnBlockEnd,
+    /// When the block dispatch finished under the idle budget of the cluster. The gas left in the
+    /// budget is passed to the receiver as a `u64` argument.
+    OnIdle,
 }
 
 /// System Event used to communicate between the contract and the runtime.
@@ -318,6 +321,10 @@ impl PinkEvent {
 ///
 /// # Supported Hook Points
 ///  - `OnBlockEnd`: The receiver contract will be invoked once all events in a Phala chain block have been processed.
+///  - `OnIdle`: The receiver contract will be invoked after the `OnBlockEnd` hooks if the executions of the block
+///    consumed less gas than the idle budget set on chain for the cluster, with the gas left in the budget as the
+///    only argument. The receivers are invoked in the order of their addresses until the budget runs out, and
+///    each is given at most the gas left in the budget, e.g. for housekeeping tasks like refreshing caches.
 ///
 /// # Arguments
 ///
@@ -3,7 +3,7 @@ use log::info;
 use pallet_contracts::{AddressGenerator, Determinism};
 use phala_crypto::sr25519::Sr25519SecretKey;
 use pink_capi::{
-    types::{AccountId, Balance, ExecutionMode, Hash, Weight},
+    types::{AccountId, Balance, ExecutionMode, ExtensionPolicy, Hash, Weight},
     v1::{
         ecall::{self, ClusterSetupConfig, TransactionArguments},
         ocall::OCalls,
@@ -290,6 +290,41 @@ impl ecall::ECalls for ECallImpl {
     fn on_idle(&mut self, block_number: BlockNumber) {
         on_idle(block_number);
     }
+
+    fn contract_upgrade(
+        &mut self,
+        address: AccountId,
+        code_hash: Hash,
+        input_data: Vec<u8>,
+        tx_args: TransactionArguments,
+    ) -> Result<(), String> {
+        let tx_args = sanitize_args(tx_args, ExecutionMode::Transaction);
+        let result = crate::contract::upgrade(address.clone(), code_hash, input_data, tx_args);
+        match &result {
+            Err(err) => {
+                log::error!("[{address:?}] upgrade to {code_hash:?} failed: {err}");
+                OCallImpl.log_to_server(
+                    address,
+                    log::Level::Error as usize as _,
+                    format!("upgrade failed: {err}"),
+                );
+            }
+            Ok(()) => {
+                log::info!("[{address:?}] upgraded to {code_hash:?}");
+                OCallImpl.log_to_server(
+                    address,
+                    log::Level::Info as usize as _,
+                    format!("upgraded to {code_hash:?}"),
+                );
+            }
+        }
+        result
+    }
+
+    fn set_extension_policy(&mut self, policy: ExtensionPolicy) {
+        info!("Extension policy set to {policy:?}");
+        PalletPink::set_extension_policy(policy);
+    }
 }
 
 /// Clip gas limit to 0.5 second for tx, 10 seconds for query
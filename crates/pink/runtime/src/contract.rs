@@ -1,3 +1,4 @@
+use frame_support::storage::{with_transaction, TransactionOutcome};
 use frame_support::weights::Weight;
 use pallet_contracts::Determinism;
 use pallet_contracts::StorageDeposit;
@@ -5,7 +6,7 @@ use pink_capi::{types::ExecutionMode, v1::ecall::TransactionArguments};
 use sp_runtime::DispatchError;
 
 use crate::{
-    runtime::{Contracts, Pink as PalletPink, PinkRuntime},
+    runtime::{Contracts, Pink as PalletPink, PinkRuntime, RuntimeOrigin},
     types::{AccountId, Balance, Hash},
 };
 use anyhow::Result;
@@ -229,6 +230,41 @@ pub fn bare_call(
     }
 }
 
+/// Swap the code of a contract, then call the contract with `input_data` to migrate its storage.
+///
+/// The call is skipped if `input_data` is empty. The code swap, along with everything done by the
+/// call, is reverted if the call fails or reverts.
+pub fn upgrade(
+    address: AccountId,
+    code_hash: Hash,
+    input_data: Vec<u8>,
+    tx_args: TransactionArguments,
+) -> Result<(), String> {
+    let result: Result<Result<(), String>, DispatchError> = with_transaction(|| {
+        if let Err(err) = Contracts::set_code(RuntimeOrigin::root(), address.clone(), code_hash) {
+            return TransactionOutcome::Rollback(Ok(Err(format!("{err:?}"))));
+        }
+        if input_data.is_empty() {
+            return TransactionOutcome::Commit(Ok(Ok(())));
+        }
+        let result = bare_call(address, input_data, ExecutionMode::Transaction, tx_args);
+        if !result.debug_message.is_empty() {
+            let message = String::from_utf8_lossy(&result.debug_message);
+            log::debug!("on_upgrade debug_message: {message:?}");
+        }
+        match result.result {
+            Err(err) => {
+                TransactionOutcome::Rollback(Ok(Err(format!("on_upgrade failed: {err:?}"))))
+            }
+            Ok(ret) if ret.did_revert() => {
+                TransactionOutcome::Rollback(Ok(Err("on_upgrade reverted".into())))
+            }
+            Ok(_) => TransactionOutcome::Commit(Ok(Ok(()))),
+        }
+    });
+    result.map_err(|err| format!("{err:?}"))?
+}
+
 fn contract_tx<T>(
     origin: AccountId,
     gas_limit: Weight,
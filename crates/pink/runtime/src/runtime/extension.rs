@@ -5,7 +5,10 @@ use log::error;
 use pallet_contracts::chain_extension::{
     ChainExtension, Environment, Ext, InitState, Result as ExtResult, RetVal,
 };
-use phala_crypto::sr25519::{Persistence, KDF};
+use phala_crypto::{
+    aead,
+    sr25519::{Persistence, KDF},
+};
 use phala_types::contract::ConvertTo;
 use pink::{
     chain_extension::{
@@ -28,6 +31,9 @@ use super::{pallet_pink, PinkRuntime, SystemEvents};
 use crate::runtime::Pink as PalletPink;
 type Error = pallet_pink::Error<PinkRuntime>;
 
+/// Max bytes of the sealed secrets of a contract, including the keys.
+const SECRET_QUOTA_BYTES: u32 = 64 * 1024;
+
 fn deposit_pink_event(contract: AccountId, event: PinkEvent) {
     let topics = [pink::PinkEvent::event_topic().into()];
     let event = super::RuntimeEvent::Contracts(pallet_contracts::Event::ContractEmitted {
@@ -103,6 +109,11 @@ impl ChainExtension<PinkRuntime> for PinkExtension {
             return Err(Error::UnknownChainExtensionId.into());
         }
 
+        if PalletPink::extension_policy().disables(env.func_id()) {
+            error!(target: "pink", "Called a disabled extension `func_id`: {:}", env.func_id());
+            return Err(Error::ExtensionDisabled.into());
+        }
+
         let address = env.ext().address().clone();
         let call_in_query = CallInQuery { address };
         let mode = OCallImpl.exec_context().mode;
@@ -153,6 +164,41 @@ impl CallInQuery {
         let slice: &[u8] = self.address.as_ref();
         slice.to_vec()
     }
+    /// The AES key sealing the secrets of the contract. It is derived by the worker from a secret
+    /// which is not derivable from the cluster storage, `Key` included, so that a copy of the
+    /// storage does not unseal the secrets.
+    fn secret_sealing_key(&self) -> Result<[u8; 32], DispatchError> {
+        OCallImpl
+            .secret_sealing_key(self.address.clone())
+            .ok_or(Error::SecretSealingUnavailable.into())
+    }
+    fn seal_secret(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, DispatchError> {
+        seal_secret(&self.secret_sealing_key()?, key, value)
+    }
+    fn unseal_secret(&self, sealed: Vec<u8>) -> Result<Vec<u8>, DispatchError> {
+        unseal_secret(&self.secret_sealing_key()?, sealed)
+    }
+}
+
+/// Seals the secret into `iv ++ ciphertext`. The IV is a hash of the content keyed by the sealing
+/// key, so that the sealed value is deterministic across the workers executing the same
+/// transaction, while a guessed value can not be checked against it without the key.
+fn seal_secret(sealing_key: &[u8; 32], key: &[u8], value: &[u8]) -> Result<Vec<u8>, DispatchError> {
+    let iv = aead::generate_iv(&sp_core::blake2_256(
+        &(b"pink_secret_iv", sealing_key, key, value).encode(),
+    ));
+    let mut ciphertext = value.to_vec();
+    aead::encrypt(&iv, sealing_key, &mut ciphertext).or(Err(Error::SecretSealingFailed))?;
+    Ok([&iv[..], &ciphertext].concat())
+}
+
+fn unseal_secret(sealing_key: &[u8; 32], mut sealed: Vec<u8>) -> Result<Vec<u8>, DispatchError> {
+    if sealed.len() < aead::IV_BYTES {
+        return Err(Error::SecretSealingFailed.into());
+    }
+    let (iv, ciphertext) = sealed.split_at_mut(aead::IV_BYTES);
+    let plain = aead::decrypt(iv, sealing_key, ciphertext).or(Err(Error::SecretSealingFailed))?;
+    Ok(plain.to_vec())
 }
 
 impl PinkExtBackend for CallInQuery {
@@ -330,6 +376,37 @@ impl PinkExtBackend for CallInQuery {
     fn worker_sgx_quote(&self) -> Result<Option<SgxQuote>, Self::Error> {
         Ok(OCallImpl.worker_sgx_quote())
     }
+
+    fn secret_set(
+        &self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+    ) -> Result<Result<(), StorageQuotaExceeded>, Self::Error> {
+        let sealed = self.seal_secret(&key, &value)?;
+        let ok = PalletPink::put_sealed_secret(
+            &self.address,
+            key.into_owned(),
+            sealed,
+            SECRET_QUOTA_BYTES,
+        );
+        Ok(if ok {
+            Ok(())
+        } else {
+            Err(StorageQuotaExceeded)
+        })
+    }
+
+    fn secret_get(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        PalletPink::sealed_secret(&self.address, &key)
+            .map(|sealed| self.unseal_secret(sealed))
+            .transpose()
+    }
+
+    fn secret_remove(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        PalletPink::remove_sealed_secret(&self.address, &key)
+            .map(|sealed| self.unseal_secret(sealed))
+            .transpose()
+    }
 }
 
 struct CallInTransaction {
@@ -504,4 +581,64 @@ impl PinkExtBackend for CallInTransaction {
     fn worker_sgx_quote(&self) -> Result<Option<SgxQuote>, Self::Error> {
         Ok(None)
     }
+
+    fn secret_set(
+        &self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+    ) -> Result<Result<(), StorageQuotaExceeded>, Self::Error> {
+        self.as_in_query.secret_set(key, value)
+    }
+
+    fn secret_get(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.as_in_query.secret_get(key)
+    }
+
+    fn secret_remove(&self, key: Cow<[u8]>) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.as_in_query.secret_remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_secret_roundtrip() {
+        let sealing_key = [1u8; 32];
+        let sealed = seal_secret(&sealing_key, b"key", b"value").unwrap();
+        assert_eq!(sealed.len(), aead::IV_BYTES + b"value".len() + 16);
+        assert_ne!(&sealed[aead::IV_BYTES..aead::IV_BYTES + 5], b"value");
+        assert_eq!(unseal_secret(&sealing_key, sealed).unwrap(), b"value");
+        assert!(unseal_secret(&sealing_key, vec![0; 4]).is_err());
+    }
+
+    #[test]
+    fn sealed_secrets_are_deterministic() {
+        let sealing_key = [1u8; 32];
+        assert_eq!(
+            seal_secret(&sealing_key, b"key", b"value").unwrap(),
+            seal_secret(&sealing_key, b"key", b"value").unwrap()
+        );
+        assert_ne!(
+            seal_secret(&sealing_key, b"key", b"value").unwrap(),
+            seal_secret(&sealing_key, b"key", b"other").unwrap()
+        );
+    }
+
+    #[test]
+    fn sealed_secrets_depend_on_the_sealing_key() {
+        let alice = [1u8; 32];
+        let bob = [2u8; 32];
+        let sealed = seal_secret(&alice, b"key", b"value").unwrap();
+        // The IV is keyed, a guessed value can not be confirmed without the sealing key.
+        let guessed_iv =
+            aead::generate_iv(&sp_core::blake2_256(&(&b"key"[..], &b"value"[..]).encode()));
+        assert_ne!(&sealed[..aead::IV_BYTES], &guessed_iv[..]);
+        assert_ne!(
+            sealed[..aead::IV_BYTES],
+            seal_secret(&bob, b"key", b"value").unwrap()[..aead::IV_BYTES]
+        );
+        assert!(unseal_secret(&bob, sealed).is_err());
+    }
 }
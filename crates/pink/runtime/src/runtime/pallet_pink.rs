@@ -10,6 +10,7 @@ pub mod pallet {
     };
     use pallet_contracts::AddressGenerator;
     use phala_crypto::sr25519::Sr25519SecretKey;
+    use pink_capi::types::ExtensionPolicy;
     use scale::{Decode, Encode};
     use scale_info::TypeInfo;
     use sp_core::crypto::UncheckedFrom;
@@ -39,6 +40,13 @@ pub mod pallet {
         DeriveKeyFailed,
         /// The system contract is missing. Should never happen.
         SystemContractMissing,
+        /// Failed to seal or unseal a contract secret. Should never happen.
+        SecretSealingFailed,
+        /// The chain extension is disabled in the cluster by the cluster owner.
+        ExtensionDisabled,
+        /// The cluster was created with its key in the cluster storage, so it has no secret to
+        /// seal the contract secrets with.
+        SecretSealingUnavailable,
     }
 
     #[derive(Clone, Eq, PartialEq, Encode, Decode, TypeInfo)]
@@ -97,6 +105,29 @@ pub mod pallet {
     #[pallet::getter(fn last_event_block_hash)]
     pub(crate) type LastEventBlockHash<T: Config> = StorageValue<_, T::Hash, ValueQuery>;
 
+    /// The secrets of the contracts, sealed with keys derived from the cluster key
+    #[pallet::storage]
+    pub(crate) type SealedSecrets<T: Config> = StorageDoubleMap<
+        _,
+        Twox64Concat,
+        T::AccountId,
+        Blake2_128Concat,
+        Vec<u8>,
+        Vec<u8>,
+        OptionQuery,
+    >;
+
+    /// Bytes of the secret keys and values stored by each contract
+    #[pallet::storage]
+    #[pallet::getter(fn secret_bytes)]
+    pub(crate) type SecretBytes<T: Config> =
+        StorageMap<_, Twox64Concat, T::AccountId, u32, ValueQuery>;
+
+    /// The chain extensions disabled by the cluster owner
+    #[pallet::storage]
+    #[pallet::getter(fn extension_policy)]
+    pub(crate) type DisabledExtensions<T: Config> = StorageValue<_, ExtensionPolicy, ValueQuery>;
+
     #[pallet::pallet]
     #[pallet::without_storage_info]
     pub struct Pallet<T>(_);
@@ -131,6 +162,10 @@ pub mod pallet {
             <Key<T>>::put(key);
         }
 
+        pub fn set_extension_policy(policy: ExtensionPolicy) {
+            <DisabledExtensions<T>>::put(policy);
+        }
+
         pub fn put_sidevm_code(
             owner: T::AccountId,
             code: Vec<u8>,
@@ -202,6 +237,42 @@ pub mod pallet {
         pub fn set_last_event_block_hash(hash: T::Hash) {
             <LastEventBlockHash<T>>::put(hash);
         }
+
+        pub fn sealed_secret(contract: &T::AccountId, key: &[u8]) -> Option<Vec<u8>> {
+            <SealedSecrets<T>>::get(contract, key)
+        }
+
+        /// Puts the sealed secret, returning false without changing anything if the bytes of the
+        /// secrets of the contract would exceed `quota`.
+        pub fn put_sealed_secret(
+            contract: &T::AccountId,
+            key: Vec<u8>,
+            sealed: Vec<u8>,
+            quota: u32,
+        ) -> bool {
+            let old_len = <SealedSecrets<T>>::decode_len(contract, &key)
+                .map(|len| key.len() + len)
+                .unwrap_or(0);
+            let used = Self::secret_bytes(contract).saturating_sub(old_len.saturated_into());
+            let new_len: u32 = (key.len() + sealed.len()).saturated_into();
+            let Some(total) = used.checked_add(new_len).filter(|total| *total <= quota) else {
+                return false;
+            };
+            <SealedSecrets<T>>::insert(contract, key, sealed);
+            <SecretBytes<T>>::insert(contract, total);
+            true
+        }
+
+        pub fn remove_sealed_secret(contract: &T::AccountId, key: &[u8]) -> Option<Vec<u8>> {
+            let sealed = <SealedSecrets<T>>::take(contract, key)?;
+            let len: u32 = (key.len() + sealed.len()).saturated_into();
+            <SecretBytes<T>>::mutate_exists(contract, |used| {
+                *used = used
+                    .map(|used| used.saturating_sub(len))
+                    .filter(|rest| *rest > 0);
+            });
+            Some(sealed)
+        }
     }
 
     impl<T: Config> Convert<Weight, BalanceOf<T>> for Pallet<T> {
@@ -211,3 +282,90 @@ pub mod pallet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::Pink, storage::in_memory_backend::InMemoryStorage, types::AccountId};
+    use pink_capi::v1::ocall::ExecContext;
+
+    fn with_storage(f: impl FnOnce()) {
+        let storage = InMemoryStorage::default();
+        let context = ExecContext {
+            block_number: 1,
+            ..Default::default()
+        };
+        storage.execute_with(&context, f);
+    }
+
+    #[test]
+    fn sealed_secrets_are_bounded_by_the_quota() {
+        with_storage(|| {
+            let contract = AccountId::new([1; 32]);
+            assert!(Pink::put_sealed_secret(
+                &contract,
+                b"a".to_vec(),
+                vec![0; 9],
+                20
+            ));
+            assert_eq!(Pink::secret_bytes(&contract), 10);
+            // Over the quota, nothing changes.
+            assert!(!Pink::put_sealed_secret(
+                &contract,
+                b"b".to_vec(),
+                vec![0; 10],
+                20
+            ));
+            assert_eq!(Pink::sealed_secret(&contract, b"b"), None);
+            assert_eq!(Pink::secret_bytes(&contract), 10);
+            // Replacing a value counts its new size only.
+            assert!(Pink::put_sealed_secret(
+                &contract,
+                b"a".to_vec(),
+                vec![1; 19],
+                20
+            ));
+            assert_eq!(Pink::secret_bytes(&contract), 20);
+            assert_eq!(Pink::sealed_secret(&contract, b"a"), Some(vec![1; 19]));
+            // Removing frees the quota.
+            assert_eq!(
+                Pink::remove_sealed_secret(&contract, b"a"),
+                Some(vec![1; 19])
+            );
+            assert_eq!(Pink::secret_bytes(&contract), 0);
+            assert_eq!(Pink::remove_sealed_secret(&contract, b"a"), None);
+            assert!(Pink::put_sealed_secret(
+                &contract,
+                b"b".to_vec(),
+                vec![0; 19],
+                20
+            ));
+        });
+    }
+
+    #[test]
+    fn sealed_secrets_are_namespaced_by_contract() {
+        with_storage(|| {
+            let alice = AccountId::new([1; 32]);
+            let bob = AccountId::new([2; 32]);
+            assert!(Pink::put_sealed_secret(
+                &alice,
+                b"key".to_vec(),
+                vec![1; 10],
+                20
+            ));
+            assert!(Pink::put_sealed_secret(
+                &bob,
+                b"key".to_vec(),
+                vec![2; 15],
+                20
+            ));
+            assert_eq!(Pink::sealed_secret(&alice, b"key"), Some(vec![1; 10]));
+            assert_eq!(Pink::sealed_secret(&bob, b"key"), Some(vec![2; 15]));
+            // The quota is per contract.
+            assert_eq!(Pink::secret_bytes(&alice), 13);
+            assert_eq!(Pink::secret_bytes(&bob), 18);
+            assert!(Pink::remove_sealed_secret(&alice, b"key").is_some());
+            assert_eq!(Pink::sealed_secret(&bob, b"key"), Some(vec![2; 15]));
+        });
+    }
+}
@@ -370,6 +370,12 @@ impl OCalls for Exec<'_> {
     fn worker_sgx_quote(&self) -> Option<SgxQuote> {
         None
     }
+
+    fn secret_sealing_key(&self, contract: AccountId) -> Option<[u8; 32]> {
+        Some(sp_core::blake2_256(
+            &(b"test_secret_sealing", contract).encode(),
+        ))
+    }
 }
 
 impl CrossCall for Exec<'_> {
@@ -2,7 +2,7 @@ use assert_matches::assert_matches;
 use phala_types::contract::ConvertTo;
 use pink::system::System;
 use pink_capi::{
-    types::{AccountId, Weight},
+    types::{AccountId, ExtensionPolicy, Weight},
     v1::ecall::{ECalls, TransactionArguments},
 };
 use pink_chain_extension::local_cache;
@@ -535,6 +535,32 @@ fn test_getrandom() {
     assert_eq!(result, b"");
 }
 
+#[test]
+fn test_disabled_extensions() {
+    let (mut cluster, checker) = create_cluster();
+    cluster.tx().set_extension_policy(ExtensionPolicy {
+        disable_randomness: true,
+        ..Default::default()
+    });
+
+    // ExtensionDisabled
+    let result = checker.call().getrandom(32).query(&mut cluster);
+    assert_eq!(format!("{result:?}"), "Err(Failed to execute call: Module(ModuleError { index: 5, error: [7, 0, 0, 0], message: None }))");
+
+    let (status, _) = checker
+        .call()
+        .http_get("https://example.com/".to_string())
+        .query(&mut cluster)
+        .unwrap();
+    assert_eq!(status, 200);
+
+    cluster
+        .tx()
+        .set_extension_policy(ExtensionPolicy::default());
+    let result = checker.call().getrandom(32).query(&mut cluster).unwrap();
+    assert_eq!(result.len(), 32);
+}
+
 #[test]
 fn test_ecdsa_signing() {
     let (mut cluster, checker) = create_cluster();
@@ -126,3 +126,22 @@ pub enum QueryResponse {
     },
     SimpleOutput(Vec<u8>),
 }
+
+/// The most keys read by one `query_chain_storage` call.
+pub const MAX_CHAIN_STORAGE_KEYS: usize = 64;
+
+/// The most bytes of values and proof nodes returned by one `query_chain_storage` call.
+pub const MAX_CHAIN_STORAGE_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Entries of the parachain storage read through `query_chain_storage`.
+#[derive(Encode, Decode, Debug)]
+pub struct ChainStorageResponse {
+    /// The block whose state the entries are read from.
+    pub block_number: u32,
+    /// The state root of the block, which the proof is checked against.
+    pub state_root: H256,
+    /// The values of the requested keys, in the order of the request.
+    pub values: Vec<Option<Vec<u8>>>,
+    /// The trie nodes proving the values.
+    pub proof: Vec<Vec<u8>>,
+}
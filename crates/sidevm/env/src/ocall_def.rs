@@ -127,6 +127,18 @@ pub trait OcallFuncs {
     /// Emit program output.
     #[ocall(id = 243)]
     fn emit_program_output(output: &[u8]) -> Result<()>;
+
+    /// Read entries of the parachain storage, with a proof of them against the state root of
+    /// the latest block dispatched by the worker.
+    ///
+    /// Returns a channel id for the encoded `Result<ChainStorageResponse, QueryError>`.
+    ///
+    /// # Limitation
+    /// Shares the limit of one outstanding request with `query_local_contract`. At most
+    /// `MAX_CHAIN_STORAGE_KEYS` keys can be read at once, and the response fails if the values
+    /// and the proof exceed `MAX_CHAIN_STORAGE_RESPONSE_SIZE` bytes.
+    #[ocall(id = 244, encode_input)]
+    fn query_chain_storage(keys: Vec<Vec<u8>>) -> Result<i32>;
 }
 
 #[repr(u8)]
@@ -0,0 +1,126 @@
+//! Persistent cache of the compiled sidevm modules.
+//!
+//! Compiling a sidevm module from scratch takes seconds for a large program, which delays the
+//! recovery of a worker running many instances after a restart. The compiled artifacts are stored
+//! on disk keyed by the code hash and the runtime version, so that the next start only needs to
+//! load them.
+//!
+//! Each artifact file starts with a checksum of the artifact. A file that fails the check, or that
+//! the engine refuses to load, is removed and the module is compiled again. The cache directory is
+//! expected to be in the protected storage in production, since the artifacts are loaded as native
+//! code without further validation.
+
+use anyhow::{Context as _, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+use wasmer::Module;
+
+use crate::run::{WasmEngine, WasmModule};
+
+/// Bump this when the compiled code changes without a version bump of this crate, e.g. the
+/// metering costs.
+const CACHE_VERSION: u32 = 1;
+const MAGIC: &[u8; 8] = b"SIDEVMAC";
+
+pub type CodeHash = [u8; 32];
+
+pub fn code_hash(wasm_code: &[u8]) -> CodeHash {
+    Sha256::digest(wasm_code).into()
+}
+
+pub struct CompileCache {
+    dir: PathBuf,
+}
+
+impl CompileCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create compile cache dir {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn artifact_path(&self, engine: &WasmEngine, code_hash: &CodeHash) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}-{}-v{CACHE_VERSION}.bin",
+            hex_fmt::HexFmt(code_hash),
+            engine.compiler(),
+            env!("CARGO_PKG_VERSION"),
+        ))
+    }
+
+    /// Whether the artifact of the given code is in the cache.
+    pub fn contains(&self, engine: &WasmEngine, code_hash: &CodeHash) -> bool {
+        self.artifact_path(engine, code_hash).exists()
+    }
+
+    /// Loads the compiled module from the cache, or compiles it and stores it into the cache.
+    pub fn compile(&self, engine: &WasmEngine, wasm_code: &[u8]) -> Result<WasmModule> {
+        let code_hash = code_hash(wasm_code);
+        let path = self.artifact_path(engine, &code_hash);
+        if let Some(module) = load(engine, &path) {
+            debug!(target: "sidevm", path = %path.display(), "Compiled module loaded from cache");
+            return Ok(module);
+        }
+        let module = engine.compile(wasm_code)?;
+        if let Err(err) = store(&path, module.inner()) {
+            warn!(target: "sidevm", ?err, "Failed to store compiled module");
+        }
+        Ok(module)
+    }
+
+    /// Compiles the codes missing in the cache, e.g. in the background after a restart.
+    pub fn warm<'a>(&self, engine: &WasmEngine, codes: impl IntoIterator<Item = &'a [u8]>) {
+        let mut n_compiled = 0;
+        for code in codes {
+            if self.contains(engine, &code_hash(code)) {
+                continue;
+            }
+            match self.compile(engine, code) {
+                Ok(_) => n_compiled += 1,
+                Err(err) => warn!(target: "sidevm", ?err, "Failed to compile module to warm up"),
+            }
+        }
+        info!(target: "sidevm", n_compiled, "Compile cache warmed up");
+    }
+}
+
+fn load(engine: &WasmEngine, path: &Path) -> Option<WasmModule> {
+    let content = std::fs::read(path).ok()?;
+    let artifact = match content
+        .strip_prefix(&MAGIC[..])
+        .filter(|rest| rest.len() >= 32)
+        .map(|rest| rest.split_at(32))
+    {
+        Some((checksum, artifact)) if checksum == Sha256::digest(artifact).as_slice() => artifact,
+        _ => {
+            warn!(target: "sidevm", path = %path.display(), "Corrupted compiled module removed");
+            let _ = std::fs::remove_file(path);
+            return None;
+        }
+    };
+    // Safety: the artifact was produced by the same runtime version and passed the checksum, and
+    // the cache directory is not writable by others.
+    match unsafe { engine.deserialize(artifact) } {
+        Ok(module) => Some(module),
+        Err(err) => {
+            warn!(target: "sidevm", ?err, path = %path.display(), "Failed to load compiled module");
+            let _ = std::fs::remove_file(path);
+            None
+        }
+    }
+}
+
+fn store(path: &Path, module: &Module) -> Result<()> {
+    let artifact = module.serialize()?;
+    let mut content = Vec::with_capacity(MAGIC.len() + 32 + artifact.len());
+    content.extend_from_slice(MAGIC);
+    content.extend_from_slice(&Sha256::digest(&artifact));
+    content.extend_from_slice(&artifact);
+    // Written aside and renamed, so that concurrent readers never see a partial file.
+    let tmp_path = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,524 @@
+//! Host side DNS resolution for the outbound connections of sidevm programs.
+//!
+//! - The resolver is either the system one or a DNS-over-HTTPS provider.
+//! - Resolved addresses are cached until their TTL expires.
+//! - The destinations a program can connect to can be restricted with a list of patterns, per
+//!   contract or for all contracts. A pattern is a domain (`example.com`), a domain with its
+//!   subdomains (`*.example.com`), an IP address or a CIDR range (`10.0.0.0/8`).
+//!
+//! The policy is evaluated on the resolved addresses rather than on the host name only:
+//! connecting to a raw IP is allowed only if it matches an IP pattern or the same instance
+//! resolved it from an allowed domain, and addresses in private ranges are dropped unless they
+//! match an IP pattern, so that neither a domain nor a raw IP can reach internal services.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use once_cell::sync::Lazy;
+use tracing::{debug, info};
+use trust_dns_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+
+use crate::{ShortId, VmId};
+
+const MAX_CACHE_ENTRIES: usize = 4096;
+const MAX_RESOLVED_PER_VM: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Resolver {
+    #[default]
+    System,
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+impl FromStr for Resolver {
+    type Err = anyhow::Error;
+
+    /// Parses `system` or `doh:<provider>`, where provider is `cloudflare`, `google` or `quad9`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "" | "system" => Self::System,
+            "doh:cloudflare" => Self::Cloudflare,
+            "doh:google" => Self::Google,
+            "doh:quad9" => Self::Quad9,
+            _ => anyhow::bail!("Unknown DNS resolver: {s}"),
+        })
+    }
+}
+
+impl fmt::Display for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => f.write_str("system"),
+            Self::Cloudflare => f.write_str("doh:cloudflare"),
+            Self::Google => f.write_str("doh:google"),
+            Self::Quad9 => f.write_str("doh:quad9"),
+        }
+    }
+}
+
+impl Resolver {
+    fn create(&self) -> io::Result<TokioAsyncResolver> {
+        let config = match self {
+            Self::System => {
+                return TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }
+            Self::Cloudflare => ResolverConfig::cloudflare_https(),
+            Self::Google => ResolverConfig::google_https(),
+            Self::Quad9 => ResolverConfig::quad9_https(),
+        };
+        Ok(TokioAsyncResolver::tokio(config, ResolverOpts::default()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pattern {
+    Domain { name: String, subdomains: bool },
+    Ip { addr: IpAddr, prefix: u8 },
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if let Some((addr, prefix)) = s.split_once('/') {
+            let addr: IpAddr = addr.parse()?;
+            let prefix: u8 = prefix.parse()?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            anyhow::ensure!(prefix <= max, "Invalid prefix length in {s}");
+            return Ok(Self::Ip { addr, prefix });
+        }
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            let prefix = if addr.is_ipv4() { 32 } else { 128 };
+            return Ok(Self::Ip { addr, prefix });
+        }
+        let (name, subdomains) = match s.strip_prefix("*.") {
+            Some(name) => (name, true),
+            None => (s.as_str(), false),
+        };
+        let name = name.trim_end_matches('.');
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains(['*', '/', ':']),
+            "Invalid domain pattern: {s}"
+        );
+        Ok(Self::Domain {
+            name: name.into(),
+            subdomains,
+        })
+    }
+}
+
+fn ip_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u32::from(addr) as u128,
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+impl Pattern {
+    fn matches_domain(&self, host: &str) -> bool {
+        let Self::Domain { name, subdomains } = self else {
+            return false;
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host == *name {
+            return true;
+        }
+        *subdomains
+            && host
+                .strip_suffix(name.as_str())
+                .map_or(false, |prefix| prefix.ends_with('.'))
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        let Self::Ip { addr, prefix } = self else {
+            return false;
+        };
+        if addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let shift = width - *prefix as u32;
+        if shift >= 128 {
+            return true;
+        }
+        (ip_bits(*addr) >> shift) == (ip_bits(ip) >> shift)
+    }
+}
+
+/// The destinations a sidevm program is allowed to connect to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DomainPolicy {
+    patterns: Vec<Pattern>,
+}
+
+impl DomainPolicy {
+    pub fn parse<S: AsRef<str>>(patterns: &[S]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| p.as_ref().parse())
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    fn allows_domain(&self, host: &str) -> bool {
+        self.patterns.iter().any(|p| p.matches_domain(host))
+    }
+
+    fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.patterns.iter().any(|p| p.matches_ip(ip))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DnsConfig {
+    pub resolver: Resolver,
+    /// The policy of the contracts without their own. `None` for unrestricted.
+    pub default_policy: Option<DomainPolicy>,
+    pub contract_policies: HashMap<VmId, DomainPolicy>,
+}
+
+impl DnsConfig {
+    fn policy_of(&self, id: &VmId) -> Option<&DomainPolicy> {
+        self.contract_policies
+            .get(id)
+            .or(self.default_policy.as_ref())
+    }
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    valid_until: Instant,
+}
+
+static CONFIG: Lazy<RwLock<Arc<DnsConfig>>> = Lazy::new(Default::default);
+static CACHE: Lazy<Mutex<HashMap<String, CacheEntry>>> = Lazy::new(Default::default);
+/// The addresses each instance resolved from the domains allowed by its policy, with their
+/// expiry. Kept per instance, so that an instance can not connect by IP to the addresses of the
+/// domains allowed to the others.
+static RESOLVED: Lazy<Mutex<HashMap<VmId, HashMap<IpAddr, Instant>>>> = Lazy::new(Default::default);
+
+/// Replaces the DNS config of all sidevm instances.
+pub fn set_config(config: DnsConfig) {
+    let mut current = CONFIG.write().unwrap();
+    if current.resolver != config.resolver {
+        CACHE.lock().unwrap().clear();
+    }
+    // The policies may have changed, the addresses resolved under the old ones are not trusted.
+    RESOLVED.lock().unwrap().clear();
+    info!(
+        resolver = %config.resolver,
+        restricted = config.default_policy.is_some(),
+        contract_policies = config.contract_policies.len(),
+        "Sidevm DNS config updated"
+    );
+    *current = Arc::new(config);
+}
+
+fn config() -> Arc<DnsConfig> {
+    CONFIG.read().unwrap().clone()
+}
+
+fn denied(id: &VmId, host: &str) -> io::Error {
+    info!(target: "sidevm", id = %ShortId(id), host, "Connection denied by the DNS policy");
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Connecting to {host} is not allowed"),
+    )
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7
+                || (segment & 0xfe00) == 0xfc00
+                // Link local, fe80::/10
+                || (segment & 0xffc0) == 0xfe80
+                || ip.to_ipv4_mapped().map_or(false, |ip| is_internal(ip.into()))
+        }
+    }
+}
+
+fn cached(host: &str) -> Option<(Vec<IpAddr>, Instant)> {
+    let cache = CACHE.lock().unwrap();
+    let entry = cache.get(host)?;
+    (entry.valid_until > Instant::now()).then(|| (entry.addrs.clone(), entry.valid_until))
+}
+
+fn cache(host: String, addrs: Vec<IpAddr>, valid_until: Instant) {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        let now = Instant::now();
+        cache.retain(|_, entry| entry.valid_until > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.clear();
+        }
+    }
+    cache.insert(host, CacheEntry { addrs, valid_until });
+}
+
+fn record_resolved(id: &VmId, addrs: &[IpAddr], valid_until: Instant) {
+    let now = Instant::now();
+    let mut resolved = RESOLVED.lock().unwrap();
+    if resolved.len() >= MAX_CACHE_ENTRIES {
+        resolved.retain(|_, ips| {
+            ips.retain(|_, until| *until > now);
+            !ips.is_empty()
+        });
+    }
+    let ips = resolved.entry(*id).or_default();
+    if ips.len() + addrs.len() > MAX_RESOLVED_PER_VM {
+        ips.retain(|_, until| *until > now);
+        if ips.len() + addrs.len() > MAX_RESOLVED_PER_VM {
+            ips.clear();
+        }
+    }
+    for ip in addrs {
+        ips.insert(*ip, valid_until);
+    }
+}
+
+/// Whether the instance resolved the ip from a domain allowed by its policy, and it did not
+/// expire yet.
+fn resolved_by(id: &VmId, ip: IpAddr) -> bool {
+    RESOLVED
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|ips| ips.get(&ip))
+        .map_or(false, |until| *until > Instant::now())
+}
+
+/// Whether the instance can connect to the raw ip.
+fn allows_literal_ip(id: &VmId, policy: &DomainPolicy, ip: IpAddr) -> bool {
+    policy.allows_ip(ip) || (!is_internal(ip) && resolved_by(id, ip))
+}
+
+async fn lookup(resolver: Resolver, host: &str) -> io::Result<(Vec<IpAddr>, Instant)> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(cached) = cached(&host) {
+        return Ok(cached);
+    }
+    let lookup = resolver
+        .create()?
+        .lookup_ip(host.as_str())
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let addrs: Vec<_> = lookup.iter().collect();
+    debug!(target: "sidevm", host, ?addrs, "DNS resolved");
+    let valid_until = lookup.valid_until();
+    cache(host, addrs.clone(), valid_until);
+    Ok((addrs, valid_until))
+}
+
+/// Checks the host against the policy of the instance, for connections whose names are resolved
+/// by a proxy.
+pub(crate) fn check_host(id: &VmId, host: &str) -> io::Result<()> {
+    let config = config();
+    let Some(policy) = config.policy_of(id) else {
+        return Ok(());
+    };
+    let allowed = match host.parse::<IpAddr>() {
+        Ok(ip) => allows_literal_ip(id, policy, ip),
+        Err(_) => policy.allows_domain(host),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(denied(id, host))
+    }
+}
+
+/// Resolves the host to the addresses the instance is allowed to connect to.
+pub(crate) async fn resolve(id: &VmId, host: &str) -> io::Result<Vec<IpAddr>> {
+    let config = config();
+    let policy = config.policy_of(id);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if let Some(policy) = policy {
+            if !allows_literal_ip(id, policy, ip) {
+                return Err(denied(id, host));
+            }
+        }
+        return Ok(vec![ip]);
+    }
+    if let Some(policy) = policy {
+        if !policy.allows_domain(host) {
+            return Err(denied(id, host));
+        }
+    }
+    let (mut addrs, valid_until) = lookup(config.resolver, host).await?;
+    if let Some(policy) = policy {
+        addrs.retain(|ip| !is_internal(*ip) || policy.allows_ip(*ip));
+        if addrs.is_empty() {
+            return Err(denied(id, host));
+        }
+        record_resolved(id, &addrs, valid_until);
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(
+            "*.Example.com.".parse::<Pattern>().unwrap(),
+            Pattern::Domain {
+                name: "example.com".into(),
+                subdomains: true
+            }
+        );
+        assert_eq!(
+            "10.0.0.0/8".parse::<Pattern>().unwrap(),
+            Pattern::Ip {
+                addr: ip("10.0.0.0"),
+                prefix: 8
+            }
+        );
+        assert_eq!(
+            "::1".parse::<Pattern>().unwrap(),
+            Pattern::Ip {
+                addr: ip("::1"),
+                prefix: 128
+            }
+        );
+        assert!("10.0.0.0/33".parse::<Pattern>().is_err());
+        assert!("*".parse::<Pattern>().is_err());
+        assert!("a*.example.com".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn policy_matches_domains_and_ips() {
+        let policy =
+            DomainPolicy::parse(&["example.com", "*.phala.network", "10.1.0.0/16"]).unwrap();
+        assert!(policy.allows_domain("example.com"));
+        assert!(policy.allows_domain("EXAMPLE.com."));
+        assert!(!policy.allows_domain("api.example.com"));
+        assert!(policy.allows_domain("api.phala.network"));
+        assert!(policy.allows_domain("phala.network"));
+        assert!(!policy.allows_domain("evilphala.network"));
+        assert!(policy.allows_ip(ip("10.1.2.3")));
+        assert!(!policy.allows_ip(ip("10.2.0.1")));
+        assert!(!policy.allows_ip(ip("::ffff:10.1.2.3")));
+        assert!(DomainPolicy::parse(&["0.0.0.0/0"])
+            .unwrap()
+            .allows_ip(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn internal_addresses() {
+        for addr in [
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(ip(addr)), "{addr}");
+        }
+        for addr in ["1.1.1.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_internal(ip(addr)), "{addr}");
+        }
+    }
+
+    #[test]
+    fn literal_ips_resolved_by_another_instance_are_denied() {
+        let vm_a = [0xa1; 32];
+        let vm_b = [0xb1; 32];
+        let policy = DomainPolicy::parse(&["example.com"]).unwrap();
+        let public = ip("93.184.216.34");
+        let valid_until = Instant::now() + Duration::from_secs(60);
+
+        assert!(!allows_literal_ip(&vm_a, &policy, public));
+        record_resolved(&vm_a, &[public], valid_until);
+        assert!(allows_literal_ip(&vm_a, &policy, public));
+        assert!(!allows_literal_ip(&vm_b, &policy, public));
+    }
+
+    #[test]
+    fn literal_internal_ips_need_an_ip_pattern() {
+        let vm = [0xa2; 32];
+        let internal = ip("10.0.0.5");
+        let valid_until = Instant::now() + Duration::from_secs(60);
+        record_resolved(&vm, &[internal], valid_until);
+
+        let policy = DomainPolicy::parse(&["example.com"]).unwrap();
+        assert!(!allows_literal_ip(&vm, &policy, internal));
+        let policy = DomainPolicy::parse(&["example.com", "10.0.0.0/24"]).unwrap();
+        assert!(allows_literal_ip(&vm, &policy, internal));
+    }
+
+    #[test]
+    fn resolved_addresses_expire() {
+        let vm = [0xa3; 32];
+        let policy = DomainPolicy::default();
+        let public = ip("1.1.1.1");
+        record_resolved(&vm, &[public], Instant::now());
+        assert!(!allows_literal_ip(&vm, &policy, public));
+    }
+
+    #[test]
+    fn resolved_addresses_are_bounded_per_instance() {
+        let vm = [0xa4; 32];
+        let valid_until = Instant::now() + Duration::from_secs(60);
+        let addrs: Vec<IpAddr> = (0..MAX_RESOLVED_PER_VM as u32 + 1)
+            .map(|i| IpAddr::V4((0x0800_0000 + i).into()))
+            .collect();
+        record_resolved(&vm, &addrs[..MAX_RESOLVED_PER_VM], valid_until);
+        record_resolved(&vm, &addrs[MAX_RESOLVED_PER_VM..], valid_until);
+        assert_eq!(RESOLVED.lock().unwrap()[&vm].len(), 1);
+        assert!(resolved_by(&vm, addrs[MAX_RESOLVED_PER_VM]));
+        assert!(!resolved_by(&vm, addrs[0]));
+    }
+
+    #[test]
+    fn cache_expires_entries() {
+        let host = "cache-test.example.com".to_string();
+        let addrs = vec![ip("1.2.3.4")];
+        cache(
+            host.clone(),
+            addrs.clone(),
+            Instant::now() + Duration::from_secs(60),
+        );
+        assert_eq!(cached(&host).unwrap().0, addrs);
+        cache(host.clone(), addrs, Instant::now());
+        assert!(cached(&host).is_none());
+    }
+}
@@ -39,6 +39,7 @@ use wasmer_middlewares::metering;
 
 use crate::{
     async_context::{get_task_cx, set_task_env, GuestWaker},
+    dns,
     resource::{Resource, ResourceKeeper, TcpListenerResource},
     tls::{load_tls_config, TlsStream},
     IncomingHttpRequest, VmId,
@@ -175,6 +176,11 @@ pub enum OutgoingRequest {
     },
     // Used by Js Engine to send js eval result
     Output(Vec<u8>),
+    /// Read entries of the chain storage with a proof.
+    ChainStorage {
+        keys: Vec<Vec<u8>>,
+        reply_tx: OneshotSender<Vec<u8>>,
+    },
 }
 
 struct VmMemory(Option<Memory>);
@@ -202,6 +208,17 @@ pub(crate) struct EnvInner {
     args: Vec<String>,
 }
 
+impl EnvInner {
+    /// Lets a log of the instance out, through the log handler if any, which decides where it
+    /// goes. Otherwise it goes to the log of the host.
+    fn emit_log(&self, level: log::Level, message: &str) {
+        match &self.log_handler {
+            Some(log_handler) => log_handler(self.id, level as u8, message),
+            None => log::log!(target: "sidevm", level, "{message}"),
+        }
+    }
+}
+
 impl VmMemory {
     pub(crate) fn unwrap_ref(&self) -> &Memory {
         self.0.as_ref().expect("memory is not initialized")
@@ -290,6 +307,10 @@ impl Env {
         }
     }
 
+    pub(crate) fn emit_log(&self, level: log::Level, message: &str) {
+        self.inner.lock().unwrap().emit_log(level, message)
+    }
+
     pub fn set_memory(&self, memory: Memory) {
         self.inner.lock().unwrap().memory.0 = Some(memory);
     }
@@ -563,7 +584,8 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             return Err(OcallError::InvalidParameter);
         }
         let host = host.to_owned();
-        let fut = async move { tcp_connect(&host, port).await };
+        let id = self.id;
+        let fut = async move { tcp_connect(&id, &host, port).await };
         self.resources.push(Resource::TcpConnect(Box::pin(fut)))
     }
 
@@ -576,8 +598,9 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             .as_str()
             .try_into()
             .or(Err(OcallError::InvalidParameter))?;
+        let id = self.id;
         let fut = async move {
-            tcp_connect(&host, port)
+            tcp_connect(&id, &host, port)
                 .await
                 .map(move |stream| TlsStream::connect(domain, stream))
         };
@@ -585,10 +608,7 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn log(&mut self, level: log::Level, message: &str) -> Result<()> {
-        log::log!(target: "sidevm", level, "{message}");
-        if let Some(log_handler) = &self.log_handler {
-            log_handler(self.id, level as u8, message);
-        }
+        self.emit_log(level, message);
         Ok(())
     }
 
@@ -672,6 +692,41 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn query_local_contract(&mut self, contract_id: [u8; 32], payload: Vec<u8>) -> Result<i32> {
+        self.send_outgoing_request(|reply_tx| OutgoingRequest::Query {
+            contract_id,
+            payload,
+            reply_tx,
+        })
+    }
+
+    /// Returns the vmid of the current instance.
+    fn vmid(&mut self) -> Result<[u8; 32]> {
+        Ok(self.id)
+    }
+
+    fn emit_program_output(&mut self, output: &[u8]) -> Result<()> {
+        let from = self.inner.id;
+        let request = OutgoingRequest::Output(output.to_vec());
+        self.inner
+            .outgoing_request_tx
+            .try_send((from, request))
+            .or(Err(OcallError::IoError))
+    }
+
+    fn query_chain_storage(&mut self, keys: Vec<Vec<u8>>) -> Result<i32> {
+        if keys.len() > env::messages::MAX_CHAIN_STORAGE_KEYS {
+            return Err(OcallError::InvalidParameter);
+        }
+        self.send_outgoing_request(|reply_tx| OutgoingRequest::ChainStorage { keys, reply_tx })
+    }
+}
+
+impl<'a, 'b> FnEnvMut<'a, &'b mut EnvInner> {
+    /// Sends a request to the host and returns the id of the channel receiving the reply.
+    fn send_outgoing_request(
+        &mut self,
+        make_request: impl FnOnce(OneshotSender<Vec<u8>>) -> OutgoingRequest,
+    ) -> Result<i32> {
         let sem = self
             .inner
             .outgoing_query_guard
@@ -681,11 +736,7 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
         let (res_tx, res_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
         let res_id = self.resources.push(Resource::ChannelRx(res_rx))?;
         let (reply_tx, reply_rx) = oneshot::channel();
-        let request = OutgoingRequest::Query {
-            contract_id,
-            payload,
-            reply_tx,
-        };
+        let request = make_request(reply_tx);
         let from = self.inner.id;
         self.inner
             .outgoing_request_tx
@@ -706,20 +757,6 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
         });
         Ok(res_id)
     }
-
-    /// Returns the vmid of the current instance.
-    fn vmid(&mut self) -> Result<[u8; 32]> {
-        Ok(self.id)
-    }
-
-    fn emit_program_output(&mut self, output: &[u8]) -> Result<()> {
-        let from = self.inner.id;
-        let request = OutgoingRequest::Output(output.to_vec());
-        self.inner
-            .outgoing_request_tx
-            .try_send((from, request))
-            .or(Err(OcallError::IoError))
-    }
 }
 
 impl EnvInner {
@@ -768,11 +805,7 @@ impl EnvInner {
     }
 }
 
-fn is_ip(host: &str) -> bool {
-    host.parse::<std::net::IpAddr>().is_ok()
-}
-
-async fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
+async fn tcp_connect(id: &VmId, host: &str, port: u16) -> io::Result<TcpStream> {
     fn get_proxy(key: &str) -> Option<String> {
         std::env::var(key).ok().and_then(|uri| {
             if uri.trim().is_empty() {
@@ -790,19 +823,13 @@ async fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
     };
 
     if let Some(proxy_url) = proxy_url.or_else(|| get_proxy("all_proxy")) {
+        dns::check_host(id, host)?;
         phala_tokio_proxy::connect((host, port), proxy_url).await
-    } else if is_ip(host) {
-        TcpStream::connect((host, port)).await
     } else {
         // By default, tokio uses the blocking DNS resovler from libc and run them in a thread pool.
         // That would cause problem such as run out of thread-pool in some poor network situation.
         // So, we use trust-dns async resolver here.
-        let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let ips = resolver
-            .lookup_ip(host)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let ips = dns::resolve(id, host).await?;
         let mut last_err = None;
         for ip in ips {
             match TcpStream::connect((ip, port)).await {
@@ -2,7 +2,6 @@ use super::{Env as WasiEnv, Result};
 use libc::{clock_getres, clock_gettime, timespec, CLOCK_MONOTONIC, CLOCK_REALTIME};
 use sidevm_env::{OcallError, OcallFuncs};
 use thiserror::Error;
-use tracing::{error, info};
 use wasmer::{
     namespace, AsStoreMut, Exports, Function, FunctionEnv, FunctionEnvMut, Memory32,
     MemoryAccessError, WasmPtr,
@@ -460,12 +459,13 @@ fn fd_write_stdio(
                 .map_err(mem_error_to_wasi)?
         };
         let bytes = buf.as_ref();
+        let level = if fd == 2 {
+            log::Level::Error
+        } else {
+            log::Level::Info
+        };
         for line in String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).lines() {
-            if fd == 2 {
-                error!(target: "sidevm", "{}", line);
-            } else {
-                info!(target: "sidevm", "{}", line);
-            }
+            env.emit_log(level, line);
         }
         written += buf.len();
     }
@@ -1,4 +1,6 @@
 mod async_context;
+pub mod compile_cache;
+pub mod dns;
 mod env;
 mod metering;
 mod resource;
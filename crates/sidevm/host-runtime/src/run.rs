@@ -23,6 +23,7 @@ pub struct WasmModule {
 #[derive(Clone)]
 pub struct WasmEngine {
     inner: Engine,
+    compiler: String,
 }
 
 impl Default for WasmEngine {
@@ -47,7 +48,15 @@ impl WasmEngine {
             _ => panic!("Unsupported compiler engine: {compiler_env}"),
         };
         engine.set_tunables(dym_mem_tunables());
-        Self { inner: engine }
+        Self {
+            inner: engine,
+            compiler: compiler_env.into(),
+        }
+    }
+
+    /// The name of the compiler the modules are compiled with.
+    pub fn compiler(&self) -> &str {
+        &self.compiler
     }
 
     pub fn compile(&self, wasm_code: &[u8]) -> Result<WasmModule> {
@@ -56,6 +65,18 @@ impl WasmEngine {
             module: Module::new(&self.inner, wasm_code)?,
         })
     }
+
+    /// Loads a module serialized by [`Module::serialize`].
+    ///
+    /// # Safety
+    ///
+    /// The artifact is loaded as native code, it must come from a trusted source.
+    pub(crate) unsafe fn deserialize(&self, artifact: &[u8]) -> Result<WasmModule> {
+        Ok(WasmModule {
+            engine: self.clone(),
+            module: Module::deserialize(&self.inner, artifact.to_vec())?,
+        })
+    }
 }
 
 fn dym_mem_tunables() -> BaseTunables {
@@ -68,6 +89,10 @@ fn dym_mem_tunables() -> BaseTunables {
 }
 
 impl WasmModule {
+    pub(crate) fn inner(&self) -> &Module {
+        &self.module
+    }
+
     pub fn run(
         &self,
         args: Vec<String>,
@@ -1,4 +1,5 @@
-use crate::env::{DynCacheOps, OcallAborted};
+use crate::compile_cache::CompileCache;
+use crate::env::{DynCacheOps, LogHandler, OcallAborted};
 use crate::run::{WasmEngine, WasmInstanceConfig};
 use crate::{ShortId, VmId};
 use anyhow::Result;
@@ -6,6 +7,7 @@ use phala_scheduler::TaskScheduler;
 use serde::{Deserialize, Serialize};
 use sidevm_env::messages::{AccountId, HttpHead, HttpResponseHead};
 use std::future::Future;
+use std::sync::{Arc, RwLock};
 use tokio::io::DuplexStream;
 use tokio::{
     sync::mpsc::{channel, Receiver, Sender},
@@ -17,6 +19,8 @@ use tracing::{debug, error, info, trace, warn, Instrument};
 
 pub use sidevm_env::messages::{Metric, SystemMessage};
 pub type CommandSender = Sender<Command>;
+/// Decides whether a log of an instance at the given level, as in `log::Level`, is let out.
+pub type LogFilter = Box<dyn Fn(&VmId, u8) -> bool + Send + Sync>;
 
 #[derive(Debug)]
 pub enum Report {
@@ -83,6 +87,8 @@ pub struct Spawner {
     report_tx: Sender<Report>,
     out_tx: crate::OutgoingRequestChannel,
     scheduler: TaskScheduler<VmId>,
+    compile_cache: Option<Arc<CompileCache>>,
+    log_filter: Arc<RwLock<Option<LogFilter>>>,
 }
 
 pub fn service(
@@ -107,6 +113,8 @@ pub fn service(
         report_tx,
         out_tx,
         scheduler: TaskScheduler::new(worker_threads as _),
+        compile_cache: None,
+        log_filter: Default::default(),
     };
     (run, spawner)
 }
@@ -137,6 +145,47 @@ impl ServiceRun {
 }
 
 impl Spawner {
+    /// Keeps the compiled modules in `cache` for the instances started afterwards.
+    pub fn set_compile_cache(&mut self, cache: CompileCache) {
+        self.compile_cache = Some(Arc::new(cache));
+    }
+
+    /// Filters the logs of the instances, including the running ones. Without a filter, all the
+    /// logs go to the log of the host.
+    pub fn set_log_filter(&self, filter: LogFilter) {
+        *self.log_filter.write().unwrap() = Some(filter);
+    }
+
+    fn log_handler(&self) -> LogHandler {
+        let log_filter = self.log_filter.clone();
+        Box::new(move |id, level, message| {
+            if let Some(filter) = &*log_filter.read().unwrap() {
+                if !filter(&id, level) {
+                    return;
+                }
+            }
+            let level = match level {
+                1 => log::Level::Error,
+                2 => log::Level::Warn,
+                3 => log::Level::Info,
+                4 => log::Level::Debug,
+                _ => log::Level::Trace,
+            };
+            log::log!(target: "sidevm", level, "{message}");
+        })
+    }
+
+    /// Compiles the given codes into the compile cache in the background, if any.
+    pub fn warm_compile_cache(&self, codes: Vec<Vec<u8>>) {
+        let Some(cache) = self.compile_cache.clone() else {
+            return;
+        };
+        self.runtime_handle.spawn_blocking(move || {
+            let engine = WasmEngine::new();
+            cache.warm(&engine, codes.iter().map(|code| &code[..]));
+        });
+    }
+
     #[tracing::instrument(parent=None, name="sidevm", fields(id = %ShortId(id)), skip_all)]
     #[allow(clippy::too_many_arguments)]
     pub fn start(
@@ -153,6 +202,8 @@ impl Spawner {
         let (cmd_tx, mut cmd_rx) = channel(128);
         let spawner = self.runtime_handle.clone();
         let scheduler = self.scheduler.clone();
+        let compile_cache = self.compile_cache.clone();
+        let log_handler = self.log_handler();
         let wasm_bytes = wasm_bytes.to_vec();
         let handle = self.spawn(async move {
             macro_rules! push_msg {
@@ -218,7 +269,11 @@ impl Spawner {
             }
             info!(target: "sidevm", "Starting sidevm instance...");
             let engine = WasmEngine::new();
-            let module = match engine.compile(&wasm_bytes) {
+            let module = match &compile_cache {
+                Some(cache) => cache.compile(&engine, &wasm_bytes),
+                None => engine.compile(&wasm_bytes),
+            };
+            let module = match module {
                 Ok(m) => m,
                 Err(err) => {
                     error!(target: "sidevm", ?err, "Failed to compile wasm module");
@@ -234,7 +289,7 @@ impl Spawner {
                 scheduler: Some(scheduler),
                 weight,
                 event_tx,
-                log_handler: None,
+                log_handler: Some(log_handler),
             };
             let (mut wasm_run, env) = match module.run(vec![], config) {
                 Ok(i) => i,
@@ -315,6 +315,10 @@ pub async fn serve(args: Args) -> anyhow::Result<()> {
                 OutgoingRequest::Output(output) => {
                     info!(%vmid, "Outgoing message: {output:?}");
                 }
+                OutgoingRequest::ChainStorage { keys, reply_tx } => {
+                    info!(%vmid, "Chain storage request for {} keys", keys.len());
+                    _ = reply_tx.send(Vec::new());
+                }
             }
         }
     });
@@ -0,0 +1,34 @@
+//! Support for reading the parachain storage
+
+use scale::Decode;
+use sidevm_env::messages::{ChainStorageResponse, QueryError};
+
+use crate::{channel, ocall};
+
+/// Read the values of `keys` from the parachain storage.
+///
+/// The values are read from the state of the latest block dispatched by the worker, which has
+/// been validated against the finalized headers of the chain. The response carries the state root
+/// of the block and a storage proof of the values, so that they can be checked independently of
+/// the worker.
+///
+/// # Limitation
+///
+/// Shares the limit of one outstanding request with [`crate::local_contract::query_pink`]. A
+/// second simultaneous request will be rejected with error `OcallError::ResourceLimited`.
+///
+/// At most [`MAX_CHAIN_STORAGE_KEYS`](sidevm_env::messages::MAX_CHAIN_STORAGE_KEYS) keys can be
+/// read at once, more are rejected with `OcallError::InvalidParameter`. A response whose values
+/// and proof exceed
+/// [`MAX_CHAIN_STORAGE_RESPONSE_SIZE`](sidevm_env::messages::MAX_CHAIN_STORAGE_RESPONSE_SIZE)
+/// bytes fails with `QueryError::OtherError`.
+pub async fn read_storage(keys: Vec<Vec<u8>>) -> Result<ChainStorageResponse, QueryError> {
+    let res = ocall::query_chain_storage(keys)?;
+    let rx = channel::Receiver::<Vec<u8>>::new(res.into());
+
+    let Some(reply) = rx.next().await else {
+        return Err(sidevm_env::OcallError::EndOfFile.into());
+    };
+    Result::<ChainStorageResponse, QueryError>::decode(&mut &reply[..])
+        .map_err(|_| QueryError::DecodeError)?
+}
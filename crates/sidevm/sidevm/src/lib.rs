@@ -11,6 +11,7 @@ pub use sidevm_macro::main;
 pub use env::spawn;
 pub use env::tasks as task;
 
+pub mod chain_storage;
 pub mod channel;
 pub mod exec;
 pub mod net;
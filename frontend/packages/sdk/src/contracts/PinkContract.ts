@@ -9,7 +9,7 @@ import type { ContractCallResult, ContractCallSend, MessageMeta } from '@polkado
 import { convertWeight, withMeta } from '@polkadot/api-contract/base/util'
 import type { AbiMessage, ContractCallOutcome, ContractOptions, DecodedEvent } from '@polkadot/api-contract/types'
 import { applyOnEvent } from '@polkadot/api-contract/util'
-import type { Bytes, Null, Result, Struct, Text, Vec, u8 } from '@polkadot/types'
+import type { Bytes, Null, Result, Struct, Text, Vec, u8, u64 } from '@polkadot/types'
 import type { AccountId, ContractExecResult, EventRecord } from '@polkadot/types/interfaces'
 import type { Codec, IEnum, IKeyringPair, ISubmittableResult, Registry } from '@polkadot/types/types'
 import { BN, BN_ZERO, hexAddPrefix, hexToU8a, isHex } from '@polkadot/util'
@@ -221,6 +221,9 @@ interface InkQueryError extends IEnum {
 
   readonly isTimeout: boolean
   readonly asTimeout: Null
+
+  readonly isOverloaded: boolean
+  readonly asOverloaded: Struct & { readonly retryAfterMs: u64 }
 }
 
 interface InkResponse extends Struct {
@@ -56,6 +56,7 @@ export const types = {
       NoResponse: null,
       ServiceUnavailable: null,
       Timeout: null,
+      Overloaded: { retryAfterMs: 'u64' },
     },
   },
   InkQueryOk: {
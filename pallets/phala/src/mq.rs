@@ -15,10 +15,14 @@ pub mod pallet {
 	use phala_types::contract::{command_topic, InkCommand};
 	use phala_types::messaging::ContractId;
 	use phala_types::messaging::{
-		BindTopic, CommandPayload, ContractCommand, Message, MessageOrigin, Path, SignedMessage,
+		compression, BindTopic, CommandPayload, ContractCommand, DecodedMessage, Message,
+		MessageOrigin, MessageReceipts, MqUsageReport, Path, SenderUsage, SignedMessage,
+		MAX_USAGE_REPORT_SENDERS, MQ_USAGE_WINDOW,
 	};
+	use phala_types::WorkerPublicKey;
 	use primitive_types::H256;
-	use sp_std::vec::Vec;
+	use sp_runtime::traits::UniqueSaturatedInto;
+	use sp_std::{borrow::Cow, vec::Vec};
 
 	#[pallet::config]
 	pub trait Config: frame_system::Config + crate::registry::Config {
@@ -47,11 +51,43 @@ pub mod pallet {
 	#[pallet::getter(fn messages)]
 	pub type OutboundMessages<T> = StorageValue<_, Vec<Message>, ValueQuery>;
 
+	/// The maximum number of senders whose egress traffic reports are kept
+	pub const MAX_USAGE_RECORDS: u32 = 10_000;
+
+	/// The weight of unpacking a byte of the payloads sent to the compressed topics, a few times
+	/// the time it takes natively
+	pub const UNPACK_WEIGHT_PER_BYTE: u64 = 50_000;
+
+	/// The latest egress traffic report of a sender
+	#[pallet::storage]
+	pub type SenderUsageReports<T> = CountedStorageMap<_, Twox64Concat, MessageOrigin, UsageRecord>;
+
+	/// Whether to acknowledge the accepted offchain messages with a `MessageReceipts` message at
+	/// the end of each block.
+	#[pallet::storage]
+	pub type AckReceiptsEnabled<T> = StorageValue<_, bool, ValueQuery>;
+
+	/// The next sequence of the senders whose offchain messages were accepted in the current
+	/// block, to acknowledge at the end of the block.
+	#[pallet::storage]
+	pub type PendingReceipts<T> = StorageMap<_, Twox64Concat, MessageOrigin, u64>;
+
+	#[derive(Encode, Decode, TypeInfo, Clone, PartialEq, Eq, RuntimeDebug)]
+	pub struct UsageRecord {
+		/// The worker which reported the usage.
+		pub reporter: WorkerPublicKey,
+		/// The last block of the accounting window.
+		pub window_end: u32,
+		pub usage: SenderUsage,
+	}
+
 	#[pallet::error]
 	pub enum Error<T> {
 		BadSender,
 		BadSequence,
 		BadDestination,
+		BadUsageWindow,
+		TooManyUsageSenders,
 	}
 
 	#[pallet::call]
@@ -61,8 +97,17 @@ pub mod pallet {
 		T::AccountId: IntoH256,
 	{
 		/// Syncs an unverified offchain message to the message queue
+		///
+		/// The weight covers the removal of the pending receipt queued by the message in
+		/// `on_finalize` as well, the unpacking of the payloads sent to the compressed topics and
+		/// the handling of the entries of the messages whose pallet handler loops over them, see
+		/// `handler_weight`.
 		#[pallet::call_index(0)]
-		#[pallet::weight(Weight::from_parts(10_000u64, 0) + T::DbWeight::get().writes(1u64))]
+		#[pallet::weight(
+			Weight::from_parts(10_000u64, 0)
+				+ T::DbWeight::get().reads_writes(2u64, 3u64)
+				+ Pallet::<T>::handler_weight(&signed_message.message)
+		)]
 		pub fn sync_offchain_message(
 			origin: OriginFor<T>,
 			signed_message: SignedMessage,
@@ -89,6 +134,9 @@ pub mod pallet {
 			crate::registry::Pallet::<T>::check_message(&signed_message)?;
 			// Update ingress
 			OffchainIngress::<T>::insert(sender.clone(), expected_seq + 1);
+			if AckReceiptsEnabled::<T>::get() {
+				PendingReceipts::<T>::insert(sender.clone(), expected_seq + 1);
+			}
 			// Call dispatch_message
 			Self::dispatch_message(signed_message.message);
 			Ok(())
@@ -105,7 +153,7 @@ pub mod pallet {
 		) -> DispatchResult {
 			let origin = ensure_signed(origin)?;
 			let sender = MessageOrigin::AccountId(origin.into_h256());
-			let message = Message::new(sender, destination, payload);
+			let message = Self::new_message(sender, destination, payload);
 			Self::dispatch_message(message);
 			Ok(())
 		}
@@ -120,18 +168,34 @@ pub mod pallet {
 		) -> DispatchResult {
 			ensure_root(origin)?;
 			let sender = MessageOrigin::Pallet(b"ForcePushed".to_vec());
-			let message = Message::new(sender, destination, payload);
+			let message = Self::new_message(sender, destination, payload);
 			Self::dispatch_message(message);
 			Ok(())
 		}
+
+		/// Enables or disables the receipts of the accepted offchain messages.
+		///
+		/// The receipts queued in the current block are dropped in `on_finalize` once disabled,
+		/// their removal being charged to the `sync_offchain_message` calls which queued them.
+		///
+		/// Can only be called by root.
+		#[pallet::call_index(3)]
+		#[pallet::weight(Weight::from_parts(10_000u64, 0) + T::DbWeight::get().writes(1u64))]
+		pub fn set_ack_receipts(origin: OriginFor<T>, enabled: bool) -> DispatchResult {
+			ensure_root(origin)?;
+			AckReceiptsEnabled::<T>::put(enabled);
+			Ok(())
+		}
 	}
 
 	impl<T: Config> Pallet<T> {
 		/// Push a validated message to the queue
 		pub fn dispatch_message(message: Message) {
-			// Notify subscribers
-			if let Err(_err) = T::QueueNotifyConfig::on_message_received(&message) {
-				// TODO: Consider to emit a message as warning. We can't stop dispatching message in any situation.
+			// Notify subscribers. A malformed compressed payload is dropped by the workers as well.
+			if let Some(unpacked) = Self::unpacked(&message) {
+				if let Err(_err) = T::QueueNotifyConfig::on_message_received(&unpacked) {
+					// TODO: Consider to emit a message as warning. We can't stop dispatching message in any situation.
+				}
 			}
 			// Notify the off-chain components
 			if T::QueueNotifyConfig::should_push_message(&message) {
@@ -139,12 +203,38 @@ pub mod pallet {
 			}
 		}
 
+		/// The message as the pallet handlers see it, with the payload sent to a compressed topic
+		/// unpacked. None if the payload is malformed.
+		pub fn unpacked(message: &Message) -> Option<Cow<Message>> {
+			if !message.destination.is_compressed() {
+				return Some(Cow::Borrowed(message));
+			}
+			let payload = compression::unpack(&message.payload).ok()?;
+			Some(Cow::Owned(Message {
+				sender: message.sender.clone(),
+				destination: message.destination.clone(),
+				payload,
+			}))
+		}
+
+		/// Creates a message, packing the payload if the topic is compressed.
+		///
+		/// The payloads are packed raw, the output of the runtime must not depend on whether the
+		/// native build links the zstd library.
+		fn new_message(sender: MessageOrigin, topic: impl Into<Path>, payload: Vec<u8>) -> Message {
+			let mut message = Message::new(sender, topic, payload);
+			if message.destination.is_compressed() {
+				message.payload = compression::pack_raw(message.payload);
+			}
+			message
+		}
+
 		pub fn push_message_to<M: Encode>(
 			topic: impl Into<Path>,
 			sender: MessageOrigin,
 			payload: M,
 		) {
-			let message = Message::new(sender, topic, payload.encode());
+			let message = Self::new_message(sender, topic, payload.encode());
 			Self::dispatch_message(message);
 		}
 
@@ -153,13 +243,124 @@ pub mod pallet {
 		}
 
 		pub fn queue_bound_message<M: Encode + BindTopic>(sender: MessageOrigin, payload: M) {
-			let message = Message::new(sender, M::topic(), payload.encode());
+			let message = Self::new_message(sender, M::topic(), payload.encode());
 			QueuedOutboundMessage::<T>::append(message);
 		}
 
 		pub fn offchain_ingress(sender: &MessageOrigin) -> Option<u64> {
 			OffchainIngress::<T>::get(sender)
 		}
+
+		/// The weight of handling the message in the pallets, on top of the fixed weight of
+		/// `sync_offchain_message`, charged per byte of the unpacked payloads of the compressed
+		/// topics and per entry of the messages with a variable number of entries.
+		pub fn handler_weight(message: &Message) -> Weight {
+			Self::unpack_weight(message).saturating_add(Self::entries_weight(message))
+		}
+
+		/// The weight of unpacking the payload of a compressed topic. The size the payload declares
+		/// is checked while unpacking, a payload which does not declare it is charged the largest
+		/// size.
+		fn unpack_weight(message: &Message) -> Weight {
+			if !message.destination.is_compressed() {
+				return Weight::zero();
+			}
+			let len = compression::unpacked_len(&message.payload)
+				.unwrap_or(compression::MAX_DECOMPRESSED_SIZE)
+				.min(compression::MAX_DECOMPRESSED_SIZE);
+			Weight::from_parts(UNPACK_WEIGHT_PER_BYTE.saturating_mul(len as u64), 0)
+		}
+
+		fn entries_weight(message: &Message) -> Weight {
+			match Self::usage_report_senders(message) {
+				// The registration of the reporter, then for each sender whether the reporter
+				// hosts it, its previous record and the number of records, and the new record.
+				Some(senders) => {
+					T::DbWeight::get().reads(1)
+						+ T::DbWeight::get()
+							.reads_writes(5, 2)
+							.saturating_mul(senders as u64)
+				}
+				None => Weight::zero(),
+			}
+		}
+
+		/// The number of senders handled for an `MqUsageReport` message, none for the other
+		/// messages. Reports with more senders than the limit are rejected before any of them is
+		/// handled, so the count is capped.
+		fn usage_report_senders(message: &Message) -> Option<u32> {
+			if message.destination.path() != &MqUsageReport::topic() {
+				return None;
+			}
+			// The senders follow the `window_end` of the report.
+			let senders = message
+				.payload
+				.get(4..)
+				.and_then(|mut senders| codec::Compact::<u32>::decode(&mut senders).ok())
+				.map(|senders| senders.0.min(MAX_USAGE_REPORT_SENDERS))
+				.unwrap_or(0);
+			Some(senders)
+		}
+
+		/// Whether the worker hosts the sender, i.e. may report its traffic.
+		fn hosts_sender(worker: &WorkerPublicKey, sender: &MessageOrigin) -> bool {
+			match sender {
+				MessageOrigin::Worker(pubkey) => pubkey == worker,
+				MessageOrigin::Gatekeeper => {
+					crate::registry::Gatekeeper::<T>::get().contains(worker)
+				}
+				_ => T::QueueNotifyConfig::is_hosted_by(sender, worker),
+			}
+		}
+
+		pub fn on_usage_report_received(message: DecodedMessage<MqUsageReport>) -> DispatchResult {
+			let MessageOrigin::Worker(reporter) = message.sender else {
+				return Err(Error::<T>::BadSender.into());
+			};
+			ensure!(
+				crate::registry::Workers::<T>::contains_key(reporter),
+				Error::<T>::BadSender
+			);
+			let report = message.payload;
+			ensure!(
+				report.senders.len() <= MAX_USAGE_REPORT_SENDERS as usize,
+				Error::<T>::TooManyUsageSenders
+			);
+			let now: u32 = frame_system::Pallet::<T>::block_number().unique_saturated_into();
+			ensure!(
+				report.window_end != 0
+					&& report.window_end <= now
+					&& report.window_end % MQ_USAGE_WINDOW == 0,
+				Error::<T>::BadUsageWindow
+			);
+			for (sender, usage) in report.senders {
+				if !Self::hosts_sender(&reporter, &sender) {
+					continue;
+				}
+				// The workers of a cluster report the same senders, and workers catching up report
+				// the past windows. Keep the first report of the latest window.
+				let outdated = SenderUsageReports::<T>::get(&sender)
+					.map(|record| record.window_end >= report.window_end)
+					.unwrap_or(false);
+				if outdated {
+					continue;
+				}
+				if !SenderUsageReports::<T>::contains_key(&sender)
+					&& SenderUsageReports::<T>::count() >= MAX_USAGE_RECORDS
+				{
+					continue;
+				}
+				SenderUsageReports::<T>::insert(
+					sender,
+					UsageRecord {
+						reporter,
+						window_end: report.window_end,
+						usage,
+					},
+				);
+			}
+			Ok(())
+		}
 	}
 
 	#[pallet::hooks]
@@ -175,10 +376,30 @@ pub mod pallet {
 				}
 			}
 
-			Weight::zero()
+			// Reserve the weight of acknowledging the receipts in `on_finalize`. The removal of each
+			// pending receipt is charged to the `sync_offchain_message` call which queued it.
+			let mut weight = T::DbWeight::get().reads(1);
+			if AckReceiptsEnabled::<T>::get() {
+				weight += T::DbWeight::get().reads_writes(2, 1);
+			}
+			weight
+		}
+
+		fn on_finalize(now: BlockNumberFor<T>) {
+			// Acknowledge the offchain messages accepted in this block in a single message
+			let receipts: Vec<_> = PendingReceipts::<T>::drain().collect();
+			if receipts.is_empty() || !AckReceiptsEnabled::<T>::get() {
+				return;
+			}
+			let block: u32 = now.unique_saturated_into();
+			<Self as MessageOriginInfo>::push_message(MessageReceipts { block, receipts });
 		}
 	}
 
+	impl<T: Config> MessageOriginInfo for Pallet<T> {
+		type Config = T;
+	}
+
 	/// Defines the behavior of received messages.
 	pub trait QueueNotifyConfig {
 		/// If true, the message queue push this message to the subscribers
@@ -189,6 +410,11 @@ pub mod pallet {
 		fn on_message_received(_message: &Message) -> DispatchResult {
 			Ok(())
 		}
+		/// Whether the worker hosts the contract or cluster sender, allowing it to report its
+		/// egress traffic
+		fn is_hosted_by(_sender: &MessageOrigin, _worker: &WorkerPublicKey) -> bool {
+			false
+		}
 	}
 	impl QueueNotifyConfig for () {}
 
@@ -286,6 +512,339 @@ pub mod pallet {
 			Pallet::<Self::Config>::queue_bound_message(Self::message_origin(), payload);
 		}
 	}
+
+	#[cfg(test)]
+	mod test {
+		use frame_support::{assert_noop, assert_ok};
+
+		use super::*;
+		use crate::mock::{
+			new_test_ext, set_block_1, setup_workers, worker_pubkey, RuntimeOrigin, System, Test,
+		};
+		// Pallets
+		use crate::mock::PhalaMq;
+
+		fn usage(messages: u32) -> SenderUsage {
+			SenderUsage {
+				messages,
+				bytes: messages as u64 * 10,
+				topics: Default::default(),
+			}
+		}
+
+		fn report(
+			reporter: WorkerPublicKey,
+			window_end: u32,
+			senders: Vec<(MessageOrigin, SenderUsage)>,
+		) -> DispatchResult {
+			PhalaMq::on_usage_report_received(DecodedMessage {
+				sender: MessageOrigin::Worker(reporter),
+				destination: MqUsageReport::topic().into(),
+				payload: MqUsageReport {
+					window_end,
+					senders,
+				},
+			})
+		}
+
+		fn setup() {
+			set_block_1();
+			setup_workers(2);
+			System::set_block_number(MQ_USAGE_WINDOW as _);
+		}
+
+		#[test]
+		fn test_usage_report_from_unregistered_worker() {
+			new_test_ext().execute_with(|| {
+				setup();
+				let unregistered = worker_pubkey(9);
+				assert_noop!(
+					report(
+						unregistered,
+						MQ_USAGE_WINDOW,
+						vec![(MessageOrigin::Worker(unregistered), usage(1))]
+					),
+					Error::<Test>::BadSender
+				);
+			});
+		}
+
+		#[test]
+		fn test_usage_report_window() {
+			new_test_ext().execute_with(|| {
+				setup();
+				let worker = worker_pubkey(1);
+				let senders = vec![(MessageOrigin::Worker(worker), usage(1))];
+				for window_end in [0, MQ_USAGE_WINDOW - 1, MQ_USAGE_WINDOW * 2, u32::MAX] {
+					assert_noop!(
+						report(worker, window_end, senders.clone()),
+						Error::<Test>::BadUsageWindow
+					);
+				}
+				assert_ok!(report(worker, MQ_USAGE_WINDOW, senders));
+				assert_eq!(
+					SenderUsageReports::<Test>::get(MessageOrigin::Worker(worker))
+						.unwrap()
+						.window_end,
+					MQ_USAGE_WINDOW
+				);
+			});
+		}
+
+		#[test]
+		fn test_usage_report_only_hosted_senders() {
+			new_test_ext().execute_with(|| {
+				setup();
+				let worker = worker_pubkey(1);
+				let other = MessageOrigin::Worker(worker_pubkey(2));
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW,
+					vec![
+						(MessageOrigin::Worker(worker), usage(1)),
+						(other.clone(), usage(2)),
+						(MessageOrigin::Gatekeeper, usage(3)),
+						(MessageOrigin::Cluster(Default::default()), usage(4)),
+					]
+				));
+				assert!(SenderUsageReports::<Test>::contains_key(
+					MessageOrigin::Worker(worker)
+				));
+				assert!(!SenderUsageReports::<Test>::contains_key(&other));
+				assert!(!SenderUsageReports::<Test>::contains_key(
+					MessageOrigin::Gatekeeper
+				));
+				assert_eq!(SenderUsageReports::<Test>::count(), 1);
+
+				// The genesis worker is a gatekeeper.
+				let gatekeeper = sp_core::sr25519::Public::from_raw([0u8; 32]);
+				assert_ok!(report(
+					gatekeeper,
+					MQ_USAGE_WINDOW,
+					vec![(MessageOrigin::Gatekeeper, usage(3))]
+				));
+				assert_eq!(
+					SenderUsageReports::<Test>::get(MessageOrigin::Gatekeeper)
+						.unwrap()
+						.reporter,
+					gatekeeper
+				);
+			});
+		}
+
+		#[test]
+		fn test_usage_report_is_bounded() {
+			new_test_ext().execute_with(|| {
+				setup();
+				let worker = worker_pubkey(1);
+				let senders = |count: u32| -> Vec<_> {
+					(0..count)
+						.map(|_| (MessageOrigin::Worker(worker), usage(1)))
+						.collect()
+				};
+				assert_noop!(
+					report(
+						worker,
+						MQ_USAGE_WINDOW,
+						senders(MAX_USAGE_REPORT_SENDERS + 1)
+					),
+					Error::<Test>::TooManyUsageSenders
+				);
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW,
+					senders(MAX_USAGE_REPORT_SENDERS)
+				));
+			});
+		}
+
+		#[test]
+		fn test_usage_report_weight_per_sender() {
+			new_test_ext().execute_with(|| {
+				let message = |count: u32| {
+					let senders = (0..count)
+						.map(|_| (MessageOrigin::Gatekeeper, usage(1)))
+						.collect();
+					Message::new(
+						MessageOrigin::Worker(worker_pubkey(1)),
+						MqUsageReport::topic(),
+						MqUsageReport {
+							window_end: MQ_USAGE_WINDOW,
+							senders,
+						}
+						.encode(),
+					)
+				};
+				let senders = |count| PhalaMq::usage_report_senders(&message(count));
+				assert_eq!(senders(0), Some(0));
+				assert_eq!(senders(3), Some(3));
+				// Charged up to the limit, the reports above it are rejected.
+				assert_eq!(
+					senders(MAX_USAGE_REPORT_SENDERS + 1),
+					Some(MAX_USAGE_REPORT_SENDERS)
+				);
+				let other = Message::new(
+					MessageOrigin::Worker(worker_pubkey(1)),
+					b"phala/mining/report".to_vec(),
+					vec![0; 64],
+				);
+				assert_eq!(PhalaMq::usage_report_senders(&other), None);
+				assert_eq!(PhalaMq::handler_weight(&other), Weight::zero());
+			});
+		}
+
+		#[test]
+		fn test_compressed_topics() {
+			new_test_ext().execute_with(|| {
+				let topic = phala_types::messaging::Topic::compressed(*b"phala/test");
+				PhalaMq::push_message_to(topic.clone(), MessageOrigin::Gatekeeper, 42u32);
+				// Packed raw on chain, unpacked for the pallet handlers.
+				let message = PhalaMq::messages().pop().unwrap();
+				assert_eq!(message.payload, compression::pack_raw(42u32.encode()));
+				assert_eq!(PhalaMq::unpacked(&message).unwrap().payload, 42u32.encode());
+
+				// A zstd frame of 1000 zeros, charged per byte unpacked.
+				let frame = hex_literal::hex!("0128b52ffd60e802431f0000");
+				let message =
+					Message::new(MessageOrigin::Gatekeeper, topic.clone(), frame.to_vec());
+				assert_eq!(PhalaMq::unpacked(&message).unwrap().payload, vec![0; 1000]);
+				assert_eq!(
+					PhalaMq::handler_weight(&message),
+					Weight::from_parts(1000 * UNPACK_WEIGHT_PER_BYTE, 0)
+				);
+
+				let malformed = Message::new(MessageOrigin::Gatekeeper, topic, vec![9]);
+				assert!(PhalaMq::unpacked(&malformed).is_none());
+			});
+		}
+
+		#[test]
+		fn test_usage_report_keeps_the_latest_window() {
+			new_test_ext().execute_with(|| {
+				setup();
+				System::set_block_number(MQ_USAGE_WINDOW as u64 * 2);
+				let worker = worker_pubkey(1);
+				let sender = MessageOrigin::Worker(worker);
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW * 2,
+					vec![(sender.clone(), usage(2))]
+				));
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW,
+					vec![(sender.clone(), usage(1))]
+				));
+				let record = SenderUsageReports::<Test>::get(&sender).unwrap();
+				assert_eq!(record.window_end, MQ_USAGE_WINDOW * 2);
+				assert_eq!(record.usage, usage(2));
+			});
+		}
+
+		#[test]
+		fn test_usage_records_are_bounded() {
+			new_test_ext().execute_with(|| {
+				setup();
+				let record = UsageRecord {
+					reporter: worker_pubkey(2),
+					window_end: 0,
+					usage: usage(1),
+				};
+				for i in 0..MAX_USAGE_RECORDS - 1 {
+					SenderUsageReports::<Test>::insert(
+						MessageOrigin::Pallet(i.encode()),
+						record.clone(),
+					);
+				}
+				let worker = worker_pubkey(1);
+				let sender = MessageOrigin::Worker(worker);
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW,
+					vec![(sender.clone(), usage(1))]
+				));
+				assert_eq!(SenderUsageReports::<Test>::count(), MAX_USAGE_RECORDS);
+
+				// No new sender beyond the limit, the known ones are still updated.
+				let worker2 = worker_pubkey(2);
+				assert_ok!(report(
+					worker2,
+					MQ_USAGE_WINDOW,
+					vec![(MessageOrigin::Worker(worker2), usage(1))]
+				));
+				assert!(!SenderUsageReports::<Test>::contains_key(
+					MessageOrigin::Worker(worker2)
+				));
+				System::set_block_number(MQ_USAGE_WINDOW as u64 * 2);
+				assert_ok!(report(
+					worker,
+					MQ_USAGE_WINDOW * 2,
+					vec![(sender.clone(), usage(5))]
+				));
+				assert_eq!(
+					SenderUsageReports::<Test>::get(&sender).unwrap().usage,
+					usage(5)
+				);
+			});
+		}
+
+		fn pushed_receipts() -> Vec<MessageReceipts> {
+			PhalaMq::messages()
+				.into_iter()
+				.filter(|message| message.destination.path() == &MessageReceipts::topic())
+				.map(|message| {
+					assert_eq!(message.sender, MessageOrigin::Pallet(b"PhalaMq".to_vec()));
+					MessageReceipts::decode(&mut &message.payload[..]).unwrap()
+				})
+				.collect()
+		}
+
+		#[test]
+		fn test_set_ack_receipts() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				assert_noop!(
+					PhalaMq::set_ack_receipts(RuntimeOrigin::signed(1), true),
+					sp_runtime::DispatchError::BadOrigin
+				);
+				assert_ok!(PhalaMq::set_ack_receipts(RuntimeOrigin::root(), true));
+				assert!(AckReceiptsEnabled::<Test>::get());
+				PendingReceipts::<Test>::insert(MessageOrigin::Gatekeeper, 1);
+				assert_ok!(PhalaMq::set_ack_receipts(RuntimeOrigin::root(), false));
+				assert!(!AckReceiptsEnabled::<Test>::get());
+				// The receipts queued before are dropped at the end of the block, not sent.
+				PhalaMq::on_finalize(1);
+				assert_eq!(PendingReceipts::<Test>::iter().count(), 0);
+				assert!(pushed_receipts().is_empty());
+			});
+		}
+
+		#[test]
+		fn test_receipts_pushed_on_finalize() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				assert_ok!(PhalaMq::set_ack_receipts(RuntimeOrigin::root(), true));
+				let sender = MessageOrigin::Worker(worker_pubkey(1));
+				PendingReceipts::<Test>::insert(sender.clone(), 3);
+				PendingReceipts::<Test>::insert(MessageOrigin::Gatekeeper, 5);
+				PhalaMq::on_finalize(2);
+				assert_eq!(PendingReceipts::<Test>::iter().count(), 0);
+				let receipts = pushed_receipts();
+				assert_eq!(receipts.len(), 1);
+				assert_eq!(receipts[0].block, 2);
+				let mut acked = receipts[0].receipts.clone();
+				acked.sort();
+				let mut expected = vec![(sender, 3), (MessageOrigin::Gatekeeper, 5)];
+				expected.sort();
+				assert_eq!(acked, expected);
+
+				// Nothing accepted, nothing acknowledged.
+				PhalaMq::on_initialize(3);
+				PhalaMq::on_finalize(3);
+				assert!(pushed_receipts().is_empty());
+			});
+		}
+	}
 }
 
 /// Provides `SignedExtension` to check message sequence.
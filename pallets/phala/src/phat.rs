@@ -33,6 +33,7 @@ pub mod pallet {
 				WorkerClusterReport,
 			},
 			ClusterInfo, ClusterPermission, CodeIndex, ContractClusterId, ContractId, ContractInfo,
+			ExtensionPolicy, LogPolicy, MAX_LOG_OPTED_OUT_CONTRACTS,
 		},
 		messaging::{bind_topic, DecodedMessage, MessageOrigin},
 		ClusterPublicKey, ContractPublicKey, WorkerIdentity, WorkerPublicKey,
@@ -102,6 +103,47 @@ pub mod pallet {
 	#[pallet::storage]
 	pub type ClusterByWorkers<T> = StorageMap<_, Twox64Concat, WorkerPublicKey, ContractClusterId>;
 
+	/// The id of the latest key refresh of each cluster.
+	#[pallet::storage]
+	pub type ClusterKeyRefreshIds<T> =
+		StorageMap<_, Twox64Concat, ContractClusterId, u64, ValueQuery>;
+
+	/// The log policy of each cluster, applied by the workers to the logs and events forwarded to
+	/// the log server.
+	#[pallet::storage]
+	pub type ClusterLogPolicies<T> =
+		StorageMap<_, Twox64Concat, ContractClusterId, LogPolicy, ValueQuery>;
+
+	/// The chain extensions disabled in each cluster, enforced by the workers when the contracts
+	/// call them.
+	#[pallet::storage]
+	pub type ClusterExtensionPolicies<T> =
+		StorageMap<_, Twox64Concat, ContractClusterId, ExtensionPolicy, ValueQuery>;
+
+	/// The block interval at which the workers of each cluster take their checkpoints, so that
+	/// all of them snapshot their states at the same heights. The workers of a cluster not listed
+	/// here take checkpoints at their own pace.
+	#[pallet::storage]
+	pub type ClusterCheckpointIntervals<T> = StorageMap<_, Twox64Concat, ContractClusterId, u32>;
+
+	/// The shortest checkpoint interval a cluster owner can set, the default pace of the workers
+	/// (30 minutes) in 12s blocks. Checkpointing is heavy, a shorter interval would let a cluster
+	/// owner stall all the workers of the cluster.
+	pub const MIN_CHECKPOINT_INTERVAL: u32 = 150;
+
+	/// The gas budget of each block in each cluster. When the executions of a block consume less,
+	/// the contracts hooked on `OnIdle` are called with the gas left. The contracts of a cluster
+	/// not listed here are never called on idle.
+	#[pallet::storage]
+	pub type ClusterIdleBudgets<T> = StorageMap<_, Twox64Concat, ContractClusterId, u64>;
+
+	/// The gas at which each contract execution in a transaction of each cluster is aborted. The
+	/// workers read it from the chain state of the block being dispatched, so all of them abort
+	/// the same executions. The executions of a cluster not listed here are only limited by the
+	/// gas limit given by their callers.
+	#[pallet::storage]
+	pub type ClusterExecutionDeadlines<T> = StorageMap<_, Twox64Concat, ContractClusterId, u64>;
+
 	/// The pink-system contract code used to deploy new clusters
 	#[pallet::storage]
 	pub type PinkSystemCode<T> = StorageValue<_, (u16, Vec<u8>), ValueQuery>;
@@ -168,6 +210,42 @@ pub mod pallet {
 			worker: WorkerPublicKey,
 			cluster: ContractClusterId,
 		},
+		ClusterLogPolicyChanged {
+			cluster: ContractClusterId,
+		},
+		ClusterCheckpointIntervalChanged {
+			cluster: ContractClusterId,
+			interval: u32,
+		},
+		ClusterExtensionPolicyChanged {
+			cluster: ContractClusterId,
+			policy: ExtensionPolicy,
+		},
+		ClusterIdleBudgetChanged {
+			cluster: ContractClusterId,
+			budget: u64,
+		},
+		ClusterExecutionDeadlineChanged {
+			cluster: ContractClusterId,
+			deadline: u64,
+		},
+		ContractUpgradeRequested {
+			contract: ContractId,
+			code_hash: H256,
+		},
+		SidevmCodeUpdateRequested {
+			contract: ContractId,
+			code_hash: H256,
+		},
+		ClusterKeyRefreshRequested {
+			cluster: ContractClusterId,
+			refresh_id: u64,
+		},
+		ClusterKeyRefreshed {
+			cluster: ContractClusterId,
+			worker: WorkerPublicKey,
+			refresh_id: u64,
+		},
 	}
 
 	#[pallet::error]
@@ -185,6 +263,9 @@ pub mod pallet {
 		NoPinkSystemCode,
 		ContractNotFound,
 		WorkerIsBusy,
+		ContractPermissionDenied,
+		CheckpointIntervalTooShort,
+		TooManyOptedOutContracts,
 	}
 
 	type CodeHash<T> = <T as frame_system::Config>::Hash;
@@ -572,6 +653,268 @@ pub mod pallet {
 			});
 			Ok(())
 		}
+
+		/// Refresh the shares of the key of a cluster held by its workers
+		///
+		/// The Gatekeeper splits the cluster key into fresh shares, one for each current worker of
+		/// the cluster, which replace the shares of the previous refresh. The public key of the
+		/// cluster doesn't change, so the contracts keep running during the refresh.
+		#[pallet::call_index(11)]
+		#[pallet::weight({0})]
+		pub fn refresh_cluster_key(
+			origin: OriginFor<T>,
+			cluster: ContractClusterId,
+		) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			ensure!(
+				Clusters::<T>::contains_key(cluster),
+				Error::<T>::ClusterNotFound
+			);
+			let pubkey =
+				registry::ClusterKeys::<T>::get(cluster).ok_or(Error::<T>::ClusterNotDeployed)?;
+			let workers = ClusterWorkers::<T>::get(cluster)
+				.into_iter()
+				.filter_map(|worker| {
+					let worker_info = registry::Workers::<T>::get(worker)?;
+					Some(WorkerIdentity {
+						pubkey: worker_info.pubkey,
+						ecdh_pubkey: worker_info.ecdh_pubkey,
+					})
+				})
+				.collect::<Vec<_>>();
+			ensure!(!workers.is_empty(), Error::<T>::NoWorkerSpecified);
+			let refresh_id = ClusterKeyRefreshIds::<T>::mutate(cluster, |id| {
+				*id += 1;
+				*id
+			});
+			Self::push_message(ClusterEvent::RefreshClusterKey {
+				cluster,
+				pubkey,
+				workers,
+				refresh_id,
+			});
+			Self::deposit_event(Event::ClusterKeyRefreshRequested {
+				cluster,
+				refresh_id,
+			});
+			Ok(())
+		}
+
+		/// Set the policy of the logs forwarded to the log server of a cluster
+		#[pallet::call_index(12)]
+		#[pallet::weight(Weight::from_parts(10_000u64, 0) + T::DbWeight::get().reads_writes(2u64, 3u64))]
+		pub fn set_cluster_log_policy(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			policy: LogPolicy,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let cluster_info = Clusters::<T>::get(cluster_id).ok_or(Error::<T>::ClusterNotFound)?;
+			ensure!(
+				cluster_info.owner == origin,
+				Error::<T>::ClusterPermissionDenied
+			);
+			ensure!(
+				policy.opted_out.len() <= MAX_LOG_OPTED_OUT_CONTRACTS,
+				Error::<T>::TooManyOptedOutContracts
+			);
+			ClusterLogPolicies::<T>::insert(cluster_id, policy.clone());
+			Self::push_message(ClusterOperation::<T::AccountId>::SetLogPolicy {
+				cluster_id,
+				policy,
+			});
+			Self::deposit_event(Event::ClusterLogPolicyChanged {
+				cluster: cluster_id,
+			});
+			Ok(())
+		}
+
+		/// Swap the code of a contract to an uploaded code
+		///
+		/// If `call_on_upgrade` is set, the `on_upgrade` message of the new code is called by the
+		/// workers with the given gas limit to migrate the storage. The upgrade is reverted if the
+		/// call fails.
+		#[pallet::call_index(13)]
+		#[pallet::weight({0})]
+		pub fn upgrade_contract(
+			origin: OriginFor<T>,
+			contract_id: ContractId,
+			code_hash: H256,
+			call_on_upgrade: bool,
+			gas_limit: u64,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				contract_info.deployer == origin,
+				Error::<T>::ContractPermissionDenied
+			);
+			Self::push_message(ClusterOperation::<T::AccountId>::UpgradeContract {
+				origin,
+				cluster_id: contract_info.cluster,
+				contract_id,
+				code_hash,
+				call_on_upgrade,
+				gas_limit,
+			});
+			Self::deposit_event(Event::ContractUpgradeRequested {
+				contract: contract_id,
+				code_hash,
+			});
+			Ok(())
+		}
+
+		/// Swap the sidevm program of a contract to an uploaded sidevm code
+		///
+		/// The workers stop the running instance and start the new code with the same config,
+		/// without reinstantiating the contract, so the contract storage and the local cache of
+		/// the sidevm are kept.
+		#[pallet::call_index(14)]
+		#[pallet::weight({0})]
+		pub fn update_sidevm_code(
+			origin: OriginFor<T>,
+			contract_id: ContractId,
+			code_hash: H256,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				contract_info.deployer == origin,
+				Error::<T>::ContractPermissionDenied
+			);
+			Self::push_message(ClusterOperation::<T::AccountId>::UpdateSidevmCode {
+				origin,
+				cluster_id: contract_info.cluster,
+				contract_id,
+				code_hash,
+			});
+			Self::deposit_event(Event::SidevmCodeUpdateRequested {
+				contract: contract_id,
+				code_hash,
+			});
+			Ok(())
+		}
+
+		/// Set the block interval at which the workers of a cluster take their checkpoints
+		///
+		/// The checkpoints are taken at the blocks whose number is a multiple of `interval`, which
+		/// must be at least [`MIN_CHECKPOINT_INTERVAL`]. 0 lets the workers take checkpoints at
+		/// their own pace again.
+		#[pallet::call_index(15)]
+		#[pallet::weight({0})]
+		pub fn set_cluster_checkpoint_interval(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			interval: u32,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let cluster_info = Clusters::<T>::get(cluster_id).ok_or(Error::<T>::ClusterNotFound)?;
+			ensure!(
+				cluster_info.owner == origin,
+				Error::<T>::ClusterPermissionDenied
+			);
+			ensure!(
+				interval == 0 || interval >= MIN_CHECKPOINT_INTERVAL,
+				Error::<T>::CheckpointIntervalTooShort
+			);
+			if interval == 0 {
+				ClusterCheckpointIntervals::<T>::remove(cluster_id);
+			} else {
+				ClusterCheckpointIntervals::<T>::insert(cluster_id, interval);
+			}
+			Self::deposit_event(Event::ClusterCheckpointIntervalChanged {
+				cluster: cluster_id,
+				interval,
+			});
+			Ok(())
+		}
+
+		/// Set the chain extensions disabled in a cluster
+		///
+		/// Contracts of the cluster calling a disabled extension fail with an `ExtensionDisabled`
+		/// error.
+		#[pallet::call_index(16)]
+		#[pallet::weight({0})]
+		pub fn set_cluster_extension_policy(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			policy: ExtensionPolicy,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let cluster_info = Clusters::<T>::get(cluster_id).ok_or(Error::<T>::ClusterNotFound)?;
+			ensure!(
+				cluster_info.owner == origin,
+				Error::<T>::ClusterPermissionDenied
+			);
+			ClusterExtensionPolicies::<T>::insert(cluster_id, policy);
+			Self::push_message(ClusterOperation::<T::AccountId>::SetExtensionPolicy {
+				cluster_id,
+				policy,
+			});
+			Self::deposit_event(Event::ClusterExtensionPolicyChanged {
+				cluster: cluster_id,
+				policy,
+			});
+			Ok(())
+		}
+
+		/// Set the gas budget of each block under which the `OnIdle` hooks of a cluster are called
+		///
+		/// 0 disables the `OnIdle` hooks of the cluster.
+		#[pallet::call_index(17)]
+		#[pallet::weight({0})]
+		pub fn set_cluster_idle_budget(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			budget: u64,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let cluster_info = Clusters::<T>::get(cluster_id).ok_or(Error::<T>::ClusterNotFound)?;
+			ensure!(
+				cluster_info.owner == origin,
+				Error::<T>::ClusterPermissionDenied
+			);
+			if budget == 0 {
+				ClusterIdleBudgets::<T>::remove(cluster_id);
+			} else {
+				ClusterIdleBudgets::<T>::insert(cluster_id, budget);
+			}
+			Self::deposit_event(Event::ClusterIdleBudgetChanged {
+				cluster: cluster_id,
+				budget,
+			});
+			Ok(())
+		}
+
+		/// Set the gas at which each contract execution in a transaction of a cluster is aborted
+		///
+		/// 0 removes the deadline of the cluster.
+		#[pallet::call_index(18)]
+		#[pallet::weight({0})]
+		pub fn set_cluster_execution_deadline(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			deadline: u64,
+		) -> DispatchResult {
+			let origin = ensure_signed(origin)?;
+			let cluster_info = Clusters::<T>::get(cluster_id).ok_or(Error::<T>::ClusterNotFound)?;
+			ensure!(
+				cluster_info.owner == origin,
+				Error::<T>::ClusterPermissionDenied
+			);
+			if deadline == 0 {
+				ClusterExecutionDeadlines::<T>::remove(cluster_id);
+			} else {
+				ClusterExecutionDeadlines::<T>::insert(cluster_id, deadline);
+			}
+			Self::deposit_event(Event::ClusterExecutionDeadlineChanged {
+				cluster: cluster_id,
+				deadline,
+			});
+			Ok(())
+		}
 	}
 
 	impl<T: Config> Pallet<T>
@@ -656,6 +999,13 @@ pub mod pallet {
 						worker: worker_pubkey,
 					});
 				}
+				WorkerClusterReport::ClusterKeyRefreshed { id, refresh_id } => {
+					Self::deposit_event(Event::ClusterKeyRefreshed {
+						cluster: id,
+						worker: worker_pubkey,
+						refresh_id,
+					});
+				}
 			}
 			Ok(())
 		}
@@ -69,7 +69,8 @@ pub async fn query<Request: Encode, Response: Decode>(
         signature: key_g.sign(&encrypted_data.encode()).0.to_vec(),
     };
 
-    let request = prpc::ContractQueryRequest::new(encrypted_data, Some(data_signature));
+    let request =
+        prpc::ContractQueryRequest::new(encrypted_data, Some(data_signature), None, None, false);
 
     // 5. Do the RPC call.
     let response = pr.contract_query(request).await?;
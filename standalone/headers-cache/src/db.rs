@@ -78,6 +78,28 @@ impl CacheDB {
         Ok(())
     }
 
+    /// The lowest block stored under the prefix.
+    fn first(&self, prefix: u8) -> Option<BlockNumber> {
+        let mode = rocksdb::IteratorMode::From(&[prefix], rocksdb::Direction::Forward);
+        let (key, _) = self.0.iterator(mode).next()?.ok()?;
+        if key.len() != size_of::<BlockNumber>() + 1 || key[0] != prefix {
+            return None;
+        }
+        Some(BlockNumber::from_be_bytes(key[1..].try_into().ok()?))
+    }
+
+    pub fn first_header(&self) -> Option<BlockNumber> {
+        self.first(b'h')
+    }
+
+    pub fn first_para_header(&self) -> Option<BlockNumber> {
+        self.first(b'p')
+    }
+
+    pub fn first_storage_changes(&self) -> Option<BlockNumber> {
+        self.first(b'c')
+    }
+
     pub fn get_header(&self, block: BlockNumber) -> Option<Vec<u8>> {
         self.get(b'h', block)
     }
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 
 use anyhow::Context;
 use log::{error, info};
@@ -217,6 +218,18 @@ enum Action {
         /// The header chunk files to merge.
         files: Vec<String>,
     },
+    /// Export the cache database to the static layout, to be served from an object storage or a
+    /// CDN. Complete chunks already exported are skipped.
+    ExportStatic {
+        /// The database file to use
+        #[arg(long, default_value = "cache.db")]
+        db: String,
+        /// Number of blocks in each chunk
+        #[arg(long, default_value_t = 1000)]
+        chunk_size: BlockNumber,
+        /// The directory to write to
+        output: String,
+    },
     /// Reset cursors
     Reset {
         /// The database file to use
@@ -250,6 +263,11 @@ async fn main() -> anyhow::Result<()> {
         } => merge(append, dest_file, files)?,
         Action::Inspect { files } => inspect(files)?,
         Action::InspectDb { db } => inspect_db(db)?,
+        Action::ExportStatic {
+            db,
+            chunk_size,
+            output,
+        } => export_static(db, chunk_size, output)?,
         Action::Reset {
             db,
             header,
@@ -409,6 +427,111 @@ fn reset(
     Ok(())
 }
 
+fn write_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed, so that a sync of the directory never picks a partial file.
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn count_records(path: &Path) -> anyhow::Result<BlockNumber> {
+    let file = File::open(path)?;
+    cache::read_items(std::io::BufReader::new(file), |_| Ok(false))
+}
+
+fn export_chunks(
+    output: &Path,
+    kind: cache::StaticKind,
+    chunk_size: BlockNumber,
+    first: Option<BlockNumber>,
+    get: impl Fn(BlockNumber) -> Option<Vec<u8>>,
+) -> anyhow::Result<Option<cache::BlockRange>> {
+    let Some(first) = first else {
+        return Ok(None);
+    };
+    let mut block = first;
+    let mut last = None;
+    loop {
+        let chunk = block / chunk_size;
+        let chunk_end = (chunk + 1) * chunk_size;
+        let path = output.join(kind.chunk_path(chunk));
+        // A chunk exported before is only complete if it holds every block of the chunk, the
+        // last chunk of a previous export may have been cut short.
+        if matches!(count_records(&path), Ok(count) if count == chunk_end - block) {
+            last = Some(chunk_end - 1);
+            block = chunk_end;
+            continue;
+        }
+        let mut data = vec![];
+        for number in block..chunk_end {
+            let Some(item) = get(number) else {
+                break;
+            };
+            cache::Record::new(&item).write(&mut data)?;
+            last = Some(number);
+        }
+        if data.is_empty() {
+            break;
+        }
+        write_file(&path, &data)?;
+        info!("Exported {}", path.display());
+        if last != Some(chunk_end - 1) {
+            break;
+        }
+        block = chunk_end;
+    }
+    Ok(last.map(|last| cache::BlockRange { first, last }))
+}
+
+fn export_static(db: String, chunk_size: BlockNumber, output: String) -> anyhow::Result<()> {
+    anyhow::ensure!(chunk_size > 0, "chunk size must be positive");
+    let cache = db::CacheDB::open(&db)?;
+    let metadata = cache.get_metadata()?.unwrap_or_default();
+    let output = Path::new(&output);
+    let mut index = cache::StaticIndex {
+        chunk_size,
+        ..Default::default()
+    };
+    for &block in &metadata.genesis {
+        if let Some(data) = cache.get_genesis(block) {
+            write_file(&output.join(cache::static_genesis_path(block)), &data)?;
+            index.genesis.push(block);
+        }
+    }
+    index.headers = export_chunks(
+        output,
+        cache::StaticKind::Headers,
+        chunk_size,
+        cache.first_header(),
+        |block| cache.get_header(block),
+    )?;
+    index.para_headers = export_chunks(
+        output,
+        cache::StaticKind::ParaHeaders,
+        chunk_size,
+        cache.first_para_header(),
+        |block| cache.get_para_header(block),
+    )?;
+    index.storage_changes = export_chunks(
+        output,
+        cache::StaticKind::StorageChanges,
+        chunk_size,
+        cache.first_storage_changes(),
+        |block| cache.get_storage_changes(block),
+    )?;
+    // The index goes last, so that it never points to chunks not written yet.
+    write_file(
+        &output.join(cache::STATIC_INDEX_FILE),
+        &serde_json::to_vec_pretty(&index)?,
+    )?;
+    println!("{}", serde_json::to_string_pretty(&index)?);
+    Ok(())
+}
+
 async fn grab(what: Grab) -> anyhow::Result<()> {
     match what {
         Grab::Headers {
@@ -549,3 +672,85 @@ async fn import(db: String, what: Import) -> anyhow::Result<()> {
     cache.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_output() -> PathBuf {
+        std::env::temp_dir().join(format!("headers-cache-test-{:016x}", rand::random::<u64>()))
+    }
+
+    fn records(output: &Path, chunk: BlockNumber) -> Vec<Vec<u8>> {
+        let path = output.join(cache::StaticKind::Headers.chunk_path(chunk));
+        let mut items = vec![];
+        cache::read_items(File::open(path).unwrap(), |record| {
+            items.push(record.payload().to_vec());
+            Ok(false)
+        })
+        .unwrap();
+        items
+    }
+
+    fn export(output: &Path, first: BlockNumber, last: BlockNumber) -> Option<cache::BlockRange> {
+        export_chunks(
+            output,
+            cache::StaticKind::Headers,
+            4,
+            Some(first),
+            |block| {
+                (first..=last)
+                    .contains(&block)
+                    .then(|| block.to_be_bytes().to_vec())
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn export_chunks_splits_blocks() {
+        let output = temp_output();
+        let range = export(&output, 2, 9).unwrap();
+        assert_eq!((range.first, range.last), (2, 9));
+        assert_eq!(records(&output, 0).len(), 2);
+        assert_eq!(records(&output, 1).len(), 4);
+        assert_eq!(records(&output, 2).len(), 2);
+        std::fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn export_chunks_completes_a_partial_chunk() {
+        let output = temp_output();
+        export(&output, 0, 5);
+        assert_eq!(records(&output, 1).len(), 2);
+        // The partial chunk is exported again once more blocks are in, even though the block
+        // after it is in as well.
+        let range = export(&output, 0, 12).unwrap();
+        assert_eq!(range.last, 12);
+        assert_eq!(
+            records(&output, 1),
+            (4u32..8)
+                .map(|b| b.to_be_bytes().to_vec())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(records(&output, 3).len(), 1);
+        std::fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn export_chunks_skips_complete_chunks() {
+        let output = temp_output();
+        export(&output, 0, 7);
+        let path = output.join(cache::StaticKind::Headers.chunk_path(0));
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let range = export(&output, 0, 9).unwrap();
+        assert_eq!(range.last, 9);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().modified().unwrap(),
+            modified
+        );
+        assert_eq!(records(&output, 2).len(), 2);
+        std::fs::remove_dir_all(output).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use log::info;
+use phactory_api::prpc::MemoryUsage;
+use std::time::Duration;
+
+use crate::types::BlockNumber;
+
+/// Adjusts the number of blocks sent in each `dispatch_blocks` call.
+///
+/// The batch grows while pRuntime handles the batches quickly, and shrinks when a batch takes
+/// too long, when pRuntime is running out of memory, or when a dispatch failed. The size always
+/// stays within `[min, max]`. With `min == max` the batch size is fixed.
+pub struct BatchSizeTuner {
+    size: BlockNumber,
+    min: BlockNumber,
+    max: BlockNumber,
+    target_latency: Duration,
+    min_free_memory: u64,
+}
+
+impl BatchSizeTuner {
+    pub fn new(
+        initial: BlockNumber,
+        min: BlockNumber,
+        max: BlockNumber,
+        target_latency: Duration,
+        min_free_memory: u64,
+    ) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            size: initial.clamp(min, max),
+            min,
+            max,
+            target_latency,
+            min_free_memory,
+        }
+    }
+
+    pub fn fixed(size: BlockNumber) -> Self {
+        Self::new(size, size, size, Duration::ZERO, 0)
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        self.min == self.max
+    }
+
+    pub fn size(&self) -> BlockNumber {
+        self.size
+    }
+
+    /// Feeds back the result of a successful dispatch of `blocks` blocks.
+    ///
+    /// `memory` is the memory usage reported by pRuntime after the dispatch, if available.
+    pub fn on_dispatched(
+        &mut self,
+        blocks: BlockNumber,
+        elapsed: Duration,
+        memory: Option<&MemoryUsage>,
+    ) {
+        if self.is_fixed() {
+            return;
+        }
+        // The free memory is only reported by pRuntimes running in an enclave.
+        let low_memory = memory
+            .map(|m| m.free > 0 && m.free < self.min_free_memory)
+            .unwrap_or(false);
+        let new_size = if low_memory {
+            self.size / 2
+        } else if elapsed > self.target_latency {
+            // Scale down proportionally to the overrun.
+            let ratio = self.target_latency.as_secs_f64() / elapsed.as_secs_f64();
+            (blocks as f64 * ratio) as BlockNumber
+        } else if blocks >= self.size && elapsed < self.target_latency / 2 {
+            // Only grow if the last batch was a full one, otherwise the latency says nothing.
+            self.size.saturating_add((self.size / 4).max(1))
+        } else {
+            self.size
+        };
+        self.set_size(new_size, || {
+            if low_memory {
+                "low memory".into()
+            } else {
+                format!("{blocks} blocks took {elapsed:?}")
+            }
+        });
+    }
+
+    /// Feeds back a failed dispatch. The batch size is halved to lower the pressure on pRuntime.
+    pub fn on_failed(&mut self) {
+        if self.is_fixed() {
+            return;
+        }
+        self.set_size(self.size / 2, || "dispatch failed".into());
+    }
+
+    fn set_size(&mut self, size: BlockNumber, reason: impl FnOnce() -> String) {
+        let size = size.clamp(self.min, self.max);
+        if size != self.size {
+            info!(
+                "dispatch batch size changed {} -> {size} ({})",
+                self.size,
+                reason()
+            );
+            self.size = size;
+        }
+    }
+}
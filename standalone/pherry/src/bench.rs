@@ -0,0 +1,156 @@
+//! The `bench` subcommand, measuring the throughput of the block sync pipeline.
+//!
+//! Storage changes of the block range are fetched batch by batch, without prefetching so that
+//! the latency of each request is measured alone, and dispatched to pRuntime unless
+//! `--null-sink` is given. Blocks dispatched to pRuntime must follow its current state, so the
+//! range starts from the next block of pRuntime and ends at its latest synced parachain header.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use codec::Encode;
+use log::info;
+
+use crate::headers_cache::Client as CacheClient;
+use crate::types::{BlockNumber, ParachainApi};
+use crate::{fetch_storage_changes, req_dispatch_block, subxt_connect_with_profile, Args};
+use phactory_api::pruntime_client;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// The first block to sync. Defaults to the next block of pRuntime, required with
+    /// --null-sink.
+    #[arg(long)]
+    from: Option<BlockNumber>,
+
+    /// The number of blocks to sync.
+    #[arg(long, default_value = "1000")]
+    count: BlockNumber,
+
+    /// Fetch the blocks only, without dispatching them to pRuntime.
+    #[arg(long)]
+    null_sink: bool,
+}
+
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    fn record(&mut self, latency: Duration) {
+        self.0.push(latency);
+    }
+
+    fn percentile(&self, p: usize) -> Duration {
+        let mut sorted = self.0.clone();
+        sorted.sort();
+        match sorted.len() {
+            0 => Duration::ZERO,
+            len => sorted[((len - 1) * p / 100).min(len - 1)],
+        }
+    }
+
+    fn report(&self, name: &str) {
+        info!(
+            "{name}: n={} p50={:?} p90={:?} p99={:?} max={:?}",
+            self.0.len(),
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.percentile(100),
+        );
+    }
+}
+
+pub async fn run(args: &Args, bench: &BenchArgs) -> Result<()> {
+    let para_uri: &str = if args.parachain {
+        &args.parachain_ws_endpoint
+    } else {
+        &args.relaychain_ws_endpoint
+    };
+    let para_profile = phaxt::ChainProfile::resolve(&args.parachain_profile)?;
+    let para_api: ParachainApi = subxt_connect_with_profile(para_uri, para_profile).await?;
+    info!("Connected to parachain node at: {para_uri}");
+    let cache_client = if !args.headers_cache_uri.is_empty() {
+        Some(CacheClient::new(&args.headers_cache_uri))
+    } else {
+        None
+    };
+    let pr = if bench.null_sink {
+        None
+    } else {
+        Some(pruntime_client::new_pruntime_client(
+            args.pruntime_endpoint.clone(),
+        ))
+    };
+
+    let (from, to) = match (&pr, bench.from) {
+        (None, None) => return Err(anyhow!("--from is required with --null-sink")),
+        (None, Some(from)) => (from, from.saturating_add(bench.count.saturating_sub(1))),
+        (Some(pr), from) => {
+            let info = pr.get_info(()).await?;
+            if from.map_or(false, |from| from != info.blocknum) {
+                return Err(anyhow!(
+                    "pRuntime is at block {}, can not sync from another block",
+                    info.blocknum
+                ));
+            }
+            let from = info.blocknum;
+            let to = from
+                .saturating_add(bench.count.saturating_sub(1))
+                .min(info.para_headernum.saturating_sub(1));
+            (from, to)
+        }
+    };
+    if bench.count == 0 || to < from {
+        return Err(anyhow!("No block to sync from {from}"));
+    }
+    let batch_size = args.sync_blocks.max(1);
+    info!(
+        "Benchmarking blocks {from}..={to} in batches of {batch_size}, sink={}",
+        if pr.is_some() { "pRuntime" } else { "null" }
+    );
+
+    let mut fetch_latencies = Latencies::default();
+    let mut dispatch_latencies = Latencies::default();
+    let mut bytes_fetched = 0_usize;
+    let mut blocks_synced: BlockNumber = 0;
+    let started = Instant::now();
+    let mut next = from;
+    while next <= to {
+        let batch_to = to.min(next.saturating_add(batch_size - 1));
+        let start = Instant::now();
+        let blocks =
+            fetch_storage_changes(&para_api, cache_client.as_ref(), next, batch_to).await?;
+        fetch_latencies.record(start.elapsed());
+        bytes_fetched += blocks.encoded_size();
+
+        let mut synced_to = batch_to;
+        if let Some(pr) = &pr {
+            let start = Instant::now();
+            let resp = req_dispatch_block(pr, blocks).await?;
+            dispatch_latencies.record(start.elapsed());
+            synced_to = resp.synced_to.min(batch_to);
+        }
+        blocks_synced += (synced_to + 1).saturating_sub(next);
+        if synced_to < batch_to {
+            info!("pRuntime stopped at block {synced_to}");
+            break;
+        }
+        next = batch_to + 1;
+    }
+
+    let elapsed = started.elapsed();
+    info!(
+        "Synced {blocks_synced} blocks in {elapsed:?}, {:.2} blocks/s",
+        blocks_synced as f64 / elapsed.as_secs_f64()
+    );
+    info!(
+        "Fetched {bytes_fetched} bytes, {:.2} KiB/s",
+        bytes_fetched as f64 / 1024.0 / elapsed.as_secs_f64()
+    );
+    fetch_latencies.report("RPC fetch latency");
+    if pr.is_some() {
+        dispatch_latencies.report("pRuntime dispatch latency");
+    }
+    Ok(())
+}
@@ -0,0 +1,402 @@
+//! An on-disk cache of the fetched parachain headers, keyed by block hash, and storage changes,
+//! keyed by block number.
+//!
+//! Enabled with `--local-block-cache-dir`, so that restarting pherry, or running several pherry
+//! instances on the same host, doesn't download the same blocks from the RPC node again. The least
+//! recently used entries are evicted once the cache exceeds `--local-block-cache-size-mb`. The cap
+//! is shared by the instances using the same directory: each of them rescans the directory from
+//! time to time to account for the entries written by the others.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use codec::{Decode, Encode};
+use log::{debug, info, warn};
+
+use crate::types::{BlockNumber, Hash};
+
+static CACHE: OnceLock<BlockCache> = OnceLock::new();
+
+/// Number of lookups between two hit rate logs.
+const LOG_INTERVAL: u64 = 1000;
+
+/// Number of writes between two rescans of the directory.
+const RESCAN_INTERVAL: u64 = 100;
+
+/// Temporary files older than this are left by an interrupted write, the younger ones may still
+/// be written by another instance.
+const STALE_TMP_AGE: Duration = Duration::from_secs(600);
+
+/// The kind of the cached data of a block.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    ParachainHeader,
+    StorageChanges,
+    StorageChangesWithRoot,
+}
+
+impl Kind {
+    fn dir_name(&self) -> &'static str {
+        match self {
+            Kind::ParachainHeader => "para-headers",
+            Kind::StorageChanges => "changes",
+            Kind::StorageChangesWithRoot => "changes-with-root",
+        }
+    }
+
+    fn all() -> [Kind; 3] {
+        [
+            Kind::ParachainHeader,
+            Kind::StorageChanges,
+            Kind::StorageChangesWithRoot,
+        ]
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    tick: u64,
+    /// The last access tick and the size of each entry.
+    entries: HashMap<PathBuf, (u64, u64)>,
+    /// The entries ordered by the last access.
+    lru: BTreeMap<u64, PathBuf>,
+    total_size: u64,
+}
+
+impl Index {
+    fn touch(&mut self, path: PathBuf, size: u64) {
+        self.tick += 1;
+        if let Some((tick, old_size)) = self.entries.insert(path.clone(), (self.tick, size)) {
+            self.lru.remove(&tick);
+            self.total_size -= old_size;
+        }
+        self.lru.insert(self.tick, path);
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((tick, size)) = self.entries.remove(path) {
+            self.lru.remove(&tick);
+            self.total_size -= size;
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<PathBuf> {
+        let (_, path) = self.lru.pop_first()?;
+        if let Some((_, size)) = self.entries.remove(&path) {
+            self.total_size -= size;
+        }
+        Some(path)
+    }
+
+    /// Syncs the index with the `files` found on disk, ordered by modification time. The entries
+    /// gone from the disk are dropped, and the new ones are added as the most recently used.
+    fn sync(&mut self, files: Vec<(PathBuf, u64)>) {
+        let on_disk: HashMap<_, _> = files.iter().cloned().collect();
+        let gone: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|path| !on_disk.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in gone {
+            self.remove(&path);
+        }
+        for (path, size) in files {
+            match self.entries.get(&path) {
+                Some(&(_, known_size)) if known_size == size => {}
+                _ => self.touch(path, size),
+            }
+        }
+    }
+}
+
+pub struct BlockCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writes: AtomicU64,
+}
+
+/// Enables the cache in `dir`, indexing the entries left by the previous runs.
+pub fn init(dir: &str, max_size_mb: u64) -> Result<()> {
+    let cache = BlockCache::open(dir.into(), max_size_mb * 1024 * 1024)?;
+    if CACHE.set(cache).is_err() {
+        anyhow::bail!("The local block cache is already initialized");
+    }
+    Ok(())
+}
+
+/// The cache if enabled.
+pub fn get() -> Option<&'static BlockCache> {
+    CACHE.get()
+}
+
+impl BlockCache {
+    fn open(dir: PathBuf, max_size: u64) -> Result<Self> {
+        for kind in Kind::all() {
+            fs::create_dir_all(dir.join(kind.dir_name()))?;
+        }
+        let cache = Self {
+            dir,
+            max_size,
+            index: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            writes: Default::default(),
+        };
+        cache.rescan()?;
+        let index = cache.index.lock().unwrap();
+        info!(
+            "Local block cache at {}: {} entries, {} bytes",
+            cache.dir.display(),
+            index.entries.len(),
+            index.total_size
+        );
+        drop(index);
+        Ok(cache)
+    }
+
+    /// Lists the entries on disk, ordered by modification time, and removes the stale temporary
+    /// files.
+    fn scan(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let now = SystemTime::now();
+        let mut files = vec![];
+        for kind in Kind::all() {
+            for entry in fs::read_dir(self.dir.join(kind.dir_name()))? {
+                let entry = entry?;
+                let Ok(meta) = entry.metadata() else {
+                    // Evicted by another instance in the meantime.
+                    continue;
+                };
+                if !meta.is_file() {
+                    continue;
+                }
+                let modified = meta.modified()?;
+                if entry.path().extension().is_some() {
+                    let age = now.duration_since(modified).unwrap_or_default();
+                    if age > STALE_TMP_AGE {
+                        let _ = fs::remove_file(entry.path());
+                    }
+                    continue;
+                }
+                files.push((modified, entry.path(), meta.len()));
+            }
+        }
+        // The files written last are the most recently used ones as far as we know.
+        files.sort();
+        Ok(files
+            .into_iter()
+            .map(|(_, path, size)| (path, size))
+            .collect())
+    }
+
+    /// Syncs the index with the directory, which other instances may have written to or evicted
+    /// from, and evicts down to the cap.
+    fn rescan(&self) -> Result<()> {
+        let files = self.scan()?;
+        self.index.lock().unwrap().sync(files);
+        self.evict();
+        Ok(())
+    }
+
+    fn path(&self, kind: Kind, hash: &Hash) -> PathBuf {
+        self.dir
+            .join(kind.dir_name())
+            .join(hex::encode(hash.as_bytes()))
+    }
+
+    fn number_path(&self, kind: Kind, number: BlockNumber) -> PathBuf {
+        self.dir
+            .join(kind.dir_name())
+            .join(format!("{number:0>10}"))
+    }
+
+    /// Gets the entry of the block with the given hash.
+    pub fn get<T: Decode>(&self, kind: Kind, hash: &Hash) -> Option<T> {
+        self.get_path(self.path(kind, hash))
+    }
+
+    /// Gets the entry of the block with the given number. It is up to the caller to check that
+    /// the entry belongs to the right chain.
+    pub fn get_at<T: Decode>(&self, kind: Kind, number: BlockNumber) -> Option<T> {
+        self.get_path(self.number_path(kind, number))
+    }
+
+    pub fn put<T: Encode>(&self, kind: Kind, hash: &Hash, value: &T) {
+        self.put_path(self.path(kind, hash), value)
+    }
+
+    pub fn put_at<T: Encode>(&self, kind: Kind, number: BlockNumber, value: &T) {
+        self.put_path(self.number_path(kind, number), value)
+    }
+
+    fn get_path<T: Decode>(&self, path: PathBuf) -> Option<T> {
+        let value = fs::read(&path)
+            .ok()
+            .and_then(|data| T::decode(&mut &data[..]).ok().map(|v| (v, data.len())));
+        let mut index = self.index.lock().unwrap();
+        match value {
+            Some((value, size)) => {
+                index.touch(path, size as u64);
+                drop(index);
+                self.record(true);
+                Some(value)
+            }
+            None => {
+                // Missing, or evicted by another pherry sharing the directory.
+                index.remove(&path);
+                drop(index);
+                self.record(false);
+                None
+            }
+        }
+    }
+
+    fn put_path<T: Encode>(&self, path: PathBuf, value: &T) {
+        let data = value.encode();
+        // Written to a temporary file first, so that readers never see a partial entry.
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        if let Err(err) = fs::write(&tmp, &data).and_then(|_| fs::rename(&tmp, &path)) {
+            warn!(
+                "Failed to write {} to the block cache: {err}",
+                path.display()
+            );
+            return;
+        }
+        self.index.lock().unwrap().touch(path, data.len() as u64);
+        if self.writes.fetch_add(1, Ordering::Relaxed) % RESCAN_INTERVAL == RESCAN_INTERVAL - 1 {
+            if let Err(err) = self.rescan() {
+                warn!("Failed to rescan the block cache: {err}");
+            }
+        } else {
+            self.evict();
+        }
+    }
+
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        while index.total_size > self.max_size {
+            let Some(path) = index.pop_lru() else {
+                break;
+            };
+            debug!("Evicting {} from the block cache", path.display());
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let (hits, misses) = self.stats();
+        if (hits + misses) % LOG_INTERVAL == 0 {
+            info!(
+                "Local block cache: {hits} hits, {misses} misses, hit rate {:.1}%",
+                hits as f64 * 100.0 / (hits + misses) as f64
+            );
+        }
+    }
+
+    /// The number of hits and misses since the start.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(name)
+    }
+
+    #[test]
+    fn index_evicts_least_recently_used_first() {
+        let mut index = Index::default();
+        index.touch(path("a"), 1);
+        index.touch(path("b"), 2);
+        index.touch(path("c"), 4);
+        index.touch(path("a"), 1);
+        assert_eq!(index.total_size, 7);
+        assert_eq!(index.pop_lru(), Some(path("b")));
+        assert_eq!(index.pop_lru(), Some(path("c")));
+        assert_eq!(index.total_size, 1);
+        assert_eq!(index.pop_lru(), Some(path("a")));
+        assert_eq!(index.pop_lru(), None);
+        assert_eq!(index.total_size, 0);
+    }
+
+    #[test]
+    fn index_accounts_for_resized_and_removed_entries() {
+        let mut index = Index::default();
+        index.touch(path("a"), 1);
+        index.touch(path("a"), 3);
+        index.touch(path("b"), 2);
+        assert_eq!(
+            (index.entries.len(), index.lru.len(), index.total_size),
+            (2, 2, 5)
+        );
+        index.remove(&path("a"));
+        index.remove(&path("missing"));
+        assert_eq!(
+            (index.entries.len(), index.lru.len(), index.total_size),
+            (1, 1, 2)
+        );
+    }
+
+    #[test]
+    fn index_syncs_with_the_disk() {
+        let mut index = Index::default();
+        index.touch(path("a"), 1);
+        index.touch(path("b"), 2);
+        // `a` is evicted by another instance, which wrote `c` as well.
+        index.sync(vec![(path("b"), 2), (path("c"), 4)]);
+        assert_eq!(index.total_size, 6);
+        assert!(!index.entries.contains_key(&path("a")));
+        // The recency of the known entries is kept.
+        assert_eq!(index.pop_lru(), Some(path("b")));
+        assert_eq!(index.pop_lru(), Some(path("c")));
+    }
+
+    #[test]
+    fn cache_cap_is_shared_by_instances() {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("pherry-block-cache-{nanos}"));
+        let a = BlockCache::open(dir.clone(), 1000).unwrap();
+        let b = BlockCache::open(dir.clone(), 1000).unwrap();
+        // Left by another instance being writing it.
+        let tmp = dir
+            .join(Kind::StorageChanges.dir_name())
+            .join("0000000001.1.tmp");
+        fs::write(&tmp, b"partial").unwrap();
+
+        for number in 0..RESCAN_INTERVAL as BlockNumber {
+            a.put_at(Kind::StorageChanges, number, &vec![0u8; 10]);
+            assert_eq!(a.get_at(Kind::StorageChanges, number), Some(vec![0u8; 10]));
+        }
+        // `b` picks up the entries of `a` on its next rescan and evicts down to the cap.
+        b.put_at(Kind::StorageChanges, 1000, &vec![0u8; 100]);
+        b.rescan().unwrap();
+        let files = b.scan().unwrap();
+        let total: u64 = files.iter().map(|(_, size)| size).sum();
+        assert!(total <= 1000, "{total}");
+        assert!(tmp.exists());
+        drop(BlockCache::open(dir.clone(), 1000).unwrap());
+        assert!(tmp.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -8,9 +8,10 @@ use codec::Encode;
 use phactory_api::blocks::StorageProof;
 use phala_node_rpc_ext::MakeInto as _;
 use phala_trie_storage::ser::StorageChanges;
-use phala_types::messaging::MessageOrigin;
+use phala_types::messaging::{BindTopic, Message, MessageOrigin, MessageReceipts};
 use phaxt::{rpc::ExtraRpcExt as _, subxt, BlockNumber, RpcClient};
 use serde_json::to_value;
+use std::collections::BTreeMap;
 use subxt::rpc::rpc_params;
 
 pub use sp_core::{twox_128, twox_64};
@@ -107,6 +108,76 @@ pub async fn mq_next_sequence(
     Ok(seq)
 }
 
+/// The storage key of the on-chain next sequence of the sender, `PhalaMq::OffchainIngress`
+pub fn mq_ingress_key(sender: &MessageOrigin) -> Vec<u8> {
+    let sender = sender.encode();
+    let mut key = twox_128(b"PhalaMq").to_vec();
+    key.extend(twox_128(b"OffchainIngress"));
+    key.extend(twox_64(&sender));
+    key.extend(sender);
+    key
+}
+
+/// Fetch the on-chain next sequences of the senders at given block in one RPC call
+///
+/// Unlike `mq_next_sequence`, the pending messages in the txpool are not taken into account.
+pub async fn mq_next_sequences_at(
+    api: &ParachainApi,
+    hash: Hash,
+    senders: &[MessageOrigin],
+) -> Result<Vec<u64>> {
+    let keys: Vec<_> = senders.iter().map(mq_ingress_key).collect();
+    let sequences = api.query_multi::<u64>(&keys, hash).await?;
+    Ok(sequences
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect())
+}
+
+/// Whether pallet-mq acknowledges the accepted offchain messages with receipts at given block,
+/// `PhalaMq::AckReceiptsEnabled`
+pub async fn mq_ack_receipts_enabled(api: &ParachainApi, hash: Hash) -> Result<bool> {
+    let key = [twox_128(b"PhalaMq"), twox_128(b"AckReceiptsEnabled")].concat();
+    let value = api.storage().at(hash).fetch_raw(&key).await?;
+    Ok(value
+        .map(|value| bool::decode(&mut &value[..]))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// The message receipts emitted by pallet-mq in given block, found in `PhalaMq::OutboundMessages`
+pub async fn mq_receipts_at(api: &ParachainApi, hash: Hash) -> Result<Option<MessageReceipts>> {
+    let key = [twox_128(b"PhalaMq"), twox_128(b"OutboundMessages")].concat();
+    let Some(value) = api.storage().at(hash).fetch_raw(&key).await? else {
+        return Ok(None);
+    };
+    let messages = Vec::<Message>::decode(&mut &value[..])?;
+    let topic = MessageReceipts::topic();
+    let receipts = messages
+        .into_iter()
+        .find(|message| message.sender.is_pallet() && message.destination.path() == &topic)
+        .map(|message| MessageReceipts::decode(&mut &message.payload[..]))
+        .transpose()?;
+    Ok(receipts)
+}
+
+/// The next sequences acknowledged by the message receipts of the best block, empty if the
+/// receipts are disabled
+pub async fn mq_best_receipts(api: &ParachainApi) -> Result<BTreeMap<MessageOrigin, u64>> {
+    let hash = api
+        .rpc()
+        .block_hash(None)
+        .await?
+        .ok_or(Error::BlockHashNotFound)?;
+    if !mq_ack_receipts_enabled(api, hash).await? {
+        return Ok(Default::default());
+    }
+    Ok(mq_receipts_at(api, hash)
+        .await?
+        .map(|receipts| receipts.receipts.into_iter().collect())
+        .unwrap_or_default())
+}
+
 pub fn decode_parachain_heads(head: Vec<u8>) -> Result<Vec<u8>, Error> {
     Decode::decode(&mut head.as_slice()).or(Err(Error::FailedToDecode))
 }
@@ -137,16 +208,38 @@ pub async fn search_suitable_genesis_for_worker(
     let block = get_worker_unregistered_block(api, pubkey, ceil)
         .await
         .context("Failed to search state for worker")?;
+    let genesis = fetch_genesis_at(api, block).await?;
+    Ok((block, genesis))
+}
+
+/// Fetches the state at `block` for a worker to start from, failing if the worker is registered
+/// at that block already, since the pRuntime refuses such a state.
+pub async fn genesis_for_worker_at(
+    api: &ParachainApi,
+    pubkey: &[u8],
+    block: BlockNumber,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if let Some(added_at) = api.worker_added_at(pubkey).await? {
+        if added_at <= block {
+            anyhow::bail!("The worker is registered since block {added_at}");
+        }
+    }
+    fetch_genesis_at(api, block).await
+}
+
+async fn fetch_genesis_at(
+    api: &ParachainApi,
+    block: BlockNumber,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
     let block_hash = api
         .rpc()
         .block_hash(Some(block.into()))
         .await
         .context("Failed to resolve block number")?
         .ok_or_else(|| anyhow::anyhow!("Block number {block} not found"))?;
-    let genesis = fetch_storage_at(api, Some(block_hash))
+    fetch_storage_at(api, Some(block_hash))
         .await
-        .context("Failed to fetch genesis storage")?;
-    Ok((block, genesis))
+        .context("Failed to fetch genesis storage")
 }
 
 async fn get_worker_unregistered_block(
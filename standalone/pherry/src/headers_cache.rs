@@ -4,8 +4,12 @@ use anyhow::{anyhow, Result};
 use codec::{Decode, Encode};
 use phaxt::{BlockNumber, ParachainApi, RelaychainApi};
 use reqwest::Response;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::stream::Stream;
 use log::{debug, error, info, warn};
@@ -436,17 +440,129 @@ pub async fn fetch_genesis_info(
     })
 }
 
+/// The index of a static cache layout, at `index.json` under the base URI.
+///
+/// The static layout lets the cache data be served from an object storage or a CDN, with no cache
+/// server to operate:
+///
+/// ```text
+/// index.json
+/// genesis/<block>.bin               SCALE encoded GenesisBlockInfo
+/// headers/<chunk>.bin               records of BlockInfo
+/// para-headers/<chunk>.bin          records of parachain Header
+/// storage-changes/<chunk>.bin       records of BlockHeaderWithChanges
+/// ```
+///
+/// Chunk `n` holds the records of the blocks `n * chunk_size .. (n + 1) * chunk_size` within the
+/// range of its kind, in the format of [`read_items`], and is named with 9 digits, e.g.
+/// `000001234.bin`. `headers-cache export-static` generates the layout from a cache database.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StaticIndex {
+    pub chunk_size: BlockNumber,
+    pub genesis: Vec<BlockNumber>,
+    pub headers: Option<BlockRange>,
+    pub para_headers: Option<BlockRange>,
+    pub storage_changes: Option<BlockRange>,
+}
+
+/// An inclusive range of blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRange {
+    pub first: BlockNumber,
+    pub last: BlockNumber,
+}
+
+impl BlockRange {
+    pub fn contains(&self, block: BlockNumber) -> bool {
+        self.first <= block && block <= self.last
+    }
+}
+
+pub const STATIC_INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaticKind {
+    Headers,
+    ParaHeaders,
+    StorageChanges,
+}
+
+impl StaticKind {
+    pub fn dir(&self) -> &'static str {
+        match self {
+            StaticKind::Headers => "headers",
+            StaticKind::ParaHeaders => "para-headers",
+            StaticKind::StorageChanges => "storage-changes",
+        }
+    }
+
+    pub fn chunk_path(&self, chunk: BlockNumber) -> String {
+        format!("{}/{chunk:0>9}.bin", self.dir())
+    }
+
+    fn range(&self, index: &StaticIndex) -> Option<BlockRange> {
+        match self {
+            StaticKind::Headers => index.headers,
+            StaticKind::ParaHeaders => index.para_headers,
+            StaticKind::StorageChanges => index.storage_changes,
+        }
+    }
+}
+
+pub fn static_genesis_path(block: BlockNumber) -> String {
+    format!("genesis/{block}.bin")
+}
+
+/// The index is fetched again after this, or when a block beyond it is requested.
+const STATIC_INDEX_TTL: Duration = Duration::from_secs(30);
+/// Number of chunks kept in memory, the syncs read the chunks of each kind sequentially.
+const STATIC_CHUNK_CACHE_SIZE: usize = 6;
+
+type Chunk = Arc<Vec<Vec<u8>>>;
+
+#[derive(Default)]
+struct StaticState {
+    index: Mutex<Option<(Instant, StaticIndex)>>,
+    chunks: Mutex<VecDeque<(StaticKind, BlockNumber, Chunk)>>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Server,
+    Static(Arc<StaticState>),
+}
+
+/// Client of a headers cache.
+///
+/// The URI is either the base URI of a cache server, or the base URI of a static layout (see
+/// [`StaticIndex`]) prefixed with `static+`, e.g. `static+https://cdn.example.com/khala`. An S3
+/// bucket readable by the public can be given as `s3://<bucket>/<prefix>`.
 #[derive(Clone)]
 pub struct Client {
     base_uri: String,
     http_client: reqwest::Client,
+    backend: Backend,
 }
 
 impl Client {
     pub fn new(uri: &str) -> Self {
+        Self::with_http_client(uri, reqwest::Client::new())
+    }
+
+    pub fn with_http_client(uri: &str, http_client: reqwest::Client) -> Self {
+        let (base_uri, backend) = if let Some(base_uri) = uri.strip_prefix("static+") {
+            (base_uri.to_string(), Backend::Static(Default::default()))
+        } else if let Some(path) = uri.strip_prefix("s3://") {
+            let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+            let base_uri = format!("https://{bucket}.s3.amazonaws.com/{prefix}");
+            (base_uri, Backend::Static(Default::default()))
+        } else {
+            (uri.to_string(), Backend::Server)
+        };
         Self {
-            base_uri: uri.to_string(),
-            http_client: reqwest::Client::new(),
+            base_uri: base_uri.trim_end_matches('/').to_string(),
+            http_client,
+            backend,
         }
     }
 
@@ -480,6 +596,11 @@ impl Client {
     }
 
     pub async fn ping(&self) -> Result<()> {
+        if let Backend::Static(state) = &self.backend {
+            let index = self.static_index(state, true).await?;
+            debug!("Pinging static headers cache {}:\n{index:?}", self.base_uri);
+            return Ok(());
+        }
         let url = format!("{}/state", self.base_uri);
         let res = self.request(&url).await?;
         if let Ok(t) = res.text().await {
@@ -494,11 +615,29 @@ impl Client {
     }
 
     pub async fn get_header(&self, block_number: BlockNumber) -> Result<BlockInfo> {
+        if let Backend::Static(state) = &self.backend {
+            let mut headers = self
+                .static_items(state, StaticKind::Headers, block_number, 1, |_| false)
+                .await?;
+            return headers.pop().ok_or_else(|| anyhow!("header not found"));
+        }
         let url = format!("{}/header/{block_number}", self.base_uri);
         self.request_scale(&url).await
     }
 
+    /// The headers from the given block up to the next one with a justification.
     pub async fn get_headers(&self, block_number: BlockNumber) -> Result<Vec<BlockInfo>> {
+        if let Backend::Static(state) = &self.backend {
+            let headers: Vec<BlockInfo> = self
+                .static_items(state, StaticKind::Headers, block_number, 10000, |info| {
+                    info.justification.is_some()
+                })
+                .await?;
+            if !matches!(headers.last(), Some(info) if info.justification.is_some()) {
+                anyhow::bail!("No justification after block {block_number} yet");
+            }
+            return Ok(headers);
+        }
         let url = format!("{}/headers/{block_number}", self.base_uri);
         self.request_scale(&url).await
     }
@@ -508,6 +647,14 @@ impl Client {
         start_number: BlockNumber,
         count: BlockNumber,
     ) -> Result<Vec<Header>> {
+        if let Backend::Static(state) = &self.backend {
+            let headers = self
+                .static_items(state, StaticKind::ParaHeaders, start_number, count, |_| {
+                    false
+                })
+                .await?;
+            return expect_count(headers, start_number, count);
+        }
         let url = format!("{}/parachain-headers/{start_number}/{count}", self.base_uri);
         self.request_scale(&url).await
     }
@@ -517,12 +664,126 @@ impl Client {
         start_number: BlockNumber,
         count: BlockNumber,
     ) -> Result<Vec<BlockHeaderWithChanges>> {
+        if let Backend::Static(state) = &self.backend {
+            let changes = self
+                .static_items(
+                    state,
+                    StaticKind::StorageChanges,
+                    start_number,
+                    count,
+                    |_| false,
+                )
+                .await?;
+            return expect_count(changes, start_number, count);
+        }
         let url = format!("{}/storage-changes/{start_number}/{count}", self.base_uri);
         self.request_scale(&url).await
     }
 
     pub async fn get_genesis(&self, block_number: BlockNumber) -> Result<GenesisBlockInfo> {
-        let url = format!("{}/genesis/{block_number}", self.base_uri);
+        let url = match &self.backend {
+            Backend::Server => format!("{}/genesis/{block_number}", self.base_uri),
+            Backend::Static(_) => {
+                format!("{}/{}", self.base_uri, static_genesis_path(block_number))
+            }
+        };
         self.request_scale(&url).await
     }
+
+    async fn static_index(&self, state: &StaticState, refresh: bool) -> Result<StaticIndex> {
+        if !refresh {
+            if let Some((fetched_at, index)) = &*state.index.lock().unwrap() {
+                if fetched_at.elapsed() < STATIC_INDEX_TTL {
+                    return Ok(index.clone());
+                }
+            }
+        }
+        let url = format!("{}/{STATIC_INDEX_FILE}", self.base_uri);
+        let body = self.request(&url).await?.bytes().await?;
+        let index: StaticIndex = serde_json::from_slice(&body)?;
+        if index.chunk_size == 0 {
+            anyhow::bail!("Invalid static cache index, chunk_size is 0");
+        }
+        *state.index.lock().unwrap() = Some((Instant::now(), index.clone()));
+        Ok(index)
+    }
+
+    async fn static_chunk(
+        &self,
+        state: &StaticState,
+        kind: StaticKind,
+        chunk: BlockNumber,
+    ) -> Result<Chunk> {
+        let cached = state
+            .chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(k, n, _)| *k == kind && *n == chunk)
+            .map(|(_, _, records)| records.clone());
+        if let Some(records) = cached {
+            return Ok(records);
+        }
+        let url = format!("{}/{}", self.base_uri, kind.chunk_path(chunk));
+        let body = self.request(&url).await?.bytes().await?;
+        let mut records = vec![];
+        read_items(&body[..], |record| {
+            records.push(record.payload().to_vec());
+            Ok(false)
+        })?;
+        let records = Arc::new(records);
+        let mut chunks = state.chunks.lock().unwrap();
+        if chunks.len() >= STATIC_CHUNK_CACHE_SIZE {
+            chunks.pop_front();
+        }
+        chunks.push_back((kind, chunk, records.clone()));
+        Ok(records)
+    }
+
+    /// Reads up to `count` items from the static layout, stopping at the end of the range or after
+    /// the item `is_last` returns true for.
+    async fn static_items<T: Decode>(
+        &self,
+        state: &StaticState,
+        kind: StaticKind,
+        start: BlockNumber,
+        count: BlockNumber,
+        is_last: impl Fn(&T) -> bool,
+    ) -> Result<Vec<T>> {
+        let mut index = self.static_index(state, false).await?;
+        if !matches!(kind.range(&index), Some(range) if range.contains(start)) {
+            index = self.static_index(state, true).await?;
+        }
+        let end = start.saturating_add(count.saturating_sub(1));
+        let range = kind
+            .range(&index)
+            .filter(|range| range.contains(start))
+            .ok_or_else(|| anyhow!("Block {start} not found in the static {}", kind.dir()))?;
+        let mut items = vec![];
+        for block in start..=end.min(range.last) {
+            let chunk = block / index.chunk_size;
+            let chunk_start = (chunk * index.chunk_size).max(range.first);
+            let records = self.static_chunk(state, kind, chunk).await?;
+            let record = records
+                .get((block - chunk_start) as usize)
+                .ok_or_else(|| anyhow!("Block {block} missing in chunk {chunk}"))?;
+            let item = T::decode(&mut &record[..])?;
+            let last = is_last(&item);
+            items.push(item);
+            if last {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn expect_count<T>(items: Vec<T>, start: BlockNumber, count: BlockNumber) -> Result<Vec<T>> {
+    if items.len() < count as usize {
+        anyhow::bail!(
+            "Only {} of {count} blocks from {start} in the static cache",
+            items.len()
+        );
+    }
+    Ok(items)
 }
@@ -17,7 +17,7 @@ use phaxt::{
     rpc::ExtraRpcExt as _,
     sp_core::{crypto::Pair, sr25519},
     subxt::{self, tx::TxPayload},
-    RpcClient,
+    FinalityStream, RpcClient,
 };
 use sp_consensus_grandpa::SetId;
 use subxt::config::{substrate::Era, Header as _};
@@ -26,6 +26,9 @@ pub use authority::get_authority_with_proof_at;
 pub use authority::verify_with_prev_authority_set;
 
 mod authority;
+mod batch_tuner;
+mod bench;
+mod block_cache;
 mod endpoint;
 mod error;
 mod msg_sync;
@@ -36,13 +39,14 @@ pub mod chain_client;
 pub mod headers_cache;
 pub mod types;
 
+use crate::batch_tuner::BatchSizeTuner;
 use crate::error::Error;
 use crate::types::{
     Block, BlockNumber, ConvertTo, Hash, Header, NotifyReq, NumberOrHex, ParachainApi, PrClient,
     RelaychainApi, SrSigner, SyncOperation,
 };
 use phactory_api::blocks::{
-    self, BlockHeader, BlockHeaderWithChanges, HeaderToSync, StorageProof,
+    self, BlockHeader, BlockHeaderWithChanges, ConsensusLog, HeaderToSync, StorageProof,
 };
 use phactory_api::prpc::{self, InitRuntimeResponse, PhactoryInfo};
 use phactory_api::pruntime_client;
@@ -53,7 +57,24 @@ use msg_sync::{Error as MsgSyncError, Receiver, Sender};
 use notify_client::NotifyClient;
 use phala_types::{AttestationProvider, AttestationReport, Collateral};
 
-pub use phaxt::connect as subxt_connect;
+/// Connects to a substrate node, logging the state changes of the connection.
+pub async fn subxt_connect(uri: &str) -> Result<phaxt::ChainApi> {
+    subxt_connect_with_profile(uri, Default::default()).await
+}
+
+/// Like [`subxt_connect`], for the chain described by `profile`.
+pub async fn subxt_connect_with_profile(
+    uri: &str,
+    profile: phaxt::ChainProfile,
+) -> Result<phaxt::ChainApi> {
+    let keep_alive = phaxt::KeepAlive {
+        on_event: Some(std::sync::Arc::new(|uri, event| {
+            warn!("Connection to {uri}: {event}");
+        })),
+        ..Default::default()
+    };
+    phaxt::connect_with_profile(uri, keep_alive, profile).await
+}
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -115,6 +136,20 @@ pub struct Args {
     )]
     parachain_ws_endpoint: String,
 
+    #[arg(
+        default_value = "kusama",
+        long,
+        help = "Profile of the relaychain, one of the named chain profiles or a JSON file"
+    )]
+    relaychain_profile: String,
+
+    #[arg(
+        default_value = "khala",
+        long,
+        help = "Profile of the parachain (or the solo chain), one of the named chain profiles or a JSON file"
+    )]
+    parachain_profile: String,
+
     #[arg(
         default_value = "http://localhost:8000",
         long,
@@ -153,6 +188,28 @@ pub struct Args {
     )]
     sync_blocks: BlockNumber,
 
+    /// Adjust the number of blocks per dispatch automatically, starting from --sync-blocks.
+    #[arg(long)]
+    auto_tune_sync_blocks: bool,
+
+    /// The minimum number of blocks per dispatch when --auto-tune-sync-blocks is enabled.
+    #[arg(long, default_value = "1")]
+    min_sync_blocks: BlockNumber,
+
+    /// The maximum number of blocks per dispatch when --auto-tune-sync-blocks is enabled.
+    #[arg(long, default_value = "100")]
+    max_sync_blocks: BlockNumber,
+
+    /// The expected time in ms for pRuntime to process one dispatch when
+    /// --auto-tune-sync-blocks is enabled.
+    #[arg(long, default_value = "3000")]
+    sync_blocks_target_ms: u64,
+
+    /// Shrink the dispatch batch when the free memory reported by pRuntime goes below this
+    /// value in MB. Only used with --auto-tune-sync-blocks.
+    #[arg(long, default_value = "256")]
+    sync_blocks_min_free_memory_mb: u64,
+
     #[arg(
         long = "operator",
         help = "The operator account to set the miner for the worker."
@@ -171,10 +228,17 @@ pub struct Args {
     #[arg(long, help = "Don't wait the substrate nodes to sync blocks")]
     no_wait: bool,
 
+    #[arg(
+        default_value = "32",
+        long,
+        help = "Max number of relaychain blocks to look ahead of a justified block for the inclusion of a parachain block. 0 for disabled"
+    )]
+    inclusion_lookahead: BlockNumber,
+
     #[arg(
         default_value = "5000",
         long,
-        help = "(Debug only) Set the wait block duration in ms"
+        help = "(Debug only) Set the max duration in ms to wait for a new finalized block at the chain tip"
     )]
     dev_wait_block_ms: u64,
 
@@ -212,10 +276,26 @@ pub struct Args {
     #[arg(long, help = "Restart if number of rpc errors reaches the threshold")]
     restart_on_rpc_error_threshold: Option<u64>,
 
-    #[arg(long, help = "URI to fetch cached headers from")]
+    #[arg(
+        long,
+        help = "URI to fetch cached headers from. A static cache layout can be given as static+<uri> or s3://<bucket>/<prefix>"
+    )]
     #[arg(default_value = "")]
     headers_cache_uri: String,
 
+    #[arg(
+        long,
+        help = "Directory to cache the fetched parachain headers and storage changes in, shared by the pherry instances on the host"
+    )]
+    local_block_cache_dir: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 4096,
+        help = "Max size in MB of the local block cache, the least recently used blocks are evicted beyond it"
+    )]
+    local_block_cache_size_mb: u64,
+
     #[arg(long, help = "Stop when synced to given parachain block")]
     #[arg(default_value_t = BlockNumber::MAX)]
     to_block: BlockNumber,
@@ -245,6 +325,12 @@ pub struct Args {
     #[arg(long)]
     prefer_genesis_at_block: Option<BlockNumber>,
 
+    /// Initialize a new pRuntime from a recent chain state instead of the genesis. The relay
+    /// chain headers are synced from the block the state was taken at, the state is validated
+    /// against the parachain header once it is synced. Parachain only.
+    #[arg(long, requires = "parachain", conflicts_with = "start_header")]
+    fast_bootstrap: bool,
+
     /// Load handover proof after blocks synced.
     #[arg(long)]
     load_handover_proof: bool,
@@ -256,6 +342,17 @@ pub struct Args {
     /// Timeout in seconds for connecting to PCCS server.
     #[arg(long, default_value = "30")]
     pccs_timeout: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Measure the block sync throughput over a block range and exit.
+    ///
+    /// The endpoints and --sync-blocks are taken from the main options.
+    Bench(bench::BenchArgs),
 }
 
 #[derive(clap::ValueEnum, Clone, Copy, Debug)]
@@ -279,6 +376,8 @@ struct RunningFlags {
     worker_registered: bool,
     endpoint_registered: bool,
     restart_failure_count: u32,
+    /// Kept across restarts, so that a failed dispatch results in a smaller batch next time.
+    dispatch_batch: BatchSizeTuner,
 }
 
 pub struct BlockSyncState {
@@ -361,6 +460,60 @@ pub async fn fetch_storage_changes_with_root_or_not(
     if to < from {
         return Ok(vec![]);
     }
+    let Some(local_cache) = block_cache::get() else {
+        return fetch_storage_changes_uncached(client, cache, from, to, with_root).await;
+    };
+    let kind = if with_root {
+        block_cache::Kind::StorageChangesWithRoot
+    } else {
+        block_cache::Kind::StorageChanges
+    };
+    let cached: Option<Vec<BlockHeaderWithChanges>> = (from..=to)
+        .map(|number| local_cache.get_at(kind, number))
+        .collect();
+    if let Some(changes) = cached {
+        // The entries are keyed by number, only the hash of the last block is needed to check
+        // that they are on the chain.
+        let to_hash = get_header_hash(client, Some(to)).await?;
+        if chains_up_to(&changes, from, to_hash) {
+            log::info!(
+                "Got {} storage changes from local cache ({from}-{to})",
+                changes.len()
+            );
+            return Ok(changes);
+        }
+        warn!("Storage changes in local cache ({from}-{to}) are not on the chain, refetching");
+    }
+    let changes = fetch_storage_changes_uncached(client, cache, from, to, with_root).await?;
+    if changes.len() as BlockNumber == to - from + 1 {
+        for (number, changes) in (from..).zip(&changes) {
+            local_cache.put_at(kind, number, changes);
+        }
+    }
+    Ok(changes)
+}
+
+/// Whether the headers of `changes` are the consecutive blocks from `from` linked by their parent
+/// hashes up to the block hashed `to_hash`.
+fn chains_up_to(changes: &[BlockHeaderWithChanges], from: BlockNumber, to_hash: Hash) -> bool {
+    let mut expected = to_hash;
+    for (i, change) in changes.iter().enumerate().rev() {
+        let header = &change.block_header;
+        if header.number != from + i as BlockNumber || header.hash() != expected {
+            return false;
+        }
+        expected = header.parent_hash;
+    }
+    true
+}
+
+async fn fetch_storage_changes_uncached(
+    client: &RpcClient,
+    cache: Option<&CacheClient>,
+    from: BlockNumber,
+    to: BlockNumber,
+    with_root: bool,
+) -> Result<Vec<BlockHeaderWithChanges>> {
     if let Some(cache) = cache {
         let count = to + 1 - from;
         if let Ok(changes) = cache.get_storage_changes(from, count).await {
@@ -426,7 +579,7 @@ pub async fn batch_sync_storage_changes(
     cache: Option<&CacheClient>,
     from: BlockNumber,
     to: BlockNumber,
-    batch_size: BlockNumber,
+    tuner: &mut BatchSizeTuner,
 ) -> Result<()> {
     info!(
         "batch syncing from {from} to {to} ({} blocks)",
@@ -435,11 +588,26 @@ pub async fn batch_sync_storage_changes(
 
     let mut fetcher = prefetcher::PrefetchClient::new();
 
-    for from in (from..=to).step_by(batch_size as _) {
-        let to = to.min(from.saturating_add(batch_size - 1));
+    let mut next = from;
+    while next <= to {
+        let from = next;
+        let to = to.min(from.saturating_add(tuner.size() - 1));
         let storage_changes = fetcher.fetch_storage_changes(api, cache, from, to).await?;
-        let r = req_dispatch_block(pr, storage_changes).await?;
+        let start = std::time::Instant::now();
+        let r = match req_dispatch_block(pr, storage_changes).await {
+            Ok(r) => r,
+            Err(err) => {
+                tuner.on_failed();
+                return Err(err);
+            }
+        };
         log::debug!("  ..dispatch_block: {:?}", r);
+        if !tuner.is_fixed() {
+            let elapsed = start.elapsed();
+            let memory = pr.get_info(()).await?.memory_usage;
+            tuner.on_dispatched(to + 1 - from, elapsed, memory.as_ref());
+        }
+        next = to + 1;
     }
     Ok(())
 }
@@ -449,10 +617,12 @@ async fn try_load_handover_proof(pr: &PrClient, api: &ParachainApi) -> Result<()
     if info.safe_mode_level < 2 {
         return Ok(());
     }
-    if info.blocknum == 0 {
+    // The blocks held back by the dispatch lag are not in the state yet.
+    let dispatched_blocknum = info.blocknum.saturating_sub(info.lagged_blocks);
+    if dispatched_blocknum == 0 {
         return Ok(());
     }
-    let current_block = info.blocknum - 1;
+    let current_block = dispatched_blocknum - 1;
     let hash = get_header_hash(api, Some(current_block)).await?;
     let proof = chain_client::read_proofs(
         api,
@@ -579,15 +749,25 @@ pub async fn get_headers(
     api: &RelaychainApi,
     from: BlockNumber,
 ) -> Result<Vec<HeaderToSync>> {
-    let first_header = get_header_at(api, Some(from)).await?;
-    let mut headers = vec![
-        HeaderToSync {
-            header: first_header.0.clone(), 
-            justification: None
-        },
-    ];
+    get_headers_until(api, from, from).await
+}
+
+/// Gets the relaychain headers from `from` to the first justified block at or after `to`.
+pub async fn get_headers_until(
+    api: &RelaychainApi,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<Vec<HeaderToSync>> {
+    let mut headers = vec![];
+    for number in from..=to {
+        let header = get_header_at(api, Some(number)).await?.0;
+        headers.push(HeaderToSync {
+            header,
+            justification: None,
+        });
+    }
 
-    let encoded_finality_proof = prove_finality_at(api, from).await?;
+    let encoded_finality_proof = prove_finality_at(api, to).await?;
     let finality_proof : FinalityProof<Header> = Decode::decode(&mut encoded_finality_proof.as_slice())?;
     headers.extend(
         finality_proof.unknown_headers
@@ -604,17 +784,127 @@ pub async fn get_headers(
     Ok(headers)
 }
 
+/// The parachain block the synced relaychain headers should include.
+struct InclusionTarget {
+    para_id: u32,
+    next_para_headernum: BlockNumber,
+    lookahead: BlockNumber,
+}
+
+async fn para_head_number_at(
+    api: &RelaychainApi,
+    para_id: u32,
+    hash: Hash,
+) -> Result<Option<BlockNumber>> {
+    let key = api.paras_heads_key(para_id)?;
+    let Some(raw_header) = api.rpc().storage(&key, Some(hash)).await? else {
+        return Ok(None);
+    };
+    let header = chain_client::decode_parachain_header(raw_header.0)?;
+    Ok(Some(header.number))
+}
+
+/// Looks ahead of a justified block for the relaychain block including the next parachain block.
+///
+/// The relaychain headers are synced up to a justified block. When the inclusion of the parachain
+/// blocks lags, e.g. during relay congestion, the parachain head at that block may already be
+/// synced to pRuntime, which can't advance until another batch of headers is synced. If a
+/// candidate of the parachain is pending availability there, the following blocks are scanned
+/// for the one where it gets included, so that the headers are synced up to it at once.
+async fn find_inclusion_block(
+    api: &RelaychainApi,
+    justified: BlockNumber,
+    target: &InclusionTarget,
+) -> Result<Option<BlockNumber>> {
+    let hash = get_header_hash(api, Some(justified)).await?;
+    let para_number = para_head_number_at(api, target.para_id, hash).await?;
+    if para_number.unwrap_or(0) >= target.next_para_headernum {
+        return Ok(None);
+    }
+    let pending_key = api.para_pending_availability_key(target.para_id)?;
+    if api.rpc().storage(&pending_key, Some(hash)).await?.is_none() {
+        // Nothing to be included, the headers ahead won't help.
+        return Ok(None);
+    }
+    let finalized = get_header_at(api, None).await?.0.number;
+    let end = finalized.min(justified.saturating_add(target.lookahead));
+    for number in justified + 1..=end {
+        let hash = get_header_hash(api, Some(number)).await?;
+        let para_number = para_head_number_at(api, target.para_id, hash).await?;
+        if para_number.unwrap_or(0) >= target.next_para_headernum {
+            info!(
+                "parachain block {} included at relaychain block {number}, {} blocks after the justified one",
+                target.next_para_headernum,
+                number - justified
+            );
+            return Ok(Some(number));
+        }
+    }
+    info!(
+        "parachain block {} not included within {} blocks after {justified}",
+        target.next_para_headernum,
+        end - justified
+    );
+    Ok(None)
+}
+
+/// Whether a header, other than the last one of a batch, changes the GRANDPA authority set.
+///
+/// pRuntime only enacts the authority set changes of the justified header ending a batch, so a
+/// change in the middle of a batch would leave it verifying the following justifications with the
+/// old set.
+fn changes_authorities_within(headers: &[HeaderToSync]) -> bool {
+    let Some((_last, rest)) = headers.split_last() else {
+        return false;
+    };
+    rest.iter().any(|h| {
+        h.header.digest.logs().iter().any(|log| {
+            matches!(
+                log.consensus_try_to::<ConsensusLog<BlockNumber>>(&GRANDPA_ENGINE_ID),
+                Some(ConsensusLog::ScheduledChange(_) | ConsensusLog::ForcedChange(..))
+            )
+        })
+    })
+}
+
 async fn sync_headers(
     pr: &PrClient,
     api: &RelaychainApi,
     from: BlockNumber,
+    inclusion: Option<InclusionTarget>,
 ) -> Result<()> {
     let headers = get_headers(api, from).await?;
-
-    info!("sending a batch of {} headers (last: {})", headers.len(), headers.last().unwrap().header.number);
+    let justified = headers.last().unwrap().header.number;
+    info!(
+        "sending a batch of {} headers (last: {})",
+        headers.len(),
+        justified
+    );
     let relay_synced_to = req_sync_header(pr, headers).await?;
     info!("  ..sync_header: {:?}", relay_synced_to);
 
+    let Some(target) = inclusion else {
+        return Ok(());
+    };
+    let Some(to) = find_inclusion_block(api, justified, &target).await? else {
+        return Ok(());
+    };
+    let ahead = get_headers_until(api, justified + 1, to).await?;
+    if changes_authorities_within(&ahead) {
+        info!(
+            "authority set changes within {}..={to}, leaving them to the next rounds",
+            justified + 1
+        );
+        return Ok(());
+    }
+    info!(
+        "sending {} headers ahead (last: {})",
+        ahead.len(),
+        ahead.last().unwrap().header.number
+    );
+    let relay_synced_to = req_sync_header(pr, ahead).await?;
+    info!("  ..sync_header: {:?}", relay_synced_to);
+
     Ok(())
 }
 
@@ -646,12 +936,23 @@ pub async fn get_parachain_headers(
                     return Ok(para_headers);
                 }
             };
-            let header = para_api
+            let local_cache = block_cache::get();
+            if let Some(header) =
+                local_cache.and_then(|c| c.get(block_cache::Kind::ParachainHeader, &hash))
+            {
+                para_headers.push(header);
+                continue;
+            }
+            let header: Header = para_api
                 .rpc()
                 .header(Some(hash))
                 .await?
-                .ok_or(Error::BlockNotFound)?;
-            para_headers.push(header.convert_to());
+                .ok_or(Error::BlockNotFound)?
+                .convert_to();
+            if let Some(local_cache) = local_cache {
+                local_cache.put(block_cache::Kind::ParachainHeader, &hash, &header);
+            }
+            para_headers.push(header);
         }
     } else {
         info!("Got {} parachain headers from cache", para_headers.len());
@@ -704,6 +1005,25 @@ async fn resolve_start_header(
     Ok((number - 1) as BlockNumber)
 }
 
+/// Resolves the parachain block to bootstrap from and the relay chain block to start at.
+///
+/// The state is loaded at the returned parachain block. Starting right before its relay parent
+/// ensures the relay chain headers synced afterwards prove the header of the loaded block.
+async fn resolve_bootstrap_point(
+    para_api: &ParachainApi,
+    prefer: Option<BlockNumber>,
+) -> Result<(BlockNumber, BlockNumber)> {
+    let para_block = match prefer {
+        Some(block) => block,
+        None => para_api.latest_finalized_block_number().await?,
+    };
+    let relay_parent = para_api
+        .relay_parent_number_at(para_block)
+        .await
+        .context("Failed to resolve the relay parent of the bootstrap block")?;
+    Ok((para_block, relay_parent.saturating_sub(1)))
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn init_runtime(
     cache: &Option<CacheClient>,
@@ -849,10 +1169,20 @@ async fn try_register_worker(
     }
 }
 
-async fn try_load_chain_state(pr: &PrClient, para_api: &ParachainApi, args: &Args) -> Result<()> {
+/// Loads the chain state into the pRuntime, at `bootstrap_at` exactly if given, otherwise at the
+/// latest block before the worker was registered, no later than `prefer`.
+async fn try_load_chain_state(
+    pr: &PrClient,
+    para_api: &ParachainApi,
+    prefer: Option<BlockNumber>,
+    bootstrap_at: Option<BlockNumber>,
+) -> Result<()> {
     let info = pr.get_info(()).await?;
     info!("info: {info:#?}");
     if !info.can_load_chain_state {
+        if bootstrap_at.is_some() {
+            return Err(anyhow!("pRuntime can not load chain state"));
+        }
         return Ok(());
     }
     let Some(pubkey) = &info.public_key else {
@@ -861,13 +1191,23 @@ async fn try_load_chain_state(pr: &PrClient, para_api: &ParachainApi, args: &Arg
     let Ok(pubkey) = hex::decode(pubkey) else {
         return Err(anyhow!("pRuntime returned an invalid pubkey"));
     };
-    let (block_number, state) = chain_client::search_suitable_genesis_for_worker(
-        para_api,
-        &pubkey,
-        args.prefer_genesis_at_block,
-    )
-    .await
-    .context("Failed to search suitable genesis state for worker")?;
+    let (block_number, state) = match bootstrap_at {
+        // The relay chain headers are synced from this block on, an earlier state could not be
+        // validated.
+        Some(block) => {
+            let state = chain_client::genesis_for_worker_at(para_api, &pubkey, block)
+                .await
+                .context("Failed to fetch the bootstrap state for worker")?;
+            (block, state)
+        }
+        None => chain_client::search_suitable_genesis_for_worker(para_api, &pubkey, prefer)
+            .await
+            .context("Failed to search suitable genesis state for worker")?,
+    };
+    info!(
+        "Loading chain state at block {block_number}, {} keys",
+        state.len()
+    );
     pr.load_chain_state(prpc::ChainState::new(block_number, state))
         .await?;
     Ok(())
@@ -891,6 +1231,36 @@ async fn wait_until_synced(client: &phaxt::RpcClient) -> Result<()> {
     }
 }
 
+/// Waits until the relaychain finalizes a new block, for at most `timeout`.
+async fn wait_for_finalized_head(
+    api: &RelaychainApi,
+    heads: &mut Option<FinalityStream>,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let stream = match heads {
+            Some(stream) => stream,
+            None => heads.insert(FinalityStream::subscribe(api).await?),
+        };
+        let last = stream.finalized().map(|head| head.number);
+        match tokio::time::timeout_at(deadline, stream.next()).await {
+            // Nothing finalized in time, check the pRuntime anyway.
+            Err(_) => return Ok(()),
+            Ok(None) => {
+                warn!("Finalized heads subscription closed, resubscribing");
+                *heads = None;
+            }
+            Ok(Some(Err(err))) => return Err(err),
+            Ok(Some(Ok(update))) => {
+                if Some(update.finalized.number) != last {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
 async fn get_sync_operation(
     relay_api: &RelaychainApi,
     para_api: &ParachainApi,
@@ -947,7 +1317,10 @@ async fn bridge(
 ) -> Result<()> {
     // Connect to substrate
 
-    let api: RelaychainApi = subxt_connect(&args.relaychain_ws_endpoint).await?;
+    let relay_profile = phaxt::ChainProfile::resolve(&args.relaychain_profile)?;
+    let para_profile = phaxt::ChainProfile::resolve(&args.parachain_profile)?;
+    let api: RelaychainApi =
+        subxt_connect_with_profile(&args.relaychain_ws_endpoint, relay_profile).await?;
     info!(
         "Connected to relaychain at: {}",
         args.relaychain_ws_endpoint
@@ -958,7 +1331,7 @@ async fn bridge(
     } else {
         &args.relaychain_ws_endpoint
     };
-    let para_api: ParachainApi = subxt_connect(para_uri).await?;
+    let para_api: ParachainApi = subxt_connect_with_profile(para_uri, para_profile).await?;
     info!("Connected to parachain node at: {para_uri}");
 
     if !args.no_wait {
@@ -975,6 +1348,10 @@ async fn bridge(
     } else {
         None
     };
+    if let Some(dir) = &args.local_block_cache_dir {
+        block_cache::init(dir, args.local_block_cache_size_mb)
+            .context("Failed to open the local block cache")?;
+    }
 
     // Other initialization
     let pr = pruntime_client::new_pruntime_client(args.pruntime_endpoint.clone());
@@ -999,8 +1376,18 @@ async fn bridge(
     if !args.no_init {
         if !info.initialized {
             info!("pRuntime not initialized. Requesting init...");
-            let start_header =
-                resolve_start_header(&para_api, args.parachain, args.start_header).await?;
+            let bootstrap_at = if args.fast_bootstrap {
+                let (para_block, relay_block) =
+                    resolve_bootstrap_point(&para_api, args.prefer_genesis_at_block).await?;
+                info!("Bootstrapping from parachain block {para_block}, relay block {relay_block}");
+                Some((para_block, relay_block))
+            } else {
+                None
+            };
+            let start_header = match bootstrap_at {
+                Some((_, relay_block)) => relay_block,
+                None => resolve_start_header(&para_api, args.parachain, args.start_header).await?,
+            };
             info!("Resolved start header at {}", start_header);
             let runtime_info = init_runtime(
                 &cache_client,
@@ -1029,6 +1416,11 @@ async fn bridge(
             .await
             .ok();
             info!("runtime_info: {:?}", runtime_info);
+            if let Some((para_block, _)) = bootstrap_at {
+                try_load_chain_state(&pr, &para_api, None, Some(para_block))
+                    .await
+                    .context("Failed to bootstrap from the chain state")?;
+            }
         } else {
             info!("pRuntime already initialized.");
             // STATUS: pruntime_initialized = true
@@ -1047,7 +1439,7 @@ async fn bridge(
         }
 
         if args.fast_sync {
-            try_load_chain_state(&pr, &para_api, args).await?;
+            try_load_chain_state(&pr, &para_api, args.prefer_genesis_at_block, None).await?;
         }
     }
 
@@ -1073,10 +1465,23 @@ async fn bridge(
         return Ok(());
     }
 
+    let para_id = if args.parachain {
+        Some(para_api.get_paraid(None).await?)
+    } else {
+        None
+    };
+    // Subscribed once the chain tip is reached, the headers would pile up during the initial sync.
+    let mut finalized_heads = None;
     loop {
         // update the latest pRuntime state
         let info = pr.get_info(()).await?;
         info!("pRuntime get_info response: {:#?}", info);
+        if info.lagged_blocks > 0 {
+            info!(
+                "pRuntime holds {} blocks back for its dispatch lag of {}",
+                info.lagged_blocks, info.dispatch_lag
+            );
+        }
         if info.blocknum >= args.to_block {
             info!("Reached target block: {}", args.to_block);
             return Ok(());
@@ -1103,7 +1508,15 @@ async fn bridge(
         ).await?;
         match sync_operation {
             SyncOperation::RelaychainHeader => {
-                sync_headers(&pr, &api, info.headernum).await?;
+                let inclusion = match para_id {
+                    Some(para_id) if args.inclusion_lookahead > 0 => Some(InclusionTarget {
+                        para_id,
+                        next_para_headernum: info.para_headernum,
+                        lookahead: args.inclusion_lookahead,
+                    }),
+                    _ => None,
+                };
+                sync_headers(&pr, &api, info.headernum, inclusion).await?;
             },
             SyncOperation::CachedRelaychainHeader(cached_headers) => {
                 sync_with_cached_headers(&pr, cached_headers).await?;
@@ -1131,7 +1544,7 @@ async fn bridge(
                     cache_client.as_ref(),
                     info.blocknum,
                     next_headernum - 1,
-                    args.sync_blocks,
+                    &mut flags.dispatch_batch,
                 )
                 .await?;
             },
@@ -1196,7 +1609,12 @@ async fn bridge(
                     handover_worker_key(&pr, &next_pr).await?;
                 }
 
-                sleep(Duration::from_millis(args.dev_wait_block_ms)).await;
+                wait_for_finalized_head(
+                    &api,
+                    &mut finalized_heads,
+                    Duration::from_millis(args.dev_wait_block_ms),
+                )
+                .await?;
                 continue;
             },
         };
@@ -1298,10 +1716,29 @@ pub async fn pherry_main() {
     let mut args = Args::parse();
     preprocess_args(&mut args);
 
+    if let Some(Command::Bench(bench_args)) = &args.command {
+        if let Err(err) = bench::run(&args, bench_args).await {
+            error!("Benchmark failed: {err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut flags = RunningFlags {
         worker_registered: false,
         endpoint_registered: false,
         restart_failure_count: 0,
+        dispatch_batch: if args.auto_tune_sync_blocks {
+            BatchSizeTuner::new(
+                args.sync_blocks,
+                args.min_sync_blocks,
+                args.max_sync_blocks,
+                Duration::from_millis(args.sync_blocks_target_ms),
+                args.sync_blocks_min_free_memory_mb * 1024 * 1024,
+            )
+        } else {
+            BatchSizeTuner::fixed(args.sync_blocks)
+        },
     };
 
     loop {
@@ -1352,3 +1789,115 @@ async fn handover_worker_key(server: &PrClient, client: &PrClient) -> Result<()>
     client.handover_receive(encrypted_key).await?;
     panic!("Worker key handover done, the new pRuntime is ready to go");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sp_consensus_grandpa::ScheduledChange;
+    use sp_runtime::{generic::Digest, DigestItem};
+
+    fn header_to_sync(number: BlockNumber, log: Option<ConsensusLog<BlockNumber>>) -> HeaderToSync {
+        let mut digest = Digest::default();
+        if let Some(log) = log {
+            digest.push(DigestItem::Consensus(GRANDPA_ENGINE_ID, log.encode()));
+        }
+        HeaderToSync {
+            header: Header {
+                parent_hash: Default::default(),
+                number,
+                state_root: Default::default(),
+                extrinsics_root: Default::default(),
+                digest,
+            },
+            justification: None,
+        }
+    }
+
+    fn scheduled_change() -> ConsensusLog<BlockNumber> {
+        ConsensusLog::ScheduledChange(ScheduledChange {
+            next_authorities: vec![],
+            delay: 0,
+        })
+    }
+
+    fn forced_change() -> ConsensusLog<BlockNumber> {
+        ConsensusLog::ForcedChange(
+            0,
+            ScheduledChange {
+                next_authorities: vec![],
+                delay: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn authority_changes_within_a_batch_are_detected() {
+        for change in [scheduled_change(), forced_change()] {
+            let headers = vec![
+                header_to_sync(10, None),
+                header_to_sync(11, Some(change)),
+                header_to_sync(12, None),
+            ];
+            assert!(changes_authorities_within(&headers));
+        }
+    }
+
+    #[test]
+    fn authority_changes_ending_a_batch_are_allowed() {
+        let headers = vec![
+            header_to_sync(10, None),
+            header_to_sync(11, None),
+            header_to_sync(12, Some(scheduled_change())),
+        ];
+        assert!(!changes_authorities_within(&headers));
+        assert!(!changes_authorities_within(&[]));
+    }
+
+    #[test]
+    fn other_grandpa_logs_are_ignored() {
+        let headers = vec![
+            header_to_sync(10, Some(ConsensusLog::OnDisabled(0))),
+            header_to_sync(11, Some(ConsensusLog::Pause(0))),
+            header_to_sync(12, None),
+        ];
+        assert!(!changes_authorities_within(&headers));
+    }
+
+    fn chain_of_changes(from: BlockNumber, count: u32) -> Vec<BlockHeaderWithChanges> {
+        let mut parent_hash = Hash::repeat_byte(1);
+        (from..from + count)
+            .map(|number| {
+                let block_header = Header {
+                    parent_hash,
+                    number,
+                    state_root: Default::default(),
+                    extrinsics_root: Default::default(),
+                    digest: Default::default(),
+                };
+                parent_hash = block_header.hash();
+                BlockHeaderWithChanges {
+                    block_header,
+                    storage_changes: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cached_changes_must_chain_up_to_the_last_hash() {
+        let changes = chain_of_changes(10, 3);
+        let to_hash = changes[2].block_header.hash();
+        assert!(chains_up_to(&changes, 10, to_hash));
+        assert!(!chains_up_to(&changes, 10, Hash::zero()));
+        assert!(!chains_up_to(&changes, 11, to_hash));
+    }
+
+    #[test]
+    fn cached_changes_of_a_fork_are_rejected() {
+        let mut changes = chain_of_changes(10, 3);
+        let to_hash = changes[2].block_header.hash();
+        // An entry of another fork in the middle breaks the parent hash links.
+        changes[1].block_header.state_root = Hash::repeat_byte(2);
+        assert!(!chains_up_to(&changes, 10, to_hash));
+    }
+}
@@ -1,9 +1,9 @@
 use anyhow::Result;
-use log::{error, info};
+use log::{error, info, warn};
 use std::time::Duration;
 
 use crate::{
-    chain_client::{mq_next_sequence, update_signer_nonce},
+    chain_client::{mq_best_receipts, mq_next_sequence, update_signer_nonce},
     types::{ParachainApi, PrClient, SrSigner},
 };
 
@@ -37,13 +37,28 @@ pub async fn maybe_sync_mq_egress(
 
     update_signer_nonce(api, signer).await?;
 
+    // The messages acknowledged in the best block are reported by pRuntime until it syncs the
+    // block. A sender whose messages are all acknowledged needs no sequence query.
+    let acked = mq_best_receipts(api).await.unwrap_or_else(|err| {
+        warn!("Failed to read the message receipts: {err:?}");
+        Default::default()
+    });
+
     let mut sync_msgs_count = 0;
 
     'sync_outer: for (sender, messages) in messages {
         if messages.is_empty() {
             continue;
         }
-        let min_seq = mq_next_sequence(api, &sender).await?;
+        let acked_seq = acked.get(&sender).copied().unwrap_or(0);
+        if messages.iter().all(|message| message.sequence < acked_seq) {
+            info!(
+                "Messages of {} before {} are acknowledged. Skipping...",
+                sender, acked_seq
+            );
+            continue;
+        }
+        let min_seq = mq_next_sequence(api, &sender).await?.max(acked_seq);
 
         info!("Next seq for {} is {}", sender, min_seq);
 
@@ -83,14 +98,11 @@ pub async fn maybe_sync_mq_egress(
                             }
                             Ok(Err(err)) => {
                                 error!("Error submitting message {}: {:?}", msg_info, err);
-                                use phaxt::subxt::{error::RpcError, Error as SubxtError};
-                                let report = match err {
-                                    SubxtError::Rpc(RpcError::ClientError(err)) => {
-                                        if err.to_string().contains("bad signature") {
-                                            Error::BadSignature
-                                        } else {
-                                            Error::OtherRpcError
-                                        }
+                                let report = match phaxt::ChainError::from(&err) {
+                                    phaxt::ChainError::TxPool(rejection)
+                                        if rejection.is_bad_signature() =>
+                                    {
+                                        Error::BadSignature
                                     }
                                     _ => Error::OtherRpcError,
                                 };
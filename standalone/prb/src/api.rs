@@ -1,15 +1,33 @@
+use crate::api_auth::ApiAuth;
+use crate::bus::ChannelSnapshot;
 use crate::cli::{ConfigCommands, WorkerManagerCliArgs};
+use crate::computation_samples::ComputationSample;
 use crate::configurator::api_handler;
+use crate::dead_letters::DeadLetterAction;
+use crate::enclave_identity::EnclaveIdentity;
 use crate::inv_db::Worker;
+use crate::jobs::{Job, JobRequest};
+use crate::maintenance::{MaintenanceStatus, MaintenanceWindow};
+use crate::messages::{release_dead_letter, snapshot_senders, PausedTopic, SenderSnapshot};
+use crate::migration::{DeadLetter, ImportReport, MigrationArchive, MAX_ARCHIVE_SIZE};
+use crate::pool_operator::{PoolOperatorAccess, PoolOperatorForSerialize};
 use crate::processor::WorkerEvent;
+use crate::reconciler::ReconcileReport;
+use crate::request_trace::RequestTraceReport;
+use crate::runtime_upgrade::RuntimeUpgradeStatus;
+use crate::shadow::ShadowReport;
+use crate::support_bundle::SupportBundle;
 use crate::tx::Transaction;
+use crate::upgrade::{UpgradeRequest, UpgradeStatus};
 use crate::wm::WrappedWorkerManagerContext;
 use crate::worker::{WorkerLifecycleCommand, WorkerLifecycleState};
-use axum::extract::State;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
 use axum::http::StatusCode;
+use axum::middleware;
 use axum::response::{IntoResponse, Response};
 use axum::routing::*;
 use axum::{Json, Router};
+use chrono::{DateTime, Utc};
 use futures::future::try_join_all;
 use log::{error, info};
 use phactory_api::prpc::PhactoryInfo;
@@ -19,6 +37,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 
 type AppContext = State<WrappedWorkerManagerContext>;
 
@@ -36,6 +55,21 @@ pub enum ApiError {
     #[error("pool not found: {0}")]
     PoolNotFound(u64),
 
+    #[error("job not found: {0}")]
+    JobNotFound(u64),
+
+    #[error("no maintenance window for pool: {0}")]
+    MaintenanceWindowNotFound(u64),
+
+    #[error("no parked message #{1} of sender {0}")]
+    DeadLetterNotFound(String, u64),
+
+    #[error("no upgrade running")]
+    NoUpgradeRunning,
+
+    #[error("shadow data sources not configured")]
+    ShadowNotConfigured,
+
     #[error("db write failed")]
     WriteFailed,
 
@@ -52,11 +86,13 @@ pub struct WorkerStatus {
     pub phactory_info: Option<PhactoryInfo>,
     pub last_message: String,
     pub session_info: Option<SessionInfo>,
+    #[serde(default)]
+    pub enclave_identity: Option<EnclaveIdentity>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WorkerStatusResponse {
-    workers: Vec<WorkerStatus>,
+    pub workers: Vec<WorkerStatus>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -67,9 +103,109 @@ pub struct TxStatusResponse {
     pub past_txs: Vec<Transaction>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobsResponse {
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnqueueJobResponse {
+    pub id: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WmStatusResponse {
     pub git_revision: String,
+    pub paused_topics: Vec<PausedTopic>,
+    /// Factor applied to the tx timeouts to compensate slow blocks or a stale height subscription.
+    pub timeout_compensation: f64,
+    pub observed_block_secs: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpgradeResponse {
+    /// The ongoing upgrade, or the last one.
+    pub upgrade: Option<UpgradeStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BusStatsResponse {
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PausedTopicsResponse {
+    pub paused_topics: Vec<PausedTopic>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SendersResponse {
+    pub senders: Vec<SenderSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SenderRetention {
+    pub sender: String,
+    pub worker_id: String,
+    /// Number of messages tracked in memory.
+    pub retained_messages: usize,
+    /// The lowest and highest sequences tracked in memory.
+    pub retained_window: Option<(u64, u64)>,
+    pub pruned_messages: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRetentionResponse {
+    pub gc_horizon_blocks: u32,
+    pub pruned_messages: u64,
+    pub senders: Vec<SenderRetention>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLettersResponse {
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReleaseDeadLetterRequest {
+    /// The sender as displayed, e.g. `Worker(<hex pubkey>)`.
+    pub sender: String,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SetTopicPausedRequest {
+    pub topic: String,
+    pub paused: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ComputationSamplesResponse {
+    pub samples: Vec<ComputationSample>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ComputationHistoryQuery {
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaintenanceWindowsResponse {
+    pub windows: Vec<MaintenanceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignerStatus {
+    #[serde(flatten)]
+    pub operator: PoolOperatorForSerialize,
+    /// Whether the key has been loaded from its provider.
+    pub loaded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignersResponse {
+    pub signers: Vec<SignerStatus>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -126,17 +262,15 @@ impl From<anyhow::Error> for ApiError {
     }
 }
 
-pub async fn start_api_server(
-    ctx: WrappedWorkerManagerContext,
-    args: WorkerManagerCliArgs,
-) -> anyhow::Result<()> {
-    // todo: mdns
-
-    let app = Router::new()
+/// The routes of the management API, behind the signature check of [`crate::api_auth`].
+pub(crate) fn router(ctx: WrappedWorkerManagerContext, auth: Arc<ApiAuth>) -> Router {
+    Router::new()
         .route("/", get(handle_get_root))
         .route("/wm/status", get(handle_get_wm_status))
         .route("/wm/restart", put(handle_restart_wm))
         .route("/wm/config", post(handle_config_wm))
+        .route("/wm/bus", get(handle_get_bus_stats))
+        .route("/metrics", get(handle_get_metrics))
         .route("/workers/status", get(handle_get_worker_status))
         .route("/workers/restart", put(handle_restart_specific_workers))
         .route(
@@ -145,9 +279,64 @@ pub async fn start_api_server(
         )
         .route("/workers/update_endpoints", put(handle_update_endpoints))
         .route("/workers/take_checkpoint", put(handle_take_checkpoint))
+        .route("/workers/upgrade", get(handle_get_upgrade))
+        .route("/workers/upgrade", put(handle_start_upgrade))
+        .route("/workers/upgrade", delete(handle_abort_upgrade))
+        .route("/workers/computation", get(handle_get_computation_samples))
+        .route(
+            "/workers/computation/:id",
+            get(handle_get_computation_history),
+        )
         .route("/tx/status", get(handle_get_tx_status))
+        .route("/tx/runtime_upgrade", get(handle_get_runtime_upgrade))
+        .route("/jobs", get(handle_get_jobs))
+        .route("/jobs", post(handle_enqueue_job))
+        .route("/jobs/:id", get(handle_get_job))
+        .route("/datasource/shadow", get(handle_get_shadow_report))
+        .route("/datasource/requests", get(handle_get_request_trace))
+        .route("/support_bundle", get(handle_get_support_bundle))
+        .route("/migration/export", get(handle_export_migration))
+        .route(
+            "/migration/import",
+            post(handle_import_migration).layer(DefaultBodyLimit::max(MAX_ARCHIVE_SIZE)),
+        )
+        .route("/messages/paused_topics", get(handle_get_paused_topics))
+        .route("/messages/paused_topics", put(handle_set_topic_paused))
+        .route("/messages/senders", get(handle_get_senders))
+        .route("/messages/retention", get(handle_get_message_retention))
+        .route("/messages/reconciliation", get(handle_get_reconciliation))
+        .route("/messages/dead_letters", get(handle_get_dead_letters))
+        .route(
+            "/messages/dead_letters/retry",
+            post(handle_retry_dead_letter),
+        )
+        .route(
+            "/messages/dead_letters/discard",
+            post(handle_discard_dead_letter),
+        )
+        .route("/pools/maintenance", get(handle_get_maintenance_windows))
+        .route("/pools/maintenance", put(handle_set_maintenance_window))
+        .route(
+            "/pools/maintenance/:pid",
+            delete(handle_end_maintenance_window),
+        )
+        .route("/pools/signers", get(handle_get_signers))
         .fallback(handle_get_root)
-        .with_state(ctx);
+        .layer(middleware::from_fn_with_state(
+            auth,
+            crate::api_auth::middleware,
+        ))
+        .with_state(ctx)
+}
+
+pub async fn start_api_server(
+    ctx: WrappedWorkerManagerContext,
+    args: WorkerManagerCliArgs,
+) -> anyhow::Result<()> {
+    // todo: mdns
+
+    let auth = Arc::new(ApiAuth::new(&args)?);
+    let app = router(ctx, auth);
 
     let fut_vec = args
         .mgmt_listen_addresses
@@ -167,9 +356,12 @@ async fn handle_get_root() -> (StatusCode, ()) {
     (StatusCode::IM_A_TEAPOT, ())
 }
 
-async fn handle_get_wm_status() -> Json<WmStatusResponse> {
+async fn handle_get_wm_status(State(ctx): AppContext) -> Json<WmStatusResponse> {
     Json(WmStatusResponse {
         git_revision: git_revision_with_ts().to_string(),
+        paused_topics: ctx.topic_toggles.paused_topics(),
+        timeout_compensation: ctx.txm.height_tracker.compensation_factor(),
+        observed_block_secs: ctx.txm.height_tracker.observed_block_secs(),
     })
 }
 
@@ -262,6 +454,36 @@ async fn handle_take_checkpoint(
     Ok((StatusCode::OK, Json(OkResponse::default())))
 }
 
+async fn handle_get_upgrade(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<UpgradeResponse>)> {
+    let upgrade = ctx.upgrade.status();
+    Ok((StatusCode::OK, Json(UpgradeResponse { upgrade })))
+}
+
+async fn handle_start_upgrade(
+    State(ctx): AppContext,
+    Json(payload): Json<UpgradeRequest>,
+) -> ApiResult<(StatusCode, Json<UpgradeResponse>)> {
+    let upgrade = crate::upgrade::start(ctx.clone(), payload).await?;
+    Ok((
+        StatusCode::OK,
+        Json(UpgradeResponse {
+            upgrade: Some(upgrade),
+        }),
+    ))
+}
+
+async fn handle_abort_upgrade(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<UpgradeResponse>)> {
+    if !ctx.upgrade.abort() {
+        return Err(ApiError::NoUpgradeRunning);
+    }
+    let upgrade = ctx.upgrade.status();
+    Ok((StatusCode::OK, Json(UpgradeResponse { upgrade })))
+}
+
 async fn handle_get_tx_status(
     State(ctx): AppContext,
 ) -> ApiResult<(StatusCode, Json<TxStatusResponse>)> {
@@ -269,6 +491,236 @@ async fn handle_get_tx_status(
     Ok((StatusCode::OK, Json(txm.dump().await?)))
 }
 
+async fn handle_get_runtime_upgrade(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<RuntimeUpgradeStatus>)> {
+    Ok((StatusCode::OK, Json(ctx.txm.runtime_upgrade.status())))
+}
+
+async fn handle_get_jobs(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<JobsResponse>)> {
+    let jobs = ctx.txm.jobs.list();
+    Ok((StatusCode::OK, Json(JobsResponse { jobs })))
+}
+
+async fn handle_get_job(
+    State(ctx): AppContext,
+    Path(id): Path<u64>,
+) -> ApiResult<(StatusCode, Json<Job>)> {
+    let job = ctx.txm.jobs.get(id).ok_or(ApiError::JobNotFound(id))?;
+    Ok((StatusCode::OK, Json(job)))
+}
+
+async fn handle_enqueue_job(
+    State(ctx): AppContext,
+    Json(payload): Json<JobRequest>,
+) -> ApiResult<(StatusCode, Json<EnqueueJobResponse>)> {
+    let id = ctx.txm.jobs.enqueue(payload)?;
+    Ok((StatusCode::OK, Json(EnqueueJobResponse { id })))
+}
+
+async fn handle_get_shadow_report(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<ShadowReport>)> {
+    let shadow = ctx.dsm.shadow.clone().ok_or(ApiError::ShadowNotConfigured)?;
+    Ok((StatusCode::OK, Json(shadow.report())))
+}
+
+async fn handle_get_request_trace(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<RequestTraceReport>)> {
+    Ok((StatusCode::OK, Json(ctx.dsm.tracer.report())))
+}
+
+async fn handle_get_bus_stats(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<BusStatsResponse>)> {
+    let channels = ctx.bus.stats.snapshot();
+    Ok((StatusCode::OK, Json(BusStatsResponse { channels })))
+}
+
+async fn handle_get_metrics(State(ctx): AppContext) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        ctx.message_metrics.render(),
+    )
+}
+
+async fn handle_get_support_bundle(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<SupportBundle>)> {
+    let bundle = SupportBundle::collect(&ctx).await?;
+    Ok((StatusCode::OK, Json(bundle)))
+}
+
+async fn handle_export_migration(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<MigrationArchive>)> {
+    let archive = MigrationArchive::collect(&ctx).await?;
+    Ok((StatusCode::OK, Json(archive)))
+}
+
+async fn handle_import_migration(
+    State(ctx): AppContext,
+    Json(archive): Json<MigrationArchive>,
+) -> ApiResult<(StatusCode, Json<ImportReport>)> {
+    let report = archive.import(&ctx).await?;
+    Ok((StatusCode::OK, Json(report)))
+}
+
+async fn handle_get_paused_topics(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<PausedTopicsResponse>)> {
+    let paused_topics = ctx.topic_toggles.paused_topics();
+    Ok((StatusCode::OK, Json(PausedTopicsResponse { paused_topics })))
+}
+
+async fn handle_set_topic_paused(
+    State(ctx): AppContext,
+    Json(payload): Json<SetTopicPausedRequest>,
+) -> ApiResult<(StatusCode, Json<PausedTopicsResponse>)> {
+    ctx.topic_toggles.set_paused(payload.topic, payload.paused);
+    let paused_topics = ctx.topic_toggles.paused_topics();
+    Ok((StatusCode::OK, Json(PausedTopicsResponse { paused_topics })))
+}
+
+async fn handle_get_senders(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<SendersResponse>)> {
+    let senders = snapshot_senders(&ctx.bus).await?;
+    Ok((StatusCode::OK, Json(SendersResponse { senders })))
+}
+
+async fn handle_get_reconciliation(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<ReconcileReport>)> {
+    Ok((StatusCode::OK, Json(ctx.reconciler.report())))
+}
+
+async fn handle_get_message_retention(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<MessageRetentionResponse>)> {
+    let senders = snapshot_senders(&ctx.bus)
+        .await?
+        .into_iter()
+        .map(|snapshot| {
+            let sequences = snapshot.pending_messages.iter().map(|m| m.sequence);
+            let retained_window = sequences.clone().min().zip(sequences.max());
+            SenderRetention {
+                sender: snapshot.sender,
+                worker_id: snapshot.worker_id,
+                retained_messages: snapshot.pending_messages.len(),
+                retained_window,
+                pruned_messages: snapshot.pruned_messages,
+            }
+        })
+        .collect::<Vec<_>>();
+    let pruned_messages = senders.iter().map(|s| s.pruned_messages).sum();
+    Ok((
+        StatusCode::OK,
+        Json(MessageRetentionResponse {
+            gc_horizon_blocks: ctx.args.message_gc_horizon_blocks,
+            pruned_messages,
+            senders,
+        }),
+    ))
+}
+
+async fn handle_get_computation_samples(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<ComputationSamplesResponse>)> {
+    let samples = ctx.computation_samples.latest();
+    Ok((StatusCode::OK, Json(ComputationSamplesResponse { samples })))
+}
+
+async fn handle_get_computation_history(
+    State(ctx): AppContext,
+    Path(id): Path<String>,
+    Query(query): Query<ComputationHistoryQuery>,
+) -> ApiResult<(StatusCode, Json<ComputationSamplesResponse>)> {
+    let samples = ctx.computation_samples.history(&id, query.since)?;
+    Ok((StatusCode::OK, Json(ComputationSamplesResponse { samples })))
+}
+
+async fn handle_get_dead_letters(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<DeadLettersResponse>)> {
+    let dead_letters = ctx.dead_letters.list();
+    Ok((StatusCode::OK, Json(DeadLettersResponse { dead_letters })))
+}
+
+async fn handle_retry_dead_letter(
+    State(ctx): AppContext,
+    Json(payload): Json<ReleaseDeadLetterRequest>,
+) -> ApiResult<(StatusCode, Json<DeadLettersResponse>)> {
+    do_release_dead_letter(&ctx, payload, DeadLetterAction::Retry).await
+}
+
+async fn handle_discard_dead_letter(
+    State(ctx): AppContext,
+    Json(payload): Json<ReleaseDeadLetterRequest>,
+) -> ApiResult<(StatusCode, Json<DeadLettersResponse>)> {
+    do_release_dead_letter(&ctx, payload, DeadLetterAction::Discard).await
+}
+
+async fn do_release_dead_letter(
+    ctx: &WrappedWorkerManagerContext,
+    payload: ReleaseDeadLetterRequest,
+    action: DeadLetterAction,
+) -> ApiResult<(StatusCode, Json<DeadLettersResponse>)> {
+    let not_found = || ApiError::DeadLetterNotFound(payload.sender.clone(), payload.sequence);
+    let letter = ctx
+        .dead_letters
+        .find(&payload.sender, payload.sequence)
+        .ok_or_else(not_found)?;
+    if !release_dead_letter(&ctx.bus, letter.origin, letter.sequence, action).await? {
+        return Err(not_found());
+    }
+    let dead_letters = ctx.dead_letters.list();
+    Ok((StatusCode::OK, Json(DeadLettersResponse { dead_letters })))
+}
+
+async fn handle_get_maintenance_windows(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<MaintenanceWindowsResponse>)> {
+    let windows = ctx.txm.maintenance.list();
+    Ok((StatusCode::OK, Json(MaintenanceWindowsResponse { windows })))
+}
+
+async fn handle_set_maintenance_window(
+    State(ctx): AppContext,
+    Json(payload): Json<MaintenanceWindow>,
+) -> ApiResult<(StatusCode, Json<MaintenanceWindowsResponse>)> {
+    ctx.txm.maintenance.set(payload)?;
+    let windows = ctx.txm.maintenance.list();
+    Ok((StatusCode::OK, Json(MaintenanceWindowsResponse { windows })))
+}
+
+async fn handle_end_maintenance_window(
+    State(ctx): AppContext,
+    Path(pid): Path<u64>,
+) -> ApiResult<(StatusCode, Json<MaintenanceWindowsResponse>)> {
+    if !ctx.txm.maintenance.end_now(pid)? {
+        return Err(ApiError::MaintenanceWindowNotFound(pid));
+    }
+    let windows = ctx.txm.maintenance.list();
+    Ok((StatusCode::OK, Json(MaintenanceWindowsResponse { windows })))
+}
+
+async fn handle_get_signers(
+    State(ctx): AppContext,
+) -> ApiResult<(StatusCode, Json<SignersResponse>)> {
+    let mut signers = Vec::new();
+    for po in ctx.txm.db.get_all_po()? {
+        signers.push(SignerStatus {
+            operator: (&po).into(),
+            loaded: ctx.txm.signers.is_loaded(po.pid).await,
+        });
+    }
+    Ok((StatusCode::OK, Json(SignersResponse { signers })))
+}
+
 async fn handle_config_wm(
     State(ctx): State<WrappedWorkerManagerContext>,
     Json(payload): Json<ConfigCommands>,
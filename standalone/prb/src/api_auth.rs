@@ -0,0 +1,809 @@
+//! Signed requests and audit log of the mutating endpoints of the management API.
+//!
+//! When admin keys are configured, every request other than GET and HEAD must be signed by one of
+//! them, so that the control of the fleet can be delegated without sharing a network path. So must
+//! the GET requests of the [`PROTECTED_READS`], which export the internal state of prb. A signed
+//! request carries the headers:
+//!
+//! - `X-Prb-Signer`: the sr25519 or ed25519 public key, hex or SS58 encoded
+//! - `X-Prb-Timestamp`: unix time in milliseconds
+//! - `X-Prb-Signature`: the hex encoded signature of
+//!   `<METHOD> <path and query>\n<timestamp>\n<hex sha256 of the body>`
+//!
+//! Requests whose timestamp is off by more than a minute, or whose signature was seen before, are
+//! rejected. The signer and the timestamp are checked before the body is read, and bodies larger
+//! than 4 MiB, or than the limit of their route in [`BODY_LIMITS`], are refused. When no key is
+//! configured the endpoints are left open, as they used to be.
+//!
+//! Every request requiring a signature, accepted or not, is appended to the audit log as a JSON line carrying
+//! the HMAC-SHA256 of the previous line, keyed by a secret kept next to the database, so that
+//! editing or removing a line breaks the chain from there on and the chain can not be recomputed
+//! without the key. `prb-audit-log verify` checks the chain, and `prb-audit-log sign` prints the
+//! headers of a request signed with an sr25519 key.
+
+use crate::cli::{AuditLogCliArgs, AuditLogCommands, WorkerManagerCliArgs};
+use anyhow::{anyhow, bail, Context, Result};
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::{ed25519, sr25519, Pair};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const SIGNER_HEADER: &str = "x-prb-signer";
+pub const TIMESTAMP_HEADER: &str = "x-prb-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-prb-signature";
+
+const MAX_CLOCK_SKEW_MS: u64 = 60_000;
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+const AUDIT_KEY_SIZE: usize = 32;
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The paths of the GET endpoints requiring a signature.
+pub const PROTECTED_READS: &[&str] = &["/support_bundle", "/migration/export"];
+
+/// The routes taking bodies larger than [`MAX_BODY_SIZE`], with the limit their router applies.
+pub const BODY_LIMITS: &[(&str, usize)] =
+    &[("/migration/import", crate::migration::MAX_ARCHIVE_SIZE)];
+
+/// The size limit of the body of a request to `path`.
+fn body_limit(path: &str) -> usize {
+    BODY_LIMITS
+        .iter()
+        .find(|(route, _)| *route == path)
+        .map(|(_, limit)| *limit)
+        .unwrap_or(MAX_BODY_SIZE)
+}
+
+fn requires_signature(method: &Method, path: &str) -> bool {
+    !(method == Method::GET || method == Method::HEAD) || PROTECTED_READS.contains(&path)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("missing header {0}")]
+    MissingHeader(&'static str),
+
+    #[error("invalid header {0}")]
+    InvalidHeader(&'static str),
+
+    #[error("signer is not an admin")]
+    UnknownSigner,
+
+    #[error("timestamp out of the allowed clock skew")]
+    Expired,
+
+    #[error("signature used already")]
+    Replayed,
+
+    #[error("bad signature")]
+    BadSignature,
+
+    #[error("request body larger than {0} bytes")]
+    BodyTooLarge(usize),
+
+    #[error("failed to read the request body")]
+    BadBody,
+}
+
+impl AuthError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AuthError::BadBody => StatusCode::BAD_REQUEST,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AuthError::BodyTooLarge(_) => "PayloadTooLarge",
+            AuthError::BadBody => "BadRequest",
+            _ => "Unauthorized",
+        }
+    }
+}
+
+struct AdminKey {
+    name: String,
+    public: [u8; 32],
+}
+
+/// The signature headers of a request whose signer and timestamp were checked.
+struct SignedHeaders {
+    key: usize,
+    timestamp: u64,
+    signature: [u8; 64],
+}
+
+/// The message an admin key signs for a request.
+pub fn signing_message(method: &str, path: &str, timestamp: u64, body: &[u8]) -> String {
+    format!(
+        "{method} {path}\n{timestamp}\n{}",
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+fn parse_public_key(key: &str) -> Result<[u8; 32]> {
+    if let Some(hex_key) = key.strip_prefix("0x") {
+        let bytes = hex::decode(hex_key).context("Invalid hex public key")?;
+        return bytes
+            .try_into()
+            .map_err(|_| anyhow!("Public key must be 32 bytes"));
+    }
+    let account = AccountId32::from_ss58check(key)
+        .map_err(|err| anyhow!("Invalid SS58 public key {key}: {err:?}"))?;
+    Ok(account.into())
+}
+
+fn parse_admin_key(spec: &str) -> Result<AdminKey> {
+    let (name, key) = match spec.split_once('=') {
+        Some((name, key)) => (name.trim().to_string(), key.trim()),
+        None => (spec.trim().to_string(), spec.trim()),
+    };
+    Ok(AdminKey {
+        name,
+        public: parse_public_key(key)?,
+    })
+}
+
+fn verify_signature(public: &[u8; 32], signature: &[u8; 64], message: &[u8]) -> bool {
+    sr25519::Pair::verify(
+        &sr25519::Signature::from_raw(*signature),
+        message,
+        &sr25519::Public::from_raw(*public),
+    ) || ed25519::Pair::verify(
+        &ed25519::Signature::from_raw(*signature),
+        message,
+        &ed25519::Public::from_raw(*public),
+    )
+}
+
+fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: String,
+    /// Name of the admin key which signed the request, none if the endpoints are open.
+    pub signer: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// None if the request was rejected before its body was read.
+    pub body_sha256: Option<String>,
+    pub status: u16,
+    pub rejected: Option<String>,
+    /// Hex HMAC-SHA256 of the previous line.
+    pub prev: String,
+}
+
+fn chain_hash(key: &[u8], line: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(line.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Reads the key of the audit log chain, generating it if the file does not exist.
+pub fn load_audit_key(path: &Path, create: bool) -> Result<Vec<u8>> {
+    if !path.exists() && create {
+        let key: [u8; AUDIT_KEY_SIZE] = rand::random();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create audit log key {}", path.display()))?;
+        file.write_all(hex::encode(key).as_bytes())?;
+        info!("Generated the audit log key {}", path.display());
+    }
+    let mut content = String::new();
+    File::open(path)
+        .with_context(|| format!("Failed to open audit log key {}", path.display()))?
+        .read_to_string(&mut content)?;
+    let key = hex::decode(content.trim()).context("Invalid audit log key")?;
+    if key.len() < AUDIT_KEY_SIZE {
+        bail!("Audit log key must be at least {AUDIT_KEY_SIZE} bytes");
+    }
+    Ok(key)
+}
+
+struct AuditState {
+    file: File,
+    next_seq: u64,
+    last_hash: String,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    key: Vec<u8>,
+    state: Mutex<AuditState>,
+}
+
+impl AuditLog {
+    pub fn open(path: impl Into<PathBuf>, key: Vec<u8>) -> Result<Self> {
+        let path = path.into();
+        let (next_seq, last_hash) = match verify_audit_log(&path, &key) {
+            Ok(tail) => tail,
+            Err(err) if path.exists() => {
+                // Keep serving, the broken chain stays in the file for the investigation.
+                error!("Audit log {} is broken: {err:?}", path.display());
+                last_line_tail(&path, &key)?
+            }
+            Err(_) => (0, GENESIS_HASH.to_string()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self {
+            path,
+            key,
+            state: Mutex::new(AuditState {
+                file,
+                next_seq,
+                last_hash,
+            }),
+        })
+    }
+
+    fn append(&self, mut entry: AuditEntry) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        entry.seq = state.next_seq;
+        entry.prev = state.last_hash.clone();
+        let line = serde_json::to_string(&entry)?;
+        writeln!(state.file, "{line}")?;
+        state.file.flush()?;
+        state.next_seq += 1;
+        state.last_hash = chain_hash(&self.key, &line);
+        Ok(())
+    }
+}
+
+/// The sequence number and hash to continue the chain with, without checking it.
+fn last_line_tail(path: &Path, key: &[u8]) -> Result<(u64, String)> {
+    let file = File::open(path)?;
+    let mut tail = (0, GENESIS_HASH.to_string());
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let seq = serde_json::from_str::<AuditEntry>(&line)
+            .map(|entry| entry.seq + 1)
+            .unwrap_or(tail.0 + 1);
+        tail = (seq, chain_hash(key, &line));
+    }
+    Ok(tail)
+}
+
+/// Checks the chain of the audit log, returning the sequence number and hash to continue it with.
+pub fn verify_audit_log(path: &Path, key: &[u8]) -> Result<(u64, String)> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open audit log {}", path.display()))?;
+    let mut next_seq = 0;
+    let mut last_hash = GENESIS_HASH.to_string();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let entry: AuditEntry = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid entry at line {}", i + 1))?;
+        if entry.seq != next_seq {
+            bail!("Line {} has seq {}, expected {next_seq}", i + 1, entry.seq);
+        }
+        if entry.prev != last_hash {
+            bail!("Line {} does not chain to the previous line", i + 1);
+        }
+        next_seq += 1;
+        last_hash = chain_hash(key, &line);
+    }
+    Ok((next_seq, last_hash))
+}
+
+pub struct ApiAuth {
+    admin_keys: Vec<AdminKey>,
+    /// Signatures seen within the clock skew, to the timestamp of their request.
+    seen: Mutex<HashMap<[u8; 64], u64>>,
+    audit: AuditLog,
+}
+
+impl ApiAuth {
+    pub fn new(args: &WorkerManagerCliArgs) -> Result<Self> {
+        let admin_keys = args
+            .api_admin_keys
+            .iter()
+            .filter(|spec| !spec.is_empty())
+            .map(|spec| parse_admin_key(spec))
+            .collect::<Result<Vec<_>>>()?;
+        if admin_keys.is_empty() {
+            warn!("No API admin key configured, the mutating endpoints of the management API are open.");
+        } else {
+            info!("{} API admin keys configured.", admin_keys.len());
+        }
+        let audit_path = match &args.api_audit_log {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&args.db_path).join("api-audit.log"),
+        };
+        let key_path = match &args.api_audit_key_file {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&args.db_path).join("api-audit.key"),
+        };
+        let key = load_audit_key(&key_path, true)?;
+        Ok(Self::with_keys(
+            admin_keys,
+            AuditLog::open(audit_path, key)?,
+        ))
+    }
+
+    fn with_keys(admin_keys: Vec<AdminKey>, audit: AuditLog) -> Self {
+        Self {
+            admin_keys,
+            seen: Default::default(),
+            audit,
+        }
+    }
+
+    /// Checks the signer and the timestamp of a request, before its body is read. Returns none if
+    /// no key is configured.
+    fn authenticate_headers(
+        &self,
+        headers: &HeaderMap,
+        now: u64,
+    ) -> Result<Option<SignedHeaders>, AuthError> {
+        if self.admin_keys.is_empty() {
+            return Ok(None);
+        }
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .ok_or(AuthError::MissingHeader(name))?
+                .to_str()
+                .map_err(|_| AuthError::InvalidHeader(name))
+        };
+        let signer = parse_public_key(header(SIGNER_HEADER)?)
+            .map_err(|_| AuthError::InvalidHeader(SIGNER_HEADER))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| AuthError::InvalidHeader(TIMESTAMP_HEADER))?;
+        let signature: [u8; 64] = hex::decode(header(SIGNATURE_HEADER)?.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(AuthError::InvalidHeader(SIGNATURE_HEADER))?;
+
+        let key = self
+            .admin_keys
+            .iter()
+            .position(|key| key.public == signer)
+            .ok_or(AuthError::UnknownSigner)?;
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW_MS {
+            return Err(AuthError::Expired);
+        }
+        Ok(Some(SignedHeaders {
+            key,
+            timestamp,
+            signature,
+        }))
+    }
+
+    /// Checks the signature of a request over its body, returning the name of the signer.
+    fn verify_request(
+        &self,
+        signed: &SignedHeaders,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<String, AuthError> {
+        let key = &self.admin_keys[signed.key];
+        let message = signing_message(method, path, signed.timestamp, body);
+        if !verify_signature(&key.public, &signed.signature, message.as_bytes()) {
+            return Err(AuthError::BadSignature);
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, ts| now.abs_diff(*ts) <= MAX_CLOCK_SKEW_MS);
+        if seen.insert(signed.signature, signed.timestamp).is_some() {
+            return Err(AuthError::Replayed);
+        }
+        Ok(key.name.clone())
+    }
+
+    /// Returns the name of the signer, or none if no key is configured.
+    #[cfg(test)]
+    fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Option<String>, AuthError> {
+        let now = now_ms();
+        match self.authenticate_headers(headers, now)? {
+            Some(signed) => self
+                .verify_request(&signed, method, path, body, now)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn audit(
+        &self,
+        signer: Option<String>,
+        method: String,
+        path: String,
+        body: Option<&[u8]>,
+        status: StatusCode,
+        rejected: Option<String>,
+    ) {
+        let entry = AuditEntry {
+            seq: 0,
+            time: chrono::Utc::now().to_rfc3339(),
+            signer,
+            method,
+            path,
+            body_sha256: body.map(|body| hex::encode(Sha256::digest(body))),
+            status: status.as_u16(),
+            rejected,
+            prev: String::new(),
+        };
+        if let Err(err) = self.audit.append(entry) {
+            error!(
+                "Failed to write audit log {}: {err:?}",
+                self.audit.path.display()
+            );
+        }
+    }
+}
+
+/// Reads the body of a request, failing if it is larger than `limit`.
+async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, AuthError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(AuthError::BodyTooLarge(limit));
+    }
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            warn!("Failed to read the request body: {err}");
+            AuthError::BadBody
+        })?;
+        if buf.len() + chunk.len() > limit {
+            return Err(AuthError::BodyTooLarge(limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.into())
+}
+
+fn reject(
+    auth: &ApiAuth,
+    method: String,
+    path: String,
+    body: Option<&[u8]>,
+    err: AuthError,
+) -> Response {
+    warn!("Rejected {method} {path}: {err}");
+    auth.audit(
+        None,
+        method,
+        path,
+        body,
+        err.status(),
+        Some(err.to_string()),
+    );
+    (
+        err.status(),
+        Json(json!({
+            "error": true,
+            "code": err.code(),
+            "message": err.to_string(),
+        })),
+    )
+        .into_response()
+}
+
+/// Middleware of the management API, checking the signature of the mutating requests and of the
+/// protected reads, and recording them into the audit log.
+pub async fn middleware(
+    State(auth): State<Arc<ApiAuth>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !requires_signature(req.method(), req.uri().path()) {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let method = parts.method.to_string();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .to_string();
+    let now = now_ms();
+    let signed = match auth.authenticate_headers(&parts.headers, now) {
+        Ok(signed) => signed,
+        Err(err) => return reject(&auth, method, path, None, err),
+    };
+    let body = match read_body(body, body_limit(parts.uri.path())).await {
+        Ok(body) => body,
+        Err(err) => return reject(&auth, method, path, None, err),
+    };
+    let signer = match &signed {
+        Some(signed) => match auth.verify_request(signed, &method, &path, &body, now) {
+            Ok(name) => Some(name),
+            Err(err) => return reject(&auth, method, path, Some(&body), err),
+        },
+        None => None,
+    };
+    let response = next
+        .run(Request::from_parts(parts, Body::from(body.clone())))
+        .await;
+    auth.audit(signer, method, path, Some(&body), response.status(), None);
+    response
+}
+
+/// The headers of a request signed with the sr25519 admin key of `suri`.
+pub fn sign_request(
+    suri: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Vec<(&'static str, String)>> {
+    let pair = sr25519::Pair::from_string(suri, None)
+        .map_err(|err| anyhow!("Invalid admin key: {err:?}"))?;
+    let timestamp = now_ms();
+    let message = signing_message(&method.to_uppercase(), path, timestamp, body);
+    let signature = pair.sign(message.as_bytes());
+    Ok(vec![
+        (SIGNER_HEADER, format!("0x{}", hex::encode(pair.public()))),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, format!("0x{}", hex::encode(signature))),
+    ])
+}
+
+pub async fn cli_main(args: AuditLogCliArgs) -> Result<()> {
+    match args.command {
+        AuditLogCommands::Verify { file, key_file } => {
+            let key = load_audit_key(Path::new(&key_file), false)?;
+            let (entries, last_hash) = verify_audit_log(Path::new(&file), &key)?;
+            println!("{entries} entries chained, last hash {last_hash}");
+        }
+        AuditLogCommands::Sign {
+            suri,
+            method,
+            path,
+            body,
+        } => {
+            let body = body.unwrap_or_default();
+            for (name, value) in sign_request(&suri, &method, &path, body.as_bytes())? {
+                println!("{name}: {value}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SURI: &str = "//Alice";
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("prb-{name}-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn test_auth(admin: bool) -> ApiAuth {
+        let admin_keys = if admin {
+            let pair = sr25519::Pair::from_string(SURI, None).unwrap();
+            let spec = format!("alice=0x{}", hex::encode(pair.public()));
+            vec![parse_admin_key(&spec).unwrap()]
+        } else {
+            vec![]
+        };
+        let audit = AuditLog::open(temp_path("audit.log"), vec![7; AUDIT_KEY_SIZE]).unwrap();
+        ApiAuth::with_keys(admin_keys, audit)
+    }
+
+    fn headers_of(signed: Vec<(&'static str, String)>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in signed {
+            headers.insert(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn set_header(headers: &mut HeaderMap, name: &'static str, value: String) {
+        headers.insert(name, value.parse().unwrap());
+    }
+
+    #[test]
+    fn authenticate_accepts_signed_requests() {
+        let auth = test_auth(true);
+        let headers = headers_of(sign_request(SURI, "put", "/workers/restart", b"{}").unwrap());
+        let signer = auth
+            .authenticate(&headers, "PUT", "/workers/restart", b"{}")
+            .unwrap();
+        assert_eq!(signer.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn authenticate_is_open_without_keys() {
+        let auth = test_auth(false);
+        let signer = auth
+            .authenticate(&HeaderMap::new(), "PUT", "/workers/restart", b"{}")
+            .unwrap();
+        assert_eq!(signer, None);
+    }
+
+    #[test]
+    fn authenticate_rejects_bad_requests() {
+        let auth = test_auth(true);
+        let signed = || headers_of(sign_request(SURI, "PUT", "/workers/restart", b"{}").unwrap());
+
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), "PUT", "/workers/restart", b"{}"),
+            Err(AuthError::MissingHeader(SIGNER_HEADER))
+        ));
+        assert!(matches!(
+            auth.authenticate(&signed(), "PUT", "/workers/restart", b"{\"all\":true}"),
+            Err(AuthError::BadSignature)
+        ));
+        assert!(matches!(
+            auth.authenticate(&signed(), "PUT", "/workers/stop", b"{}"),
+            Err(AuthError::BadSignature)
+        ));
+
+        let bob = sr25519::Pair::from_string("//Bob", None).unwrap();
+        let mut headers = signed();
+        set_header(
+            &mut headers,
+            SIGNER_HEADER,
+            format!("0x{}", hex::encode(bob.public())),
+        );
+        assert!(matches!(
+            auth.authenticate(&headers, "PUT", "/workers/restart", b"{}"),
+            Err(AuthError::UnknownSigner)
+        ));
+
+        let pair = sr25519::Pair::from_string(SURI, None).unwrap();
+        let timestamp = now_ms() - 2 * MAX_CLOCK_SKEW_MS;
+        let message = signing_message("PUT", "/workers/restart", timestamp, b"{}");
+        let mut headers = signed();
+        set_header(&mut headers, TIMESTAMP_HEADER, timestamp.to_string());
+        set_header(
+            &mut headers,
+            SIGNATURE_HEADER,
+            hex::encode(pair.sign(message.as_bytes())),
+        );
+        assert!(matches!(
+            auth.authenticate(&headers, "PUT", "/workers/restart", b"{}"),
+            Err(AuthError::Expired)
+        ));
+    }
+
+    #[test]
+    fn authenticate_rejects_replayed_signatures() {
+        let auth = test_auth(true);
+        let headers = headers_of(sign_request(SURI, "PUT", "/workers/restart", b"{}").unwrap());
+        assert!(auth
+            .authenticate(&headers, "PUT", "/workers/restart", b"{}")
+            .is_ok());
+        assert!(matches!(
+            auth.authenticate(&headers, "PUT", "/workers/restart", b"{}"),
+            Err(AuthError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn protected_reads_require_a_signature() {
+        assert!(requires_signature(&Method::GET, "/support_bundle"));
+        assert!(requires_signature(&Method::GET, "/migration/export"));
+        assert!(requires_signature(&Method::HEAD, "/support_bundle"));
+        assert!(requires_signature(&Method::PUT, "/workers/restart"));
+        assert!(!requires_signature(&Method::GET, "/workers/status"));
+
+        let auth = test_auth(true);
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), "GET", "/support_bundle", b""),
+            Err(AuthError::MissingHeader(SIGNER_HEADER))
+        ));
+        let headers = headers_of(sign_request(SURI, "GET", "/support_bundle", b"").unwrap());
+        let signer = auth
+            .authenticate(&headers, "GET", "/support_bundle", b"")
+            .unwrap();
+        assert_eq!(signer.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn read_body_is_bounded() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let body = read_body(Body::from(vec![1u8; 16]), 16).await.unwrap();
+            assert_eq!(body.len(), 16);
+            assert!(matches!(
+                read_body(Body::from(vec![1u8; 17]), 16).await,
+                Err(AuthError::BodyTooLarge(16))
+            ));
+        });
+    }
+
+    #[test]
+    fn body_limit_follows_the_route() {
+        assert_eq!(body_limit("/workers/restart"), MAX_BODY_SIZE);
+        assert_eq!(
+            body_limit("/migration/import"),
+            crate::migration::MAX_ARCHIVE_SIZE
+        );
+    }
+
+    fn write_entries(path: &Path, key: &[u8], count: usize) {
+        let auth = ApiAuth::with_keys(vec![], AuditLog::open(path, key.to_vec()).unwrap());
+        for i in 0..count {
+            auth.audit(
+                None,
+                "PUT".into(),
+                format!("/workers/{i}"),
+                Some(b"{}"),
+                StatusCode::OK,
+                None,
+            );
+        }
+    }
+
+    #[test]
+    fn verify_audit_log_checks_the_chain() {
+        let key = vec![7; AUDIT_KEY_SIZE];
+        let path = temp_path("audit.log");
+        write_entries(&path, &key, 3);
+
+        let (entries, last_hash) = verify_audit_log(&path, &key).unwrap();
+        assert_eq!(entries, 3);
+        // Reopening continues the chain.
+        let reopened = AuditLog::open(&path, key.clone()).unwrap();
+        assert_eq!(reopened.state.lock().unwrap().last_hash, last_hash);
+        write_entries(&path, &key, 1);
+        assert_eq!(verify_audit_log(&path, &key).unwrap().0, 4);
+
+        // A different key can not verify, nor have computed, the chain.
+        assert!(verify_audit_log(&path, &[8; AUDIT_KEY_SIZE]).is_err());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+
+        let edited = content.replacen("/workers/1", "/workers/9", 1);
+        std::fs::write(&path, edited).unwrap();
+        assert!(verify_audit_log(&path, &key).is_err());
+
+        let removed = [lines[0], lines[2], lines[3]].join("\n");
+        std::fs::write(&path, removed).unwrap();
+        assert!(verify_audit_log(&path, &key).is_err());
+
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        assert_eq!(verify_audit_log(&path, &key).unwrap().0, 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn audit_key_is_generated_once() {
+        let path = temp_path("audit.key");
+        assert!(load_audit_key(&path, false).is_err());
+        let key = load_audit_key(&path, true).unwrap();
+        assert_eq!(key.len(), AUDIT_KEY_SIZE);
+        assert_eq!(load_audit_key(&path, true).unwrap(), key);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
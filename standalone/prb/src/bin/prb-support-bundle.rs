@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() {
+    prb::cli::start_support_bundle().await
+}
@@ -0,0 +1,493 @@
+//! Bootstrap of the configuration of a pool from its on-chain state, for new pool operators.
+//!
+//! `prb-config init --pool <pid> --operator <key>` reads the pool from the chain and checks that
+//! the operator key can manage it, either as the owner or through a proxy of the owner. The given
+//! pRuntime endpoints are probed, matched against the workers bound to the pool by their public
+//! keys, and the workers already computing keep their on-chain stake. Then it writes:
+//!
+//! - the pool, its workers and the operator into the inventory;
+//! - the data source config, unless the file already exists;
+//! - an env file with the transaction settings of the worker manager suited to the setup.
+//!
+//! Nothing is written if anything fails to validate, and `--dry-run` only prints the report.
+
+use crate::cli::ConfigCommands;
+use crate::datasource::{
+    DataSource, DataSourceConfig, DataSourceRole, HeadersCacheHttpSource,
+    ParachainDataSourceConfig, RelaychainDataSourceConfig, SelectPolicy, SubstrateWebSocketSource,
+};
+use crate::inv_db::{
+    self, get_all_workers, get_pool_by_pid, get_worker_by_name, validate_bn_string,
+    validate_endpoint, WrappedDb,
+};
+use crate::key_provider::KeySource;
+use crate::khala::runtime_types::khala_parachain_runtime::ProxyType;
+use crate::pool_operator::{OperatorKey, PoolOperator, PoolOperatorAccess, DB};
+use crate::pruntime::create_client;
+use crate::utils::map_key;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use parity_scale_codec::Decode;
+use phactory_api::prpc::PhactoryInfo;
+use phala_pallets::compute::pool_proxy::PoolProxy;
+use phaxt::{ChainApi, KeyHasher, StorageMap};
+use serde::Serialize;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::sr25519::Pair as Sr25519Pair;
+use sp_core::Pair;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct InitArgs {
+    pub pid: u64,
+    pub name: Option<String>,
+    /// A mnemonic or a seed, or a [`KeySource`] in JSON.
+    pub operator: String,
+    pub parachain_rpc: Vec<String>,
+    pub relaychain_rpc: Vec<String>,
+    pub headers_cache: Option<String>,
+    pub endpoints: Vec<String>,
+    pub stake: Option<String>,
+    pub db_path: String,
+    pub data_source_config_path: String,
+    pub env_path: String,
+    pub dry_run: bool,
+}
+
+/// The settings of the worker manager about submitting transactions, written to the env file.
+#[derive(Debug, Serialize)]
+pub struct TxPolicy {
+    pub dual_submit_offchain_messages: bool,
+    pub sender_stall_alert_blocks: u32,
+    pub max_concurrent_rpc_ops: usize,
+}
+
+impl TxPolicy {
+    fn for_setup(n_parachain_rpc: usize, n_workers: usize) -> Self {
+        Self {
+            // Only worth it with a second endpoint to broadcast through.
+            dual_submit_offchain_messages: n_parachain_rpc > 1,
+            sender_stall_alert_blocks: 50,
+            // A few operations in flight per worker, without flooding a single node.
+            max_concurrent_rpc_ops: (n_workers * 4).clamp(16, 64),
+        }
+    }
+
+    fn to_env(&self, db_path: &str, data_source_config_path: &str, pid: u64) -> String {
+        format!(
+            "# Generated by prb-config init for pool {pid}\n\
+             DB_PATH={db_path}\n\
+             DATA_SOURCE_CONFIG_PATH={data_source_config_path}\n\
+             DUAL_SUBMIT_OFFCHAIN_MESSAGES={}\n\
+             SENDER_STALL_ALERT_BLOCKS={}\n\
+             MAX_CONCURRENT_RPC_OPS={}\n",
+            self.dual_submit_offchain_messages,
+            self.sender_stall_alert_blocks,
+            self.max_concurrent_rpc_ops,
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnChainState {
+    /// Not bound to any pool yet, prb adds it to the pool.
+    New,
+    /// Bound to the pool but not computing.
+    Idle,
+    /// Computing with the stake on chain.
+    Computing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InitWorker {
+    pub endpoint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<OnChainState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stake: Option<String>,
+    /// Why the endpoint is not added, if so.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InitReport {
+    pub dry_run: bool,
+    pub pid: u64,
+    pub pool_name: String,
+    pub owner: String,
+    pub operator: String,
+    /// Whether the operator manages the pool through a proxy of the owner.
+    pub proxied: bool,
+    pub workers: Vec<InitWorker>,
+    /// Workers bound to the pool on chain without any of the given endpoints.
+    pub unmatched_workers: Vec<String>,
+    pub tx_policy: TxPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_source_config_path: Option<String>,
+    pub env_path: String,
+}
+
+async fn fetch<T: Decode>(api: &ChainApi, key: Vec<u8>) -> Result<Option<T>> {
+    let fetched = api
+        .storage()
+        .at_latest()
+        .await?
+        .fetch_raw(key.as_slice())
+        .await?;
+    match fetched {
+        Some(fetched) => Ok(Some(T::decode(&mut fetched.as_slice())?)),
+        None => Ok(None),
+    }
+}
+
+async fn load_operator_key(operator: &str) -> Result<OperatorKey> {
+    if operator.trim_start().starts_with('{') {
+        let source: KeySource = serde_json::from_str(operator).context("Bad key source")?;
+        OperatorKey::from_source(source).await
+    } else {
+        Ok(OperatorKey::Plain(Sr25519Pair::from_string(
+            operator, None,
+        )?))
+    }
+}
+
+/// Whether `operator` can send the pool management calls on behalf of `owner`.
+async fn is_proxy_of(api: &ChainApi, owner: &AccountId32, operator: &AccountId32) -> Result<bool> {
+    // (Vec<ProxyDefinition { delegate, proxy_type, delay }>, deposit)
+    let proxies: Option<(Vec<(AccountId32, ProxyType, u32)>, u128)> =
+        fetch(api, map_key("Proxy", "Proxies", owner)).await?;
+    let Some((proxies, _)) = proxies else {
+        return Ok(false);
+    };
+    Ok(proxies.iter().any(|(delegate, proxy_type, delay)| {
+        delegate == operator
+            // Announced proxies can't call `proxy` directly.
+            && *delay == 0
+            && matches!(
+                proxy_type,
+                ProxyType::Any | ProxyType::NonTransfer | ProxyType::StakePoolManager
+            )
+    }))
+}
+
+/// `PhalaComputation::WorkerBindings`, the session account each worker is bound to.
+struct WorkerBindings;
+
+impl StorageMap for WorkerBindings {
+    const PALLET: &'static str = "PhalaComputation";
+    const MAP: &'static str = "WorkerBindings";
+    const HASHER: KeyHasher = KeyHasher::Twox64Concat;
+    type Key = phala_types::WorkerPublicKey;
+    type Value = AccountId32;
+}
+
+/// `PhalaComputation::Stakes`, the stake of each computing session.
+struct Stakes;
+
+impl StorageMap for Stakes {
+    const PALLET: &'static str = "PhalaComputation";
+    const MAP: &'static str = "Stakes";
+    const HASHER: KeyHasher = KeyHasher::Twox64Concat;
+    type Key = AccountId32;
+    type Value = u128;
+}
+
+async fn probe(endpoint: &str) -> Result<PhactoryInfo> {
+    let client = create_client(endpoint.to_string(), None);
+    tokio::time::timeout(PROBE_TIMEOUT, client.get_info(()))
+        .await
+        .map_err(|_| anyhow!("timed out"))?
+        .map_err(|e| anyhow!("{e:?}"))
+}
+
+fn validate_ws_endpoint(endpoint: &str) -> Result<()> {
+    let url = url::Url::parse(endpoint).with_context(|| format!("Bad RPC endpoint {endpoint}"))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        bail!("RPC endpoint {endpoint} is not a WebSocket URL");
+    }
+    Ok(())
+}
+
+fn substrate_sources(endpoints: &[String]) -> Vec<DataSource> {
+    endpoints
+        .iter()
+        .map(|endpoint| {
+            DataSource::SubstrateWebSocketSource(SubstrateWebSocketSource {
+                endpoint: endpoint.clone(),
+                // Fast-sync and the storage changes need archive nodes.
+                pruned: false,
+                max_concurrent_requests: 1024,
+                proxy: None,
+                role: DataSourceRole::Any,
+            })
+        })
+        .collect()
+}
+
+fn data_source_config(args: &InitArgs) -> DataSourceConfig {
+    let mut relaychain_sources = substrate_sources(&args.relaychain_rpc);
+    let mut parachain_sources = substrate_sources(&args.parachain_rpc);
+    if let Some(endpoint) = &args.headers_cache {
+        let source = DataSource::HeadersCacheHttpSource(HeadersCacheHttpSource {
+            endpoint: endpoint.clone(),
+            proxy: None,
+        });
+        relaychain_sources.push(source.clone());
+        parachain_sources.push(source);
+    }
+    DataSourceConfig {
+        relaychain: RelaychainDataSourceConfig {
+            select_policy: SelectPolicy::Failover,
+            data_sources: relaychain_sources,
+            profile: None,
+        },
+        parachain: ParachainDataSourceConfig {
+            select_policy: SelectPolicy::Failover,
+            data_sources: parachain_sources,
+            profile: None,
+        },
+        shadow: None,
+        tracing: Default::default(),
+    }
+}
+
+/// Generates the configuration of the pool, writing it unless `args.dry_run` is set.
+pub async fn init_pool(db: WrappedDb, po_db: &DB, args: InitArgs) -> Result<InitReport> {
+    let pid = args.pid;
+    if get_pool_by_pid(db.clone(), pid)?.is_some() {
+        bail!("Pool {pid} is already configured");
+    }
+    if args.parachain_rpc.is_empty() || args.relaychain_rpc.is_empty() {
+        bail!("Both parachain and relaychain RPC endpoints are required");
+    }
+    for endpoint in args.parachain_rpc.iter().chain(&args.relaychain_rpc) {
+        validate_ws_endpoint(endpoint)?;
+    }
+    if let Some(endpoint) = &args.headers_cache {
+        validate_endpoint(endpoint.clone()).context("Bad headers cache endpoint")?;
+    }
+    let default_stake = args.stake.clone().map(validate_bn_string).transpose()?;
+
+    let api = phaxt::connect(&args.parachain_rpc[0])
+        .await
+        .context("Failed to connect to the parachain")?;
+    let pool: PoolProxy<AccountId32, u128> = fetch(&api, map_key("PhalaBasePool", "Pools", &pid))
+        .await?
+        .ok_or_else(|| anyhow!("Pool {pid} not found on chain"))?;
+    let PoolProxy::StakePool(pool) = pool else {
+        bail!("Pool {pid} is a vault, only stake pools have workers");
+    };
+    let owner = pool.basepool.owner.clone();
+
+    let key = load_operator_key(&args.operator).await?;
+    let mut operator = PoolOperator {
+        pid,
+        key,
+        proxied: None,
+    };
+    let operator_account: AccountId32 = operator.public().into();
+    if operator_account != owner {
+        if !is_proxy_of(&api, &owner, &operator_account).await? {
+            bail!(
+                "{} is neither the owner {} of pool {pid} nor a proxy of the owner allowed to manage it",
+                operator_account.to_ss58check(),
+                owner.to_ss58check()
+            );
+        }
+        operator.proxied = Some(owner.clone());
+    }
+
+    let genesis_hash = hex::encode(api.genesis_hash());
+    let pool_name = args.name.clone().unwrap_or_else(|| format!("pool-{pid}"));
+    let bound: HashSet<_> = pool.workers.iter().map(hex::encode).collect();
+    // Read at the same block, the sessions of all the workers on chain by their public keys.
+    let at = api.rpc().finalized_head().await?;
+    let sessions: HashMap<String, AccountId32> = api
+        .iter_map::<WorkerBindings>(Some(at))
+        .await?
+        .into_iter()
+        .map(|(pubkey, session)| (hex::encode(pubkey), session))
+        .collect();
+    let stakes: HashMap<AccountId32, u128> = api
+        .iter_map::<Stakes>(Some(at))
+        .await?
+        .into_iter()
+        .collect();
+    let known_endpoints: HashSet<_> = get_all_workers(db.clone())?
+        .into_iter()
+        .map(|w| w.endpoint)
+        .collect();
+    let mut names = HashSet::new();
+    let mut matched = HashSet::new();
+    let mut workers = Vec::new();
+    for (i, endpoint) in args.endpoints.iter().enumerate() {
+        let mut worker = InitWorker {
+            endpoint: endpoint.clone(),
+            name: None,
+            public_key: None,
+            state: None,
+            stake: None,
+            skipped: None,
+        };
+        let endpoint = match validate_endpoint(endpoint.clone()) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                worker.skipped = Some(format!("bad endpoint: {e}"));
+                workers.push(worker);
+                continue;
+            }
+        };
+        worker.endpoint = endpoint.clone();
+        if known_endpoints.contains(&endpoint) {
+            worker.skipped = Some("already in the inventory".into());
+            workers.push(worker);
+            continue;
+        }
+        let info = match probe(&endpoint).await {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("Failed to probe the pRuntime at {endpoint}: {e}");
+                worker.skipped = Some(format!("unreachable: {e}"));
+                workers.push(worker);
+                continue;
+            }
+        };
+        if let Some(hash) = &info.genesis_block_hash {
+            if hash.trim_start_matches("0x") != genesis_hash {
+                worker.skipped = Some(format!("initialized for another chain, genesis {hash}"));
+                workers.push(worker);
+                continue;
+            }
+        }
+        // The key is only known once the runtime is initialized, until then it is a new worker.
+        let public_key = info
+            .public_key
+            .as_ref()
+            .map(|k| k.trim_start_matches("0x").to_lowercase());
+        let on_chain_stake = match &public_key {
+            Some(k) if bound.contains(k) => {
+                matched.insert(k.clone());
+                let stake = sessions.get(k).and_then(|session| stakes.get(session));
+                worker.state = Some(match stake {
+                    Some(_) => OnChainState::Computing,
+                    None => OnChainState::Idle,
+                });
+                stake.map(|s| s.to_string())
+            }
+            Some(k) => {
+                if sessions.contains_key(k) {
+                    worker.public_key = public_key.clone();
+                    worker.skipped = Some("bound to another pool".into());
+                    workers.push(worker);
+                    continue;
+                }
+                worker.state = Some(OnChainState::New);
+                None
+            }
+            None => {
+                worker.state = Some(OnChainState::New);
+                None
+            }
+        };
+        worker.public_key = public_key.clone();
+        let Some(stake) = on_chain_stake.or_else(|| default_stake.clone()) else {
+            worker.skipped = Some("not computing and no --stake given".into());
+            workers.push(worker);
+            continue;
+        };
+        worker.stake = Some(stake);
+        let name = match &public_key {
+            Some(k) => format!("{pool_name}-{}", &k[..8]),
+            None => format!("{pool_name}-{i}"),
+        };
+        if !names.insert(name.clone()) || get_worker_by_name(db.clone(), name.clone())?.is_some() {
+            worker.skipped = Some(format!("worker name {name} is taken"));
+            workers.push(worker);
+            continue;
+        }
+        worker.name = Some(name);
+        workers.push(worker);
+    }
+    let unmatched_workers: Vec<_> = pool
+        .workers
+        .iter()
+        .map(hex::encode)
+        .filter(|k| !matched.contains(k))
+        .collect();
+
+    let n_workers = workers.iter().filter(|w| w.skipped.is_none()).count();
+    let tx_policy = TxPolicy::for_setup(args.parachain_rpc.len(), n_workers);
+    let write_data_source_config = !Path::new(&args.data_source_config_path).exists();
+    if !write_data_source_config {
+        warn!(
+            "Keeping the existing data source config at {}",
+            args.data_source_config_path
+        );
+    }
+    let report = InitReport {
+        dry_run: args.dry_run,
+        pid,
+        pool_name: pool_name.clone(),
+        owner: owner.to_ss58check(),
+        operator: operator_account.to_ss58check(),
+        proxied: operator.proxied.is_some(),
+        workers,
+        unmatched_workers,
+        tx_policy,
+        data_source_config_path: write_data_source_config
+            .then(|| args.data_source_config_path.clone()),
+        env_path: args.env_path.clone(),
+    };
+    if args.dry_run {
+        return Ok(report);
+    }
+
+    inv_db::add_pool(
+        db.clone(),
+        ConfigCommands::AddPool {
+            name: pool_name,
+            pid,
+            disabled: false,
+            sync_only: false,
+        },
+    )?;
+    po_db.set_po(pid, operator)?;
+    for worker in report.workers.iter().filter(|w| w.skipped.is_none()) {
+        inv_db::add_worker(
+            db.clone(),
+            ConfigCommands::AddWorker {
+                name: worker.name.clone().expect("Added workers are named"),
+                endpoint: worker.endpoint.clone(),
+                stake: worker.stake.clone().expect("Added workers have stakes"),
+                pid,
+                disabled: false,
+                sync_only: false,
+                gatekeeper: false,
+                proxy: None,
+            },
+        )?;
+    }
+    if write_data_source_config {
+        let config = serde_yaml::to_string(&data_source_config(&args))?;
+        std::fs::write(&args.data_source_config_path, config)
+            .context("Failed to write the data source config")?;
+    }
+    let env = report
+        .tx_policy
+        .to_env(&args.db_path, &args.data_source_config_path, pid);
+    std::fs::write(&args.env_path, env).context("Failed to write the env file")?;
+    info!(
+        "Configured pool {pid} with {n_workers} workers, start prb with the settings in {}",
+        args.env_path
+    );
+    Ok(report)
+}
@@ -1,33 +1,142 @@
-use log::error;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::SendError as StdSendError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc::error::SendError;
 
 use crate::processor::{PRuntimeRequest, ProcessorEvent, ProcessorTx, WorkerEvent};
 use crate::messages::{MessagesEvent, MessagesTx};
+use crate::notifications::{Notification, Notifier};
 use crate::worker_status::{WorkerStatusEvent, WorkerStatusTx};
 
+/// Number of the distinct call sites remembered as the recent producers of a channel.
+const RECENT_PRODUCERS: usize = 8;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counters of a channel of the bus. The channels are unbounded, so the depth is derived from
+/// the events sent and received, and the dropped events are the ones sent after the consumer
+/// has gone.
+#[derive(Default)]
+pub struct ChannelStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+    max_depth: AtomicU64,
+    recent_producers: Mutex<VecDeque<&'static Location<'static>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelSnapshot {
+    pub name: String,
+    pub depth: u64,
+    pub max_depth: u64,
+    pub sent: u64,
+    pub received: u64,
+    pub dropped: u64,
+    /// The call sites which sent to the channel most recently, the latest first.
+    pub recent_producers: Vec<String>,
+}
+
+impl ChannelStats {
+    fn record_sent(&self, producer: &'static Location<'static>, ok: bool) {
+        if !ok {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.max_depth.fetch_max(self.depth(), Ordering::Relaxed);
+        let mut producers = self.recent_producers.lock().unwrap();
+        if producers.front() != Some(&producer) {
+            producers.retain(|p| *p != producer);
+            producers.push_front(producer);
+            producers.truncate(RECENT_PRODUCERS);
+        }
+    }
+
+    /// Called by the consumer of the channel for the events it took out.
+    pub fn record_received(&self, count: usize) {
+        self.received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn depth(&self) -> u64 {
+        let received = self.received.load(Ordering::Relaxed);
+        self.sent.load(Ordering::Relaxed).saturating_sub(received)
+    }
+
+    pub fn snapshot(&self, name: &str) -> ChannelSnapshot {
+        ChannelSnapshot {
+            name: name.to_string(),
+            depth: self.depth(),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            recent_producers: self
+                .recent_producers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BusStats {
+    pub processor: ChannelStats,
+    pub messages: ChannelStats,
+    pub worker_status: ChannelStats,
+}
+
+impl BusStats {
+    fn channels(&self) -> [(&'static str, &ChannelStats); 3] {
+        [
+            ("processor", &self.processor),
+            ("messages", &self.messages),
+            ("worker_status", &self.worker_status),
+        ]
+    }
+
+    pub fn snapshot(&self) -> Vec<ChannelSnapshot> {
+        self.channels()
+            .into_iter()
+            .map(|(name, stats)| stats.snapshot(name))
+            .collect()
+    }
+}
+
 #[derive(Clone)]
 pub struct Bus {
     pub processor_tx: ProcessorTx,
     pub messages_tx: MessagesTx,
     pub worker_status_tx: WorkerStatusTx,
+    pub stats: Arc<BusStats>,
 }
 
 impl Bus {
+    #[track_caller]
     pub fn send_processor_event(&self, event: ProcessorEvent) -> Result<(), StdSendError<ProcessorEvent>> {
         let result = self.processor_tx.send(event);
+        self.stats.processor.record_sent(Location::caller(), result.is_ok());
         if let Err(err) = &result {
             error!("Fail to send message to processor_tx. {}", err);
         }
         result
     }
 
+    #[track_caller]
     pub fn send_worker_event(&self, worker_id: String, event: WorkerEvent) -> Result<(), StdSendError<ProcessorEvent>> {
         self.send_processor_event(
             ProcessorEvent::WorkerEvent((worker_id, event)),
         )
     }
 
+    #[track_caller]
     pub fn send_worker_update_message(&self, worker_id: String, message: String) -> Result<(), StdSendError<ProcessorEvent>> {
         self.send_worker_event(
             worker_id,
@@ -35,6 +144,7 @@ impl Bus {
         )
     }
 
+    #[track_caller]
     pub fn send_worker_mark_error(&self, worker_id: String, message: String) -> Result<(), StdSendError<ProcessorEvent>> {
         self.send_worker_event(
             worker_id,
@@ -42,6 +152,7 @@ impl Bus {
         )
     }
 
+    #[track_caller]
     pub fn send_pruntime_request(&self, worker_id: String, request: PRuntimeRequest) -> Result<(), StdSendError<ProcessorEvent>> {
         self.send_worker_event(
             worker_id,
@@ -49,19 +160,57 @@ impl Bus {
         )
     }
 
+    #[track_caller]
     pub fn send_messages_event(&self, event: MessagesEvent) -> Result<(), SendError<MessagesEvent>> {
         let result = self.messages_tx.send(event);
+        self.stats.messages.record_sent(Location::caller(), result.is_ok());
         if let Err(err) = &result {
             error!("Fail to send message to messages_tx. {}", err);
         }
         result
     }
 
+    #[track_caller]
     pub fn send_worker_status_event(&self, event: WorkerStatusEvent) -> Result<(), SendError<WorkerStatusEvent>>{
         let result = self.worker_status_tx.send(event);
+        self.stats.worker_status.record_sent(Location::caller(), result.is_ok());
         if let Err(err) = &result {
             error!("Fail to send message to worker_status_update_tx. {}", err);
         }
         result
     }
-}
\ No newline at end of file
+}
+
+/// Samples the depth of the channels periodically, and notifies when a channel reaches
+/// `high_water_mark` or drains below half of it again. 0 disables the notifications.
+pub async fn watch_saturation(bus: Arc<Bus>, notifier: Arc<Notifier>, high_water_mark: u64) {
+    let mut saturated = [false; 3];
+    loop {
+        tokio::time::sleep(SAMPLE_INTERVAL).await;
+        let channels = bus.stats.channels();
+        for ((name, stats), saturated) in channels.into_iter().zip(&mut saturated) {
+            let depth = stats.depth();
+            debug!("Bus channel {name} depth: {depth}");
+            if high_water_mark == 0 {
+                continue;
+            }
+            if !*saturated && depth >= high_water_mark {
+                *saturated = true;
+                let snapshot = stats.snapshot(name);
+                notifier.notify(Notification::BusChannelSaturated {
+                    channel: name.to_string(),
+                    depth,
+                    high_water_mark,
+                    dropped: snapshot.dropped,
+                    recent_producers: snapshot.recent_producers,
+                });
+            } else if *saturated && depth < high_water_mark / 2 {
+                *saturated = false;
+                notifier.notify(Notification::BusChannelRecovered {
+                    channel: name.to_string(),
+                    depth,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Time series of the on-chain computation sessions of the managed workers.
+//!
+//! Every `--computation-sample-interval` seconds, the session of each worker with a known public
+//! key is read from the finalized parachain state through the data sources: its state, V, Ve, P
+//! and the total reward of the session. The samples are persisted in the pool operator db, so
+//! that the on-chain earnings of a worker can be correlated with its offchain message activity
+//! without another tool. Samples older than `--computation-sample-retention-days` are pruned.
+
+use crate::pool_operator::DB;
+use crate::use_parachain_api;
+use crate::utils::map_key;
+use crate::wm::WrappedWorkerManagerContext;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{debug, info, warn};
+use parity_scale_codec::Decode;
+use phala_pallets::pallet_computation::WorkerState;
+use phaxt::ChainApi;
+use serde::{Deserialize, Serialize};
+use sp_core::crypto::AccountId32;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static SAMPLE_KEY_PREFIX: &str = "computation_sample:";
+
+/// `pallet_computation::SessionInfo`, whose fields are not all public. The nested `Benchmark`
+/// and `SessionStats` are flattened, which doesn't change the encoding.
+#[derive(Decode)]
+struct RawSessionInfo {
+    state: WorkerState,
+    ve: u128,
+    v: u128,
+    _v_updated_at: u64,
+    p_init: u32,
+    p_instant: u32,
+    _iterations: u64,
+    _working_start_time: u64,
+    _challenge_time_last: u64,
+    _cool_down_start: u64,
+    total_reward: u128,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ComputationSample {
+    pub worker: String,
+    pub at: DateTime<Utc>,
+    /// The finalized parachain block the session was read at.
+    pub block: u32,
+    pub session: String,
+    pub state: WorkerState,
+    pub v: f64,
+    pub ve: f64,
+    pub p_init: u32,
+    pub p_instant: u32,
+    /// The total reward of the session, in the smallest unit.
+    pub total_reward: u128,
+}
+
+fn sample_key(worker: &str, at: DateTime<Utc>) -> String {
+    // Zero-padded so that the samples of a worker are sorted by time.
+    format!("{SAMPLE_KEY_PREFIX}{worker}:{:020}", at.timestamp_millis())
+}
+
+fn u64f64_to_f64(bits: u128) -> f64 {
+    bits as f64 / (1u128 << 64) as f64
+}
+
+pub struct ComputationSamples {
+    db: Arc<DB>,
+    latest: Mutex<BTreeMap<String, ComputationSample>>,
+}
+
+impl ComputationSamples {
+    pub fn load(db: Arc<DB>) -> Result<Self> {
+        let mut latest = BTreeMap::new();
+        for sample in iter_samples(&db, SAMPLE_KEY_PREFIX) {
+            let sample = sample?;
+            latest.insert(sample.worker.clone(), sample);
+        }
+        info!(
+            "Loaded the computation samples of {} workers.",
+            latest.len()
+        );
+        Ok(Self {
+            db,
+            latest: Mutex::new(latest),
+        })
+    }
+
+    fn insert(&self, sample: ComputationSample) -> Result<()> {
+        self.db.put(
+            sample_key(&sample.worker, sample.at),
+            serde_json::to_vec(&sample)?,
+        )?;
+        self.latest
+            .lock()
+            .unwrap()
+            .insert(sample.worker.clone(), sample);
+        Ok(())
+    }
+
+    /// The last sample of each worker.
+    pub fn latest(&self) -> Vec<ComputationSample> {
+        self.latest.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The samples of the worker since the given time, oldest first.
+    pub fn history(
+        &self,
+        worker: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<ComputationSample>> {
+        let prefix = format!("{SAMPLE_KEY_PREFIX}{worker}:");
+        let mut samples = Vec::new();
+        for sample in iter_samples(&self.db, &prefix) {
+            let sample = sample?;
+            if since.map_or(true, |since| sample.at >= since) {
+                samples.push(sample);
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Removes the samples taken before `cutoff`.
+    fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let cutoff_ms = cutoff.timestamp_millis();
+        let mut keys = Vec::new();
+        for item in self.db.prefix_iterator(SAMPLE_KEY_PREFIX) {
+            let (key, _) = item?;
+            if !key.starts_with(SAMPLE_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            let taken_ms = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|key| key.rsplit(':').next())
+                .and_then(|ms| ms.parse::<i64>().ok());
+            if matches!(taken_ms, Some(ms) if ms < cutoff_ms) {
+                keys.push(key);
+            }
+        }
+        for key in keys.iter() {
+            self.db.delete(key)?;
+        }
+        self.latest
+            .lock()
+            .unwrap()
+            .retain(|_, sample| sample.at >= cutoff);
+        Ok(keys.len())
+    }
+}
+
+fn iter_samples<'a>(
+    db: &'a DB,
+    prefix: &'a str,
+) -> impl Iterator<Item = Result<ComputationSample>> + 'a {
+    db.prefix_iterator(prefix)
+        .map_while(move |item| match item {
+            Ok((key, _)) if !key.starts_with(prefix.as_bytes()) => None,
+            Ok((_, value)) => Some(serde_json::from_slice(&value).map_err(Into::into)),
+            Err(err) => Some(Err(err.into())),
+        })
+}
+
+/// Reads the session the worker with the hex `public_key` is bound to.
+async fn fetch_session(
+    api: &ChainApi,
+    at: phaxt::Hash,
+    public_key: &str,
+) -> Result<Option<(AccountId32, RawSessionInfo)>> {
+    let raw: [u8; 32] = hex::decode(public_key.trim_start_matches("0x"))?
+        .try_into()
+        .map_err(|_| anyhow!("Bad public key {public_key}"))?;
+    let pubkey = phala_types::WorkerPublicKey::from_raw(raw);
+    let storage = api.storage().at(at);
+    let Some(session) = storage
+        .fetch_raw(&map_key("PhalaComputation", "WorkerBindings", &pubkey))
+        .await?
+    else {
+        return Ok(None);
+    };
+    let session = AccountId32::decode(&mut session.as_slice())?;
+    let Some(info) = storage
+        .fetch_raw(&map_key("PhalaComputation", "Sessions", &session))
+        .await?
+    else {
+        return Ok(None);
+    };
+    let info = RawSessionInfo::decode(&mut info.as_slice())?;
+    Ok(Some((session, info)))
+}
+
+async fn sample_workers(ctx: &WrappedWorkerManagerContext) -> Result<()> {
+    let workers = ctx
+        .worker_status_map
+        .lock()
+        .await
+        .values()
+        .filter_map(|status| {
+            let public_key = status.phactory_info.as_ref()?.public_key.clone()?;
+            Some((status.worker.id.clone(), public_key))
+        })
+        .collect::<Vec<_>>();
+
+    let api = use_parachain_api!(ctx.dsm, false)
+        .ok_or_else(|| anyhow!("No parachain data source available"))?;
+    let hash = api.rpc().finalized_head().await?;
+    let block = api
+        .rpc()
+        .header(Some(hash))
+        .await?
+        .ok_or_else(|| anyhow!("Finalized header {hash:?} not found"))?
+        .number;
+    let at = Utc::now();
+
+    let mut n_sampled = 0;
+    for (worker, public_key) in workers {
+        let (session, info) = match fetch_session(&api, hash, &public_key).await {
+            Ok(Some(session)) => session,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("[{worker}] Failed to read the computation session: {err}");
+                continue;
+            }
+        };
+        ctx.computation_samples.insert(ComputationSample {
+            worker,
+            at,
+            block,
+            session: session.to_string(),
+            state: info.state,
+            v: u64f64_to_f64(info.v),
+            ve: u64f64_to_f64(info.ve),
+            p_init: info.p_init,
+            p_instant: info.p_instant,
+            total_reward: info.total_reward,
+        })?;
+        n_sampled += 1;
+    }
+    debug!("Sampled the computation sessions of {n_sampled} workers at block {block}.");
+
+    let cutoff = at - ChronoDuration::days(ctx.args.computation_sample_retention_days);
+    let n_pruned = ctx.computation_samples.prune(cutoff)?;
+    if n_pruned > 0 {
+        debug!("Pruned {n_pruned} computation samples.");
+    }
+    Ok(())
+}
+
+pub async fn sampling_loop(ctx: WrappedWorkerManagerContext) -> Result<()> {
+    let interval = ctx.args.computation_sample_interval;
+    if interval == 0 {
+        info!("Computation sampling disabled.");
+        return std::future::pending().await;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        if let Err(err) = sample_workers(&ctx).await {
+            warn!("Failed to sample the computation sessions: {err}");
+        }
+    }
+}
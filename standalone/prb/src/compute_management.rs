@@ -1,13 +1,23 @@
 use crate::bus::Bus;
+use crate::jobs::{JobKind, JobRequest};
 use crate::processor::*;
+use crate::pruntime::PRuntimeClient;
 use crate::tx::TxManager;
 use crate::worker::WorkerLifecycleState;
+use anyhow::{anyhow, Context, Result};
 use chrono::Utc;
-use log::{error, info, trace};
-use phactory_api::prpc::InitRuntimeResponse;
+use log::{error, info, trace, warn};
+use phactory_api::prpc::{GetRuntimeInfoRequest, InitRuntimeResponse};
+use phaxt::ChainError;
+use sp_core::crypto::AccountId32;
 use sp_core::sr25519::Public as Sr25519Public;
 use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of registrations of a worker, each with a fresh attestation, before giving up.
+const MAX_REGISTER_ATTEMPTS: u32 = 3;
+const REGISTER_RETRY_DELAY: Duration = Duration::from_secs(12);
 
 #[derive(Debug, PartialEq)]
 pub enum ComputeManagementStage {
@@ -187,46 +197,82 @@ impl Processor {
     }
 }
 
+/// Whether a failed registration is worth another attempt with a fresh attestation. The
+/// attestation ages while the registration is retried, so it is never reused.
+fn should_register_again(err: &anyhow::Error) -> bool {
+    match ChainError::classify(err) {
+        ChainError::Module { pallet, variant } => {
+            pallet == "PhalaRegistry" && variant == "OutdatedIASReport"
+        }
+        err => err.is_retryable(),
+    }
+}
+
+async fn register_once(
+    txm: Arc<TxManager>,
+    worker_id: &str,
+    pool_id: u64,
+    response: InitRuntimeResponse,
+    pccs_url: &str,
+    pccs_timeout_secs: u64,
+) -> Result<()> {
+    let attestation = response
+        .attestation
+        .ok_or_else(|| anyhow!("worker has no attestation"))?;
+    let v2 = attestation.payload.is_none();
+    let attestation = pherry::attestation_to_report(attestation, pccs_url, pccs_timeout_secs)
+        .await
+        .context("Failed to get attestation report")?;
+    let job = JobRequest::new(
+        format!("register:{worker_id}"),
+        JobKind::RegisterWorker {
+            pid: pool_id,
+            pruntime_info: response.encoded_runtime_info,
+            attestation,
+            v2,
+        },
+    );
+    txm.run_job(job).await
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn do_register(
     bus: Arc<Bus>,
     txm: Arc<TxManager>,
+    client: Arc<PRuntimeClient>,
     worker_id: String,
     pool_id: u64,
+    operator: Option<AccountId32>,
     response: InitRuntimeResponse,
     pccs_url: String,
     pccs_timeout_secs: u64,
 ) {
-    let attestation = match response.attestation {
-        Some(attestation) => attestation,
-        None => {
-            error!("[{}] Worker has no attestation.", worker_id);
-            let _ = bus.send_worker_mark_error(
-                worker_id,
-                "Failed to register: worker has no attestation".to_string(),
-            );
-            return;
-        },
-    };
-    let v2 = attestation.payload.is_none();
-    let attestation = pherry::attestation_to_report(
-        attestation,
-        &pccs_url,
-        pccs_timeout_secs,
-    )
-    .await;
-    let attestation = match attestation {
-        Ok(attestation) => attestation,
-        Err(err) => {
-            error!("[{}] Failed to get attestation report. {}", worker_id, err);
-            let _ = bus.send_worker_mark_error(
-                worker_id,
-                err.to_string(),
-            );
-            return;
-        },
+    let mut response = response;
+    let mut attempts = 1;
+    let result = loop {
+        let result = register_once(
+            txm.clone(),
+            &worker_id,
+            pool_id,
+            response,
+            &pccs_url,
+            pccs_timeout_secs,
+        )
+        .await;
+        match result {
+            Err(err) if attempts < MAX_REGISTER_ATTEMPTS && should_register_again(&err) => {
+                warn!("[{}] Worker Register Failed, retrying with a fresh attestation: {}", worker_id, err);
+                tokio::time::sleep(REGISTER_RETRY_DELAY * attempts).await;
+                attempts += 1;
+                let request = GetRuntimeInfoRequest::new(true, operator.clone());
+                response = match client.get_runtime_info(request).await {
+                    Ok(response) => response,
+                    Err(err) => break Err(anyhow!("Failed to refresh the attestation: {}", err)),
+                };
+            },
+            result => break result,
+        }
     };
-
-    let result = txm.register_worker(pool_id, response.encoded_runtime_info, attestation, v2).await;
     match result {
         Ok(_) => {
             info!("[{}] Worker Register Completed.", worker_id);
@@ -240,10 +286,10 @@ pub async fn do_register(
             let _ = bus.send_pruntime_request(worker_id.clone(), PRuntimeRequest::RegularGetInfo);
         },
         Err(err) => {
-            error!("[{}] Worker Register Failed: {}", worker_id, err);
+            error!("[{}] Worker Register Failed: {:#}", worker_id, err);
             let _ = bus.send_worker_mark_error(
                 worker_id,
-                err.to_string(),
+                format!("Failed to register: {:#}", err),
             );
         },
     }
@@ -256,7 +302,12 @@ async fn do_add_worker_to_pool(
     pool_id: u64,
     worker_public_key: Sr25519Public,
 ) {
-    let result = txm.add_worker(pool_id, worker_public_key).await;
+    let worker = format!("0x{}", hex::encode(worker_public_key));
+    let job = JobRequest::new(
+        format!("add_worker:{pool_id}:{worker}"),
+        JobKind::AddWorker { pid: pool_id, worker },
+    );
+    let result = txm.run_job(job).await;
     if let Err(err) = result {
         let err_msg = format!("Failed to add_worker_to_pool. {}", err);
         error!("[{}] {}", worker_id, err_msg);
@@ -272,10 +323,42 @@ async fn do_start_computing(
     worker_public_key: Sr25519Public,
     stake: String,
 ) {
-    let result = txm.start_computing(pool_id, worker_public_key, stake).await;
+    let worker = format!("0x{}", hex::encode(worker_public_key));
+    let job = JobRequest::new(
+        format!("start_computing:{pool_id}:{worker}"),
+        JobKind::StartComputing { pid: pool_id, worker, stake },
+    );
+    let result = txm.run_job(job).await;
     if let Err(err) = result {
         let err_msg = format!("Failed to start computing. {}", err);
         error!("[{}] {}", worker_id, err_msg);
         let _ = bus.send_worker_mark_error(worker_id, err_msg);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_error(pallet: &str, variant: &str) -> anyhow::Error {
+        ChainError::Module {
+            pallet: pallet.into(),
+            variant: variant.into(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn stale_attestations_are_refreshed() {
+        assert!(should_register_again(&module_error("PhalaRegistry", "OutdatedIASReport")));
+        // As returned by a finished job, which only keeps the message.
+        assert!(should_register_again(&anyhow!("PhalaRegistry::OutdatedIASReport")));
+        assert!(should_register_again(&ChainError::Timeout.into()));
+    }
+
+    #[test]
+    fn rejected_registrations_are_not_retried() {
+        assert!(!should_register_again(&module_error("PhalaRegistry", "InvalidSignature")));
+        assert!(!should_register_again(&module_error("PhalaComputation", "OutdatedIASReport")));
+        assert!(!should_register_again(&anyhow!("worker has no attestation")));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::api::ApiError::{PoolNotFound, WriteFailed};
 use crate::api::OkResponse;
+use crate::bootstrap::{init_pool, InitArgs};
 use crate::bus::Bus;
 use crate::cli::{AccountType, ConfigCliArgs, ConfigCommands};
 use crate::inv_db;
@@ -8,7 +9,12 @@ use crate::inv_db::{
     get_pool_by_pid_with_workers, get_worker_by_name, remove_worker, setup_inventory_db,
     update_worker, WrappedDb,
 };
-use crate::pool_operator::{get_options, PoolOperator, PoolOperatorAccess, PoolOperatorForSerialize, DB};
+use crate::key_provider::{write_encrypted_key_file, KeySource};
+use crate::legacy_import::{import_legacy, read_legacy_dump};
+use crate::offline_tx::{export_unsigned, submit_signed};
+use crate::pool_operator::{
+    get_options, OperatorKey, PoolOperator, PoolOperatorAccess, PoolOperatorForSerialize, DB,
+};
 use crate::processor::{ProcessorEvent, WorkerEvent};
 use anyhow::{anyhow, Context, Result};
 use schnorrkel::SecretKey;
@@ -122,7 +128,7 @@ pub async fn cli_main(args: ConfigCliArgs) -> Result<()> {
             let pid = *pid;
             let po = PoolOperator {
                 pid,
-                pair: match account_type {
+                key: OperatorKey::Plain(match account_type {
                     AccountType::Seed => Sr22519Pair::from_string(account, None)?,
                     AccountType::SecretKey => {
                         let bytes = hex::decode(account)?;
@@ -131,7 +137,26 @@ pub async fn cli_main(args: ConfigCliArgs) -> Result<()> {
                             .map_err(|e| anyhow!(e.to_string()))?;
                         Sr22519Pair::from(key)
                     }
+                }),
+                proxied: match proxied_account_id.clone() {
+                    None => None,
+                    Some(i) => Some(AccountId32::from_string(&i)?),
                 },
+            };
+            let po = po_db.set_po(pid, po)?;
+            let po = serde_json::to_string_pretty::<PoolOperatorForSerialize>(&(&po).into())?;
+            println!("{po}");
+        }
+        ConfigCommands::SetPoolOperatorKeySource {
+            pid,
+            source,
+            proxied_account_id,
+        } => {
+            let pid = *pid;
+            let source: KeySource = serde_json::from_str(source).context("Bad key source")?;
+            let po = PoolOperator {
+                pid,
+                key: OperatorKey::from_source(source).await?,
                 proxied: match proxied_account_id.clone() {
                     None => None,
                     Some(i) => Some(AccountId32::from_string(&i)?),
@@ -141,6 +166,90 @@ pub async fn cli_main(args: ConfigCliArgs) -> Result<()> {
             let po = serde_json::to_string_pretty::<PoolOperatorForSerialize>(&(&po).into())?;
             println!("{po}");
         }
+        ConfigCommands::EncryptOperatorKey { account, output } => {
+            let public = write_encrypted_key_file(account, output)?;
+            println!(
+                "Wrote the encrypted key of {} to {output}",
+                AccountId32::from(public)
+            );
+        }
+        ConfigCommands::ImportLegacy { file, dry_run } => {
+            let dump = read_legacy_dump(file)?;
+            let report = import_legacy(db, &po_db, dump, *dry_run)?;
+            let report = serde_json::to_string_pretty(&report)?;
+            println!("{report}");
+        }
+        ConfigCommands::Init {
+            pool,
+            name,
+            operator,
+            parachain_rpc,
+            relaychain_rpc,
+            headers_cache,
+            endpoints,
+            stake,
+            data_source_config_path,
+            env_path,
+            dry_run,
+        } => {
+            let in_db_dir = |file: &str| {
+                Path::new(&args.db_path)
+                    .join(file)
+                    .to_string_lossy()
+                    .to_string()
+            };
+            let init_args = InitArgs {
+                pid: *pool,
+                name: name.clone(),
+                operator: operator.clone(),
+                parachain_rpc: parachain_rpc.clone(),
+                relaychain_rpc: relaychain_rpc.clone(),
+                headers_cache: headers_cache.clone(),
+                endpoints: endpoints.clone(),
+                stake: stake.clone(),
+                db_path: args.db_path.clone(),
+                data_source_config_path: data_source_config_path
+                    .clone()
+                    .unwrap_or_else(|| in_db_dir("ds.yml")),
+                env_path: env_path.clone().unwrap_or_else(|| in_db_dir("prb.env")),
+                dry_run: *dry_run,
+            };
+            let report = init_pool(db, &po_db, init_args).await?;
+            let report = serde_json::to_string_pretty(&report)?;
+            println!("{report}");
+        }
+        ConfigCommands::ExportUnsigned {
+            parachain_rpc,
+            signer,
+            call,
+            nonce,
+            longevity,
+            tip,
+            output,
+        } => {
+            let unsigned = export_unsigned(
+                parachain_rpc,
+                signer,
+                call,
+                *nonce,
+                *longevity,
+                *tip,
+                output,
+            )
+            .await?;
+            println!(
+                "Wrote the extrinsic to {output}, the payload to sign is 0x{}",
+                hex::encode(unsigned.signer_payload)
+            );
+        }
+        ConfigCommands::SubmitSigned {
+            parachain_rpc,
+            file,
+            signature,
+        } => {
+            let hash = submit_signed(parachain_rpc, file, signature).await?;
+            println!("Submitted extrinsic {hash}");
+        }
     };
     Ok(())
 }
@@ -206,7 +315,7 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, bus: Arc<Bus>, command:
                 v.clone(),
                 get_pool_by_pid(db.clone(), pid).unwrap().map(|p| p.sync_only),
                 po_db.get_po(pid)?.map(|po| po.operator()),
-                crate::pruntime::create_client(v.endpoint.clone()),
+                crate::pruntime::create_client(v.endpoint.clone(), v.proxy.as_deref()),
             )));
             let v = serde_json::to_string_pretty(&v)?;
             Ok(v)
@@ -259,7 +368,7 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, bus: Arc<Bus>, command:
         } => {
             let po = PoolOperator {
                 pid,
-                pair: match account_type {
+                key: OperatorKey::Plain(match account_type {
                     AccountType::Seed => Sr22519Pair::from_string(account.as_str(), None)?,
                     AccountType::SecretKey => {
                         let bytes = hex::decode(account)?;
@@ -268,7 +377,26 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, bus: Arc<Bus>, command:
                             .map_err(|e| anyhow!(e.to_string()))?;
                         Sr22519Pair::from(key)
                     }
+                }),
+                proxied: match proxied_account_id {
+                    None => None,
+                    Some(i) => Some(AccountId32::from_string(&i)?),
                 },
+            };
+            let po = po_db.set_po(pid, po)?;
+            let _ = bus.send_processor_event(ProcessorEvent::UpdatePoolOperator((pid, Some(po.operator()))));
+            let po = serde_json::to_string_pretty::<PoolOperatorForSerialize>(&(&po).into())?;
+            Ok(po)
+        }
+        ConfigCommands::SetPoolOperatorKeySource {
+            pid,
+            source,
+            proxied_account_id,
+        } => {
+            let source: KeySource = serde_json::from_str(&source).context("Bad key source")?;
+            let po = PoolOperator {
+                pid,
+                key: OperatorKey::from_source(source).await?,
                 proxied: match proxied_account_id {
                     None => None,
                     Some(i) => Some(AccountId32::from_string(&i)?),
@@ -279,5 +407,21 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, bus: Arc<Bus>, command:
             let po = serde_json::to_string_pretty::<PoolOperatorForSerialize>(&(&po).into())?;
             Ok(po)
         }
+        ConfigCommands::EncryptOperatorKey { .. } => {
+            // The key would have to be sent to the API in plain.
+            Err(anyhow!("Encrypting keys is only available with prb-config"))
+        }
+        ConfigCommands::ImportLegacy { .. } => {
+            // Imported entries would not be picked up by the running processor.
+            Err(anyhow!("Importing is only available with prb-config while prb is stopped"))
+        }
+        ConfigCommands::Init { .. } => {
+            // The generated data source config and env file only take effect on the next start.
+            Err(anyhow!("Bootstrapping a pool is only available with prb-config while prb is stopped"))
+        }
+        ConfigCommands::ExportUnsigned { .. } | ConfigCommands::SubmitSigned { .. } => {
+            // The point is to keep the key off prb, there is nothing to do with the running one.
+            Err(anyhow!("Offline signing is only available with prb-config"))
+        }
     }
 }
@@ -1,7 +1,10 @@
 use crate::datasource::DataSourceError::*;
+use crate::proxy::Route;
+use crate::request_trace::{RequestTraceConfig, RequestTracer};
+use crate::shadow::{Shadow, ShadowConfig};
 use anyhow::{anyhow, Context, Result};
 use jsonrpsee::{
-    async_client::ClientBuilder,
+    async_client::{Client as WsClient, ClientBuilder},
     client_transport::ws::{Uri, WsTransportClientBuilder},
 };
 use log::{debug, error, info, warn};
@@ -14,7 +17,7 @@ use phactory_api::{
 };
 use phala_types::AttestationProvider;
 use phaxt::subxt::rpc::types as subxt_types;
-use phaxt::{ChainApi, RpcClient};
+use phaxt::{ChainApi, ChainProfile, ConnectionEvent};
 
 use moka::future::Cache;
 use pherry::types::ConvertTo;
@@ -40,6 +43,12 @@ static CACHE_SIZE_EXPANSION: f64 = 1.25;
 pub struct DataSourceConfig {
     pub relaychain: RelaychainDataSourceConfig,
     pub parachain: ParachainDataSourceConfig,
+    /// Sources compared with the current ones without serving requests, see [`crate::shadow`].
+    #[serde(default)]
+    pub shadow: Option<ShadowConfig>,
+    /// Latency tracking of the requests, see [`crate::request_trace`].
+    #[serde(default)]
+    pub tracing: RequestTraceConfig,
 }
 
 impl DataSourceConfig {
@@ -53,12 +62,18 @@ impl DataSourceConfig {
 pub struct RelaychainDataSourceConfig {
     pub select_policy: SelectPolicy,
     pub data_sources: Vec<DataSource>,
+    /// A named chain profile or the path of a JSON profile, `kusama` if not set.
+    #[serde(default)]
+    pub profile: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ParachainDataSourceConfig {
     pub select_policy: SelectPolicy,
     pub data_sources: Vec<DataSource>,
+    /// A named chain profile or the path of a JSON profile, `khala` if not set.
+    #[serde(default)]
+    pub profile: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -79,15 +94,59 @@ pub struct SubstrateWebSocketSource {
     pub pruned: bool,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: usize,
+    /// Overrides the global proxy, see [`crate::proxy`].
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub role: DataSourceRole,
 }
 
 fn default_max_concurrent_requests() -> usize {
     1024
 }
 
+/// What a substrate source is used for, e.g. a cheap read replica for the queries and the
+/// subscriptions, and a trusted full node for the submissions.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+pub enum DataSourceRole {
+    #[default]
+    Any,
+    Read,
+    /// Only used for the parachain, the transactions are never sent to the relaychain.
+    Submit,
+}
+
+impl DataSourceRole {
+    pub fn can_read(self) -> bool {
+        self != Self::Submit
+    }
+
+    pub fn can_submit(self) -> bool {
+        self != Self::Read
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct HeadersCacheHttpSource {
     pub endpoint: String,
+    /// Overrides the global proxy, see [`crate::proxy`].
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+/// Whether a configured data source is currently connected.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DataSourceHealth {
+    pub chain: String,
+    pub kind: String,
+    pub endpoint: String,
+    pub pruned: bool,
+    #[serde(default)]
+    pub role: DataSourceRole,
+    pub connected: bool,
+    /// Consecutive failures of submitting transactions through the source.
+    #[serde(default)]
+    pub submit_failures: u32,
 }
 
 pub struct SubstrateWebSocketSourceInstance {
@@ -147,10 +206,10 @@ impl DataSourceCacheItem {
             },
             DataSourceCacheItem::ParaHeader(e) => {
                 (e.encoded_size() as f64 * CACHE_SIZE_EXPANSION) as _
-            },
+            }
             DataSourceCacheItem::ParaHeadersToSyncWithoutProof(e) => {
                 (e.encoded_len() as f64 * CACHE_SIZE_EXPANSION) as _
-            },
+            }
         };
         ret
     }
@@ -185,24 +244,38 @@ pub struct DataSourceManager {
     pub relaychain_headers_cache_map: WrappedHeadersCacheHttpSourceMap,
     pub parachain_rpc_client_ids: DataSourceIdList,
     pub parachain_full_rpc_client_ids: DataSourceIdList,
+    pub parachain_submit_rpc_client_ids: DataSourceIdList,
     pub parachain_headers_cache_ids: DataSourceIdList,
     pub parachain_rpc_client_map: WrappedSubstrateWebSocketSourceMap,
     pub parachain_headers_cache_map: WrappedHeadersCacheHttpSourceMap,
     pub is_relaychain_full: bool,
     pub is_parachain_full: bool,
     pub cache: Cache<String, Arc<DataSourceCacheItem>>,
+    pub shadow: Option<Arc<Shadow>>,
+    pub relaychain_profile: ChainProfile,
+    pub parachain_profile: ChainProfile,
+    pub tracer: RequestTracer,
+    /// Consecutive submission failures by source id, a success resets them.
+    submit_failures: std::sync::Mutex<HashMap<String, u32>>,
 }
 
 macro_rules! dump_ds_ids_from_config {
     ($source:expr) => {{
         let mut full_rpc_ids = Vec::new();
         let mut rpc_ids = Vec::new();
+        let mut submit_rpc_ids = Vec::new();
         let mut hc_ids = Vec::new();
 
         for c in $source.data_sources.clone() {
             match c {
                 DataSource::SubstrateWebSocketSource(c) => {
                     let id = Uuid::new_v5(&Uuid::NAMESPACE_URL, c.endpoint.as_bytes()).to_string();
+                    if c.role.can_submit() {
+                        submit_rpc_ids.push(id.clone());
+                    }
+                    if !c.role.can_read() {
+                        continue;
+                    }
                     rpc_ids.push(id.clone());
                     if !c.pruned {
                         full_rpc_ids.push(id);
@@ -214,7 +287,7 @@ macro_rules! dump_ds_ids_from_config {
                 }
             }
         }
-        (full_rpc_ids, rpc_ids, hc_ids)
+        (full_rpc_ids, rpc_ids, submit_rpc_ids, hc_ids)
     }};
 }
 
@@ -241,7 +314,8 @@ macro_rules! ds_loop {
                 match config {
                     DataSource::SubstrateWebSocketSource(config) => {
                         let map = &dsm.[<$t _rpc_client_map>];
-                        Self::subxt_loop(config, map.clone()).await;
+                        let profile = dsm.[<$t _profile>].clone();
+                        Self::subxt_loop(config, profile, map.clone()).await;
                     }
                     DataSource::HeadersCacheHttpSource(config) => {
                         let map = &dsm.[<$t _headers_cache_map>];
@@ -304,6 +378,46 @@ impl DataSourceManager {
         None
     }
 
+    /// Returns a parachain RPC client to submit transactions through, other than the one with
+    /// `exclude_id` if given. The sources with fewer consecutive submission failures are
+    /// preferred, in the order of the select policy otherwise.
+    pub async fn current_parachain_submit_client(
+        self: Arc<Self>,
+        exclude_id: Option<&str>,
+    ) -> Option<WrappedSubstrateWebSocketSourceInstance> {
+        let mut ids = self.parachain_submit_rpc_client_ids.clone();
+        if let Some(exclude_id) = exclude_id {
+            ids.retain(|i| i != exclude_id);
+        }
+        match &self.config.parachain.select_policy {
+            SelectPolicy::Random => ids.shuffle(&mut thread_rng()),
+            SelectPolicy::Failover => {}
+        };
+        {
+            let failures = self.submit_failures.lock().unwrap();
+            ids.sort_by_key(|i| failures.get(i).copied().unwrap_or(0));
+        }
+
+        let map = self.parachain_rpc_client_map.clone();
+        let map = map.read().await;
+        for i in ids {
+            if let Some(i) = map.get(&i) {
+                return Some(i.clone());
+            }
+        }
+        None
+    }
+
+    /// Records the result of submitting a transaction through the source with `id`.
+    pub fn report_submission(&self, id: &str, ok: bool) {
+        let mut failures = self.submit_failures.lock().unwrap();
+        if ok {
+            failures.remove(id);
+        } else {
+            *failures.entry(id.to_string()).or_default() += 1;
+        }
+    }
+
     pub async fn current_relaychain_headers_cache(
         self: Arc<Self>,
     ) -> Option<WrappedHeadersCacheHttpSourceInstance> {
@@ -344,12 +458,81 @@ impl DataSourceManager {
         None
     }
 
+    /// Records a sender whose next sequence is compared with the shadow sources, if any.
+    pub fn observe_mq_sender(&self, sender: &phala_types::messaging::MessageOrigin) {
+        if let Some(shadow) = &self.shadow {
+            shadow.observe_sender(sender);
+        }
+    }
+
+    /// Lists the configured data sources with their connection state, a source is connected
+    /// while it stays in the client map of its chain.
+    pub async fn health(&self) -> Vec<DataSourceHealth> {
+        let mut ret = Vec::new();
+        for (chain, data_sources, rpc_map, hc_map) in [
+            (
+                "relaychain",
+                &self.config.relaychain.data_sources,
+                &self.relaychain_rpc_client_map,
+                &self.relaychain_headers_cache_map,
+            ),
+            (
+                "parachain",
+                &self.config.parachain.data_sources,
+                &self.parachain_rpc_client_map,
+                &self.parachain_headers_cache_map,
+            ),
+        ] {
+            let rpc_map = rpc_map.read().await;
+            let hc_map = hc_map.read().await;
+            let submit_failures = self.submit_failures.lock().unwrap().clone();
+            for c in data_sources {
+                let health = match c {
+                    DataSource::SubstrateWebSocketSource(c) => {
+                        let id =
+                            Uuid::new_v5(&Uuid::NAMESPACE_URL, c.endpoint.as_bytes()).to_string();
+                        DataSourceHealth {
+                            chain: chain.to_string(),
+                            kind: "SubstrateWebSocketSource".to_string(),
+                            endpoint: c.endpoint.clone(),
+                            pruned: c.pruned,
+                            role: c.role,
+                            connected: rpc_map.contains_key(&id),
+                            submit_failures: submit_failures.get(&id).copied().unwrap_or(0),
+                        }
+                    }
+                    DataSource::HeadersCacheHttpSource(c) => {
+                        let id =
+                            Uuid::new_v5(&Uuid::NAMESPACE_URL, c.endpoint.as_bytes()).to_string();
+                        DataSourceHealth {
+                            chain: chain.to_string(),
+                            kind: "HeadersCacheHttpSource".to_string(),
+                            endpoint: c.endpoint.clone(),
+                            pruned: false,
+                            role: DataSourceRole::Read,
+                            connected: hc_map.contains_key(&id),
+                            submit_failures: 0,
+                        }
+                    }
+                };
+                ret.push(health);
+            }
+        }
+        ret
+    }
+
     pub async fn wait_until_rpc_avail(self: Arc<Self>, full: bool) {
         info!("Waiting for Substrate RPC clients to be available...");
         loop {
             if (self.clone().current_relaychain_rpc_client(full).await).is_some()
                 && (self.clone().current_parachain_rpc_client(full).await).is_some()
-                && (self.is_relaychain_full || self.clone().current_relaychain_headers_cache().await.is_some())
+                && (self.clone().current_parachain_submit_client(None).await).is_some()
+                && (self.is_relaychain_full
+                    || self
+                        .clone()
+                        .current_relaychain_headers_cache()
+                        .await
+                        .is_some())
             {
                 break;
             }
@@ -376,14 +559,36 @@ impl DataSourceManager {
         let (
             relaychain_full_rpc_client_ids,
             relaychain_rpc_client_ids,
+            _,
             relaychain_headers_cache_ids,
-        ): (DataSourceIdList, DataSourceIdList, DataSourceIdList) =
-            dump_ds_ids_from_config!(config.relaychain);
-        let (parachain_full_rpc_client_ids, parachain_rpc_client_ids, parachain_headers_cache_ids): (
+        ): (
+            DataSourceIdList,
+            DataSourceIdList,
+            DataSourceIdList,
+            DataSourceIdList,
+        ) = dump_ds_ids_from_config!(config.relaychain);
+        let (
+            parachain_full_rpc_client_ids,
+            parachain_rpc_client_ids,
+            parachain_submit_rpc_client_ids,
+            parachain_headers_cache_ids,
+        ): (
+            DataSourceIdList,
             DataSourceIdList,
             DataSourceIdList,
             DataSourceIdList,
         ) = dump_ds_ids_from_config!(config.parachain);
+        if config.relaychain.data_sources.iter().any(|ds| {
+            matches!(ds, DataSource::SubstrateWebSocketSource(c) if c.role == DataSourceRole::Submit)
+        }) {
+            warn!("The relaychain sources with the Submit role are not used.");
+        }
+        if parachain_rpc_client_ids.is_empty() {
+            return Err(anyhow!("No parachain source with the Any or Read role"));
+        }
+        if parachain_submit_rpc_client_ids.is_empty() {
+            return Err(anyhow!("No parachain source with the Any or Submit role"));
+        }
 
         let is_relaychain_full = !relaychain_full_rpc_client_ids.is_empty();
         let is_parachain_full = !parachain_full_rpc_client_ids.is_empty();
@@ -394,6 +599,13 @@ impl DataSourceManager {
 
         let cache = cache.build();
 
+        let relaychain_profile = resolve_profile(config.relaychain.profile.as_deref(), "kusama")?;
+        let parachain_profile = resolve_profile(config.parachain.profile.as_deref(), "khala")?;
+        // For the addresses formatted without a client at hand.
+        if let Err(err) = phaxt::profile::install(parachain_profile.clone()) {
+            warn!("{err}");
+        }
+
         let dsm = Self {
             config: config.clone(),
             relaychain_rpc_client_ids,
@@ -403,12 +615,18 @@ impl DataSourceManager {
             relaychain_headers_cache_map,
             parachain_rpc_client_ids,
             parachain_full_rpc_client_ids,
+            parachain_submit_rpc_client_ids,
             parachain_rpc_client_map,
             parachain_headers_cache_ids,
             parachain_headers_cache_map,
             is_relaychain_full,
             is_parachain_full,
             cache,
+            shadow: config.shadow.clone().map(|c| Arc::new(Shadow::new(c))),
+            relaychain_profile,
+            parachain_profile,
+            tracer: RequestTracer::new(config.tracing.clone()),
+            submit_failures: Default::default(),
         };
         let dsm = Arc::new(dsm);
         let ret = dsm.clone();
@@ -422,7 +640,10 @@ impl DataSourceManager {
             invoke_ds_loops!(config, dsm, relaychain),
             invoke_ds_loops!(config, dsm, parachain),
         ];
-        let handles = handles.into_iter().flatten().collect::<Vec<_>>();
+        let mut handles = handles.into_iter().flatten().collect::<Vec<_>>();
+        if let Some(shadow) = ret.shadow.clone() {
+            handles.extend(shadow.spawn(dsm.clone()));
+        }
 
         Ok((ret, handles))
     }
@@ -430,13 +651,25 @@ impl DataSourceManager {
     ds_loop!(relaychain);
     ds_loop!(parachain);
 
-    async fn headers_cache_loop(
+    pub(crate) async fn headers_cache_loop(
         config: HeadersCacheHttpSource,
         map: WrappedHeadersCacheHttpSourceMap,
     ) {
         let uuid = Uuid::new_v5(&Uuid::NAMESPACE_URL, config.endpoint.as_bytes());
         let uuid_str = uuid.to_string();
-        let client = CacheClient::new(config.endpoint.as_str());
+        let http_client = match crate::proxy::client_builder(config.proxy.as_deref())
+            .and_then(|builder| Ok(builder.build()?))
+        {
+            Ok(http_client) => http_client,
+            Err(e) => {
+                error!(
+                    "Headers cache {}({}) failed: {}",
+                    &uuid_str, &config.endpoint, e
+                );
+                return;
+            }
+        };
+        let client = CacheClient::with_http_client(config.endpoint.as_str(), http_client);
         let instance = HeadersCacheHttpSourceInstance {
             uuid,
             uuid_str: uuid_str.clone(),
@@ -472,11 +705,15 @@ impl DataSourceManager {
         }
     }
 
-    async fn subxt_loop(config: SubstrateWebSocketSource, map: WrappedSubstrateWebSocketSourceMap) {
+    pub(crate) async fn subxt_loop(
+        config: SubstrateWebSocketSource,
+        profile: ChainProfile,
+        map: WrappedSubstrateWebSocketSourceMap,
+    ) {
         let uuid = Uuid::new_v5(&Uuid::NAMESPACE_URL, config.endpoint.as_bytes());
         loop {
             let uuid_str = uuid.to_string();
-            match Self::subxt_connect(&config, &uuid, map.clone()).await {
+            match Self::subxt_connect(&config, &profile, &uuid, map.clone()).await {
                 Ok(_) => {
                     error!(
                         "SubstrateWebSocketSource {}({}) disconnected!",
@@ -505,10 +742,59 @@ impl DataSourceManager {
 
     async fn subxt_connect(
         config: &SubstrateWebSocketSource,
+        profile: &ChainProfile,
         uuid: &Uuid,
         map: WrappedSubstrateWebSocketSourceMap,
     ) -> Result<()> {
         let uuid_str = uuid.to_string();
+
+        // The client reconnects by itself once connected, keep the source out of the map while
+        // the connection is being re-established.
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
+        let keep_alive = phaxt::KeepAlive {
+            on_event: Some(Arc::new(move |endpoint, event| {
+                warn!("SubstrateWebSocketSource {}: {}", endpoint, event);
+                let _ = event_tx.send(event.clone());
+            })),
+            ..Default::default()
+        };
+        let connector: phaxt::keep_alive::Connector = {
+            let config = config.clone();
+            Arc::new(move || {
+                let config = config.clone();
+                Box::pin(async move { Self::ws_client(&config).await })
+            })
+        };
+        let client =
+            phaxt::connect_with_connector(&config.endpoint, connector, keep_alive, profile.clone())
+                .await
+                .context("Failed to connect to substrate")?;
+
+        let instance = Arc::new(SubstrateWebSocketSourceInstance {
+            uuid: *uuid,
+            uuid_str: uuid_str.clone(),
+            client,
+            endpoint: config.endpoint.clone(),
+            pruned: config.pruned,
+        });
+
+        map.write().await.insert(uuid_str.clone(), instance.clone());
+
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                ConnectionEvent::Unhealthy { .. } => {
+                    map.write().await.remove(&uuid_str);
+                }
+                ConnectionEvent::Reconnected => {
+                    map.write().await.insert(uuid_str.clone(), instance.clone());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn ws_client(config: &SubstrateWebSocketSource) -> Result<WsClient> {
         let raw_uri: Uri = config.endpoint.parse().context("Invalid websocket url")?;
         let raw_port = raw_uri.port_u16();
         let scheme = raw_uri.scheme_str().unwrap();
@@ -531,43 +817,32 @@ impl DataSourceManager {
         debug!("raw: {}, parsed: {}", &config.endpoint, &uri);
         let uri: Uri = uri.parse().context("Invalid websocket url")?;
 
-        let (sender, receiver) = WsTransportClientBuilder::default()
-            .max_request_body_size(u32::MAX)
-            .build(uri)
-            .await?;
-        let ws_client = ClientBuilder::default()
+        let client_builder = ClientBuilder::default()
             .max_concurrent_requests(config.max_concurrent_requests)
-            .ping_interval(core::time::Duration::from_secs(3))
-            .build_with_tokio(sender, receiver);
-        let ws_client = Arc::new(ws_client);
-
-        let client = RpcClient::from_rpc_client(ws_client.clone())
-            .await
-            .context("Failed to connect to substrate")?;
-        let update_client = client.updater();
-        tokio::spawn(async move {
-            let result = update_client.perform_runtime_updates().await;
-            error!("Runtime update failed with result={result:?}");
-        });
-
-        let client = ChainApi(client);
-        let instance = SubstrateWebSocketSourceInstance {
-            uuid: *uuid,
-            uuid_str: uuid_str.clone(),
-            client,
-            endpoint: config.endpoint.clone(),
-            pruned: config.pruned,
+            .ping_interval(core::time::Duration::from_secs(3));
+        let ws_client = match crate::proxy::resolve(config.proxy.as_deref())? {
+            Route::Proxy(proxy) => {
+                let (sender, receiver) = crate::proxy::connect_ws(&uri, &proxy).await?;
+                client_builder.build_with_tokio(sender, receiver)
+            }
+            Route::Default | Route::Direct => {
+                let (sender, receiver) = WsTransportClientBuilder::default()
+                    .max_request_body_size(u32::MAX)
+                    .build(uri)
+                    .await?;
+                client_builder.build_with_tokio(sender, receiver)
+            }
         };
-
-        map.write().await.insert(uuid_str, Arc::new(instance));
-
-        ws_client.on_disconnect().await;
-        Ok(())
+        Ok(ws_client)
     }
 }
 
 pub type WrappedDataSourceManager = Arc<DataSourceManager>;
 
+fn resolve_profile(spec: Option<&str>, default: &str) -> Result<ChainProfile> {
+    ChainProfile::resolve(spec.unwrap_or(default))
+}
+
 pub async fn setup_data_source_manager(
     config_path: &str,
     cache_size: usize,
@@ -622,16 +897,41 @@ impl DataSourceManager {
         self: Arc<Self>,
         provider: Option<AttestationProvider>,
     ) -> Result<Arc<DataSourceCacheItem>> {
-        let relay_hc = use_relaychain_hc!(self);
+        let relay_hc = self.clone().current_relaychain_headers_cache().await;
 
-        let relay_api = use_relaychain_api!(self, false).ok_or(NoValidDataSource)?;
-        let para_api = use_parachain_api!(self, false).ok_or(NoValidDataSource)?;
-
-        let relaychain_start_block = para_api.clone().relay_parent_number().await? - 1;
+        let relay = self
+            .clone()
+            .current_relaychain_rpc_client(false)
+            .await
+            .ok_or(NoValidDataSource)?;
+        let para = self
+            .clone()
+            .current_parachain_rpc_client(false)
+            .await
+            .ok_or(NoValidDataSource)?;
+        let tracer = &self.tracer;
+
+        let relaychain_start_block = tracer
+            .trace(
+                "relay_parent_number",
+                "",
+                &para.endpoint,
+                para.client.clone().relay_parent_number(),
+            )
+            .await?
+            - 1;
         debug!("relaychain_start_block: {relaychain_start_block}");
 
         let mut genesis_info: Option<GenesisBlockInfo> = match relay_hc {
-            Some(relay_hc) => match relay_hc.get_genesis(relaychain_start_block).await {
+            Some(relay_hc) => match tracer
+                .trace(
+                    "get_genesis",
+                    relaychain_start_block,
+                    &relay_hc.endpoint,
+                    relay_hc.client.get_genesis(relaychain_start_block),
+                )
+                .await
+            {
                 Ok(genesis_info) => Some(genesis_info),
                 Err(e) => {
                     warn!("get_init_runtime_default_request: {}", e);
@@ -641,11 +941,24 @@ impl DataSourceManager {
             None => None,
         };
         if genesis_info.is_none() {
-            let genesis_block = get_block_at(&relay_api, Some(relaychain_start_block))
+            let genesis_block = tracer
+                .trace(
+                    "get_block_at",
+                    relaychain_start_block,
+                    &relay.endpoint,
+                    get_block_at(&relay.client, Some(relaychain_start_block)),
+                )
                 .await?
                 .0
                 .block;
-            let set_proof = get_authority_with_proof_at(&relay_api, &genesis_block.header).await?;
+            let set_proof = tracer
+                .trace(
+                    "get_authority_with_proof_at",
+                    relaychain_start_block,
+                    &relay.endpoint,
+                    get_authority_with_proof_at(&relay.client, &genesis_block.header),
+                )
+                .await?;
             genesis_info = Some(GenesisBlockInfo {
                 block_header: genesis_block.header.clone(),
                 authority_set: set_proof.authority_set,
@@ -653,7 +966,14 @@ impl DataSourceManager {
             });
         }
         let genesis_info = genesis_info.unwrap();
-        let genesis_state = chain_client::fetch_genesis_storage(&para_api).await?;
+        let genesis_state = tracer
+            .trace(
+                "fetch_genesis_storage",
+                "",
+                &para.endpoint,
+                chain_client::fetch_genesis_storage(&para.client),
+            )
+            .await?;
         let ret = InitRuntimeRequest::new(
             false,
             genesis_info.clone(),
@@ -692,22 +1012,32 @@ impl DataSourceManager {
         to: u32,
     ) -> Result<Arc<DataSourceCacheItem>> {
         let hc = self.clone().current_parachain_headers_cache().await;
-        let para_api = if hc.is_some() {
-            use_parachain_api!(self, false)
-        } else {
-            use_parachain_api!(self, true)
-        }
-        .ok_or(NoValidDataSource)?;
+        let para = self
+            .clone()
+            .current_parachain_rpc_client(hc.is_none())
+            .await
+            .ok_or(NoValidDataSource)?;
+        let params = format!("{from}..={to}");
         let ret = if let Some(hc) = hc {
-            pherry::fetch_storage_changes(&para_api, Some(&hc.client), from, to).await
+            let fut = pherry::fetch_storage_changes(&para.client, Some(&hc.client), from, to);
+            self.tracer
+                .trace("fetch_storage_changes", params, &hc.endpoint, fut)
+                .await
         } else {
-            pherry::fetch_storage_changes(&para_api, None, from, to).await
+            let fut = pherry::fetch_storage_changes(&para.client, None, from, to);
+            self.tracer
+                .trace("fetch_storage_changes", params, &para.endpoint, fut)
+                .await
         }?;
 
         let ret = ret.into_iter().map(Arc::new).collect::<Vec<_>>();
         Ok(Arc::new(DataSourceCacheItem::StorageChanges(ret)))
     }
-    pub async fn fetch_storage_changes(self: Arc<Self>, from: u32, to: u32) -> Result<Vec<Arc<phactory_api::blocks::BlockHeaderWithChanges>>> {
+    pub async fn fetch_storage_changes(
+        self: Arc<Self>,
+        from: u32,
+        to: u32,
+    ) -> Result<Vec<Arc<phactory_api::blocks::BlockHeaderWithChanges>>> {
         let key = format!("sc:{from}:{to}");
         let cache = self.cache.clone();
         match cache
@@ -726,22 +1056,46 @@ impl DataSourceManager {
         self: Arc<Self>,
         height: u32,
     ) -> Result<Arc<DataSourceCacheItem>> {
-        let hc = use_relaychain_hc!(self);
+        let hc = self.clone().current_relaychain_headers_cache().await;
         if let Some(hc) = hc {
-            if let Ok(block_info) = hc.get_header(height).await {
+            let fut = hc.client.get_header(height);
+            if let Ok(block_info) = self
+                .tracer
+                .trace("get_header", height, &hc.endpoint, fut)
+                .await
+            {
                 if let Some(para_header) = block_info.para_header {
                     return Ok(Arc::new(DataSourceCacheItem::ParaHeaderByRelayHeight(
-                        Some((para_header.fin_header_num, para_header.proof))
+                        Some((para_header.fin_header_num, para_header.proof)),
                     )));
                 }
             }
         }
 
-        let relay_api = use_relaychain_api!(self, false).ok_or(NoValidDataSource)?;
+        let relay = self
+            .clone()
+            .current_relaychain_rpc_client(false)
+            .await
+            .ok_or(NoValidDataSource)?;
         let para_api = use_parachain_api!(self, true).ok_or(NoValidDataSource)?;
 
-        let last_header_hash = get_header_hash(&relay_api, Some(height)).await?;
-        let header = get_finalized_header(&relay_api, &para_api, last_header_hash)
+        let last_header_hash = self
+            .tracer
+            .trace(
+                "get_header_hash",
+                height,
+                &relay.endpoint,
+                get_header_hash(&relay.client, Some(height)),
+            )
+            .await?;
+        let header = self
+            .tracer
+            .trace(
+                "get_finalized_header",
+                height,
+                &relay.endpoint,
+                get_finalized_header(&relay.client, &para_api, last_header_hash),
+            )
             .await?
             .map(|(h, proof)| (h.number, proof));
         Ok(Arc::new(DataSourceCacheItem::ParaHeaderByRelayHeight(
@@ -769,25 +1123,36 @@ impl DataSourceManager {
         }
     }
 
-    pub async fn do_get_para_header(
-        self: Arc<Self>,
-        num: u32,
-    ) -> Result<Arc<DataSourceCacheItem>> {
-        let para_api = use_parachain_api!(self, true).ok_or(NoValidDataSource)?;
+    pub async fn do_get_para_header(self: Arc<Self>, num: u32) -> Result<Arc<DataSourceCacheItem>> {
+        let para = self
+            .clone()
+            .current_parachain_rpc_client(true)
+            .await
+            .ok_or(NoValidDataSource)?;
         let block_num = subxt_types::BlockNumber::from(subxt_types::NumberOrHex::Number(num as _));
-        let hash = para_api
-            .rpc()
-            .block_hash(Some(block_num))
+        let hash = self
+            .tracer
+            .trace(
+                "chain_getBlockHash",
+                num,
+                &para.endpoint,
+                para.client.rpc().block_hash(Some(block_num)),
+            )
             .await?
             .ok_or(BlockHashNotFound(num))?;
-        let header = para_api
-            .rpc()
-            .header(Some(hash))
+        let header = self
+            .tracer
+            .trace(
+                "chain_getHeader",
+                num,
+                &para.endpoint,
+                para.client.rpc().header(Some(hash)),
+            )
             .await?
             .ok_or(BlockNotFound(num))?;
-        Ok(Arc::new(
-            DataSourceCacheItem::ParaHeader(header.convert_to()),
-        ))
+        Ok(Arc::new(DataSourceCacheItem::ParaHeader(
+            header.convert_to(),
+        )))
     }
 
     pub async fn get_para_headers(
@@ -814,12 +1179,20 @@ impl DataSourceManager {
             return Ok(headers);
         }
 
-        if let Some(hc) = use_parachain_hc!(self) {
+        if let Some(hc) = self.clone().current_parachain_headers_cache().await {
             let count = to - from + 1;
-            if let Ok(remain_headers) = hc.get_parachain_headers(from, count).await {
+            let fut = hc.client.get_parachain_headers(from, count);
+            let params = format!("{from}..={to}");
+            if let Ok(remain_headers) = self
+                .tracer
+                .trace("get_parachain_headers", params, &hc.endpoint, fut)
+                .await
+            {
                 for header in &remain_headers {
                     let key = format!("ph:{}", header.number);
-                    cache.insert(key, DataSourceCacheItem::ParaHeader(header.clone()).into()).await;
+                    cache
+                        .insert(key, DataSourceCacheItem::ParaHeader(header.clone()).into())
+                        .await;
                     headers.push(header.clone());
                 }
                 return Ok(headers);
@@ -0,0 +1,162 @@
+//! Dead-letter queue of the offchain messages.
+//!
+//! A message is parked as unrecoverable when the chain rejects it for a reason no retry can fix,
+//! or when it used up the retry budget of `--message-max-attempts`. It then holds back the
+//! following messages of its sender, since the sequence cannot be skipped.
+//!
+//! The parked messages are persisted in the pool operator db, so that they survive restarts: they
+//! are restored into the message loop at startup, and dropped once the on-chain sequence of their
+//! sender moves past them. They are listed by `GET /messages/dead_letters`, and can be released by
+//! `POST /messages/dead_letters/retry`, which submits the message again with a fresh retry budget,
+//! or `POST /messages/dead_letters/discard`, which forgets it until its worker offers it again.
+
+use crate::messages::{fetch_chain_next_sequences, import_senders, ImportedSender};
+use crate::migration::DeadLetter;
+use crate::pool_operator::DB;
+use crate::wm::WrappedWorkerManagerContext;
+use anyhow::{Context, Result};
+use log::{error, info};
+use parity_scale_codec::Encode;
+use phala_types::messaging::MessageOrigin;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+static DEAD_LETTER_KEY_PREFIX: &str = "dead_letter:";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterAction {
+    /// Submits the message again, with a fresh retry budget.
+    Retry,
+    /// Forgets the message, it is submitted again only if its worker offers it again.
+    Discard,
+}
+
+pub struct DeadLetters {
+    db: Arc<DB>,
+    letters: Mutex<BTreeMap<(MessageOrigin, u64), DeadLetter>>,
+}
+
+impl DeadLetters {
+    pub fn load(db: Arc<DB>) -> Result<Self> {
+        let mut letters = BTreeMap::new();
+        for item in db.prefix_iterator(DEAD_LETTER_KEY_PREFIX) {
+            let (key, value) = item?;
+            if !key.starts_with(DEAD_LETTER_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            let letter: DeadLetter = serde_json::from_slice(&value)?;
+            letters.insert((letter.origin.clone(), letter.sequence), letter);
+        }
+        info!("Loaded {} dead letters.", letters.len());
+        Ok(Self {
+            db,
+            letters: Mutex::new(letters),
+        })
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Finds the parked message by its sender as displayed, e.g. `Worker(<hex pubkey>)`.
+    pub fn find(&self, sender: &str, sequence: u64) -> Option<DeadLetter> {
+        self.letters
+            .lock()
+            .unwrap()
+            .values()
+            .find(|letter| letter.sequence == sequence && letter.origin.to_string() == sender)
+            .cloned()
+    }
+
+    pub fn park(&self, letter: DeadLetter) {
+        if let Err(err) = self.persist(&letter) {
+            error!(
+                "[{}] Failed to persist the dead letter #{}: {err:?}",
+                letter.origin, letter.sequence
+            );
+        }
+        self.letters
+            .lock()
+            .unwrap()
+            .insert((letter.origin.clone(), letter.sequence), letter);
+    }
+
+    fn persist(&self, letter: &DeadLetter) -> Result<()> {
+        let key = dead_letter_db_key(&letter.origin, letter.sequence);
+        self.db.put(key, serde_json::to_vec(letter)?)?;
+        Ok(())
+    }
+
+    /// Removes the parked message, returns false if it was not parked.
+    pub fn remove(&self, origin: &MessageOrigin, sequence: u64) -> bool {
+        let removed = self
+            .letters
+            .lock()
+            .unwrap()
+            .remove(&(origin.clone(), sequence))
+            .is_some();
+        if removed {
+            if let Err(err) = self.db.delete(dead_letter_db_key(origin, sequence)) {
+                error!("[{origin}] Failed to delete the dead letter #{sequence}: {err:?}");
+            }
+        }
+        removed
+    }
+
+    /// Removes the parked messages of the sender below its on-chain next sequence, which took
+    /// effect some other way.
+    pub fn resolve(&self, origin: &MessageOrigin, next_sequence: u64) {
+        let resolved = self
+            .letters
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(o, sequence)| o == origin && *sequence < next_sequence)
+            .map(|(_, sequence)| *sequence)
+            .collect::<Vec<_>>();
+        for sequence in resolved {
+            info!("[{origin}] Dead letter #{sequence} resolved on chain");
+            self.remove(origin, sequence);
+        }
+    }
+}
+
+fn dead_letter_db_key(origin: &MessageOrigin, sequence: u64) -> String {
+    format!(
+        "{DEAD_LETTER_KEY_PREFIX}{}:{sequence:020}",
+        hex::encode(origin.encode())
+    )
+}
+
+/// Parks the persisted dead letters in the message loop again after a restart.
+pub async fn restore_dead_letters(ctx: WrappedWorkerManagerContext) -> Result<()> {
+    let mut senders = BTreeMap::<MessageOrigin, ImportedSender>::new();
+    for letter in ctx.dead_letters.list() {
+        senders
+            .entry(letter.origin.clone())
+            .or_insert_with(|| ImportedSender {
+                worker_id: letter.worker_id.clone(),
+                chain_next_sequence: 0,
+                dead_letters: vec![],
+            })
+            .dead_letters
+            .push(letter);
+    }
+    if senders.is_empty() {
+        return Ok(());
+    }
+    let origins: Vec<_> = senders.keys().cloned().collect();
+    let chain_next_sequences = fetch_chain_next_sequences(&ctx.dsm, &origins)
+        .await
+        .context("Failed to fetch the on-chain sequences of the senders")?;
+    for (sender, next_sequence) in senders.values_mut().zip(chain_next_sequences) {
+        sender.chain_next_sequence = next_sequence;
+    }
+    let outcomes = import_senders(&ctx.bus, senders).await?;
+    let restored: usize = outcomes.iter().map(|o| o.restored).sum();
+    let resolved: usize = outcomes.iter().map(|o| o.resolved).sum();
+    info!("Restored {restored} dead letters, {resolved} were resolved on chain meanwhile.");
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Pulling of the egress messages of the workers driven by the depth of their queues.
+//!
+//! Instead of pulling all the egress messages of a worker after every dispatched block, prb probes
+//! the depth of the queue of each sender and pulls the messages only when enough new messages
+//! have been queued since the last pull, or when messages have been outstanding for too many
+//! blocks. The latter makes sure that the messages of quiet senders are pulled again if the
+//! previous pull was lost, e.g. in a hiccup of the bus.
+
+use phala_types::messaging::{MessageOrigin, QueueDepth};
+use std::collections::HashMap;
+
+struct SenderPullState {
+    /// The next sequence of the sender at the last pull.
+    next_sequence: u64,
+    /// The block number of the worker at the last pull.
+    block: u32,
+}
+
+/// The pull states of the senders of a worker.
+#[derive(Default)]
+pub struct EgressQueueState {
+    senders: HashMap<MessageOrigin, SenderPullState>,
+}
+
+pub struct EgressPoller {
+    pull_threshold: u64,
+    max_age_blocks: u32,
+}
+
+impl EgressPoller {
+    pub fn new(pull_threshold: u64, max_age_blocks: u32) -> Self {
+        Self {
+            pull_threshold: pull_threshold.max(1),
+            max_age_blocks,
+        }
+    }
+
+    /// Whether the egress messages of a worker at `block` should be pulled, given the depths of
+    /// its queues and `onchain_next`, the next sequence of a sender accepted on chain.
+    ///
+    /// When it returns true, the messages are considered pulled at `block`.
+    pub fn should_pull(
+        &self,
+        state: &mut EgressQueueState,
+        depths: &[(MessageOrigin, QueueDepth)],
+        block: u32,
+        onchain_next: impl Fn(&MessageOrigin) -> u64,
+    ) -> bool {
+        let mut pull = false;
+        let mut outstanding_senders = vec![];
+        for (sender, depth) in depths {
+            let first_pending = depth.next_sequence.saturating_sub(depth.pending as u64);
+            let first_outstanding = onchain_next(sender).max(first_pending);
+            if first_outstanding >= depth.next_sequence {
+                state.senders.remove(sender);
+                continue;
+            }
+            // Outstanding messages seen for the first time count from this block.
+            let last_pull = state
+                .senders
+                .entry(sender.clone())
+                .or_insert(SenderPullState {
+                    next_sequence: first_outstanding,
+                    block,
+                });
+            let fresh = depth.next_sequence.saturating_sub(last_pull.next_sequence);
+            let age = block.saturating_sub(last_pull.block);
+            if fresh >= self.pull_threshold || age >= self.max_age_blocks {
+                pull = true;
+            }
+            outstanding_senders.push((sender, depth.next_sequence));
+        }
+        if pull {
+            for (sender, next_sequence) in outstanding_senders {
+                state.senders.insert(
+                    sender.clone(),
+                    SenderPullState {
+                        next_sequence,
+                        block,
+                    },
+                );
+            }
+        }
+        pull
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth(pending: u32, next_sequence: u64) -> QueueDepth {
+        QueueDepth {
+            pending,
+            next_sequence,
+        }
+    }
+
+    #[test]
+    fn pulls_when_enough_messages_are_queued() {
+        let poller = EgressPoller::new(3, 100);
+        let mut state = EgressQueueState::default();
+        let sender = MessageOrigin::Gatekeeper;
+        let onchain = |_: &MessageOrigin| 10;
+
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(2, 12))], 1, onchain));
+        assert!(poller.should_pull(&mut state, &[(sender.clone(), depth(3, 13))], 2, onchain));
+        // Counted from the last pull.
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(4, 14))], 3, onchain));
+        assert!(poller.should_pull(&mut state, &[(sender, depth(6, 16))], 4, onchain));
+    }
+
+    #[test]
+    fn pulls_outstanding_messages_when_too_old() {
+        let poller = EgressPoller::new(10, 5);
+        let mut state = EgressQueueState::default();
+        let sender = MessageOrigin::Gatekeeper;
+        let onchain = |_: &MessageOrigin| 0;
+
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(1, 1))], 10, onchain));
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(1, 1))], 14, onchain));
+        assert!(poller.should_pull(&mut state, &[(sender.clone(), depth(1, 1))], 15, onchain));
+        assert!(!poller.should_pull(&mut state, &[(sender, depth(1, 1))], 16, onchain));
+    }
+
+    #[test]
+    fn forgets_senders_without_outstanding_messages() {
+        let poller = EgressPoller::new(2, 5);
+        let mut state = EgressQueueState::default();
+        let sender = MessageOrigin::Gatekeeper;
+
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(1, 5))], 1, |_| 4));
+        // Accepted on chain meanwhile.
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(1, 5))], 3, |_| 5));
+        assert!(state.senders.is_empty());
+        // The age restarts from the block the messages are seen outstanding again.
+        assert!(!poller.should_pull(&mut state, &[(sender.clone(), depth(1, 6))], 4, |_| 5));
+        assert!(!poller.should_pull(&mut state, &[(sender, depth(1, 6))], 8, |_| 5));
+    }
+
+    #[test]
+    fn pulls_all_outstanding_senders_at_once() {
+        let poller = EgressPoller::new(2, 100);
+        let mut state = EgressQueueState::default();
+        let busy = MessageOrigin::Gatekeeper;
+        let quiet = MessageOrigin::Reserved;
+        let onchain = |_: &MessageOrigin| 0;
+
+        let depths = [(busy.clone(), depth(2, 2)), (quiet.clone(), depth(1, 1))];
+        assert!(poller.should_pull(&mut state, &depths, 1, onchain));
+        assert_eq!(state.senders[&busy].next_sequence, 2);
+        assert_eq!(state.senders[&quiet].next_sequence, 1);
+        assert_eq!(state.senders[&quiet].block, 1);
+    }
+}
@@ -0,0 +1,267 @@
+//! Selection of the worker whose egress of a sender is forwarded to the chain.
+//!
+//! Several managed workers may emit the egress of the same sender, e.g. the GK workers, or a
+//! worker being migrated to another machine. Forwarding the first batch to come from any of them
+//! interleaves the stale queue of a lagging worker with the fresh queue of another, so for each
+//! sender one source is selected and the batches of the others are dropped.
+//!
+//! The source is the worker with the freshest queue, i.e. the highest sequence, then the highest
+//! health score, then the lowest worker id, so that the choice is deterministic. The current
+//! source is kept as long as no other candidate ranks above it, and a candidate which has not
+//! offered messages for a while is forgotten. Each change of source is recorded with the reason.
+
+use chrono::{DateTime, Utc};
+use log::info;
+use phala_types::messaging::MessageOrigin;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+
+/// Blocks after which a candidate which offered no messages is forgotten.
+const CANDIDATE_EXPIRY_BLOCKS: u32 = 10;
+/// Number of recent source changes kept per sender.
+const MAX_DECISIONS: usize = 10;
+
+/// How fit a worker is to be the source of an egress.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SourceHealth {
+    /// The number of blocks the worker is behind the parachain tip.
+    pub lag_blocks: u32,
+    /// The number of recent failed pRuntime requests.
+    pub recent_errors: usize,
+}
+
+impl SourceHealth {
+    /// From 0 to 100, higher is healthier.
+    pub fn score(&self) -> u32 {
+        let errors = self.recent_errors.min(5) as u32;
+        100u32
+            .saturating_sub(self.lag_blocks.min(50))
+            .saturating_sub(errors * 10)
+    }
+}
+
+struct Candidate {
+    health: SourceHealth,
+    latest_sequence: u64,
+    offered_at: u32,
+}
+
+impl Candidate {
+    fn rank<'a>(&self, worker_id: &'a str) -> (u64, u32, Reverse<&'a str>) {
+        (
+            self.latest_sequence,
+            self.health.score(),
+            Reverse(worker_id),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceDecision {
+    pub worker_id: String,
+    pub previous: Option<String>,
+    pub height: u32,
+    pub at: DateTime<Utc>,
+    pub latest_sequence: u64,
+    pub score: u32,
+    pub reason: String,
+}
+
+/// The candidate sources of the egress of a sender.
+#[derive(Default)]
+pub struct EgressSources {
+    candidates: HashMap<String, Candidate>,
+    selected: Option<String>,
+    decisions: VecDeque<SourceDecision>,
+}
+
+impl EgressSources {
+    /// Records a batch of messages up to `latest_sequence` offered by the worker at `height`,
+    /// returns whether the worker is the selected source of the sender.
+    pub fn offer(
+        &mut self,
+        sender: &MessageOrigin,
+        worker_id: &str,
+        health: SourceHealth,
+        latest_sequence: u64,
+        height: u32,
+    ) -> bool {
+        self.candidates.insert(
+            worker_id.to_string(),
+            Candidate {
+                health,
+                latest_sequence,
+                offered_at: height,
+            },
+        );
+        self.candidates
+            .retain(|_, c| height.saturating_sub(c.offered_at) <= CANDIDATE_EXPIRY_BLOCKS);
+
+        let best = self
+            .candidates
+            .iter()
+            .max_by_key(|&(id, c)| c.rank(id))
+            .map(|(id, _)| id.clone())
+            .expect("The worker was just inserted");
+        let current = self
+            .selected
+            .as_ref()
+            .and_then(|id| self.candidates.get(id).map(|c| (id, c)));
+        let reason = match current {
+            Some((id, _)) if *id == best => None,
+            Some((id, c)) => {
+                let best_candidate = &self.candidates[&best];
+                if best_candidate.latest_sequence > c.latest_sequence {
+                    Some(format!(
+                        "{id} is stale at #{}, {best} is at #{}",
+                        c.latest_sequence, best_candidate.latest_sequence
+                    ))
+                } else if best_candidate.health.score() > c.health.score() {
+                    Some(format!(
+                        "{id} has health score {}, {best} has {}",
+                        c.health.score(),
+                        best_candidate.health.score()
+                    ))
+                } else {
+                    // Tied, keep the current source.
+                    None
+                }
+            }
+            None => Some(match &self.selected {
+                Some(id) => format!("{id} stopped offering messages"),
+                None => "first source".to_string(),
+            }),
+        };
+        if let Some(reason) = reason {
+            let candidate = &self.candidates[&best];
+            info!("[{sender}] forwarding the egress of {best}: {reason}");
+            if self.decisions.len() >= MAX_DECISIONS {
+                self.decisions.pop_front();
+            }
+            self.decisions.push_back(SourceDecision {
+                worker_id: best.clone(),
+                previous: self.selected.take(),
+                height,
+                at: Utc::now(),
+                latest_sequence: candidate.latest_sequence,
+                score: candidate.health.score(),
+                reason,
+            });
+            self.selected = Some(best);
+        }
+        self.is_selected(worker_id)
+    }
+
+    pub fn is_selected(&self, worker_id: &str) -> bool {
+        self.selected.as_deref() == Some(worker_id)
+    }
+
+    pub fn selected(&self) -> Option<&str> {
+        self.selected.as_deref()
+    }
+
+    pub fn decisions(&self) -> Vec<SourceDecision> {
+        self.decisions.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: MessageOrigin = MessageOrigin::Gatekeeper;
+
+    fn healthy() -> SourceHealth {
+        SourceHealth::default()
+    }
+
+    fn lagging(lag_blocks: u32) -> SourceHealth {
+        SourceHealth {
+            lag_blocks,
+            recent_errors: 0,
+        }
+    }
+
+    #[test]
+    fn health_score() {
+        assert_eq!(healthy().score(), 100);
+        assert_eq!(lagging(20).score(), 80);
+        let unhealthy = SourceHealth {
+            lag_blocks: 1000,
+            recent_errors: 100,
+        };
+        assert_eq!(unhealthy.score(), 0);
+    }
+
+    #[test]
+    fn freshest_queue_is_selected() {
+        let mut sources = EgressSources::default();
+        assert!(sources.offer(&SENDER, "a", healthy(), 5, 0));
+        // A fresher queue wins over a healthier one.
+        assert!(sources.offer(&SENDER, "b", lagging(20), 7, 1));
+        assert!(!sources.offer(&SENDER, "a", healthy(), 6, 2));
+        assert_eq!(sources.selected(), Some("b"));
+
+        let decisions = sources.decisions();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].reason, "first source");
+        assert_eq!(decisions[1].previous.as_deref(), Some("a"));
+        assert!(decisions[1].reason.contains("stale"));
+    }
+
+    #[test]
+    fn healthiest_source_is_selected_on_the_same_sequence() {
+        let mut sources = EgressSources::default();
+        assert!(sources.offer(&SENDER, "a", healthy(), 5, 0));
+        assert!(!sources.offer(&SENDER, "b", healthy(), 5, 0));
+        assert!(!sources.offer(&SENDER, "a", lagging(20), 5, 1));
+        assert_eq!(sources.selected(), Some("b"));
+        assert!(sources.decisions()[1].reason.contains("health score"));
+    }
+
+    #[test]
+    fn current_source_is_kept_on_ties() {
+        let mut sources = EgressSources::default();
+        assert!(sources.offer(&SENDER, "b", healthy(), 5, 0));
+        // Ranks above b by its id, but ties with it otherwise.
+        assert!(!sources.offer(&SENDER, "a", healthy(), 5, 0));
+        assert!(sources.offer(&SENDER, "b", healthy(), 5, 1));
+        assert_eq!(sources.decisions().len(), 1);
+    }
+
+    #[test]
+    fn lowest_worker_id_breaks_ties_without_a_current_source() {
+        let mut sources = EgressSources::default();
+        assert!(sources.offer(&SENDER, "c", healthy(), 5, 0));
+        assert!(!sources.offer(&SENDER, "a", healthy(), 5, 5));
+        assert!(!sources.offer(&SENDER, "b", healthy(), 5, 5));
+        // c expires, a and b tie.
+        assert!(!sources.offer(&SENDER, "b", healthy(), 5, 11));
+        assert_eq!(sources.selected(), Some("a"));
+        assert_eq!(sources.decisions()[1].reason, "c stopped offering messages");
+    }
+
+    #[test]
+    fn silent_candidates_expire() {
+        let mut sources = EgressSources::default();
+        assert!(sources.offer(&SENDER, "a", healthy(), 5, 0));
+        assert!(!sources.offer(&SENDER, "b", healthy(), 4, 5));
+        assert!(!sources.offer(&SENDER, "b", healthy(), 4, CANDIDATE_EXPIRY_BLOCKS));
+        // a offered nothing for more than CANDIDATE_EXPIRY_BLOCKS.
+        assert!(sources.offer(&SENDER, "b", healthy(), 4, CANDIDATE_EXPIRY_BLOCKS + 1));
+        assert_eq!(sources.decisions()[1].reason, "a stopped offering messages");
+        // a is a candidate again once it offers.
+        assert!(sources.offer(&SENDER, "a", healthy(), 6, CANDIDATE_EXPIRY_BLOCKS + 2));
+    }
+
+    #[test]
+    fn decisions_are_bounded() {
+        let mut sources = EgressSources::default();
+        for i in 0..(MAX_DECISIONS as u64 * 2) {
+            let worker = if i % 2 == 0 { "a" } else { "b" };
+            assert!(sources.offer(&SENDER, worker, healthy(), i, 0));
+        }
+        assert_eq!(sources.decisions().len(), MAX_DECISIONS);
+    }
+}
@@ -0,0 +1,87 @@
+//! Verified identity of the enclaves of the workers.
+//!
+//! The identity bundle returned by pRuntime is signed by the worker identity key. It is only
+//! recorded in the worker status after checking the signature and that the key is the one the
+//! worker reported, so that the recorded measurements can be used for fleet audits.
+
+use anyhow::{bail, Result};
+use parity_scale_codec::Decode;
+use phactory_api::prpc::EnclaveIdentityResponse;
+use phala_types::{wrap_content_to_sign, AttestationProvider, SignedContentType};
+use serde::{Deserialize, Serialize};
+use sp_core::sr25519::{Pair as Sr25519Pair, Signature};
+use sp_core::Pair;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SgxMeasurement {
+    pub mr_enclave: String,
+    pub mr_signer: String,
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnclaveIdentity {
+    pub public_key: String,
+    pub sgx_measurement: Option<SgxMeasurement>,
+    pub attestation_provider: Option<String>,
+    pub supported_attestation_methods: Vec<String>,
+    pub version: String,
+    pub git_revision: String,
+    pub rpc_features: u64,
+    pub max_supported_pink_runtime_version: String,
+    pub genesis_block_hash: String,
+    pub para_id: u32,
+    pub dev_mode: bool,
+    pub signing_time: u64,
+    /// The SCALE encoded payload and its signature in hex, to be verified again by third parties.
+    pub encoded_payload: String,
+    pub signature: String,
+}
+
+/// Verifies the identity returned by pRuntime, `public_key` is the hex worker public key
+/// reported in `PhactoryInfo`.
+pub fn verify(response: &EnclaveIdentityResponse, public_key: &str) -> Result<EnclaveIdentity> {
+    let payload = response.decode_identity_payload()?;
+    let signature = Signature::decode(&mut &response.signature[..])?;
+    let wrapped_data = wrap_content_to_sign(
+        &response.encoded_identity_payload,
+        SignedContentType::EnclaveIdentity,
+    );
+    if !Sr25519Pair::verify(&signature, &wrapped_data, &payload.pubkey) {
+        bail!("Bad signature of the enclave identity");
+    }
+    let signer = hex::encode(payload.pubkey);
+    if signer != public_key.trim_start_matches("0x") {
+        bail!("Enclave identity signed by {signer}, expected {public_key}");
+    }
+    let (major, minor) = payload.max_supported_pink_runtime_version;
+    Ok(EnclaveIdentity {
+        public_key: signer,
+        sgx_measurement: payload.sgx_measurement.map(|m| SgxMeasurement {
+            mr_enclave: hex::encode(m.mr_enclave),
+            mr_signer: hex::encode(m.mr_signer),
+            isv_prod_id: m.isv_prod_id,
+            isv_svn: m.isv_svn,
+        }),
+        attestation_provider: payload.attestation_provider.map(|p| {
+            match p {
+                AttestationProvider::Root => "root",
+                AttestationProvider::Ias => "ias",
+                AttestationProvider::Dcap => "dcap",
+            }
+            .to_string()
+        }),
+        supported_attestation_methods: payload.supported_attestation_methods,
+        version: payload.version,
+        git_revision: payload.git_revision,
+        rpc_features: payload.rpc_features,
+        max_supported_pink_runtime_version: format!("{major}.{minor}"),
+        genesis_block_hash: hex::encode(payload.genesis_block_hash),
+        para_id: payload.para_id,
+        dev_mode: payload.dev_mode,
+        signing_time: payload.signing_time,
+        encoded_payload: hex::encode(&response.encoded_identity_payload),
+        signature: hex::encode(&response.signature),
+    })
+}
@@ -1,4 +1,5 @@
 use crate::cli::ConfigCommands;
+use crate::proxy::validate_proxy;
 use anyhow::{anyhow, Context, Result};
 use indradb::{
     Datastore, EdgeDirection, EdgeKey, Identifier, MemoryDatastore, PipeEdgeQuery,
@@ -25,6 +26,7 @@ pub const ID_PROP_WORKER_STAKE: &str = "stake";
 pub const ID_PROP_WORKER_ENABLED: &str = "enabled";
 pub const ID_PROP_WORKER_SYNC_ONLY: &str = "sync_only";
 pub const ID_PROP_WORKER_GATEKEEPER: &str = "gatekeeper";
+pub const ID_PROP_WORKER_PROXY: &str = "proxy";
 
 // Account-related settings moved to trade service
 pub const ID_PROP_POOL_NAME: &str = "name";
@@ -57,6 +59,8 @@ pub struct Worker {
     pub enabled: bool,
     pub sync_only: bool,
     pub gatekeeper: bool,
+    #[serde(default)]
+    pub proxy: Option<String>,
 }
 
 impl From<VertexProperties> for Pool {
@@ -99,6 +103,7 @@ impl From<VertexProperties> for Worker {
             enabled: true,
             sync_only: false,
             gatekeeper: false,
+            proxy: None,
         };
         value.props.iter().for_each(|p| match p.name.as_str() {
             ID_PROP_WORKER_NAME => {
@@ -119,6 +124,9 @@ impl From<VertexProperties> for Worker {
             ID_PROP_WORKER_GATEKEEPER => {
                 ret.gatekeeper = p.value.as_bool().unwrap();
             }
+            ID_PROP_WORKER_PROXY => {
+                ret.proxy = p.value.as_str().map(|s| s.to_string());
+            }
             &_ => {}
         });
         ret
@@ -440,10 +448,12 @@ pub fn add_worker(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
             disabled,
             sync_only,
             gatekeeper,
+            proxy,
         } => {
             let stake = validate_bn_string(stake)?;
             let name = validate_worker_name_existence(db.clone(), name)?;
             let endpoint = validate_endpoint(endpoint)?;
+            let proxy = proxy.map(validate_proxy).transpose()?;
 
             let p = get_raw_pool_by_pid(db.clone(), pid)?.context("Pool not found!")?;
             let p = p.vertex.id;
@@ -494,6 +504,13 @@ pub fn add_worker(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
                     },
                     serde_json::Value::Bool(gatekeeper),
                 )?;
+                db.set_vertex_properties(
+                    VertexPropertyQuery {
+                        inner: uq.clone(),
+                        name: Identifier::new(ID_PROP_WORKER_PROXY).unwrap(),
+                    },
+                    proxy.map_or(serde_json::Value::Null, serde_json::Value::String),
+                )?;
                 let e = EdgeKey {
                     outbound_id: id,
                     t: Identifier::new(ID_EDGE_BELONG_TO)?,
@@ -525,10 +542,12 @@ pub fn update_worker(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
             disabled,
             sync_only,
             gatekeeper,
+            proxy,
         } => {
             let worker =
                 get_raw_worker_by_name(db.clone(), name.clone())?.context("Worker not found!")?;
             let id = worker.vertex.id;
+            let proxy = proxy.map(validate_proxy).transpose()?;
 
             let name = match new_name {
                 None => name,
@@ -608,11 +627,18 @@ pub fn update_worker(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
             )?;
             db.set_vertex_properties(
                 VertexPropertyQuery {
-                    inner: uq,
+                    inner: uq.clone(),
                     name: Identifier::new(ID_PROP_WORKER_GATEKEEPER).unwrap(),
                 },
                 serde_json::Value::Bool(gatekeeper),
             )?;
+            db.set_vertex_properties(
+                VertexPropertyQuery {
+                    inner: uq,
+                    name: Identifier::new(ID_PROP_WORKER_PROXY).unwrap(),
+                },
+                proxy.map_or(serde_json::Value::Null, serde_json::Value::String),
+            )?;
 
             Ok(id)
         }
@@ -0,0 +1,576 @@
+//! A durable queue of the lifecycle extrinsics, i.e. everything but the mq messages.
+//!
+//! Jobs are persisted in the pool operator db so that they survive restarts. A job can have:
+//!
+//! - an idempotency key: while a job with the same key is unfinished, enqueueing it again returns
+//!   the existing job instead of adding a new one;
+//! - dependencies, the keys of the jobs which have to succeed before it runs. A job fails if one
+//!   of its dependencies fails;
+//! - a number of attempts, retried with an exponential backoff. Only the jobs which are safe to
+//!   submit again are retried, see [`JobKind::max_attempts`].
+//!
+//! Jobs which were running when prb stopped are retried on start if they have attempts left,
+//! failed otherwise.
+
+use crate::pool_operator::DB;
+use crate::tx::TxManager;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use phaxt::ChainError;
+use serde::{Deserialize, Serialize};
+use sp_core::sr25519::Public as Sr25519Public;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+static JOB_KEY_PREFIX: &str = "job:";
+static JOB_NEXT_ID: &str = "job_next_id";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF_BASE_SECS: i64 = 6;
+const RETRY_BACKOFF_MAX_SECS: i64 = 300;
+const MAX_FINISHED_JOBS: usize = 1000;
+const JOB_LOOP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum JobKind {
+    RegisterWorker {
+        pid: u64,
+        pruntime_info: Vec<u8>,
+        attestation: Vec<u8>,
+        v2: bool,
+    },
+    AddWorker {
+        pid: u64,
+        /// Hex encoded public key of the worker.
+        worker: String,
+    },
+    StartComputing {
+        pid: u64,
+        worker: String,
+        stake: String,
+    },
+    StopComputing {
+        pid: u64,
+        worker: String,
+    },
+}
+
+impl JobKind {
+    fn pid(&self) -> u64 {
+        match self {
+            Self::RegisterWorker { pid, .. }
+            | Self::AddWorker { pid, .. }
+            | Self::StartComputing { pid, .. }
+            | Self::StopComputing { pid, .. } => *pid,
+        }
+    }
+
+    /// The most attempts of a job of this kind. Adding a worker and starting computing are not
+    /// retried: an attempt which timed out may still be included, and submitting it again would
+    /// fail or act twice. Registrations are retried by the caller with a fresh attestation.
+    fn max_attempts(&self) -> u32 {
+        match self {
+            Self::StopComputing { .. } => DEFAULT_MAX_ATTEMPTS,
+            Self::RegisterWorker { .. } | Self::AddWorker { .. } | Self::StartComputing { .. } => 1,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Self::RegisterWorker { .. } => {}
+            Self::AddWorker { worker, .. } | Self::StopComputing { worker, .. } => {
+                parse_public_key(worker)?;
+            }
+            Self::StartComputing { worker, stake, .. } => {
+                parse_public_key(worker)?;
+                stake.parse::<u128>().context("Invalid stake")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JobState {
+    Pending,
+    Running,
+    /// Failed, retried at the given time.
+    Retrying(DateTime<Utc>),
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub key: Option<String>,
+    pub kind: JobKind,
+    /// Ids of the jobs this one depends on, resolved from their keys when enqueued.
+    pub depends_on: Vec<u64>,
+    pub state: JobState,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRequest {
+    #[serde(default)]
+    pub key: Option<String>,
+    pub kind: JobKind,
+    /// Keys of the jobs to wait for.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+impl JobRequest {
+    pub fn new(key: String, kind: JobKind) -> Self {
+        Self {
+            key: Some(key),
+            kind,
+            depends_on: vec![],
+            max_attempts: None,
+        }
+    }
+}
+
+fn parse_public_key(hex_str: &str) -> Result<Sr25519Public> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x")).context("Invalid worker key")?;
+    let raw: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow!("Invalid worker key length"))?;
+    Ok(Sr25519Public::from_raw(raw))
+}
+
+/// Whether a failed job is worth another attempt. The errors that can't be told apart, e.g. a
+/// missing data source, are retried.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    match ChainError::classify(err) {
+        ChainError::Other(_) => true,
+        err => err.is_retryable(),
+    }
+}
+
+fn retry_backoff(attempts: u32) -> chrono::Duration {
+    let secs = RETRY_BACKOFF_BASE_SECS
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(RETRY_BACKOFF_MAX_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+pub struct JobQueue {
+    db: Arc<DB>,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    notify: Notify,
+}
+
+impl JobQueue {
+    pub fn load(db: Arc<DB>) -> Result<Self> {
+        let mut jobs = BTreeMap::new();
+        for item in db.prefix_iterator(JOB_KEY_PREFIX) {
+            let (key, value) = item?;
+            if !key.starts_with(JOB_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            let mut job: Job = serde_json::from_slice(&value)?;
+            if job.state == JobState::Running {
+                // Interrupted by the restart, the extrinsic may or may not have been included.
+                if job.attempts >= job.max_attempts {
+                    job.state = JobState::Failed;
+                    job.last_error = Some("Interrupted by a restart".into());
+                } else {
+                    job.state = JobState::Pending;
+                }
+                db.put(job_db_key(job.id), serde_json::to_vec(&job)?)?;
+            }
+            jobs.insert(job.id, job);
+        }
+        let unfinished = jobs.values().filter(|j| !j.state.is_finished()).count();
+        info!("Loaded {} jobs, {unfinished} unfinished.", jobs.len());
+        Ok(Self {
+            db,
+            jobs: Mutex::new(jobs),
+            notify: Notify::new(),
+        })
+    }
+
+    fn next_id(&self) -> Result<u64> {
+        let id = match self.db.get(JOB_NEXT_ID)? {
+            Some(b) => u64::from_le_bytes(b.try_into().map_err(|_| anyhow!("Bad job id"))?),
+            None => 0,
+        };
+        self.db.put(JOB_NEXT_ID, (id + 1).to_le_bytes())?;
+        Ok(id)
+    }
+
+    fn save(&self, job: &Job) -> Result<()> {
+        self.db.put(job_db_key(job.id), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    /// Adds a job, or returns the id of the unfinished job with the same key.
+    pub fn enqueue(&self, request: JobRequest) -> Result<u64> {
+        request.kind.validate()?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let latest_by_key = |key: &str| {
+            jobs.values()
+                .rev()
+                .find(|j| j.key.as_deref() == Some(key))
+                .map(|j| (j.id, j.state.is_finished()))
+        };
+        if let Some(key) = &request.key {
+            if let Some((id, false)) = latest_by_key(key) {
+                debug!("Job with key {key} is already queued as #{id}");
+                return Ok(id);
+            }
+        }
+        let depends_on = request
+            .depends_on
+            .iter()
+            .map(|key| {
+                latest_by_key(key)
+                    .map(|(id, _)| id)
+                    .ok_or_else(|| anyhow!("Unknown dependency: {key}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let max_attempts = request
+            .max_attempts
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+            .clamp(1, request.kind.max_attempts());
+        let now = Utc::now();
+        let job = Job {
+            id: self.next_id()?,
+            key: request.key,
+            kind: request.kind,
+            depends_on,
+            state: JobState::Pending,
+            attempts: 0,
+            max_attempts,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        self.save(&job)?;
+        info!("Enqueued job #{}: {:?}", job.id, job.key);
+        let id = job.id;
+        jobs.insert(id, job);
+        self.prune(&mut jobs)?;
+        drop(jobs);
+        self.notify.notify_waiters();
+        Ok(id)
+    }
+
+    fn prune(&self, jobs: &mut BTreeMap<u64, Job>) -> Result<()> {
+        let finished = jobs.values().filter(|j| j.state.is_finished()).count();
+        let to_remove = jobs
+            .values()
+            .filter(|j| j.state.is_finished())
+            .map(|j| j.id)
+            .take(finished.saturating_sub(MAX_FINISHED_JOBS))
+            .collect::<Vec<_>>();
+        for id in to_remove {
+            self.db.delete(job_db_key(id))?;
+            jobs.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Job)) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("Job #{id} not found"))?;
+        f(job);
+        job.updated_at = Utc::now();
+        self.save(job)?;
+        drop(jobs);
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Waits for the job to finish, returning its error if it failed.
+    pub async fn wait(&self, id: u64) -> Result<()> {
+        loop {
+            let notified = self.notify.notified();
+            let job = self.get(id).ok_or_else(|| anyhow!("Job #{id} not found"))?;
+            match job.state {
+                JobState::Succeeded => return Ok(()),
+                JobState::Failed => {
+                    return Err(anyhow!(job.last_error.unwrap_or_else(|| "Failed".into())))
+                }
+                _ => notified.await,
+            }
+        }
+    }
+
+    /// Marks the jobs ready to run as running and returns them, failing the ones whose
+    /// dependencies failed. Jobs for which `held` returns true are left pending.
+    fn take_ready(&self, held: impl Fn(&Job) -> bool) -> Result<Vec<Job>> {
+        let now = Utc::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut ready = vec![];
+        let mut failed = vec![];
+        for job in jobs.values() {
+            match &job.state {
+                JobState::Pending => {}
+                JobState::Retrying(at) if *at <= now => {}
+                _ => continue,
+            }
+            if held(job) {
+                continue;
+            }
+            let mut deps_done = true;
+            for dep in &job.depends_on {
+                match jobs.get(dep).map(|j| &j.state) {
+                    Some(JobState::Succeeded) => {}
+                    Some(JobState::Failed) | None => {
+                        failed.push((job.id, format!("Dependency #{dep} failed")));
+                        deps_done = false;
+                        break;
+                    }
+                    Some(_) => deps_done = false,
+                }
+            }
+            if deps_done {
+                ready.push(job.id);
+            }
+        }
+        let mut changed = !failed.is_empty();
+        for (id, reason) in failed {
+            let job = jobs.get_mut(&id).expect("Job must exist");
+            warn!("Job #{id} failed: {reason}");
+            job.state = JobState::Failed;
+            job.last_error = Some(reason);
+            job.updated_at = now;
+            self.save(job)?;
+        }
+        let mut taken = vec![];
+        for id in ready {
+            let job = jobs.get_mut(&id).expect("Job must exist");
+            job.state = JobState::Running;
+            job.attempts += 1;
+            job.updated_at = now;
+            self.save(job)?;
+            taken.push(job.clone());
+            changed = true;
+        }
+        drop(jobs);
+        if changed {
+            self.notify.notify_waiters();
+        }
+        Ok(taken)
+    }
+
+    fn finish(&self, id: u64, result: Result<()>) -> Result<()> {
+        self.update(id, |job| match result {
+            Ok(()) => {
+                info!("Job #{id} succeeded");
+                job.state = JobState::Succeeded;
+                job.last_error = None;
+            }
+            Err(err) => {
+                job.last_error = Some(err.to_string());
+                if !is_retryable(&err) {
+                    error!("Job #{id} failed: {err}");
+                    job.state = JobState::Failed;
+                } else if job.attempts >= job.max_attempts {
+                    error!("Job #{id} failed after {} attempts: {err}", job.attempts);
+                    job.state = JobState::Failed;
+                } else {
+                    let at = Utc::now() + retry_backoff(job.attempts);
+                    warn!("Job #{id} failed, retrying at {at}: {err}");
+                    job.state = JobState::Retrying(at);
+                }
+            }
+        })
+    }
+}
+
+fn job_db_key(id: u64) -> String {
+    // Zero padded so that the jobs are iterated in the order of their ids.
+    format!("{JOB_KEY_PREFIX}{id:020}")
+}
+
+impl TxManager {
+    /// Enqueues the job and waits for it to finish.
+    pub async fn run_job(self: Arc<Self>, request: JobRequest) -> Result<()> {
+        let id = self.jobs.enqueue(request)?;
+        self.jobs.wait(id).await
+    }
+
+    async fn execute_job(self: Arc<Self>, kind: JobKind) -> Result<()> {
+        let pid = kind.pid();
+        match kind {
+            JobKind::RegisterWorker {
+                pruntime_info,
+                attestation,
+                v2,
+                ..
+            } => {
+                self.register_worker(pid, pruntime_info, attestation, v2)
+                    .await
+            }
+            JobKind::AddWorker { worker, .. } => {
+                self.add_worker(pid, parse_public_key(&worker)?).await
+            }
+            JobKind::StartComputing { worker, stake, .. } => {
+                self.start_computing(pid, parse_public_key(&worker)?, stake)
+                    .await
+            }
+            JobKind::StopComputing { worker, .. } => {
+                self.stop_computing(pid, parse_public_key(&worker)?).await
+            }
+        }
+    }
+
+    pub async fn job_loop(self: Arc<Self>) -> Result<()> {
+        loop {
+            // Stopping computing is the only job which still goes out during maintenance.
+            let held = |job: &Job| {
+                !matches!(job.kind, JobKind::StopComputing { .. })
+                    && self.maintenance.is_active(job.kind.pid())
+            };
+            for job in self.jobs.take_ready(held)? {
+                let txm = self.clone();
+                tokio::spawn(async move {
+                    debug!("Running job #{}: {:?}", job.id, job.key);
+                    let result = txm.clone().execute_job(job.kind).await;
+                    if let Err(err) = txm.jobs.finish(job.id, result) {
+                        error!("Failed to update job #{}: {err}", job.id);
+                    }
+                });
+            }
+            let _ = tokio::time::timeout(JOB_LOOP_INTERVAL, self.jobs.notify.notified()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Arc<DB> {
+        let path = std::env::temp_dir().join(format!("prb-jobs-{}", uuid::Uuid::new_v4()));
+        Arc::new(DB::open(&crate::pool_operator::get_options(None), path).unwrap())
+    }
+
+    fn queue() -> JobQueue {
+        JobQueue::load(db()).unwrap()
+    }
+
+    fn add_worker() -> JobRequest {
+        JobRequest::new(
+            "add".into(),
+            JobKind::AddWorker {
+                pid: 0,
+                worker: hex::encode([1u8; 32]),
+            },
+        )
+    }
+
+    fn stop_computing() -> JobRequest {
+        JobRequest::new(
+            "stop".into(),
+            JobKind::StopComputing {
+                pid: 0,
+                worker: hex::encode([1u8; 32]),
+            },
+        )
+    }
+
+    fn run_once(queue: &JobQueue, result: Result<()>) -> Job {
+        let job = queue.take_ready(|_| false).unwrap().pop().unwrap();
+        queue.finish(job.id, result).unwrap();
+        queue.get(job.id).unwrap()
+    }
+
+    #[test]
+    fn retryable_errors_are_retried() {
+        let queue = queue();
+        queue.enqueue(stop_computing()).unwrap();
+        let job = run_once(&queue, Err(ChainError::Timeout.into()));
+        assert!(matches!(job.state, JobState::Retrying(_)));
+        assert_eq!(job.last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn unknown_errors_are_retried() {
+        let queue = queue();
+        queue.enqueue(stop_computing()).unwrap();
+        let job = run_once(&queue, Err(anyhow!("No valid data source")));
+        assert!(matches!(job.state, JobState::Retrying(_)));
+    }
+
+    #[test]
+    fn fatal_errors_fail_at_once() {
+        let queue = queue();
+        queue.enqueue(stop_computing()).unwrap();
+        let err = ChainError::Module {
+            pallet: "PhalaComputation".into(),
+            variant: "WorkerNotBound".into(),
+        };
+        // Classified by its message once formatted.
+        let job = run_once(&queue, Err(anyhow!("{err}")));
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.attempts, 1);
+    }
+
+    #[test]
+    fn jobs_unsafe_to_submit_again_are_not_retried() {
+        let queue = queue();
+        let id = queue
+            .enqueue(JobRequest {
+                max_attempts: Some(5),
+                ..add_worker()
+            })
+            .unwrap();
+        assert_eq!(queue.get(id).unwrap().max_attempts, 1);
+        let job = run_once(&queue, Err(ChainError::Timeout.into()));
+        assert_eq!(job.state, JobState::Failed);
+
+        let id = queue
+            .enqueue(JobRequest {
+                max_attempts: Some(3),
+                ..stop_computing()
+            })
+            .unwrap();
+        assert_eq!(queue.get(id).unwrap().max_attempts, 3);
+    }
+
+    #[test]
+    fn interrupted_jobs_are_retried_only_with_attempts_left() {
+        let db = db();
+        let queue = JobQueue::load(db.clone()).unwrap();
+        let add = queue.enqueue(add_worker()).unwrap();
+        let stop = queue.enqueue(stop_computing()).unwrap();
+        assert_eq!(queue.take_ready(|_| false).unwrap().len(), 2);
+        drop(queue);
+
+        let queue = JobQueue::load(db).unwrap();
+        let add = queue.get(add).unwrap();
+        assert_eq!(add.state, JobState::Failed);
+        assert_eq!(add.last_error.as_deref(), Some("Interrupted by a restart"));
+        assert_eq!(queue.get(stop).unwrap().state, JobState::Pending);
+    }
+}
@@ -0,0 +1,455 @@
+//! Registers the pRuntime pods of a Kubernetes cluster as workers.
+//!
+//! The pods matching a label selector are polled from the Kubernetes API with the service account
+//! of the prb pod. Each running pod with an IP becomes a worker named `k8s-<namespace>-<pod>`,
+//! configured with the pod annotations:
+//!
+//! - `prb.phala.network/pid`: the pool pid, required;
+//! - `prb.phala.network/stake`: the stake in BN string, defaults to 0;
+//! - `prb.phala.network/port`: the pRuntime port, defaults to 8000;
+//! - `prb.phala.network/sync-only`: `true` to run the worker in sync-only mode.
+//!
+//! The names of the workers registered by the discovery are kept in the pool operator db. Only
+//! those are updated, and removed once their pods are gone; a pod which is not running yet or
+//! anymore keeps its worker. Workers added by other means are left untouched, even when their
+//! name has the `k8s-` prefix. The workers with the prefix registered before the names were kept
+//! are taken as discovered ones.
+
+use crate::bus::Bus;
+use crate::cli::ConfigCommands;
+use crate::configurator::api_handler;
+use crate::inv_db::{get_all_workers, validate_bn_string, validate_endpoint, Worker, WrappedDb};
+use crate::pool_operator::DB;
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const WORKER_NAME_PREFIX: &str = "k8s-";
+
+const ANNOTATION_PID: &str = "prb.phala.network/pid";
+const ANNOTATION_STAKE: &str = "prb.phala.network/stake";
+const ANNOTATION_PORT: &str = "prb.phala.network/port";
+const ANNOTATION_SYNC_ONLY: &str = "prb.phala.network/sync-only";
+
+const DEFAULT_PRUNTIME_PORT: u16 = 8000;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+static DISCOVERED_WORKERS_KEY: &str = "k8s_discovered_workers";
+
+#[derive(Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: ObjectMeta,
+    status: Option<PodStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    name: String,
+    namespace: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    deletion_timestamp: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PodStatus {
+    phase: Option<String>,
+    pod_ip: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DiscoveredWorker {
+    endpoint: String,
+    pid: u64,
+    stake: String,
+    sync_only: bool,
+}
+
+struct KubeClient {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl KubeClient {
+    fn in_cluster() -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("Not running in a Kubernetes cluster")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host
+        };
+        let token = std::fs::read_to_string(format!("{SERVICE_ACCOUNT_DIR}/token"))
+            .context("Failed to read the service account token")?;
+        let ca = std::fs::read(format!("{SERVICE_ACCOUNT_DIR}/ca.crt"))
+            .context("Failed to read the cluster CA")?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .no_proxy()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: format!("https://{host}:{port}"),
+            token: token.trim().to_string(),
+        })
+    }
+
+    async fn list_pods(&self, namespace: &str, selector: &str) -> Result<Vec<Pod>> {
+        let url = format!("{}/api/v1/namespaces/{namespace}/pods", self.base_url);
+        let pods: PodList = self
+            .client
+            .get(url)
+            .query(&[("labelSelector", selector)])
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(pods.items)
+    }
+}
+
+fn worker_name(pod: &Pod) -> String {
+    format!(
+        "{WORKER_NAME_PREFIX}{}-{}",
+        pod.metadata.namespace, pod.metadata.name
+    )
+}
+
+fn discovered_worker(pod: &Pod) -> Result<Option<DiscoveredWorker>> {
+    if pod.metadata.deletion_timestamp.is_some() {
+        return Ok(None);
+    }
+    let Some(status) = &pod.status else {
+        return Ok(None);
+    };
+    if status.phase.as_deref() != Some("Running") {
+        return Ok(None);
+    }
+    let Some(ip) = &status.pod_ip else {
+        return Ok(None);
+    };
+    let annotations = &pod.metadata.annotations;
+    let pid = annotations
+        .get(ANNOTATION_PID)
+        .context("Missing pid annotation")?
+        .parse()
+        .context("Invalid pid annotation")?;
+    let port = match annotations.get(ANNOTATION_PORT) {
+        Some(port) => port.parse().context("Invalid port annotation")?,
+        None => DEFAULT_PRUNTIME_PORT,
+    };
+    let host = if ip.contains(':') {
+        format!("[{ip}]")
+    } else {
+        ip.clone()
+    };
+    let stake = annotations
+        .get(ANNOTATION_STAKE)
+        .cloned()
+        .unwrap_or_else(|| "0".into());
+    Ok(Some(DiscoveredWorker {
+        endpoint: validate_endpoint(format!("http://{host}:{port}"))?,
+        pid,
+        stake: validate_bn_string(stake).context("Invalid stake annotation")?,
+        sync_only: annotations.get(ANNOTATION_SYNC_ONLY).map(|v| v.as_str()) == Some("true"),
+    }))
+}
+
+/// Whether the registered worker has to be updated to match the pod.
+fn is_outdated(worker: &Worker, discovered: &DiscoveredWorker) -> bool {
+    worker.endpoint != discovered.endpoint
+        || worker.pid != Some(discovered.pid)
+        || worker.stake != discovered.stake
+        || worker.sync_only != discovered.sync_only
+}
+
+/// The names of the workers registered by the discovery.
+fn load_tracked(po_db: &DB, registered: &HashMap<String, Worker>) -> Result<BTreeSet<String>> {
+    match po_db.get(DISCOVERED_WORKERS_KEY)? {
+        Some(value) => Ok(serde_json::from_slice(&value)?),
+        None => Ok(registered
+            .keys()
+            .filter(|name| name.starts_with(WORKER_NAME_PREFIX))
+            .cloned()
+            .collect()),
+    }
+}
+
+fn save_tracked(po_db: &DB, tracked: &BTreeSet<String>) -> Result<()> {
+    po_db.put(DISCOVERED_WORKERS_KEY, serde_json::to_vec(tracked)?)?;
+    Ok(())
+}
+
+/// The commands bringing the workers registered by the discovery, `tracked`, in line with `pods`.
+fn plan(
+    registered: &HashMap<String, Worker>,
+    tracked: &BTreeSet<String>,
+    pods: &[Pod],
+) -> Vec<ConfigCommands> {
+    let present: HashSet<_> = pods.iter().map(worker_name).collect();
+    let mut discovered = BTreeMap::new();
+    for pod in pods {
+        match discovered_worker(pod) {
+            Ok(Some(worker)) => {
+                discovered.insert(worker_name(pod), worker);
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Ignoring pod {}: {err}", pod.metadata.name),
+        }
+    }
+
+    let mut commands = vec![];
+    for name in tracked {
+        let Some(worker) = registered.get(name) else {
+            continue;
+        };
+        if !present.contains(name) {
+            info!("Deregistering worker {name}, its pod is gone");
+            commands.push(ConfigCommands::RemoveWorker { name: name.clone() });
+            continue;
+        }
+        match discovered.get(name) {
+            Some(d) if is_outdated(worker, d) => {
+                info!("Updating worker {name} to {}", d.endpoint);
+                commands.push(ConfigCommands::UpdateWorker {
+                    name: name.clone(),
+                    new_name: None,
+                    endpoint: d.endpoint.clone(),
+                    stake: d.stake.clone(),
+                    pid: d.pid,
+                    disabled: !worker.enabled,
+                    sync_only: d.sync_only,
+                    gatekeeper: worker.gatekeeper,
+                    proxy: worker.proxy.clone(),
+                });
+            }
+            // Not running, e.g. restarting: the worker is kept until the pod is gone.
+            _ => {}
+        }
+    }
+    for (name, d) in discovered {
+        if registered.contains_key(&name) {
+            if !tracked.contains(&name) {
+                warn!(
+                    "Not registering pod {name}, a worker with its name was added by other means"
+                );
+            }
+            continue;
+        }
+        info!("Registering worker {name} at {}", d.endpoint);
+        commands.push(ConfigCommands::AddWorker {
+            name,
+            endpoint: d.endpoint,
+            stake: d.stake,
+            pid: d.pid,
+            disabled: false,
+            sync_only: d.sync_only,
+            gatekeeper: false,
+            proxy: None,
+        });
+    }
+    commands
+}
+
+async fn reconcile(db: WrappedDb, po_db: Arc<DB>, bus: Arc<Bus>, pods: Vec<Pod>) -> Result<()> {
+    let registered = get_all_workers(db.clone())?
+        .into_iter()
+        .map(|w| (w.name.clone(), w))
+        .collect::<HashMap<_, _>>();
+    let mut tracked = load_tracked(&po_db, &registered)?;
+    // Forget the workers removed by other means.
+    tracked.retain(|name| registered.contains_key(name));
+
+    for command in plan(&registered, &tracked, &pods) {
+        debug!("Applying {command:?}");
+        let change = match &command {
+            ConfigCommands::AddWorker { name, .. } => Some((name.clone(), true)),
+            ConfigCommands::RemoveWorker { name } => Some((name.clone(), false)),
+            _ => None,
+        };
+        match api_handler(db.clone(), po_db.clone(), bus.clone(), command).await {
+            Ok(_) => match change {
+                Some((name, true)) => {
+                    tracked.insert(name);
+                }
+                Some((name, false)) => {
+                    tracked.remove(&name);
+                }
+                None => {}
+            },
+            Err(err) => error!("Failed to apply discovered worker: {err}"),
+        }
+    }
+    save_tracked(&po_db, &tracked)
+}
+
+/// Polls the pods matching `selector` in `namespace` every `interval`, registering them as
+/// workers. The namespace of the prb pod is used if `namespace` is `None`.
+pub async fn discovery_loop(
+    db: WrappedDb,
+    po_db: Arc<DB>,
+    bus: Arc<Bus>,
+    selector: String,
+    namespace: Option<String>,
+    interval: Duration,
+) -> Result<()> {
+    let client = KubeClient::in_cluster()?;
+    let namespace = match namespace {
+        Some(namespace) => namespace,
+        None => std::fs::read_to_string(format!("{SERVICE_ACCOUNT_DIR}/namespace"))
+            .context("Failed to read the namespace of the service account")?
+            .trim()
+            .to_string(),
+    };
+    info!("Discovering workers in namespace {namespace} with selector {selector}");
+    loop {
+        match client.list_pods(&namespace, &selector).await {
+            Ok(pods) => {
+                if let Err(err) = reconcile(db.clone(), po_db.clone(), bus.clone(), pods).await {
+                    error!("Failed to reconcile discovered workers: {err}");
+                }
+            }
+            Err(err) => error!("Failed to list pods: {err}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(name: &str, phase: &str, ip: Option<&str>) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": name,
+                "namespace": "ns",
+                "annotations": { ANNOTATION_PID: "1" },
+            },
+            "status": { "phase": phase, "podIP": ip },
+        }))
+        .unwrap()
+    }
+
+    fn worker(name: &str, endpoint: &str) -> Worker {
+        Worker {
+            id: name.into(),
+            name: name.into(),
+            endpoint: endpoint.into(),
+            stake: "0".into(),
+            pid: Some(1),
+            enabled: true,
+            sync_only: false,
+            gatekeeper: false,
+            proxy: None,
+        }
+    }
+
+    fn registered(workers: &[Worker]) -> HashMap<String, Worker> {
+        workers
+            .iter()
+            .map(|w| (w.name.clone(), w.clone()))
+            .collect()
+    }
+
+    fn tracked(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn summary(commands: &[ConfigCommands]) -> Vec<String> {
+        commands
+            .iter()
+            .map(|command| match command {
+                ConfigCommands::AddWorker { name, .. } => format!("add {name}"),
+                ConfigCommands::UpdateWorker { name, endpoint, .. } => {
+                    format!("update {name} {endpoint}")
+                }
+                ConfigCommands::RemoveWorker { name } => format!("remove {name}"),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_pods_are_registered() {
+        let pods = [
+            pod("a", "Running", Some("10.0.0.1")),
+            pod("b", "Pending", None),
+        ];
+        let commands = plan(&registered(&[]), &tracked(&[]), &pods);
+        assert_eq!(summary(&commands), vec!["add k8s-ns-a"]);
+    }
+
+    #[test]
+    fn workers_are_removed_only_when_their_pods_are_gone() {
+        let workers = [
+            worker("k8s-ns-a", "http://10.0.0.1:8000"),
+            worker("k8s-ns-b", "http://10.0.0.2:8000"),
+        ];
+        // `a` is restarting, `b` is gone.
+        let pods = [pod("a", "Pending", None)];
+        let commands = plan(
+            &registered(&workers),
+            &tracked(&["k8s-ns-a", "k8s-ns-b"]),
+            &pods,
+        );
+        assert_eq!(summary(&commands), vec!["remove k8s-ns-b"]);
+    }
+
+    #[test]
+    fn discovered_workers_follow_their_pods() {
+        let workers = [worker("k8s-ns-a", "http://10.0.0.1:8000")];
+        let pods = [pod("a", "Running", Some("10.0.0.9"))];
+        let commands = plan(&registered(&workers), &tracked(&["k8s-ns-a"]), &pods);
+        assert_eq!(
+            summary(&commands),
+            vec!["update k8s-ns-a http://10.0.0.9:8000"]
+        );
+    }
+
+    #[test]
+    fn workers_added_by_other_means_are_left_untouched() {
+        let workers = [
+            worker("k8s-ns-a", "http://10.0.0.1:8000"),
+            worker("k8s-manual", "http://10.0.0.2:8000"),
+        ];
+        // `k8s-ns-a` was added by hand although a pod has its name.
+        let pods = [pod("a", "Running", Some("10.0.0.9"))];
+        let commands = plan(&registered(&workers), &tracked(&[]), &pods);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn prefixed_workers_are_adopted_without_tracked_names() {
+        let path = std::env::temp_dir().join(format!("prb-k8s-{}", uuid::Uuid::new_v4()));
+        let po_db = DB::open(&crate::pool_operator::get_options(None), &path).unwrap();
+        let workers = registered(&[
+            worker("k8s-ns-a", "http://10.0.0.1:8000"),
+            worker("manual", "http://10.0.0.2:8000"),
+        ]);
+        assert_eq!(
+            load_tracked(&po_db, &workers).unwrap(),
+            tracked(&["k8s-ns-a"])
+        );
+        save_tracked(&po_db, &tracked(&[])).unwrap();
+        assert!(load_tracked(&po_db, &workers).unwrap().is_empty());
+        drop(po_db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+//! Signer keys of the pool operators kept in external secret stores.
+//!
+//! Instead of storing the key pair of a pool operator in the db, only its public key and a
+//! [`KeySource`] are stored. The pair is loaded from the source the first time it signs a tx, and
+//! only kept in memory. The secrets read from the sources are zeroized once parsed, and the key
+//! pairs zeroize themselves when dropped.
+//!
+//! The supported sources are:
+//!
+//! - HashiCorp Vault, reading a field of a KV secret with the token from an env var;
+//! - AWS KMS, decrypting a ciphertext blob with the credentials from the standard AWS env vars;
+//! - a file encrypted with a passphrase from an env var, created with
+//!   `prb-config encrypt-operator-key`.
+//!
+//! A secret is either a secret key in hex, or a mnemonic or a seed as accepted by
+//! `Pair::from_string`.
+
+use crate::pool_operator::{OperatorKey, PoolOperator, PoolOperatorAccess, DB};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use rand::RngCore;
+use schnorrkel::SecretKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sp_core::sr25519::Pair as Sr25519Pair;
+use sp_core::Pair;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const ENCRYPTED_FILE_VERSION: u32 = 1;
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+fn default_vault_field() -> String {
+    "key".into()
+}
+
+fn default_vault_token_env() -> String {
+    "VAULT_TOKEN".into()
+}
+
+fn default_passphrase_env() -> String {
+    "PRB_KEY_PASSPHRASE".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeySource {
+    Vault {
+        /// Address of the Vault server, e.g. `https://vault.example.com:8200`
+        addr: String,
+        /// Path of the secret, e.g. `secret/data/prb/pool-1` for a KV v2 engine
+        path: String,
+        /// Field of the secret holding the key
+        #[serde(default = "default_vault_field")]
+        field: String,
+        /// Env var holding the Vault token
+        #[serde(default = "default_vault_token_env")]
+        token_env: String,
+    },
+    AwsKms {
+        region: String,
+        /// File with the base64 ciphertext blob returned by `aws kms encrypt`
+        ciphertext_file: String,
+    },
+    EncryptedFile {
+        path: String,
+        /// Env var holding the passphrase
+        #[serde(default = "default_passphrase_env")]
+        passphrase_env: String,
+    },
+}
+
+impl KeySource {
+    pub fn provider(&self) -> Box<dyn KeyProvider> {
+        match self.clone() {
+            KeySource::Vault {
+                addr,
+                path,
+                field,
+                token_env,
+            } => Box::new(VaultProvider {
+                addr,
+                path,
+                field,
+                token_env,
+            }),
+            KeySource::AwsKms {
+                region,
+                ciphertext_file,
+            } => Box::new(AwsKmsProvider {
+                region,
+                ciphertext_file,
+            }),
+            KeySource::EncryptedFile {
+                path,
+                passphrase_env,
+            } => Box::new(EncryptedFileProvider {
+                path,
+                passphrase_env,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Name of the provider, reported by the API.
+    fn name(&self) -> &'static str;
+
+    /// Reads the secret from the store.
+    async fn load_secret(&self) -> Result<Zeroizing<String>>;
+
+    async fn load_pair(&self) -> Result<Sr25519Pair> {
+        let secret = self.load_secret().await?;
+        parse_secret(&secret).with_context(|| format!("Bad key from {}", self.name()))
+    }
+}
+
+/// Parses a secret key in hex, or a mnemonic or a seed.
+pub fn parse_secret(secret: &str) -> Result<Sr25519Pair> {
+    let secret = secret.trim();
+    let hex = secret.strip_prefix("0x").unwrap_or(secret);
+    if hex.len() == 128 {
+        if let Ok(bytes) = hex::decode(hex).map(Zeroizing::new) {
+            let key = SecretKey::from_ed25519_bytes(&bytes).map_err(|e| anyhow!(e.to_string()))?;
+            return Ok(Sr25519Pair::from(key));
+        }
+    }
+    Sr25519Pair::from_string(secret, None).map_err(|e| anyhow!("Invalid secret: {e:?}"))
+}
+
+fn read_env(name: &str) -> Result<Zeroizing<String>> {
+    std::env::var(name)
+        .map(Zeroizing::new)
+        .with_context(|| format!("Env var {name} is not set"))
+}
+
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?)
+}
+
+struct VaultProvider {
+    addr: String,
+    path: String,
+    field: String,
+    token_env: String,
+}
+
+#[async_trait]
+impl KeyProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn load_secret(&self) -> Result<Zeroizing<String>> {
+        let token = read_env(&self.token_env)?;
+        let url = format!(
+            "{}/v1/{}",
+            self.addr.trim_end_matches('/'),
+            self.path.trim_start_matches('/')
+        );
+        let resp = http_client()?
+            .get(&url)
+            .header("X-Vault-Token", token.as_str())
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            bail!("Vault returned {} for {}", resp.status(), self.path);
+        }
+        let body = Zeroizing::new(resp.text().await?);
+        let value: serde_json::Value = serde_json::from_str(&body)?;
+        // KV v2 nests the secret in another `data`.
+        let data = &value["data"];
+        let secret = data["data"][&self.field]
+            .as_str()
+            .or_else(|| data[&self.field].as_str())
+            .ok_or_else(|| anyhow!("No field {} in the Vault secret", self.field))?;
+        Ok(Zeroizing::new(secret.to_string()))
+    }
+}
+
+struct AwsKmsProvider {
+    region: String,
+    ciphertext_file: String,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl AwsKmsProvider {
+    /// Signs a KMS request with AWS Signature Version 4, returning the headers to send.
+    fn sign(&self, target: &str, body: &str) -> Result<Vec<(&'static str, String)>> {
+        let access_key = read_env("AWS_ACCESS_KEY_ID")?;
+        let secret_key = read_env("AWS_SECRET_ACCESS_KEY")?;
+        let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = format!("kms.{}.amazonaws.com", self.region);
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+        headers.push(("x-amz-target", target.to_string()));
+
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{canonical_headers}\n{signed_headers}\n{}",
+            hex::encode(Sha256::digest(body.as_bytes()))
+        );
+        let scope = format!("{date}/{}/kms/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = Zeroizing::new(format!("AWS4{}", secret_key.as_str()));
+        let k_date = hmac_sha256(signing_key.as_bytes(), &date);
+        let k_region = hmac_sha256(&k_date, &self.region);
+        let k_service = hmac_sha256(&k_region, "kms");
+        let k_signing = Zeroizing::new(hmac_sha256(&k_service, "aws4_request"));
+        let signature = hex::encode(hmac_sha256(&k_signing, &string_to_sign));
+
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                access_key.as_str()
+            ),
+        ));
+        Ok(headers)
+    }
+}
+
+#[async_trait]
+impl KeyProvider for AwsKmsProvider {
+    fn name(&self) -> &'static str {
+        "aws_kms"
+    }
+
+    async fn load_secret(&self) -> Result<Zeroizing<String>> {
+        let blob = std::fs::read_to_string(&self.ciphertext_file)
+            .with_context(|| format!("Failed to read {}", self.ciphertext_file))?;
+        let body = serde_json::json!({ "CiphertextBlob": blob.trim() }).to_string();
+        let headers = self.sign("TrentService.Decrypt", &body)?;
+        let mut req = http_client()?.post(format!("https://kms.{}.amazonaws.com/", self.region));
+        for (name, value) in headers {
+            if name != "host" {
+                req = req.header(name, value);
+            }
+        }
+        let resp = req.body(body).send().await?;
+        if !resp.status().is_success() {
+            bail!("KMS returned {}: {}", resp.status(), resp.text().await?);
+        }
+        let body = Zeroizing::new(resp.text().await?);
+        let value: serde_json::Value = serde_json::from_str(&body)?;
+        let plaintext = value["Plaintext"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No Plaintext in the KMS response"))?;
+        let plaintext =
+            Zeroizing::new(base64::engine::general_purpose::STANDARD.decode(plaintext)?);
+        let secret = std::str::from_utf8(&plaintext).context("The decrypted key is not UTF-8")?;
+        Ok(Zeroizing::new(secret.to_string()))
+    }
+}
+
+/// The content of a key file encrypted with a passphrase.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncryptedKeyFile {
+    pub version: u32,
+    pub scrypt_log_n: u8,
+    pub scrypt_r: u32,
+    pub scrypt_p: u32,
+    pub salt: String,
+    pub nonce: String,
+    /// The secret encrypted with AES-256-GCM.
+    pub ciphertext: String,
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+) -> Result<Zeroizing<[u8; 32]>> {
+    let params = scrypt::Params::new(log_n, r, p).map_err(|e| anyhow!("Bad scrypt params: {e}"))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, key.as_mut())
+        .map_err(|e| anyhow!("scrypt failed: {e}"))?;
+    Ok(key)
+}
+
+impl EncryptedKeyFile {
+    pub fn encrypt(secret: &str, passphrase: &str) -> Result<Self> {
+        Self::encrypt_with(secret, passphrase, SCRYPT_LOG_N)
+    }
+
+    fn encrypt_with(secret: &str, passphrase: &str, scrypt_log_n: u8) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let key = derive_key(passphrase, &salt, scrypt_log_n, SCRYPT_R, SCRYPT_P)?;
+        let cipher = Aes256Gcm::new_from_slice(key.as_ref()).expect("The key is 32 bytes");
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), secret.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt the key"))?;
+        Ok(Self {
+            version: ENCRYPTED_FILE_VERSION,
+            scrypt_log_n,
+            scrypt_r: SCRYPT_R,
+            scrypt_p: SCRYPT_P,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<Zeroizing<String>> {
+        if self.version != ENCRYPTED_FILE_VERSION {
+            bail!("Unsupported key file version {}", self.version);
+        }
+        let key = derive_key(
+            passphrase,
+            &hex::decode(&self.salt)?,
+            self.scrypt_log_n,
+            self.scrypt_r,
+            self.scrypt_p,
+        )?;
+        let cipher = Aes256Gcm::new_from_slice(key.as_ref()).expect("The key is 32 bytes");
+        let nonce = hex::decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            bail!("Bad nonce length");
+        }
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                hex::decode(&self.ciphertext)?.as_ref(),
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow!("Failed to decrypt the key, wrong passphrase?"))?;
+        let secret = std::str::from_utf8(&plaintext).context("The decrypted key is not UTF-8")?;
+        Ok(Zeroizing::new(secret.to_string()))
+    }
+}
+
+struct EncryptedFileProvider {
+    path: String,
+    passphrase_env: String,
+}
+
+#[async_trait]
+impl KeyProvider for EncryptedFileProvider {
+    fn name(&self) -> &'static str {
+        "encrypted_file"
+    }
+
+    async fn load_secret(&self) -> Result<Zeroizing<String>> {
+        let content =
+            std::fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path))?;
+        let file: EncryptedKeyFile = serde_json::from_slice(&content)?;
+        let passphrase = read_env(&self.passphrase_env)?;
+        file.decrypt(&passphrase)
+    }
+}
+
+impl OperatorKey {
+    /// Loads the key from the source once, only keeping its public key.
+    pub async fn from_source(source: KeySource) -> Result<Self> {
+        let public = source.provider().load_pair().await?.public();
+        Ok(OperatorKey::External { public, source })
+    }
+}
+
+/// Encrypts the secret with the passphrase in `PRB_KEY_PASSPHRASE` into a file for the
+/// [`KeySource::EncryptedFile`] source. Returns the public key.
+pub fn write_encrypted_key_file(secret: &str, output: &str) -> Result<sp_core::sr25519::Public> {
+    let public = parse_secret(secret)?.public();
+    let passphrase = read_env(&default_passphrase_env())?;
+    let file = EncryptedKeyFile::encrypt(secret, &passphrase)?;
+    std::fs::write(output, serde_json::to_vec_pretty(&file)?)
+        .with_context(|| format!("Failed to write {output}"))?;
+    Ok(public)
+}
+
+/// The key pairs loaded from the key providers, by pid.
+#[derive(Default)]
+pub struct Signers {
+    pairs: Mutex<HashMap<u64, Sr25519Pair>>,
+}
+
+impl Signers {
+    /// Returns the key pair of the pool operator, loading it from its key provider if needed.
+    pub async fn pair(&self, po: &PoolOperator) -> Result<Sr25519Pair> {
+        let (public, source) = match &po.key {
+            OperatorKey::Plain(pair) => return Ok(pair.clone()),
+            OperatorKey::External { public, source } => (public, source),
+        };
+        let mut pairs = self.pairs.lock().await;
+        if let Some(pair) = pairs.get(&po.pid) {
+            if pair.public() == *public {
+                return Ok(pair.clone());
+            }
+        }
+        let provider = source.provider();
+        let pair = provider.load_pair().await?;
+        if pair.public() != *public {
+            bail!(
+                "Key loaded from {} does not match the operator of pool #{}",
+                provider.name(),
+                po.pid
+            );
+        }
+        info!(
+            "Loaded the signer of pool #{} from {}",
+            po.pid,
+            provider.name()
+        );
+        pairs.insert(po.pid, pair.clone());
+        Ok(pair)
+    }
+
+    pub async fn is_loaded(&self, pid: u64) -> bool {
+        self.pairs.lock().await.contains_key(&pid)
+    }
+
+    /// Loads the keys of all the pool operators, so that a broken provider shows up on start.
+    pub async fn preload(self: Arc<Self>, db: Arc<DB>) {
+        let operators = match db.get_all_po() {
+            Ok(operators) => operators,
+            Err(err) => {
+                error!("Failed to list the pool operators: {err}");
+                return;
+            }
+        };
+        for po in operators {
+            if let Err(err) = self.pair(&po).await {
+                error!("Failed to load the signer of pool #{}: {err:?}", po.pid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC: &str = "bottom drive obey lake curtain smoke basket hold race lonely fit walk";
+
+    /// Cheap scrypt parameters, the decryption reads them from the file.
+    const TEST_SCRYPT_LOG_N: u8 = 4;
+
+    #[test]
+    fn encrypted_key_file_round_trip() {
+        let file =
+            EncryptedKeyFile::encrypt_with(MNEMONIC, "passphrase", TEST_SCRYPT_LOG_N).unwrap();
+        assert_eq!(file.decrypt("passphrase").unwrap().as_str(), MNEMONIC);
+        assert!(file.decrypt("wrong passphrase").is_err());
+
+        // Survives the serialization into the key file.
+        let file: EncryptedKeyFile =
+            serde_json::from_slice(&serde_json::to_vec(&file).unwrap()).unwrap();
+        assert_eq!(file.decrypt("passphrase").unwrap().as_str(), MNEMONIC);
+    }
+
+    #[test]
+    fn encrypted_key_file_is_salted() {
+        let a = EncryptedKeyFile::encrypt_with(MNEMONIC, "passphrase", TEST_SCRYPT_LOG_N).unwrap();
+        let b = EncryptedKeyFile::encrypt_with(MNEMONIC, "passphrase", TEST_SCRYPT_LOG_N).unwrap();
+        assert_ne!(a.salt, b.salt);
+        assert_ne!(a.nonce, b.nonce);
+        assert_ne!(a.ciphertext, b.ciphertext);
+    }
+
+    #[test]
+    fn tampered_key_file_is_rejected() {
+        let file =
+            EncryptedKeyFile::encrypt_with(MNEMONIC, "passphrase", TEST_SCRYPT_LOG_N).unwrap();
+
+        let mut ciphertext = hex::decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        let tampered = EncryptedKeyFile {
+            ciphertext: hex::encode(ciphertext),
+            ..file.clone()
+        };
+        assert!(tampered.decrypt("passphrase").is_err());
+
+        let other_salt = EncryptedKeyFile {
+            salt: hex::encode([0u8; 16]),
+            ..file.clone()
+        };
+        assert!(other_salt.decrypt("passphrase").is_err());
+
+        let future = EncryptedKeyFile {
+            version: ENCRYPTED_FILE_VERSION + 1,
+            ..file
+        };
+        assert!(future.decrypt("passphrase").is_err());
+    }
+
+    #[test]
+    fn parse_secret_accepts_hex_keys_and_mnemonics() {
+        let secret_key = hex::encode([7u8; 64]);
+        let pair = parse_secret(&secret_key).unwrap();
+        assert_eq!(
+            parse_secret(&format!("0x{secret_key}")).unwrap().public(),
+            pair.public()
+        );
+
+        let seed = [1u8; 32];
+        assert_eq!(
+            parse_secret(&format!("0x{}", hex::encode(seed)))
+                .unwrap()
+                .public(),
+            Sr25519Pair::from_seed(&seed).public()
+        );
+        assert_eq!(
+            parse_secret(&format!("  {MNEMONIC}\n")).unwrap().public(),
+            Sr25519Pair::from_string(MNEMONIC, None).unwrap().public()
+        );
+        assert!(parse_secret("not a secret").is_err());
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        // Test case 2 of RFC 4231.
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", "what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Importer for the inventory of legacy runtime-bridge (prb v2) deployments.
+//!
+//! The legacy lifecycle manager keeps pools, workers and pool owner keys in its own store. They
+//! are read from a JSON or YAML dump of that store, with the field names used by the legacy
+//! lifecycle API:
+//!
+//! ```yaml
+//! pools:
+//!   - { uuid: ..., pid: 1, name: pool1, enabled: true, syncOnly: false, realPhalaSs58: ... }
+//! workers:
+//!   - { uuid: ..., pid: 1, name: w1, endpoint: http://10.0.0.1:8000, stake: "1000", enabled: true }
+//! keys:
+//!   - { pid: 1, mnemonic: "..." }
+//! ```
+//!
+//! Entries already present in the inventory are skipped, so an import can be safely re-run.
+
+use crate::cli::ConfigCommands;
+use crate::inv_db::{
+    self, get_pool_by_pid, get_worker_by_name, validate_bn_string, validate_endpoint, WrappedDb,
+};
+use crate::pool_operator::{OperatorKey, PoolOperator, PoolOperatorAccess, DB};
+use anyhow::{anyhow, Context, Result};
+use schnorrkel::SecretKey;
+use serde::{Deserialize, Serialize};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::sr25519::Pair as Sr25519Pair;
+use sp_core::Pair;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct LegacyDump {
+    pub pools: Vec<LegacyPool>,
+    pub workers: Vec<LegacyWorker>,
+    pub keys: Vec<LegacyKey>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyPool {
+    #[serde(default)]
+    pub uuid: String,
+    pub pid: u64,
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub sync_only: bool,
+    #[serde(default)]
+    pub deleted: bool,
+    /// The pool owner when the pool is operated through a proxy.
+    #[serde(default)]
+    pub real_phala_ss58: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyWorker {
+    #[serde(default)]
+    pub uuid: String,
+    pub pid: u64,
+    pub name: String,
+    pub endpoint: String,
+    #[serde(default)]
+    pub stake: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub sync_only: bool,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyKey {
+    pub pid: u64,
+    /// Hex encoded 64 bytes sr25519 secret key, in the ed25519-expanded layout of schnorrkel.
+    /// Hex encoded ed25519 secret key bytes.
+    pub secret_key: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Create,
+    Skip,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportItem {
+    pub kind: &'static str,
+    pub name: String,
+    pub action: ImportAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub skipped: usize,
+    pub items: Vec<ImportItem>,
+}
+
+impl ImportReport {
+    fn create(&mut self, kind: &'static str, name: String) {
+        self.created += 1;
+        self.items.push(ImportItem {
+            kind,
+            name,
+            action: ImportAction::Create,
+            reason: None,
+        });
+    }
+
+    fn skip(&mut self, kind: &'static str, name: String, reason: impl Into<String>) {
+        self.skipped += 1;
+        self.items.push(ImportItem {
+            kind,
+            name,
+            action: ImportAction::Skip,
+            reason: Some(reason.into()),
+        });
+    }
+}
+
+pub fn read_legacy_dump(path: &str) -> Result<LegacyDump> {
+    let content = std::fs::read_to_string(path).context("Failed to read the legacy dump")?;
+    // YAML is a superset of JSON, so both formats are accepted.
+    serde_yaml::from_str(&content).context("Failed to parse the legacy dump")
+}
+
+fn key_pair(key: &LegacyKey) -> Result<Sr25519Pair> {
+    match (&key.mnemonic, &key.secret_key) {
+        (Some(mnemonic), _) => Ok(Sr25519Pair::from_string(mnemonic, None)?),
+        (None, Some(secret_key)) => {
+            let bytes = hex::decode(secret_key.trim_start_matches("0x"))?;
+            let key = SecretKey::from_ed25519_bytes(&bytes).map_err(|e| anyhow!(e.to_string()))?;
+            Ok(Sr25519Pair::from(key))
+        }
+        (None, None) => Err(anyhow!("Neither mnemonic nor secretKey is given")),
+    }
+}
+
+/// Imports the legacy inventory, or only reports what would be imported if `dry_run` is set.
+pub fn import_legacy(
+    db: WrappedDb,
+    po_db: &DB,
+    dump: LegacyDump,
+    dry_run: bool,
+) -> Result<ImportReport> {
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut pids = HashSet::new();
+    let mut proxied_by_pid = HashMap::new();
+    for pool in dump.pools {
+        let name = format!("{} (pid {})", pool.name, pool.pid);
+        if pool.deleted {
+            report.skip("pool", name, "deleted");
+            continue;
+        }
+        let proxied = match &pool.real_phala_ss58 {
+            Some(s) if !s.is_empty() => match AccountId32::from_string(s) {
+                Ok(account) => Some(account),
+                Err(e) => {
+                    report.skip("pool", name, format!("bad realPhalaSs58: {e:?}"));
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        proxied_by_pid.insert(pool.pid, proxied);
+        pids.insert(pool.pid);
+        if get_pool_by_pid(db.clone(), pool.pid)?.is_some() {
+            report.skip("pool", name, "already exists");
+            continue;
+        }
+        if !dry_run {
+            inv_db::add_pool(
+                db.clone(),
+                ConfigCommands::AddPool {
+                    name: pool.name,
+                    pid: pool.pid,
+                    disabled: !pool.enabled,
+                    sync_only: pool.sync_only,
+                },
+            )?;
+        }
+        report.create("pool", name);
+    }
+
+    let mut names = HashSet::new();
+    for worker in dump.workers {
+        let name = worker.name.clone();
+        if worker.deleted {
+            report.skip("worker", name, "deleted");
+            continue;
+        }
+        if !pids.contains(&worker.pid) && get_pool_by_pid(db.clone(), worker.pid)?.is_none() {
+            report.skip("worker", name, format!("pool {} not found", worker.pid));
+            continue;
+        }
+        if !names.insert(name.clone()) || get_worker_by_name(db.clone(), name.clone())?.is_some() {
+            report.skip("worker", name, "already exists");
+            continue;
+        }
+        let stake = if worker.stake.is_empty() {
+            "0".to_string()
+        } else {
+            worker.stake
+        };
+        if let Err(e) = validate_bn_string(stake.clone()) {
+            report.skip("worker", name, format!("bad stake: {e}"));
+            continue;
+        }
+        if let Err(e) = validate_endpoint(worker.endpoint.clone()) {
+            report.skip("worker", name, format!("bad endpoint: {e}"));
+            continue;
+        }
+        if !dry_run {
+            inv_db::add_worker(
+                db.clone(),
+                ConfigCommands::AddWorker {
+                    name: worker.name,
+                    endpoint: worker.endpoint,
+                    stake,
+                    pid: worker.pid,
+                    disabled: !worker.enabled,
+                    sync_only: worker.sync_only,
+                    gatekeeper: false,
+                    proxy: None,
+                },
+            )?;
+        }
+        report.create("worker", name);
+    }
+
+    for key in dump.keys {
+        let name = format!("operator of pid {}", key.pid);
+        if po_db.get_po(key.pid)?.is_some() {
+            report.skip("pool_operator", name, "already exists");
+            continue;
+        }
+        let pair = match key_pair(&key) {
+            Ok(pair) => pair,
+            Err(e) => {
+                report.skip("pool_operator", name, format!("bad key: {e}"));
+                continue;
+            }
+        };
+        if !dry_run {
+            po_db.set_po(
+                key.pid,
+                PoolOperator {
+                    pid: key.pid,
+                    key: OperatorKey::Plain(pair),
+                    proxied: proxied_by_pid.get(&key.pid).cloned().flatten(),
+                },
+            )?;
+        }
+        report.create("pool_operator", name);
+    }
+
+    Ok(report)
+}
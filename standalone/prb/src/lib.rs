@@ -1,16 +1,44 @@
 pub mod api;
+pub mod api_auth;
+pub mod bootstrap;
 pub mod bus;
 pub mod cli;
+pub mod computation_samples;
 pub mod configurator;
 pub mod datasource;
+pub mod dead_letters;
+pub mod egress_poller;
+pub mod egress_sources;
+pub mod enclave_identity;
 pub mod headers_db;
 pub mod inv_db;
+pub mod jobs;
+pub mod k8s_discovery;
+pub mod key_provider;
+pub mod legacy_import;
+pub mod maintenance;
+pub mod message_metrics;
 pub mod messages;
+pub mod migration;
+pub mod notifications;
+pub mod offline_tx;
 pub mod pool_operator;
 pub mod processor;
+pub mod proxy;
 pub mod pruntime;
+pub mod public_api;
+pub mod reconciler;
 pub mod repository;
+pub mod request_trace;
+pub mod retry_policy;
+pub mod runtime_upgrade;
+pub mod shadow;
+pub mod support_bundle;
+pub mod sync_scheduler;
+pub mod term;
+pub mod top;
 pub mod tx;
+pub mod upgrade;
 pub mod utils;
 pub mod wm;
 pub mod worker;
@@ -0,0 +1,267 @@
+//! Maintenance windows of the pools.
+//!
+//! A maintenance window covers a planned interruption of a pool, e.g. upgrading its nodes. While
+//! the window is active, prb keeps syncing the workers of the pool and tracking their states, but:
+//!
+//! - the non-critical submissions (registering, updating endpoints, adding to the pool and
+//!   starting computing) are held back, offchain messages and stopping computing still go out;
+//! - the errors of the workers are logged as warnings instead of marking the workers as failed.
+//!
+//! When the window ends, the held submissions resume and the workers of the pool are reconciled:
+//! the ones which ran into errors during the window are restarted, the others resume their
+//! compute management.
+//!
+//! Windows are persisted in the pool operator db so that they survive restarts.
+
+use crate::bus::Bus;
+use crate::pool_operator::DB;
+use crate::processor::ProcessorEvent;
+use crate::tx::TxManager;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+static MAINTENANCE_KEY_PREFIX: &str = "maintenance:";
+
+const MAINTENANCE_LOOP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceWindow {
+    pub pid: u64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.start <= now && now < self.end
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceStatus {
+    #[serde(flatten)]
+    pub window: MaintenanceWindow,
+    pub active: bool,
+    /// Number of submissions held back since the window started.
+    pub held_submissions: u64,
+    /// Number of worker errors which did not mark the worker as failed since the window started.
+    pub suppressed_errors: u64,
+}
+
+struct Entry {
+    window: MaintenanceWindow,
+    started: bool,
+    held_submissions: u64,
+    suppressed_errors: u64,
+}
+
+impl Entry {
+    fn new(window: MaintenanceWindow) -> Self {
+        Self {
+            window,
+            started: false,
+            held_submissions: 0,
+            suppressed_errors: 0,
+        }
+    }
+
+    fn status(&self, now: DateTime<Utc>) -> MaintenanceStatus {
+        MaintenanceStatus {
+            window: self.window.clone(),
+            active: self.window.is_active(now),
+            held_submissions: self.held_submissions,
+            suppressed_errors: self.suppressed_errors,
+        }
+    }
+}
+
+/// The maintenance windows, at most one per pool.
+pub struct MaintenanceWindows {
+    db: Arc<DB>,
+    windows: Mutex<BTreeMap<u64, Entry>>,
+    notify: Notify,
+}
+
+impl MaintenanceWindows {
+    pub fn load(db: Arc<DB>) -> Result<Self> {
+        let mut windows = BTreeMap::new();
+        for item in db.prefix_iterator(MAINTENANCE_KEY_PREFIX) {
+            let (key, value) = item?;
+            if !key.starts_with(MAINTENANCE_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            let window: MaintenanceWindow = serde_json::from_slice(&value)?;
+            windows.insert(window.pid, Entry::new(window));
+        }
+        info!("Loaded {} maintenance windows.", windows.len());
+        Ok(Self {
+            db,
+            windows: Mutex::new(windows),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Adds the window of the pool, replacing the existing one.
+    pub fn set(&self, window: MaintenanceWindow) -> Result<()> {
+        if window.end <= window.start {
+            bail!("Maintenance window must end after it starts");
+        }
+        if window.end <= Utc::now() {
+            bail!("Maintenance window is already over");
+        }
+        self.db
+            .put(maintenance_db_key(window.pid), serde_json::to_vec(&window)?)?;
+        info!(
+            "Scheduled maintenance of pool #{} from {} to {}: {}",
+            window.pid,
+            window.start,
+            window.end,
+            window.reason.as_deref().unwrap_or("no reason given")
+        );
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(&window.pid) {
+            // Keep the counters of an ongoing window which is being extended.
+            Some(entry) => entry.window = window,
+            None => {
+                windows.insert(window.pid, Entry::new(window));
+            }
+        }
+        drop(windows);
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Ends the window of the pool now, or cancels it if it has not started yet. Returns false if
+    /// the pool has no window.
+    pub fn end_now(&self, pid: u64) -> Result<bool> {
+        let now = Utc::now();
+        let mut windows = self.windows.lock().unwrap();
+        let Some(entry) = windows.get_mut(&pid) else {
+            return Ok(false);
+        };
+        entry.window.start = entry.window.start.min(now);
+        entry.window.end = now;
+        self.db
+            .put(maintenance_db_key(pid), serde_json::to_vec(&entry.window)?)?;
+        drop(windows);
+        self.notify.notify_waiters();
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<MaintenanceStatus> {
+        let now = Utc::now();
+        self.windows
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.status(now))
+            .collect()
+    }
+
+    pub fn is_active(&self, pid: u64) -> bool {
+        let now = Utc::now();
+        self.windows
+            .lock()
+            .unwrap()
+            .get(&pid)
+            .map(|entry| entry.window.is_active(now))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the pool is under maintenance, counting the held submission.
+    pub fn hold(&self, pid: u64) -> bool {
+        self.count_if_active(pid, |entry| entry.held_submissions += 1)
+    }
+
+    /// Returns true if the pool is under maintenance, counting the suppressed error.
+    pub fn suppress(&self, pid: u64) -> bool {
+        self.count_if_active(pid, |entry| entry.suppressed_errors += 1)
+    }
+
+    fn count_if_active(&self, pid: u64, f: impl FnOnce(&mut Entry)) -> bool {
+        let now = Utc::now();
+        match self.windows.lock().unwrap().get_mut(&pid) {
+            Some(entry) if entry.window.is_active(now) => {
+                f(entry);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Waits until the pool is not under maintenance.
+    pub async fn wait(&self, pid: u64) {
+        loop {
+            let notified = self.notify.notified();
+            let now = Utc::now();
+            let end = match self.windows.lock().unwrap().get(&pid) {
+                Some(entry) if entry.window.is_active(now) => entry.window.end,
+                _ => return,
+            };
+            let remaining = (end - now)
+                .to_std()
+                .unwrap_or_default()
+                .min(MAINTENANCE_LOOP_INTERVAL);
+            let _ = tokio::time::timeout(remaining, notified).await;
+        }
+    }
+
+    /// Removes the windows which are over, returning the ones which had started.
+    fn take_ended(&self) -> Result<Vec<MaintenanceStatus>> {
+        let now = Utc::now();
+        let mut windows = self.windows.lock().unwrap();
+        for entry in windows.values_mut() {
+            if !entry.started && entry.window.is_active(now) {
+                entry.started = true;
+                warn!(
+                    "Maintenance of pool #{} started, until {}",
+                    entry.window.pid, entry.window.end
+                );
+            }
+        }
+        let ended = windows
+            .values()
+            .filter(|entry| entry.window.end <= now)
+            .map(|entry| entry.window.pid)
+            .collect::<Vec<_>>();
+        let mut ret = vec![];
+        for pid in ended {
+            self.db.delete(maintenance_db_key(pid))?;
+            let entry = windows.remove(&pid).expect("Window must exist");
+            if entry.started {
+                ret.push(entry.status(now));
+            } else {
+                info!("Maintenance of pool #{pid} cancelled before it started");
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn maintenance_db_key(pid: u64) -> String {
+    format!("{MAINTENANCE_KEY_PREFIX}{pid:020}")
+}
+
+/// Tracks the start and the end of the windows, reconciling the workers of the pools whose
+/// maintenance is over.
+pub async fn maintenance_loop(txm: Arc<TxManager>, bus: Arc<Bus>) -> Result<()> {
+    loop {
+        for status in txm.maintenance.take_ended()? {
+            info!(
+                "Maintenance of pool #{} ended, {} submissions were held and {} errors suppressed. Reconciling workers.",
+                status.window.pid, status.held_submissions, status.suppressed_errors
+            );
+            let _ = bus.send_processor_event(ProcessorEvent::MaintenanceEnded(status.window.pid));
+        }
+        let _ = tokio::time::timeout(MAINTENANCE_LOOP_INTERVAL, txm.maintenance.notify.notified())
+            .await;
+    }
+}
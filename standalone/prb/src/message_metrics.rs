@@ -0,0 +1,246 @@
+//! Prometheus metrics of the offchain message loop, exposed by `GET /metrics`.
+//!
+//! The counters cover the life of the messages: offered by the workers, filtered out as pending,
+//! completed or coming from a non-selected source, submitted, retried and timed out. The latency
+//! is the number of blocks from the submission to the inclusion. The per-sender gauges are
+//! refreshed at every best block, the `prb_messages_sender_stalled_blocks` of a sender growing
+//! with pending messages is the one to alert on.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds of the buckets of the submission latency, in blocks.
+const LATENCY_BUCKETS: [u64; 8] = [1, 2, 3, 5, 10, 20, 50, 100];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: u64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The gauges of a sender, refreshed at every best block.
+pub struct SenderGauges {
+    pub worker_id: String,
+    /// Messages tracked and not confirmed on chain yet.
+    pub pending: u64,
+    pub next_sequence: u64,
+    /// Blocks since the on-chain next sequence last advanced, 0 while nothing is pending or before
+    /// the sender is seen advancing.
+    pub stalled_blocks: u32,
+}
+
+#[derive(Default)]
+pub struct MessageMetrics {
+    received: AtomicU64,
+    filtered: AtomicU64,
+    submitted: AtomicU64,
+    retried: AtomicU64,
+    timed_out: AtomicU64,
+    unrecoverable: AtomicU64,
+    latency: Histogram,
+    senders: Mutex<BTreeMap<String, SenderGauges>>,
+}
+
+impl MessageMetrics {
+    pub fn record_received(&self, count: usize) {
+        self.received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_filtered(&self, count: usize) {
+        self.filtered.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_unrecoverable(&self) {
+        self.unrecoverable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_included(&self, latency_in_blocks: u32) {
+        self.latency.observe(latency_in_blocks as u64);
+    }
+
+    /// Replaces the gauges of all the senders, keyed by the sender as displayed.
+    pub fn set_senders(&self, senders: BTreeMap<String, SenderGauges>) {
+        *self.senders.lock().unwrap() = senders;
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "received",
+                "Offchain messages offered by the workers.",
+                &self.received,
+            ),
+            (
+                "filtered",
+                "Offchain messages dropped as pending, completed or from a non-selected source.",
+                &self.filtered,
+            ),
+            (
+                "submitted",
+                "Offchain message submissions, including the retries.",
+                &self.submitted,
+            ),
+            (
+                "retried",
+                "Offchain message submissions retrying a failed or timed out one.",
+                &self.retried,
+            ),
+            (
+                "timed_out",
+                "Offchain message submissions without result in time.",
+                &self.timed_out,
+            ),
+            (
+                "unrecoverable",
+                "Offchain messages parked as unrecoverable.",
+                &self.unrecoverable,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP prb_messages_{name}_total {help}");
+            let _ = writeln!(out, "# TYPE prb_messages_{name}_total counter");
+            let _ = writeln!(
+                out,
+                "prb_messages_{name}_total {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let name = "prb_messages_submission_latency_blocks";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Blocks from the submission of an offchain message to its inclusion."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency.buckets) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.latency.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "{name}_sum {}",
+            self.latency.sum.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "{name}_count {count}");
+
+        let senders = self.senders.lock().unwrap();
+        let gauges: [(&str, &str, fn(&SenderGauges) -> u64); 3] = [
+            (
+                "pending",
+                "Offchain messages of the sender not confirmed on chain yet.",
+                |g| g.pending,
+            ),
+            (
+                "next_sequence",
+                "The on-chain next sequence of the sender.",
+                |g| g.next_sequence,
+            ),
+            (
+                "stalled_blocks",
+                "Blocks since the on-chain next sequence of the sender advanced.",
+                |g| g.stalled_blocks as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP prb_messages_sender_{name} {help}");
+            let _ = writeln!(out, "# TYPE prb_messages_sender_{name} gauge");
+            for (sender, g) in senders.iter() {
+                let _ = writeln!(
+                    out,
+                    "prb_messages_sender_{name}{{sender=\"{}\",worker_id=\"{}\"}} {}",
+                    escape_label(sender),
+                    escape_label(&g.worker_id),
+                    value(g)
+                );
+            }
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposes_counters_histogram_and_gauges() {
+        let metrics = MessageMetrics::default();
+        metrics.record_received(3);
+        metrics.record_submitted();
+        metrics.record_included(2);
+        metrics.record_included(30);
+        metrics.set_senders(BTreeMap::from([(
+            "Worker(\"0x01\")".to_string(),
+            SenderGauges {
+                worker_id: "w1".into(),
+                pending: 2,
+                next_sequence: 7,
+                stalled_blocks: 12,
+            },
+        )]));
+
+        let out = metrics.render();
+        let lines: Vec<_> = out.lines().collect();
+        for expected in [
+            "# TYPE prb_messages_received_total counter",
+            "prb_messages_received_total 3",
+            "prb_messages_submitted_total 1",
+            "prb_messages_retried_total 0",
+            "# TYPE prb_messages_submission_latency_blocks histogram",
+            "prb_messages_submission_latency_blocks_bucket{le=\"1\"} 0",
+            "prb_messages_submission_latency_blocks_bucket{le=\"2\"} 1",
+            "prb_messages_submission_latency_blocks_bucket{le=\"20\"} 1",
+            "prb_messages_submission_latency_blocks_bucket{le=\"50\"} 2",
+            "prb_messages_submission_latency_blocks_bucket{le=\"+Inf\"} 2",
+            "prb_messages_submission_latency_blocks_sum 32",
+            "prb_messages_submission_latency_blocks_count 2",
+            "# TYPE prb_messages_sender_pending gauge",
+            r#"prb_messages_sender_pending{sender="Worker(\"0x01\")",worker_id="w1"} 2"#,
+            r#"prb_messages_sender_next_sequence{sender="Worker(\"0x01\")",worker_id="w1"} 7"#,
+            r#"prb_messages_sender_stalled_blocks{sender="Worker(\"0x01\")",worker_id="w1"} 12"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {expected} in\n{out}");
+        }
+    }
+}
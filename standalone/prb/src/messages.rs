@@ -1,34 +1,170 @@
 use crate::bus::Bus;
-use crate::datasource::DataSourceManager;
+use crate::dead_letters::{DeadLetterAction, DeadLetters};
+use crate::message_metrics::{MessageMetrics, SenderGauges};
+use crate::datasource::{DataSourceError::NoValidDataSource, DataSourceManager};
+use crate::egress_sources::{EgressSources, SourceDecision, SourceHealth};
+use crate::migration::{ArchivedSender, DeadLetter};
+use crate::notifications::{Notification, Notifier};
+use crate::retry_policy::{RetryPolicy, RetryRule};
 use crate::tx::TxManager;
 use crate::use_parachain_api;
-use anyhow::Result;
-use futures::StreamExt;
+use anyhow::{Context, Result};
 use log::{debug, error, info, trace, warn};
-use phala_types::messaging::{MessageOrigin, SignedMessage};
-use std::collections::{hash_map::Entry::{Occupied, Vacant}, HashMap};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::mpsc;
+use phala_types::messaging::{MessageOrigin, MessageReceipts, SignedMessage};
+use phaxt::{ChainApi, ChainError, FinalityStream, HeadUpdate};
+use pherry::types::Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry::{Occupied, Vacant}, BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 
-const TX_TIMEOUT_IN_BLOCKS: u32 = 6;
+/// The parachain block time when the chain is healthy.
+const EXPECTED_BLOCK_SECS: f64 = 12.0;
+const MAX_TIMEOUT_COMPENSATION: f64 = 10.0;
+const HEIGHT_SAMPLES: usize = 20;
+/// Number of recent submission errors kept per sender, for the stall notification.
+const MAX_LAST_ERRORS: usize = 5;
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tracks the wall-clock rate of parachain height updates, to widen the timeouts when the block
+/// production slows down or the height subscription goes stale.
+#[derive(Default)]
+pub struct HeightTracker {
+    samples: Mutex<VecDeque<(Instant, u32)>>,
+}
+
+impl HeightTracker {
+    pub fn update(&self, height: u32) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.back().map(|(_, h)| *h >= height).unwrap_or(false) {
+            return;
+        }
+        samples.push_back((Instant::now(), height));
+        if samples.len() > HEIGHT_SAMPLES {
+            samples.pop_front();
+        }
+    }
+
+    /// The average seconds per block in the recent window.
+    fn average_block_secs(samples: &VecDeque<(Instant, u32)>) -> f64 {
+        match (samples.front(), samples.back()) {
+            (Some(first), Some(last)) if last.1 > first.1 => {
+                (last.0 - first.0).as_secs_f64() / (last.1 - first.1) as f64
+            },
+            _ => EXPECTED_BLOCK_SECS,
+        }
+    }
+
+    /// The observed seconds per block in the recent window, or the time since the last update if
+    /// it is longer.
+    pub fn observed_block_secs(&self) -> f64 {
+        let samples = self.samples.lock().unwrap();
+        let Some(last) = samples.back() else {
+            return EXPECTED_BLOCK_SECS;
+        };
+        Self::average_block_secs(&samples).max(last.0.elapsed().as_secs_f64())
+    }
+
+    /// The first time at `offset` after the import of a best block which is not passed yet,
+    /// extrapolated with the average block interval.
+    ///
+    /// `None` if no block was seen yet or the height updates are stale, in which case there is no
+    /// block phase to align to.
+    pub fn next_submission_slot(&self, offset: Duration) -> Option<Instant> {
+        let samples = self.samples.lock().unwrap();
+        let (last_at, _) = *samples.back()?;
+        let interval = Duration::from_secs_f64(Self::average_block_secs(&samples));
+        let now = Instant::now();
+        if now.duration_since(last_at) > interval * 2 {
+            return None;
+        }
+        let mut slot = last_at + offset.min(interval);
+        while slot < now {
+            slot += interval;
+        }
+        Some(slot)
+    }
+
+    /// How much the timeouts are widened, 1.0 when the chain is healthy.
+    pub fn compensation_factor(&self) -> f64 {
+        (self.observed_block_secs() / EXPECTED_BLOCK_SECS).clamp(1.0, MAX_TIMEOUT_COMPENSATION)
+    }
+
+    pub fn scale_blocks(&self, blocks: u32) -> u32 {
+        (blocks as f64 * self.compensation_factor()).ceil() as u32
+    }
+
+    pub fn scale_duration(&self, duration: Duration) -> Duration {
+        duration.mul_f64(self.compensation_factor())
+    }
+}
 
 pub enum MessagesEvent {
-    SyncMessages((String, u64, MessageOrigin, Vec<SignedMessage>)),
+    SyncMessages((String, u64, MessageOrigin, Vec<SignedMessage>, SourceHealth)),
     DoSyncMessages((String, u64, MessageOrigin, Vec<SignedMessage>, Option<u64>)),
-    Completed((String, MessageOrigin, u64, Result<()>)),
+    /// The result of submitting a message, the number of the block including it on success.
+    Completed((String, MessageOrigin, u64, Result<u32>)),
     RemoveSender(MessageOrigin),
     CurrentHeight(u32),
+    /// The on-chain next sequence of the sender, `None` if it could not be fetched.
+    Confirmed((MessageOrigin, Option<u64>)),
+    /// The receipts emitted by pallet-mq in the best block, `None` if the receipts are disabled on
+    /// chain.
+    Receipts(Option<MessageReceipts>),
+    /// Dumps the sender contexts, used by the support bundle.
+    Snapshot(oneshot::Sender<Vec<SenderSnapshot>>),
+    /// Dumps the sender contexts with their origins, used by the migration archive.
+    Export(oneshot::Sender<Vec<ArchivedSender>>),
+    /// Restores the sender contexts of a migration archive.
+    Import((BTreeMap<MessageOrigin, ImportedSender>, oneshot::Sender<Vec<SenderImport>>)),
+    /// Submits a parked message again or forgets it, replies whether it was parked.
+    ReleaseDeadLetter((MessageOrigin, u64, DeadLetterAction, oneshot::Sender<bool>)),
+    /// Corrects the sender contexts with the state re-derived by the reconciler.
+    Reconcile((BTreeMap<MessageOrigin, SenderTruth>, oneshot::Sender<Vec<(MessageOrigin, SenderCorrections)>>)),
+    FinalizedHeight((u32, Hash)),
+    /// The next sequence of the sender at the finalized block of the given height, `None` if it
+    /// could not be fetched.
+    Finalized((MessageOrigin, u32, Option<u64>)),
 }
 
 pub type MessagesRx = mpsc::UnboundedReceiver<MessagesEvent>;
 pub type MessagesTx = mpsc::UnboundedSender<MessagesEvent>;
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MessageState {
     Pending,
+    /// The extrinsic was included at the given height, but the on-chain sequence of the sender has
+    /// not advanced past the message yet.
+    Included(u32),
+    /// The on-chain sequence of the sender has advanced past the message.
     Successful,
     Failure,
     Timeout,
+    /// The message was rejected with the given error, which would be hit again by any retry.
+    Unrecoverable(String),
+}
+
+/// Whether the error of submitting an offchain message is a module error that the message hits
+/// whenever it is submitted, e.g. a signature the chain does not accept.
+///
+/// Errors depending on on-chain state that may change later, e.g. an unknown contract or cluster,
+/// are not included.
+fn is_unrecoverable(err: &ChainError) -> bool {
+    match err {
+        ChainError::Module { pallet, variant } => matches!(
+            (pallet.as_str(), variant.as_str()),
+            ("PhalaMq", "BadSender" | "BadDestination")
+                | (
+                    "PhalaRegistry",
+                    "CannotHandleUnknownMessage"
+                        | "InvalidSignatureLength"
+                        | "MalformedSignature"
+                        | "InvalidSignature"
+                )
+        ),
+        _ => false,
+    }
 }
 
 pub struct MessageContext {
@@ -37,10 +173,33 @@ pub struct MessageContext {
     state: MessageState,
     submitted_at: u32,
     prev_try_count: usize,
+    /// The best height at which the message was confirmed, it is final only once a finalized
+    /// block at or above it confirms it as well.
+    confirmed_at: Option<u32>,
+    /// The height at which the last submission was reported as failed.
+    failed_at: Option<u32>,
+    retry: Arc<RetryRule>,
 }
 
 impl MessageContext {
-    pub fn is_pending(&self, current_height: u32) -> bool {
+    /// The height of the last change of the message.
+    fn last_active_at(&self) -> u32 {
+        match self.state {
+            MessageState::Included(included_at) => included_at,
+            _ => self.confirmed_at.unwrap_or(self.submitted_at),
+        }
+    }
+
+    /// The height at which the message was seen on chain, either included or confirmed.
+    fn settled_at(&self) -> Option<u32> {
+        match self.state {
+            MessageState::Included(included_at) => Some(included_at),
+            MessageState::Successful => self.confirmed_at,
+            _ => None,
+        }
+    }
+
+    pub fn is_pending(&self, current_height: u32, timeout_in_blocks: u32) -> bool {
         if matches!(self.state, MessageState::Timeout) {
             if current_height <= self.submitted_at {
                 trace!("[{} #{}] Message was marked as timeout, but current H#{} <= {}, still treated as pending",
@@ -50,13 +209,13 @@ impl MessageContext {
                     self.submitted_at,
                 );
                 return true;
-            } else if current_height.saturating_sub(self.submitted_at) <= TX_TIMEOUT_IN_BLOCKS {
+            } else if current_height.saturating_sub(self.submitted_at) <= timeout_in_blocks {
                 trace!("[{} #{}] Message was marked as timeout, but current H#{} - {} <= {}, wait a little more time to allow potential success.",
                     self.sender,
                     self.sequence,
                     current_height,
                     self.submitted_at,
-                    TX_TIMEOUT_IN_BLOCKS,
+                    timeout_in_blocks,
                 );
                 return true;
             } else {
@@ -65,18 +224,30 @@ impl MessageContext {
                     self.sequence,
                     current_height,
                     self.submitted_at,
-                    TX_TIMEOUT_IN_BLOCKS,
+                    timeout_in_blocks,
+                );
+                return false;
+            }
+        } else if let MessageState::Included(included_at) = self.state {
+            if current_height.saturating_sub(included_at) > timeout_in_blocks {
+                trace!("[{} #{}] Message was included at H#{} but not confirmed at H#{}, treat as failure.",
+                    self.sender,
+                    self.sequence,
+                    included_at,
+                    current_height,
                 );
                 return false;
+            } else {
+                return true;
             }
         } else if matches!(self.state, MessageState::Pending) {
-            if current_height > self.submitted_at && current_height.saturating_sub(self.submitted_at) > TX_TIMEOUT_IN_BLOCKS {
+            if current_height > self.submitted_at && current_height.saturating_sub(self.submitted_at) > timeout_in_blocks {
                 trace!("[{} #{}] Message is still pending, but H#{} - {} > {}, treat as timeout.",
                     self.sender,
                     self.sequence,
                     current_height,
                     self.submitted_at,
-                    TX_TIMEOUT_IN_BLOCKS,
+                    timeout_in_blocks,
                 );
                 return false;
             } else {
@@ -86,27 +257,562 @@ impl MessageContext {
         false
     }
 
-    pub fn is_pending_or_success(&self, current_height: u32) -> bool {
-        self.is_pending(current_height) || matches!(self.state, MessageState::Successful)
+    /// The height from which the failed message can be submitted again, after the backoff of its
+    /// retry rule. A submission timed out without result failed once the timeout elapsed.
+    fn retry_at(&self, timeout_in_blocks: u32) -> u32 {
+        let failed_at = self
+            .failed_at
+            .unwrap_or_else(|| self.last_active_at().saturating_add(timeout_in_blocks));
+        failed_at.saturating_add(self.retry.backoff(self.prev_try_count + 1))
+    }
+
+    /// The dead letter of the message, if it is parked as unrecoverable.
+    fn dead_letter(&self, worker_id: &str) -> Option<DeadLetter> {
+        match &self.state {
+            MessageState::Unrecoverable(error) => Some(DeadLetter {
+                origin: self.sender.clone(),
+                worker_id: worker_id.to_string(),
+                sequence: self.sequence,
+                error: error.clone(),
+                submitted_at: self.submitted_at,
+                prev_try_count: self.prev_try_count,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn is_pending_or_success(&self, current_height: u32, timeout_in_blocks: u32) -> bool {
+        self.is_pending(current_height, timeout_in_blocks) || matches!(self.state, MessageState::Successful)
+    }
+
+    pub fn is_timeout_or_failure(&self, current_height: u32, timeout_in_blocks: u32) -> bool {
+        !self.is_pending_or_success(current_height, timeout_in_blocks)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PausedTopic {
+    pub topic: String,
+    /// Number of times a message to the topic was held back since it was paused.
+    pub held_messages: u64,
+}
+
+/// Runtime switches to stop submitting offchain messages of some topics, e.g. to shed low value
+/// traffic during chain congestion.
+///
+/// Messages of a sender must be submitted in sequence, so a message to a paused topic also holds
+/// back the following messages of the same sender until the topic is resumed.
+#[derive(Default)]
+pub struct TopicToggles {
+    paused: Mutex<BTreeMap<String, u64>>,
+}
+
+impl TopicToggles {
+    pub fn new(paused_topics: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            paused: Mutex::new(paused_topics.into_iter().map(|topic| (topic, 0)).collect()),
+        }
+    }
+
+    pub fn set_paused(&self, topic: String, paused: bool) {
+        let mut map = self.paused.lock().unwrap();
+        if paused {
+            if !map.contains_key(&topic) {
+                warn!("Pausing offchain messages to topic {}", topic);
+                map.insert(topic, 0);
+            }
+        } else if let Some(held) = map.remove(&topic) {
+            warn!("Resuming offchain messages to topic {}, {} submissions were held", topic, held);
+        }
+    }
+
+    pub fn paused_topics(&self) -> Vec<PausedTopic> {
+        self.paused
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(topic, held)| PausedTopic { topic: topic.clone(), held_messages: *held })
+            .collect()
     }
 
-    pub fn is_timeout_or_failure(&self, current_height: u32) -> bool {
-        !self.is_pending_or_success(current_height)
+    /// Returns true if the topic is paused, counting the held message.
+    fn hold(&self, topic: &str) -> bool {
+        match self.paused.lock().unwrap().get_mut(topic) {
+            Some(held) => {
+                *held += 1;
+                true
+            },
+            None => false,
+        }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageSnapshot {
+    pub sequence: u64,
+    pub state: MessageState,
+    pub submitted_at: u32,
+    pub prev_try_count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SenderSnapshot {
+    pub sender: String,
+    pub worker_id: String,
+    pub node_next_sequence: u64,
+    pub confirming: bool,
+    pub pending_messages: Vec<MessageSnapshot>,
+    pub stalled: bool,
+    pub last_errors: Vec<String>,
+    /// Number of messages dropped from memory by the garbage collection.
+    pub pruned_messages: u64,
+    /// The worker whose egress of the sender is forwarded.
+    #[serde(default)]
+    pub egress_source: Option<String>,
+    #[serde(default)]
+    pub source_decisions: Vec<SourceDecision>,
+}
+
+/// Takes the snapshots of the sender contexts from the message loop, sorted by sender.
+pub async fn snapshot_senders(bus: &Bus) -> Result<Vec<SenderSnapshot>> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    bus.send_messages_event(MessagesEvent::Snapshot(reply_tx))
+        .map_err(|_| anyhow::anyhow!("message loop is not running"))?;
+    let senders = tokio::time::timeout(SNAPSHOT_TIMEOUT, reply_rx)
+        .await
+        .context("timed out waiting for the message loop")?
+        .context("message loop dropped the snapshot request")?;
+    Ok(senders)
+}
+
+/// Takes the sender contexts with their origins from the message loop, sorted by sender.
+pub async fn export_senders(bus: &Bus) -> Result<Vec<ArchivedSender>> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    bus.send_messages_event(MessagesEvent::Export(reply_tx))
+        .map_err(|_| anyhow::anyhow!("message loop is not running"))?;
+    let senders = tokio::time::timeout(SNAPSHOT_TIMEOUT, reply_rx)
+        .await
+        .context("timed out waiting for the message loop")?
+        .context("message loop dropped the export request")?;
+    Ok(senders)
+}
+
+/// A sender context carried over from another prb.
+pub struct ImportedSender {
+    pub worker_id: String,
+    /// The on-chain next sequence of the sender at the import.
+    pub chain_next_sequence: u64,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+/// How the parked messages of an imported sender were reconciled.
+#[derive(Debug, Default)]
+pub struct SenderImport {
+    /// The sender was already known, its live state was kept.
+    pub live: bool,
+    pub restored: usize,
+    /// Parked messages below the next sequence, which took effect some other way.
+    pub resolved: usize,
+    /// Parked messages the live state of the sender already tracks.
+    pub conflicting: usize,
+}
+
+/// Restores the sender contexts of a migration archive in the message loop.
+pub async fn import_senders(
+    bus: &Bus,
+    senders: BTreeMap<MessageOrigin, ImportedSender>,
+) -> Result<Vec<SenderImport>> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    bus.send_messages_event(MessagesEvent::Import((senders, reply_tx)))
+        .map_err(|_| anyhow::anyhow!("message loop is not running"))?;
+    let outcomes = tokio::time::timeout(SNAPSHOT_TIMEOUT, reply_rx)
+        .await
+        .context("timed out waiting for the message loop")?
+        .context("message loop dropped the import request")?;
+    Ok(outcomes)
+}
+
+/// The state of a sender re-derived from the chain and its worker by the reconciler.
+pub struct SenderTruth {
+    /// The on-chain next sequence of the sender at the best block.
+    pub chain_next_sequence: u64,
+    /// The next sequence in the egress queue of the worker, `None` if the worker is unreachable.
+    /// Only used for the senders of a single worker.
+    pub worker_next_sequence: Option<u64>,
+}
+
+/// The drift of a sender context corrected by the reconciler.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SenderCorrections {
+    /// The next sequence was behind the chain.
+    pub next_sequence: bool,
+    /// Messages below the on-chain next sequence which were not seen on chain, marked as included
+    /// to be confirmed and finalized as usual.
+    pub confirmed: usize,
+    /// Messages still pending long after their submission, whose completion was lost.
+    pub lost: usize,
+    /// Messages not in flight beyond the egress queue of the worker, which it will never resend.
+    /// Only counted for the senders of a single worker.
+    pub phantom: usize,
+}
+
+impl SenderCorrections {
+    pub fn total(&self) -> usize {
+        self.next_sequence as usize + self.confirmed + self.lost + self.phantom
+    }
+}
+
+/// Submits the parked message again or forgets it in the message loop, and drops its dead
+/// letter. Returns false if the message was not parked.
+pub async fn release_dead_letter(
+    bus: &Bus,
+    sender: MessageOrigin,
+    sequence: u64,
+    action: DeadLetterAction,
+) -> Result<bool> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    bus.send_messages_event(MessagesEvent::ReleaseDeadLetter((sender, sequence, action, reply_tx)))
+        .map_err(|_| anyhow::anyhow!("message loop is not running"))?;
+    let released = tokio::time::timeout(SNAPSHOT_TIMEOUT, reply_rx)
+        .await
+        .context("timed out waiting for the message loop")?
+        .context("message loop dropped the release request")?;
+    Ok(released)
+}
+
+/// Corrects the sender contexts in the message loop, returns the corrections of each sender.
+pub async fn reconcile_senders(
+    bus: &Bus,
+    truths: BTreeMap<MessageOrigin, SenderTruth>,
+) -> Result<Vec<(MessageOrigin, SenderCorrections)>> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    bus.send_messages_event(MessagesEvent::Reconcile((truths, reply_tx)))
+        .map_err(|_| anyhow::anyhow!("message loop is not running"))?;
+    let corrections = tokio::time::timeout(SNAPSHOT_TIMEOUT, reply_rx)
+        .await
+        .context("timed out waiting for the message loop")?
+        .context("message loop dropped the reconcile request")?;
+    Ok(corrections)
+}
+
 pub struct SenderContext {
     // sender: MessageOrigin,
+    worker_id: String,
     node_next_sequence: u64,
     pending_messages: HashMap<u64, MessageContext>,
+    confirming: bool,
+    reconciling: bool,
+    /// The height at which `node_next_sequence` last advanced, 0 if unknown yet.
+    advanced_at: u32,
+    last_errors: VecDeque<String>,
+    /// The height at which the stall of the sender was notified.
+    stall_notified_at: Option<u32>,
+    pruned_messages: u64,
+    /// The retry rule of the messages of the sender.
+    retry: Arc<RetryRule>,
 }
 
 impl SenderContext {
-    pub fn calculate_next_sequence(&self, current_height: u32) -> u64 {
+    fn new(worker_id: String, node_next_sequence: u64, current_height: u32, retry: Arc<RetryRule>) -> Self {
+        Self {
+            worker_id,
+            node_next_sequence,
+            pending_messages: HashMap::new(),
+            confirming: false,
+            reconciling: false,
+            advanced_at: current_height,
+            last_errors: VecDeque::new(),
+            stall_notified_at: None,
+            pruned_messages: 0,
+            retry,
+        }
+    }
+
+    /// Updates the states of the messages with the on-chain next sequence of the sender.
+    ///
+    /// Messages below it took effect on chain and are marked as successful, even if their
+    /// submissions were reported as failed or timed out. Returns the sequences of the messages
+    /// which were included long enough ago but did not advance the sequence.
+    pub fn confirm(&mut self, chain_next_sequence: u64, current_height: u32, timeout_in_blocks: u32) -> Vec<u64> {
+        if chain_next_sequence > self.node_next_sequence {
+            self.advanced_at = current_height;
+        }
+        self.node_next_sequence = chain_next_sequence;
+        let mut no_op = vec![];
+        for (sequence, ctx) in self.pending_messages.iter_mut() {
+            if *sequence < chain_next_sequence {
+                if !matches!(ctx.state, MessageState::Successful) {
+                    trace!("[{}] Msg#{} confirmed on chain.", ctx.sender, sequence);
+                    ctx.state = MessageState::Successful;
+                    ctx.confirmed_at = Some(current_height);
+                }
+            } else if let MessageState::Included(included_at) = ctx.state {
+                if current_height.saturating_sub(included_at) > timeout_in_blocks {
+                    ctx.state = MessageState::Failure;
+                    no_op.push(*sequence);
+                }
+            }
+        }
+        no_op.sort();
+        no_op
+    }
+
+    /// Reconciles the messages with the next sequence of the sender at the finalized block of
+    /// `finalized_height`.
+    ///
+    /// Messages below it are final and dropped. The others which were included or confirmed at
+    /// or below the finalized height were in blocks orphaned by a fork, or did not take effect,
+    /// so they are marked as failed to be submitted again. Returns the sequences of them.
+    pub fn reconcile_finalized(&mut self, finalized_next_sequence: u64, finalized_height: u32) -> Vec<u64> {
+        self.pending_messages.retain(|sequence, _| *sequence >= finalized_next_sequence);
+        let mut orphaned = vec![];
+        for (sequence, ctx) in self.pending_messages.iter_mut() {
+            if ctx.settled_at().map(|at| at <= finalized_height).unwrap_or(false) {
+                trace!("[{}] Msg#{} is not on the finalized chain.", ctx.sender, sequence);
+                ctx.state = MessageState::Failure;
+                ctx.confirmed_at = None;
+                orphaned.push(*sequence);
+            }
+        }
+        if orphaned.is_empty() {
+            self.node_next_sequence = self.node_next_sequence.max(finalized_next_sequence);
+        } else {
+            // The best chain we confirmed against was dropped.
+            self.node_next_sequence = finalized_next_sequence;
+        }
+        orphaned.sort();
+        orphaned
+    }
+
+    /// Drops the messages below the on-chain next sequence which have not changed since `cutoff`.
+    /// Returns the number of them.
+    fn collect_garbage(&mut self, cutoff: u32) -> usize {
+        let next_sequence = self.node_next_sequence;
+        let before = self.pending_messages.len();
+        self.pending_messages
+            .retain(|sequence, ctx| *sequence >= next_sequence || ctx.last_active_at() >= cutoff);
+        let pruned = before - self.pending_messages.len();
+        self.pruned_messages += pruned as u64;
+        pruned
+    }
+
+    /// Whether some messages were seen on chain at or below the finalized height.
+    fn has_unfinalized(&self, finalized_height: u32) -> bool {
+        self.pending_messages
+            .values()
+            .any(|ctx| ctx.settled_at().map(|at| at <= finalized_height).unwrap_or(false))
+    }
+
+    fn has_unconfirmed(&self) -> bool {
+        self.pending_messages
+            .values()
+            .any(|ctx| matches!(ctx.state, MessageState::Included(_)))
+    }
+
+    /// Whether a message was included long enough ago but not acknowledged by any receipt, which
+    /// needs polling the on-chain sequence to tell if it took effect.
+    fn has_overdue(&self, current_height: u32, timeout_in_blocks: u32) -> bool {
+        self.pending_messages.values().any(|ctx| match ctx.state {
+            MessageState::Included(included_at) => current_height.saturating_sub(included_at) > timeout_in_blocks,
+            _ => false,
+        })
+    }
+
+    fn record_error(&mut self, err: String) {
+        if self.last_errors.len() >= MAX_LAST_ERRORS {
+            self.last_errors.pop_front();
+        }
+        self.last_errors.push_back(err);
+    }
+
+    /// The gauges of the sender, it is not stalled while nothing is pending.
+    fn gauges(&self, current_height: u32) -> SenderGauges {
+        let pending = self.pending_messages
+            .values()
+            .filter(|ctx| !matches!(ctx.state, MessageState::Successful))
+            .count() as u64;
+        let stalled_blocks = if pending == 0 || self.advanced_at == 0 {
+            0
+        } else {
+            current_height.saturating_sub(self.advanced_at)
+        };
+        SenderGauges {
+            worker_id: self.worker_id.clone(),
+            pending,
+            next_sequence: self.node_next_sequence,
+            stalled_blocks,
+        }
+    }
+
+    /// Whether the on-chain next sequence has not advanced for `alert_blocks` blocks, while the
+    /// message at it has been submitted more than once.
+    fn is_stalled(&self, current_height: u32, alert_blocks: u32) -> bool {
+        self.advanced_at != 0
+            && current_height.saturating_sub(self.advanced_at) >= alert_blocks
+            && self.pending_messages
+                .get(&self.node_next_sequence)
+                .map(|ctx| ctx.prev_try_count > 0)
+                .unwrap_or(false)
+    }
+
+    /// Notifies once when the sender stalls, and once when it advances again.
+    fn check_stall(
+        &mut self,
+        sender: &MessageOrigin,
+        current_height: u32,
+        alert_blocks: u32,
+        bus: &Bus,
+        notifier: &Notifier,
+    ) {
+        if self.advanced_at == 0 {
+            self.advanced_at = current_height;
+        }
+        match self.stall_notified_at {
+            None if self.is_stalled(current_height, alert_blocks) => {
+                self.stall_notified_at = Some(current_height);
+                let stalled_blocks = current_height.saturating_sub(self.advanced_at);
+                error!("[{}] next sequence #{} has not advanced for {} blocks, last errors: {:?}",
+                    sender, self.node_next_sequence, stalled_blocks, self.last_errors);
+                let _ = bus.send_worker_update_message(
+                    self.worker_id.clone(),
+                    format!("Offchain messages are stuck at #{} for {} blocks.", self.node_next_sequence, stalled_blocks)
+                );
+                notifier.notify(Notification::SenderStalled {
+                    sender: sender.to_string(),
+                    worker_id: self.worker_id.clone(),
+                    stuck_sequence: self.node_next_sequence,
+                    stalled_blocks,
+                    pending_messages: self.pending_messages.len(),
+                    last_errors: self.last_errors.iter().cloned().collect(),
+                });
+            },
+            Some(notified_at) if self.advanced_at >= notified_at => {
+                self.stall_notified_at = None;
+                info!("[{}] next sequence advanced to #{} after the stall", sender, self.node_next_sequence);
+                notifier.notify(Notification::SenderRecovered {
+                    sender: sender.to_string(),
+                    worker_id: self.worker_id.clone(),
+                    next_sequence: self.node_next_sequence,
+                });
+            },
+            _ => {},
+        }
+    }
+
+    pub fn snapshot(&self, sender: &MessageOrigin, sources: Option<&EgressSources>) -> SenderSnapshot {
+        let mut pending_messages = self.pending_messages
+            .values()
+            .map(|ctx| MessageSnapshot {
+                sequence: ctx.sequence,
+                state: ctx.state.clone(),
+                submitted_at: ctx.submitted_at,
+                prev_try_count: ctx.prev_try_count,
+            })
+            .collect::<Vec<_>>();
+        pending_messages.sort_by_key(|m| m.sequence);
+        SenderSnapshot {
+            sender: sender.to_string(),
+            worker_id: self.worker_id.clone(),
+            node_next_sequence: self.node_next_sequence,
+            confirming: self.confirming,
+            pending_messages,
+            stalled: self.stall_notified_at.is_some(),
+            last_errors: self.last_errors.iter().cloned().collect(),
+            pruned_messages: self.pruned_messages,
+            egress_source: sources.and_then(|s| s.selected()).map(Into::into),
+            source_decisions: sources.map(|s| s.decisions()).unwrap_or_default(),
+        }
+    }
+
+    /// Parks the dead letters of a migration archive or of the dead-letter queue as unrecoverable,
+    /// unless they are below the next sequence or already tracked.
+    fn import_dead_letters(
+        &mut self,
+        sender: &MessageOrigin,
+        chain_next_sequence: u64,
+        dead_letters: Vec<DeadLetter>,
+        queue: &DeadLetters,
+    ) -> SenderImport {
+        let next_sequence = self.node_next_sequence.max(chain_next_sequence);
+        let mut outcome = SenderImport::default();
+        for letter in dead_letters {
+            if letter.sequence < next_sequence {
+                outcome.resolved += 1;
+                continue;
+            }
+            match self.pending_messages.entry(letter.sequence) {
+                Occupied(_) => outcome.conflicting += 1,
+                Vacant(entry) => {
+                    queue.park(letter.clone());
+                    entry.insert(MessageContext {
+                        sender: sender.clone(),
+                        sequence: letter.sequence,
+                        state: MessageState::Unrecoverable(letter.error),
+                        submitted_at: letter.submitted_at,
+                        prev_try_count: letter.prev_try_count,
+                        confirmed_at: None,
+                        failed_at: None,
+                        retry: self.retry.clone(),
+                    });
+                    outcome.restored += 1;
+                },
+            }
+        }
+        queue.resolve(sender, next_sequence);
+        outcome
+    }
+
+    /// Corrects the drift from the state re-derived from the chain and the worker.
+    ///
+    /// Only a next sequence behind the chain is corrected: one ahead of the best block is either
+    /// a race with a newer confirmation or a fork, which the finalized reconciliation handles.
+    /// The messages below the best block sequence are only marked as included, so a fork still
+    /// orphans them.
+    ///
+    /// The egress queue of one worker is not the truth of a sender shared by several workers,
+    /// e.g. the gatekeeper: a lagging one would drop messages the others still resend.
+    fn reconcile(
+        &mut self,
+        sender: &MessageOrigin,
+        truth: &SenderTruth,
+        current_height: u32,
+        timeout_in_blocks: u32,
+    ) -> SenderCorrections {
+        let mut corrections = SenderCorrections::default();
+        if truth.chain_next_sequence > self.node_next_sequence {
+            corrections.next_sequence = true;
+            self.node_next_sequence = truth.chain_next_sequence;
+            self.advanced_at = current_height;
+        }
+        for (sequence, ctx) in self.pending_messages.iter_mut() {
+            if *sequence < truth.chain_next_sequence {
+                if !matches!(ctx.state, MessageState::Successful | MessageState::Included(_)) {
+                    ctx.state = MessageState::Included(current_height);
+                    corrections.confirmed += 1;
+                }
+            } else if matches!(ctx.state, MessageState::Pending)
+                && current_height.saturating_sub(ctx.submitted_at) > timeout_in_blocks
+            {
+                ctx.state = MessageState::Timeout;
+                corrections.lost += 1;
+            }
+        }
+        if let (MessageOrigin::Worker(_), Some(worker_next_sequence)) = (sender, truth.worker_next_sequence) {
+            let before = self.pending_messages.len();
+            self.pending_messages.retain(|sequence, ctx| {
+                *sequence < worker_next_sequence
+                    || matches!(ctx.state, MessageState::Pending | MessageState::Included(_))
+            });
+            corrections.phantom = before - self.pending_messages.len();
+        }
+        corrections
+    }
+
+    pub fn calculate_next_sequence(&self, current_height: u32, timeout_in_blocks: u32) -> u64 {
         let mut next_sequence = self.node_next_sequence;
         while
             self.pending_messages.get(&next_sequence)
-                .map(|p_msg| p_msg.is_pending_or_success(current_height))
+                .map(|p_msg| p_msg.is_pending_or_success(current_height, timeout_in_blocks))
                 .unwrap_or(false)
         {
             next_sequence += 1;
@@ -120,25 +826,64 @@ pub async fn master_loop(
     bus: Arc<Bus>,
     dsm: Arc<DataSourceManager>,
     txm: Arc<TxManager>,
+    topic_toggles: Arc<TopicToggles>,
+    notifier: Arc<Notifier>,
+    dead_letters: Arc<DeadLetters>,
+    retry_policy: Arc<RetryPolicy>,
+    metrics: Arc<MessageMetrics>,
+    stall_alert_blocks: u32,
+    gc_horizon_blocks: u32,
 ) -> Result<()> {
     let mut sender_contexts = HashMap::<MessageOrigin, SenderContext>::new();
+    let mut egress_sources = HashMap::<MessageOrigin, EgressSources>::new();
 
-    tokio::spawn(background_update_current_height(bus.clone(), dsm.clone()));
+    tokio::spawn(background_update_heights(bus.clone(), dsm.clone()));
     tokio::time::sleep(Duration::from_secs(5)).await;
 
     let mut current_height: u32 = 0;
+    let mut receipts_enabled = false;
+    // The messages waiting for the next sequences of their senders, refreshed in one storage query
+    // once the received events are drained.
+    let mut pending_syncs = Vec::<PendingSync>::new();
     loop {
-        let messages_event = rx.recv().await;
+        let messages_event = match rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(_) => {
+                if !pending_syncs.is_empty() {
+                    tokio::spawn(do_update_next_sequences_and_sync_messages(
+                        bus.clone(),
+                        dsm.clone(),
+                        std::mem::take(&mut pending_syncs),
+                    ));
+                }
+                rx.recv().await
+            },
+        };
         let event = messages_event;
         if event.is_none() {
             break
         }
+        bus.stats.messages.record_received(1);
+        let timeout_in_blocks = txm.height_tracker.scale_blocks(retry_policy.tx_timeout_blocks);
 
         let event = event.unwrap();
         match event {
-            MessagesEvent::SyncMessages((worker_id, pool_id, sender, messages)) => {
+            MessagesEvent::SyncMessages((worker_id, pool_id, sender, messages, health)) => {
                 trace!("[{}] Received {} messages, start filtering.", sender, messages.len());
+                metrics.record_received(messages.len());
 
+                let latest_sequence = messages.iter().map(|m| m.sequence).max().unwrap_or_default();
+                let selected = egress_sources
+                    .entry(sender.clone())
+                    .or_default()
+                    .offer(&sender, &worker_id, health, latest_sequence, current_height);
+                if !selected {
+                    trace!("[{}] Ignoring the messages of {} which is not the selected source.", sender, worker_id);
+                    metrics.record_filtered(messages.len());
+                    continue;
+                }
+
+                let received = messages.len();
                 let messages = match sender_contexts.entry(sender.clone()) {
                     Occupied(entry) => {
                         let sender_context = entry.get();
@@ -147,41 +892,41 @@ pub async fn master_loop(
                             .filter(|message| {
                                 sender_context.pending_messages
                                     .get(&message.sequence)
-                                    .map(|p_msg| p_msg.is_timeout_or_failure(current_height))
+                                    .map(|p_msg| p_msg.is_timeout_or_failure(current_height, timeout_in_blocks))
                                     .unwrap_or(true)
                             })
                             .collect::<Vec<_>>()
                     },
                     Vacant(_) => messages,
                 };
+                metrics.record_filtered(received - messages.len());
                 if messages.is_empty() {
                     trace!("[{}] all messages are pending or completed", sender);
                     continue;
                 }
 
                 trace!("[{}] {} messages needs will send for check.", sender, messages.len());
-                tokio::spawn(do_update_next_sequence_and_sync_messages(
-                    bus.clone(),
-                    dsm.clone(),
-                    worker_id,
-                    pool_id,
-                    sender,
-                    messages
-                ));
+                pending_syncs.push((worker_id, pool_id, sender, messages));
             },
 
             MessagesEvent::DoSyncMessages((worker_id, pool_id, sender, messages, next_sequence)) => {
                 trace!("[{}] DoSync: Receveid {} messages.", sender, messages.len());
+                // Another source may have been selected while fetching the next sequence.
+                if !egress_sources.get(&sender).map(|s| s.is_selected(&worker_id)).unwrap_or(false) {
+                    trace!("[{}] {} is no longer the selected source.", sender, worker_id);
+                    continue;
+                }
 
                 let sender_context = match sender_contexts.entry(sender.clone()) {
                     Occupied(entry) => entry.into_mut(),
                     Vacant(entry) => match next_sequence {
                         Some(next_sequence) => {
-                            entry.insert(SenderContext {
-                                // sender: sender.clone(),
-                                node_next_sequence: next_sequence,
-                                pending_messages: HashMap::new(),
-                            })
+                            entry.insert(SenderContext::new(
+                                worker_id.clone(),
+                                next_sequence,
+                                current_height,
+                                retry_policy.rule_for(&sender),
+                            ))
                         },
                         None => {
                             error!("[{}] no last node sequence received for new sender.", sender);
@@ -190,42 +935,88 @@ pub async fn master_loop(
                     },
                 };
 
+                sender_context.worker_id = worker_id.clone();
                 if let Some(next_sequence) = next_sequence {
-                    sender_context.node_next_sequence = next_sequence;
+                    let no_op = sender_context.confirm(next_sequence, current_height, timeout_in_blocks);
+                    report_no_op_messages(&bus, &sender_context.worker_id, &sender, &no_op);
+                    dead_letters.resolve(&sender, next_sequence);
                 }
 
                 for message in messages {
-                    let next_sequence = sender_context.calculate_next_sequence(current_height);
+                    let next_sequence = sender_context.calculate_next_sequence(current_height, timeout_in_blocks);
                     if message.sequence != next_sequence {
                         debug!("[{}] Ignoring #{} message since not matching next_sequence {}.",
                             sender, message.sequence, next_sequence);
                         continue;
                     }
 
+                    let topic = String::from_utf8_lossy(message.message.destination.path());
+                    if topic_toggles.hold(&topic) {
+                        debug!("[{}] Holding #{} message and the following ones since topic {} is paused.",
+                            sender, message.sequence, topic);
+                        break;
+                    }
+
                     match sender_context.pending_messages.entry(message.sequence) {
                         Occupied(entry) => {
                             trace!("[{}] Msg#{} has message_context, checking if retry needed.", sender, message.sequence);
 
                             let message_context = entry.into_mut();
-                            if message_context.is_pending_or_success(current_height) {
+                            if let MessageState::Unrecoverable(err) = &message_context.state {
+                                debug!("[{}] Holding #{} message and the following ones since it is unrecoverable: {}",
+                                    sender, message.sequence, err);
+                                break;
+                            }
+                            if message_context.is_pending_or_success(current_height, timeout_in_blocks) {
                                 trace!("[{}] message #{} is pending or successful.", sender, message.sequence);
                                 continue;
                             }
 
+                            let attempts = message_context.prev_try_count + 1;
+                            if message_context.retry.is_exhausted(attempts) {
+                                let err = format!("Given up after {} attempts", attempts);
+                                error!("[{}] message #{} is unrecoverable, holding the sender. {}", sender, message.sequence, err);
+                                message_context.state = MessageState::Unrecoverable(err.clone());
+                                metrics.record_unrecoverable();
+                                if let Some(letter) = message_context.dead_letter(&worker_id) {
+                                    dead_letters.park(letter);
+                                }
+                                let _ = bus.send_worker_update_message(
+                                    worker_id.clone(),
+                                    format!("Offchain message #{} is unrecoverable, the following ones are held back. {}", message.sequence, err)
+                                );
+                                notifier.notify(Notification::MessageUnrecoverable {
+                                    sender: sender.to_string(),
+                                    worker_id: worker_id.clone(),
+                                    sequence: message.sequence,
+                                    error: err,
+                                });
+                                break;
+                            }
+                            let retry_at = message_context.retry_at(timeout_in_blocks);
+                            if current_height < retry_at {
+                                debug!("[{}] Holding #{} message and the following ones until H#{} to back off.",
+                                    sender, message.sequence, retry_at);
+                                break;
+                            }
+
                             debug!("[{}] Msg#{} needs to retry.", sender, message.sequence);
 
                             if matches!(message_context.state, MessageState::Pending) {
+                                metrics.record_timed_out();
                                 warn!("[{}] message #{} is pending, but {} is more than {} blocks, need retry.",
                                     sender,
                                     message.sequence,
                                     current_height.saturating_sub(message_context.submitted_at),
-                                    TX_TIMEOUT_IN_BLOCKS,
+                                    timeout_in_blocks,
                                 );
                             }
 
                             message_context.state = MessageState::Pending;
                             message_context.submitted_at = current_height;
+                            message_context.failed_at = None;
                             message_context.prev_try_count += 1;
+                            metrics.record_retried();
                             info!(
                                 "[{}] message #{} was failed for {} times. Trying again now..",
                                 sender, message.sequence, message_context.prev_try_count
@@ -240,11 +1031,15 @@ pub async fn master_loop(
                                 state: MessageState::Pending,
                                 submitted_at: current_height,
                                 prev_try_count: 0,
+                                confirmed_at: None,
+                                failed_at: None,
+                                retry: sender_context.retry.clone(),
                             });
                         }
                     }
 
                     debug!("[{}] Sending #{} message", sender, message.sequence);
+                    metrics.record_submitted();
                     tokio::spawn(do_sync_message(
                         bus.clone(),
                         txm.clone(),
@@ -265,22 +1060,36 @@ pub async fn master_loop(
                     },
                 };
                 let mut send_back_err = None;
+                let mut unrecoverable = None;
+                if let Err(err) = &result {
+                    sender_context.record_error(format!("#{}: {}", sequence, err));
+                }
                 match sender_context.pending_messages.get_mut(&sequence) {
                     Some(ctx) => {
                         ctx.state = match result {
-                            Ok(_) => MessageState::Successful,
+                            // Only confirmed once the on-chain sequence advances past it.
+                            Ok(included_at) => {
+                                metrics.record_included(included_at.saturating_sub(ctx.submitted_at));
+                                MessageState::Included(included_at)
+                            },
                             Err(err) => {
-                                let err_str = err.to_string();
-
-                                // do not show on website if it's the first error
-                                if ctx.prev_try_count > 0 {
-                                    send_back_err = Some(err);
-                                }
-
-                                if err_str.contains("Tx timed out!") {
-                                    MessageState::Timeout
+                                ctx.failed_at = Some(current_height);
+                                let chain_err = ChainError::classify(&err);
+                                if is_unrecoverable(&chain_err) {
+                                    unrecoverable = Some(err.to_string());
+                                    MessageState::Unrecoverable(chain_err.to_string())
                                 } else {
-                                    MessageState::Failure
+                                    // do not show on website if it's the first error
+                                    if ctx.prev_try_count > 0 {
+                                        send_back_err = Some(err);
+                                    }
+
+                                    if matches!(chain_err, ChainError::Timeout) {
+                                        metrics.record_timed_out();
+                                        MessageState::Timeout
+                                    } else {
+                                        MessageState::Failure
+                                    }
                                 }
                             },
                         };
@@ -290,7 +1099,23 @@ pub async fn master_loop(
                         continue;
                     },
                 };
-                if let Some(err) = send_back_err {
+                if let Some(err) = unrecoverable {
+                    metrics.record_unrecoverable();
+                    if let Some(letter) = sender_context.pending_messages.get(&sequence).and_then(|ctx| ctx.dead_letter(&worker_id)) {
+                        dead_letters.park(letter);
+                    }
+                    error!("[{}] message #{} is unrecoverable, holding the sender. {}", sender, sequence, err);
+                    let _ = bus.send_worker_update_message(
+                        worker_id.clone(),
+                        format!("Offchain message #{} is unrecoverable, the following ones are held back. {}", sequence, err)
+                    );
+                    notifier.notify(Notification::MessageUnrecoverable {
+                        sender: sender.to_string(),
+                        worker_id,
+                        sequence,
+                        error: err,
+                    });
+                } else if let Some(err) = send_back_err {
                     error!("[{}] sync offchain message completed with error. {}", sender, err);
                     let _ = bus.send_worker_update_message(
                         worker_id,
@@ -300,6 +1125,7 @@ pub async fn master_loop(
             },
 
             MessagesEvent::RemoveSender(sender) => {
+                egress_sources.remove(&sender);
                 match sender_contexts.remove(&sender) {
                     Some(_) => {
                         trace!("[{}] Removed from SenderContext", sender);
@@ -312,7 +1138,202 @@ pub async fn master_loop(
 
             MessagesEvent::CurrentHeight(height) => {
                 current_height = height;
+                txm.height_tracker.update(height);
                 trace!("Updated Current Para Height #{}", current_height);
+
+                let mut senders = vec![];
+                for (sender, sender_context) in sender_contexts.iter_mut() {
+                    sender_context.check_stall(sender, current_height, stall_alert_blocks, &bus, &notifier);
+                    if sender_context.confirming || !sender_context.has_unconfirmed() {
+                        continue;
+                    }
+                    // The accepted messages are confirmed by the receipts, only poll for the ones
+                    // which did not take effect.
+                    if receipts_enabled && !sender_context.has_overdue(current_height, timeout_in_blocks) {
+                        continue;
+                    }
+                    sender_context.confirming = true;
+                    senders.push(sender.clone());
+                }
+                if !senders.is_empty() {
+                    tokio::spawn(do_confirm_messages(bus.clone(), dsm.clone(), senders));
+                }
+                metrics.set_senders(
+                    sender_contexts
+                        .iter()
+                        .map(|(sender, sender_context)| (sender.to_string(), sender_context.gauges(current_height)))
+                        .collect(),
+                );
+            },
+
+            MessagesEvent::Confirmed((sender, chain_next_sequence)) => {
+                let sender_context = match sender_contexts.get_mut(&sender) {
+                    Some(ctx) => ctx,
+                    None => {
+                        trace!("[{}] sender was removed before confirmation", sender);
+                        continue;
+                    },
+                };
+                sender_context.confirming = false;
+                let Some(chain_next_sequence) = chain_next_sequence else {
+                    continue;
+                };
+                let no_op = sender_context.confirm(chain_next_sequence, current_height, timeout_in_blocks);
+                report_no_op_messages(&bus, &sender_context.worker_id, &sender, &no_op);
+                dead_letters.resolve(&sender, chain_next_sequence);
+            },
+
+            MessagesEvent::Receipts(receipts) => {
+                receipts_enabled = receipts.is_some();
+                let Some(receipts) = receipts else {
+                    continue;
+                };
+                trace!("Received {} message receipts at H#{}", receipts.receipts.len(), receipts.block);
+                for (sender, next_sequence) in receipts.receipts {
+                    let Some(sender_context) = sender_contexts.get_mut(&sender) else {
+                        continue;
+                    };
+                    // The receipts may arrive out of order with the polled sequences.
+                    let next_sequence = next_sequence.max(sender_context.node_next_sequence);
+                    let no_op = sender_context.confirm(next_sequence, current_height, timeout_in_blocks);
+                    report_no_op_messages(&bus, &sender_context.worker_id, &sender, &no_op);
+                    dead_letters.resolve(&sender, next_sequence);
+                }
+            },
+
+            MessagesEvent::FinalizedHeight((height, hash)) => {
+                trace!("Updated Finalized Para Height #{}", height);
+                if gc_horizon_blocks > 0 && height > gc_horizon_blocks {
+                    let cutoff = height - gc_horizon_blocks;
+                    let pruned: usize = sender_contexts
+                        .values_mut()
+                        .map(|sender_context| sender_context.collect_garbage(cutoff))
+                        .sum();
+                    if pruned > 0 {
+                        debug!("Pruned {} messages inactive since H#{}", pruned, cutoff);
+                    }
+                }
+                let mut senders = vec![];
+                for (sender, sender_context) in sender_contexts.iter_mut() {
+                    if sender_context.reconciling || !sender_context.has_unfinalized(height) {
+                        continue;
+                    }
+                    sender_context.reconciling = true;
+                    senders.push(sender.clone());
+                }
+                if !senders.is_empty() {
+                    tokio::spawn(do_reconcile_messages(bus.clone(), dsm.clone(), senders, height, hash));
+                }
+            },
+
+            MessagesEvent::Finalized((sender, finalized_height, finalized_next_sequence)) => {
+                let sender_context = match sender_contexts.get_mut(&sender) {
+                    Some(ctx) => ctx,
+                    None => {
+                        trace!("[{}] sender was removed before reconciliation", sender);
+                        continue;
+                    },
+                };
+                sender_context.reconciling = false;
+                let Some(finalized_next_sequence) = finalized_next_sequence else {
+                    continue;
+                };
+                let orphaned = sender_context.reconcile_finalized(finalized_next_sequence, finalized_height);
+                if !orphaned.is_empty() {
+                    sender_context.record_error(format!("{:?}: not on the finalized chain", orphaned));
+                    warn!("[{}] messages {:?} are not on the finalized chain at H#{}, will retry.",
+                        sender, orphaned, finalized_height);
+                    let _ = bus.send_worker_update_message(
+                        sender_context.worker_id.clone(),
+                        format!("Offchain messages {:?} were dropped by a fork, will retry.", orphaned)
+                    );
+                }
+            },
+
+            MessagesEvent::Snapshot(reply) => {
+                let mut snapshots = sender_contexts
+                    .iter()
+                    .map(|(sender, sender_context)| sender_context.snapshot(sender, egress_sources.get(sender)))
+                    .collect::<Vec<_>>();
+                snapshots.sort_by(|a, b| a.sender.cmp(&b.sender));
+                let _ = reply.send(snapshots);
+            },
+
+            MessagesEvent::Export(reply) => {
+                let mut senders = sender_contexts
+                    .iter()
+                    .map(|(sender, sender_context)| ArchivedSender {
+                        origin: sender.clone(),
+                        snapshot: sender_context.snapshot(sender, egress_sources.get(sender)),
+                    })
+                    .collect::<Vec<_>>();
+                senders.sort_by(|a, b| a.snapshot.sender.cmp(&b.snapshot.sender));
+                let _ = reply.send(senders);
+            },
+
+            MessagesEvent::Import((senders, reply)) => {
+                let outcomes = senders
+                    .into_iter()
+                    .map(|(sender, imported)| {
+                        let live = sender_contexts.contains_key(&sender);
+                        let sender_context = sender_contexts
+                            .entry(sender.clone())
+                            .or_insert_with(|| SenderContext::new(
+                                imported.worker_id,
+                                imported.chain_next_sequence,
+                                current_height,
+                                retry_policy.rule_for(&sender),
+                            ));
+                        let outcome = sender_context.import_dead_letters(&sender, imported.chain_next_sequence, imported.dead_letters, &dead_letters);
+                        info!("[{}] imported, live: {}, {} parked messages restored, {} resolved on chain, {} conflicting",
+                            sender, live, outcome.restored, outcome.resolved, outcome.conflicting);
+                        SenderImport { live, ..outcome }
+                    })
+                    .collect();
+                let _ = reply.send(outcomes);
+            },
+
+            MessagesEvent::ReleaseDeadLetter((sender, sequence, action, reply)) => {
+                let mut released = false;
+                if let Some(sender_context) = sender_contexts.get_mut(&sender) {
+                    if let Occupied(entry) = sender_context.pending_messages.entry(sequence) {
+                        if matches!(entry.get().state, MessageState::Unrecoverable(_)) {
+                            match action {
+                                DeadLetterAction::Retry => {
+                                    let ctx = entry.into_mut();
+                                    ctx.state = MessageState::Failure;
+                                    ctx.prev_try_count = 0;
+                                    ctx.failed_at = Some(current_height);
+                                },
+                                DeadLetterAction::Discard => {
+                                    entry.remove();
+                                },
+                            }
+                            released = true;
+                        }
+                    }
+                }
+                released |= dead_letters.remove(&sender, sequence);
+                if released {
+                    info!("[{}] Released the parked message #{} to {:?}", sender, sequence, action);
+                }
+                let _ = reply.send(released);
+            },
+
+            MessagesEvent::Reconcile((truths, reply)) => {
+                let corrections = truths
+                    .into_iter()
+                    .filter_map(|(sender, truth)| {
+                        let sender_context = sender_contexts.get_mut(&sender)?;
+                        let corrections = sender_context.reconcile(&sender, &truth, current_height, timeout_in_blocks);
+                        if corrections.total() > 0 {
+                            warn!("[{}] reconciled with the chain at #{} and the worker at {:?}: {:?}",
+                                sender, truth.chain_next_sequence, truth.worker_next_sequence, corrections);
+                        }
+                        Some((sender, corrections))
+                    })
+                    .collect();
+                let _ = reply.send(corrections);
             },
         }
     }
@@ -320,33 +1341,126 @@ pub async fn master_loop(
     Ok(())
 }
 
-async fn do_update_next_sequence_and_sync_messages(
+/// The worker id, pool id, sender and messages of a `SyncMessages` to refresh the next sequence
+/// for.
+type PendingSync = (String, u64, MessageOrigin, Vec<SignedMessage>);
+
+/// Refreshes the next sequences of the senders at the best block in a single storage query, then
+/// syncs their messages.
+///
+/// Unlike `mq_next_sequence`, the messages pending in the txpool are not taken into account.
+async fn do_update_next_sequences_and_sync_messages(
     bus: Arc<Bus>,
     dsm: Arc<DataSourceManager>,
-    worker_id: String,
-    pool_id: u64,
-    sender: MessageOrigin,
-    messages: Vec<SignedMessage>,
+    syncs: Vec<PendingSync>,
 ) {
-    let next_sequence = match use_parachain_api!(dsm, false) {
-        Some(para_api) => {
-            match pherry::chain_client::mq_next_sequence(&para_api, &sender).await {
-                Ok(next_sequence) => Some(next_sequence),
-                Err(err) => {
-                    warn!("[{}] met error, will use last node sequence: {}", sender, err);
-                    None
-                },
-            }
+    let senders: Vec<_> = syncs.iter().map(|(_, _, sender, _)| sender.clone()).collect();
+    for sender in &senders {
+        dsm.observe_mq_sender(sender);
+    }
+    let next_sequences = match fetch_chain_next_sequences(&dsm, &senders).await {
+        Ok(sequences) => sequences.into_iter().map(Some).collect(),
+        Err(err) => {
+            warn!("failed to refresh the sequences of {} senders, will use last node sequences: {}", senders.len(), err);
+            vec![None; senders.len()]
+        },
+    };
+    for ((worker_id, pool_id, sender, messages), next_sequence) in syncs.into_iter().zip(next_sequences) {
+        let _ = bus.send_messages_event(MessagesEvent::DoSyncMessages((
+            worker_id,
+            pool_id,
+            sender,
+            messages,
+            next_sequence,
+        )));
+    }
+}
+
+fn report_no_op_messages(bus: &Bus, worker_id: &str, sender: &MessageOrigin, sequences: &[u64]) {
+    if sequences.is_empty() {
+        return;
+    }
+    warn!("[{}] messages {:?} were included but did not take effect on chain, will retry.", sender, sequences);
+    let _ = bus.send_worker_update_message(
+        worker_id.to_string(),
+        format!("Offchain messages {:?} were included but did not take effect on chain, will retry.", sequences)
+    );
+}
+
+/// Fetches the on-chain next sequences of all the senders to confirm at the best block, in a
+/// single storage query.
+async fn do_confirm_messages(
+    bus: Arc<Bus>,
+    dsm: Arc<DataSourceManager>,
+    senders: Vec<MessageOrigin>,
+) {
+    let chain_next_sequences = match fetch_chain_next_sequences(&dsm, &senders).await {
+        Ok(sequences) => sequences.into_iter().map(Some).collect(),
+        Err(err) => {
+            warn!("failed to fetch the on-chain sequences of {} senders for confirmation: {}", senders.len(), err);
+            vec![None; senders.len()]
         },
-        None => None,
     };
-    let _ = bus.send_messages_event(MessagesEvent::DoSyncMessages((
-        worker_id,
-        pool_id,
-        sender,
-        messages,
-        next_sequence,
-    )));
+    for (sender, chain_next_sequence) in senders.into_iter().zip(chain_next_sequences) {
+        let _ = bus.send_messages_event(MessagesEvent::Confirmed((sender, chain_next_sequence)));
+    }
+}
+
+pub(crate) async fn fetch_chain_next_sequences(
+    dsm: &Arc<DataSourceManager>,
+    senders: &[MessageOrigin],
+) -> Result<Vec<u64>> {
+    let para_api = use_parachain_api!(dsm, false).ok_or(NoValidDataSource)?;
+    let hash = para_api
+        .rpc()
+        .block_hash(None)
+        .await?
+        .ok_or(anyhow::anyhow!("best block hash not found"))?;
+    pherry::chain_client::mq_next_sequences_at(&para_api, hash, senders).await
+}
+
+/// Reads the message receipts emitted in the best block, if they are enabled on chain.
+async fn do_fetch_receipts(bus: Arc<Bus>, para_api: ChainApi, hash: Hash) {
+    match fetch_receipts(&para_api, hash).await {
+        Ok(receipts) => {
+            let _ = bus.send_messages_event(MessagesEvent::Receipts(receipts));
+        },
+        Err(err) => {
+            warn!("failed to fetch the message receipts at {:?}: {}", hash, err);
+        },
+    }
+}
+
+async fn fetch_receipts(para_api: &ChainApi, hash: Hash) -> Result<Option<MessageReceipts>> {
+    if !pherry::chain_client::mq_ack_receipts_enabled(para_api, hash).await? {
+        return Ok(None);
+    }
+    Ok(Some(pherry::chain_client::mq_receipts_at(para_api, hash).await?.unwrap_or_default()))
+}
+
+/// Fetches the next sequences of the senders at the finalized block, to reconcile the messages
+/// confirmed at the best blocks.
+async fn do_reconcile_messages(
+    bus: Arc<Bus>,
+    dsm: Arc<DataSourceManager>,
+    senders: Vec<MessageOrigin>,
+    finalized_height: u32,
+    finalized_hash: Hash,
+) {
+    let result = match use_parachain_api!(dsm, false) {
+        Some(para_api) => pherry::chain_client::mq_next_sequences_at(&para_api, finalized_hash, &senders).await,
+        None => Err(NoValidDataSource.into()),
+    };
+    let finalized_next_sequences = match result {
+        Ok(sequences) => sequences.into_iter().map(Some).collect(),
+        Err(err) => {
+            warn!("failed to fetch the finalized sequences of {} senders at H#{}: {}", senders.len(), finalized_height, err);
+            vec![None; senders.len()]
+        },
+    };
+    for (sender, finalized_next_sequence) in senders.into_iter().zip(finalized_next_sequences) {
+        let _ = bus.send_messages_event(MessagesEvent::Finalized((sender, finalized_height, finalized_next_sequence)));
+    }
 }
 
 async fn do_sync_message(
@@ -364,7 +1478,13 @@ async fn do_sync_message(
     );
 }
 
-pub async fn background_update_current_height(
+/// Follows the best and finalized heads of the parachain, every finalized block is reported in
+/// order for the reconciliation.
+///
+/// The blocks finalized while resubscribing after a lost connection are not reported, the
+/// reconciliation catches up at the next finalized block reported, which reads the sequences
+/// reached in the skipped blocks as well.
+pub async fn background_update_heights(
     bus: Arc<Bus>,
     dsm: Arc<DataSourceManager>,
 ) -> Result<()> {
@@ -378,26 +1498,218 @@ pub async fn background_update_current_height(
             },
         };
 
-        let blocks_sub = para_api.blocks().subscribe_best().await;
-        let mut blocks_sub = match blocks_sub {
-            Ok(blocks_sub) => blocks_sub,
+        let mut heads = match FinalityStream::subscribe(&para_api).await {
+            Ok(heads) => heads,
             Err(e) => {
-                error!("Subscribe best blocks failed, wait 1 seconds. {e}");
+                error!("Subscribe heads failed, wait 1 seconds. {e}");
                 tokio::time::sleep(Duration::from_secs(1)).await;
                 continue;
             },
         };
 
-        while let Some(block) = blocks_sub.next().await {
-            let block = match block {
-                Ok(block) => block,
+        let mut last: Option<HeadUpdate> = None;
+        while let Some(update) = heads.next().await {
+            let update = match update {
+                Ok(update) => update,
                 Err(e) => {
-                    error!("Got error for next block. {e}");
+                    error!("Got error for next heads. {e}");
                     continue;
                 },
             };
 
-            let _ = bus.send_messages_event(MessagesEvent::CurrentHeight(block.number()));
+            if last.map(|last| last.best) != Some(update.best) {
+                let _ = bus.send_messages_event(MessagesEvent::CurrentHeight(update.best.number));
+                tokio::spawn(do_fetch_receipts(bus.clone(), para_api.clone(), update.best.hash));
+            }
+            if last.map(|last| last.finalized) != Some(update.finalized) {
+                let _ = bus.send_messages_event(MessagesEvent::FinalizedHeight((update.finalized.number, update.finalized.hash)));
+            }
+            last = Some(update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: u32 = 10;
+
+    fn worker_sender() -> MessageOrigin {
+        MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([1; 32]))
+    }
+
+    fn sender_context(sender: &MessageOrigin, messages: Vec<(u64, MessageState)>) -> SenderContext {
+        let mut context = SenderContext::new("worker".into(), 0, 100, Default::default());
+        for (sequence, state) in messages {
+            context.pending_messages.insert(
+                sequence,
+                MessageContext {
+                    sender: sender.clone(),
+                    sequence,
+                    state,
+                    submitted_at: 100,
+                    prev_try_count: 1,
+                    confirmed_at: None,
+                    failed_at: None,
+                    retry: Default::default(),
+                },
+            );
         }
+        context
+    }
+
+    fn state(context: &SenderContext, sequence: u64) -> Option<&MessageState> {
+        context
+            .pending_messages
+            .get(&sequence)
+            .map(|ctx| &ctx.state)
+    }
+
+    #[test]
+    fn reconciled_messages_are_only_included() {
+        let sender = worker_sender();
+        let mut context = sender_context(
+            &sender,
+            vec![
+                (0, MessageState::Failure),
+                (1, MessageState::Included(101)),
+                (2, MessageState::Pending),
+            ],
+        );
+        let truth = SenderTruth {
+            chain_next_sequence: 2,
+            worker_next_sequence: None,
+        };
+        let corrections = context.reconcile(&sender, &truth, 105, TIMEOUT);
+        assert!(corrections.next_sequence);
+        assert_eq!(corrections.confirmed, 1);
+        assert_eq!(context.node_next_sequence, 2);
+        assert!(matches!(
+            state(&context, 0),
+            Some(MessageState::Included(105))
+        ));
+        assert!(matches!(
+            state(&context, 1),
+            Some(MessageState::Included(101))
+        ));
+
+        // The best block was on a fork, the finalized chain is still at #0.
+        let orphaned = context.reconcile_finalized(0, 105);
+        assert_eq!(orphaned, vec![0, 1]);
+        assert_eq!(context.node_next_sequence, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn messages_included_ahead_of_the_best_height_are_not_orphaned() {
+        let sender = worker_sender();
+        // Included at #106 while the best height known here was still #105.
+        let mut context = sender_context(&sender, vec![(0, MessageState::Included(106))]);
+        assert!(!context.has_unfinalized(105));
+        assert!(context.reconcile_finalized(0, 105).is_empty());
+        assert!(matches!(
+            state(&context, 0),
+            Some(MessageState::Included(106))
+        ));
+
+        // Missing from the finalized #106, the block including it was orphaned.
+        assert!(context.has_unfinalized(106));
+        assert_eq!(context.reconcile_finalized(0, 106), vec![0]);
+        assert!(matches!(state(&context, 0), Some(MessageState::Failure)));
+    }
+
+    #[test]
+    fn included_messages_are_dropped_once_finalized() {
+        let sender = worker_sender();
+        let mut context = sender_context(
+            &sender,
+            vec![(0, MessageState::Included(106)), (1, MessageState::Pending)],
+        );
+        assert!(context.confirm(1, 107, TIMEOUT).is_empty());
+        assert!(matches!(state(&context, 0), Some(MessageState::Successful)));
+        assert!(context.reconcile_finalized(1, 107).is_empty());
+        assert!(state(&context, 0).is_none());
+        assert!(matches!(state(&context, 1), Some(MessageState::Pending)));
+    }
+
+    #[test]
+    fn included_messages_time_out_from_their_inclusion_block() {
+        let sender = worker_sender();
+        let mut context = sender_context(&sender, vec![(0, MessageState::Included(106))]);
+        assert!(context.confirm(0, 106 + TIMEOUT, TIMEOUT).is_empty());
+        assert_eq!(context.confirm(0, 107 + TIMEOUT, TIMEOUT), vec![0]);
+        assert!(matches!(state(&context, 0), Some(MessageState::Failure)));
+    }
+
+    #[test]
+    fn lost_submissions_time_out() {
+        let sender = worker_sender();
+        let mut context = sender_context(&sender, vec![(0, MessageState::Pending)]);
+        let truth = SenderTruth {
+            chain_next_sequence: 0,
+            worker_next_sequence: None,
+        };
+        let corrections = context.reconcile(&sender, &truth, 100 + TIMEOUT, TIMEOUT);
+        assert_eq!(corrections.total(), 0);
+        let corrections = context.reconcile(&sender, &truth, 101 + TIMEOUT, TIMEOUT);
+        assert_eq!(corrections.lost, 1);
+        assert!(matches!(state(&context, 0), Some(MessageState::Timeout)));
+    }
+
+    #[test]
+    fn phantom_messages_of_a_worker_are_dropped() {
+        let sender = worker_sender();
+        let mut context = sender_context(
+            &sender,
+            vec![
+                (0, MessageState::Failure),
+                (1, MessageState::Failure),
+                (2, MessageState::Unrecoverable("BadSender".into())),
+                (3, MessageState::Pending),
+            ],
+        );
+        let truth = SenderTruth {
+            chain_next_sequence: 0,
+            worker_next_sequence: Some(1),
+        };
+        let corrections = context.reconcile(&sender, &truth, 101, TIMEOUT);
+        assert_eq!(corrections.phantom, 2);
+        let mut kept: Vec<_> = context.pending_messages.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, vec![0, 3]);
+    }
+
+    #[test]
+    fn shared_senders_keep_their_messages() {
+        let sender = MessageOrigin::Gatekeeper;
+        let mut context = sender_context(
+            &sender,
+            vec![
+                (0, MessageState::Failure),
+                (1, MessageState::Unrecoverable("BadSender".into())),
+            ],
+        );
+        // A lagging worker of the gatekeeper.
+        let truth = SenderTruth {
+            chain_next_sequence: 0,
+            worker_next_sequence: Some(0),
+        };
+        let corrections = context.reconcile(&sender, &truth, 101, TIMEOUT);
+        assert_eq!(corrections.total(), 0);
+        assert_eq!(context.pending_messages.len(), 2);
+    }
+
+    #[test]
+    fn idle_senders_are_not_stalled() {
+        let sender = worker_sender();
+        let mut context = sender_context(&sender, vec![]);
+        assert_eq!(context.gauges(200).stalled_blocks, 0);
+
+        context = sender_context(&sender, vec![(0, MessageState::Failure)]);
+        assert_eq!(context.gauges(200).pending, 1);
+        assert_eq!(context.gauges(200).stalled_blocks, 100);
+
+        context.advanced_at = 0;
+        assert_eq!(context.gauges(200).stalled_blocks, 0);
+    }
+}
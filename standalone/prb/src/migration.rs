@@ -0,0 +1,379 @@
+//! Migration of a pool to another prb host.
+//!
+//! Part of the state of prb lives in memory only and is lost when a pool moves to a new host: the
+//! sender contexts of the message loop, the dead letters, i.e. the messages parked as
+//! unrecoverable which hold back their senders, and the journal of the finished transactions.
+//! `GET /migration/export` packs them in a versioned archive, and `POST /migration/import`
+//! restores it on the new host. `prb-migrate export` and `prb-migrate import` wrap both.
+//!
+//! The chain moves on between the export and the import, so the archive is reconciled with the
+//! on-chain next sequences of the senders:
+//!
+//! - a dead letter below the next sequence took effect some other way and is dropped;
+//! - a sender already known to the new host keeps its live state, only the dead letters it does
+//!   not track yet are parked;
+//! - the other senders are restored at their on-chain next sequence.
+//!
+//! The journal is appended to the past transactions, skipping the ones imported before.
+
+use crate::cli::{MigrateCliArgs, MigrateCommands};
+use crate::messages::{
+    export_senders, fetch_chain_next_sequences, import_senders, ImportedSender, MessageState,
+    SenderSnapshot,
+};
+use crate::tx::Transaction;
+use crate::wm::WorkerManagerContext;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use phala_git_revision::git_revision_with_ts;
+use phala_types::messaging::MessageOrigin;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const ARCHIVE_VERSION: u32 = 1;
+/// The largest archive accepted by the import endpoint.
+pub const MAX_ARCHIVE_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchivedSender {
+    pub origin: MessageOrigin,
+    pub snapshot: SenderSnapshot,
+}
+
+/// A message parked as unrecoverable, holding back the following ones of its sender.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    pub origin: MessageOrigin,
+    pub worker_id: String,
+    pub sequence: u64,
+    pub error: String,
+    pub submitted_at: u32,
+    pub prev_try_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MigrationArchive {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub git_revision: String,
+    pub senders: Vec<ArchivedSender>,
+    pub dead_letters: Vec<DeadLetter>,
+    /// The finished transactions, latest first.
+    pub tx_journal: Vec<Transaction>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ImportReport {
+    pub restored_senders: usize,
+    /// The senders already known to this prb, whose live state was kept.
+    pub live_senders: usize,
+    pub restored_dead_letters: usize,
+    /// The dead letters below the on-chain next sequence of their sender.
+    pub resolved_dead_letters: usize,
+    /// The dead letters whose sequence the live state of the sender already tracks.
+    pub conflicting_dead_letters: usize,
+    pub imported_txs: usize,
+    /// The transactions which were unfinished or imported before.
+    pub skipped_txs: usize,
+}
+
+impl MigrationArchive {
+    pub async fn collect(ctx: &WorkerManagerContext) -> Result<Self> {
+        let senders = export_senders(&ctx.bus).await?;
+        let dead_letters = senders
+            .iter()
+            .flat_map(|sender| {
+                sender
+                    .snapshot
+                    .pending_messages
+                    .iter()
+                    .filter_map(|message| match &message.state {
+                        MessageState::Unrecoverable(error) => Some(DeadLetter {
+                            origin: sender.origin.clone(),
+                            worker_id: sender.snapshot.worker_id.clone(),
+                            sequence: message.sequence,
+                            error: error.clone(),
+                            submitted_at: message.submitted_at,
+                            prev_try_count: message.prev_try_count,
+                        }),
+                        _ => None,
+                    })
+            })
+            .collect();
+        let tx_journal = ctx.txm.clone().dump().await?.past_txs;
+
+        Ok(Self {
+            version: ARCHIVE_VERSION,
+            created_at: Utc::now(),
+            git_revision: git_revision_with_ts().to_string(),
+            senders,
+            dead_letters,
+            tx_journal,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path}"))?;
+        let archive: Self = serde_json::from_reader(std::io::BufReader::new(file))?;
+        archive.check_version()?;
+        Ok(archive)
+    }
+
+    fn check_version(&self) -> Result<()> {
+        if self.version > ARCHIVE_VERSION {
+            bail!(
+                "archive version {} is not supported, upgrade prb",
+                self.version
+            );
+        }
+        Ok(())
+    }
+
+    /// Restores the archive, reconciled with the current on-chain state.
+    pub async fn import(self, ctx: &WorkerManagerContext) -> Result<ImportReport> {
+        self.check_version()?;
+        info!(
+            "Importing the migration archive created at {} by {}",
+            self.created_at, self.git_revision
+        );
+
+        let mut imported = BTreeMap::<MessageOrigin, ImportedSender>::new();
+        for sender in self.senders {
+            imported.insert(
+                sender.origin,
+                ImportedSender {
+                    worker_id: sender.snapshot.worker_id,
+                    chain_next_sequence: 0,
+                    dead_letters: vec![],
+                },
+            );
+        }
+        for letter in self.dead_letters {
+            imported
+                .entry(letter.origin.clone())
+                .or_insert_with(|| ImportedSender {
+                    worker_id: letter.worker_id.clone(),
+                    chain_next_sequence: 0,
+                    dead_letters: vec![],
+                })
+                .dead_letters
+                .push(letter);
+        }
+
+        let mut report = ImportReport::default();
+        if !imported.is_empty() {
+            let origins: Vec<_> = imported.keys().cloned().collect();
+            let chain_next_sequences = fetch_chain_next_sequences(&ctx.dsm, &origins)
+                .await
+                .context("Failed to fetch the on-chain sequences of the senders")?;
+            for (sender, next_sequence) in imported.values_mut().zip(chain_next_sequences) {
+                sender.chain_next_sequence = next_sequence;
+            }
+            for outcome in import_senders(&ctx.bus, imported).await? {
+                if outcome.live {
+                    report.live_senders += 1;
+                } else {
+                    report.restored_senders += 1;
+                }
+                report.restored_dead_letters += outcome.restored;
+                report.resolved_dead_letters += outcome.resolved;
+                report.conflicting_dead_letters += outcome.conflicting;
+            }
+        }
+
+        let (imported_txs, skipped_txs) = ctx.txm.import_past_txs(self.tx_journal).await?;
+        report.imported_txs = imported_txs;
+        report.skipped_txs = skipped_txs;
+        info!("Imported the migration archive: {report:?}");
+        Ok(report)
+    }
+}
+
+pub async fn cli_main(args: MigrateCliArgs) -> Result<()> {
+    match args.command {
+        MigrateCommands::Export { url, output, suri } => {
+            let path = "/migration/export";
+            let url = format!("{}{path}", url.trim_end_matches('/'));
+            let mut req = reqwest::Client::new().get(&url);
+            if let Some(suri) = suri {
+                for (name, value) in crate::api_auth::sign_request(&suri, "GET", path, b"")? {
+                    req = req.header(name, value);
+                }
+            }
+            let resp = req.send().await?;
+            if !resp.status().is_success() {
+                bail!("{} returned {}: {}", url, resp.status(), resp.text().await?);
+            }
+            let bytes = resp.bytes().await?;
+            let archive: MigrationArchive =
+                serde_json::from_slice(&bytes).context("Invalid archive")?;
+            std::fs::write(&output, &bytes).with_context(|| format!("Failed to write {output}"))?;
+            println!(
+                "Exported {} senders, {} dead letters and {} transactions to {output}",
+                archive.senders.len(),
+                archive.dead_letters.len(),
+                archive.tx_journal.len()
+            );
+        }
+        MigrateCommands::Import { url, file, suri } => {
+            let archive = MigrationArchive::load(&file)?;
+            let body = serde_json::to_vec(&archive)?;
+            let path = "/migration/import";
+            let url = format!("{}{path}", url.trim_end_matches('/'));
+            let mut req = reqwest::Client::new()
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json");
+            if let Some(suri) = suri {
+                for (name, value) in crate::api_auth::sign_request(&suri, "POST", path, &body)? {
+                    req = req.header(name, value);
+                }
+            }
+            let resp = req.body(body).send().await?;
+            if !resp.status().is_success() {
+                bail!("{} returned {}: {}", url, resp.status(), resp.text().await?);
+            }
+            let report: ImportReport = resp.json().await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::router;
+    use crate::api_auth::{sign_request, ApiAuth};
+    use crate::bus::Bus;
+    use crate::cli::WorkerManagerCliArgs;
+    use crate::computation_samples::ComputationSamples;
+    use crate::datasource::{DataSourceConfig, DataSourceManager};
+    use crate::dead_letters::DeadLetters;
+    use crate::inv_db::setup_inventory_db;
+    use crate::messages::TopicToggles;
+    use crate::notifications::Notifier;
+    use crate::tx::{TransactionState, TransactionSuccess, TxManager};
+    use crate::upgrade::FleetUpgrade;
+    use crate::wm::WrappedWorkerManagerContext;
+    use clap::Parser;
+    use sp_core::{sr25519, Pair};
+    use std::sync::Arc;
+
+    const SURI: &str = "//Alice";
+
+    const DATA_SOURCES: &str = r#"
+relaychain:
+  select_policy: Failover
+  data_sources:
+    - !SubstrateWebSocketSource
+      endpoint: ws://127.0.0.1:9
+      pruned: true
+parachain:
+  select_policy: Failover
+  data_sources:
+    - !SubstrateWebSocketSource
+      endpoint: ws://127.0.0.1:9
+      pruned: true
+"#;
+
+    async fn test_context(db_path: &str) -> WrappedWorkerManagerContext {
+        let admin = sr25519::Pair::from_string(SURI, None).unwrap();
+        let admin_key = format!("alice=0x{}", hex::encode(admin.public()));
+        let args = WorkerManagerCliArgs::parse_from([
+            "prb",
+            "--db-path",
+            db_path,
+            "--api-admin-keys",
+            &admin_key,
+        ]);
+        let config: DataSourceConfig = serde_yaml::from_str(DATA_SOURCES).unwrap();
+        let (dsm, _) = DataSourceManager::from_config(config, 1024).await.unwrap();
+        let (processor_tx, _) = std::sync::mpsc::channel();
+        let (messages_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let (worker_status_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let bus = Arc::new(Bus {
+            processor_tx,
+            messages_tx,
+            worker_status_tx,
+            stats: Default::default(),
+        });
+        let (txm, _) = TxManager::new(db_path, dsm.clone(), false, None).unwrap();
+        Arc::new(WorkerManagerContext {
+            inv_db: setup_inventory_db(db_path),
+            worker_status_map: Default::default(),
+            txm: txm.clone(),
+            dsm,
+            bus,
+            topic_toggles: Arc::new(TopicToggles::new(vec![])),
+            dead_letters: Arc::new(DeadLetters::load(txm.db.clone()).unwrap()),
+            message_metrics: Default::default(),
+            computation_samples: Arc::new(ComputationSamples::load(txm.db.clone()).unwrap()),
+            upgrade: FleetUpgrade::load(txm.db.clone()).unwrap(),
+            reconciler: Default::default(),
+            notifier: Arc::new(Notifier::new(None)),
+            args,
+        })
+    }
+
+    fn large_archive(txs: usize) -> MigrationArchive {
+        let tx_journal = (0..txs)
+            .map(|i| Transaction {
+                id: i,
+                state: TransactionState::Success(TransactionSuccess::default()),
+                desc: format!("Sync offchain message #{i} {}", "x".repeat(1024)),
+                pid: 1,
+                created_at: Utc::now(),
+                dual_submit: false,
+                tx_payload: None,
+                shot: None,
+            })
+            .collect();
+        MigrationArchive {
+            version: ARCHIVE_VERSION,
+            created_at: Utc::now(),
+            git_revision: git_revision_with_ts().to_string(),
+            senders: vec![],
+            dead_letters: vec![],
+            tx_journal,
+        }
+    }
+
+    #[test]
+    fn import_accepts_archives_above_the_default_body_limit() {
+        let db_path = std::env::temp_dir().join(format!("prb-migration-{}", uuid::Uuid::new_v4()));
+        let db_path = db_path.to_str().unwrap().to_string();
+        std::fs::create_dir_all(&db_path).unwrap();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let ctx = test_context(&db_path).await;
+            let auth = Arc::new(ApiAuth::new(&ctx.args).unwrap());
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router(ctx, auth).into_make_service());
+            tokio::spawn(server);
+
+            let archive = large_archive(5000);
+            let body = serde_json::to_vec(&archive).unwrap();
+            assert!(body.len() > 4 * 1024 * 1024);
+            assert!(body.len() < MAX_ARCHIVE_SIZE);
+
+            let path = "/migration/import";
+            let mut req = reqwest::Client::new()
+                .post(format!("http://{addr}{path}"))
+                .header(reqwest::header::CONTENT_TYPE, "application/json");
+            for (name, value) in sign_request(SURI, "POST", path, &body).unwrap() {
+                req = req.header(name, value);
+            }
+            let resp = req.body(body).send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::OK);
+            let report: ImportReport = resp.json().await.unwrap();
+            assert_eq!(report.imported_txs, 5000);
+        });
+    }
+}
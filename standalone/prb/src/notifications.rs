@@ -0,0 +1,99 @@
+//! Notifications of the failures which need the attention of the operator.
+//!
+//! Notifications are always logged, and posted as JSON to the webhook endpoint if one is
+//! configured with `--webhook-url`.
+
+use log::{error, warn};
+use serde::Serialize;
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    /// The on-chain next sequence of an offchain message sender has not advanced for a while,
+    /// despite its pending messages being submitted again and again.
+    SenderStalled {
+        sender: String,
+        worker_id: String,
+        /// The on-chain next sequence the sender is stuck at.
+        stuck_sequence: u64,
+        stalled_blocks: u32,
+        pending_messages: usize,
+        /// The most recent errors of submitting the messages of the sender.
+        last_errors: Vec<String>,
+    },
+    /// The sender advanced again after a [`Notification::SenderStalled`].
+    SenderRecovered {
+        sender: String,
+        worker_id: String,
+        next_sequence: u64,
+    },
+    /// An offchain message was rejected by the chain for a reason that no retry can fix. The
+    /// following messages of the sender are held back, since the sequence cannot be skipped.
+    MessageUnrecoverable {
+        sender: String,
+        worker_id: String,
+        sequence: u64,
+        error: String,
+    },
+    /// The events queued in a channel of the bus reached the high-water mark, i.e. its consumer
+    /// is falling behind.
+    BusChannelSaturated {
+        channel: String,
+        depth: u64,
+        high_water_mark: u64,
+        /// Number of the events sent after the consumer had gone.
+        dropped: u64,
+        /// The call sites which sent to the channel most recently.
+        recent_producers: Vec<String>,
+    },
+    /// The channel drained below half of the high-water mark after a
+    /// [`Notification::BusChannelSaturated`].
+    BusChannelRecovered { channel: String, depth: u64 },
+    /// A worker failed to be upgraded to a new pRuntime version. The upgrade stopped there,
+    /// leaving the following workers untouched.
+    UpgradeFailed {
+        worker_id: String,
+        target_version: String,
+        /// The step of the upgrade the worker failed at.
+        step: String,
+        error: String,
+    },
+}
+
+pub struct Notifier {
+    webhook_url: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(webhook_url: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .expect("Failed to build the webhook client");
+        Self {
+            webhook_url: webhook_url.filter(|url| !url.is_empty()),
+            client,
+        }
+    }
+
+    pub fn notify(&self, notification: Notification) {
+        warn!("Notification: {:?}", notification);
+        let Some(url) = self.webhook_url.clone() else {
+            return;
+        };
+        let request = self.client.post(url).json(&notification);
+        tokio::spawn(async move {
+            let result = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                error!("Failed to post the notification to the webhook: {err}");
+            }
+        });
+    }
+}
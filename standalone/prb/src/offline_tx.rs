@@ -0,0 +1,52 @@
+//! Offline signing of the pool management calls, for owners keeping their key off prb.
+//!
+//! `prb-config export-unsigned` writes the call along with the payload to be signed to a JSON
+//! file. Once the `signer_payload` is signed, e.g. by a hardware wallet or an air-gapped machine,
+//! `prb-config submit-signed` assembles the extrinsic with the signature and submits it. The
+//! export has to be signed again if the runtime is upgraded in between.
+
+use anyhow::{Context, Result};
+use phaxt::offline::{RawCall, UnsignedExtrinsic};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+fn decode_hex(hex_str: &str) -> Result<Vec<u8>> {
+    Ok(hex::decode(hex_str.trim().trim_start_matches("0x"))?)
+}
+
+pub async fn export_unsigned(
+    parachain_rpc: &str,
+    signer: &str,
+    call: &str,
+    nonce: Option<u32>,
+    longevity: u64,
+    tip: u128,
+    output: &str,
+) -> Result<UnsignedExtrinsic> {
+    let signer = AccountId32::from_string(signer).context("Bad signer")?;
+    let call = decode_hex(call).context("Bad call")?;
+    let api = phaxt::connect(parachain_rpc)
+        .await
+        .context("Failed to connect to the parachain")?;
+    let signer = phaxt::AccountId::from(<[u8; 32]>::from(signer));
+    let unsigned = api
+        .export_unsigned(&RawCall(&call), &signer, nonce, longevity, tip)
+        .await?;
+    std::fs::write(output, serde_json::to_string_pretty(&unsigned)?)
+        .with_context(|| format!("Failed to write {output}"))?;
+    Ok(unsigned)
+}
+
+/// Submits the exported extrinsic with the signature of its signer payload, returning the hash
+/// of the extrinsic.
+pub async fn submit_signed(parachain_rpc: &str, file: &str, signature: &str) -> Result<String> {
+    let unsigned: UnsignedExtrinsic = serde_json::from_slice(
+        &std::fs::read(file).with_context(|| format!("Failed to read {file}"))?,
+    )
+    .context("Bad exported extrinsic")?;
+    let signature = decode_hex(signature).context("Bad signature")?;
+    let api = phaxt::connect(parachain_rpc)
+        .await
+        .context("Failed to connect to the parachain")?;
+    let hash = api.submit_signed(&unsigned, &signature).await?;
+    Ok(format!("{hash:?}"))
+}
@@ -1,21 +1,17 @@
+use crate::key_provider::KeySource;
 pub use crate::khala;
 use anyhow::{anyhow, Result};
-use lazy_static::lazy_static;
 use parity_scale_codec::{Decode, Encode};
 use rocksdb::{DBCompactionStyle, DBWithThreadMode, MultiThreaded, Options};
 use schnorrkel::keys::Keypair;
 use serde::{Deserialize, Serialize};
 use sp_core::crypto::{AccountId32, Ss58AddressFormat, Ss58Codec};
-use sp_core::sr25519::Pair as Sr25519Pair;
-use sp_core::Pair;
-
-static PHALA_SS58_FORMAT_U8: u8 = 30;
-lazy_static! {
-    static ref PHALA_SS58_FORMAT: Ss58AddressFormat = Ss58AddressFormat::from(PHALA_SS58_FORMAT_U8);
-}
+use sp_core::sr25519::{Pair as Sr25519Pair, Public as Sr25519Public};
+use sp_core::{ByteArray, Pair};
 
 static PO_LIST: &str = "po_list";
 static PO_BY_PID: &str = "po:pid:";
+static EXTERNAL_PO_BY_PID: &str = "po:external:pid:";
 
 pub type DB = DBWithThreadMode<MultiThreaded>;
 
@@ -44,18 +40,45 @@ pub fn get_options(max_open_files: Option<i32>) -> Options {
     opts
 }
 
+#[derive(Clone)]
+pub enum OperatorKey {
+    /// The key pair, stored in the db.
+    Plain(Sr25519Pair),
+    /// Only the public key is stored, the pair is loaded from an external key provider, see
+    /// [`crate::key_provider`].
+    External {
+        public: Sr25519Public,
+        source: KeySource,
+    },
+}
+
 #[derive(Clone)]
 pub struct PoolOperator {
     pub pid: u64,
-    pub pair: Sr25519Pair,
+    pub key: OperatorKey,
     pub proxied: Option<AccountId32>,
 }
 
 impl PoolOperator {
+    pub fn public(&self) -> Sr25519Public {
+        match &self.key {
+            OperatorKey::Plain(pair) => pair.public(),
+            OperatorKey::External { public, .. } => *public,
+        }
+    }
+
     pub fn operator(&self) -> AccountId32 {
         match &self.proxied {
             Some(owner) => owner.clone(),
-            None => self.pair.public().into(),
+            None => self.public().into(),
+        }
+    }
+
+    /// Name of the provider of the signer key, never the key itself.
+    pub fn key_provider(&self) -> &'static str {
+        match &self.key {
+            OperatorKey::Plain(_) => "plain",
+            OperatorKey::External { source, .. } => source.provider().name(),
         }
     }
 }
@@ -67,37 +90,91 @@ pub struct PoolOperatorForEncode {
     pub proxied: Option<AccountId32>,
 }
 
+/// A pool operator whose key is loaded from an external key provider.
 #[derive(Serialize, Deserialize, Clone)]
+struct ExternalPoolOperatorForSerialize {
+    pid: u64,
+    public: String,
+    proxied: Option<String>,
+    source: KeySource,
+}
+
+impl TryFrom<&PoolOperator> for ExternalPoolOperatorForSerialize {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &PoolOperator) -> Result<Self> {
+        let OperatorKey::External { public, source } = &v.key else {
+            return Err(anyhow!("pool operator #{} has a plain key", v.pid));
+        };
+        Ok(Self {
+            pid: v.pid,
+            public: hex::encode(public),
+            proxied: v.proxied.as_ref().map(|a| a.to_ss58check()),
+            source: source.clone(),
+        })
+    }
+}
+
+impl TryFrom<&ExternalPoolOperatorForSerialize> for PoolOperator {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &ExternalPoolOperatorForSerialize) -> Result<Self> {
+        let public = Sr25519Public::from_slice(&hex::decode(&v.public)?)
+            .map_err(|_| anyhow!("bad public key of pool operator #{}", v.pid))?;
+        let proxied = match &v.proxied {
+            Some(a) => Some(AccountId32::from_ss58check(a).map_err(|e| anyhow!("{e:?}"))?),
+            None => None,
+        };
+        Ok(Self {
+            pid: v.pid,
+            key: OperatorKey::External {
+                public,
+                source: v.source.clone(),
+            },
+            proxied,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PoolOperatorForSerialize {
     pub pid: u64,
     pub operator_account_id: String,
     pub proxied_account_id: Option<String>,
+    #[serde(default)]
+    pub key_provider: String,
 }
 
 impl From<&PoolOperator> for PoolOperatorForSerialize {
     fn from(v: &PoolOperator) -> Self {
-        let operator_account_id: AccountId32 = v.pair.public().into();
-        let operator_account_id = operator_account_id.to_ss58check_with_version(*PHALA_SS58_FORMAT);
+        let ss58_format = Ss58AddressFormat::custom(phaxt::profile::installed().ss58_prefix);
+        let operator_account_id: AccountId32 = v.public().into();
+        let operator_account_id = operator_account_id.to_ss58check_with_version(ss58_format);
         let proxied_account_id = v
             .proxied
             .as_ref()
-            .map(|a| a.to_ss58check_with_version(*PHALA_SS58_FORMAT));
+            .map(|a| a.to_ss58check_with_version(ss58_format));
         Self {
             pid: v.pid,
             operator_account_id,
             proxied_account_id,
+            key_provider: v.key_provider().to_string(),
         }
     }
 }
 
-impl From<&PoolOperator> for PoolOperatorForEncode {
-    fn from(v: &PoolOperator) -> Self {
-        let pair = v.pair.as_ref().to_bytes();
-        Self {
+impl TryFrom<&PoolOperator> for PoolOperatorForEncode {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &PoolOperator) -> Result<Self> {
+        let OperatorKey::Plain(pair) = &v.key else {
+            return Err(anyhow!("pool operator #{} has an external key", v.pid));
+        };
+        Ok(Self {
             pid: v.pid,
-            pair,
+            pair: pair.as_ref().to_bytes(),
             proxied: v.proxied.clone(),
-        }
+        })
     }
 }
 
@@ -106,7 +183,7 @@ impl From<&PoolOperatorForEncode> for PoolOperator {
         let pair = Sr25519Pair::from(Keypair::from_bytes(v.pair.as_ref()).expect("parse key"));
         Self {
             pid: v.pid,
-            pair,
+            key: OperatorKey::Plain(pair),
             proxied: v.proxied.clone(),
         }
     }
@@ -149,6 +226,11 @@ impl PoolOperatorAccess for DB {
         Ok(ret)
     }
     fn get_po(&self, pid: u64) -> Result<Option<PoolOperator>> {
+        let key = format!("{EXTERNAL_PO_BY_PID}:{pid}");
+        if let Some(b) = self.get(key)? {
+            let po: ExternalPoolOperatorForSerialize = serde_json::from_slice(&b)?;
+            return Ok(Some((&po).try_into()?));
+        }
         let key = format!("{PO_BY_PID}:{pid}");
         let b = self.get(key)?;
         if b.is_none() {
@@ -163,9 +245,20 @@ impl PoolOperatorAccess for DB {
         pl.retain(|&i| i != pid);
         pl.push(pid);
         let key = format!("{PO_BY_PID}:{pid}");
-        let b = PoolOperatorForEncode::from(&po);
-        let b = b.encode();
-        self.put(key, b)?;
+        let external_key = format!("{EXTERNAL_PO_BY_PID}:{pid}");
+        match &po.key {
+            OperatorKey::Plain(_) => {
+                let b = PoolOperatorForEncode::try_from(&po)?;
+                self.put(key, b.encode())?;
+                self.delete(external_key)?;
+            }
+            OperatorKey::External { .. } => {
+                let b = ExternalPoolOperatorForSerialize::try_from(&po)?;
+                self.put(external_key, serde_json::to_vec(&b)?)?;
+                // Do not leave a previous plain key behind.
+                self.delete(key)?;
+            }
+        }
         let r = self.get_po(pid)?;
         let _ = self.set_pid_list(pl)?;
         Ok(r.unwrap())
@@ -2,10 +2,13 @@ use crate::api::WorkerStatus;
 use crate::bus::Bus;
 use crate::compute_management::*;
 use crate::datasource::DataSourceManager;
+use crate::egress_poller::{EgressPoller, EgressQueueState};
+use crate::egress_sources::SourceHealth;
 use crate::repository::{do_request_next_sync, get_load_state_request, ChaintipInfo, SyncRequest, SyncRequestManifest, WorkerSyncInfo};
 use crate::messages::MessagesEvent;
 use crate::pool_operator::DB;
-use crate::pruntime::PRuntimeClient;
+use crate::pruntime::{is_method_not_found, PRuntimeClient};
+use crate::sync_scheduler::SyncScheduler;
 use crate::tx::TxManager;
 use crate::{use_parachain_api, use_relaychain_api};
 use crate::worker::{WorkerLifecycleCommand, WorkerLifecycleState};
@@ -15,8 +18,10 @@ use chrono::{DateTime, Duration, Utc};
 use derive_more::Display;
 use log::{debug, error, info, trace, warn};
 use phactory_api::prpc::{
-    self, ChainState, GetEgressMessagesResponse, GetEndpointResponse, GetRuntimeInfoRequest,
-    InitRuntimeRequest, InitRuntimeResponse, PhactoryInfo, SignEndpointsRequest,
+    self, features, ChainState, CombinedHeadersToSync, EnclaveIdentityResponse,
+    GetEgressMessagesResponse, GetEgressQueueDepthResponse, GetEndpointResponse,
+    GetRuntimeInfoRequest, HeadersToSync, InitRuntimeRequest, InitRuntimeResponse,
+    ParaHeadersToSync, PhactoryInfo, SignEndpointsRequest,
 };
 use phala_pallets::pallet_computation::{SessionInfo, WorkerState};
 use phala_pallets::registry::WorkerInfoV2;
@@ -71,10 +76,15 @@ pub struct WorkerContext {
     pub phactory_info_requested: bool,
     pub phactory_info_requested_at: DateTime<Utc>,
 
+    pub egress_queue: EgressQueueState,
+
     pub stopped: bool,
 
     pub compute_management_context: Option<ComputeManagementContext>,
     pub session_updated: bool,
+
+    /// An error was not marked because the pool was under maintenance.
+    pub error_suppressed: bool,
 }
 
 impl WorkerContext {
@@ -107,6 +117,7 @@ impl WorkerContext {
                 phactory_info: None,
                 last_message: String::new(),
                 session_info: None,
+                enclave_identity: None,
             },
             worker_info: None,
             session_id: None,
@@ -123,10 +134,14 @@ impl WorkerContext {
             phactory_info_requested: false,
             phactory_info_requested_at: DateTime::<Utc>::MIN_UTC,
 
+            egress_queue: EgressQueueState::default(),
+
             stopped: false,
 
             compute_management_context: None,
             session_updated: false,
+
+            error_suppressed: false,
         }
     }
 
@@ -231,8 +246,10 @@ pub enum PRuntimeRequest {
     RegularGetInfo,
     PrepareRegister((bool, Option<sp_core::crypto::AccountId32>, bool)),
     GetEgressMessages,
+    GetEgressQueueDepth,
     SignEndpoints(Vec<String>),
     TakeCheckpoint,
+    GetEnclaveIdentity,
 }
 
 pub enum PRuntimeResponse {
@@ -243,8 +260,10 @@ pub enum PRuntimeResponse {
     RegularGetInfo(PhactoryInfo),
     PrepareRegister(InitRuntimeResponse),
     GetEgressMessages(GetEgressMessagesResponse),
+    GetEgressQueueDepth(GetEgressQueueDepthResponse),
     SignEndpoints(GetEndpointResponse),
     TakeCheckpoint(u32),
+    GetEnclaveIdentity(EnclaveIdentityResponse),
 }
 
 impl fmt::Display for PRuntimeRequest {
@@ -270,8 +289,10 @@ impl fmt::Display for PRuntimeRequest {
             PRuntimeRequest::RegularGetInfo => write!(f, "RegularGetInfo"),
             PRuntimeRequest::PrepareRegister(_) => write!(f, "PrepareRegister"),
             PRuntimeRequest::GetEgressMessages => write!(f, "GetEgressMessages"),
+            PRuntimeRequest::GetEgressQueueDepth => write!(f, "GetEgressQueueDepth"),
             PRuntimeRequest::SignEndpoints(_) => write!(f, "SignEndpoints"),
             PRuntimeRequest::TakeCheckpoint => write!(f, "TakeCheckpoint"),
+            PRuntimeRequest::GetEnclaveIdentity => write!(f, "GetEnclaveIdentity"),
         }
     }
 }
@@ -299,8 +320,10 @@ impl fmt::Display for PRuntimeResponse {
             PRuntimeResponse::RegularGetInfo(_) => write!(f, "RegularGetInfo"),
             PRuntimeResponse::PrepareRegister(_) => write!(f, "PrepareRegister"),
             PRuntimeResponse::GetEgressMessages(_) => write!(f, "GetEgressMessages"),
+            PRuntimeResponse::GetEgressQueueDepth(_) => write!(f, "GetEgressQueueDepth"),
             PRuntimeResponse::SignEndpoints(_) => write!(f, "SignEndpoints"),
             PRuntimeResponse::TakeCheckpoint(_) => write!(f, "TakeCheckpoint"),
+            PRuntimeResponse::GetEnclaveIdentity(_) => write!(f, "GetEnclaveIdentity"),
         }
     }
 }
@@ -350,6 +373,8 @@ pub enum ProcessorEvent {
     ReceivedParaChainState(Vec<(Vec<u8>, Vec<u8>)>),
     #[display(fmt = "ReceivedParaStorageChanges")]
     ReceivedParaStorageChanges(phactory_api::blocks::StorageChanges),
+    #[display(fmt = "MaintenanceEnded({})", "_0")]
+    MaintenanceEnded(u64),
 }
 
 pub type ProcessorRx = mpsc::Receiver<ProcessorEvent>;
@@ -400,6 +425,9 @@ pub struct Processor {
 
     pub chaintip: ChaintipInfo,
 
+    pub sync_scheduler: Arc<SyncScheduler>,
+    pub egress_poller: EgressPoller,
+
     storage: Storage,
 }
 
@@ -442,6 +470,12 @@ impl Processor {
                 parachain: use_parachain_api!(dsm, false).unwrap().latest_finalized_block_number().await.unwrap(),
             },
 
+            sync_scheduler: Arc::new(SyncScheduler::new(
+                std::time::Duration::from_millis(args.sync_jitter_ms),
+                args.max_concurrent_rpc_ops,
+            )),
+            egress_poller: EgressPoller::new(args.egress_pull_threshold, args.egress_max_age_blocks),
+
             storage,
         }
     }
@@ -456,13 +490,17 @@ impl Processor {
                 Ok(event) => event,
                 Err(_) => break,
             };
+            self.bus.stats.processor.record_received(1);
 
             let start_time = Instant::now();
             let event_display = format!("{event}");
             match event {
                 ProcessorEvent::AddWorker((added_worker, pool_sync_only, operator, pruntime_client)) => {
                     let worker_id = added_worker.id.clone();
-                    let worker_context = WorkerContext::create(added_worker, pool_sync_only, operator, pruntime_client);
+                    let mut worker_context = WorkerContext::create(added_worker, pool_sync_only, operator, pruntime_client);
+                    // Spread the regular info polls of the workers across the interval.
+                    let phase = self.sync_scheduler.phase(UPDATE_PHACTORY_INFO_INTERVAL.to_std().unwrap());
+                    worker_context.phactory_info_requested_at = Utc::now() - UPDATE_PHACTORY_INFO_INTERVAL + Duration::from_std(phase).unwrap();
                     if workers.contains_key(&worker_id) {
                         error!("[{}] Failed to add worker because the UUID is existed.", worker_id);
                     } else {
@@ -595,6 +633,20 @@ impl Processor {
                     self.storage.0.apply_changes(state_root, transaction);
                     debug!("Applied delta set for processor chain state cache.");
                 },
+                ProcessorEvent::MaintenanceEnded(pool_id) => {
+                    for worker in workers.values_mut() {
+                        if worker.pool_id != pool_id || worker.stopped {
+                            continue;
+                        }
+                        if std::mem::take(&mut worker.error_suppressed) {
+                            info!("[{}] Ran into errors during maintenance, restarting.", worker.uuid);
+                            self.handle_worker_lifecycle_command(worker, WorkerLifecycleCommand::ShouldRestart);
+                        } else if worker.is_reached_chaintip(&self.chaintip) && worker.is_compute_management_needed() {
+                            trace!("[{}] Maintenance ended, resuming compute management.", worker.uuid);
+                            self.request_compute_management(worker);
+                        }
+                    }
+                },
             }
             let cost = start_time.elapsed().as_micros();
             debug!("measuring {event_display} cost {cost} microseconds.");
@@ -618,8 +670,13 @@ impl Processor {
 
         match event {
             WorkerEvent::UpdateWorker(updated_worker) => {
-                if worker.worker_status.worker.endpoint != updated_worker.endpoint {
-                    worker.client = Arc::new(crate::pruntime::create_client(updated_worker.endpoint.clone()));
+                if worker.worker_status.worker.endpoint != updated_worker.endpoint ||
+                    worker.worker_status.worker.proxy != updated_worker.proxy
+                {
+                    worker.client = Arc::new(crate::pruntime::create_client(
+                        updated_worker.endpoint.clone(),
+                        updated_worker.proxy.as_deref(),
+                    ));
                 }
                 if worker.worker_status.worker.enabled != updated_worker.enabled ||
                     worker.worker_status.worker.sync_only != updated_worker.sync_only
@@ -741,6 +798,16 @@ impl Processor {
                 );
             },
             WorkerEvent::MarkError((timestamp, error_msg)) => {
+                if self.txm.maintenance.suppress(worker.pool_id) {
+                    warn!("[{}] Pool #{} is under maintenance, not marking error: {}", worker.uuid, worker.pool_id, error_msg);
+                    worker.error_suppressed = true;
+                    self.update_worker_message(
+                        worker,
+                        &format!("(maintenance) {}", error_msg),
+                        Some(timestamp),
+                    );
+                    return;
+                }
                 self.update_worker_state_and_message(
                     worker,
                     WorkerLifecycleState::HasError(error_msg.clone()),
@@ -899,6 +966,14 @@ impl Processor {
             worker.phactory_info_requested_at = Utc::now();
         }
 
+        // The RPC-heavy operations take a slot of the budget. A sync was already jittered when it
+        // was generated, so only the egress message polls are jittered here.
+        let deadline = match &request {
+            PRuntimeRequest::Sync(_) => Some(std::time::Duration::ZERO),
+            PRuntimeRequest::GetEgressMessages => Some(self.sync_deadline(worker)),
+            _ => None,
+        };
+
         worker.pruntime_lock = true;
         tokio::spawn(
             dispatch_pruntime_request(
@@ -906,6 +981,8 @@ impl Processor {
                 worker.uuid.clone(),
                 worker.client.clone(),
                 request,
+                self.sync_scheduler.clone(),
+                deadline,
             )
         );
     }
@@ -926,6 +1003,13 @@ impl Processor {
                 worker.blocknum = info.blocknum;
 
                 self.request_prepare_lifecycle(worker);
+
+                if info.system.is_some()
+                    && worker.worker_status.enclave_identity.is_none()
+                    && info.rpc_features & features::GET_ENCLAVE_IDENTITY != 0
+                {
+                    self.add_pruntime_request(worker, PRuntimeRequest::GetEnclaveIdentity);
+                }
             },
             PRuntimeResponse::InitRuntime(_response) => {
                 self.update_worker_message(worker, "InitRuntime Completed.", None);
@@ -968,8 +1052,10 @@ impl Processor {
                 tokio::spawn(do_register(
                     self.bus.clone(),
                     self.txm.clone(),
+                    worker.client.clone(),
                     worker.uuid.clone(),
                     worker.pool_id,
+                    worker.operator.clone(),
                     response,
                     self.pccs_url.clone(),
                     self.pccs_timeout_secs,
@@ -978,6 +1064,9 @@ impl Processor {
             PRuntimeResponse::GetEgressMessages(response) => {
                 self.handle_pruntime_egress_messages(worker, response)
             },
+            PRuntimeResponse::GetEgressQueueDepth(response) => {
+                self.handle_pruntime_egress_queue_depth(worker, response)
+            },
             PRuntimeResponse::SignEndpoints(response) => {
                 tokio::spawn(do_update_endpoints(
                     self.bus.clone(),
@@ -994,6 +1083,31 @@ impl Processor {
                     None
                 );
             },
+            PRuntimeResponse::GetEnclaveIdentity(response) => {
+                let public_key = worker.worker_status.phactory_info.as_ref()
+                    .and_then(|info| info.system.as_ref())
+                    .map(|system| system.public_key.clone())
+                    .unwrap_or_default();
+                match crate::enclave_identity::verify(&response, &public_key) {
+                    Ok(identity) => {
+                        info!("[{}] Enclave identity verified, version {} ({})",
+                            worker.uuid,
+                            identity.version,
+                            identity.git_revision,
+                        );
+                        worker.worker_status.enclave_identity = Some(identity);
+                        self.send_worker_status(worker);
+                    },
+                    Err(err) => {
+                        warn!("[{}] Failed to verify the enclave identity: {}", worker.uuid, err);
+                        self.update_worker_message(
+                            worker,
+                            &format!("Failed to verify the enclave identity: {}", err),
+                            None
+                        );
+                    },
+                }
+            },
         }
         trace!("[{}] Handled PRuntimeResponse", worker.uuid);
     }
@@ -1017,8 +1131,13 @@ impl Processor {
         }
 
         if worker.is_registered() && info.blocknum.is_some() && worker.para_headernum == worker.blocknum {
-            trace!("[{}] Dispatched a block, requesting EgressMessages", worker.uuid);
-            self.add_pruntime_request(worker, PRuntimeRequest::GetEgressMessages);
+            if worker.client.client.capabilities.supports(features::GET_EGRESS_QUEUE_DEPTH) {
+                trace!("[{}] Dispatched a block, requesting EgressQueueDepth", worker.uuid);
+                self.add_pruntime_request(worker, PRuntimeRequest::GetEgressQueueDepth);
+            } else {
+                trace!("[{}] Dispatched a block, requesting EgressMessages", worker.uuid);
+                self.add_pruntime_request(worker, PRuntimeRequest::GetEgressMessages);
+            }
         }
 
         if !worker.is_reached_chaintip(&self.chaintip) {
@@ -1109,6 +1228,14 @@ impl Processor {
         self.add_pruntime_request(worker, PRuntimeRequest::InitRuntime(request));
     }
 
+    /// The deadline of the RPC-heavy operations of the worker, zero if it is catching up.
+    fn sync_deadline(
+        &self,
+        worker: &WorkerContext,
+    ) -> std::time::Duration {
+        SyncScheduler::deadline(self.chaintip.parachain.saturating_sub(worker.blocknum))
+    }
+
     fn request_next_sync(
         &mut self,
         worker: &WorkerContext,
@@ -1117,6 +1244,8 @@ impl Processor {
             self.bus.clone(),
             self.dsm.clone(),
             self.headers_db.clone(),
+            self.sync_scheduler.clone(),
+            self.sync_deadline(worker),
             WorkerSyncInfo {
                 worker_id: worker.uuid.clone(),
                 headernum: worker.headernum,
@@ -1139,6 +1268,10 @@ impl Processor {
             },
         };
 
+        let health = SourceHealth {
+            lag_blocks: self.chaintip.parachain.saturating_sub(worker.blocknum),
+            recent_errors: worker.pruntime_recent_error_count,
+        };
         for (sender, mut messages) in messages {
             if messages.is_empty() {
                 trace!("[{}] Received empty messages for sender {}", worker.uuid, sender);
@@ -1158,11 +1291,38 @@ impl Processor {
                     worker.pool_id,
                     sender,
                     messages,
+                    health,
                 ))
             );
         }
     }
 
+    fn handle_pruntime_egress_queue_depth(
+        &mut self,
+        worker: &mut WorkerContext,
+        response: GetEgressQueueDepthResponse,
+    ) {
+        let depths = match response.decode_depths() {
+            Ok(depths) => depths,
+            Err(err) => {
+                error!("[{}] failed to decode egress queue depths. {}", worker.uuid, err);
+                return;
+            },
+        };
+
+        let storage = &self.storage;
+        let should_pull = self.egress_poller.should_pull(
+            &mut worker.egress_queue,
+            &depths,
+            worker.blocknum,
+            |sender| storage.mq_sequence(sender),
+        );
+        if should_pull {
+            trace!("[{}] Egress queues due, requesting EgressMessages", worker.uuid);
+            self.add_pruntime_request(worker, PRuntimeRequest::GetEgressMessages);
+        }
+    }
+
     fn handle_worker_lifecycle_command(
         &mut self,
         worker: &mut WorkerContext,
@@ -1210,7 +1370,16 @@ async fn dispatch_pruntime_request(
     worker_id: String,
     client: Arc<PRuntimeClient>,
     request: PRuntimeRequest,
+    scheduler: Arc<SyncScheduler>,
+    deadline: Option<std::time::Duration>,
 ) {
+    let _permit = match deadline {
+        Some(deadline) => {
+            scheduler.jitter(deadline).await;
+            scheduler.acquire().await
+        },
+        None => None,
+    };
     let start_time = Instant::now();
     let request_display = format!("{}", request);
     debug!("[{}] Start to dispatch {}", worker_id, request_display);
@@ -1226,7 +1395,10 @@ async fn dispatch_pruntime_request(
         PRuntimeRequest::PrepareLifecycle => {
             client.get_info(())
                 .await
-                .map(PRuntimeResponse::PrepareLifecycle)
+                .map(|info| {
+                    client.client.capabilities.negotiate(&info);
+                    PRuntimeResponse::PrepareLifecycle(info)
+                })
         },
         PRuntimeRequest::InitRuntime(request) => {
             client.init_runtime(request)
@@ -1246,7 +1418,10 @@ async fn dispatch_pruntime_request(
         PRuntimeRequest::RegularGetInfo => {
             client.get_info(())
                 .await
-                .map(PRuntimeResponse::RegularGetInfo)
+                .map(|info| {
+                    client.client.capabilities.negotiate(&info);
+                    PRuntimeResponse::RegularGetInfo(info)
+                })
         },
         PRuntimeRequest::PrepareRegister((force_refresh_ra, operator, _)) => {
             let request = GetRuntimeInfoRequest::new(force_refresh_ra, operator);
@@ -1261,6 +1436,18 @@ async fn dispatch_pruntime_request(
                     PRuntimeResponse::GetEgressMessages(response)
                 })
         },
+        PRuntimeRequest::GetEgressQueueDepth => {
+            match client.get_egress_queue_depth(()).await {
+                Err(err) if is_method_not_found(&err) => {
+                    warn!("pRuntime does not support GetEgressQueueDepth, falling back to GetEgressMessages");
+                    client.client.capabilities.mark_unsupported(features::GET_EGRESS_QUEUE_DEPTH);
+                    client.get_egress_messages(())
+                        .await
+                        .map(PRuntimeResponse::GetEgressMessages)
+                },
+                result => result.map(PRuntimeResponse::GetEgressQueueDepth),
+            }
+        },
         PRuntimeRequest::SignEndpoints(endpoints) => {
             client.sign_endpoint_info(SignEndpointsRequest::new(endpoints))
                 .await
@@ -1275,6 +1462,11 @@ async fn dispatch_pruntime_request(
                     PRuntimeResponse::TakeCheckpoint(response.synced_to)
                 })
         },
+        PRuntimeRequest::GetEnclaveIdentity => {
+            client.get_enclave_identity(())
+                .await
+                .map(PRuntimeResponse::GetEnclaveIdentity)
+        },
     };
 
     if let Err(err) = &result {
@@ -1319,15 +1511,26 @@ async fn do_sync_request(
     }
 
     if let Some(combined_headers) = request.combined_headers {
-        match client.sync_combined_headers(combined_headers).await {
-            Ok(synced_to) => {
-                response.headernum = Some(synced_to.relaychain_synced_to);
-                response.para_headernum = Some(synced_to.parachain_synced_to);
-            },
-            Err(err) => {
-                return Err(err);
-            },
-        }
+        let capabilities = &client.client.capabilities;
+        let result = if capabilities.supports(features::SYNC_COMBINED_HEADERS) {
+            match client.sync_combined_headers(combined_headers.clone()).await {
+                Err(err) if is_method_not_found(&err) => {
+                    warn!("pRuntime does not support SyncCombinedHeaders, falling back to separate calls");
+                    capabilities.mark_unsupported(features::SYNC_COMBINED_HEADERS);
+                    None
+                },
+                result => Some(result),
+            }
+        } else {
+            None
+        };
+        let (headernum, para_headernum) = match result {
+            Some(Ok(synced_to)) => (synced_to.relaychain_synced_to, synced_to.parachain_synced_to),
+            Some(Err(err)) => return Err(err),
+            None => sync_combined_headers_separately(&client, combined_headers).await?,
+        };
+        response.headernum = Some(headernum);
+        response.para_headernum = Some(para_headernum);
     }
 
     if let Some(blocks) = request.blocks {
@@ -1344,6 +1547,27 @@ async fn do_sync_request(
     Ok(response)
 }
 
+/// Compatibility path for pRuntimes without SyncCombinedHeaders.
+///
+/// The parachain header proof is against the last relaychain header, which has been synced by
+/// the first call when the second one is validated.
+async fn sync_combined_headers_separately(
+    client: &PRuntimeClient,
+    combined_headers: CombinedHeadersToSync,
+) -> Result<(u32, u32), prpc::client::Error> {
+    let headers = HeadersToSync {
+        encoded_headers: combined_headers.encoded_relaychain_headers,
+        encoded_authority_set_change: combined_headers.authority_set_change,
+    };
+    let headernum = client.sync_header(headers).await?.synced_to;
+    let para_headers = ParaHeadersToSync {
+        encoded_headers: combined_headers.encoded_parachain_headers,
+        proof: combined_headers.proof,
+    };
+    let para_headernum = client.sync_para_header(para_headers).await?.synced_to;
+    Ok((headernum, para_headernum))
+}
+
 async fn do_restart(
     bus: Arc<Bus>,
     worker: crate::inv_db::Worker,
@@ -1356,7 +1580,7 @@ async fn do_restart(
     info!("[{}] Restarting: Remove WorkerContext command sent, wait {} seconds and then add back",
         worker_id, RESTART_WORKER_COOL_PERIOD.num_seconds());
     tokio::time::sleep(RESTART_WORKER_COOL_PERIOD.to_std().unwrap()).await;
-    let client = crate::pruntime::create_client(worker.endpoint.clone());
+    let client = crate::pruntime::create_client(worker.endpoint.clone(), worker.proxy.as_deref());
     let _ = bus.send_processor_event(ProcessorEvent::AddWorker((
         worker,
         Some(pool_sync_only),
@@ -1,11 +1,12 @@
 use anyhow::Result;
-use log::debug;
+use log::{debug, info};
 use phactory_api::prpc::client::{Error as ClientError, RequestClient};
 use phactory_api::prpc::phactory_api_client::PhactoryApiClient;
 use phactory_api::prpc::server::ProtoError as ServerError;
-use phactory_api::prpc::Message;
+use phactory_api::prpc::{Message, PhactoryInfo};
 use reqwest::Client;
 use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 
@@ -15,6 +16,41 @@ pub struct RpcRequest {
     base_url: String,
     client: Client,
     semaphore: Arc<Semaphore>,
+    pub capabilities: Capabilities,
+}
+
+/// Optional RPC features of the connected pRuntime, see `phactory_api::prpc::features`.
+///
+/// Everything is assumed to be supported until the pRuntime tells otherwise, either by reporting
+/// its feature bits in GetInfo, or by answering "Method Not Found" to a request.
+#[derive(Default)]
+pub struct Capabilities {
+    unsupported: AtomicU64,
+}
+
+impl Capabilities {
+    pub fn negotiate(&self, info: &PhactoryInfo) {
+        // Legacy pRuntimes don't report the feature bits, detect them lazily instead.
+        if info.rpc_features == 0 {
+            return;
+        }
+        let unsupported = !info.rpc_features;
+        if self.unsupported.swap(unsupported, Ordering::Relaxed) != unsupported {
+            info!("pRuntime {} reported RPC features: {:#x}", info.version, info.rpc_features);
+        }
+    }
+
+    pub fn supports(&self, feature: u64) -> bool {
+        self.unsupported.load(Ordering::Relaxed) & feature == 0
+    }
+
+    pub fn mark_unsupported(&self, feature: u64) {
+        self.unsupported.fetch_or(feature, Ordering::Relaxed);
+    }
+}
+
+pub fn is_method_not_found(err: &ClientError) -> bool {
+    matches!(err, ClientError::ServerError(err) if err.message == "Method Not Found")
 }
 
 #[async_trait::async_trait]
@@ -36,8 +72,9 @@ impl PRuntimeClientWithSemaphore for PRuntimeClient {
 }
 
 impl RpcRequest {
-    pub fn new(base_url: String) -> Self {
-        let client = Client::builder()
+    pub fn new(base_url: String, proxy: Option<&str>) -> Self {
+        let client = crate::proxy::client_builder(proxy)
+            .expect("Should resolve the proxy")
             .tcp_keepalive(Some(core::time::Duration::from_secs(10)))
             .timeout(core::time::Duration::from_secs(180))
             .build()
@@ -46,12 +83,14 @@ impl RpcRequest {
             base_url,
             client,
             semaphore: Arc::new(Semaphore::new(1)),
+            capabilities: Default::default(),
         }
     }
 }
 
-pub fn create_client(base_url: String) -> PRuntimeClient {
-    PhactoryApiClient::new(RpcRequest::new(base_url))
+/// Creates a client of the pRuntime at `base_url`, see [`crate::proxy`] for the `proxy` setting.
+pub fn create_client(base_url: String, proxy: Option<&str>) -> PRuntimeClient {
+    PhactoryApiClient::new(RpcRequest::new(base_url, proxy))
 }
 
 fn from_display(err: impl core::fmt::Display) -> ClientError {
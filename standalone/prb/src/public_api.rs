@@ -0,0 +1,203 @@
+//! Read-only public API for the statistics of the pools.
+//!
+//! Pools show the status of their workers on public websites. This API serves a subset of the
+//! worker status without authentication: aggregate counts, and the sync height and state of each
+//! worker. Nothing that leads to the workers is served, i.e. no endpoints, error messages, keys or
+//! session accounts, so it can be exposed behind a reverse proxy. It listens on its own addresses
+//! so that the management API stays private, and requests beyond the rate limit are rejected with
+//! `429 Too Many Requests`.
+
+use crate::api::WorkerStatus;
+use crate::cli::WorkerManagerCliArgs;
+use crate::wm::WrappedWorkerManagerContext;
+use crate::worker::WorkerLifecycleState;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::future::try_join_all;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Global token bucket, shared by all clients since behind a reverse proxy they all come from the
+/// same address.
+struct RateLimiter {
+    rate_per_sec: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate_per_sec: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate_per_sec: rate_per_sec as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last_refill) = &mut *state;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last_refill).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate_per_sec).min(self.burst);
+        *last_refill = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+struct PublicApiContext {
+    wm: WrappedWorkerManagerContext,
+    limiter: RateLimiter,
+}
+
+type PublicContext = State<Arc<PublicApiContext>>;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublicWorkerStatus {
+    pub name: String,
+    pub pid: Option<u64>,
+    pub state: String,
+    pub headernum: Option<u32>,
+    pub para_headernum: Option<u32>,
+    pub blocknum: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublicSummaryResponse {
+    pub total_workers: usize,
+    pub states: BTreeMap<String, usize>,
+    /// The highest block number among the workers.
+    pub max_blocknum: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublicWorkersResponse {
+    pub workers: Vec<PublicWorkerStatus>,
+}
+
+/// The state of a worker without the details, the error messages may contain endpoints.
+pub(crate) fn state_label(state: &WorkerLifecycleState) -> &'static str {
+    match state {
+        WorkerLifecycleState::Starting => "Starting",
+        WorkerLifecycleState::Synchronizing => "Synchronizing",
+        WorkerLifecycleState::Preparing => "Preparing",
+        WorkerLifecycleState::Working => "Working",
+        WorkerLifecycleState::GatekeeperWorking => "GatekeeperWorking",
+        WorkerLifecycleState::HasError(_) => "HasError",
+        WorkerLifecycleState::Restarting => "Restarting",
+        WorkerLifecycleState::Disabled => "Disabled",
+    }
+}
+
+impl From<&WorkerStatus> for PublicWorkerStatus {
+    fn from(status: &WorkerStatus) -> Self {
+        let info = status.phactory_info.as_ref();
+        Self {
+            name: status.worker.name.clone(),
+            pid: status.worker.pid,
+            state: state_label(&status.state).to_string(),
+            headernum: info.map(|info| info.headernum),
+            para_headernum: info.map(|info| info.para_headernum),
+            blocknum: info.map(|info| info.blocknum),
+        }
+    }
+}
+
+pub async fn start_public_api_server(
+    ctx: WrappedWorkerManagerContext,
+    args: WorkerManagerCliArgs,
+) -> anyhow::Result<()> {
+    let ctx = Arc::new(PublicApiContext {
+        wm: ctx,
+        limiter: RateLimiter::new(args.public_api_rate_limit, args.public_api_burst),
+    });
+    let app = Router::new()
+        .route("/public/summary", get(handle_get_summary))
+        .route("/public/workers", get(handle_get_workers))
+        .fallback(handle_not_found)
+        .with_state(ctx);
+
+    let fut_vec = args
+        .public_api_listen_addresses
+        .into_iter()
+        .map(|addr| {
+            info!("Listening on {} for public API.", &addr);
+            let addr = SocketAddr::from_str(&addr).unwrap();
+            axum::Server::bind(&addr).serve(app.clone().into_make_service())
+        })
+        .collect::<Vec<_>>();
+
+    try_join_all(fut_vec).await?;
+    Ok(())
+}
+
+fn too_many_requests() -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, "1")],
+        (),
+    )
+        .into_response()
+}
+
+fn public_json<T: Serialize>(body: T) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, "public, max-age=5")],
+        Json(body),
+    )
+        .into_response()
+}
+
+async fn handle_not_found() -> StatusCode {
+    StatusCode::NOT_FOUND
+}
+
+async fn handle_get_summary(State(ctx): PublicContext) -> Response {
+    if !ctx.limiter.try_acquire() {
+        return too_many_requests();
+    }
+    let map = ctx.wm.worker_status_map.lock().await;
+    let mut states = BTreeMap::<String, usize>::new();
+    for status in map.values() {
+        *states
+            .entry(state_label(&status.state).to_string())
+            .or_default() += 1;
+    }
+    let max_blocknum = map
+        .values()
+        .filter_map(|status| status.phactory_info.as_ref().map(|info| info.blocknum))
+        .max();
+    public_json(PublicSummaryResponse {
+        total_workers: map.len(),
+        states,
+        max_blocknum,
+    })
+}
+
+async fn handle_get_workers(State(ctx): PublicContext) -> Response {
+    if !ctx.limiter.try_acquire() {
+        return too_many_requests();
+    }
+    let map = ctx.wm.worker_status_map.lock().await;
+    let mut workers = map
+        .values()
+        .map(PublicWorkerStatus::from)
+        .collect::<Vec<_>>();
+    drop(map);
+    workers.sort_by(|a, b| a.name.cmp(&b.name));
+    public_json(PublicWorkersResponse { workers })
+}
@@ -0,0 +1,184 @@
+//! Periodic reconciliation of the sender contexts with the chain and the workers.
+//!
+//! The sender contexts of the message loop are driven by events: the completions of the
+//! submissions, the receipts and the polled sequences. An event lost on the way, e.g. a
+//! `Completed` dropped with a crashed task, leaves a context drifting from the truth until the
+//! sender is synced again, which may never happen for a quiet sender.
+//!
+//! Every `--reconcile-interval` seconds, the on-chain next sequence of every managed sender is
+//! read at the best block, and the next sequence of its egress queue from its worker. The message
+//! loop corrects the contexts with them, see [`SenderCorrections`] for the kinds of drift. The
+//! number of corrections is exposed through `GET /messages/reconciliation`: it should stay at 0,
+//! a growing count points at a leak of events.
+
+use crate::messages::{
+    export_senders, fetch_chain_next_sequences, reconcile_senders, SenderCorrections, SenderTruth,
+};
+use crate::wm::WrappedWorkerManagerContext;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use phala_types::messaging::MessageOrigin;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReconcileRound {
+    pub at: DateTime<Utc>,
+    pub senders: usize,
+    pub corrections: usize,
+    /// The workers whose egress queues could not be read, their senders are reconciled with the
+    /// chain only.
+    pub unreachable_workers: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ReconcileReport {
+    pub interval: u64,
+    pub rounds: u64,
+    pub total_corrections: u64,
+    pub last_round: Option<ReconcileRound>,
+    /// The corrections of each sender since the start, the senders never corrected are omitted.
+    pub senders: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+pub struct Reconciler {
+    report: Mutex<ReconcileReport>,
+    trigger: Notify,
+}
+
+impl Reconciler {
+    pub fn report(&self) -> ReconcileReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Runs a round now instead of at the next tick, if the reconciliation is enabled.
+    pub fn trigger(&self) {
+        self.trigger.notify_one();
+    }
+
+    fn record(&self, round: ReconcileRound, corrections: &[(MessageOrigin, SenderCorrections)]) {
+        let mut report = self.report.lock().unwrap();
+        report.rounds += 1;
+        report.total_corrections += round.corrections as u64;
+        for (sender, corrections) in corrections {
+            if corrections.total() > 0 {
+                *report.senders.entry(sender.to_string()).or_default() +=
+                    corrections.total() as u64;
+            }
+        }
+        report.last_round = Some(round);
+    }
+}
+
+pub async fn reconcile_loop(ctx: WrappedWorkerManagerContext) -> Result<()> {
+    let interval = ctx.args.reconcile_interval;
+    ctx.reconciler.report.lock().unwrap().interval = interval;
+    if interval == 0 {
+        info!("Sender reconciliation disabled.");
+        return std::future::pending().await;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    // The first tick completes immediately, let the message loop settle first.
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = ctx.reconciler.trigger.notified() => {
+                info!("Reconciling the senders on demand");
+                ticker.reset();
+            }
+        }
+        let mut round = ReconcileRound {
+            at: Utc::now(),
+            senders: 0,
+            corrections: 0,
+            unreachable_workers: vec![],
+            error: None,
+        };
+        let corrections = match reconcile(&ctx, &mut round).await {
+            Ok(corrections) => corrections,
+            Err(err) => {
+                warn!("Failed to reconcile the senders: {err:?}");
+                round.error = Some(format!("{err:?}"));
+                vec![]
+            }
+        };
+        round.corrections = corrections.iter().map(|(_, c)| c.total()).sum();
+        if round.corrections > 0 {
+            warn!(
+                "Reconciled {} senders with {} corrections",
+                round.senders, round.corrections
+            );
+        }
+        ctx.reconciler.record(round, &corrections);
+    }
+}
+
+async fn reconcile(
+    ctx: &WrappedWorkerManagerContext,
+    round: &mut ReconcileRound,
+) -> Result<Vec<(MessageOrigin, SenderCorrections)>> {
+    let senders = export_senders(&ctx.bus).await?;
+    round.senders = senders.len();
+    if senders.is_empty() {
+        return Ok(vec![]);
+    }
+    let origins: Vec<_> = senders.iter().map(|s| s.origin.clone()).collect();
+    let chain_next_sequences = fetch_chain_next_sequences(&ctx.dsm, &origins)
+        .await
+        .context("Failed to fetch the on-chain sequences of the senders")?;
+
+    let mut worker_queues = HashMap::<String, Option<HashMap<MessageOrigin, u64>>>::new();
+    let mut truths = BTreeMap::new();
+    for (sender, chain_next_sequence) in senders.into_iter().zip(chain_next_sequences) {
+        let worker_id = sender.snapshot.worker_id;
+        if !worker_queues.contains_key(&worker_id) {
+            let queues = match worker_next_sequences(ctx, &worker_id).await {
+                Ok(queues) => Some(queues),
+                Err(err) => {
+                    warn!("[{worker_id}] Failed to read the egress queues: {err:?}");
+                    round.unreachable_workers.push(worker_id.clone());
+                    None
+                }
+            };
+            worker_queues.insert(worker_id.clone(), queues);
+        }
+        let worker_next_sequence = worker_queues[&worker_id]
+            .as_ref()
+            .and_then(|queues| queues.get(&sender.origin).copied());
+        truths.insert(
+            sender.origin,
+            SenderTruth {
+                chain_next_sequence,
+                worker_next_sequence,
+            },
+        );
+    }
+    reconcile_senders(&ctx.bus, truths).await
+}
+
+/// The next sequences of the egress queues of the worker.
+async fn worker_next_sequences(
+    ctx: &WrappedWorkerManagerContext,
+    worker_id: &str,
+) -> Result<HashMap<MessageOrigin, u64>> {
+    let worker = ctx
+        .worker_status_map
+        .lock()
+        .await
+        .get(worker_id)
+        .map(|status| status.worker.clone())
+        .context("Worker not found")?;
+    let client = crate::pruntime::create_client(worker.endpoint, worker.proxy.as_deref());
+    let depths = client.get_egress_queue_depth(()).await?.decode_depths()?;
+    Ok(depths
+        .into_iter()
+        .map(|(sender, depth)| (sender, depth.next_sequence))
+        .collect())
+}
@@ -13,6 +13,7 @@ use crate::datasource::DataSourceManager;
 use crate::headers_db::*;
 use crate::processor::{PRuntimeRequest, ProcessorEvent};
 use crate::pool_operator::DB;
+use crate::sync_scheduler::SyncScheduler;
 use crate::{use_parachain_api, use_relaychain_api};
 
 use phactory_api::prpc::{Blocks, ChainState, CombinedHeadersToSync, HeadersToSync, ParaHeadersToSync};
@@ -377,12 +378,18 @@ pub async fn do_request_next_sync(
     bus: Arc<Bus>,
     dsm: Arc<DataSourceManager>,
     headers_db: Arc<DB>,
+    scheduler: Arc<SyncScheduler>,
+    deadline: Duration,
     info: WorkerSyncInfo,
 ) {
     trace!("[{}] Received next sync. {}-{}-{}", info.worker_id, info.headernum, info.para_headernum, info.blocknum);
+    scheduler.jitter(deadline).await;
     let mut try_count = 0;
     let request = loop {
-        match generate_sync_request(dsm.clone(), headers_db.clone(), info.clone()).await {
+        let permit = scheduler.acquire().await;
+        let result = generate_sync_request(dsm.clone(), headers_db.clone(), info.clone()).await;
+        drop(permit);
+        match result {
             Ok(request) => break request,
             Err(err) => {
                 try_count += 1;
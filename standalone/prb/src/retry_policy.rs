@@ -0,0 +1,201 @@
+//! Retry policy of the offchain messages.
+//!
+//! A message whose submission failed or timed out is resubmitted when its sender is synced
+//! again. By default it is resubmitted right away and forever, which is what a sender needs to
+//! make progress, but hammers the chain when a message keeps failing. The policy bounds it:
+//!
+//! - `--message-tx-timeout-blocks`: blocks after which a submission without result is retried.
+//! - `--message-max-attempts`: submissions of a message before it is given up, 0 for unlimited.
+//!   A message given up is parked as unrecoverable, holding back the following messages of the
+//!   sender like a message rejected by the chain.
+//! - `--message-retry-backoff-blocks`: comma separated blocks to wait after the n-th failed
+//!   submission before the next one. The last one applies to all the following retries.
+//! - `--message-retry-overrides`: a JSON object overriding these per sender, keyed by the sender
+//!   as displayed in the logs, e.g. `Worker(<hex pubkey>)`, or by its kind, e.g. `Gatekeeper`
+//!   or `Worker`. The exact sender takes precedence over the kind:
+//!
+//! ```text
+//! {"Gatekeeper": {"max_attempts": 0}, "Worker": {"max_attempts": 20, "backoff_blocks": [1, 5]}}
+//! ```
+
+use crate::cli::WorkerManagerCliArgs;
+use anyhow::{Context, Result};
+use phala_types::messaging::MessageOrigin;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// The retry rule of the messages of a sender.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryRule {
+    /// Number of submissions of a message before it is given up, 0 for unlimited.
+    pub max_attempts: u32,
+    /// Blocks to wait after the n-th failed submission, the last one repeats.
+    pub backoff_blocks: Vec<u32>,
+}
+
+impl RetryRule {
+    /// Blocks to wait before resubmitting a message which failed `failures` times.
+    pub fn backoff(&self, failures: usize) -> u32 {
+        match failures.checked_sub(1) {
+            Some(index) => self
+                .backoff_blocks
+                .get(index)
+                .or(self.backoff_blocks.last())
+                .copied()
+                .unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Whether a message submitted `attempts` times must not be submitted again.
+    pub fn is_exhausted(&self, attempts: usize) -> bool {
+        self.max_attempts != 0 && attempts >= self.max_attempts as usize
+    }
+}
+
+/// Fields of a [`RetryRule`] overridden for some senders, the others fall back to the default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryOverride {
+    pub max_attempts: Option<u32>,
+    pub backoff_blocks: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub tx_timeout_blocks: u32,
+    pub default: Arc<RetryRule>,
+    /// The resolved rules of the overridden senders and sender kinds.
+    pub overrides: BTreeMap<String, Arc<RetryRule>>,
+}
+
+impl RetryPolicy {
+    pub fn from_args(args: &WorkerManagerCliArgs) -> Result<Self> {
+        let default = RetryRule {
+            max_attempts: args.message_max_attempts,
+            backoff_blocks: args.message_retry_backoff_blocks.clone(),
+        };
+        Self::new(
+            args.message_tx_timeout_blocks,
+            default,
+            args.message_retry_overrides.as_deref(),
+        )
+    }
+
+    /// Resolves the `overrides` JSON against the default rule.
+    fn new(tx_timeout_blocks: u32, default: RetryRule, overrides: Option<&str>) -> Result<Self> {
+        let overrides = match overrides {
+            Some(overrides) => serde_json::from_str::<BTreeMap<String, RetryOverride>>(overrides)
+                .context("Invalid --message-retry-overrides")?,
+            None => Default::default(),
+        };
+        let overrides = overrides
+            .into_iter()
+            .map(|(key, o)| {
+                let rule = RetryRule {
+                    max_attempts: o.max_attempts.unwrap_or(default.max_attempts),
+                    backoff_blocks: o
+                        .backoff_blocks
+                        .unwrap_or_else(|| default.backoff_blocks.clone()),
+                };
+                (key, Arc::new(rule))
+            })
+            .collect();
+        Ok(Self {
+            tx_timeout_blocks,
+            default: Arc::new(default),
+            overrides,
+        })
+    }
+
+    /// The rule of the sender, by the exact sender, then its kind, then the default.
+    pub fn rule_for(&self, sender: &MessageOrigin) -> Arc<RetryRule> {
+        self.overrides
+            .get(&sender.to_string())
+            .or_else(|| self.overrides.get(origin_kind(sender)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn origin_kind(origin: &MessageOrigin) -> &'static str {
+    match origin {
+        MessageOrigin::Pallet(_) => "Pallet",
+        MessageOrigin::Contract(_) => "Contract",
+        MessageOrigin::Worker(_) => "Worker",
+        MessageOrigin::AccountId(_) => "AccountId",
+        MessageOrigin::MultiLocation(_) => "MultiLocation",
+        MessageOrigin::Gatekeeper => "Gatekeeper",
+        MessageOrigin::Cluster(_) => "Cluster",
+        MessageOrigin::Reserved => "Reserved",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(max_attempts: u32, backoff_blocks: &[u32]) -> RetryRule {
+        RetryRule {
+            max_attempts,
+            backoff_blocks: backoff_blocks.to_vec(),
+        }
+    }
+
+    fn worker(byte: u8) -> MessageOrigin {
+        MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([byte; 32]))
+    }
+
+    #[test]
+    fn backoff_repeats_the_last_step() {
+        let rule = rule(0, &[1, 5, 10]);
+        assert_eq!(rule.backoff(0), 0);
+        assert_eq!(rule.backoff(1), 1);
+        assert_eq!(rule.backoff(2), 5);
+        assert_eq!(rule.backoff(3), 10);
+        assert_eq!(rule.backoff(100), 10);
+    }
+
+    #[test]
+    fn backoff_is_immediate_without_steps() {
+        let rule = rule(0, &[]);
+        assert_eq!(rule.backoff(0), 0);
+        assert_eq!(rule.backoff(1), 0);
+        assert_eq!(rule.backoff(7), 0);
+    }
+
+    #[test]
+    fn is_exhausted_after_max_attempts() {
+        let bounded = rule(3, &[]);
+        assert!(!bounded.is_exhausted(0));
+        assert!(!bounded.is_exhausted(2));
+        assert!(bounded.is_exhausted(3));
+        assert!(bounded.is_exhausted(4));
+
+        let unlimited = rule(0, &[]);
+        assert!(!unlimited.is_exhausted(usize::MAX));
+    }
+
+    #[test]
+    fn rule_for_prefers_the_exact_sender_over_its_kind() {
+        let overridden = worker(1);
+        let overrides = format!(
+            r#"{{"Worker": {{"max_attempts": 20, "backoff_blocks": [1, 5]}}, "{overridden}": {{"max_attempts": 2}}, "Gatekeeper": {{"backoff_blocks": [3]}}}}"#
+        );
+        let policy = RetryPolicy::new(10, rule(5, &[2]), Some(&overrides)).unwrap();
+
+        // The exact sender, falling back to the default for the fields it doesn't override,
+        // not to the kind.
+        assert_eq!(*policy.rule_for(&overridden), rule(2, &[2]));
+        assert_eq!(*policy.rule_for(&worker(2)), rule(20, &[1, 5]));
+        assert_eq!(*policy.rule_for(&MessageOrigin::Gatekeeper), rule(5, &[3]));
+        assert_eq!(*policy.rule_for(&MessageOrigin::Reserved), rule(5, &[2]));
+    }
+
+    #[test]
+    fn unknown_override_fields_are_rejected() {
+        assert!(RetryPolicy::new(10, rule(5, &[]), Some(r#"{"Worker": {"retries": 1}}"#)).is_err());
+        assert!(RetryPolicy::new(10, rule(5, &[]), Some("not json")).is_err());
+    }
+}
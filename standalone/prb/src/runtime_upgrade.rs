@@ -0,0 +1,319 @@
+//! Submission blackout around the runtime upgrades of the parachain.
+//!
+//! Transactions submitted around a runtime upgrade often fail with invalid-transaction errors:
+//! the ones built against the old runtime are checked against the new one, and the clients keep
+//! encoding with the old metadata until they refresh it.
+//!
+//! The upgrade of the parachain is enacted at the relay block stored in `Paras.FutureCodeUpgrades`
+//! of the relay chain. From `--runtime-upgrade-hold-before-blocks` relay blocks before it, every
+//! submission is held back until:
+//!
+//! - the upgrade is enacted, i.e. the spec version of the parachain changed;
+//! - `--runtime-upgrade-hold-after-blocks` parachain blocks passed since then;
+//! - the metadata of the submission client is refreshed to the new spec version.
+//!
+//! The held submissions then resume, and the offchain message senders are reconciled right away
+//! since the messages submitted just before the upgrade may have been dropped.
+
+use crate::datasource::DataSourceError::NoValidDataSource;
+use crate::utils::map_key;
+use crate::wm::WrappedWorkerManagerContext;
+use crate::{use_parachain_api, use_relaychain_api};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use parity_scale_codec::Decode;
+use phaxt::ChainApi;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(6);
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RuntimeUpgradeStatus {
+    /// The spec version of the parachain runtime.
+    pub spec_version: Option<u32>,
+    /// The relay block at which the scheduled upgrade is enacted.
+    pub scheduled_at: Option<u32>,
+    /// The parachain block at which the last upgrade was seen enacted.
+    pub enacted_at: Option<u32>,
+    pub holding: bool,
+    /// Number of submission rounds held back since the start.
+    pub held_submissions: u64,
+    /// Number of upgrades seen since the start.
+    pub upgrades: u64,
+}
+
+/// What the chain looked like at a poll.
+struct Observation {
+    spec_version: u32,
+    para_height: u32,
+    relay_height: u32,
+    scheduled_at: Option<u32>,
+    /// The spec version the metadata of the submission client was fetched for.
+    client_spec_version: u32,
+}
+
+#[derive(Default)]
+pub struct RuntimeUpgradeGuard {
+    status: Mutex<RuntimeUpgradeStatus>,
+    notify: Notify,
+}
+
+impl RuntimeUpgradeGuard {
+    pub fn status(&self) -> RuntimeUpgradeStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Returns true if the submissions are held back, counting the held ones.
+    pub fn hold(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if status.holding {
+            status.held_submissions += 1;
+        }
+        status.holding
+    }
+
+    /// Waits until the submissions are not held back.
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if !self.status.lock().unwrap().holding {
+                return;
+            }
+            let _ = tokio::time::timeout(POLL_INTERVAL, notified).await;
+        }
+    }
+
+    /// Updates the status with the observation, returning true if the submissions resumed.
+    fn observe(&self, obs: Observation, hold_before: u32, hold_after: u32) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if matches!(status.spec_version, Some(prev) if prev != obs.spec_version) {
+            info!(
+                "Runtime upgraded to spec version {} at #{}",
+                obs.spec_version, obs.para_height
+            );
+            status.enacted_at = Some(obs.para_height);
+            status.upgrades += 1;
+        }
+        status.spec_version = Some(obs.spec_version);
+        status.scheduled_at = obs.scheduled_at;
+
+        let imminent = obs.scheduled_at.map_or(false, |at| {
+            at.saturating_sub(obs.relay_height) <= hold_before
+        });
+        let settling = status.enacted_at.map_or(false, |at| {
+            obs.para_height < at.saturating_add(hold_after)
+                || obs.client_spec_version != obs.spec_version
+        });
+        let holding = imminent || settling;
+        let resumed = status.holding && !holding;
+        if holding && !status.holding {
+            match obs.scheduled_at {
+                Some(at) => {
+                    warn!("Runtime upgrade scheduled at relay block #{at}, holding the submissions")
+                }
+                None => warn!("Runtime upgrade enacted, holding the submissions"),
+            }
+        } else if resumed {
+            info!(
+                "Runtime upgrade settled, resuming the submissions after {} held rounds",
+                status.held_submissions
+            );
+        }
+        status.holding = holding;
+        drop(status);
+        if resumed {
+            self.notify.notify_waiters();
+        }
+        resumed
+    }
+}
+
+pub async fn runtime_upgrade_loop(ctx: WrappedWorkerManagerContext) -> Result<()> {
+    let hold_before = ctx.args.runtime_upgrade_hold_before_blocks;
+    let hold_after = ctx.args.runtime_upgrade_hold_after_blocks;
+    if hold_before == 0 && hold_after == 0 {
+        info!("Runtime upgrade blackout disabled.");
+        return std::future::pending().await;
+    }
+    let mut para_id = None;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let obs = match poll_chain(&ctx, &mut para_id).await {
+            Ok(obs) => obs,
+            Err(err) => {
+                warn!("Failed to check the runtime upgrades: {err:?}");
+                continue;
+            }
+        };
+        if ctx
+            .txm
+            .runtime_upgrade
+            .observe(obs, hold_before, hold_after)
+        {
+            ctx.reconciler.trigger();
+        }
+    }
+}
+
+async fn poll_chain(
+    ctx: &WrappedWorkerManagerContext,
+    para_id: &mut Option<u32>,
+) -> Result<Observation> {
+    let para_api = use_parachain_api!(ctx.dsm, false).ok_or(NoValidDataSource)?;
+    let relay_api = use_relaychain_api!(ctx.dsm, false).ok_or(NoValidDataSource)?;
+    let id = match *para_id {
+        Some(id) => id,
+        None => *para_id.insert(para_api.get_paraid(None).await?),
+    };
+    let spec_version = para_api.rpc().runtime_version(None).await?.spec_version;
+    let para_height = best_number(&para_api).await?;
+    let relay_height = best_number(&relay_api).await?;
+    let scheduled_at = relay_api
+        .storage()
+        .at_latest()
+        .await?
+        .fetch_raw(&map_key("Paras", "FutureCodeUpgrades", &id))
+        .await?
+        .map(|raw| u32::decode(&mut raw.as_slice()))
+        .transpose()?;
+    let client_spec_version = ctx
+        .dsm
+        .clone()
+        .current_parachain_submit_client(None)
+        .await
+        .ok_or(NoValidDataSource)?
+        .client
+        .runtime_version()
+        .spec_version;
+    Ok(Observation {
+        spec_version,
+        para_height,
+        relay_height,
+        scheduled_at,
+        client_spec_version,
+    })
+}
+
+async fn best_number(api: &ChainApi) -> Result<u32> {
+    let header = api
+        .rpc()
+        .header(None)
+        .await?
+        .context("Best header not found")?;
+    Ok(header.number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD_BEFORE: u32 = 5;
+    const HOLD_AFTER: u32 = 3;
+
+    fn obs(
+        spec_version: u32,
+        para_height: u32,
+        relay_height: u32,
+        scheduled_at: Option<u32>,
+    ) -> Observation {
+        Observation {
+            spec_version,
+            para_height,
+            relay_height,
+            scheduled_at,
+            client_spec_version: spec_version,
+        }
+    }
+
+    fn observe(guard: &RuntimeUpgradeGuard, obs: Observation) -> bool {
+        guard.observe(obs, HOLD_BEFORE, HOLD_AFTER)
+    }
+
+    #[test]
+    fn holds_just_before_the_upgrade() {
+        let guard = RuntimeUpgradeGuard::default();
+        assert!(!observe(&guard, obs(1, 100, 1000, None)));
+        assert!(!guard.hold());
+
+        // Scheduled, but not imminent yet.
+        observe(&guard, obs(1, 101, 1000, Some(1010)));
+        assert!(!guard.status().holding);
+        assert!(!guard.hold());
+
+        observe(&guard, obs(1, 102, 1005, Some(1010)));
+        assert!(guard.status().holding);
+        assert!(guard.hold());
+        assert!(guard.hold());
+        assert_eq!(guard.status().held_submissions, 2);
+    }
+
+    #[test]
+    fn settles_after_the_upgrade() {
+        let guard = RuntimeUpgradeGuard::default();
+        observe(&guard, obs(1, 100, 1008, Some(1010)));
+        assert!(guard.status().holding);
+
+        // Enacted at #101, held until #104.
+        assert!(!observe(&guard, obs(2, 101, 1010, None)));
+        let status = guard.status();
+        assert!(status.holding);
+        assert_eq!(status.enacted_at, Some(101));
+        assert_eq!(status.upgrades, 1);
+        assert!(!observe(&guard, obs(2, 103, 1012, None)));
+        assert!(guard.status().holding);
+
+        assert!(observe(&guard, obs(2, 104, 1013, None)));
+        assert!(!guard.status().holding);
+        assert!(!guard.hold());
+        // Resumes only once.
+        assert!(!observe(&guard, obs(2, 105, 1014, None)));
+    }
+
+    #[test]
+    fn holds_until_the_metadata_is_refreshed() {
+        let guard = RuntimeUpgradeGuard::default();
+        observe(&guard, obs(1, 100, 1000, None));
+        let stale_client = |para_height| Observation {
+            client_spec_version: 1,
+            ..obs(2, para_height, 1000 + para_height, None)
+        };
+        assert!(!observe(&guard, stale_client(101)));
+        assert!(guard.status().holding);
+        // Past the settling blocks, but still encoding with the old metadata.
+        assert!(!observe(&guard, stale_client(110)));
+        assert!(guard.status().holding);
+
+        assert!(observe(&guard, obs(2, 111, 1111, None)));
+        assert!(!guard.status().holding);
+    }
+
+    #[test]
+    fn wait_returns_once_resumed() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let guard = std::sync::Arc::new(RuntimeUpgradeGuard::default());
+            guard.wait().await;
+
+            observe(&guard, obs(1, 100, 1008, Some(1010)));
+            let waiting = tokio::spawn({
+                let guard = guard.clone();
+                async move { guard.wait().await }
+            });
+            tokio::task::yield_now().await;
+            assert!(!waiting.is_finished());
+
+            observe(&guard, obs(2, 101, 1010, None));
+            assert!(observe(&guard, obs(2, 104, 1013, None)));
+            tokio::time::timeout(Duration::from_secs(1), waiting)
+                .await
+                .expect("wait did not return once resumed")
+                .unwrap();
+        });
+    }
+}
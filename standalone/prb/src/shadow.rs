@@ -0,0 +1,390 @@
+//! Shadow mode of the data sources.
+//!
+//! Before switching a pool to a new RPC provider or headers cache, the new sources can be put in
+//! the `shadow` section of the data source config. They are connected like the regular sources,
+//! but never used to serve requests: every round the same reads are issued against the current
+//! sources and the shadow ones, and the results are compared. The divergence rates are exposed
+//! through `GET /datasource/shadow`.
+//!
+//! Compared values:
+//! - finalized heights of RPC sources, a lag of a few blocks is tolerated;
+//! - block hashes and headers at a recent height;
+//! - storage changes of a recent parachain block;
+//! - next mq sequences of the senders recently synced by the message loop. The sequences take
+//!   the tx pool of the node into account, so occasional divergences are expected.
+
+use crate::datasource::{
+    DataSource, DataSourceError::NoValidDataSource, DataSourceManager, WrappedDataSourceManager,
+    WrappedHeadersCacheHttpSourceMap, WrappedSubstrateWebSocketSourceMap,
+};
+use crate::{use_parachain_api, use_relaychain_api};
+use anyhow::Result;
+use log::{info, warn};
+use parity_scale_codec::Encode;
+use phala_types::messaging::MessageOrigin;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Finalized heights within this distance are considered as the same.
+const MAX_HEIGHT_LAG: u32 = 3;
+/// The sampled height is picked from the latest blocks, which pruned nodes still keep.
+const SAMPLE_WINDOW: u32 = 64;
+const MAX_SENDERS: usize = 32;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ShadowConfig {
+    #[serde(default)]
+    pub relaychain: Vec<DataSource>,
+    #[serde(default)]
+    pub parachain: Vec<DataSource>,
+    /// Seconds between two rounds of comparison.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct CheckStats {
+    pub total: u64,
+    pub divergent: u64,
+    pub errors: u64,
+    pub divergence_rate: f64,
+    pub last_divergence: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShadowSourceReport {
+    pub chain: String,
+    pub kind: String,
+    pub endpoint: String,
+    pub checks: BTreeMap<String, CheckStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShadowReport {
+    pub interval: u64,
+    pub rounds: u64,
+    pub sources: Vec<ShadowSourceReport>,
+}
+
+#[derive(Clone, Copy)]
+enum Chain {
+    Relaychain,
+    Parachain,
+}
+
+impl Chain {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Chain::Relaychain => "relaychain",
+            Chain::Parachain => "parachain",
+        }
+    }
+}
+
+/// The result of a comparison, `Ok(Some(detail))` on divergence.
+type CheckResult = Result<Option<String>>;
+
+fn compare<T: PartialEq + std::fmt::Debug>(primary: T, shadow: T) -> Option<String> {
+    (primary != shadow).then(|| format!("primary: {primary:?}, shadow: {shadow:?}"))
+}
+
+fn compare_encoded(primary: impl Encode, shadow: impl Encode) -> Option<String> {
+    let primary = primary.encode();
+    let shadow = shadow.encode();
+    (primary != shadow).then(|| {
+        format!(
+            "primary: {} bytes, shadow: {} bytes",
+            primary.len(),
+            shadow.len()
+        )
+    })
+}
+
+pub struct Shadow {
+    config: ShadowConfig,
+    relaychain_rpc_client_map: WrappedSubstrateWebSocketSourceMap,
+    relaychain_headers_cache_map: WrappedHeadersCacheHttpSourceMap,
+    parachain_rpc_client_map: WrappedSubstrateWebSocketSourceMap,
+    parachain_headers_cache_map: WrappedHeadersCacheHttpSourceMap,
+    senders: Mutex<VecDeque<MessageOrigin>>,
+    sources: Mutex<BTreeMap<(&'static str, &'static str, String), BTreeMap<String, CheckStats>>>,
+    rounds: AtomicU64,
+}
+
+impl Shadow {
+    pub fn new(config: ShadowConfig) -> Self {
+        Self {
+            config,
+            relaychain_rpc_client_map: Arc::new(RwLock::new(HashMap::new())),
+            relaychain_headers_cache_map: Arc::new(RwLock::new(HashMap::new())),
+            parachain_rpc_client_map: Arc::new(RwLock::new(HashMap::new())),
+            parachain_headers_cache_map: Arc::new(RwLock::new(HashMap::new())),
+            senders: Mutex::new(VecDeque::new()),
+            sources: Mutex::new(BTreeMap::new()),
+            rounds: AtomicU64::new(0),
+        }
+    }
+
+    /// Connects the shadow sources and starts comparing them with the current ones.
+    pub(crate) fn spawn(self: Arc<Self>, dsm: WrappedDataSourceManager) -> Vec<JoinHandle<()>> {
+        let mut handles = vec![];
+        for (sources, profile, rpc_map, hc_map) in [
+            (
+                &self.config.relaychain,
+                &dsm.relaychain_profile,
+                &self.relaychain_rpc_client_map,
+                &self.relaychain_headers_cache_map,
+            ),
+            (
+                &self.config.parachain,
+                &dsm.parachain_profile,
+                &self.parachain_rpc_client_map,
+                &self.parachain_headers_cache_map,
+            ),
+        ] {
+            for source in sources.clone() {
+                handles.push(match source {
+                    DataSource::SubstrateWebSocketSource(config) => tokio::spawn(
+                        DataSourceManager::subxt_loop(config, profile.clone(), rpc_map.clone()),
+                    ),
+                    DataSource::HeadersCacheHttpSource(config) => tokio::spawn(
+                        DataSourceManager::headers_cache_loop(config, hc_map.clone()),
+                    ),
+                });
+            }
+        }
+        handles.push(tokio::spawn(self.compare_loop(dsm)));
+        handles
+    }
+
+    /// Remembers a sender whose next sequence is compared in the following rounds.
+    pub fn observe_sender(&self, sender: &MessageOrigin) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|s| s != sender);
+        senders.push_front(sender.clone());
+        senders.truncate(MAX_SENDERS);
+    }
+
+    pub fn report(&self) -> ShadowReport {
+        let sources = self
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((chain, kind, endpoint), checks)| ShadowSourceReport {
+                chain: chain.to_string(),
+                kind: kind.to_string(),
+                endpoint: endpoint.clone(),
+                checks: checks.clone(),
+            })
+            .collect();
+        ShadowReport {
+            interval: self.config.interval,
+            rounds: self.rounds.load(Ordering::Relaxed),
+            sources,
+        }
+    }
+
+    fn record(
+        &self,
+        chain: Chain,
+        kind: &'static str,
+        endpoint: &str,
+        check: &str,
+        result: CheckResult,
+    ) {
+        let mut sources = self.sources.lock().unwrap();
+        let stats = sources
+            .entry((chain.as_str(), kind, endpoint.to_string()))
+            .or_default()
+            .entry(check.to_string())
+            .or_default();
+        stats.total += 1;
+        match result {
+            Ok(None) => {}
+            Ok(Some(detail)) => {
+                warn!(
+                    "Shadow {} source {endpoint} diverged on {check}: {detail}",
+                    chain.as_str()
+                );
+                stats.divergent += 1;
+                stats.last_divergence = Some(detail);
+            }
+            Err(err) => {
+                stats.errors += 1;
+                stats.last_error = Some(err.to_string());
+            }
+        }
+        let compared = stats.total - stats.errors;
+        if compared > 0 {
+            stats.divergence_rate = stats.divergent as f64 / compared as f64;
+        }
+    }
+
+    async fn compare_loop(self: Arc<Self>, dsm: WrappedDataSourceManager) {
+        info!(
+            "Comparing shadow data sources every {}s",
+            self.config.interval
+        );
+        loop {
+            sleep(Duration::from_secs(self.config.interval.max(1))).await;
+            if let Err(err) = self.compare_relaychain(&dsm).await {
+                warn!("Shadow comparison of relaychain skipped: {err}");
+            }
+            if let Err(err) = self.compare_parachain(&dsm).await {
+                warn!("Shadow comparison of parachain skipped: {err}");
+            }
+            self.rounds.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Errors of the current sources skip the round, only the shadow ones are recorded.
+    async fn compare_relaychain(&self, dsm: &WrappedDataSourceManager) -> Result<()> {
+        let chain = Chain::Relaychain;
+        let primary = use_relaychain_api!(dsm, false).ok_or(NoValidDataSource)?;
+        let primary_height = primary.latest_finalized_block_number().await?;
+        let sample = sample_height(primary_height);
+        let primary_hash = pherry::get_header_hash(&primary, Some(sample)).await?;
+
+        let instances = self.relaychain_rpc_client_map.read().await.clone();
+        for instance in instances.values() {
+            let client = &instance.client;
+            let result = async {
+                let height = client.latest_finalized_block_number().await?;
+                anyhow::Ok(compare_heights(primary_height, height))
+            };
+            self.record(
+                chain,
+                "rpc",
+                &instance.endpoint,
+                "finalized_height",
+                result.await,
+            );
+            let result = async {
+                let hash = pherry::get_header_hash(client, Some(sample)).await?;
+                anyhow::Ok(compare(primary_hash, hash))
+            };
+            self.record(chain, "rpc", &instance.endpoint, "block_hash", result.await);
+        }
+
+        let instances = self.relaychain_headers_cache_map.read().await.clone();
+        if instances.is_empty() {
+            return Ok(());
+        }
+        let (primary_header, _) = pherry::get_header_at(&primary, Some(sample)).await?;
+        for instance in instances.values() {
+            let result = async {
+                let info = instance.client.get_header(sample).await?;
+                anyhow::Ok(compare_encoded(&primary_header, &info.header))
+            };
+            self.record(
+                chain,
+                "headers_cache",
+                &instance.endpoint,
+                "header",
+                result.await,
+            );
+        }
+        Ok(())
+    }
+
+    async fn compare_parachain(&self, dsm: &WrappedDataSourceManager) -> Result<()> {
+        let chain = Chain::Parachain;
+        let primary = use_parachain_api!(dsm, false).ok_or(NoValidDataSource)?;
+        let primary_height = primary.latest_finalized_block_number().await?;
+        let sample = sample_height(primary_height);
+        let primary_hash = pherry::get_header_hash(&primary, Some(sample)).await?;
+        let primary_changes = dsm.clone().fetch_storage_changes(sample, sample).await?;
+        let senders: Vec<_> = self.senders.lock().unwrap().iter().cloned().collect();
+        let mut primary_sequences = vec![];
+        for sender in &senders {
+            let sequence = pherry::chain_client::mq_next_sequence(&primary, sender).await?;
+            primary_sequences.push(sequence);
+        }
+
+        let instances = self.parachain_rpc_client_map.read().await.clone();
+        for instance in instances.values() {
+            let client = &instance.client;
+            let endpoint = &instance.endpoint;
+            let result = async {
+                let height = client.latest_finalized_block_number().await?;
+                anyhow::Ok(compare_heights(primary_height, height))
+            };
+            self.record(chain, "rpc", endpoint, "finalized_height", result.await);
+            let result = async {
+                let hash = pherry::get_header_hash(client, Some(sample)).await?;
+                anyhow::Ok(compare(primary_hash, hash))
+            };
+            self.record(chain, "rpc", endpoint, "block_hash", result.await);
+            let result = async {
+                let changes = pherry::fetch_storage_changes(client, None, sample, sample).await?;
+                anyhow::Ok(compare_encoded(&primary_changes, &changes))
+            };
+            self.record(chain, "rpc", endpoint, "storage_changes", result.await);
+            for (sender, primary_sequence) in senders.iter().zip(&primary_sequences) {
+                let result = async {
+                    let sequence = pherry::chain_client::mq_next_sequence(client, sender).await?;
+                    anyhow::Ok(
+                        compare(*primary_sequence, sequence)
+                            .map(|detail| format!("next sequence of {sender}, {detail}")),
+                    )
+                };
+                self.record(chain, "rpc", endpoint, "next_sequence", result.await);
+            }
+        }
+
+        let instances = self.parachain_headers_cache_map.read().await.clone();
+        if instances.is_empty() {
+            return Ok(());
+        }
+        let primary_headers = dsm.clone().get_para_headers(sample, sample).await?;
+        for instance in instances.values() {
+            let result = async {
+                let headers = instance.client.get_parachain_headers(sample, 1).await?;
+                anyhow::Ok(compare_encoded(&primary_headers, &headers))
+            };
+            self.record(
+                chain,
+                "headers_cache",
+                &instance.endpoint,
+                "header",
+                result.await,
+            );
+            let result = async {
+                let changes = instance.client.get_storage_changes(sample, 1).await?;
+                anyhow::Ok(compare_encoded(&primary_changes, &changes))
+            };
+            self.record(
+                chain,
+                "headers_cache",
+                &instance.endpoint,
+                "storage_changes",
+                result.await,
+            );
+        }
+        Ok(())
+    }
+}
+
+fn sample_height(finalized: u32) -> u32 {
+    // Leave a few blocks for the shadow sources to catch up.
+    let top = finalized.saturating_sub(MAX_HEIGHT_LAG);
+    top.saturating_sub(rand::thread_rng().gen_range(0..SAMPLE_WINDOW))
+}
+
+fn compare_heights(primary: u32, shadow: u32) -> Option<String> {
+    (primary.abs_diff(shadow) > MAX_HEIGHT_LAG)
+        .then(|| format!("primary: #{primary}, shadow: #{shadow}"))
+}
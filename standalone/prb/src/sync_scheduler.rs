@@ -0,0 +1,124 @@
+//! Staggered scheduling of the RPC-heavy operations of the workers.
+//!
+//! The workers following the chaintip are all triggered by the same new blocks, so without
+//! scheduling their syncs and egress message polls hit the node and the pRuntimes at the same
+//! moment of every block interval. Each of these operations is delayed by a random jitter within
+//! the window instead, capped by a deadline so that workers lagging behind are never held back,
+//! then each of its attempts waits for a slot of the budget of concurrent RPC-heavy operations.
+
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Workers further behind the chaintip than this are catching up and not jittered.
+const FOLLOWING_LAG_BLOCKS: u32 = 2;
+/// Half of the parachain block time, the operations of a block must start within it.
+const FOLLOWING_DEADLINE: Duration = Duration::from_secs(6);
+
+pub struct SyncScheduler {
+    jitter: Duration,
+    budget: Option<Arc<Semaphore>>,
+}
+
+impl SyncScheduler {
+    /// A zero `max_concurrent_ops` leaves the operations unlimited.
+    pub fn new(jitter: Duration, max_concurrent_ops: usize) -> Self {
+        Self {
+            jitter,
+            budget: (max_concurrent_ops > 0).then(|| Arc::new(Semaphore::new(max_concurrent_ops))),
+        }
+    }
+
+    /// The deadline of the next operation of a worker `lag_blocks` behind the parachain tip.
+    pub fn deadline(lag_blocks: u32) -> Duration {
+        if lag_blocks > FOLLOWING_LAG_BLOCKS {
+            Duration::ZERO
+        } else {
+            FOLLOWING_DEADLINE
+        }
+    }
+
+    /// A random offset within `period`, to spread the periodic polls of the workers.
+    pub fn phase(&self, period: Duration) -> Duration {
+        if period.is_zero() {
+            return Duration::ZERO;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..period)
+    }
+
+    /// Waits for a random delay within the jitter window but not longer than `deadline`. An
+    /// operation is jittered once, not again on its retries or its follow-up requests.
+    pub async fn jitter(&self, deadline: Duration) {
+        let max_delay = self.jitter.min(deadline);
+        if !max_delay.is_zero() {
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..=max_delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Waits for a slot of the budget, held until the returned permit is dropped. The permit must
+    /// be dropped before waiting to retry, so that a failing worker doesn't starve the others.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.budget {
+            Some(budget) => budget.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn deadline_is_zero_when_catching_up() {
+        assert_eq!(SyncScheduler::deadline(0), FOLLOWING_DEADLINE);
+        assert_eq!(
+            SyncScheduler::deadline(FOLLOWING_LAG_BLOCKS),
+            FOLLOWING_DEADLINE
+        );
+        assert_eq!(
+            SyncScheduler::deadline(FOLLOWING_LAG_BLOCKS + 1),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn jitter_is_capped_by_deadline() {
+        let scheduler = SyncScheduler::new(Duration::from_secs(60), 0);
+        let start = std::time::Instant::now();
+        block_on(scheduler.jitter(Duration::ZERO));
+        block_on(scheduler.jitter(Duration::from_millis(20)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn budget_is_released_with_permit() {
+        let scheduler = SyncScheduler::new(Duration::ZERO, 1);
+        block_on(async {
+            let permit = scheduler.acquire().await;
+            assert!(permit.is_some());
+            let blocked =
+                tokio::time::timeout(Duration::from_millis(20), scheduler.acquire()).await;
+            assert!(blocked.is_err());
+            drop(permit);
+            let again = tokio::time::timeout(Duration::from_millis(20), scheduler.acquire()).await;
+            assert!(matches!(again, Ok(Some(_))));
+        });
+    }
+
+    #[test]
+    fn unlimited_budget_has_no_permit() {
+        let scheduler = SyncScheduler::new(Duration::ZERO, 0);
+        assert!(block_on(scheduler.acquire()).is_none());
+    }
+}
@@ -0,0 +1,305 @@
+//! A minimal terminal for `prb-top`: raw mode and the alternate screen on the termios of the tty,
+//! the keys read from stdin and the lines drawn with ANSI styles.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bold: bool,
+    pub reversed: bool,
+}
+
+impl Style {
+    pub fn fg(color: Color) -> Self {
+        Self {
+            fg: Some(color),
+            ..Default::default()
+        }
+    }
+
+    pub fn bold() -> Self {
+        Self {
+            bold: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn reversed(self) -> Self {
+        Self {
+            reversed: true,
+            ..self
+        }
+    }
+
+    /// The SGR sequence switching from the default style to this one.
+    fn sgr(&self) -> String {
+        let mut codes = vec![];
+        if self.bold {
+            codes.push("1");
+        }
+        if self.reversed {
+            codes.push("7");
+        }
+        match self.fg {
+            Some(Color::Red) => codes.push("31"),
+            Some(Color::Green) => codes.push("32"),
+            Some(Color::Yellow) => codes.push("33"),
+            None => {}
+        }
+        if codes.is_empty() {
+            String::new()
+        } else {
+            format!("\x1b[{}m", codes.join(";"))
+        }
+    }
+}
+
+/// A line of styled text.
+#[derive(Default)]
+pub struct Line(Vec<(String, Style)>);
+
+impl Line {
+    pub fn raw(text: impl Into<String>) -> Self {
+        Self::styled(text, Style::default())
+    }
+
+    pub fn styled(text: impl Into<String>, style: Style) -> Self {
+        Self(vec![(text.into(), style)])
+    }
+
+    pub fn push(&mut self, text: impl Into<String>, style: Style) {
+        self.0.push((text.into(), style));
+    }
+
+    /// Renders the line into exactly `width` columns, cutting it or padding it with spaces.
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut left = width;
+        for (text, style) in &self.0 {
+            let text = cut(text, left);
+            left -= text.chars().count();
+            if *style == Style::default() {
+                out.push_str(&text);
+            } else {
+                out.push_str(&style.sgr());
+                out.push_str(&text);
+                out.push_str(RESET);
+            }
+        }
+        out.extend(std::iter::repeat(' ').take(left));
+        out
+    }
+}
+
+/// Cuts the text to at most `width` characters, replacing the control characters which would move
+/// the cursor.
+fn cut(text: &str, width: usize) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(width)
+        .collect()
+}
+
+/// Cuts the text to `width` characters or pads it with spaces.
+pub fn fit(text: &str, width: usize) -> String {
+    let mut out = cut(text, width);
+    let len = out.chars().count();
+    out.extend(std::iter::repeat(' ').take(width - len));
+    out
+}
+
+/// Draws the lines in a box of `width` by `height`, with the title on the top border.
+pub fn bordered(title: &str, lines: &[Line], width: usize, height: usize) -> Vec<String> {
+    if width < 2 || height < 2 {
+        return vec![];
+    }
+    let inner = width - 2;
+    let title = cut(title, inner);
+    let mut out = vec![format!(
+        "┌{title}{}┐",
+        "─".repeat(inner - title.chars().count())
+    )];
+    let blank = Line::default();
+    for i in 0..height - 2 {
+        let line = lines.get(i).unwrap_or(&blank);
+        out.push(format!("│{}│", line.render(inner)));
+    }
+    out.push(format!("└{}┘", "─".repeat(inner)));
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Tab,
+    BackTab,
+    Left,
+    Right,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Esc,
+    Unknown,
+}
+
+/// Parses a key from the bytes of a read from the tty.
+pub fn parse_key(input: &[u8]) -> Key {
+    match input {
+        [0x1b] => Key::Esc,
+        [0x1b, b'[' | b'O', rest @ ..] => match rest {
+            [b'A'] => Key::Up,
+            [b'B'] => Key::Down,
+            [b'C'] => Key::Right,
+            [b'D'] => Key::Left,
+            [b'Z'] => Key::BackTab,
+            [b'5', b'~'] => Key::PageUp,
+            [b'6', b'~'] => Key::PageDown,
+            _ => Key::Unknown,
+        },
+        [b'\t'] => Key::Tab,
+        _ => {
+            let mut chars = std::str::from_utf8(input).unwrap_or_default().chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !c.is_control() => Key::Char(c),
+                _ => Key::Unknown,
+            }
+        }
+    }
+}
+
+/// The tty in raw mode showing the alternate screen, restored when dropped.
+pub struct Terminal {
+    original: libc::termios,
+    out: io::Stdout,
+}
+
+impl Terminal {
+    pub fn enter() -> io::Result<Self> {
+        // SAFETY: the termios is plain data filled by tcgetattr.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut terminal = Self {
+            original,
+            out: io::stdout(),
+        };
+        // Enter the alternate screen and hide the cursor.
+        terminal.out.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        terminal.out.flush()?;
+        Ok(terminal)
+    }
+
+    /// The columns and rows of the tty.
+    pub fn size(&self) -> (usize, usize) {
+        // SAFETY: the winsize is plain data filled by the ioctl.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if ret != 0 || size.ws_col == 0 || size.ws_row == 0 {
+            return (80, 24);
+        }
+        (size.ws_col as usize, size.ws_row as usize)
+    }
+
+    /// Draws the lines from the top left of the screen, clearing what is left of the last frame.
+    pub fn draw(&mut self, lines: &[String]) -> io::Result<()> {
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                frame.push_str("\r\n");
+            }
+            frame.push_str(line);
+            frame.push_str(RESET);
+            frame.push_str("\x1b[K");
+        }
+        frame.push_str("\x1b[J");
+        self.out.write_all(frame.as_bytes())?;
+        self.out.flush()
+    }
+
+    /// Waits up to `timeout` for a key.
+    pub fn poll_key(&self, timeout: Duration) -> io::Result<Option<Key>> {
+        let mut fds = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+        // Read the fd directly, the buffer of `io::stdin` would hold back the rest of a sequence.
+        let mut buf = [0u8; 16];
+        let len = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(parse_key(&buf[..len as usize])))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Show the cursor and leave the alternate screen.
+        _ = self.out.write_all(b"\x1b[?25h\x1b[?1049l");
+        _ = self.out.flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_key(b"q"), Key::Char('q'));
+        assert_eq!(parse_key(b"\x1b"), Key::Esc);
+        assert_eq!(parse_key(b"\t"), Key::Tab);
+        assert_eq!(parse_key(b"\x1b[Z"), Key::BackTab);
+        assert_eq!(parse_key(b"\x1b[A"), Key::Up);
+        assert_eq!(parse_key(b"\x1bOB"), Key::Down);
+        assert_eq!(parse_key(b"\x1b[6~"), Key::PageDown);
+        assert_eq!(parse_key(b"\x1b[15~"), Key::Unknown);
+        assert_eq!(parse_key(b"\r"), Key::Unknown);
+    }
+
+    #[test]
+    fn lines_fill_the_width() {
+        assert_eq!(Line::raw("abc").render(5), "abc  ");
+        assert_eq!(Line::raw("a\nbcdef").render(4), "a bc");
+        let mut line = Line::raw("ab");
+        line.push("cd", Style::fg(Color::Red));
+        assert_eq!(line.render(3), "ab\x1b[31mc\x1b[0m");
+    }
+
+    #[test]
+    fn boxes_have_the_given_size() {
+        let lines = bordered("t", &[Line::raw("hello")], 6, 4);
+        assert_eq!(lines, ["┌t───┐", "│hell│", "│    │", "└────┘"]);
+    }
+}
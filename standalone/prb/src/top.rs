@@ -0,0 +1,618 @@
+//! `prb-top`, a terminal dashboard of a running prb.
+//!
+//! It polls the management API and shows the workers, the offchain message senders, the recent
+//! errors and the transactions in tables, for the operators working over SSH without Grafana.
+//!
+//! Keys: `Tab`/`←`/`→` or `1`-`4` switch the views, `↑`/`↓` or `j`/`k` move the selection, `r`
+//! refreshes immediately and `q`/`Esc` quits.
+
+use crate::api::{SendersResponse, TxStatusResponse, WmStatusResponse, WorkerStatusResponse};
+use crate::cli::TopCliArgs;
+use crate::public_api::state_label;
+use crate::term::{bordered, fit, Color, Key, Line, Style, Terminal};
+use crate::tx::{Transaction, TransactionState};
+use crate::worker::WorkerLifecycleState;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::de::DeserializeOwned;
+use std::time::{Duration, Instant};
+
+/// Window of the finished transactions counted in the throughput.
+const THROUGHPUT_WINDOW_MINS: i64 = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The lines taken by the summary, the tabs and the status bar.
+const CHROME_HEIGHT: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Workers,
+    Senders,
+    Errors,
+    Transactions,
+}
+
+impl View {
+    const ALL: [View; 4] = [
+        View::Workers,
+        View::Senders,
+        View::Errors,
+        View::Transactions,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            View::Workers => "1 Workers",
+            View::Senders => "2 Senders",
+            View::Errors => "3 Errors",
+            View::Transactions => "4 Transactions",
+        }
+    }
+
+    fn index(self) -> usize {
+        Self::ALL
+            .iter()
+            .position(|v| *v == self)
+            .unwrap_or_default()
+    }
+}
+
+struct ErrorEntry {
+    at: Option<DateTime<Utc>>,
+    source: String,
+    message: String,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    wm: Option<WmStatusResponse>,
+    workers: Option<WorkerStatusResponse>,
+    senders: Option<SendersResponse>,
+    txs: Option<TxStatusResponse>,
+    fetched_at: Option<DateTime<Utc>>,
+    /// The error of the last poll, shown in the status bar.
+    error: Option<String>,
+}
+
+/// A row of a table.
+struct Row {
+    cells: Vec<String>,
+    style: Style,
+}
+
+impl Row {
+    fn new(cells: Vec<String>) -> Self {
+        Self {
+            cells,
+            style: Style::default(),
+        }
+    }
+
+    fn style(self, style: Style) -> Self {
+        Self { style, ..self }
+    }
+}
+
+struct App {
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    view: View,
+    /// The selected row of the table and the first row shown.
+    selected: Option<usize>,
+    offset: usize,
+    snapshot: Snapshot,
+    last_poll: Option<Instant>,
+}
+
+impl App {
+    fn new(args: TopCliArgs) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            client,
+            url: args.url.trim_end_matches('/').to_string(),
+            interval: Duration::from_secs(args.interval.max(1)),
+            view: View::Workers,
+            selected: Some(0),
+            offset: 0,
+            snapshot: Snapshot::default(),
+            last_poll: None,
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{path}", self.url);
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to request {url}"))?;
+        if !resp.status().is_success() {
+            anyhow::bail!("{} returned {}", url, resp.status());
+        }
+        Ok(resp.json().await?)
+    }
+
+    async fn poll(&mut self) {
+        self.last_poll = Some(Instant::now());
+        let (wm, workers, senders, txs) = tokio::join!(
+            self.get::<WmStatusResponse>("/wm/status"),
+            self.get::<WorkerStatusResponse>("/workers/status"),
+            self.get::<SendersResponse>("/messages/senders"),
+            self.get::<TxStatusResponse>("/tx/status"),
+        );
+        let mut errors = vec![];
+        macro_rules! update {
+            ($field: ident) => {
+                match $field {
+                    Ok(value) => self.snapshot.$field = Some(value),
+                    Err(err) => errors.push(format!("{err:#}")),
+                }
+            };
+        }
+        update!(wm);
+        update!(workers);
+        update!(senders);
+        update!(txs);
+        self.snapshot.fetched_at = Some(Utc::now());
+        self.snapshot.error = errors.into_iter().next();
+    }
+
+    fn should_poll(&self) -> bool {
+        self.last_poll
+            .map(|at| at.elapsed() >= self.interval)
+            .unwrap_or(true)
+    }
+
+    fn row_count(&self) -> usize {
+        match self.view {
+            View::Workers => self
+                .snapshot
+                .workers
+                .as_ref()
+                .map(|w| w.workers.len())
+                .unwrap_or_default(),
+            View::Senders => self
+                .snapshot
+                .senders
+                .as_ref()
+                .map(|s| s.senders.len())
+                .unwrap_or_default(),
+            View::Errors => self.errors().len(),
+            View::Transactions => self.transactions().len(),
+        }
+    }
+
+    fn select_view(&mut self, view: View) {
+        self.view = view;
+        self.selected = Some(0);
+        self.offset = 0;
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let count = self.row_count();
+        if count == 0 {
+            self.selected = None;
+            return;
+        }
+        let current = self.selected.unwrap_or_default() as isize;
+        let next = (current + delta).clamp(0, count as isize - 1);
+        self.selected = Some(next as usize);
+    }
+
+    /// Handles a key, returns false to quit.
+    fn handle_key(&mut self, key: Key) -> bool {
+        let index = self.view.index();
+        match key {
+            Key::Char('q') | Key::Esc => return false,
+            Key::Tab | Key::Right => self.select_view(View::ALL[(index + 1) % View::ALL.len()]),
+            Key::BackTab | Key::Left => {
+                self.select_view(View::ALL[(index + View::ALL.len() - 1) % View::ALL.len()])
+            }
+            Key::Char(c @ '1'..='4') => self.select_view(View::ALL[c as usize - '1' as usize]),
+            Key::Down | Key::Char('j') => self.move_selection(1),
+            Key::Up | Key::Char('k') => self.move_selection(-1),
+            Key::PageDown => self.move_selection(10),
+            Key::PageUp => self.move_selection(-10),
+            Key::Char('r') => self.last_poll = None,
+            _ => {}
+        }
+        true
+    }
+
+    /// The transactions, the latest first.
+    fn transactions(&self) -> Vec<&Transaction> {
+        let Some(txs) = &self.snapshot.txs else {
+            return vec![];
+        };
+        let mut all = txs
+            .running_txs
+            .iter()
+            .chain(txs.pending_txs.iter())
+            .chain(txs.past_txs.iter())
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| b.id.cmp(&a.id));
+        all
+    }
+
+    /// The errors of the workers, senders and transactions, the latest first.
+    fn errors(&self) -> Vec<ErrorEntry> {
+        let mut errors = vec![];
+        if let Some(workers) = &self.snapshot.workers {
+            for status in &workers.workers {
+                if let WorkerLifecycleState::HasError(message) = &status.state {
+                    errors.push(ErrorEntry {
+                        at: None,
+                        source: format!("worker {}", status.worker.name),
+                        message: message.clone(),
+                    });
+                }
+            }
+        }
+        if let Some(senders) = &self.snapshot.senders {
+            for sender in &senders.senders {
+                for message in &sender.last_errors {
+                    errors.push(ErrorEntry {
+                        at: None,
+                        source: format!("sender {}", sender.sender),
+                        message: message.clone(),
+                    });
+                }
+            }
+        }
+        for tx in self.transactions() {
+            if let TransactionState::Error(err) = &tx.state {
+                errors.push(ErrorEntry {
+                    at: Some(err.updated_at),
+                    source: format!("tx #{} {}", tx.id, tx.desc),
+                    message: err.message.clone(),
+                });
+            }
+        }
+        // The current errors without a time first, then the failed transactions.
+        errors.sort_by(|a, b| b.at.is_none().cmp(&a.at.is_none()).then(b.at.cmp(&a.at)));
+        errors
+    }
+
+    /// The number of transactions succeeded and failed in the throughput window.
+    fn throughput(&self) -> (usize, usize) {
+        let since = Utc::now() - ChronoDuration::minutes(THROUGHPUT_WINDOW_MINS);
+        let mut succeeded = 0;
+        let mut failed = 0;
+        if let Some(txs) = &self.snapshot.txs {
+            for tx in &txs.past_txs {
+                match &tx.state {
+                    TransactionState::Success(s) if s.updated_at >= since => succeeded += 1,
+                    TransactionState::Error(e) if e.updated_at >= since => failed += 1,
+                    _ => {}
+                }
+            }
+        }
+        (succeeded, failed)
+    }
+
+    /// Draws the screen of `width` by `height` into lines.
+    fn draw(&mut self, width: usize, height: usize) -> Vec<String> {
+        let mut screen = self.draw_summary(width);
+        let mut tabs = Line::default();
+        for (i, view) in View::ALL.iter().enumerate() {
+            if i > 0 {
+                tabs.push(" │ ", Style::default());
+            }
+            let style = if *view == self.view {
+                Style::default().reversed()
+            } else {
+                Style::default()
+            };
+            tabs.push(view.title(), style);
+        }
+        screen.extend(bordered("", &[tabs], width, 3));
+        let table_height = height.saturating_sub(CHROME_HEIGHT).max(3);
+        screen.extend(match self.view {
+            View::Workers => self.draw_workers(width, table_height),
+            View::Senders => self.draw_senders(width, table_height),
+            View::Errors => self.draw_errors(width, table_height),
+            View::Transactions => self.draw_transactions(width, table_height),
+        });
+        let status = match &self.snapshot.error {
+            Some(err) => Line::styled(err.clone(), Style::fg(Color::Red)),
+            None => Line::raw(format!(
+                "{}  updated {}  q: quit  tab: switch view  j/k: select  r: refresh",
+                self.url,
+                self.snapshot
+                    .fetched_at
+                    .map(|at| at.format("%H:%M:%S").to_string())
+                    .unwrap_or_else(|| "never".into()),
+            )),
+        };
+        screen.push(status.render(width));
+        screen.truncate(height);
+        screen
+    }
+
+    fn draw_summary(&self, width: usize) -> Vec<String> {
+        let (mut working, mut syncing, mut failing, mut total) = (0, 0, 0, 0);
+        if let Some(workers) = &self.snapshot.workers {
+            total = workers.workers.len();
+            for status in &workers.workers {
+                match status.state {
+                    WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking => {
+                        working += 1
+                    }
+                    WorkerLifecycleState::Synchronizing => syncing += 1,
+                    WorkerLifecycleState::HasError(_) => failing += 1,
+                    _ => {}
+                }
+            }
+        }
+        let pending_messages = self
+            .snapshot
+            .senders
+            .as_ref()
+            .map(|s| {
+                s.senders
+                    .iter()
+                    .map(|s| s.pending_messages.len())
+                    .sum::<usize>()
+            })
+            .unwrap_or_default();
+        let stalled = self
+            .snapshot
+            .senders
+            .as_ref()
+            .map(|s| s.senders.iter().filter(|s| s.stalled).count())
+            .unwrap_or_default();
+        let (succeeded, failed) = self.throughput();
+        let (running, pending) = self
+            .snapshot
+            .txs
+            .as_ref()
+            .map(|txs| (txs.running_txs.len(), txs.pending_txs.len()))
+            .unwrap_or_default();
+        let revision = self
+            .snapshot
+            .wm
+            .as_ref()
+            .map(|wm| wm.git_revision.clone())
+            .unwrap_or_default();
+        let lines = [
+            Line::raw(format!(
+                "workers: {total} total, {working} working, {syncing} syncing, {failing} failing    \
+                 messages: {pending_messages} pending, {stalled} stalled senders"
+            )),
+            Line::raw(format!(
+                "txs: {running} running, {pending} pending, \
+                 {succeeded} ok / {failed} failed in the last {THROUGHPUT_WINDOW_MINS} min    \
+                 prb {revision}"
+            )),
+        ];
+        bordered("prb-top", &lines, width, 4)
+    }
+
+    /// Draws the rows in a box of `width` by `height` under the header, scrolled to the selected
+    /// row. The columns are as wide as `widths`, the last one takes the rest of the width.
+    fn render_table(
+        &mut self,
+        width: usize,
+        height: usize,
+        header: &[&str],
+        rows: Vec<Row>,
+        widths: &[usize],
+    ) -> Vec<String> {
+        let inner = width.saturating_sub(2);
+        let visible = height.saturating_sub(3).max(1);
+        if let Some(selected) = self.selected {
+            if selected < self.offset {
+                self.offset = selected;
+            } else if selected >= self.offset + visible {
+                self.offset = selected + 1 - visible;
+            }
+        }
+        let header = header.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let mut lines = vec![Line::styled(columns(&header, widths, inner), Style::bold())];
+        for (i, row) in rows.into_iter().enumerate().skip(self.offset).take(visible) {
+            let style = if Some(i) == self.selected {
+                row.style.reversed()
+            } else {
+                row.style
+            };
+            lines.push(Line::styled(columns(&row.cells, widths, inner), style));
+        }
+        bordered("", &lines, width, height)
+    }
+
+    fn draw_workers(&mut self, width: usize, height: usize) -> Vec<String> {
+        let mut rows = vec![];
+        if let Some(workers) = &self.snapshot.workers {
+            let mut workers = workers.workers.iter().collect::<Vec<_>>();
+            workers.sort_by(|a, b| a.worker.name.cmp(&b.worker.name));
+            for status in workers {
+                let info = status.phactory_info.as_ref();
+                let style = match status.state {
+                    WorkerLifecycleState::HasError(_) => Style::fg(Color::Red),
+                    WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking => {
+                        Style::fg(Color::Green)
+                    }
+                    _ => Style::default(),
+                };
+                rows.push(
+                    Row::new(vec![
+                        status.worker.name.clone(),
+                        status.worker.pid.map(|p| p.to_string()).unwrap_or_default(),
+                        state_label(&status.state).to_string(),
+                        num(info.map(|i| i.headernum)),
+                        num(info.map(|i| i.para_headernum)),
+                        num(info.map(|i| i.blocknum)),
+                        num(info.map(|i| i.pending_messages)),
+                        status.last_message.clone(),
+                    ])
+                    .style(style),
+                );
+            }
+        }
+        self.render_table(
+            width,
+            height,
+            &[
+                "Name", "Pool", "State", "Header", "Para", "Block", "Pending", "Message",
+            ],
+            rows,
+            &[20, 6, 18, 10, 10, 10, 8, 20],
+        )
+    }
+
+    fn draw_senders(&mut self, width: usize, height: usize) -> Vec<String> {
+        let mut rows = vec![];
+        if let Some(senders) = &self.snapshot.senders {
+            for sender in &senders.senders {
+                let style = if sender.stalled {
+                    Style::fg(Color::Red)
+                } else {
+                    Style::default()
+                };
+                rows.push(
+                    Row::new(vec![
+                        sender.sender.clone(),
+                        sender.worker_id.clone(),
+                        sender.node_next_sequence.to_string(),
+                        sender.pending_messages.len().to_string(),
+                        if sender.confirming { "yes" } else { "" }.to_string(),
+                        if sender.stalled { "STALLED" } else { "" }.to_string(),
+                        sender.last_errors.last().cloned().unwrap_or_default(),
+                    ])
+                    .style(style),
+                );
+            }
+        }
+        self.render_table(
+            width,
+            height,
+            &[
+                "Sender",
+                "Worker",
+                "Next seq",
+                "Pending",
+                "Confirming",
+                "Stalled",
+                "Last error",
+            ],
+            rows,
+            &[30, 36, 10, 8, 10, 8, 20],
+        )
+    }
+
+    fn draw_errors(&mut self, width: usize, height: usize) -> Vec<String> {
+        let rows = self
+            .errors()
+            .into_iter()
+            .map(|err| {
+                Row::new(vec![
+                    err.at
+                        .map(|at| at.format("%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_else(|| "now".into()),
+                    err.source,
+                    err.message,
+                ])
+            })
+            .collect();
+        self.render_table(
+            width,
+            height,
+            &["Time", "Source", "Error"],
+            rows,
+            &[14, 40, 20],
+        )
+    }
+
+    fn draw_transactions(&mut self, width: usize, height: usize) -> Vec<String> {
+        let rows = self
+            .transactions()
+            .into_iter()
+            .map(|tx| {
+                let (state, style) = match &tx.state {
+                    TransactionState::Pending => ("Pending", Style::default()),
+                    TransactionState::Running => ("Running", Style::fg(Color::Yellow)),
+                    TransactionState::Success(_) => ("Success", Style::fg(Color::Green)),
+                    TransactionState::Error(_) => ("Error", Style::fg(Color::Red)),
+                };
+                Row::new(vec![
+                    tx.id.to_string(),
+                    tx.pid.to_string(),
+                    state.to_string(),
+                    tx.created_at.format("%m-%d %H:%M:%S").to_string(),
+                    tx.desc.clone(),
+                ])
+                .style(style)
+            })
+            .collect();
+        self.render_table(
+            width,
+            height,
+            &["Id", "Pool", "State", "Created", "Description"],
+            rows,
+            &[8, 6, 8, 14, 20],
+        )
+    }
+}
+
+fn num<T: ToString>(n: Option<T>) -> String {
+    n.map(|n| n.to_string()).unwrap_or_default()
+}
+
+/// Lays the cells out in columns separated by a space.
+fn columns(cells: &[String], widths: &[usize], width: usize) -> String {
+    let mut out = String::new();
+    let mut left = width;
+    for (i, cell) in cells.iter().enumerate() {
+        if left == 0 {
+            break;
+        }
+        if i > 0 {
+            out.push(' ');
+            left -= 1;
+        }
+        let column = match widths.get(i) {
+            Some(w) if i + 1 < cells.len() => (*w).min(left),
+            _ => left,
+        };
+        out.push_str(&fit(cell, column));
+        left -= column;
+    }
+    fit(&out, width)
+}
+
+pub async fn cli_main(args: TopCliArgs) -> Result<()> {
+    let mut app = App::new(args)?;
+    let mut terminal = Terminal::enter().context("Failed to set up the terminal")?;
+    run(&mut terminal, &mut app).await
+}
+
+async fn run(terminal: &mut Terminal, app: &mut App) -> Result<()> {
+    loop {
+        if app.should_poll() {
+            app.poll().await;
+            app.move_selection(0);
+        }
+        let (width, height) = terminal.size();
+        terminal.draw(&app.draw(width, height))?;
+        if let Some(key) = terminal.poll_key(Duration::from_millis(200))? {
+            if !app.handle_key(key) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_fill_the_width() {
+        let cells = ["ab".to_string(), "cdefgh".to_string(), "tail".to_string()];
+        assert_eq!(columns(&cells, &[3, 4], 14), "ab  cdef tail ");
+        assert_eq!(columns(&cells, &[3, 4], 6), "ab  cd");
+    }
+}
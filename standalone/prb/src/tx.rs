@@ -1,34 +1,41 @@
 use crate::api::TxStatusResponse;
 use crate::datasource::WrappedDataSourceManager;
+use crate::jobs::JobQueue;
+use crate::key_provider::Signers;
 pub use crate::khala;
 use crate::khala::runtime_types::khala_parachain_runtime::ProxyType;
 use crate::khala::utility::events::ItemFailed;
+use crate::maintenance::MaintenanceWindows;
+use crate::messages::HeightTracker;
 use crate::pool_operator::*;
+use crate::runtime_upgrade::RuntimeUpgradeGuard;
 use crate::tx::TxManagerError::*;
-use crate::use_parachain_api;
 use anyhow::{anyhow, Error, Result};
 use chrono::{DateTime, Utc};
 use futures::future::BoxFuture;
+use futures::FutureExt;
 use hex::ToHex;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use moka_cht::HashMap;
 use parity_scale_codec::Encode;
 use phactory_api::prpc::GetEndpointResponse;
 use phala_types::messaging::SignedMessage;
 use phaxt::dynamic::tx::EncodedPayload;
+use phaxt::resubmit::Resubmission;
 use phaxt::rpc::ExtraRpcExt;
+use phaxt::ChainError;
 use pherry::mk_params;
 use serde::{Deserialize, Serialize};
 use sp_core::crypto::AccountId32;
 use sp_core::sr25519::Public as Sr25519Public;
-use std::collections::{HashMap as StdHashMap, VecDeque};
+use std::collections::{HashMap as StdHashMap, HashSet, VecDeque};
 use std::fmt::{Debug, Display, Formatter};
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use subxt::error::DispatchError as SubxtDispatchError;
-use subxt::tx::{PairSigner, TxPayload};
+use subxt::tx::{PairSigner, SubmittableExtrinsic, TxPayload};
 use subxt::utils::{Encoded, MultiAddress};
 use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio_stream::wrappers::UnboundedReceiverStream;
@@ -121,10 +128,13 @@ pub struct Transaction {
     pub desc: String,
     pub pid: u64,
     pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub dual_submit: bool,
     #[serde(skip)]
     pub tx_payload: Option<EncodedPayload>,
+    /// Receives the number of the block including the transaction.
     #[serde(skip)]
-    pub shot: Option<oneshot::Sender<Result<()>>>,
+    pub shot: Option<oneshot::Sender<Result<u32>>>,
 }
 
 impl Transaction {
@@ -133,7 +143,8 @@ impl Transaction {
         pid: u64,
         tx_payload: EncodedPayload,
         desc: String,
-        shot: oneshot::Sender<Result<()>>,
+        dual_submit: bool,
+        shot: oneshot::Sender<Result<u32>>,
     ) -> Self {
         Self {
             id,
@@ -141,6 +152,7 @@ impl Transaction {
             desc,
             pid,
             created_at: Utc::now(),
+            dual_submit,
             tx_payload: Some(tx_payload),
             shot: Some(shot),
         }
@@ -152,6 +164,7 @@ impl Transaction {
             desc: self.desc.clone(),
             pid: self.pid,
             created_at: self.created_at,
+            dual_submit: self.dual_submit,
             tx_payload: None,
             shot: None,
         }
@@ -167,6 +180,13 @@ impl Clone for Transaction {
 pub struct TxManager {
     pub db: Arc<DB>,
     dsm: WrappedDataSourceManager,
+    dual_submit_offchain_messages: bool,
+    submission_offset: Option<Duration>,
+    pub height_tracker: Arc<HeightTracker>,
+    pub jobs: JobQueue,
+    pub maintenance: MaintenanceWindows,
+    pub runtime_upgrade: RuntimeUpgradeGuard,
+    pub signers: Arc<Signers>,
     tx_count: AtomicUsize,
     tx_map: HashMap<usize, Arc<Mutex<Transaction>>>,
     pending_txs: Mutex<VecDeque<usize>>,
@@ -214,19 +234,63 @@ impl TxManager {
 }
 
 impl TxManager {
+    /// Appends the finished transactions of another prb to the past ones, as the oldest. Returns
+    /// the numbers of the imported transactions and of the skipped ones, which were unfinished or
+    /// imported already.
+    pub async fn import_past_txs(&self, txs: Vec<Transaction>) -> Result<(usize, usize)> {
+        let past_ids = self.past_txs.lock().await.clone();
+        let mut known = HashSet::new();
+        for id in past_ids {
+            let tx = self.tx_map.get(&id).ok_or(UnknownDataMismatch)?;
+            let tx = tx.lock().await;
+            known.insert((tx.created_at, tx.pid, tx.desc.clone()));
+        }
+
+        let mut imported = vec![];
+        let mut skipped = 0;
+        for mut tx in txs {
+            let finished = matches!(
+                tx.state,
+                TransactionState::Success(_) | TransactionState::Error(_)
+            );
+            if !finished || !known.insert((tx.created_at, tx.pid, tx.desc.clone())) {
+                skipped += 1;
+                continue;
+            }
+            let id = self.tx_count.fetch_add(1, Ordering::SeqCst);
+            tx.id = id;
+            self.tx_map.insert(id, Arc::new(Mutex::new(tx)));
+            imported.push(id);
+        }
+        let count = imported.len();
+        self.past_txs.lock().await.extend(imported);
+        Ok((count, skipped))
+    }
+
     pub fn new(
         path_base: &str,
         dsm: WrappedDataSourceManager,
+        dual_submit_offchain_messages: bool,
+        submission_offset: Option<Duration>,
     ) -> Result<(Arc<Self>, BoxFuture<'static, Result<()>>)> {
         let opts = get_options(None);
         let path = Path::new(path_base).join("po");
-        let db = DB::open(&opts, path)?;
+        let db = Arc::new(DB::open(&opts, path)?);
+        let jobs = JobQueue::load(db.clone())?;
+        let maintenance = MaintenanceWindows::load(db.clone())?;
 
         let (tx, rx) = mpsc::unbounded_channel::<usize>();
 
         let txm = Arc::new(TxManager {
-            db: Arc::new(db),
+            db,
             dsm,
+            dual_submit_offchain_messages,
+            submission_offset,
+            height_tracker: Default::default(),
+            jobs,
+            maintenance,
+            runtime_upgrade: Default::default(),
+            signers: Default::default(),
             tx_count: AtomicUsize::new(0),
             tx_map: HashMap::new(),
             pending_txs: Mutex::new(VecDeque::new()),
@@ -239,13 +303,30 @@ impl TxManager {
         Ok((txm, handle))
     }
     async fn start_trader(self: Arc<Self>, rx: mpsc::UnboundedReceiver<usize>) -> Result<()> {
-        let rx_stream = UnboundedReceiverStream::new(rx).chunks_timeout(
-            TX_QUEUE_CHUNK_SIZE,
-            Duration::from_millis(TX_QUEUE_CHUNK_TIMEOUT_IN_MS),
-        );
+        let rx_stream =
+            futures::StreamExt::peekable(UnboundedReceiverStream::new(rx).chunks_timeout(
+                TX_QUEUE_CHUNK_SIZE,
+                Duration::from_millis(TX_QUEUE_CHUNK_TIMEOUT_IN_MS),
+            ));
         tokio::pin!(rx_stream);
 
+        let mut in_slot = false;
         while let Some(current_txs) = rx_stream.next().await {
+            if self.runtime_upgrade.hold() {
+                info!(
+                    "Holding {} transactions during the runtime upgrade.",
+                    current_txs.len()
+                );
+                self.runtime_upgrade.wait().await;
+                in_slot = false;
+            }
+            if !in_slot {
+                self.wait_for_submission_slot().await;
+            }
+            // The chunks which got ready while waiting are submitted in the same slot, instead of
+            // waiting for a slot each.
+            in_slot = rx_stream.as_mut().peek().now_or_never().flatten().is_some();
+
             let mut pending_txs = self.pending_txs.lock().await;
             let mut running_txs = self.running_txs.lock().await;
             let mut past_txs = self.past_txs.lock().await;
@@ -300,6 +381,23 @@ impl TxManager {
         error!("Unexpected exit of start_trader!");
         std::process::exit(255);
     }
+
+    /// Waits until the configured phase of the block interval, the transactions arriving in the
+    /// meantime are submitted in the next round.
+    async fn wait_for_submission_slot(&self) {
+        let Some(offset) = self.submission_offset else {
+            return;
+        };
+        let Some(slot) = self.height_tracker.next_submission_slot(offset) else {
+            return;
+        };
+        debug!(
+            "Holding the transactions for {:?} until the submission slot.",
+            slot.saturating_duration_since(std::time::Instant::now())
+        );
+        tokio::time::sleep_until(slot.into()).await;
+    }
+
     async fn wrap_send_tx_group(self: Arc<Self>, pid: u64, ids: Vec<usize>) -> Result<()> {
         if ids.is_empty() {
             anyhow::bail!("TxGroup can't be empty!");
@@ -336,7 +434,7 @@ impl TxManager {
                     let mut tx = tx.lock().await;
                     let shot = tx.shot.take().ok_or(UnknownDataMismatch)?;
                     tx.state = TransactionState::Error((&e).into());
-                    if shot.send(Err(anyhow!(e.to_string()))).is_err() {
+                    if shot.send(Err(ChainError::classify(&e).into())).is_err() {
                         return Err(anyhow!("shot can't be sent"));
                     }
                     drop(tx);
@@ -345,22 +443,32 @@ impl TxManager {
         }
         Ok(())
     }
-    async fn send_tx_group(self: Arc<Self>, pid: u64, ids: Vec<usize>) -> Result<Vec<Result<()>>> {
+    /// Sends the transactions in a batch, returns the results of each of them with the number of
+    /// the block including the batch.
+    async fn send_tx_group(self: Arc<Self>, pid: u64, ids: Vec<usize>) -> Result<Vec<Result<u32>>> {
         debug!("send_tx_group: {:?}", &ids);
         let po = self.db.get_po(pid)?.ok_or(InvalidPoolOperator)?;
         let proxied = po.proxied.is_some();
 
-        let api = use_parachain_api!(self.dsm, false).ok_or(NoValidSubstrateDataSource)?;
+        let primary = self
+            .dsm
+            .clone()
+            .current_parachain_submit_client(None)
+            .await
+            .ok_or(NoValidSubstrateDataSource)?;
+        let api = primary.client.clone();
         let metadata = api.metadata();
         let mut calls = Vec::new();
+        let mut dual_submit = false;
         for i in ids.iter() {
             let tx = self.tx_map.get(i).ok_or(UnknownDataMismatch)?;
             let mut tx = tx.lock().await;
             let call = tx.tx_payload.take().ok_or(UnknownDataMismatch)?;
             calls.push(call);
+            dual_submit |= tx.dual_submit;
             drop(tx);
         }
-        let signer = PairSigner::new(po.pair.clone());
+        let signer = PairSigner::new(self.signers.pair(&po).await?);
 
         let single = ids.len() == 1;
 
@@ -399,24 +507,95 @@ impl TxManager {
         let mut encoded = Vec::new();
         call.encode_call_data_to(&metadata, &mut encoded)?;
         let nonce = api.extra_rpc().account_nonce(signer.account_id()).await?;
-        debug!("sending tx: 0x{}, with nonce={}", hex::encode(&encoded), nonce);
+        debug!(
+            "sending tx: 0x{}, with nonce={}",
+            hex::encode(&encoded),
+            nonce
+        );
 
         let params = mk_params(&api, TX_LONGEVITY, TX_TIP).await?;
-        let tx_progress = api
+        let signed = api
             .tx()
-            .create_signed_with_nonce(&call, &signer, nonce, params)?
-            .submit_and_watch()
-            .await?;
+            .create_signed_with_nonce(&call, &signer, nonce, params)?;
+
+        // In dual-submit mode the very same signed extrinsic is broadcast through another
+        // endpoint as well. They share the nonce so at most one of them can land on chain.
+        let mut clients = vec![(primary.uuid_str.clone(), api.0.clone())];
+        if dual_submit {
+            match self
+                .dsm
+                .clone()
+                .current_parachain_submit_client(Some(&primary.uuid_str))
+                .await
+            {
+                Some(i) => clients.push((i.uuid_str.clone(), i.client.0.clone())),
+                None => {
+                    warn!("No alternative parachain endpoint for dual-submit, submitting once.")
+                }
+            }
+        }
+        let mut extrinsic = signed.encoded().to_vec();
+        let mut resigned = false;
+        let tx = loop {
+            let mut watchers = Vec::new();
+            let mut last_err = None;
+            for (id, client) in clients.iter() {
+                let submitted = SubmittableExtrinsic::from_bytes(client.clone(), extrinsic.clone())
+                    .submit_and_watch()
+                    .await;
+                self.dsm.report_submission(id, submitted.is_ok());
+                match submitted {
+                    Ok(tx_progress) => watchers.push(Box::pin(tx_progress.wait_for_finalized())),
+                    Err(e) => {
+                        warn!(
+                            "Failed to submit tx with nonce={} through {}: {}",
+                            nonce, id, &e
+                        );
+                        last_err = Some(e);
+                    }
+                }
+            }
+            if watchers.is_empty() {
+                return Err(last_err.ok_or(UnknownDataMismatch)?.into());
+            }
 
-        let tx_and_timeout = tokio::spawn(tokio::time::timeout(
-            Duration::from_secs(TX_TIMEOUT_SECS),
-            tx_progress.wait_for_finalized()
-        )).await?;
-        let tx = match tx_and_timeout {
-            Ok(tx) => tx,
-            Err(_) => anyhow::bail!("Tx timed out!"),
+            // Only the first finalized status counts, the duplicated one is dropped.
+            let tx_and_timeout = tokio::spawn(tokio::time::timeout(
+                self.height_tracker
+                    .scale_duration(Duration::from_secs(TX_TIMEOUT_SECS)),
+                futures::future::select_ok(watchers),
+            ))
+            .await?;
+            match tx_and_timeout {
+                Ok(tx) => break tx,
+                Err(_) if !resigned => {
+                    // The era of the extrinsic is over by now. Sign the very same call again with
+                    // a new era, keeping the nonce so it can only replace the previous one.
+                    warn!(
+                        "Tx with nonce={} timed out, signing it again with a new era.",
+                        nonce
+                    );
+                    let resubmission = Resubmission {
+                        nonce: Some(nonce),
+                        ..Default::default()
+                    };
+                    extrinsic = api
+                        .resign(&extrinsic, &signer, resubmission)
+                        .await?
+                        .encoded()
+                        .to_vec();
+                    resigned = true;
+                }
+                Err(_) => return Err(ChainError::Timeout.into()),
+            }
         };
-        let tx = tx?.wait_for_success().await?;
+        let tx = tx?.0.wait_for_success().await?;
+        let included_at = api
+            .rpc()
+            .header(Some(tx.block_hash()))
+            .await?
+            .ok_or_else(|| anyhow!("Header of block {:?} not found", tx.block_hash()))?
+            .number;
 
         if proxied {
             let event_proxy = tx
@@ -424,25 +603,19 @@ impl TxManager {
                 .ok_or(anyhow!("ProxyExecuted event not found!"))?;
             if let Err(e) = event_proxy.result {
                 let e = e.encode();
-                match SubxtDispatchError::decode_from(&e, api.metadata())? {
-                    SubxtDispatchError::Module(e) => {
-                        anyhow::bail!("{}", &e);
-                    }
-                    _ => {
-                        anyhow::bail!("NotAModuleError: {:?}", &e);
-                    }
-                };
+                let e = SubxtDispatchError::decode_from(&e, api.metadata())?;
+                return Err(ChainError::from(&e).into());
             }
         }
         if single {
-            return Ok(vec![Ok(())]);
+            return Ok(vec![Ok(included_at)]);
         }
 
         if tx
             .find_first::<khala::utility::events::BatchCompleted>()?
             .is_some()
         {
-            return Ok((0..ids.len()).map(|_| Ok(())).collect::<Vec<_>>());
+            return Ok((0..ids.len()).map(|_| Ok(included_at)).collect::<Vec<_>>());
         }
         tx.find_first::<khala::utility::events::BatchCompletedWithErrors>()?
             .ok_or(anyhow!("BatchCompletedWithErrors event not found!"))?;
@@ -453,7 +626,7 @@ impl TxManager {
             if i.pallet_name() == "Utility" {
                 match i.variant_name() {
                     "ItemCompleted" => {
-                        ret.push(Ok(()));
+                        ret.push(Ok(included_at));
                     }
                     "ItemFailed" => {
                         let i = i
@@ -461,14 +634,8 @@ impl TxManager {
                             .ok_or(anyhow!("ItemFailed not parsed from event"))?;
                         let i = i.error;
                         let i_bytes = i.encode();
-                        match SubxtDispatchError::decode_from(i_bytes, api.metadata())? {
-                            SubxtDispatchError::Module(e) => {
-                                ret.push(Err(anyhow!(format!("{}", e))))
-                            }
-                            _ => {
-                                ret.push(Err(anyhow!(format!("NotAModuleError: {:?}", &i))));
-                            }
-                        }
+                        let e = SubxtDispatchError::decode_from(i_bytes, api.metadata())?;
+                        ret.push(Err(ChainError::from(&e).into()));
                     }
                     _ => {}
                 }
@@ -486,6 +653,17 @@ impl TxManager {
         tx_payload: EncodedPayload,
         desc: String,
     ) -> Result<()> {
+        self.do_send_to_queue(pid, tx_payload, desc, false).await?;
+        Ok(())
+    }
+
+    async fn do_send_to_queue(
+        &self,
+        pid: u64,
+        tx_payload: EncodedPayload,
+        desc: String,
+        dual_submit: bool,
+    ) -> Result<u32> {
         let (shot, rx) = oneshot::channel();
         tokio::pin!(rx);
 
@@ -500,12 +678,26 @@ impl TxManager {
         self.tx_map.insert(
             id,
             Arc::new(Mutex::new(Transaction::new(
-                id, pid, tx_payload, desc, shot,
+                id,
+                pid,
+                tx_payload,
+                desc,
+                dual_submit,
+                shot,
             ))),
         );
         self.channel_tx.clone().send(id)?;
         rx.await?
     }
+
+    /// Holds back a non-critical submission while the pool is under maintenance.
+    async fn wait_for_maintenance(&self, pid: u64, desc: &str) {
+        if self.maintenance.hold(pid) {
+            info!("Pool #{pid} is under maintenance, holding: {desc}");
+            self.maintenance.wait(pid).await;
+            info!("Maintenance of pool #{pid} is over, resuming: {desc}");
+        }
+    }
 }
 
 impl TxManager {
@@ -524,6 +716,7 @@ impl TxManager {
         };
 
         let desc = format!("Register worker for pool #{pid}");
+        self.wait_for_maintenance(pid, &desc).await;
         self.clone().send_to_queue(pid, tx_payload, desc).await
     }
     pub async fn update_worker_endpoint(
@@ -541,18 +734,25 @@ impl TxManager {
             (Encoded(endpoint_payload), signature).encode(),
         );
         let desc = "Update endpoint of worker.".to_string();
+        self.wait_for_maintenance(pid, &desc).await;
         self.clone().send_to_queue(pid, tx_payload, desc).await
     }
+    /// Submits an offchain message, returns the number of the block including it.
     pub async fn sync_offchain_message(
         self: Arc<Self>,
         pid: u64,
         signed_message: SignedMessage,
-    ) -> Result<()> {
+    ) -> Result<u32> {
         let encoded = signed_message.encode();
         let tx_payload = EncodedPayload::new("PhalaMq", "sync_offchain_message", encoded);
-        let desc = format!("Sync offchain message #{} from {}.",
-            signed_message.sequence, signed_message.message.sender);
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        let desc = format!(
+            "Sync offchain message #{} from {}.",
+            signed_message.sequence, signed_message.message.sender
+        );
+        let dual_submit = self.dual_submit_offchain_messages;
+        self.clone()
+            .do_send_to_queue(pid, tx_payload, desc, dual_submit)
+            .await
     }
     pub async fn add_worker(self: Arc<Self>, pid: u64, pubkey: Sr25519Public) -> Result<()> {
         let desc = format!(
@@ -564,6 +764,7 @@ impl TxManager {
             "add_worker",
             (pid, Encoded(pubkey.encode())).encode(),
         );
+        self.wait_for_maintenance(pid, &desc).await;
         self.clone().send_to_queue(pid, tx_payload, desc).await
     }
     pub async fn start_computing(
@@ -582,6 +783,7 @@ impl TxManager {
             "start_computing",
             (pid, Encoded(worker.encode()), stake.parse::<u128>()?).encode(),
         );
+        self.wait_for_maintenance(pid, &desc).await;
         self.clone().send_to_queue(pid, tx_payload, desc).await
     }
     pub async fn stop_computing(self: Arc<Self>, pid: u64, worker: Sr25519Public) -> Result<()> {
@@ -596,4 +798,4 @@ impl TxManager {
         );
         self.clone().send_to_queue(pid, tx_payload, desc).await
     }
-}
\ No newline at end of file
+}
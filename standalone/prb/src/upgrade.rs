@@ -0,0 +1,561 @@
+//! Coordinated upgrades of the pRuntimes of the workers.
+//!
+//! An upgrade brings the workers to a target pRuntime version, one worker at a time so that the
+//! pool never loses more than one worker at once. Each worker goes through:
+//!
+//! 1. checkpointing: the pRuntime saves its state, so that the new version resumes from it instead
+//!    of syncing from scratch;
+//! 2. draining: prb waits for the pRuntime to report no contract query in flight, for at most the
+//!    query timeout of the pRuntime or `--upgrade-drain-secs`. A pRuntime not reporting its
+//!    queries in flight is given the whole time;
+//! 3. restarting: the `--upgrade-hook` command is run to replace the pRuntime, e.g. pulling the new
+//!    image and recreating the container. It is killed if it doesn't exit within
+//!    `--upgrade-step-timeout-secs`. Without a hook, the operator restarts it by hand. Either way,
+//!    prb waits for the pRuntime to report the target version, then restarts the worker;
+//! 4. verifying: the worker must come back registered and working.
+//!
+//! Workers already at the target version are skipped. The upgrade stops at the first failure,
+//! leaving the remaining workers untouched, so that a broken release doesn't take down the pool.
+//!
+//! The status of the last upgrade is persisted in the pool operator db. An upgrade interrupted by
+//! a restart of prb is not resumed, the worker it was upgrading is marked as failed.
+
+use crate::notifications::Notification;
+use crate::pool_operator::DB;
+use crate::processor::WorkerEvent;
+use crate::wm::WrappedWorkerManagerContext;
+use crate::worker::{WorkerLifecycleCommand, WorkerLifecycleState};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use phactory_api::prpc::StatisticsReqeust;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static STATUS_KEY: &str = "upgrade_status";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradeRequest {
+    /// The pRuntime version to upgrade to, as reported in the `version` of `PhactoryInfo`.
+    pub target_version: String,
+    /// The ids of the workers to upgrade, in this order. All the workers if empty.
+    #[serde(default)]
+    pub workers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum UpgradeStep {
+    Pending,
+    Checkpointing,
+    Draining,
+    Restarting,
+    Verifying,
+    Done,
+    /// Already at the target version.
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerUpgrade {
+    pub worker_id: String,
+    pub name: String,
+    /// The version reported by the pRuntime before the upgrade.
+    pub from_version: Option<String>,
+    pub step: UpgradeStep,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpgradeStatus {
+    pub target_version: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Set when the upgrade was aborted through the API, the current worker is finished first.
+    pub aborted: bool,
+    pub workers: Vec<WorkerUpgrade>,
+}
+
+impl UpgradeStatus {
+    pub fn is_running(&self) -> bool {
+        self.finished_at.is_none()
+    }
+
+    /// Marks the upgrade as finished after an interruption, failing the worker being upgraded.
+    fn interrupt(&mut self) {
+        let now = Utc::now();
+        if let Some(worker) = self.workers.iter_mut().find(|worker| {
+            !matches!(
+                worker.step,
+                UpgradeStep::Pending | UpgradeStep::Done | UpgradeStep::Skipped
+            )
+        }) {
+            worker.step = UpgradeStep::Failed("Interrupted by a restart of prb".into());
+            worker.updated_at = now;
+        }
+        self.finished_at = Some(now);
+    }
+}
+
+/// The last or the ongoing upgrade.
+#[derive(Default)]
+pub struct FleetUpgrade {
+    status: Mutex<Option<UpgradeStatus>>,
+    abort: AtomicBool,
+    db: Option<Arc<DB>>,
+}
+
+impl FleetUpgrade {
+    /// Loads the status of the last upgrade, finishing it if it was interrupted.
+    pub fn load(db: Arc<DB>) -> Result<Self> {
+        let mut status: Option<UpgradeStatus> = match db.get(STATUS_KEY)? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        };
+        if let Some(status) = status.as_mut().filter(|status| status.is_running()) {
+            warn!(
+                "The upgrade to {} was interrupted by a restart",
+                status.target_version
+            );
+            status.interrupt();
+        }
+        let upgrade = Self {
+            status: Mutex::new(status),
+            abort: Default::default(),
+            db: Some(db),
+        };
+        if let Some(status) = upgrade.status() {
+            upgrade.persist(&status);
+        }
+        Ok(upgrade)
+    }
+
+    pub fn status(&self) -> Option<UpgradeStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Stops the upgrade after the worker being upgraded. Returns false if no upgrade is running.
+    pub fn abort(&self) -> bool {
+        self.update(|status| {
+            if !status.is_running() {
+                return false;
+            }
+            status.aborted = true;
+            self.abort.store(true, Ordering::Relaxed);
+            true
+        })
+        .unwrap_or(false)
+    }
+
+    fn set_step(&self, index: usize, step: UpgradeStep) {
+        self.update(|status| {
+            let worker = &mut status.workers[index];
+            info!(
+                "[{}] Upgrade to {}: {:?}",
+                worker.name, status.target_version, step
+            );
+            worker.step = step;
+            worker.updated_at = Utc::now();
+        });
+    }
+
+    fn finish(&self) {
+        self.update(|status| status.finished_at = Some(Utc::now()));
+    }
+
+    /// Applies `f` to the current status and persists the result.
+    fn update<R>(&self, f: impl FnOnce(&mut UpgradeStatus) -> R) -> Option<R> {
+        let mut current = self.status.lock().unwrap();
+        let status = current.as_mut()?;
+        let ret = f(status);
+        self.persist(status);
+        Some(ret)
+    }
+
+    fn persist(&self, status: &UpgradeStatus) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        let result = serde_json::to_vec(status)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| Ok(db.put(STATUS_KEY, value)?));
+        if let Err(err) = result {
+            warn!("Failed to persist the upgrade status: {err}");
+        }
+    }
+}
+
+/// Starts upgrading the workers in the background.
+pub async fn start(
+    ctx: WrappedWorkerManagerContext,
+    request: UpgradeRequest,
+) -> Result<UpgradeStatus> {
+    let statuses = ctx.worker_status_map.lock().await.clone();
+    let ids = if request.workers.is_empty() {
+        let mut ids = statuses.keys().cloned().collect::<Vec<_>>();
+        ids.sort_by_key(|id| statuses[id].worker.name.clone());
+        ids
+    } else {
+        request.workers.clone()
+    };
+    let mut workers = Vec::with_capacity(ids.len());
+    for id in ids {
+        let status = statuses
+            .get(&id)
+            .ok_or_else(|| anyhow!("Worker not found: {id}"))?;
+        workers.push(WorkerUpgrade {
+            worker_id: id,
+            name: status.worker.name.clone(),
+            from_version: status
+                .phactory_info
+                .as_ref()
+                .map(|info| info.version.clone()),
+            step: UpgradeStep::Pending,
+            updated_at: Utc::now(),
+        });
+    }
+
+    let status = UpgradeStatus {
+        target_version: request.target_version,
+        started_at: Utc::now(),
+        finished_at: None,
+        aborted: false,
+        workers,
+    };
+    {
+        let mut current = ctx.upgrade.status.lock().unwrap();
+        if current.as_ref().map_or(false, |status| status.is_running()) {
+            bail!("An upgrade is already running");
+        }
+        ctx.upgrade.persist(&status);
+        *current = Some(status.clone());
+    }
+    ctx.upgrade.abort.store(false, Ordering::Relaxed);
+    info!(
+        "Upgrading {} workers to pRuntime {}",
+        status.workers.len(),
+        status.target_version
+    );
+    tokio::spawn(run(ctx, status.clone()));
+    Ok(status)
+}
+
+async fn run(ctx: WrappedWorkerManagerContext, status: UpgradeStatus) {
+    let target = &status.target_version;
+    for (index, worker) in status.workers.iter().enumerate() {
+        if ctx.upgrade.abort.load(Ordering::Relaxed) {
+            info!("Upgrade to {target} aborted");
+            break;
+        }
+        if let Err(err) = upgrade_worker(&ctx, index, &worker.worker_id, target).await {
+            let step = ctx
+                .upgrade
+                .status()
+                .map(|status| status.workers[index].step.clone());
+            warn!("[{}] Upgrade to {target} failed: {err:?}", worker.name);
+            ctx.upgrade
+                .set_step(index, UpgradeStep::Failed(err.to_string()));
+            ctx.notifier.notify(Notification::UpgradeFailed {
+                worker_id: worker.worker_id.clone(),
+                target_version: target.clone(),
+                step: format!("{:?}", step.unwrap_or(UpgradeStep::Pending)),
+                error: err.to_string(),
+            });
+            break;
+        }
+    }
+    ctx.upgrade.finish();
+    info!("Upgrade to {target} finished");
+}
+
+async fn upgrade_worker(
+    ctx: &WrappedWorkerManagerContext,
+    index: usize,
+    worker_id: &str,
+    target: &str,
+) -> Result<()> {
+    let status = ctx
+        .worker_status_map
+        .lock()
+        .await
+        .get(worker_id)
+        .cloned()
+        .ok_or_else(|| anyhow!("Worker removed"))?;
+    let info = status.phactory_info.as_ref();
+    if info.map_or(false, |info| info.version == target) {
+        ctx.upgrade.set_step(index, UpgradeStep::Skipped);
+        return Ok(());
+    }
+    let worker = status.worker;
+    let client = crate::pruntime::create_client(worker.endpoint.clone(), worker.proxy.as_deref());
+
+    ctx.upgrade.set_step(index, UpgradeStep::Checkpointing);
+    let synced_to = client.take_checkpoint(()).await?.synced_to;
+    info!("[{}] Checkpoint taken at block {synced_to}", worker.name);
+
+    ctx.upgrade.set_step(index, UpgradeStep::Draining);
+    let drain_secs = ctx
+        .args
+        .upgrade_drain_secs
+        .or(info.map(|info| info.query_timeout as u64))
+        .unwrap_or_default();
+    drain(&client, &worker.name, Duration::from_secs(drain_secs)).await;
+
+    ctx.upgrade.set_step(index, UpgradeStep::Restarting);
+    let timeout = Duration::from_secs(ctx.args.upgrade_step_timeout_secs);
+    if let Some(hook) = ctx.args.upgrade_hook.as_deref() {
+        let env = [
+            ("PRB_WORKER_ID", worker.id.as_str()),
+            ("PRB_WORKER_NAME", worker.name.as_str()),
+            ("PRB_WORKER_ENDPOINT", worker.endpoint.as_str()),
+            ("PRB_TARGET_VERSION", target),
+        ];
+        run_hook(hook, &env, timeout).await?;
+    } else {
+        info!(
+            "[{}] No upgrade hook configured, waiting for the pRuntime to be restarted with {target}",
+            worker.name
+        );
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        match client.get_info(()).await {
+            Ok(info) if info.version == target => break,
+            Ok(info) => {
+                if Instant::now() > deadline {
+                    bail!(
+                        "pRuntime still reports version {} after {timeout:?}",
+                        info.version
+                    );
+                }
+            }
+            Err(err) => {
+                if Instant::now() > deadline {
+                    bail!("pRuntime not back after {timeout:?}: {err}");
+                }
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    let _ = ctx.bus.send_worker_event(
+        worker_id.to_string(),
+        WorkerEvent::WorkerLifecycleCommand(WorkerLifecycleCommand::ShouldRestart),
+    );
+
+    ctx.upgrade.set_step(index, UpgradeStep::Verifying);
+    let deadline = Instant::now() + timeout;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let status = ctx
+            .worker_status_map
+            .lock()
+            .await
+            .get(worker_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Worker removed"))?;
+        let registered = status
+            .phactory_info
+            .as_ref()
+            .map_or(false, |info| info.registered && info.version == target);
+        let working = matches!(
+            status.state,
+            WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking
+        ) || (worker.sync_only
+            && !matches!(status.state, WorkerLifecycleState::HasError(_)));
+        if registered && working {
+            break;
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "Worker not back to work after {timeout:?}, state: {:?}, last message: {}",
+                status.state,
+                status.last_message
+            );
+        }
+    }
+    ctx.upgrade.set_step(index, UpgradeStep::Done);
+    Ok(())
+}
+
+/// Waits until the pRuntime has no contract query in flight, for at most `timeout`.
+async fn drain(client: &crate::pruntime::PRuntimeClient, name: &str, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let in_flight = match client.statistics(StatisticsReqeust::default()).await {
+            Ok(stats) => stats.queries_in_flight,
+            Err(err) => {
+                debug!("[{name}] Failed to get the queries in flight: {err}");
+                None
+            }
+        };
+        if in_flight == Some(0) {
+            info!("[{name}] No contract query in flight");
+            return;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            match in_flight {
+                Some(count) => warn!("[{name}] Still {count} contract queries in flight"),
+                None => info!("[{name}] Drained for {timeout:?}"),
+            }
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Runs the hook replacing the pRuntime of the worker, with the worker given in the environment
+/// `env`. The hook is killed if it doesn't exit within `timeout`.
+async fn run_hook(hook: &str, env: &[(&str, &str)], timeout: Duration) -> Result<()> {
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(hook)
+        .envs(env.iter().copied())
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    // Dropping the output future on timeout kills the hook.
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => bail!("Upgrade hook killed after {timeout:?}"),
+    };
+    if !output.status.success() {
+        bail!(
+            "Upgrade hook exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn temp_db() -> (Arc<DB>, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("prb-upgrade-{}", uuid::Uuid::new_v4()));
+        let opts = crate::pool_operator::get_options(None);
+        (Arc::new(DB::open(&opts, &path).unwrap()), path)
+    }
+
+    fn worker(id: &str, step: UpgradeStep) -> WorkerUpgrade {
+        WorkerUpgrade {
+            worker_id: id.into(),
+            name: id.into(),
+            from_version: None,
+            step,
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn running(workers: Vec<WorkerUpgrade>) -> UpgradeStatus {
+        UpgradeStatus {
+            target_version: "2.0.0".into(),
+            started_at: Utc::now(),
+            finished_at: None,
+            aborted: false,
+            workers,
+        }
+    }
+
+    #[test]
+    fn hook_gets_the_worker_in_its_environment() {
+        let env = [("PRB_WORKER_ID", "w1"), ("PRB_TARGET_VERSION", "2.0.0")];
+        let hook = r#"test "$PRB_WORKER_ID-$PRB_TARGET_VERSION" = "w1-2.0.0""#;
+        block_on(run_hook(hook, &env, Duration::from_secs(10))).unwrap();
+    }
+
+    #[test]
+    fn hook_failure_reports_stderr() {
+        let err = block_on(run_hook(
+            "echo broken >&2; exit 3",
+            &[],
+            Duration::from_secs(10),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("broken"), "{err}");
+    }
+
+    #[test]
+    fn hook_is_killed_on_timeout() {
+        let marker = std::env::temp_dir().join(format!("prb-hook-{}", uuid::Uuid::new_v4()));
+        let hook = format!("sleep 2; touch {}", marker.display());
+        let started = Instant::now();
+        let err = block_on(run_hook(&hook, &[], Duration::from_millis(200))).unwrap_err();
+        assert!(err.to_string().contains("killed"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        std::thread::sleep(Duration::from_secs(3));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn status_is_persisted() {
+        let (db, path) = temp_db();
+        let upgrade = FleetUpgrade::load(db.clone()).unwrap();
+        assert!(upgrade.status().is_none());
+        let status = running(vec![worker("w1", UpgradeStep::Pending)]);
+        upgrade.persist(&status);
+        *upgrade.status.lock().unwrap() = Some(status);
+        upgrade.set_step(0, UpgradeStep::Done);
+        upgrade.finish();
+        drop(upgrade);
+
+        let upgrade = FleetUpgrade::load(db).unwrap();
+        let status = upgrade.status().unwrap();
+        assert!(!status.is_running());
+        assert_eq!(status.workers[0].step, UpgradeStep::Done);
+        assert!(!upgrade.abort());
+        drop(upgrade);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn interrupted_upgrade_is_finished_on_load() {
+        let (db, path) = temp_db();
+        let status = running(vec![
+            worker("w1", UpgradeStep::Done),
+            worker("w2", UpgradeStep::Draining),
+            worker("w3", UpgradeStep::Pending),
+        ]);
+        db.put(STATUS_KEY, serde_json::to_vec(&status).unwrap())
+            .unwrap();
+
+        let upgrade = FleetUpgrade::load(db.clone()).unwrap();
+        let status = upgrade.status().unwrap();
+        assert!(!status.is_running());
+        assert_eq!(status.workers[0].step, UpgradeStep::Done);
+        assert!(matches!(status.workers[1].step, UpgradeStep::Failed(_)));
+        assert_eq!(status.workers[2].step, UpgradeStep::Pending);
+        // The interruption is persisted as well.
+        let stored: UpgradeStatus =
+            serde_json::from_slice(&db.get(STATUS_KEY).unwrap().unwrap()).unwrap();
+        assert!(!stored.is_running());
+        drop(upgrade);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn abort_only_a_running_upgrade() {
+        let upgrade = FleetUpgrade::default();
+        assert!(!upgrade.abort());
+        *upgrade.status.lock().unwrap() = Some(running(vec![worker("w1", UpgradeStep::Pending)]));
+        assert!(upgrade.abort());
+        assert!(upgrade.status().unwrap().aborted);
+        upgrade.finish();
+        assert!(!upgrade.abort());
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use futures::future::try_join_all;
 use log::{debug, error};
-use parity_scale_codec::Decode;
+use parity_scale_codec::{Decode, Encode};
 use phaxt::ChainApi;
 use subxt::storage::address::Yes;
 use subxt::storage::StorageAddress;
@@ -40,6 +40,17 @@ pub(crate) fn storage_address_bytes<Address: StorageAddress>(
     Ok(bytes)
 }
 
+/// `Twox64Concat` map key of `pallet::entry` at `key`.
+pub(crate) fn map_key(pallet: &str, entry: &str, key: &impl Encode) -> Vec<u8> {
+    let key = key.encode();
+    let mut out = Vec::new();
+    out.extend(sp_core::hashing::twox_128(pallet.as_bytes()));
+    out.extend(sp_core::hashing::twox_128(entry.as_bytes()));
+    out.extend(sp_core::hashing::twox_64(&key));
+    out.extend(key);
+    out
+}
+
 pub async fn fetch_storage_bytes<'a, Address, T>(
     api: &'a ChainApi,
     address: &'a Address,
@@ -1,13 +1,22 @@
 use crate::api::{start_api_server, WorkerStatus};
-use crate::bus::Bus;
+use crate::bus::{watch_saturation, Bus};
 use crate::cli::WorkerManagerCliArgs;
-use crate::repository::Repository;
-use crate::datasource::setup_data_source_manager;
+use crate::computation_samples::ComputationSamples;
+use crate::datasource::{setup_data_source_manager, WrappedDataSourceManager};
+use crate::dead_letters::{restore_dead_letters, DeadLetters};
 use crate::inv_db::{get_all_workers, setup_inventory_db, WrappedDb};
-use crate::messages::{master_loop as message_master_loop, MessagesEvent};
+use crate::message_metrics::MessageMetrics;
+use crate::messages::{master_loop as message_master_loop, MessagesEvent, TopicToggles};
+use crate::notifications::Notifier;
 use crate::pool_operator::PoolOperatorAccess;
 use crate::processor::{Processor, ProcessorEvent};
+use crate::public_api::start_public_api_server;
+use crate::reconciler::Reconciler;
+use crate::repository::Repository;
+use crate::retry_policy::RetryPolicy;
+use crate::support_bundle::redacted_args;
 use crate::tx::TxManager;
+use crate::upgrade::FleetUpgrade;
 use crate::worker_status::{update_worker_status, WorkerStatusEvent};
 use chrono::{Timelike, Utc};
 use futures::future::{try_join4, try_join_all};
@@ -21,13 +30,24 @@ pub struct WorkerManagerContext {
     pub inv_db: WrappedDb,
     pub worker_status_map: Arc<TokioMutex<HashMap<String, WorkerStatus>>>,
     pub txm: Arc<TxManager>,
+    pub dsm: WrappedDataSourceManager,
     pub bus: Arc<Bus>,
+    pub topic_toggles: Arc<TopicToggles>,
+    pub dead_letters: Arc<DeadLetters>,
+    pub message_metrics: Arc<MessageMetrics>,
+    pub computation_samples: Arc<ComputationSamples>,
+    pub upgrade: FleetUpgrade,
+    pub reconciler: Reconciler,
+    pub notifier: Arc<Notifier>,
+    pub args: WorkerManagerCliArgs,
 }
 
 pub type WrappedWorkerManagerContext = Arc<WorkerManagerContext>;
 
 pub async fn wm(args: WorkerManagerCliArgs) {
-    info!("Staring prb-wm with {:?}", &args);
+    info!("Starting prb-wm with {:?}", redacted_args(&args));
+
+    crate::proxy::set_default_proxy(args.proxy.clone()).expect("Invalid proxy");
 
     let (dsm, ds_handles) =
         setup_data_source_manager(&args.data_source_config_path, args.cache_size)
@@ -45,7 +65,14 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         processor_tx: processor_tx.clone(),
         messages_tx: messages_tx.clone(),
         worker_status_tx: worker_status_tx.clone(),
+        stats: Default::default(),
     });
+    let notifier = Arc::new(Notifier::new(args.webhook_url.clone()));
+    tokio::spawn(watch_saturation(
+        bus.clone(),
+        notifier.clone(),
+        args.bus_high_water_mark,
+    ));
 
     let headers_db = {
         let opts = crate::pool_operator::get_options(None);
@@ -54,12 +81,13 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         Arc::new(db)
     };
 
-    let mut repository = Repository::create(
-        bus.clone(),
-        dsm.clone(),
-        headers_db.clone(),
-    ).await.unwrap();
-    repository.background(true, args.verify_saved_headers).await.unwrap();
+    let mut repository = Repository::create(bus.clone(), dsm.clone(), headers_db.clone())
+        .await
+        .unwrap();
+    repository
+        .background(true, args.verify_saved_headers)
+        .await
+        .unwrap();
 
     if args.download_headers_only {
         headers_db.cancel_all_background_work(true);
@@ -67,20 +95,40 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         return;
     }
 
+    let retry_policy = Arc::new(RetryPolicy::from_args(&args).expect("RetryPolicy"));
     let inv_db = setup_inventory_db(&args.db_path);
-    let (txm, txm_handle) = TxManager::new(&args.db_path, dsm.clone()).expect("TxManager");
+    let (txm, txm_handle) = TxManager::new(
+        &args.db_path,
+        dsm.clone(),
+        args.dual_submit_offchain_messages,
+        args.submission_offset_ms
+            .map(std::time::Duration::from_millis),
+    )
+    .expect("TxManager");
     let ctx = Arc::new(WorkerManagerContext {
         inv_db: inv_db.clone(),
         txm: txm.clone(),
+        dsm: dsm.clone(),
         worker_status_map: Arc::new(TokioMutex::new(HashMap::new())),
         bus: bus.clone(),
+        topic_toggles: Arc::new(TopicToggles::new(args.paused_topics.clone())),
+        dead_letters: Arc::new(DeadLetters::load(txm.db.clone()).expect("DeadLetters")),
+        message_metrics: Default::default(),
+        computation_samples: Arc::new(
+            ComputationSamples::load(txm.db.clone()).expect("ComputationSamples"),
+        ),
+        upgrade: FleetUpgrade::load(txm.db.clone()).expect("FleetUpgrade"),
+        reconciler: Default::default(),
+        notifier: notifier.clone(),
+        args: args.clone(),
     });
 
     let workers = get_all_workers(inv_db.clone()).unwrap();
     let workers = workers
         .into_par_iter()
         .map(|worker| {
-            let client = crate::pruntime::create_client(worker.endpoint.clone());
+            let client =
+                crate::pruntime::create_client(worker.endpoint.clone(), worker.proxy.as_deref());
             match worker.pid {
                 Some(pid) => {
                     let pool = match crate::inv_db::get_pool_by_pid(inv_db.clone(), pid) {
@@ -88,17 +136,17 @@ pub async fn wm(args: WorkerManagerCliArgs) {
                         Err(err) => {
                             error!("Fail to get pool #{}. {}", pid, err);
                             None
-                        },
+                        }
                     };
                     let operator = match txm.clone().db.get_po(pid) {
                         Ok(po) => po.map(|po| po.operator()),
                         Err(err) => {
                             error!("Fail to get pool operator #{}. {}", pid, err);
                             None
-                        },
+                        }
                     };
                     (worker, pool, operator, client)
-                },
+                }
                 None => (worker, None, None, client),
             }
         })
@@ -120,10 +168,42 @@ pub async fn wm(args: WorkerManagerCliArgs) {
                 let _ = bus.send_processor_event(ProcessorEvent::Heartbeat);
                 let nanos = 1_000_000_000 - Utc::now().nanosecond() % 1_000_000_000;
                 tokio::time::sleep(std::time::Duration::from_nanos(nanos.into())).await;
-            };
+            }
         })
     };
 
+    let k8s_discovery = args.k8s_discovery_selector.clone().map(|selector| {
+        tokio::spawn(crate::k8s_discovery::discovery_loop(
+            inv_db.clone(),
+            txm.db.clone(),
+            bus.clone(),
+            selector,
+            args.k8s_discovery_namespace.clone(),
+            std::time::Duration::from_secs(args.k8s_discovery_interval),
+        ))
+    });
+
+    tokio::spawn(txm.signers.clone().preload(txm.db.clone()));
+
+    {
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = restore_dead_letters(ctx).await {
+                error!("Failed to restore the dead letters: {:?}", err);
+            }
+        });
+    }
+
+    if !args.public_api_listen_addresses.is_empty() {
+        let ctx = ctx.clone();
+        let args = args.clone();
+        tokio::spawn(async move {
+            if let Err(err) = start_public_api_server(ctx, args).await {
+                error!("Public API server exited: {:?}", err);
+            }
+        });
+    }
+
     let join_handle = try_join4(
         tokio::spawn(start_api_server(ctx.clone(), args.clone())),
         tokio::spawn(txm_handle),
@@ -138,22 +218,63 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         headers_db.clone(),
         dsm.clone(),
         &args,
-    ).await;
-
+    )
+    .await;
 
     tokio::select! {
         _ = tokio::task::spawn_blocking(move || {
             processor.master_loop();
         }) => {}
 
-        _ = message_master_loop(messages_rx, bus.clone(), dsm.clone(), txm.clone()) => {}
+        _ = message_master_loop(
+            messages_rx,
+            bus.clone(),
+            dsm.clone(),
+            txm.clone(),
+            ctx.topic_toggles.clone(),
+            notifier.clone(),
+            ctx.dead_letters.clone(),
+            retry_policy,
+            ctx.message_metrics.clone(),
+            args.sender_stall_alert_blocks,
+            args.message_gc_horizon_blocks,
+        ) => {}
 
         _ = update_worker_status(ctx.clone(), worker_status_rx) => {}
 
         _ = repository.background(false, false) => {}
 
+        ret = txm.clone().job_loop() => {
+            error!("Job loop exited: {:?}", ret);
+        }
+
+        ret = crate::maintenance::maintenance_loop(txm.clone(), bus.clone()) => {
+            error!("Maintenance loop exited: {:?}", ret);
+        }
+
+        ret = crate::computation_samples::sampling_loop(ctx.clone()) => {
+            error!("Computation sampling loop exited: {:?}", ret);
+        }
+
+        ret = crate::reconciler::reconcile_loop(ctx.clone()) => {
+            error!("Reconcile loop exited: {:?}", ret);
+        }
+
+        ret = crate::runtime_upgrade::runtime_upgrade_loop(ctx.clone()) => {
+            error!("Runtime upgrade loop exited: {:?}", ret);
+        }
+
+        ret = async {
+            match k8s_discovery {
+                Some(handle) => handle.await,
+                None => std::future::pending().await,
+            }
+        } => {
+            error!("Kubernetes discovery loop exited: {:?}", ret);
+        }
+
         ret = join_handle => {
             info!("wm.join_handle: {:?}", ret);
         }
     }
-}
\ No newline at end of file
+}
@@ -28,6 +28,7 @@ pub async fn update_worker_status(
         if received_count == 0 {
             break
         }
+        ctx.bus.stats.worker_status.record_received(received_count);
 
         let status_map = ctx.worker_status_map.clone();
         let mut status_map = status_map.lock().await;
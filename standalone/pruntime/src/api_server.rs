@@ -178,6 +178,7 @@ fn rpc_type(method: &str) -> RpcType {
             InitRuntime => Private,
             GetRuntimeInfo => Private,
             GetEgressMessages => Private,
+            GetEgressQueueDepth => Private,
             GetWorkerState => Private,
             AddEndpoint => Private,
             RefreshEndpointSigningTime => Private,
@@ -213,6 +214,12 @@ fn rpc_type(method: &str) -> RpcType {
             SaveClusterState => Public,
             LoadClusterState => Private,
             TryUpgradePinkRuntime => Private,
+            GetContractEvents => Private,
+            ListContracts => Public,
+            GetEnclaveIdentity => Public,
+            DryRunInstantiate => Private,
+            GetQueryResponseChunk => Public,
+            GetDispatchAudit => Private,
         },
     }
 }
@@ -229,6 +236,7 @@ fn default_payload_limit_for_method(method: PhactoryAPIMethod) -> ByteUnit {
         InitRuntime => 10.mebibytes(),
         GetRuntimeInfo => 1.kibibytes(),
         GetEgressMessages => 1.kibibytes(),
+        GetEgressQueueDepth => 1.kibibytes(),
         ContractQuery => 500.kibibytes(),
         GetWorkerState => 1.kibibytes(),
         AddEndpoint => 10.kibibytes(),
@@ -262,6 +270,12 @@ fn default_payload_limit_for_method(method: PhactoryAPIMethod) -> ByteUnit {
         SaveClusterState => 1.kibibytes(),
         LoadClusterState => 1.kibibytes(),
         TryUpgradePinkRuntime => 1.kibibytes(),
+        GetContractEvents => 10.kibibytes(),
+        ListContracts => 1.kibibytes(),
+        GetEnclaveIdentity => 1.kibibytes(),
+        DryRunInstantiate => 100.kibibytes(),
+        GetQueryResponseChunk => 1.kibibytes(),
+        GetDispatchAudit => 1.kibibytes(),
     }
 }
 
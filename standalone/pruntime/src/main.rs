@@ -84,6 +84,13 @@ struct Args {
     #[arg(default_value_t = 0)]
     safe_mode_level: u8,
 
+    /// Boot in safe mode for diagnostics.
+    ///
+    /// Implies --safe-mode-level=1. Additionally, no block is dispatched, so the state loaded
+    /// from the checkpoint is kept intact and can be backed up via prpc.TakeCheckpoint.
+    #[arg(long)]
+    safe_mode: bool,
+
     /// Disable the RCU policy to update the Phactory state.
     #[arg(long)]
     no_rcu: bool,
@@ -100,6 +107,36 @@ struct Args {
     /// Out of range value will be clamped to the nearest bound.
     #[arg(long, default_value = "10")]
     query_timeout: u64,
+
+    /// The number of recent blocks whose state is kept for contract queries at a historical
+    /// block. 0 to disable.
+    #[arg(long, default_value = "8")]
+    query_history_blocks: u32,
+
+    /// Number of blocks to hold back before dispatching them, so that the contracts are executed
+    /// that many blocks behind the chain head. Reduces the value of the timing side channels.
+    #[arg(long, default_value = "0")]
+    dispatch_lag: u32,
+
+    /// Don't keep the compiled sidevm modules across restarts.
+    #[arg(long)]
+    no_sidevm_compile_cache: bool,
+
+    /// The heap budget in MiB. When the heap usage approaches it, caches are evicted, sidevm
+    /// instances are not restarted and new contract queries are rejected, in that order. 0 to
+    /// disable.
+    #[arg(long, default_value = "0")]
+    memory_budget_mb: u64,
+
+    /// The max size in KiB of an encoded contract query response. Larger responses fail, unless
+    /// the query asks to read them in chunks. 0 for unlimited.
+    #[arg(long, default_value = "0")]
+    max_query_response_kb: u64,
+
+    /// Record a hash chain of the state-mutating operations of the dispatch of each block, read
+    /// with the GetDispatchAudit RPC, to find where two workers diverge.
+    #[arg(long)]
+    audit_dispatch: bool,
 }
 
 impl Args {
@@ -138,11 +175,22 @@ impl Args {
             max_checkpoint_files: self.max_checkpoint_files,
             cores,
             public_port: self.public_port,
-            safe_mode_level: self.safe_mode_level,
+            safe_mode_level: if self.safe_mode {
+                self.safe_mode_level.max(1)
+            } else {
+                self.safe_mode_level
+            },
+            safe_mode: self.safe_mode,
             no_rcu: self.no_rcu,
             ra_timeout: self.ra_timeout,
             ra_max_retries: self.ra_max_retries,
             query_timeout: self.query_timeout.clamp(5, 600),
+            query_history_blocks: self.query_history_blocks,
+            dispatch_lag: self.dispatch_lag,
+            sidevm_compile_cache: !self.no_sidevm_compile_cache,
+            memory_budget_mb: self.memory_budget_mb,
+            max_query_response_kb: self.max_query_response_kb,
+            audit_dispatch: self.audit_dispatch,
         }
     }
 }
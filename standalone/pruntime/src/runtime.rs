@@ -2,7 +2,7 @@ use crate::pal_gramine::GraminePlatform;
 
 use anyhow::Result;
 use core::sync::atomic::{AtomicU32, Ordering};
-use phactory::{benchmark, Phactory, RpcService};
+use phactory::{benchmark, crash_report, Phactory, RpcService};
 use rocket::http::Status;
 use sidevm_host_runtime::rocket_stream::{connect, RequestInfo, StreamResponse};
 use std::path::PathBuf;
@@ -40,6 +40,7 @@ pub fn ecall_init(args: phactory_api::ecall_args::InitArgs) -> Result<()> {
         anyhow::bail!("Enclave already initialized.");
     }
     let weak_phactory = APPLICATION.weak_phactory();
+    crash_report::install_panic_hook(weak_phactory.clone(), GraminePlatform, &args.storage_path);
     if args.enable_checkpoint {
         match Phactory::restore_from_checkpoint(&GraminePlatform, &args, weak_phactory) {
             Ok(Some(factory)) => {
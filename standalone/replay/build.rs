@@ -0,0 +1,361 @@
+//! Generates the OpenAPI specification and the client of the HTTP server from the route
+//! definitions of `src/replay_gk/httpserver.rs`.
+//!
+//! A route is an actix handler annotated with `#[get("/path/{param}")]` or alike. Its doc comment
+//! becomes the description of the operation, `web::Path<T>` gives the type of the path parameter,
+//! the fields of `T` in `web::Query<T>` give the query parameters and `web::Json<T>` marks a JSON
+//! request body. A handler returning `ApiResult<T>` responds with `T`, one of the structs of
+//! `src/api.rs` whose fields give the response schema and which the client deserializes into.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+const SERVER_SOURCE: &str = "src/replay_gk/httpserver.rs";
+const SOURCE_DIR: &str = "src/replay_gk";
+const API_SOURCE: &str = "src/api.rs";
+const METHODS: [&str; 4] = ["get", "post", "put", "delete"];
+
+struct Route {
+    method: String,
+    path: String,
+    name: String,
+    doc: Vec<String>,
+    path_type: Option<String>,
+    query_type: Option<String>,
+    body_type: Option<String>,
+    response_type: Option<String>,
+}
+
+impl Route {
+    fn path_params(&self) -> Vec<String> {
+        self.path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(Into::into)
+            .collect()
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={SOURCE_DIR}");
+    println!("cargo:rerun-if-changed={API_SOURCE}");
+    let source = fs::read_to_string(SERVER_SOURCE).expect("Failed to read the server source");
+    let routes = parse_routes(&source);
+    let sources = read_sources();
+    let api_source = fs::read_to_string(API_SOURCE).expect("Failed to read the api source");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let spec = openapi_spec(&routes, &sources, &api_source);
+    fs::write(
+        Path::new(&out_dir).join("openapi.json"),
+        serde_json::to_string_pretty(&spec).unwrap(),
+    )
+    .unwrap();
+    fs::write(Path::new(&out_dir).join("client.rs"), client_code(&routes)).unwrap();
+}
+
+fn read_sources() -> String {
+    let mut sources = String::new();
+    for entry in fs::read_dir(SOURCE_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |ext| ext == "rs") {
+            sources += &fs::read_to_string(path).unwrap();
+        }
+    }
+    sources
+}
+
+fn parse_routes(source: &str) -> Vec<Route> {
+    let mut routes = vec![];
+    let mut doc = vec![];
+    let mut lines = source.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if let Some(text) = line.strip_prefix("///") {
+            doc.push(text.trim().to_string());
+            continue;
+        }
+        let Some((method, path)) = parse_route_attr(line) else {
+            doc.clear();
+            continue;
+        };
+        let mut signature = String::new();
+        for line in lines.by_ref() {
+            signature += line.trim();
+            if signature.contains("->") {
+                break;
+            }
+        }
+        let Some(name) = signature
+            .split("async fn ")
+            .nth(1)
+            .and_then(|s| s.split('(').next())
+        else {
+            panic!("No handler found for {}", path);
+        };
+        let (params, ret) = signature.split_once("->").unwrap();
+        routes.push(Route {
+            method,
+            path,
+            name: name.into(),
+            doc: std::mem::take(&mut doc),
+            path_type: extractor_type(params, "web::Path<"),
+            query_type: extractor_type(params, "web::Query<"),
+            body_type: extractor_type(params, "web::Json<"),
+            response_type: extractor_type(ret, "ApiResult<"),
+        });
+    }
+    routes
+}
+
+fn parse_route_attr(line: &str) -> Option<(String, String)> {
+    let attr = line.strip_prefix("#[")?.strip_suffix(")]")?;
+    let (method, path) = attr.split_once('(')?;
+    if !METHODS.contains(&method) {
+        return None;
+    }
+    let path = path.strip_prefix('"')?.strip_suffix('"')?;
+    Some((method.into(), path.into()))
+}
+
+fn extractor_type(signature: &str, extractor: &str) -> Option<String> {
+    let ty = signature.split(extractor).nth(1)?;
+    Some(ty.split('>').next()?.trim().to_string())
+}
+
+/// The fields of the struct `name` defined in `sources`, with whether they are required.
+fn struct_fields(sources: &str, name: &str) -> Vec<(String, String, bool)> {
+    let header = format!("pub struct {name} {{");
+    let Some(body) = sources.split(header.as_str()).nth(1) else {
+        return vec![];
+    };
+    body.lines()
+        .map(str::trim)
+        .take_while(|line| !line.starts_with('}'))
+        .filter_map(|line| {
+            let field = line.strip_prefix("pub ")?.strip_suffix(',')?;
+            let (name, ty) = field.split_once(':')?;
+            let ty = ty.trim();
+            match ty
+                .strip_prefix("Option<")
+                .and_then(|ty| ty.strip_suffix('>'))
+            {
+                Some(inner) => Some((name.into(), inner.into(), false)),
+                None => Some((name.into(), ty.into(), true)),
+            }
+        })
+        .collect()
+}
+
+fn json_type(rust_type: &str) -> &'static str {
+    match rust_type {
+        "u8" | "u16" | "u32" | "u64" | "usize" | "i32" | "i64" | "BlockNumber" => "integer",
+        "f32" | "f64" => "number",
+        "bool" => "boolean",
+        _ => "string",
+    }
+}
+
+fn generic_arg<'a>(rust_type: &'a str, name: &str) -> Option<&'a str> {
+    rust_type
+        .strip_prefix(name)?
+        .strip_prefix('<')?
+        .strip_suffix('>')
+}
+
+/// The schema of a field of a response.
+fn field_schema(rust_type: &str) -> Value {
+    if let Some(item) = generic_arg(rust_type, "Vec") {
+        return json!({ "type": "array", "items": field_schema(item) });
+    }
+    if let Some(args) = generic_arg(rust_type, "BTreeMap") {
+        let (_, value) = args.split_once(',').expect("Invalid map type");
+        return json!({ "type": "object", "additionalProperties": field_schema(value.trim()) });
+    }
+    match rust_type {
+        // Any JSON value.
+        "Value" => json!({}),
+        ty => json!({ "type": json_type(ty) }),
+    }
+}
+
+/// The schema of the response struct `name` defined in `api_source`.
+fn response_schema(api_source: &str, name: &str) -> Value {
+    let fields = struct_fields(api_source, name);
+    if fields.is_empty() {
+        panic!("Response {name} not found in {API_SOURCE}");
+    }
+    let mut properties = Map::new();
+    let mut required = vec![];
+    for (field, ty, is_required) in fields {
+        if is_required {
+            required.push(field.clone());
+        }
+        properties.insert(field, field_schema(&ty));
+    }
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn openapi_spec(routes: &[Route], sources: &str, api_source: &str) -> Value {
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    for route in routes {
+        let mut parameters = vec![];
+        for param in route.path_params() {
+            let ty = route.path_type.as_deref().unwrap_or("String");
+            parameters.push(json!({
+                "name": param,
+                "in": "path",
+                "required": true,
+                "schema": { "type": json_type(ty) },
+            }));
+        }
+        if let Some(query_type) = &route.query_type {
+            for (name, ty, required) in struct_fields(sources, query_type) {
+                parameters.push(json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required,
+                    "schema": { "type": json_type(&ty) },
+                }));
+            }
+        }
+        let response_schema = match &route.response_type {
+            Some(response_type) => {
+                schemas.insert(
+                    response_type.clone(),
+                    response_schema(api_source, response_type),
+                );
+                json!({ "$ref": format!("#/components/schemas/{response_type}") })
+            }
+            None => json!({ "type": "object" }),
+        };
+        let mut operation = json!({
+            "operationId": route.name,
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "content": { "application/json": { "schema": response_schema } },
+                },
+            },
+        });
+        if !route.doc.is_empty() {
+            let summary: Vec<_> = route
+                .doc
+                .iter()
+                .take_while(|l| !l.is_empty())
+                .cloned()
+                .collect();
+            operation["summary"] = summary.join(" ").into();
+            operation["description"] = route.doc.join("\n").into();
+        }
+        if let Some(body_type) = &route.body_type {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": { "type": "object", "title": body_type },
+                    },
+                },
+            });
+        }
+        paths
+            .entry(route.path.clone())
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap()
+            .insert(route.method.clone(), operation);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "replay",
+            "description": "The HTTP server of the GK replay.",
+            "version": env::var("CARGO_PKG_VERSION").unwrap(),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "security": [{ "bearer": [] }],
+    })
+}
+
+fn client_code(routes: &[Route]) -> String {
+    let mut code = String::from("impl Client {\n");
+    for route in routes {
+        let mut args = vec![];
+        for param in route.path_params() {
+            let ty = match route.path_type.as_deref().unwrap_or("String") {
+                "String" => "&str",
+                "BlockNumber" | "u32" => "u32",
+                "u64" => "u64",
+                _ => "impl core::fmt::Display",
+            };
+            args.push(format!("{param}: {ty}"));
+        }
+        if route.query_type.is_some() {
+            args.push("query: &impl serde::Serialize".into());
+        }
+        if route.body_type.is_some() {
+            args.push("body: &impl serde::Serialize".into());
+        }
+        let segments: Vec<String> = route
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment.strip_prefix('{') {
+                Some(param) => match route.path_type.as_deref() {
+                    Some("String") | None => param.trim_end_matches('}').to_string(),
+                    _ => format!("&{}.to_string()", param.trim_end_matches('}')),
+                },
+                None => format!("{segment:?}"),
+            })
+            .collect();
+        let query = match route.query_type {
+            Some(_) => "Some(query)",
+            None => "None::<&()>",
+        };
+        let body = match route.body_type {
+            Some(_) => "Some(body)",
+            None => "None::<&()>",
+        };
+        for line in route.doc.iter() {
+            code += &format!("    ///{}{line}\n", if line.is_empty() { "" } else { " " });
+        }
+        if !route.doc.is_empty() {
+            code += "    ///\n";
+        }
+        code += &format!("    /// `{} {}`\n", route.method.to_uppercase(), route.path);
+        let response = match &route.response_type {
+            Some(response_type) => format!("crate::api::{response_type}"),
+            None => "serde_json::Value".into(),
+        };
+        code += &format!(
+            "    pub async fn {}(&self{}) -> anyhow::Result<{response}> {{\n",
+            route.name,
+            args.iter()
+                .map(|arg| format!(", {arg}"))
+                .collect::<String>()
+        );
+        code += &format!(
+            "        self.request(reqwest::Method::{}, &[{}], {query}, {body}).await\n",
+            route.method.to_uppercase(),
+            segments.join(", ")
+        );
+        code += "    }\n";
+    }
+    code += "}\n";
+    code
+}
@@ -16,6 +16,8 @@ CREATE TABLE "worker_finance_events" (
     "v" numeric NOT NULL,
     "p" numeric NOT NULL,
     "payout" numeric NOT NULL,
+    "session_id" integer,
+    "halving_period" integer,
     PRIMARY KEY(time, sequence)
 ) WITH (oids = false);
 
@@ -25,3 +27,22 @@ SELECT create_hypertable(
     'time',
     chunk_time_interval := interval '7 days'
 );
+
+DROP TABLE IF EXISTS "tokenomic_parameters";
+CREATE TABLE "tokenomic_parameters" (
+    "block" integer NOT NULL,
+    "time" timestamp without time zone NOT NULL,
+    "params" jsonb NOT NULL,
+    PRIMARY KEY(block)
+) WITH (oids = false);
+
+DROP TABLE IF EXISTS "worker_state_snapshots";
+CREATE TABLE "worker_state_snapshots" (
+    "block" integer NOT NULL,
+    "time" timestamp without time zone NOT NULL,
+    "pubkey" bytea NOT NULL,
+    "v" numeric NOT NULL,
+    "p" numeric NOT NULL,
+    "state" text NOT NULL,
+    PRIMARY KEY(block, pubkey)
+) WITH (oids = false);
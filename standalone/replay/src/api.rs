@@ -0,0 +1,91 @@
+//! The responses of the HTTP server of the replay.
+//!
+//! They are shared by the server and the generated [`crate::client`], and described in the OpenAPI
+//! specification by the build script, which reads their fields below. The nested values whose
+//! types are internal to the replay, e.g. the state of a worker, are kept as JSON.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub type BlockNumber = u32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemInfoResponse {
+    pub storage_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerStateResponse {
+    pub current_block: BlockNumber,
+    /// The sum of the shares of the computing workers, in decimal.
+    pub total_share: String,
+    /// The `WorkerState` of the worker.
+    pub worker: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkersResponse {
+    pub current_block: BlockNumber,
+    /// The sum of the shares of the computing workers, in decimal.
+    pub total_share: String,
+    /// The `WorkerState` of each worker, by its hex encoded public key.
+    pub workers: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub live: bool,
+    pub current_block: BlockNumber,
+    pub finalized_block: BlockNumber,
+    pub lag: BlockNumber,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutEstimatesResponse {
+    pub current_block: BlockNumber,
+    pub finalized_block: BlockNumber,
+    /// The estimated payout of each working worker in decimal, by its hex encoded public key.
+    pub workers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutEstimateResponse {
+    pub current_block: BlockNumber,
+    pub finalized_block: BlockNumber,
+    /// In decimal.
+    pub estimated_payout: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenomicParametersResponse {
+    pub current_block: BlockNumber,
+    /// The parameter sets with the block they were applied at.
+    pub timeline: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CohortsResponse {
+    pub current_block: BlockNumber,
+    pub cohorts: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockMessagesResponse {
+    pub block: BlockNumber,
+    /// The decoded mq messages.
+    pub messages: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhooksResponse {
+    pub enabled: bool,
+    pub rules: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookRulesResponse {
+    /// The rules once changed.
+    pub rules: Vec<Value>,
+}
@@ -0,0 +1,84 @@
+//! A client of the HTTP server of the replay.
+//!
+//! The methods, one per route, are generated by the build script from the routes of the server,
+//! the same way as the OpenAPI specification served at `/openapi.json`. The responses are
+//! deserialized into the types of [`crate::api`] the server responds with.
+//!
+//! ```ignore
+//! let client = replay::client::Client::new("http://127.0.0.1:8080")?.with_token(token);
+//! let status = client.status().await?;
+//! println!("{} blocks behind", status.lag);
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Method, Url};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub struct Client {
+    base_url: Url,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Result<Self> {
+        let base_url = Url::parse(base_url).context("Invalid base url")?;
+        if base_url.cannot_be_a_base() {
+            bail!("Invalid base url {base_url}");
+        }
+        Ok(Self {
+            base_url,
+            token: None,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Sends the token in the `Authorization: Bearer` header of the requests.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn request<R, Q, B>(
+        &self,
+        method: Method,
+        segments: &[&str],
+        query: Option<&Q>,
+        body: Option<&B>,
+    ) -> Result<R>
+    where
+        R: DeserializeOwned,
+        Q: Serialize + ?Sized,
+        B: Serialize + ?Sized,
+    {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid base url"))?
+            .pop_if_empty()
+            .extend(segments);
+        let mut req = self.http.request(method, url.clone());
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        if let Some(query) = query {
+            req = req.query(query);
+        }
+        if let Some(body) = body {
+            req = req
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(body)?);
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        let bytes = resp.bytes().await?;
+        if !status.is_success() {
+            bail!(
+                "{url} returned {status}: {}",
+                String::from_utf8_lossy(&bytes)
+            );
+        }
+        serde_json::from_slice(&bytes).with_context(|| format!("Invalid response of {url}"))
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/client.rs"));
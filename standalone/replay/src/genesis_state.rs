@@ -0,0 +1,108 @@
+//! The chain state the replay starts from.
+//!
+//! Downloading the full state of a block takes a while, so it is cached in `--genesis-cache-dir`,
+//! keyed by the genesis hash of the chain and the block number. `--genesis-state-file` supplies
+//! the state explicitly instead, e.g. one exported on another machine. Either way, the state read
+//! from a file is verified against the state root of the block before being used.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use parity_scale_codec::{Decode, Encode};
+use phactory::ChainStorage;
+use phaxt::rpc::ExtraRpcExt as _;
+use pherry::types::{phaxt, subxt, BlockNumber, Hash, NumberOrHex, ParachainApi, StorageKey};
+
+type StatePairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Loads the state at block `pos`, from `state_file` if given, otherwise from the cache in
+/// `cache_dir` or the chain. An empty `cache_dir` disables the cache.
+pub async fn load(
+    api: &ParachainApi,
+    pos: BlockNumber,
+    state_file: Option<&str>,
+    cache_dir: &str,
+) -> Result<StatePairs> {
+    let number = subxt::rpc::types::BlockNumber::from(NumberOrHex::Number(pos.into()));
+    let hash = api
+        .rpc()
+        .block_hash(Some(number))
+        .await?
+        .ok_or_else(|| anyhow!("Block {pos} not found"))?;
+    let state_root = api
+        .rpc()
+        .header(Some(hash))
+        .await?
+        .ok_or_else(|| anyhow!("Header of block {pos} not found"))?
+        .state_root;
+
+    if let Some(filename) = state_file {
+        let state = read_state(Path::new(filename))?;
+        verify(&state, state_root.0)
+            .with_context(|| format!("Invalid genesis state file {filename}"))?;
+        log::info!("Loaded the state of block {pos} from {filename}");
+        return Ok(state);
+    }
+
+    let cache_file = (!cache_dir.is_empty()).then(|| {
+        Path::new(cache_dir).join(format!("{}-{pos}.state", hex::encode(api.genesis_hash().0)))
+    });
+    if let Some(path) = cache_file.as_ref().filter(|path| path.exists()) {
+        match read_state(path).and_then(|state| verify(&state, state_root.0).map(|_| state)) {
+            Ok(state) => {
+                log::info!("Loaded the state of block {pos} from {}", path.display());
+                return Ok(state);
+            }
+            Err(err) => {
+                log::warn!("Ignoring the cached state {}: {err:?}", path.display());
+            }
+        }
+    }
+
+    log::info!("Fetching the state of block {pos}");
+    let state = fetch(api, hash).await?;
+    if let Some(path) = cache_file {
+        if let Err(err) = write_state(&path, &state) {
+            log::warn!("Failed to cache the state to {}: {err:?}", path.display());
+        }
+    }
+    Ok(state)
+}
+
+async fn fetch(api: &ParachainApi, hash: Hash) -> Result<StatePairs> {
+    let response = api
+        .extra_rpc()
+        .storage_pairs(StorageKey(vec![]), Some(hash))
+        .await?;
+    Ok(response.into_iter().map(|(k, v)| (k.0, v.0)).collect())
+}
+
+fn verify(state: &StatePairs, state_root: [u8; 32]) -> Result<()> {
+    let storage = ChainStorage::from_pairs(state.iter().map(|(k, v)| (k, v)));
+    let root = storage.root();
+    if root.0 != state_root {
+        bail!(
+            "State root mismatch, expected 0x{}, got 0x{}",
+            hex::encode(state_root),
+            hex::encode(root.0)
+        );
+    }
+    Ok(())
+}
+
+fn read_state(path: &Path) -> Result<StatePairs> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    StatePairs::decode(&mut &data[..]).context("Failed to decode the state")
+}
+
+/// Writes to a temporary file first, so that an interrupted write never leaves a truncated cache.
+fn write_state(path: &Path, state: &StatePairs) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, state.encode())?;
+    fs::rename(&tmp, path)?;
+    log::info!("Cached the state to {}", path.display());
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use core::fmt::Debug;
 use parity_scale_codec::Decode;
-use phala_mq::{AccountId, BindTopic, Message};
+use phactory_api::blocks::BlockHeaderWithChanges;
+use phala_mq::{compression, AccountId, BindTopic, Message, Topic};
 use phala_pallets::{
     pallet_phat::{ClusterRegistryEvent, ContractRegistryEvent},
     pallet_registry::{GatekeeperRegistryEvent, RegistryEvent},
@@ -9,12 +10,13 @@ use phala_types::{
     contract::messaging::{ClusterEvent, ClusterOperation, ContractOperation, WorkerClusterReport},
     messaging::{
         GatekeeperChange, GatekeeperEvent, GatekeeperLaunch, KeyDistribution, SystemEvent,
-        WorkingInfoUpdateEvent, WorkingReportEvent,
+        TokenomicParameters, WorkingInfoUpdateEvent, WorkingReportEvent,
     },
 };
+use pherry::types::{phaxt::sp_core::twox_128, BlockNumber};
 
 fn try_decode<T: Debug + Decode + BindTopic>(topic: &[u8], mut payload: &[u8]) -> Option<String> {
-    if T::topic() != topic {
+    if !Topic::new(T::topic()).accepts(topic) {
         return None;
     }
     let decoded = T::decode(&mut payload).ok()?;
@@ -22,6 +24,16 @@ fn try_decode<T: Debug + Decode + BindTopic>(topic: &[u8], mut payload: &[u8]) -
 }
 
 pub(crate) fn try_decode_message(topic: &[u8], payload: &[u8]) -> String {
+    if Topic::new(topic).is_compressed() {
+        return match compression::unpack(payload) {
+            Ok(unpacked) => try_decode_unpacked(topic, &unpacked),
+            Err(err) => format!("{err}: {}", hex_fmt::HexFmt(payload)),
+        };
+    }
+    try_decode_unpacked(topic, payload)
+}
+
+fn try_decode_unpacked(topic: &[u8], payload: &[u8]) -> String {
     macro_rules! try_decode {
         ($($t:ty),*) => {
             $(
@@ -65,3 +77,46 @@ pub(crate) fn is_gk_launch(msg: &Message) -> bool {
         Err(_) => false,
     }
 }
+
+/// The new tokenomic parameters if the message is a parameter change from the chain.
+pub(crate) fn tokenomic_parameters_changed(msg: &Message) -> Option<TokenomicParameters> {
+    if !msg.sender.is_pallet() || msg.destination.path() != &GatekeeperEvent::topic() {
+        return None;
+    }
+    match GatekeeperEvent::decode(&mut &msg.payload[..]).ok()? {
+        GatekeeperEvent::TokenomicParametersChanged(params) => Some(params),
+        _ => None,
+    }
+}
+
+/// The mq messages sent in a block, re-derived from the value of `PhalaMq::OutboundMessages` in
+/// its storage changes.
+///
+/// The messages are cleared at the beginning of every block, so a block leaving the key unchanged
+/// sent no message.
+pub(crate) fn block_mq_messages(
+    block: &BlockHeaderWithChanges,
+) -> Result<Vec<Message>, parity_scale_codec::Error> {
+    let key = [twox_128(b"PhalaMq"), twox_128(b"OutboundMessages")].concat();
+    let value = block
+        .storage_changes
+        .main_storage_changes
+        .iter()
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.as_ref());
+    match value {
+        Some(value) => Vec::<Message>::decode(&mut &value[..]),
+        None => Ok(vec![]),
+    }
+}
+
+pub(crate) fn message_json(block_number: BlockNumber, message: &Message) -> serde_json::Value {
+    let topic = message.destination.path();
+    serde_json::json!({
+        "block": block_number,
+        "sender": message.sender.to_string(),
+        "destination": String::from_utf8_lossy(topic),
+        "payload": hex::encode(&message.payload),
+        "decoded": try_decode_message(topic, &message.payload),
+    })
+}
@@ -0,0 +1,4 @@
+//! The client of the HTTP server of the replay, for the tools consuming its API.
+
+pub mod api;
+pub mod client;
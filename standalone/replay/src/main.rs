@@ -1,4 +1,6 @@
+mod genesis_state;
 mod helper;
+mod replay_cluster;
 mod replay_gk;
 
 use clap::Parser;
@@ -13,6 +15,13 @@ pub struct Args {
     )]
     node_uri: String,
 
+    #[arg(
+        default_value = "khala",
+        long,
+        help = "Profile of the chain, one of the named chain profiles or a JSON file."
+    )]
+    chain_profile: String,
+
     #[arg(long, help = "Headers cache endpoint.")]
     cache_uri: Option<String>,
 
@@ -23,9 +32,29 @@ pub struct Args {
     )]
     start_at: u32,
 
+    #[arg(
+        long,
+        help = "A file of the chain state at --start-at written by a previous run, used instead of fetching it from the node."
+    )]
+    genesis_state_file: Option<String>,
+
+    #[arg(
+        default_value = "./genesis-cache",
+        long,
+        help = "The directory to cache the chain state at --start-at in. Empty to disable."
+    )]
+    genesis_cache_dir: String,
+
     #[arg(long, help = "The block number to stop at.")]
     stop_at: Option<u32>,
 
+    #[arg(
+        long,
+        conflicts_with_all = ["stop_at", "assume_finalized"],
+        help = "Keep following the finalized head after catching up, without the waiting logs."
+    )]
+    live: bool,
+
     #[arg(
         default_value = "127.0.0.1:8080",
         long,
@@ -33,13 +62,134 @@ pub struct Args {
     )]
     bind_addr: String,
 
+    #[arg(
+        long,
+        env = "REPLAY_READ_TOKENS",
+        hide_env_values = true,
+        value_delimiter = ',',
+        help = "Comma separated tokens granting read-only access to the HTTP server."
+    )]
+    read_tokens: Vec<String>,
+
+    #[arg(
+        long,
+        env = "REPLAY_ADMIN_TOKENS",
+        hide_env_values = true,
+        value_delimiter = ',',
+        help = "Comma separated tokens granting admin access to the HTTP server. The server requires no token if neither these nor read tokens are given."
+    )]
+    admin_tokens: Vec<String>,
+
     #[arg(
         default_value = "",
         long,
-        help = "The PostgresQL database to store the events."
+        help = "The database to store the events, a PostgresQL URI or a clickhouse:// URI."
     )]
     persist_events_to: String,
 
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = [
+            "working_started",
+            "working_stopped",
+            "heartbeat_challenge",
+            "heartbeat",
+            "enter_unresponsive",
+            "exit_unresponsive",
+            "recover_v",
+        ],
+        help = "Comma separated event types to persist, e.g. heartbeat,enter_unresponsive. Default to all."
+    )]
+    persist_events: Vec<String>,
+
+    #[arg(
+        default_value = "0",
+        long,
+        help = "The number of blocks between two snapshots of the state of all the workers. 0 for disabled"
+    )]
+    snapshot_interval: u32,
+
+    #[arg(
+        long,
+        help = "A CSV file to append the worker state snapshots of --snapshot-interval to. Default to the database of --persist-events-to."
+    )]
+    snapshot_to: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = ["current"],
+        help = "Replay a second GK of the given variant side by side, aborting when their events or worker states diverge."
+    )]
+    compare_gk: Option<String>,
+
+    #[arg(
+        long,
+        help = "A CSV file of events exported by another replay run or implementation, to diff against the replayed events block by block, aborting at the first divergence."
+    )]
+    import_events: Option<String>,
+
+    #[arg(
+        default_value = "0.000001",
+        long,
+        help = "The tolerance comparing the v, p and payout of the imported events."
+    )]
+    import_tolerance: f64,
+
+    #[arg(
+        long,
+        help = "A JSON file of webhook rules evaluated against the replayed events, also updated by the /webhooks endpoints of the HTTP server."
+    )]
+    webhook_rules: Option<String>,
+
+    #[arg(
+        long,
+        requires = "stop_at",
+        help = "A file to write the signed integrity manifest of the replayed blocks to, when the replay reaches --stop-at."
+    )]
+    manifest_out: Option<String>,
+
+    #[arg(
+        long,
+        env = "REPLAY_MANIFEST_KEY",
+        hide_env_values = true,
+        help = "The sr25519 secret URI, e.g. a mnemonic, of the operator signing the manifest."
+    )]
+    manifest_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "A manifest of a previous run to verify the replayed blocks against, aborting at the first divergence."
+    )]
+    manifest_verify: Option<String>,
+
+    #[arg(
+        long,
+        requires = "manifest_verify",
+        help = "The hex encoded public key the verified manifest must be signed by."
+    )]
+    manifest_signer: Option<String>,
+
+    #[arg(
+        long,
+        requires = "stop_at",
+        help = "Verify the workers working in the GK against the computing sessions on chain when the replay reaches --stop-at."
+    )]
+    verify_sessions: bool,
+
+    #[arg(
+        long,
+        help = "A file of `block,time_ms` lines giving the time of the blocks whose timestamp is missing in the chain storage."
+    )]
+    block_times: Option<String>,
+
+    #[arg(
+        default_value = "12000",
+        long,
+        help = "The block interval assumed to extrapolate the time of the blocks whose timestamp is missing."
+    )]
+    block_interval_ms: u64,
+
     #[arg(
         default_value = "0",
         long,
@@ -59,6 +209,35 @@ pub struct Args {
         help = "The checkpoint file to restore from. Default is to use the latest checkpoint."
     )]
     restore_from: Option<String>,
+
+    #[arg(
+        long,
+        requires = "cluster_key",
+        conflicts_with_all = ["compare_gk", "import_events", "manifest_out", "manifest_verify", "persist_events_to", "restore_from", "snapshot_to", "verify_sessions", "webhook_rules"],
+        help = "Replay the contracts of the given cluster instead of the GK. The replay must start before the cluster is created."
+    )]
+    replay_cluster: Option<String>,
+
+    #[arg(
+        long,
+        env = "REPLAY_CLUSTER_KEY",
+        hide_env_values = true,
+        help = "The hex encoded secret key of the cluster to replay. For dev/testing networks only."
+    )]
+    cluster_key: Option<String>,
+
+    #[arg(
+        long,
+        help = "A file to write the messages sent by the replayed cluster to, as JSON lines."
+    )]
+    cluster_output: Option<String>,
+
+    #[arg(
+        default_value = "./cluster-replay",
+        long,
+        help = "The directory for the files of the replayed contracts."
+    )]
+    cluster_storage_path: String,
 }
 
 #[tokio::main]
@@ -66,5 +245,11 @@ async fn main() {
     env_logger::init();
 
     let args = Args::parse();
-    replay_gk::replay(args).await.expect("Failed to run replay");
+    if args.replay_cluster.is_some() {
+        replay_cluster::replay(args)
+            .await
+            .expect("Failed to run cluster replay");
+    } else {
+        replay_gk::replay(args).await.expect("Failed to run replay");
+    }
 }
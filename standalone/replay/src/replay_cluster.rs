@@ -0,0 +1,229 @@
+//! Replays the contract commands of a single cluster off-chain.
+//!
+//! The blocks are fed into a pink cluster deployed with the cluster key given on the command line,
+//! so that the state transitions of the contracts can be re-executed deterministically, e.g. to
+//! investigate an incident. The replay has to start before the cluster is created. The messages
+//! sent by the cluster are written to `--cluster-output` as JSON lines, one per message.
+//!
+//! This requires the secret key of the cluster, which is only available in dev/testing networks
+//! or to the owner of a worker of the cluster.
+
+use std::{convert::TryInto, fs::File, io::Write, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use phactory::cluster_replay::ClusterReplay;
+use phactory_pal::{AppInfo, AppVersion, Machine, MemoryStats, MemoryUsage, Sealing, RA};
+use phala_mq::ContractClusterId;
+use phala_types::AttestationProvider;
+use pherry::types::{phaxt, subxt, NumberOrHex, ParachainApi};
+
+use crate::helper::message_json;
+use crate::replay_gk::{fetch_block, restart_required, wait_for_block, wait_forever};
+use crate::Args;
+
+/// A platform without TEE, the replayed cluster neither seals data nor creates attestations.
+#[derive(Clone)]
+struct ReplayPlatform;
+
+impl Sealing for ReplayPlatform {
+    type SealError = std::io::Error;
+    type UnsealError = std::io::Error;
+
+    fn seal_data(&self, _path: impl AsRef<Path>, _data: &[u8]) -> Result<(), Self::SealError> {
+        Ok(())
+    }
+
+    fn unseal_data(&self, _path: impl AsRef<Path>) -> Result<Option<Vec<u8>>, Self::UnsealError> {
+        Ok(None)
+    }
+}
+
+impl RA for ReplayPlatform {
+    type Error = anyhow::Error;
+
+    fn create_attestation_report(
+        &self,
+        _provider: Option<AttestationProvider>,
+        _data: &[u8],
+        _timeout: Duration,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Err(anyhow!("Attestation is not available in replay"))
+    }
+
+    fn quote_test(&self, _provider: Option<AttestationProvider>) -> Result<(), Self::Error> {
+        Err(anyhow!("Attestation is not available in replay"))
+    }
+
+    fn measurement(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn supported_attestation_methods(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl Machine for ReplayPlatform {
+    fn machine_id(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn cpu_core_num(&self) -> u32 {
+        1
+    }
+
+    fn cpu_feature_level(&self) -> u32 {
+        0
+    }
+}
+
+impl MemoryStats for ReplayPlatform {
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::default()
+    }
+}
+
+impl AppInfo for ReplayPlatform {
+    fn app_version() -> AppVersion {
+        AppVersion {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        }
+    }
+}
+
+fn parse_hex<const N: usize>(name: &str, value: &str) -> Result<[u8; N]> {
+    let bytes = hex::decode(value.trim_start_matches("0x"))
+        .with_context(|| format!("Invalid hex in {name}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("{name} must be {N} bytes"))
+}
+
+async fn genesis_block_hash(api: &ParachainApi) -> Result<[u8; 32]> {
+    let pos = subxt::rpc::types::BlockNumber::from(NumberOrHex::Number(0));
+    let hash = api
+        .rpc()
+        .block_hash(Some(pos))
+        .await?
+        .ok_or_else(|| anyhow!("Genesis block not found"))?;
+    Ok(hash.0)
+}
+
+pub async fn replay(args: Args) -> Result<()> {
+    let cluster_id = args
+        .replay_cluster
+        .as_deref()
+        .ok_or_else(|| anyhow!("No cluster to replay"))?;
+    let cluster_id = ContractClusterId(parse_hex("--replay-cluster", cluster_id)?);
+    let cluster_key = args
+        .cluster_key
+        .as_deref()
+        .ok_or_else(|| anyhow!("--cluster-key is required to replay a cluster"))?;
+    let cluster_key = parse_hex("--cluster-key", cluster_key)?;
+
+    let mut output = match &args.cluster_output {
+        Some(filename) => Some(
+            File::create(filename)
+                .with_context(|| format!("Failed to create the output file {filename}"))?,
+        ),
+        None => None,
+    };
+
+    let profile = phaxt::ChainProfile::resolve(&args.chain_profile)?;
+    let mut api: ParachainApi = pherry::subxt_connect_with_profile(&args.node_uri, profile.clone())
+        .await
+        .expect("Failed to connect to substrate");
+    log::info!("Connected to substrate at: {}", args.node_uri);
+
+    let genesis_state = crate::genesis_state::load(
+        &api,
+        args.start_at,
+        args.genesis_state_file.as_deref(),
+        &args.genesis_cache_dir,
+    )
+    .await?;
+    let mut replay = ClusterReplay::new(
+        ReplayPlatform,
+        args.cluster_storage_path.clone(),
+        genesis_block_hash(&api).await?,
+        genesis_state,
+        cluster_id,
+        &cluster_key,
+    );
+    log::info!(
+        "Replaying cluster {cluster_id:?} from block {}",
+        args.start_at
+    );
+
+    let cache = args
+        .cache_uri
+        .as_ref()
+        .map(|uri| pherry::headers_cache::Client::new(uri));
+    let mut block_number = args.start_at + 1;
+    let mut n_contracts = 0;
+
+    loop {
+        loop {
+            if block_number >= args.stop_at.unwrap_or(std::u32::MAX) {
+                log::info!("Replay finished");
+                wait_forever().await;
+            }
+            if let Err(err) =
+                wait_for_block(&api, block_number, args.assume_finalized, args.live).await
+            {
+                log::error!("{}", err);
+                if restart_required(&err) {
+                    break;
+                }
+            }
+            log::info!("Fetching block {}", block_number);
+            let block = match fetch_block(&api, cache.as_ref(), block_number).await {
+                Ok(block) => block,
+                Err(err) => {
+                    log::error!("{}", err);
+                    if restart_required(&err) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            let deployed = replay.cluster_id().is_some();
+            let messages = replay
+                .dispatch_block(block)
+                .expect("Failed to replay block");
+            if !deployed && replay.cluster_id().is_some() {
+                log::info!("Cluster {cluster_id:?} deployed at block {block_number}");
+            }
+            let contracts = replay.contracts();
+            if contracts.len() != n_contracts {
+                log::info!(
+                    "{} contracts in the cluster at block {block_number}",
+                    contracts.len()
+                );
+                n_contracts = contracts.len();
+            }
+            for message in &messages {
+                let json = message_json(block_number, message);
+                log::debug!(target: "cluster_egress", "{json}");
+                if let Some(output) = output.as_mut() {
+                    writeln!(output, "{json}").context("Failed to write the output")?;
+                }
+            }
+            block_number += 1;
+        }
+
+        api = loop {
+            log::info!("Reconnecting to substrate");
+            match pherry::subxt_connect_with_profile(&args.node_uri, profile.clone()).await {
+                Ok(client) => break client,
+                Err(err) => {
+                    log::error!("Failed to connect to substrate: {}", err);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,15 @@
+mod auth;
+mod block_time;
+mod cohort;
+mod compare;
+mod csv;
 mod data_persist;
 mod httpserver;
+mod import;
+mod manifest;
+mod snapshot;
+mod verify;
+mod webhooks;
 
 use std::{
     fs::File,
@@ -13,16 +23,26 @@ use anyhow::Error;
 use anyhow::Result;
 use phactory::{gk, BaseBlockInfo, ChainStorage};
 use phactory_api::blocks::BlockHeaderWithChanges;
-use phala_mq::{MessageDispatcher, Path as MqPath, Sr25519Signer, Topic};
-use phala_types::WorkerPublicKey;
+use phala_mq::{
+    DispatchMiddleware, Message, MessageDispatcher, Path as MqPath, Sr25519Signer, Topic,
+};
+use phala_types::{messaging::TokenomicParameters, WorkerPublicKey};
 use phaxt::rpc::ExtraRpcExt as _;
-use pherry::types::{phaxt, subxt, BlockNumber, NumberOrHex, ParachainApi, StorageKey};
+use pherry::types::{phaxt, BlockNumber, ParachainApi};
 use serde::{Deserialize, Serialize};
+use sp_runtime::traits::Header as _;
 use tokio::sync::{mpsc, Mutex};
 
 use crate::Args;
 
-type RecordSender = mpsc::Sender<EventRecord>;
+type RecordSender = mpsc::Sender<PersistRecord>;
+
+#[derive(Debug)]
+enum PersistRecord {
+    Event(EventRecord),
+    TokenomicParameters(TokenomicParamsRecord),
+    WorkerSnapshot(snapshot::SnapshotRecord),
+}
 
 #[derive(Debug)]
 struct EventRecord {
@@ -33,6 +53,50 @@ struct EventRecord {
     event: gk::EconomicEvent,
     v: gk::FixedPoint,
     p: gk::FixedPoint,
+    /// The computing session of the worker when the event was emitted.
+    session_id: Option<u32>,
+    /// The reward halving period of the block, read from the chain storage.
+    halving_period: Option<u32>,
+}
+
+/// A tokenomic parameter set applied by the GK.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenomicParamsRecord {
+    block_number: BlockNumber,
+    time_ms: u64,
+    params: TokenomicParameters,
+}
+
+impl TokenomicParamsRecord {
+    /// The parameters with the fixed point fields decoded to decimal strings.
+    fn params_json(&self) -> serde_json::Value {
+        let fp = |bits: u128| gk::FixedPoint::from_bits(bits).to_string();
+        let p = &self.params;
+        serde_json::json!({
+            "pha_rate": fp(p.pha_rate),
+            "rho": fp(p.rho),
+            "budget_per_block": fp(p.budget_per_block),
+            "v_max": fp(p.v_max),
+            "cost_k": fp(p.cost_k),
+            "cost_b": fp(p.cost_b),
+            "slash_rate": fp(p.slash_rate),
+            "treasury_ratio": fp(p.treasury_ratio),
+            "heartbeat_window": p.heartbeat_window,
+            "rig_k": fp(p.rig_k),
+            "rig_b": fp(p.rig_b),
+            "re": fp(p.re),
+            "k": fp(p.k),
+            "kappa": fp(p.kappa),
+        })
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "block": self.block_number,
+            "time_ms": self.time_ms,
+            "params": self.params_json(),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,11 +109,44 @@ pub struct ReplayFactory {
     recv_mq: MessageDispatcher,
     gk: gk::ComputingEconomics<ReplayMsgChannel>,
     gk_launched: bool,
+    #[serde(skip)]
+    #[serde(default)]
+    finalized_block: BlockNumber,
+    /// Forked again from `gk` when restoring from a checkpoint.
+    #[serde(skip)]
+    #[serde(default)]
+    shadow_gk: Option<compare::ShadowGk>,
+    /// Loaded again when restoring from a checkpoint.
+    #[serde(skip)]
+    #[serde(default)]
+    imported_events: Option<import::ImportedEvents>,
+    /// Records the blocks replayed after a restore only.
+    #[serde(skip)]
+    #[serde(default)]
+    manifest_writer: Option<manifest::ManifestWriter>,
+    /// Loaded again when restoring from a checkpoint.
+    #[serde(skip)]
+    #[serde(default)]
+    manifest_verifier: Option<manifest::ManifestVerifier>,
+    /// Every tokenomic parameter set applied since the GK launched.
+    #[serde(default)]
+    tokenomic_timeline: Vec<TokenomicParamsRecord>,
+    #[serde(default)]
+    block_times: block_time::BlockTimes,
+    /// Loaded again when restoring from a checkpoint.
+    #[serde(skip)]
+    #[serde(default)]
+    webhooks: webhooks::Webhooks,
+    /// Configured again when restoring from a checkpoint.
+    #[serde(skip)]
+    #[serde(default)]
+    snapshots: Option<snapshot::Snapshots>,
 }
 
 impl ReplayFactory {
     fn new(genesis_state: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
         let mut recv_mq = MessageDispatcher::new();
+        recv_mq.add_middleware(Arc::new(LogMessages));
         let mut storage = ChainStorage::default();
         storage.load(genesis_state.into_iter());
         let gk = gk::ComputingEconomics::new(&mut recv_mq, ReplayMsgChannel);
@@ -60,9 +157,28 @@ impl ReplayFactory {
             recv_mq,
             gk,
             gk_launched: false,
+            finalized_block: 0,
+            shadow_gk: None,
+            imported_events: None,
+            manifest_writer: None,
+            manifest_verifier: None,
+            tokenomic_timeline: vec![],
+            block_times: Default::default(),
+            webhooks: Default::default(),
+            snapshots: None,
         }
     }
 
+    /// Replays a GK of the given variant side by side with the current one.
+    fn enable_comparison(&mut self, variant: &str) {
+        log::info!("Comparing GK with variant {}", variant);
+        let gk = compare::fork_variant(variant, &self.gk, &mut self.recv_mq);
+        self.shadow_gk = Some(compare::ShadowGk {
+            variant: variant.into(),
+            gk,
+        });
+    }
+
     async fn dispatch_block(
         &mut self,
         block: BlockHeaderWithChanges,
@@ -81,21 +197,60 @@ impl ReplayFactory {
         self.storage
             .inner_mut()
             .apply_changes(state_root, transaction);
-        self.handle_inbound_messages(header.number, event_tx)
+        let (mq_messages, events) = self
+            .handle_inbound_messages(header.number, event_tx)
             .await?;
+        if self.manifest_writer.is_some() || self.manifest_verifier.is_some() {
+            let entry = manifest::BlockEntry {
+                number: header.number,
+                hash: header.hash(),
+                state_root,
+                mq_messages: mq_messages as _,
+                events: events as _,
+            };
+            if let Some(verifier) = self.manifest_verifier.as_mut() {
+                if let Err(err) = verifier.verify(&entry) {
+                    log::error!("Manifest {}", err);
+                    return Err("Manifest diverged");
+                }
+            }
+            if let Some(writer) = self.manifest_writer.as_mut() {
+                writer.record(entry);
+            }
+        }
         self.current_block = header.number;
         Ok(())
     }
 
+    /// Writes the manifest of the replayed blocks, if requested, and ensures that the replay
+    /// covered the whole verified manifest.
+    fn finish_manifest(&self) -> Result<()> {
+        if let Some(verifier) = &self.manifest_verifier {
+            if !verifier.finished() {
+                anyhow::bail!("The replay stopped before the end of the verified manifest");
+            }
+        }
+        match &self.manifest_writer {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the number of mq messages and the number of GK events of the block.
     async fn handle_inbound_messages(
         &mut self,
         block_number: BlockNumber,
         event_tx: &Option<RecordSender>,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(usize, usize), &'static str> {
         // Dispatch events
         let messages = self.storage.mq_messages();
+        let n_messages = messages.len();
 
-        let now_ms = self.storage.timestamp_now();
+        let now_ms = self
+            .block_times
+            .time_ms(block_number, self.storage.try_timestamp_now())
+            .ok_or("Block time unavailable")?;
+        let halving_period = self.storage.halving_period(block_number);
 
         let block = BaseBlockInfo {
             block_number,
@@ -109,6 +264,14 @@ impl ReplayFactory {
 
         let next_seq = &mut self.next_event_seq;
         let mut records = vec![];
+        let mut params_records = vec![];
+        let mut record_params = |params: TokenomicParameters| {
+            params_records.push(TokenomicParamsRecord {
+                block_number,
+                time_ms: now_ms,
+                params,
+            });
+        };
         let mut event_handler = |event: gk::EconomicEvent, state: &gk::WorkerInfo| {
             log::debug!(target: "event", "event={event:?}, state={state:?}");
             let record = EventRecord {
@@ -119,37 +282,96 @@ impl ReplayFactory {
                 event,
                 v: state.tokenomic_info().v,
                 p: state.tokenomic_info().p_instant,
+                session_id: state.session_id(),
+                halving_period,
             };
             records.push(record);
             *next_seq += 1;
         };
 
+        let mut shadow = self.shadow_gk.as_mut();
+        let mut shadow_events = vec![];
+        let mut shadow_handler = |event: gk::EconomicEvent, state: &gk::WorkerInfo| {
+            shadow_events.push((*state.pubkey(), event));
+        };
+
         self.gk.will_process_block(&block);
+        if let Some(shadow) = shadow.as_mut() {
+            shadow.gk.will_process_block(&block);
+        }
         for message in messages {
-            log::debug!(
-                target: "event",
-                "mq message: sender={}, dst={:?}, payload={}",
-                message.sender,
-                message.destination,
-                crate::helper::try_decode_message(message.destination.path(), &message.payload)
-            );
             if !self.gk_launched {
                 if !crate::helper::is_gk_launch(&message) {
                     continue;
                 }
                 log::info!("GK launched");
                 if let Some(params) = self.storage.tokenomic_parameters() {
+                    if let Some(shadow) = shadow.as_mut() {
+                        shadow.gk.update_tokenomic_parameters(params.clone());
+                    }
+                    record_params(params.clone());
                     self.gk.update_tokenomic_parameters(params);
                 }
                 self.gk_launched = true;
             }
+            if let Some(params) = crate::helper::tokenomic_parameters_changed(&message) {
+                record_params(params);
+            }
             block.recv_mq.dispatch(message);
             self.gk.process_messages(&block, &mut event_handler);
+            if let Some(shadow) = shadow.as_mut() {
+                shadow.gk.process_messages(&block, &mut shadow_handler);
+            }
         }
+        let mut n_events = 0;
         if self.gk_launched {
             self.gk.did_process_block(&block, &mut event_handler);
+            n_events = records.len();
+
+            if let Some(shadow) = shadow {
+                shadow.gk.did_process_block(&block, &mut shadow_handler);
+                let events: Vec<_> = records.iter().map(|r| (r.pubkey, r.event)).collect();
+                let workers = self.gk.dump_workers_state();
+                if let Err(err) = shadow.compare(block_number, &events, &shadow_events, &workers) {
+                    log::error!("GK {}", err);
+                    return Err("GK variants diverged");
+                }
+            }
+            if let Some(imported) = self.imported_events.as_mut() {
+                if let Err(err) = imported.compare(block_number, &records) {
+                    log::error!("Imported {}", err);
+                    return Err("Imported events diverged");
+                }
+            }
+
+            for record in &params_records {
+                log::info!("Tokenomic parameters changed at {}", record.block_number);
+            }
+            self.tokenomic_timeline
+                .extend(params_records.iter().cloned());
+            self.webhooks.process(&records);
+
+            let mut snapshot_record = None;
+            if let Some(snapshots) = self.snapshots.as_mut() {
+                if snapshots.is_due(block_number) {
+                    let record = snapshot::SnapshotRecord::take(
+                        block_number,
+                        now_ms,
+                        self.gk.dump_workers_state(),
+                    );
+                    snapshot_record = snapshots.write(record).map_err(|err| {
+                        log::error!("Failed to write the snapshot: {:?}", err);
+                        "Failed to write the snapshot"
+                    })?;
+                }
+            }
 
             if let Some(tx) = event_tx.as_ref() {
+                let records = params_records
+                    .into_iter()
+                    .map(PersistRecord::TokenomicParameters)
+                    .chain(records.into_iter().map(PersistRecord::Event))
+                    .chain(snapshot_record.map(PersistRecord::WorkerSnapshot));
                 for record in records {
                     match tx.send(record).await {
                         Ok(()) => (),
@@ -166,7 +388,7 @@ impl ReplayFactory {
             log::warn!("There are {} unhandled messages dropped", n_unhandled);
         }
 
-        Ok(())
+        Ok((n_messages, n_events))
     }
 
     fn load(reader: impl Read) -> Self {
@@ -176,6 +398,7 @@ impl ReplayFactory {
                 serde_cbor::from_reader(reader).expect("Failed to load checkpoint")
             });
         factory.recv_mq = dispatcher;
+        factory.recv_mq.add_middleware(Arc::new(LogMessages));
         factory
     }
 
@@ -194,6 +417,22 @@ impl ReplayFactory {
     }
 }
 
+/// Logs the mq messages dispatched to the GK.
+struct LogMessages;
+
+impl DispatchMiddleware for LogMessages {
+    fn before_dispatch(&self, message: &Message) -> bool {
+        log::debug!(
+            target: "event",
+            "mq message: sender={}, dst={:?}, payload={}",
+            message.sender,
+            message.destination,
+            crate::helper::try_decode_message(message.destination.path(), &message.payload)
+        );
+        true
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ReplayMsgChannel;
 
@@ -210,20 +449,6 @@ impl phala_mq::traits::MessageChannel for ReplayMsgChannel {
     }
 }
 
-pub async fn fetch_genesis_storage(
-    api: &ParachainApi,
-    pos: BlockNumber,
-) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
-    let pos = subxt::rpc::types::BlockNumber::from(NumberOrHex::Number(pos.into()));
-    let hash = api.rpc().block_hash(Some(pos)).await?;
-    let response = api
-        .extra_rpc()
-        .storage_pairs(StorageKey(vec![]), hash)
-        .await?;
-    let storage = response.into_iter().map(|(k, v)| (k.0, v.0)).collect();
-    Ok(storage)
-}
-
 async fn finalized_number(api: &ParachainApi) -> Result<BlockNumber> {
     let hash = api.rpc().finalized_head().await?;
     let header = api.rpc().header(Some(hash)).await?;
@@ -232,16 +457,28 @@ async fn finalized_number(api: &ParachainApi) -> Result<BlockNumber> {
         .number)
 }
 
-async fn wait_for_block(
+/// Waits until the given block is finalized, returns the finalized block number.
+pub(crate) async fn wait_for_block(
     api: &ParachainApi,
     block: BlockNumber,
     assume_finalized: u32,
-) -> Result<()> {
+    live: bool,
+) -> Result<BlockNumber> {
     loop {
         let finalized = finalized_number(api).await.unwrap_or(0);
         let state = api.extra_rpc().system_sync_state().await?;
         if block <= state.current_block as BlockNumber && block <= finalized.max(assume_finalized) {
-            return Ok(());
+            return Ok(finalized);
+        }
+        if live {
+            // Waiting for the next block is the normal state once caught up.
+            log::debug!(
+                "Waiting for {} to be finalized. (finalized={})",
+                block,
+                finalized
+            );
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
         }
         log::info!(
             "Waiting for {} to be finalized. (finalized={}, assume_finalized={}, latest={})",
@@ -256,39 +493,96 @@ async fn wait_for_block(
 
 pub async fn replay(args: Args) -> Result<()> {
     let db_uri = args.persist_events_to;
+    let persist_events = args.persist_events;
     let bind_addr = args.bind_addr;
+    let live = args.live;
     let assume_finalized = args.assume_finalized;
+    let auth = auth::Auth::new(args.read_tokens, args.admin_tokens);
+    let profile = phaxt::ChainProfile::resolve(&args.chain_profile)?;
 
-    let mut api: ParachainApi = pherry::subxt_connect(&args.node_uri)
+    let mut api: ParachainApi = pherry::subxt_connect_with_profile(&args.node_uri, profile.clone())
         .await
         .expect("Failed to connect to substrate");
     log::info!("Connected to substrate at: {}", args.node_uri);
 
-    let genesis_state = fetch_genesis_storage(&api, args.start_at).await?;
+    let genesis_state = crate::genesis_state::load(
+        &api,
+        args.start_at,
+        args.genesis_state_file.as_deref(),
+        &args.genesis_cache_dir,
+    )
+    .await?;
     let event_tx = if !db_uri.is_empty() {
         let (event_tx, event_rx) = mpsc::channel(1024 * 5);
-        let _db_task =
-            tokio::spawn(async move { data_persist::run_persist(event_rx, &db_uri).await });
+        let _db_task = tokio::spawn(async move {
+            data_persist::run_persist(event_rx, &db_uri, persist_events).await
+        });
         Some(event_tx)
     } else {
         None
     };
 
-    let factory = match get_checkpoint_path(&args.restore_from) {
+    let mut factory = match get_checkpoint_path(&args.restore_from) {
         Some(filename) => {
             log::info!("Restoring from checkpoint: {}", filename);
             ReplayFactory::load_from_file(&filename)
         }
         None => ReplayFactory::new(genesis_state),
     };
+    if let Some(variant) = &args.compare_gk {
+        factory.enable_comparison(variant);
+    }
+    if let Some(filename) = &args.import_events {
+        factory.imported_events = Some(import::ImportedEvents::load(
+            filename,
+            args.import_tolerance,
+        )?);
+    }
+    if let Some(filename) = &args.manifest_out {
+        let key = args
+            .manifest_key
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("--manifest-key is required to sign the manifest"))?;
+        factory.manifest_writer = Some(manifest::ManifestWriter::new(filename, key)?);
+    }
+    if let Some(filename) = &args.manifest_verify {
+        factory.manifest_verifier = Some(manifest::ManifestVerifier::load(
+            filename,
+            args.manifest_signer.as_deref(),
+        )?);
+    }
+    if let Some(filename) = &args.webhook_rules {
+        factory.webhooks = webhooks::Webhooks::load(filename)?;
+    }
+    if args.snapshot_interval > 0 {
+        factory.snapshots = Some(snapshot::Snapshots::new(
+            args.snapshot_interval,
+            args.snapshot_to.as_deref(),
+            event_tx.is_some(),
+        )?);
+    }
+    factory
+        .block_times
+        .configure(args.block_times.as_deref(), args.block_interval_ms)?;
     let mut last_checkpoint_block: BlockNumber = factory.current_block;
     let factory = Arc::new(Mutex::new(factory));
 
+    let cache = args
+        .cache_uri
+        .as_ref()
+        .map(|uri| pherry::headers_cache::Client::new(uri));
+    let blocks = Arc::new(BlockSource {
+        api: std::sync::Mutex::new(api.clone()),
+        cache: cache.clone(),
+        start_at: args.start_at,
+    });
+
     let _http_task = std::thread::spawn({
         let factory = factory.clone();
+        let blocks = blocks.clone();
         move || {
             let system = actix_rt::System::new();
-            system.block_on(httpserver::serve(bind_addr, factory))
+            system.block_on(httpserver::serve(bind_addr, factory, blocks, live, auth))
         }
     });
 
@@ -298,37 +592,36 @@ pub async fn replay(args: Args) -> Result<()> {
         last_checkpoint_block + 1
     };
 
-    let cache = args
-        .cache_uri
-        .as_ref()
-        .map(|uri| pherry::headers_cache::Client::new(uri));
-
     loop {
         loop {
             if block_number >= args.stop_at.unwrap_or(std::u32::MAX) {
                 log::info!("Replay finished");
+                factory.lock().await.finish_manifest()?;
+                if args.verify_sessions {
+                    verify::verify_sessions(&api, &*factory.lock().await).await?;
+                }
                 wait_forever().await;
             }
-            if let Err(err) = wait_for_block(&api, block_number, assume_finalized).await {
-                log::error!("{}", err);
-                if restart_required(&err) {
-                    break;
+            match wait_for_block(&api, block_number, assume_finalized, live).await {
+                Ok(finalized) => {
+                    factory.lock().await.finalized_block = finalized;
+                }
+                Err(err) => {
+                    log::error!("{}", err);
+                    if restart_required(&err) {
+                        break;
+                    }
                 }
             }
             log::info!("Fetching block {}", block_number);
-            match pherry::fetch_storage_changes(&api, cache.as_ref(), block_number, block_number)
-                .await
-            {
-                Ok(mut blocks) => {
-                    let mut block = blocks.pop().expect("Expected one block");
-                    let (header, _hash) = pherry::get_header_at(&api, Some(block_number)).await?;
-                    block.block_header = header;
+            match fetch_block(&api, cache.as_ref(), block_number).await {
+                Ok(block) => {
                     log::info!("Replaying block {}", block_number);
                     let mut factory = factory.lock().await;
                     factory
                         .dispatch_block(block, &event_tx)
                         .await
-                        .expect("Block is valid");
+                        .expect("Failed to replay block");
                     if args.checkpoint_interval > 0
                         && block_number >= args.checkpoint_interval + last_checkpoint_block
                     {
@@ -358,27 +651,60 @@ pub async fn replay(args: Args) -> Result<()> {
 
         api = loop {
             log::info!("Reconnecting to substrate");
-            let api = match pherry::subxt_connect(&args.node_uri).await {
-                Ok(client) => client,
-                Err(err) => {
-                    log::error!("Failed to connect to substrate: {}", err);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    continue;
-                }
-            };
+            let api =
+                match pherry::subxt_connect_with_profile(&args.node_uri, profile.clone()).await {
+                    Ok(client) => client,
+                    Err(err) => {
+                        log::error!("Failed to connect to substrate: {}", err);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        continue;
+                    }
+                };
             break api;
-        }
+        };
+        *blocks.api.lock().unwrap() = api.clone();
+    }
+}
+
+/// Fetches the blocks again for the HTTP server, with the connection of the replay.
+pub(crate) struct BlockSource {
+    api: std::sync::Mutex<ParachainApi>,
+    cache: Option<pherry::headers_cache::Client>,
+    /// The blocks after it are replayed.
+    start_at: BlockNumber,
+}
+
+impl BlockSource {
+    pub(crate) fn start_at(&self) -> BlockNumber {
+        self.start_at
+    }
+
+    pub(crate) async fn fetch(&self, block_number: BlockNumber) -> Result<BlockHeaderWithChanges> {
+        let api = self.api.lock().unwrap().clone();
+        fetch_block(&api, self.cache.as_ref(), block_number).await
     }
 }
 
-async fn wait_forever() {
+pub(crate) async fn fetch_block(
+    api: &ParachainApi,
+    cache: Option<&pherry::headers_cache::Client>,
+    block_number: BlockNumber,
+) -> Result<BlockHeaderWithChanges> {
+    let mut blocks = pherry::fetch_storage_changes(api, cache, block_number, block_number).await?;
+    let mut block = blocks.pop().expect("Expected one block");
+    let (header, _hash) = pherry::get_header_at(api, Some(block_number)).await?;
+    block.block_header = header;
+    Ok(block)
+}
+
+pub(crate) async fn wait_forever() {
     loop {
         tokio::time::sleep(Duration::from_secs(1000)).await;
     }
 }
 
-fn restart_required(error: &Error) -> bool {
-    format!("{error}").contains("restart required")
+pub(crate) fn restart_required(error: &Error) -> bool {
+    phaxt::ChainError::classify(error).is_restart_required()
 }
 
 fn get_checkpoint_path(from: &Option<String>) -> Option<String> {
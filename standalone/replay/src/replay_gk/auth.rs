@@ -0,0 +1,122 @@
+//! Token based authentication of the HTTP server.
+//!
+//! Each token is granted either the read-only or the admin role. Requests must carry one of the
+//! tokens in an `Authorization: Bearer <token>` header. GET and HEAD requests require the
+//! read-only role, any other method changes the replay state and requires the admin role.
+//!
+//! When no token is configured the server is left open, as it used to be.
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    HttpResponse,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+impl Role {
+    fn required_by(method: &Method) -> Self {
+        if method == Method::GET || method == Method::HEAD {
+            Role::ReadOnly
+        } else {
+            Role::Admin
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Auth {
+    tokens: Arc<Vec<(String, Role)>>,
+}
+
+impl Auth {
+    pub fn new(read_tokens: Vec<String>, admin_tokens: Vec<String>) -> Self {
+        let tokens = read_tokens
+            .into_iter()
+            .map(|token| (token, Role::ReadOnly))
+            .chain(admin_tokens.into_iter().map(|token| (token, Role::Admin)))
+            .filter(|(token, _)| !token.is_empty())
+            .collect();
+        Self {
+            tokens: Arc::new(tokens),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    fn role_of(&self, token: &str) -> Option<Role> {
+        // Go through all the tokens so that the time taken does not tell which one matched.
+        let mut role = None;
+        for (candidate, candidate_role) in self.tokens.iter() {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                role = role.max(Some(*candidate_role));
+            }
+        }
+        role
+    }
+
+    /// Returns the error response if the request is not allowed.
+    fn check(&self, req: &ServiceRequest) -> Result<(), HttpResponse> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        let Some(role) = token.and_then(|token| self.role_of(token)) else {
+            return Err(HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(serde_json::json!({
+                    "error": "Missing or invalid token"
+                })));
+        };
+        if role < Role::required_by(req.method()) {
+            return Err(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Admin role required"
+            })));
+        }
+        Ok(())
+    }
+
+    /// The middleware function, to be installed with `App::wrap_fn`.
+    pub fn middleware<S>(
+        &self,
+        req: ServiceRequest,
+        srv: &S,
+    ) -> Pin<Box<dyn Future<Output = Result<ServiceResponse, actix_web::Error>>>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+        S::Future: 'static,
+    {
+        match self.check(&req) {
+            Ok(()) => Box::pin(srv.call(req)),
+            Err(response) => {
+                log::warn!(
+                    "Rejected {} {}: {}",
+                    req.method(),
+                    req.path(),
+                    response.status()
+                );
+                Box::pin(async move { Ok(req.into_response(response)) })
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
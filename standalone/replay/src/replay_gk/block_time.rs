@@ -0,0 +1,88 @@
+//! The time of the replayed blocks.
+//!
+//! The time is read from the timestamp pallet in the chain storage. Some historical ranges have
+//! blocks where it is missing or fails to decode after runtime upgrades, for them the time is
+//! taken from the mapping file given by `--block-times` if any, or extrapolated from the times of
+//! the preceding blocks.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::BlockNumber;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct BlockTimes {
+    /// Loaded from the mapping file, not persisted in the checkpoints.
+    #[serde(skip)]
+    mapping: BTreeMap<BlockNumber, u64>,
+    #[serde(skip)]
+    default_interval_ms: u64,
+    /// The last two blocks with a known time, the latest first.
+    known: Vec<(BlockNumber, u64)>,
+}
+
+impl BlockTimes {
+    /// Configures the fallbacks. `mapping_file` holds lines of `block,time_ms`, lines starting
+    /// with `#` are ignored.
+    pub fn configure(
+        &mut self,
+        mapping_file: Option<&str>,
+        default_interval_ms: u64,
+    ) -> Result<()> {
+        self.default_interval_ms = default_interval_ms;
+        let Some(filename) = mapping_file else {
+            return Ok(());
+        };
+        let content = std::fs::read_to_string(filename)
+            .with_context(|| format!("Failed to read block times from {filename}"))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parse = || -> Option<(BlockNumber, u64)> {
+                let (block, time) = line.split_once(',')?;
+                Some((block.trim().parse().ok()?, time.trim().parse().ok()?))
+            };
+            let (block, time_ms) = parse()
+                .with_context(|| format!("Invalid block time at {filename}:{}", lineno + 1))?;
+            self.mapping.insert(block, time_ms);
+        }
+        log::info!("Loaded {} block times from {filename}", self.mapping.len());
+        Ok(())
+    }
+
+    /// The time of `block`, given its timestamp read from the chain storage.
+    pub fn time_ms(&mut self, block: BlockNumber, on_chain: Option<u64>) -> Option<u64> {
+        if let Some(time_ms) = on_chain {
+            self.record(block, time_ms);
+            return Some(time_ms);
+        }
+        if let Some(&time_ms) = self.mapping.get(&block) {
+            log::warn!("Timestamp missing at block {block}, using {time_ms} from the mapping");
+            self.record(block, time_ms);
+            return Some(time_ms);
+        }
+        let time_ms = self.extrapolate(block)?;
+        log::warn!("Timestamp missing at block {block}, extrapolated to {time_ms}");
+        Some(time_ms)
+    }
+
+    fn record(&mut self, block: BlockNumber, time_ms: u64) {
+        self.known.insert(0, (block, time_ms));
+        self.known.truncate(2);
+    }
+
+    fn extrapolate(&self, block: BlockNumber) -> Option<u64> {
+        let &(last_block, last_time) = self.known.first()?;
+        let interval_ms = match self.known.get(1) {
+            Some(&(prev_block, prev_time)) if prev_block < last_block => {
+                last_time.saturating_sub(prev_time) / (last_block - prev_block) as u64
+            }
+            _ => self.default_interval_ms,
+        };
+        Some(last_time + interval_ms * block.saturating_sub(last_block) as u64)
+    }
+}
@@ -0,0 +1,190 @@
+//! Aggregates over all the workers in the replayed state, for fleet-level tokenomics analysis.
+//!
+//! The workers can be grouped by their confidence level, read from the registry in the chain
+//! storage. Workers missing from the registry fall into a group without a confidence level.
+
+use std::collections::BTreeMap;
+
+use phactory_api::prpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::ReplayFactory;
+
+const PERCENTILES: &[u32] = &[1, 5, 10, 25, 50, 75, 90, 95, 99];
+const DEFAULT_BUCKETS: usize = 20;
+const MAX_BUCKETS: usize = 200;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    ConfidenceLevel,
+}
+
+#[derive(Deserialize)]
+pub struct CohortQuery {
+    pub group_by: Option<GroupBy>,
+    /// Number of buckets of the histograms.
+    pub buckets: Option<usize>,
+}
+
+impl CohortQuery {
+    pub fn buckets(&self) -> usize {
+        self.buckets
+            .unwrap_or(DEFAULT_BUCKETS)
+            .clamp(1, MAX_BUCKETS)
+    }
+}
+
+struct WorkerSample {
+    state: &'static str,
+    v: Option<f64>,
+    p_instant: Option<f64>,
+}
+
+pub(super) fn state_name(state: &pb::WorkerState) -> &'static str {
+    if !state.registered {
+        return "unregistered";
+    }
+    if state.unresponsive {
+        return "unresponsive";
+    }
+    match &state.working_state {
+        Some(working) if working.paused => "paused",
+        Some(_) => "computing",
+        None if state.bench_state.is_some() => "benchmarking",
+        None => "idle",
+    }
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Nearest-rank percentiles, keyed by `p<N>`.
+    pub percentiles: BTreeMap<String, f64>,
+}
+
+impl Summary {
+    /// Summarizes the values, which must be sorted.
+    fn of(sorted: &[f64]) -> Option<Self> {
+        let (min, max) = (*sorted.first()?, *sorted.last()?);
+        let percentiles = PERCENTILES
+            .iter()
+            .map(|&p| {
+                let rank = (p as usize * sorted.len() + 99) / 100;
+                (format!("p{p}"), sorted[rank.max(1) - 1])
+            })
+            .collect();
+        Some(Self {
+            count: sorted.len(),
+            min,
+            max,
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            percentiles,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Bucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: usize,
+}
+
+/// Splits the range of the values, which must be sorted, into buckets of equal width.
+fn histogram(sorted: &[f64], buckets: usize) -> Vec<Bucket> {
+    let (Some(&min), Some(&max)) = (sorted.first(), sorted.last()) else {
+        return vec![];
+    };
+    let width = (max - min) / buckets as f64;
+    if width <= 0.0 {
+        return vec![Bucket {
+            lower: min,
+            upper: max,
+            count: sorted.len(),
+        }];
+    }
+    let mut ret: Vec<_> = (0..buckets)
+        .map(|i| Bucket {
+            lower: min + width * i as f64,
+            upper: min + width * (i + 1) as f64,
+            count: 0,
+        })
+        .collect();
+    for v in sorted {
+        let i = (((v - min) / width) as usize).min(buckets - 1);
+        ret[i].count += 1;
+    }
+    ret
+}
+
+#[derive(Serialize)]
+pub struct Cohort {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence_level: Option<u8>,
+    pub workers: usize,
+    pub states: BTreeMap<&'static str, usize>,
+    pub v: Option<Summary>,
+    pub p_instant: Option<Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v_histogram: Option<Vec<Bucket>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p_instant_histogram: Option<Vec<Bucket>>,
+}
+
+impl Cohort {
+    fn of(confidence_level: Option<u8>, samples: &[WorkerSample], buckets: Option<usize>) -> Self {
+        let mut states = BTreeMap::new();
+        for sample in samples {
+            *states.entry(sample.state).or_default() += 1;
+        }
+        let sorted = |f: fn(&WorkerSample) -> Option<f64>| {
+            let mut values: Vec<f64> = samples.iter().filter_map(f).collect();
+            values.sort_by(|a, b| a.total_cmp(b));
+            values
+        };
+        let v = sorted(|s| s.v);
+        let p_instant = sorted(|s| s.p_instant);
+        Self {
+            confidence_level,
+            workers: samples.len(),
+            states,
+            v: Summary::of(&v),
+            p_instant: Summary::of(&p_instant),
+            v_histogram: buckets.map(|n| histogram(&v, n)),
+            p_instant_histogram: buckets.map(|n| histogram(&p_instant, n)),
+        }
+    }
+}
+
+/// Aggregates the workers into cohorts. Histograms are only computed if `buckets` is given.
+pub fn cohorts(
+    factory: &ReplayFactory,
+    group_by: Option<GroupBy>,
+    buckets: Option<usize>,
+) -> Vec<Cohort> {
+    let mut groups = BTreeMap::<Option<u8>, Vec<WorkerSample>>::new();
+    for (pubkey, state) in factory.gk.dump_workers_state() {
+        let group = match group_by {
+            Some(GroupBy::ConfidenceLevel) => factory.storage.worker_confidence_level(&pubkey),
+            None => None,
+        };
+        let parse = |s: &str| s.parse::<f64>().ok();
+        let tokenomic = state.tokenomic_info.as_ref();
+        groups.entry(group).or_default().push(WorkerSample {
+            state: state_name(&state),
+            v: tokenomic.and_then(|t| parse(&t.v)),
+            p_instant: tokenomic.and_then(|t| parse(&t.p_instant)),
+        });
+    }
+    if groups.is_empty() {
+        groups.insert(None, vec![]);
+    }
+    groups
+        .into_iter()
+        .map(|(group, samples)| Cohort::of(group, &samples, buckets))
+        .collect()
+}
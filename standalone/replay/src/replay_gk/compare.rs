@@ -0,0 +1,118 @@
+//! Side by side replay of two GK implementations.
+//!
+//! A shadow GK is forked from the state of the replaying GK and fed with the same messages. After
+//! each block, the events emitted and the worker states of both are compared, and the replay is
+//! aborted at the first divergence.
+//!
+//! To validate a tokenomic refactor, compile the old or new algorithm in as another [`GkVariant`]
+//! and register it in [`fork_variant`] and in the `--compare-gk` values. The `current` variant forks the running implementation,
+//! which checks the replay is deterministic.
+
+use phactory::{gk, BaseBlockInfo};
+use phactory_api::prpc as pb;
+use phala_mq::MessageDispatcher;
+use phala_types::{messaging::TokenomicParameters, WorkerPublicKey};
+
+use super::{BlockNumber, ReplayMsgChannel};
+
+pub type EventListener<'a> = &'a mut dyn FnMut(gk::EconomicEvent, &gk::WorkerInfo);
+
+/// A GK implementation that can be replayed side by side with the current one.
+pub trait GkVariant: Send {
+    fn will_process_block(&mut self, block: &BaseBlockInfo);
+    fn process_messages(&mut self, block: &BaseBlockInfo, listener: EventListener);
+    fn did_process_block(&mut self, block: &BaseBlockInfo, listener: EventListener);
+    fn update_tokenomic_parameters(&mut self, params: TokenomicParameters);
+    fn dump_workers_state(&self) -> Vec<(WorkerPublicKey, pb::WorkerState)>;
+}
+
+impl GkVariant for gk::ComputingEconomics<ReplayMsgChannel> {
+    fn will_process_block(&mut self, block: &BaseBlockInfo) {
+        gk::ComputingEconomics::will_process_block(self, block)
+    }
+
+    fn process_messages(&mut self, block: &BaseBlockInfo, mut listener: EventListener) {
+        gk::ComputingEconomics::process_messages(self, block, &mut listener)
+    }
+
+    fn did_process_block(&mut self, block: &BaseBlockInfo, mut listener: EventListener) {
+        gk::ComputingEconomics::did_process_block(self, block, &mut listener)
+    }
+
+    fn update_tokenomic_parameters(&mut self, params: TokenomicParameters) {
+        gk::ComputingEconomics::update_tokenomic_parameters(self, params)
+    }
+
+    fn dump_workers_state(&self) -> Vec<(WorkerPublicKey, pb::WorkerState)> {
+        gk::ComputingEconomics::dump_workers_state(self)
+    }
+}
+
+/// Forks a GK of the given variant from the state of `gk`, subscribing it to `recv_mq`.
+pub fn fork_variant(
+    variant: &str,
+    gk: &gk::ComputingEconomics<ReplayMsgChannel>,
+    recv_mq: &mut MessageDispatcher,
+) -> Box<dyn GkVariant> {
+    match variant {
+        "current" => Box::new(fork_current(gk, recv_mq)),
+        _ => panic!("Unknown GK variant: {variant}"),
+    }
+}
+
+fn fork_current(
+    gk: &gk::ComputingEconomics<ReplayMsgChannel>,
+    recv_mq: &mut MessageDispatcher,
+) -> gk::ComputingEconomics<ReplayMsgChannel> {
+    // Cloning would share the message receivers, go through the checkpoint format instead to get
+    // receivers of its own.
+    let state = serde_cbor::to_vec(gk).expect("Failed to serialize the GK");
+    phala_mq::checkpoint_helper::using_dispatcher(recv_mq, || {
+        serde_cbor::from_slice(&state).expect("Failed to fork the GK")
+    })
+}
+
+pub struct ShadowGk {
+    pub variant: String,
+    pub gk: Box<dyn GkVariant>,
+}
+
+impl ShadowGk {
+    /// Compares the shadow against the replaying GK after a block processed by both.
+    ///
+    /// `events` and `shadow_events` are the events emitted in the block by the replaying GK and
+    /// by the shadow.
+    pub fn compare(
+        &self,
+        block_number: BlockNumber,
+        events: &[(WorkerPublicKey, gk::EconomicEvent)],
+        shadow_events: &[(WorkerPublicKey, gk::EconomicEvent)],
+        workers: &[(WorkerPublicKey, pb::WorkerState)],
+    ) -> Result<(), String> {
+        if shadow_events != events {
+            return Err(format!(
+                "events diverged at block {block_number}: current={events:?}, {}={shadow_events:?}",
+                self.variant
+            ));
+        }
+        let shadow_workers = self.gk.dump_workers_state();
+        if shadow_workers.len() != workers.len() {
+            return Err(format!(
+                "number of workers diverged at block {block_number}: current={}, {}={}",
+                workers.len(),
+                self.variant,
+                shadow_workers.len()
+            ));
+        }
+        for ((pubkey, state), (shadow_pubkey, shadow_state)) in workers.iter().zip(&shadow_workers)
+        {
+            if pubkey != shadow_pubkey || state != shadow_state {
+                return Err(format!(
+                    "worker state diverged at block {block_number}: current={pubkey:?}:{state:?}, {}={shadow_pubkey:?}:{shadow_state:?}",
+                    self.variant
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! Reading and writing the CSV files of the replay, as in RFC 4180: fields separated by commas,
+//! quoted with `"` when they contain a comma, a quote or a line break, and quotes in the quoted
+//! fields doubled.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// A row by the names in the header line.
+pub type Row = BTreeMap<String, String>;
+
+/// Reads a CSV file with a header line. The names and values are trimmed.
+pub fn read(filename: &str) -> Result<Vec<Row>> {
+    let content =
+        std::fs::read_to_string(filename).with_context(|| format!("Failed to read {filename}"))?;
+    let mut records = parse(&content)?.into_iter();
+    let Some(headers) = records.next() else {
+        bail!("Missing the header line");
+    };
+    let rows = records
+        .map(|record| {
+            headers
+                .iter()
+                .zip(record.iter())
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect()
+        })
+        .collect();
+    Ok(rows)
+}
+
+/// Splits the content into records of fields. Empty lines are skipped.
+fn parse(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quoted field at record {}", records.len() + 1);
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes a record, quoting the fields where needed.
+pub fn write_record(out: &mut impl Write, fields: &[&str]) -> io::Result<()> {
+    let line = fields
+        .iter()
+        .map(|field| {
+            if field.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(out, "{line}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_fields() {
+        let records = parse("a,b\r\n1,\"x, \"\"y\"\"\nz\"\n\n2,\n").unwrap();
+        assert_eq!(
+            records,
+            [vec!["a", "b"], vec!["1", "x, \"y\"\nz"], vec!["2", ""],]
+        );
+        assert!(parse("a\n\"b").is_err());
+    }
+
+    #[test]
+    fn written_records_parse_back() {
+        let fields = ["plain", "with,comma", "with \"quote\"", "two\nlines", ""];
+        let mut out = vec![];
+        write_record(&mut out, &fields).unwrap();
+        let records = parse(std::str::from_utf8(&out).unwrap()).unwrap();
+        assert_eq!(records, [fields.to_vec()]);
+    }
+}
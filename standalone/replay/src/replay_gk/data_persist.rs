@@ -1,32 +1,132 @@
-use super::EventRecord;
+use super::snapshot::SnapshotRecord;
+use super::{EventRecord, PersistRecord, TokenomicParamsRecord};
 use anyhow::Result;
 use chrono::{LocalResult, TimeZone as _, Utc};
+use clickhouse::ClickHouse;
 use phactory::gk;
 use sqlx::types::Decimal;
 use sqlx::{postgres::PgPoolOptions, Row};
 use std::time::Duration;
 use tokio::sync::mpsc;
 
-pub(super) async fn run_persist(mut rx: mpsc::Receiver<EventRecord>, uri: &str) {
+mod clickhouse;
+
+const CREATE_SNAPSHOTS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS worker_state_snapshots (
+    block integer NOT NULL,
+    time timestamp without time zone NOT NULL,
+    pubkey bytea NOT NULL,
+    v numeric NOT NULL,
+    p numeric NOT NULL,
+    state text NOT NULL,
+    PRIMARY KEY(block, pubkey)
+)
+"#;
+
+enum EventStore {
+    Postgres(sqlx::Pool<sqlx::Postgres>),
+    ClickHouse(ClickHouse),
+}
+
+impl EventStore {
+    async fn connect(uri: &str) -> Result<Self> {
+        if uri.starts_with("clickhouse://") || uri.starts_with("clickhouses://") {
+            return Ok(EventStore::ClickHouse(ClickHouse::connect(uri).await?));
+        }
+        let pool = PgPoolOptions::new().max_connections(5).connect(uri).await?;
+        // Columns added after the initial schema.
+        sqlx::query(
+            r#"
+            ALTER TABLE worker_finance_events
+                ADD COLUMN IF NOT EXISTS session_id integer,
+                ADD COLUMN IF NOT EXISTS halving_period integer
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // Tables added after the initial schema.
+        sqlx::query(CREATE_SNAPSHOTS_TABLE).execute(&pool).await?;
+        Ok(EventStore::Postgres(pool))
+    }
+
+    fn batch_size(&self) -> usize {
+        match self {
+            EventStore::Postgres(_) => 1000,
+            // ClickHouse prefers few large inserts.
+            EventStore::ClickHouse(_) => 100_000,
+        }
+    }
+
+    async fn insert_records(&self, records: &[EventRecord]) -> Result<()> {
+        match self {
+            EventStore::Postgres(pool) => insert_records(pool, records).await,
+            EventStore::ClickHouse(store) => store.insert_records(records).await,
+        }
+    }
+
+    async fn get_last_sequence(&self) -> Result<i64> {
+        match self {
+            EventStore::Postgres(pool) => get_last_sequence(pool).await,
+            EventStore::ClickHouse(store) => store.get_last_sequence().await,
+        }
+    }
+
+    async fn insert_tokenomic_parameters(&self, records: &[TokenomicParamsRecord]) -> Result<()> {
+        match self {
+            EventStore::Postgres(pool) => insert_tokenomic_parameters(pool, records).await,
+            EventStore::ClickHouse(store) => store.insert_tokenomic_parameters(records).await,
+        }
+    }
+
+    async fn insert_snapshot(&self, record: &SnapshotRecord) -> Result<()> {
+        match self {
+            EventStore::Postgres(pool) => insert_snapshot(pool, record).await,
+            EventStore::ClickHouse(store) => store.insert_snapshot(record).await,
+        }
+    }
+}
+
+/// Writes the received events to the database, a Postgres one or a ClickHouse one given a
+/// `clickhouse://` URI. If `events` is not empty, only the event types listed in it are written.
+/// Tokenomic parameter changes and worker state snapshots are always written.
+pub(super) async fn run_persist(
+    mut rx: mpsc::Receiver<PersistRecord>,
+    uri: &str,
+    events: Vec<String>,
+) {
     log::info!("Connecting to {}", uri);
+    if !events.is_empty() {
+        log::info!("Only persisting events: {}", events.join(","));
+    }
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(uri)
+    let store = EventStore::connect(uri)
         .await
         .expect("Connect to database failed");
+    let batch_size = store.batch_size();
 
     let mut stopped = false;
 
     while !stopped {
         let mut records = vec![];
+        let mut params_records = vec![];
+        let mut snapshots = vec![];
         loop {
             match tokio::time::timeout(Duration::from_secs(2), rx.recv()).await {
-                Ok(Some(record)) => {
+                Ok(Some(PersistRecord::TokenomicParameters(record))) => {
+                    params_records.push(record);
+                }
+                Ok(Some(PersistRecord::WorkerSnapshot(record))) => {
+                    snapshots.push(record);
+                }
+                Ok(Some(PersistRecord::Event(record))) => {
+                    if !events.is_empty()
+                        && !events.iter().any(|e| e == record.event.event_string())
+                    {
+                        continue;
+                    }
                     records.push(record);
 
-                    const BATCH_SIZE: usize = 1000;
-                    if records.len() >= BATCH_SIZE {
+                    if records.len() >= batch_size {
                         break;
                     }
                 }
@@ -41,17 +141,36 @@ pub(super) async fn run_persist(mut rx: mpsc::Receiver<EventRecord>, uri: &str)
                 }
             };
         }
+        if !params_records.is_empty() {
+            // Upserted by block, so simply retried until it succeeds.
+            while let Err(err) = store.insert_tokenomic_parameters(&params_records).await {
+                log::error!("Insert tokenomic parameters error: {}", err);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+        for snapshot in &snapshots {
+            log::info!(
+                "Inserting the snapshot of {} workers at {}.",
+                snapshot.workers.len(),
+                snapshot.block_number
+            );
+            // Upserted by block and worker, so simply retried until it succeeds.
+            while let Err(err) = store.insert_snapshot(snapshot).await {
+                log::error!("Insert snapshot error: {}", err);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
         if !records.is_empty() {
             log::info!("Inserting {} records.", records.len());
             'try_insert: loop {
-                match insert_records(&pool, &records).await {
+                match store.insert_records(&records).await {
                     Ok(()) => {
                         break;
                     }
                     Err(err) => {
                         log::error!("Insert {} records error.", records.len());
                         log::error!("{}", err);
-                        match get_last_sequence(&pool).await {
+                        match store.get_last_sequence().await {
                             Ok(last_sequence) => {
                                 log::info!("last_sequence={}", last_sequence);
                                 if last_sequence
@@ -90,6 +209,8 @@ async fn insert_records(pool: &sqlx::Pool<sqlx::Postgres>, records: &[EventRecor
     let mut vs = vec![];
     let mut ps = vec![];
     let mut payouts = vec![];
+    let mut session_ids = vec![];
+    let mut halving_periods = vec![];
 
     let last_seq = get_last_sequence(pool).await?;
 
@@ -108,6 +229,8 @@ async fn insert_records(pool: &sqlx::Pool<sqlx::Postgres>, records: &[EventRecor
         vs.push(cvt_fp(rec.v));
         ps.push(cvt_fp(rec.p));
         payouts.push(cvt_fp(rec.event.payout()));
+        session_ids.push(rec.session_id.map(|id| id as i32));
+        halving_periods.push(rec.halving_period.map(|period| period as i32));
     }
 
     if sequences.is_empty() {
@@ -117,14 +240,14 @@ async fn insert_records(pool: &sqlx::Pool<sqlx::Postgres>, records: &[EventRecor
     sqlx::query(
         r#"
         INSERT INTO worker_finance_events
-            (sequence, pubkey, block, time, event, v, p, payout)
+            (sequence, pubkey, block, time, event, v, p, payout, session_id, halving_period)
         SELECT *
-        FROM UNNEST($1, $2, $3, $4, $5, $6, $7, $8)
+        FROM UNNEST($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
         ON CONFLICT (time, sequence)
         DO UPDATE
-        SET (pubkey, block, event, v, p, payout) = (
+        SET (pubkey, block, event, v, p, payout, session_id, halving_period) = (
             EXCLUDED.pubkey, EXCLUDED.block, EXCLUDED.event, EXCLUDED.v, EXCLUDED.p,
-            EXCLUDED.payout
+            EXCLUDED.payout, EXCLUDED.session_id, EXCLUDED.halving_period
         )
         "#,
     )
@@ -136,6 +259,8 @@ async fn insert_records(pool: &sqlx::Pool<sqlx::Postgres>, records: &[EventRecor
     .bind(&vs)
     .bind(&ps)
     .bind(&payouts)
+    .bind(&session_ids)
+    .bind(&halving_periods)
     .execute(pool)
     .await?;
 
@@ -144,6 +269,67 @@ async fn insert_records(pool: &sqlx::Pool<sqlx::Postgres>, records: &[EventRecor
     Ok(())
 }
 
+async fn insert_tokenomic_parameters(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    records: &[TokenomicParamsRecord],
+) -> Result<()> {
+    for rec in records {
+        let time = match Utc.timestamp_millis_opt(rec.time_ms as _) {
+            LocalResult::Single(ts) => ts,
+            _ => anyhow::bail!("Incorrect timestamp_millis"),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO tokenomic_parameters (block, time, params)
+            VALUES ($1, $2, $3::jsonb)
+            ON CONFLICT (block)
+            DO UPDATE SET (time, params) = (EXCLUDED.time, EXCLUDED.params)
+            "#,
+        )
+        .bind(rec.block_number)
+        .bind(time)
+        .bind(rec.params_json().to_string())
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn insert_snapshot(pool: &sqlx::Pool<sqlx::Postgres>, record: &SnapshotRecord) -> Result<()> {
+    if record.workers.is_empty() {
+        return Ok(());
+    }
+    let time = match Utc.timestamp_millis_opt(record.time_ms as _) {
+        LocalResult::Single(ts) => ts,
+        _ => anyhow::bail!("Incorrect timestamp_millis"),
+    };
+    let n = record.workers.len();
+    let pubkeys: Vec<_> = record.workers.iter().map(|w| w.pubkey.0.to_vec()).collect();
+    let vs: Vec<_> = record.workers.iter().map(|w| cvt_fp(w.v)).collect();
+    let ps: Vec<_> = record.workers.iter().map(|w| cvt_fp(w.p)).collect();
+    let states: Vec<_> = record.workers.iter().map(|w| w.state).collect();
+
+    sqlx::query(
+        r#"
+        INSERT INTO worker_state_snapshots (block, time, pubkey, v, p, state)
+        SELECT *
+        FROM UNNEST($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (block, pubkey)
+        DO UPDATE
+        SET (time, v, p, state) = (EXCLUDED.time, EXCLUDED.v, EXCLUDED.p, EXCLUDED.state)
+        "#,
+    )
+    .bind(vec![record.block_number as i32; n])
+    .bind(vec![time; n])
+    .bind(&pubkeys)
+    .bind(&vs)
+    .bind(&ps)
+    .bind(&states)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 fn cvt_fp(v: gk::FixedPoint) -> Decimal {
     Decimal::from_i128_with_scale((v * 10000000000).to_num(), 10)
 }
@@ -0,0 +1,215 @@
+//! ClickHouse event store, written through the HTTP interface.
+//!
+//! The URI is `clickhouse://[user[:password]@]host[:port][/database]`, or `clickhouses://` for
+//! HTTPS. The table is created if it doesn't exist. It is a ReplacingMergeTree ordered by the
+//! sequence, so rows inserted again after a failed batch are deduplicated like the upsert of the
+//! Postgres store.
+
+use super::{cvt_fp, EventRecord, SnapshotRecord, TokenomicParamsRecord};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{LocalResult, TimeZone as _, Utc};
+use reqwest::Url;
+
+const CREATE_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS worker_finance_events (
+    sequence Int64,
+    pubkey String,
+    block UInt32,
+    time DateTime64(3, 'UTC'),
+    event LowCardinality(String),
+    v Decimal(38, 10),
+    p Decimal(38, 10),
+    payout Decimal(38, 10),
+    session_id Nullable(UInt32),
+    halving_period Nullable(UInt32)
+)
+ENGINE = ReplacingMergeTree
+PARTITION BY toYYYYMM(time)
+ORDER BY sequence
+"#;
+
+/// Columns added after the initial schema.
+const MIGRATE_TABLE: &str = r#"
+ALTER TABLE worker_finance_events
+    ADD COLUMN IF NOT EXISTS session_id Nullable(UInt32),
+    ADD COLUMN IF NOT EXISTS halving_period Nullable(UInt32)
+"#;
+
+const CREATE_PARAMS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS tokenomic_parameters (
+    block UInt32,
+    time DateTime64(3, 'UTC'),
+    params String
+)
+ENGINE = ReplacingMergeTree
+ORDER BY block
+"#;
+
+const CREATE_SNAPSHOTS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS worker_state_snapshots (
+    block UInt32,
+    time DateTime64(3, 'UTC'),
+    pubkey String,
+    v Decimal(38, 10),
+    p Decimal(38, 10),
+    state LowCardinality(String)
+)
+ENGINE = ReplacingMergeTree
+ORDER BY (block, pubkey)
+"#;
+
+pub(super) struct ClickHouse {
+    client: reqwest::Client,
+    endpoint: Url,
+    database: Option<String>,
+    user: Option<(String, String)>,
+}
+
+impl ClickHouse {
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let uri = Url::parse(uri).context("Invalid ClickHouse URI")?;
+        let (scheme, default_port) = match uri.scheme() {
+            "clickhouse" => ("http", 8123),
+            "clickhouses" => ("https", 8443),
+            scheme => bail!("Unsupported scheme {scheme}"),
+        };
+        let host = uri
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing ClickHouse host"))?;
+        let port = uri.port().unwrap_or(default_port);
+        let endpoint = Url::parse(&format!("{scheme}://{host}:{port}/"))?;
+        let database = Some(uri.path().trim_start_matches('/'))
+            .filter(|db| !db.is_empty())
+            .map(|db| db.to_string());
+        let user = if uri.username().is_empty() {
+            None
+        } else {
+            Some((
+                uri.username().to_string(),
+                uri.password().unwrap_or_default().to_string(),
+            ))
+        };
+        let store = Self {
+            client: reqwest::Client::new(),
+            endpoint,
+            database,
+            user,
+        };
+        store.execute(CREATE_TABLE, String::new()).await?;
+        store.execute(MIGRATE_TABLE, String::new()).await?;
+        store.execute(CREATE_PARAMS_TABLE, String::new()).await?;
+        store.execute(CREATE_SNAPSHOTS_TABLE, String::new()).await?;
+        Ok(store)
+    }
+
+    async fn execute(&self, query: &str, body: String) -> Result<String> {
+        let mut url = self.endpoint.clone();
+        url.query_pairs_mut().append_pair("query", query);
+        if let Some(database) = &self.database {
+            url.query_pairs_mut().append_pair("database", database);
+        }
+        let mut request = self.client.post(url).body(body);
+        if let Some((user, password)) = &self.user {
+            request = request
+                .header("X-ClickHouse-User", user)
+                .header("X-ClickHouse-Key", password);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            bail!("ClickHouse responded {status}: {text}");
+        }
+        Ok(text)
+    }
+
+    pub async fn insert_records(&self, records: &[EventRecord]) -> Result<()> {
+        let mut body = String::new();
+        for rec in records {
+            let time = match Utc.timestamp_millis_opt(rec.time_ms as _) {
+                LocalResult::Single(ts) => ts,
+                _ => bail!("Incorrect timestamp_millis"),
+            };
+            let row = serde_json::json!({
+                "sequence": rec.sequence,
+                "pubkey": hex::encode(rec.pubkey.0),
+                "block": rec.block_number,
+                "time": time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                "event": rec.event.event_string(),
+                "v": cvt_fp(rec.v).to_string(),
+                "p": cvt_fp(rec.p).to_string(),
+                "payout": cvt_fp(rec.event.payout()).to_string(),
+                "session_id": rec.session_id,
+                "halving_period": rec.halving_period,
+            });
+            body.push_str(&row.to_string());
+            body.push('\n');
+        }
+        self.execute("INSERT INTO worker_finance_events FORMAT JSONEachRow", body)
+            .await?;
+        log::debug!("Inserted {} records.", records.len());
+        Ok(())
+    }
+
+    pub async fn insert_tokenomic_parameters(
+        &self,
+        records: &[TokenomicParamsRecord],
+    ) -> Result<()> {
+        let mut body = String::new();
+        for rec in records {
+            let time = match Utc.timestamp_millis_opt(rec.time_ms as _) {
+                LocalResult::Single(ts) => ts,
+                _ => bail!("Incorrect timestamp_millis"),
+            };
+            let row = serde_json::json!({
+                "block": rec.block_number,
+                "time": time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                "params": rec.params_json().to_string(),
+            });
+            body.push_str(&row.to_string());
+            body.push('\n');
+        }
+        self.execute("INSERT INTO tokenomic_parameters FORMAT JSONEachRow", body)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_snapshot(&self, record: &SnapshotRecord) -> Result<()> {
+        let time = match Utc.timestamp_millis_opt(record.time_ms as _) {
+            LocalResult::Single(ts) => ts,
+            _ => bail!("Incorrect timestamp_millis"),
+        };
+        let time = time.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+        let mut body = String::new();
+        for worker in &record.workers {
+            let row = serde_json::json!({
+                "block": record.block_number,
+                "time": time,
+                "pubkey": hex::encode(worker.pubkey.0),
+                "v": cvt_fp(worker.v).to_string(),
+                "p": cvt_fp(worker.p).to_string(),
+                "state": worker.state,
+            });
+            body.push_str(&row.to_string());
+            body.push('\n');
+        }
+        self.execute(
+            "INSERT INTO worker_state_snapshots FORMAT JSONEachRow",
+            body,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_last_sequence(&self) -> Result<i64> {
+        let text = self
+            .execute(
+                "SELECT max(sequence) FROM worker_finance_events FORMAT TabSeparated",
+                String::new(),
+            )
+            .await?;
+        text.trim()
+            .parse()
+            .context("Invalid sequence returned by ClickHouse")
+    }
+}
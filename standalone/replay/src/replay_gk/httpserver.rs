@@ -1,15 +1,77 @@
 use std::str::FromStr;
 
+use super::auth::Auth;
+use super::cohort::{self, CohortQuery};
+use super::webhooks::Rule;
 use super::*;
-use actix_web::{get, web, App, HttpResponse, HttpServer};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, App, HttpResponse, HttpServer, ResponseError};
+use replay::api::{
+    BlockMessagesResponse, CohortsResponse, MemInfoResponse, PayoutEstimateResponse,
+    PayoutEstimatesResponse, StatusResponse, TokenomicParametersResponse, WebhookRulesResponse,
+    WebhooksResponse, WorkerStateResponse, WorkersResponse,
+};
+use serde::Serialize;
 use sp_runtime::AccountId32;
 
+/// Generated from the routes below by the build script.
+const OPENAPI_SPEC: &str = include_str!(concat!(env!("OUT_DIR"), "/openapi.json"));
+
 struct AppState {
     factory: Arc<Mutex<ReplayFactory>>,
+    blocks: Arc<BlockSource>,
+    live: bool,
+}
+
+/// An error response, `{"error": <message>}` with the HTTP status.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    body: serde_json::Value,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message.into() }),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.body)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(&self.body)
+    }
+}
+
+/// The build script describes the routes returning `ApiResult<T>` with the fields of `T`, which
+/// must be one of the responses in `replay::api`.
+type ApiResult<T> = Result<web::Json<T>, ApiError>;
+
+fn to_json(value: impl Serialize) -> Result<serde_json::Value, ApiError> {
+    serde_json::to_value(value)
+        .map_err(|err| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+fn parse_pubkey(pubkey: &str) -> Result<WorkerPublicKey, ApiError> {
+    AccountId32::from_str(pubkey)
+        .map(|accid| WorkerPublicKey(accid.into()))
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid pubkey"))
 }
 
 #[get("/meminfo")]
-async fn meminfo(data: web::Data<AppState>) -> HttpResponse {
+async fn meminfo(data: web::Data<AppState>) -> ApiResult<MemInfoResponse> {
     let factory = data.factory.lock().await;
     let size = factory
         .storage
@@ -19,61 +81,279 @@ async fn meminfo(data: web::Data<AppState>) -> HttpResponse {
         .map(|(k, v)| k.len() + v.len())
         .sum::<usize>();
     log::info!("Storage size: {}", size);
-    HttpResponse::Ok().json(serde_json::json!({
-            "storage_size": size,
-    }))
+    Ok(web::Json(MemInfoResponse { storage_size: size }))
 }
 
 #[get("/worker-state/{pubkey}")]
-async fn get_worker_state(pubkey: web::Path<String>, data: web::Data<AppState>) -> HttpResponse {
+async fn get_worker_state(
+    pubkey: web::Path<String>,
+    data: web::Data<AppState>,
+) -> ApiResult<WorkerStateResponse> {
     let factory = data.factory.lock().await;
-    let pubkey = match AccountId32::from_str(pubkey.as_str()) {
-        Ok(accid) => WorkerPublicKey(accid.into()),
-        Err(_) => {
-            return HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Invalid pubkey"
-            }));
-        }
-    };
+    let pubkey = parse_pubkey(pubkey.as_str())?;
 
     let total_share = factory.gk.sum_share();
-    match factory.gk.worker_state(&pubkey) {
-        None => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "Worker not found"
-        })),
-        Some(state) => HttpResponse::Ok().json(serde_json::json!({
-            "current_block": factory.current_block,
-            "total_share": total_share.to_string(),
-            "worker": state,
-        })),
-    }
+    let state = factory
+        .gk
+        .worker_state(&pubkey)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Worker not found"))?;
+    Ok(web::Json(WorkerStateResponse {
+        current_block: factory.current_block,
+        total_share: total_share.to_string(),
+        worker: to_json(state)?,
+    }))
 }
 
 #[get("/workers")]
-async fn dump_workers(data: web::Data<AppState>) -> HttpResponse {
+async fn dump_workers(data: web::Data<AppState>) -> ApiResult<WorkersResponse> {
     let factory = data.factory.lock().await;
 
     let total_share = factory.gk.sum_share();
-    let workers = factory.gk.dump_workers_state();
-    let workers: std::collections::BTreeMap<_, _> = workers
+    let mut workers = std::collections::BTreeMap::new();
+    for (k, v) in factory.gk.dump_workers_state() {
+        workers.insert("0x".to_string() + &hex::encode(k), to_json(v)?);
+    }
+    Ok(web::Json(WorkersResponse {
+        current_block: factory.current_block,
+        total_share: total_share.to_string(),
+        workers,
+    }))
+}
+
+#[get("/status")]
+async fn status(data: web::Data<AppState>) -> ApiResult<StatusResponse> {
+    let factory = data.factory.lock().await;
+    Ok(web::Json(StatusResponse {
+        live: data.live,
+        current_block: factory.current_block,
+        finalized_block: factory.finalized_block,
+        lag: factory
+            .finalized_block
+            .saturating_sub(factory.current_block),
+    }))
+}
+
+/// The payout each working worker would get if it sent a heartbeat at the current block.
+///
+/// The estimate is reset by each on-chain settlement of the worker, so it tells how much has been
+/// accumulated since the last settlement.
+#[get("/payout-estimates")]
+async fn payout_estimates(data: web::Data<AppState>) -> ApiResult<PayoutEstimatesResponse> {
+    let factory = data.factory.lock().await;
+    let workers = factory
+        .gk
+        .estimate_payouts(factory.current_block)
+        .into_iter()
+        .map(|(k, v)| ("0x".to_string() + &hex::encode(k), v.to_string()))
+        .collect();
+    Ok(web::Json(PayoutEstimatesResponse {
+        current_block: factory.current_block,
+        finalized_block: factory.finalized_block,
+        workers,
+    }))
+}
+
+#[get("/payout-estimate/{pubkey}")]
+async fn payout_estimate(
+    pubkey: web::Path<String>,
+    data: web::Data<AppState>,
+) -> ApiResult<PayoutEstimateResponse> {
+    let factory = data.factory.lock().await;
+    let pubkey = parse_pubkey(pubkey.as_str())?;
+    let (_, payout) = factory
+        .gk
+        .estimate_payouts(factory.current_block)
+        .into_iter()
+        .find(|(k, _)| *k == pubkey)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Worker not found or not working"))?;
+    Ok(web::Json(PayoutEstimateResponse {
+        current_block: factory.current_block,
+        finalized_block: factory.finalized_block,
+        estimated_payout: payout.to_string(),
+    }))
+}
+
+/// Every tokenomic parameter set applied by the GK, in the order of the blocks.
+#[get("/tokenomic-parameters")]
+async fn tokenomic_parameters(data: web::Data<AppState>) -> ApiResult<TokenomicParametersResponse> {
+    let factory = data.factory.lock().await;
+    let timeline = factory
+        .tokenomic_timeline
         .iter()
-        .map(|(k, v)| ("0x".to_string() + &hex::encode(k), v))
+        .map(|r| r.to_json())
         .collect();
-    HttpResponse::Ok().json(serde_json::json!({
-        "current_block": factory.current_block,
-        "total_share": total_share.to_string(),
-        "workers": workers
+    Ok(web::Json(TokenomicParametersResponse {
+        current_block: factory.current_block,
+        timeline,
+    }))
+}
+
+/// Counts by state and percentiles of `v` and `p_instant` over all the workers, optionally
+/// grouped with `?group_by=confidence_level`.
+#[get("/cohorts")]
+async fn cohorts(
+    query: web::Query<CohortQuery>,
+    data: web::Data<AppState>,
+) -> ApiResult<CohortsResponse> {
+    let factory = data.factory.lock().await;
+    let cohorts = cohort::cohorts(&factory, query.group_by, None);
+    Ok(web::Json(CohortsResponse {
+        current_block: factory.current_block,
+        cohorts: cohorts.iter().map(to_json).collect::<Result<_, _>>()?,
+    }))
+}
+
+/// Like `/cohorts`, with histograms of `v` and `p_instant` in `?buckets=N` buckets.
+#[get("/cohorts/histograms")]
+async fn cohort_histograms(
+    query: web::Query<CohortQuery>,
+    data: web::Data<AppState>,
+) -> ApiResult<CohortsResponse> {
+    let factory = data.factory.lock().await;
+    let cohorts = cohort::cohorts(&factory, query.group_by, Some(query.buckets()));
+    Ok(web::Json(CohortsResponse {
+        current_block: factory.current_block,
+        cohorts: cohorts.iter().map(to_json).collect::<Result<_, _>>()?,
+    }))
+}
+
+/// The mq messages of a replayed block, re-derived from its storage changes and decoded, i.e. the
+/// messages the GK saw at that block.
+#[get("/block/{number}/messages")]
+async fn block_messages(
+    number: web::Path<BlockNumber>,
+    data: web::Data<AppState>,
+) -> ApiResult<BlockMessagesResponse> {
+    let number = number.into_inner();
+    let current_block = data.factory.lock().await.current_block;
+    if number <= data.blocks.start_at() || number > current_block {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            body: serde_json::json!({
+                "error": "Block not replayed",
+                "current_block": current_block,
+            }),
+        });
+    }
+    let block = data.blocks.fetch(number).await.map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch block: {err}"),
+        )
+    })?;
+    let messages = crate::helper::block_mq_messages(&block).map_err(|err| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to decode the messages: {err}"),
+        )
+    })?;
+    Ok(web::Json(BlockMessagesResponse {
+        block: number,
+        messages: messages
+            .iter()
+            .map(|message| crate::helper::message_json(number, message))
+            .collect(),
+    }))
+}
+
+fn rules_json(rules: &[Rule]) -> Result<Vec<serde_json::Value>, ApiError> {
+    rules.iter().map(to_json).collect()
+}
+
+#[get("/webhooks")]
+async fn list_webhooks(data: web::Data<AppState>) -> ApiResult<WebhooksResponse> {
+    let factory = data.factory.lock().await;
+    Ok(web::Json(WebhooksResponse {
+        enabled: factory.webhooks.is_enabled(),
+        rules: rules_json(factory.webhooks.rules())?,
     }))
 }
 
-pub async fn serve(bind_addr: String, factory: Arc<Mutex<ReplayFactory>>) {
+/// Adds a webhook rule, replacing the one of the same name.
+#[post("/webhooks")]
+async fn add_webhook(
+    rule: web::Json<Rule>,
+    data: web::Data<AppState>,
+) -> ApiResult<WebhookRulesResponse> {
+    let mut factory = data.factory.lock().await;
+    if !factory.webhooks.is_enabled() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Webhooks disabled, start the replay with --webhook-rules",
+        ));
+    }
+    factory
+        .webhooks
+        .add_rule(rule.into_inner())
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, format!("{err:?}")))?;
+    Ok(web::Json(WebhookRulesResponse {
+        rules: rules_json(factory.webhooks.rules())?,
+    }))
+}
+
+#[delete("/webhooks/{name}")]
+async fn remove_webhook(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> ApiResult<WebhookRulesResponse> {
+    let mut factory = data.factory.lock().await;
+    let removed = factory.webhooks.remove_rule(name.as_str()).map_err(|err| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save the rules: {err}"),
+        )
+    })?;
+    if !removed {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Rule not found"));
+    }
+    Ok(web::Json(WebhookRulesResponse {
+        rules: rules_json(factory.webhooks.rules())?,
+    }))
+}
+
+/// The OpenAPI specification of this server.
+#[get("/openapi.json")]
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI_SPEC)
+}
+
+pub async fn serve(
+    bind_addr: String,
+    factory: Arc<Mutex<ReplayFactory>>,
+    blocks: Arc<BlockSource>,
+    live: bool,
+    auth: Auth,
+) {
+    if !auth.is_enabled() {
+        log::warn!("No HTTP token configured, the replay HTTP server is open to anyone");
+    }
     HttpServer::new(move || {
         let factory = factory.clone();
+        let blocks = blocks.clone();
+        let auth = auth.clone();
         App::new()
-            .app_data(web::Data::new(AppState { factory }))
+            .app_data(web::Data::new(AppState {
+                factory,
+                blocks,
+                live,
+            }))
+            .wrap_fn(move |req, srv| auth.middleware(req, srv))
             .service(get_worker_state)
             .service(meminfo)
             .service(dump_workers)
+            .service(status)
+            .service(payout_estimates)
+            .service(payout_estimate)
+            .service(tokenomic_parameters)
+            .service(cohorts)
+            .service(cohort_histograms)
+            .service(block_messages)
+            .service(list_webhooks)
+            .service(add_webhook)
+            .service(remove_webhook)
+            .service(openapi)
     })
     .disable_signals()
     .bind(&bind_addr)
@@ -82,3 +362,47 @@ pub async fn serve(bind_addr: String, factory: Arc<Mutex<ReplayFactory>>) {
     .await
     .expect("Http server failed");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openapi_spec_covers_every_route() {
+        let spec: serde_json::Value = serde_json::from_str(OPENAPI_SPEC).unwrap();
+        let mut operations = vec![];
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for operation in item.as_object().unwrap().values() {
+                let name = operation["operationId"].as_str().unwrap();
+                operations.push(name);
+                if path == "/openapi.json" {
+                    continue;
+                }
+                let schema =
+                    &operation["responses"]["200"]["content"]["application/json"]["schema"];
+                let response = schema["$ref"]
+                    .as_str()
+                    .and_then(|r| r.strip_prefix("#/components/schemas/"))
+                    .unwrap_or_else(|| panic!("{name} has no response type"));
+                let properties = &spec["components"]["schemas"][response]["properties"];
+                assert!(
+                    properties.as_object().map_or(false, |p| !p.is_empty()),
+                    "{response} has no fields"
+                );
+            }
+        }
+
+        let services: Vec<&str> = include_str!("httpserver.rs")
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix(".service(")?.strip_suffix(')'))
+            .collect();
+        assert!(!services.is_empty());
+        for service in &services {
+            assert!(
+                operations.contains(service),
+                "{service} is missing from the OpenAPI spec"
+            );
+        }
+        assert_eq!(operations.len(), services.len());
+    }
+}
@@ -0,0 +1,157 @@
+//! Cross-validation of the replay against an event stream computed elsewhere.
+//!
+//! The events exported by another replay run, or by a port of the GK logic to another language or
+//! version, are loaded from a CSV file with the columns of the persisted events: `block`, `pubkey`,
+//! `event`, and optionally `v`, `p` and `payout`. Other columns are ignored. After each
+//! replayed block within the imported range, the events emitted by the GK are compared with the
+//! imported ones of the block in order, and the replay is aborted at the first divergence.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::csv::{self, Row};
+use super::{BlockNumber, EventRecord};
+
+#[derive(Debug, Clone, PartialEq)]
+struct ImportedEvent {
+    pubkey: String,
+    event: String,
+    v: Option<f64>,
+    p: Option<f64>,
+    payout: Option<f64>,
+}
+
+impl ImportedEvent {
+    fn from_record(rec: &EventRecord) -> Self {
+        Self {
+            pubkey: hex::encode(rec.pubkey.0),
+            event: rec.event.event_string().into(),
+            v: Some(rec.v.to_num()),
+            p: Some(rec.p.to_num()),
+            payout: Some(rec.event.payout().to_num()),
+        }
+    }
+
+    /// Whether the imported event matches the replayed one. Fixed point values are compared
+    /// within `tolerance`, since other implementations may round differently.
+    fn matches(&self, replayed: &Self, tolerance: f64) -> bool {
+        let close = |imported: Option<f64>, replayed: Option<f64>| match (imported, replayed) {
+            (Some(a), Some(b)) => (a - b).abs() <= tolerance,
+            _ => true,
+        };
+        self.pubkey == replayed.pubkey
+            && self.event == replayed.event
+            && close(self.v, replayed.v)
+            && close(self.p, replayed.p)
+            && close(self.payout, replayed.payout)
+    }
+}
+
+pub struct ImportedEvents {
+    filename: String,
+    blocks: BTreeMap<BlockNumber, Vec<ImportedEvent>>,
+    first_block: BlockNumber,
+    last_block: BlockNumber,
+    tolerance: f64,
+    n_compared: usize,
+    finished: bool,
+}
+
+impl ImportedEvents {
+    /// Loads the events from a CSV file with a header line.
+    pub fn load(filename: &str, tolerance: f64) -> Result<Self> {
+        let rows = csv::read(filename)
+            .with_context(|| format!("Failed to import events from {filename}"))?;
+        let mut blocks = BTreeMap::<BlockNumber, Vec<ImportedEvent>>::new();
+        for (i, row) in rows.iter().enumerate() {
+            let (block, event) =
+                parse_row(row).with_context(|| format!("Invalid event at row {}", i + 1))?;
+            blocks.entry(block).or_default().push(event);
+        }
+        let (Some(first_block), Some(last_block)) = (
+            blocks.keys().next().copied(),
+            blocks.keys().next_back().copied(),
+        ) else {
+            bail!("No events in {filename}");
+        };
+        log::info!(
+            "Imported {} events of blocks {first_block}..={last_block} from {filename}",
+            rows.len()
+        );
+        Ok(Self {
+            filename: filename.into(),
+            blocks,
+            first_block,
+            last_block,
+            tolerance,
+            n_compared: 0,
+            finished: false,
+        })
+    }
+
+    /// Compares the events emitted by the GK in a block with the imported ones.
+    pub fn compare(
+        &mut self,
+        block_number: BlockNumber,
+        records: &[EventRecord],
+    ) -> Result<(), String> {
+        if block_number < self.first_block || self.finished {
+            return Ok(());
+        }
+        if block_number > self.last_block {
+            self.finished = true;
+            log::info!(
+                "All {} events imported from {} matched",
+                self.n_compared,
+                self.filename
+            );
+            return Ok(());
+        }
+        let replayed: Vec<_> = records.iter().map(ImportedEvent::from_record).collect();
+        let imported = self.blocks.remove(&block_number).unwrap_or_default();
+        for (i, (imported, replayed)) in imported.iter().zip(&replayed).enumerate() {
+            if !imported.matches(replayed, self.tolerance) {
+                return Err(format!(
+                    "event #{i} diverged at block {block_number}: replayed={replayed:?}, imported={imported:?}"
+                ));
+            }
+        }
+        if imported.len() != replayed.len() {
+            let i = imported.len().min(replayed.len());
+            return Err(format!(
+                "number of events diverged at block {block_number}: replayed={}, imported={}, first unmatched: replayed={:?}, imported={:?}",
+                replayed.len(),
+                imported.len(),
+                replayed.get(i),
+                imported.get(i)
+            ));
+        }
+        self.n_compared += imported.len();
+        Ok(())
+    }
+}
+
+fn parse_row(row: &Row) -> Result<(BlockNumber, ImportedEvent)> {
+    let column = |name: &str| row.get(name).map(String::as_str).filter(|v| !v.is_empty());
+    let required = |name: &str| column(name).ok_or_else(|| anyhow!("Missing column {name}"));
+    let decimal = |name: &str| -> Result<Option<f64>> {
+        column(name)
+            .map(|v| v.parse().with_context(|| format!("Invalid {name}: {v}")))
+            .transpose()
+    };
+    let block = required("block")?.parse().context("Invalid block number")?;
+    // Postgres exports bytea as `\x...`.
+    let pubkey = required("pubkey")?
+        .trim_start_matches("0x")
+        .trim_start_matches("\\x")
+        .to_lowercase();
+    let event = ImportedEvent {
+        pubkey,
+        event: required("event")?.into(),
+        v: decimal("v")?,
+        p: decimal("p")?,
+        payout: decimal("payout")?,
+    };
+    Ok((block, event))
+}
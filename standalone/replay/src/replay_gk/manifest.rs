@@ -0,0 +1,342 @@
+//! Integrity manifests of replayed block ranges, for publishing reproducible tokenomics audits.
+//!
+//! With `--manifest-out`, the hash, state root, number of mq messages and number of GK events of
+//! each replayed block are recorded, and written when the replay reaches `--stop-at`, signed by
+//! the sr25519 key given in `--manifest-key`. The signature covers the SCALE encoding of
+//! `(MANIFEST_SIGNING_TAG, start, end, blocks)`, so that third parties can check it without this
+//! tool, and the signature can't be replayed as one over some other payload of the same key.
+//!
+//! With `--manifest-verify`, the signature of a published manifest is checked at startup, then
+//! each replayed block within its range is compared with its entry and the replay is aborted at
+//! the first difference. A manifest must cover every block of its range, and so must the
+//! replay: a block skipped on either side is reported as a divergence.
+
+use anyhow::{anyhow, bail, Context, Result};
+use parity_scale_codec::Encode;
+use pherry::types::phaxt::sp_core::{sr25519, Pair, H256};
+use serde::{Deserialize, Serialize};
+
+use super::BlockNumber;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode)]
+pub struct BlockEntry {
+    pub number: BlockNumber,
+    pub hash: H256,
+    pub state_root: H256,
+    pub mq_messages: u32,
+    pub events: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    start: BlockNumber,
+    end: BlockNumber,
+    blocks: Vec<BlockEntry>,
+    /// Hex encoded sr25519 public key.
+    signer: String,
+    /// Hex encoded sr25519 signature.
+    signature: String,
+}
+
+/// The domain separation tag prepended to the signed payload.
+pub const MANIFEST_SIGNING_TAG: &[u8] = b"phala-replay-manifest-v1";
+
+fn signed_payload(start: BlockNumber, end: BlockNumber, blocks: &[BlockEntry]) -> Vec<u8> {
+    (MANIFEST_SIGNING_TAG, start, end, blocks).encode()
+}
+
+/// Ensures that `blocks` are exactly the blocks `start..=end`, in order.
+fn check_contiguous(start: BlockNumber, end: BlockNumber, blocks: &[BlockEntry]) -> Result<()> {
+    if start > end {
+        bail!("Empty block range {start}..={end}");
+    }
+    let mut expected = start;
+    for block in blocks {
+        if block.number != expected {
+            bail!("Block {expected} is missing, got block {}", block.number);
+        }
+        expected += 1;
+    }
+    if expected != end + 1 {
+        bail!("Blocks {expected}..={end} are missing");
+    }
+    Ok(())
+}
+
+pub struct ManifestWriter {
+    filename: String,
+    key: sr25519::Pair,
+    blocks: Vec<BlockEntry>,
+}
+
+impl ManifestWriter {
+    /// `key` is a secret URI, e.g. a mnemonic or a hex seed.
+    pub fn new(filename: &str, key: &str) -> Result<Self> {
+        let key = sr25519::Pair::from_string(key, None)
+            .map_err(|err| anyhow!("Invalid manifest key: {err:?}"))?;
+        Ok(Self {
+            filename: filename.into(),
+            key,
+            blocks: vec![],
+        })
+    }
+
+    pub fn record(&mut self, entry: BlockEntry) {
+        self.blocks.push(entry);
+    }
+
+    /// Signs the recorded blocks and writes the manifest.
+    pub fn finish(&self) -> Result<()> {
+        let (Some(first), Some(last)) = (self.blocks.first(), self.blocks.last()) else {
+            bail!("No block replayed for the manifest");
+        };
+        let (start, end) = (first.number, last.number);
+        check_contiguous(start, end, &self.blocks).context("Incomplete manifest")?;
+        let signature = self.key.sign(&signed_payload(start, end, &self.blocks));
+        let manifest = Manifest {
+            start,
+            end,
+            blocks: self.blocks.clone(),
+            signer: hex::encode(self.key.public()),
+            signature: hex::encode(signature),
+        };
+        let file = std::fs::File::create(&self.filename)
+            .with_context(|| format!("Failed to create {}", self.filename))?;
+        serde_json::to_writer_pretty(file, &manifest)?;
+        log::info!(
+            "Wrote the manifest of blocks {start}..={end} to {}, signed by {}",
+            self.filename,
+            manifest.signer
+        );
+        Ok(())
+    }
+}
+
+pub struct ManifestVerifier {
+    filename: String,
+    blocks: Vec<BlockEntry>,
+    start: BlockNumber,
+    end: BlockNumber,
+    /// The next block of the range to be verified.
+    next: BlockNumber,
+    finished: bool,
+}
+
+impl ManifestVerifier {
+    /// Loads the manifest and checks its signature, against `expected_signer` if given.
+    pub fn load(filename: &str, expected_signer: Option<&str>) -> Result<Self> {
+        let file =
+            std::fs::File::open(filename).with_context(|| format!("Failed to open {filename}"))?;
+        let manifest: Manifest = serde_json::from_reader(file)
+            .with_context(|| format!("Failed to parse the manifest {filename}"))?;
+        let signer = manifest.signer.trim_start_matches("0x").to_lowercase();
+        if let Some(expected) = expected_signer {
+            if expected.trim_start_matches("0x").to_lowercase() != signer {
+                bail!("Manifest {filename} is signed by {signer}, expected {expected}");
+            }
+        }
+        let public: [u8; 32] = hex::decode(&signer)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid signer in the manifest"))?;
+        let signature: [u8; 64] = hex::decode(manifest.signature.trim_start_matches("0x"))?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid signature in the manifest"))?;
+        let payload = signed_payload(manifest.start, manifest.end, &manifest.blocks);
+        if !sr25519::Pair::verify(
+            &sr25519::Signature::from_raw(signature),
+            payload,
+            &sr25519::Public::from_raw(public),
+        ) {
+            bail!("Bad signature of the manifest {filename}");
+        }
+        check_contiguous(manifest.start, manifest.end, &manifest.blocks)
+            .with_context(|| format!("Incomplete manifest {filename}"))?;
+        log::info!(
+            "Verifying against the manifest of blocks {}..={} signed by {signer}",
+            manifest.start,
+            manifest.end
+        );
+        Ok(Self {
+            filename: filename.into(),
+            blocks: manifest.blocks,
+            start: manifest.start,
+            end: manifest.end,
+            next: manifest.start,
+            finished: false,
+        })
+    }
+
+    /// Compares a replayed block with its entry in the manifest. The blocks of the range must be
+    /// replayed one by one, in order.
+    pub fn verify(&mut self, replayed: &BlockEntry) -> Result<(), String> {
+        if self.finished || replayed.number < self.next {
+            return Ok(());
+        }
+        if replayed.number != self.next {
+            return Err(format!(
+                "blocks {}..{} were not replayed",
+                self.next, replayed.number
+            ));
+        }
+        let expected = &self.blocks[(replayed.number - self.start) as usize];
+        if expected != replayed {
+            return Err(format!(
+                "block {} diverged: replayed={replayed:?}, manifest={expected:?}",
+                replayed.number
+            ));
+        }
+        self.next += 1;
+        if self.next > self.end {
+            self.finished = true;
+            log::info!(
+                "All {} blocks in the manifest {} matched",
+                self.blocks.len(),
+                self.filename
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether every block of the manifest has been replayed and matched.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(number: BlockNumber) -> BlockEntry {
+        BlockEntry {
+            number,
+            hash: H256::repeat_byte(number as u8),
+            state_root: H256::repeat_byte(!(number as u8)),
+            mq_messages: number,
+            events: 1,
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("replay-{name}-{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    const KEY: &str = "//Alice";
+
+    fn write_manifest(name: &str, blocks: impl IntoIterator<Item = BlockNumber>) -> String {
+        let filename = temp_file(name);
+        let mut writer = ManifestWriter::new(&filename, KEY).unwrap();
+        for number in blocks {
+            writer.record(entry(number));
+        }
+        writer.finish().unwrap();
+        filename
+    }
+
+    fn signer() -> String {
+        hex::encode(sr25519::Pair::from_string(KEY, None).unwrap().public())
+    }
+
+    fn edit_manifest(filename: &str, edit: impl FnOnce(&mut Manifest)) {
+        let mut manifest: Manifest =
+            serde_json::from_slice(&std::fs::read(filename).unwrap()).unwrap();
+        edit(&mut manifest);
+        std::fs::write(filename, serde_json::to_vec(&manifest).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let filename = write_manifest("round-trip", 10..=12);
+        let mut verifier = ManifestVerifier::load(&filename, Some(&signer())).unwrap();
+        for number in 8..=13 {
+            verifier.verify(&entry(number)).unwrap();
+        }
+        assert!(verifier.finished());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn wrong_signer_is_rejected() {
+        let filename = write_manifest("wrong-signer", 10..=12);
+        let bob = hex::encode(sr25519::Pair::from_string("//Bob", None).unwrap().public());
+        assert!(ManifestVerifier::load(&filename, Some(&bob)).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn tampered_manifest_is_rejected() {
+        let filename = write_manifest("tampered", 10..=12);
+        edit_manifest(&filename, |manifest| manifest.blocks[1].events += 1);
+        assert!(ManifestVerifier::load(&filename, None).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn signature_without_the_tag_is_rejected() {
+        let filename = write_manifest("untagged", 10..=12);
+        edit_manifest(&filename, |manifest| {
+            let key = sr25519::Pair::from_string(KEY, None).unwrap();
+            let untagged = (manifest.start, manifest.end, &manifest.blocks).encode();
+            manifest.signature = hex::encode(key.sign(&untagged));
+        });
+        assert!(ManifestVerifier::load(&filename, None).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn manifest_with_gaps_is_rejected() {
+        let filename = temp_file("gaps");
+        let mut writer = ManifestWriter::new(&filename, KEY).unwrap();
+        for number in [10, 11, 13] {
+            writer.record(entry(number));
+        }
+        assert!(writer.finish().is_err());
+
+        // Signed by a writer not checking the blocks.
+        let key = sr25519::Pair::from_string(KEY, None).unwrap();
+        let blocks = vec![entry(10), entry(12)];
+        let manifest = Manifest {
+            start: 10,
+            end: 12,
+            signature: hex::encode(key.sign(&signed_payload(10, 12, &blocks))),
+            blocks,
+            signer: signer(),
+        };
+        std::fs::write(&filename, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        assert!(ManifestVerifier::load(&filename, None).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn skipped_blocks_are_reported() {
+        let filename = write_manifest("skipped", 10..=12);
+        let mut verifier = ManifestVerifier::load(&filename, None).unwrap();
+        verifier.verify(&entry(10)).unwrap();
+        assert!(verifier.verify(&entry(12)).is_err());
+
+        // Starting the replay past the start of the range.
+        let mut verifier = ManifestVerifier::load(&filename, None).unwrap();
+        assert!(verifier.verify(&entry(11)).is_err());
+
+        // Stopping past the range without replaying its end.
+        let mut verifier = ManifestVerifier::load(&filename, None).unwrap();
+        verifier.verify(&entry(10)).unwrap();
+        assert!(verifier.verify(&entry(13)).is_err());
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn divergence_is_detected() {
+        let filename = write_manifest("diverged", 10..=12);
+        let mut verifier = ManifestVerifier::load(&filename, None).unwrap();
+        verifier.verify(&entry(10)).unwrap();
+        let mut diverged = entry(11);
+        diverged.state_root = H256::zero();
+        assert!(verifier.verify(&diverged).is_err());
+        assert!(!verifier.finished());
+        std::fs::remove_file(filename).unwrap();
+    }
+}
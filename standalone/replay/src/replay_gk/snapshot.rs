@@ -0,0 +1,123 @@
+//! Periodic snapshots of the state of all the workers.
+//!
+//! The persisted events only tell how the workers changed. With `--snapshot-interval N`, the
+//! pubkey, `v`, `p_instant` and state of every worker are also taken at each block multiple of N,
+//! giving regular cross-sections of the fleet without replaying to these heights again. The
+//! snapshots go to the `worker_state_snapshots` table of the event store, or to the CSV file
+//! given by `--snapshot-to`, appended to if it exists.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+use anyhow::{bail, Context, Result};
+use phactory::gk;
+use phactory_api::prpc as pb;
+use phala_types::WorkerPublicKey;
+use pherry::types::BlockNumber;
+
+use super::csv;
+
+#[derive(Debug)]
+pub(super) struct WorkerSnapshot {
+    pub pubkey: WorkerPublicKey,
+    pub v: gk::FixedPoint,
+    pub p: gk::FixedPoint,
+    pub state: &'static str,
+}
+
+#[derive(Debug)]
+pub(super) struct SnapshotRecord {
+    pub block_number: BlockNumber,
+    pub time_ms: u64,
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+impl SnapshotRecord {
+    pub fn take(
+        block_number: BlockNumber,
+        time_ms: u64,
+        workers: Vec<(WorkerPublicKey, pb::WorkerState)>,
+    ) -> Self {
+        let parse = |s: &str| s.parse::<gk::FixedPoint>().unwrap_or_default();
+        let workers = workers
+            .into_iter()
+            .map(|(pubkey, state)| {
+                let tokenomic = state.tokenomic_info.as_ref();
+                WorkerSnapshot {
+                    pubkey,
+                    v: tokenomic.map(|t| parse(&t.v)).unwrap_or_default(),
+                    p: tokenomic.map(|t| parse(&t.p_instant)).unwrap_or_default(),
+                    state: super::cohort::state_name(&state),
+                }
+            })
+            .collect();
+        Self {
+            block_number,
+            time_ms,
+            workers,
+        }
+    }
+}
+
+const CSV_HEADERS: [&str; 6] = ["block", "time_ms", "pubkey", "v", "p", "state"];
+
+enum Output {
+    /// Sent to the event store with the events.
+    Store,
+    Csv(BufWriter<File>),
+}
+
+pub(super) struct Snapshots {
+    interval: BlockNumber,
+    output: Output,
+}
+
+impl Snapshots {
+    pub fn new(interval: BlockNumber, csv_file: Option<&str>, has_store: bool) -> Result<Self> {
+        let output = match csv_file {
+            Some(filename) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(filename)
+                    .with_context(|| format!("Failed to open {filename}"))?;
+                let has_headers = file.metadata()?.len() == 0;
+                let mut writer = BufWriter::new(file);
+                if has_headers {
+                    csv::write_record(&mut writer, &CSV_HEADERS)?;
+                }
+                Output::Csv(writer)
+            }
+            None if has_store => Output::Store,
+            None => bail!("--snapshot-interval requires --snapshot-to or --persist-events-to"),
+        };
+        Ok(Self { interval, output })
+    }
+
+    pub fn is_due(&self, block_number: BlockNumber) -> bool {
+        block_number % self.interval == 0
+    }
+
+    /// Writes the snapshot to the CSV file, or gives it back to be sent to the event store.
+    pub fn write(&mut self, record: SnapshotRecord) -> Result<Option<SnapshotRecord>> {
+        let writer = match &mut self.output {
+            Output::Store => return Ok(Some(record)),
+            Output::Csv(writer) => writer,
+        };
+        for worker in &record.workers {
+            csv::write_record(
+                writer,
+                &[
+                    &record.block_number.to_string(),
+                    &record.time_ms.to_string(),
+                    &hex::encode(worker.pubkey.0),
+                    &worker.v.to_string(),
+                    &worker.p.to_string(),
+                    worker.state,
+                ],
+            )?;
+        }
+        writer.flush()?;
+        Ok(None)
+    }
+}
@@ -0,0 +1,101 @@
+//! Verification of the replayed GK against the on-chain computing sessions.
+//!
+//! With `--verify-sessions`, once the replay reaches `--stop-at`, the workers the GK sees working
+//! are compared with the workers whose session is computing on chain at the last replayed block,
+//! i.e. in the `WorkerIdle` or `WorkerUnresponsive` state. Both are driven by the same on-chain
+//! calls, so any difference means the replayed state diverged from the chain.
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use phala_pallets::pallet_computation::{SessionInfo, WorkerState};
+use phaxt::{KeyHasher, StorageMap};
+use pherry::types::{phaxt, BlockNumber, ParachainApi};
+use sp_runtime::AccountId32;
+
+use super::{ReplayFactory, WorkerPublicKey};
+
+struct WorkerBindings;
+
+impl StorageMap for WorkerBindings {
+    const PALLET: &'static str = "PhalaComputation";
+    const MAP: &'static str = "WorkerBindings";
+    const HASHER: KeyHasher = KeyHasher::Twox64Concat;
+    type Key = WorkerPublicKey;
+    type Value = AccountId32;
+}
+
+struct Sessions;
+
+impl StorageMap for Sessions {
+    const PALLET: &'static str = "PhalaComputation";
+    const MAP: &'static str = "Sessions";
+    const HASHER: KeyHasher = KeyHasher::Twox64Concat;
+    type Key = AccountId32;
+    type Value = SessionInfo;
+}
+
+/// Fails if the working workers of the GK differ from the computing workers on chain.
+pub async fn verify_sessions(api: &ParachainApi, factory: &ReplayFactory) -> Result<()> {
+    let block: BlockNumber = factory.current_block;
+    let hash = api
+        .rpc()
+        .block_hash(Some(block.into()))
+        .await?
+        .ok_or_else(|| anyhow!("Block {block} not found"))?;
+    log::info!("Verifying the computing sessions at block {block}");
+
+    let sessions: HashMap<AccountId32, SessionInfo> = api
+        .iter_map::<Sessions>(Some(hash))
+        .await?
+        .into_iter()
+        .collect();
+    let on_chain: BTreeSet<WorkerPublicKey> = api
+        .iter_map::<WorkerBindings>(Some(hash))
+        .await?
+        .into_iter()
+        .filter(|(_, session)| {
+            sessions.get(session).map_or(false, |info| {
+                matches!(
+                    info.state,
+                    WorkerState::WorkerIdle | WorkerState::WorkerUnresponsive
+                )
+            })
+        })
+        .map(|(pubkey, _)| pubkey)
+        .collect();
+    let replayed: BTreeSet<WorkerPublicKey> = factory
+        .gk
+        .dump_workers_state()
+        .into_iter()
+        .filter(|(_, state)| state.working_state.is_some())
+        .map(|(pubkey, _)| pubkey)
+        .collect();
+
+    let only_on_chain: Vec<_> = on_chain.difference(&replayed).collect();
+    let only_replayed: Vec<_> = replayed.difference(&on_chain).collect();
+    for pubkey in only_on_chain.iter() {
+        log::error!(
+            "Computing on chain, not working in the GK: 0x{}",
+            hex::encode(pubkey.0)
+        );
+    }
+    for pubkey in only_replayed.iter() {
+        log::error!(
+            "Working in the GK, not computing on chain: 0x{}",
+            hex::encode(pubkey.0)
+        );
+    }
+    if !only_on_chain.is_empty() || !only_replayed.is_empty() {
+        bail!(
+            "{} of {} computing workers diverged at block {block}",
+            only_on_chain.len() + only_replayed.len(),
+            on_chain.len()
+        );
+    }
+    log::info!(
+        "The {} computing workers match the chain at block {block}",
+        on_chain.len()
+    );
+    Ok(())
+}
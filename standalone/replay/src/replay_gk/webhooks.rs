@@ -0,0 +1,251 @@
+//! Webhooks fired by the events of the replayed GK.
+//!
+//! The rules are loaded from the JSON array in `--webhook-rules`, and can be added or removed at
+//! runtime through the `/webhooks` endpoints of the HTTP server, which rewrite the file. Each rule
+//! is evaluated against the events as they are produced, and on match a JSON payload describing
+//! the event is POSTed to its `url`. With `--live`, this turns the replay into an alerting service
+//! for the tokenomics of the workers.
+//!
+//! A rule matches either some types of events, e.g. `enter_unresponsive` for the workers about to
+//! be slashed, or a drop of the `v` of a worker by more than a percentage since the start of its
+//! computing session. The latter fires once per session, and the start of a session is the first
+//! event of the session seen since the replay started.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{EventRecord, WorkerPublicKey};
+
+const DELIVERY_QUEUE_SIZE: usize = 1024;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// Any event of the given types, e.g. `enter_unresponsive`.
+    Event { events: Vec<String> },
+    /// The `v` of the worker dropped by more than `percent` since the start of its session.
+    VDrop { percent: f64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    pub url: String,
+    /// The hex encoded public key of the worker to watch. All the workers if unset.
+    #[serde(default)]
+    pub worker: Option<String>,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+impl Rule {
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            bail!("Empty rule name");
+        }
+        reqwest::Url::parse(&self.url).with_context(|| format!("Invalid url of {}", self.name))?;
+        if let Some(worker) = &self.worker {
+            match hex::decode(worker.trim_start_matches("0x")) {
+                Ok(key) if key.len() == 32 => (),
+                _ => bail!("Invalid worker public key of {}", self.name),
+            }
+        }
+        if let Condition::VDrop { percent } = self.condition {
+            if !(percent > 0.0 && percent <= 100.0) {
+                bail!("The percent of {} must be within (0, 100]", self.name);
+            }
+        }
+        Ok(())
+    }
+
+    fn watches(&self, pubkey: &WorkerPublicKey) -> bool {
+        match &self.worker {
+            Some(worker) => worker.trim_start_matches("0x") == hex::encode(pubkey.0),
+            None => true,
+        }
+    }
+}
+
+/// The `v` of a worker at the start of its current session.
+struct SessionStart {
+    session_id: Option<u32>,
+    v: f64,
+    /// The `v_drop` rules already fired in the session.
+    fired: BTreeSet<String>,
+}
+
+struct Delivery {
+    url: String,
+    payload: serde_json::Value,
+}
+
+#[derive(Default)]
+pub struct Webhooks {
+    rules: Vec<Rule>,
+    rules_file: Option<String>,
+    sessions: HashMap<WorkerPublicKey, SessionStart>,
+    tx: Option<mpsc::Sender<Delivery>>,
+}
+
+impl Webhooks {
+    /// Loads the rules of `rules_file`, which is created when missing, and starts the delivery.
+    pub fn load(rules_file: &str) -> Result<Self> {
+        let rules: Vec<Rule> = match std::fs::read(rules_file) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid webhook rules in {rules_file}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).context(format!("Failed to read {rules_file}")),
+        };
+        for rule in rules.iter() {
+            rule.validate()?;
+        }
+        log::info!("Loaded {} webhook rules from {rules_file}", rules.len());
+        let (tx, rx) = mpsc::channel(DELIVERY_QUEUE_SIZE);
+        tokio::spawn(deliver(rx));
+        Ok(Self {
+            rules,
+            rules_file: Some(rules_file.into()),
+            sessions: Default::default(),
+            tx: Some(tx),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Adds the rule, replacing the one of the same name.
+    pub fn add_rule(&mut self, rule: Rule) -> Result<()> {
+        rule.validate()?;
+        let mut rules = self.rules.clone();
+        rules.retain(|r| r.name != rule.name);
+        rules.push(rule);
+        self.save(rules)
+    }
+
+    /// Returns false if there is no rule of the name.
+    pub fn remove_rule(&mut self, name: &str) -> Result<bool> {
+        let mut rules = self.rules.clone();
+        rules.retain(|r| r.name != name);
+        if rules.len() == self.rules.len() {
+            return Ok(false);
+        }
+        self.save(rules)?;
+        Ok(true)
+    }
+
+    fn save(&mut self, rules: Vec<Rule>) -> Result<()> {
+        if let Some(filename) = &self.rules_file {
+            let tmp = format!("{filename}.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&rules)?)?;
+            std::fs::rename(&tmp, filename)?;
+        }
+        self.rules = rules;
+        Ok(())
+    }
+
+    /// Evaluates the rules against the events of a block, queueing the webhooks of the matches.
+    pub(super) fn process(&mut self, records: &[EventRecord]) {
+        let Some(tx) = self.tx.clone() else {
+            return;
+        };
+        for rec in records {
+            let v = rec.v.to_num::<f64>();
+            let start = self
+                .sessions
+                .entry(rec.pubkey)
+                .or_insert_with(|| SessionStart {
+                    session_id: rec.session_id,
+                    v,
+                    fired: Default::default(),
+                });
+            if start.session_id != rec.session_id {
+                *start = SessionStart {
+                    session_id: rec.session_id,
+                    v,
+                    fired: Default::default(),
+                };
+            }
+            for rule in self.rules.iter().filter(|rule| rule.watches(&rec.pubkey)) {
+                let mut payload = event_payload(&rule.name, rec);
+                match &rule.condition {
+                    Condition::Event { events } => {
+                        if !events.iter().any(|e| e == rec.event.event_string()) {
+                            continue;
+                        }
+                    }
+                    Condition::VDrop { percent } => {
+                        if start.v <= 0.0 || start.fired.contains(&rule.name) {
+                            continue;
+                        }
+                        let dropped = (start.v - v) / start.v * 100.0;
+                        if dropped <= *percent {
+                            continue;
+                        }
+                        start.fired.insert(rule.name.clone());
+                        payload["v_session_start"] = start.v.to_string().into();
+                        payload["v_drop_percent"] = dropped.into();
+                    }
+                }
+                let delivery = Delivery {
+                    url: rule.url.clone(),
+                    payload,
+                };
+                if let Err(err) = tx.try_send(delivery) {
+                    log::warn!("Dropped the webhook of {}: {err}", rule.name);
+                }
+            }
+        }
+    }
+}
+
+fn event_payload(rule: &str, rec: &EventRecord) -> serde_json::Value {
+    serde_json::json!({
+        "rule": rule,
+        "block": rec.block_number,
+        "time_ms": rec.time_ms,
+        "pubkey": "0x".to_string() + &hex::encode(rec.pubkey.0),
+        "event": rec.event.event_string(),
+        "v": rec.v.to_string(),
+        "p": rec.p.to_string(),
+        "payout": rec.event.payout().to_string(),
+        "session_id": rec.session_id,
+    })
+}
+
+async fn deliver(mut rx: mpsc::Receiver<Delivery>) {
+    let client = reqwest::Client::new();
+    while let Some(delivery) = rx.recv().await {
+        let body = delivery.payload.to_string();
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            let result = client
+                .post(&delivery.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .timeout(DELIVERY_TIMEOUT)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => break,
+                Err(err) if attempt < DELIVERY_ATTEMPTS => {
+                    log::debug!("Webhook to {} failed, retrying: {err}", delivery.url);
+                    tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                }
+                Err(err) => {
+                    log::warn!("Webhook to {} failed: {err}", delivery.url);
+                }
+            }
+        }
+    }
+}
@@ -1980,6 +1980,59 @@ mod tests {
             .fold(0, |acc, x| acc.checked_add(*x).unwrap());
     }
 
+    #[test]
+    fn cluster_checkpoint_interval_has_a_minimum() {
+        use frame_support::{assert_noop, assert_ok};
+        use pallet_phat::{ClusterCheckpointIntervals, Clusters, Error, MIN_CHECKPOINT_INTERVAL};
+        use phala_types::contract::{ClusterInfo, ClusterPermission};
+
+        let mut t: sp_io::TestExternalities = frame_system::GenesisConfig::<Runtime>::default()
+            .build_storage()
+            .unwrap()
+            .into();
+        t.execute_with(|| {
+            System::set_block_number(1);
+            let owner = AccountId::from([1u8; 32]);
+            let cluster = sp_core::H256::repeat_byte(2);
+            Clusters::<Runtime>::insert(
+                cluster,
+                ClusterInfo {
+                    owner: owner.clone(),
+                    permission: ClusterPermission::Public,
+                    system_contract: sp_core::H256::repeat_byte(3),
+                    gas_price: 0,
+                    deposit_per_item: 0,
+                    deposit_per_byte: 0,
+                },
+            );
+            let set = |who: &AccountId, interval| {
+                PhalaPhatContracts::set_cluster_checkpoint_interval(
+                    RuntimeOrigin::signed(who.clone()),
+                    cluster,
+                    interval,
+                )
+            };
+
+            assert_noop!(
+                set(&owner, MIN_CHECKPOINT_INTERVAL - 1),
+                Error::<Runtime>::CheckpointIntervalTooShort
+            );
+            assert_noop!(set(&owner, 1), Error::<Runtime>::CheckpointIntervalTooShort);
+            assert_noop!(
+                set(&AccountId::from([9u8; 32]), MIN_CHECKPOINT_INTERVAL),
+                Error::<Runtime>::ClusterPermissionDenied
+            );
+
+            assert_ok!(set(&owner, MIN_CHECKPOINT_INTERVAL));
+            assert_eq!(
+                ClusterCheckpointIntervals::<Runtime>::get(cluster),
+                Some(MIN_CHECKPOINT_INTERVAL)
+            );
+            assert_ok!(set(&owner, 0));
+            assert_eq!(ClusterCheckpointIntervals::<Runtime>::get(cluster), None);
+        });
+    }
+
     #[test]
     fn call_size() {
         let size = core::mem::size_of::<RuntimeCall>();
@@ -2,7 +2,8 @@ use super::pallet_mq;
 use codec::Decode;
 use frame_support::dispatch::DispatchResult;
 use sp_runtime::DispatchError;
-use phala_types::messaging::{BindTopic, DecodedMessage, Message};
+use phala_types::messaging::{BindTopic, DecodedMessage, Message, MessageOrigin};
+use phala_types::WorkerPublicKey;
 
 pub struct MessageRouteConfig;
 
@@ -38,8 +39,23 @@ impl pallet_mq::QueueNotifyConfig for MessageRouteConfig {
             PhalaPhatContracts::on_worker_cluster_message_received,
             PhalaPhatContracts::on_cluster_message_received,
             PhalaPhatContracts::on_contract_message_received,
+            PhalaMq::on_usage_report_received,
             // BridgeTransfer::on_message_received,
         };
         Ok(())
     }
+
+    fn is_hosted_by(sender: &MessageOrigin, worker: &WorkerPublicKey) -> bool {
+        use super::pallet_phat::{ClusterWorkers, Contracts};
+        use super::Runtime;
+        let cluster = match sender {
+            MessageOrigin::Cluster(cluster) => *cluster,
+            MessageOrigin::Contract(contract) => match Contracts::<Runtime>::get(contract) {
+                Some(info) => info.cluster,
+                None => return false,
+            },
+            _ => return false,
+        };
+        ClusterWorkers::<Runtime>::get(cluster).contains(worker)
+    }
 }